@@ -167,6 +167,7 @@
               nativeCheckInputs = with pkgs; [
                 binutils-unwrapped
                 sbsigntool
+                gnupg
               ];
             };
           };
@@ -179,10 +180,11 @@
             } ''
             mkdir -p $out/bin
 
-            # Clean PATH to only contain what we need to do objcopy. Also
-            # tell lanzatool where to find our UEFI binaries.
+            # Clean PATH to only contain what we need to do objcopy, signing,
+            # release signature verification and boot entries. Also tell lanzatool where to find our UEFI
+            # binaries.
             makeWrapper ${tool}/bin/lzbt-systemd $out/bin/lzbt \
-              --set PATH ${lib.makeBinPath [ pkgs.binutils-unwrapped pkgs.sbsigntool ]} \
+              --set PATH ${lib.makeBinPath [ pkgs.binutils-unwrapped pkgs.sbsigntool pkgs.efibootmgr pkgs.gnupg ]} \
               --set LANZABOOTE_STUB ${stub}/bin/lanzaboote_stub.efi
           '';
         in
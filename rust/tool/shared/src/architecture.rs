@@ -1,6 +1,7 @@
 use std::path::PathBuf;
 
 use anyhow::{bail, Result};
+use goblin::pe::header::{COFF_MACHINE_ARM64, COFF_MACHINE_X86_64};
 
 /// Supported system
 #[non_exhaustive]
@@ -21,6 +22,14 @@ impl Architecture {
     pub fn efi_fallback_filename(&self) -> PathBuf {
         format!("BOOT{}.EFI", self.efi_representation().to_ascii_uppercase()).into()
     }
+
+    /// The `Machine` field in the COFF header of PE binaries for this architecture.
+    pub fn pe_machine(&self) -> u16 {
+        match self {
+            Self::X86 => COFF_MACHINE_X86_64,
+            Self::AArch64 => COFF_MACHINE_ARM64,
+        }
+    }
 }
 
 impl Architecture {
@@ -32,4 +41,47 @@ impl Architecture {
             _ => bail!(format!("Unsupported NixOS system: {}.", system_double)),
         })
     }
+
+    /// Determine the architecture of a PE binary from the `Machine` field of its COFF header.
+    pub fn from_pe_machine(machine: u16) -> Result<Self> {
+        Ok(match machine {
+            COFF_MACHINE_X86_64 => Self::X86,
+            COFF_MACHINE_ARM64 => Self::AArch64,
+            _ => bail!("Unsupported PE machine type: {machine:#06x}."),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_architectures_to_efi_filenames() {
+        assert_eq!(
+            Architecture::from_nixos_system("x86_64-linux")
+                .unwrap()
+                .efi_fallback_filename(),
+            PathBuf::from("BOOTX64.EFI")
+        );
+        assert_eq!(
+            Architecture::from_nixos_system("aarch64-linux")
+                .unwrap()
+                .efi_fallback_filename(),
+            PathBuf::from("BOOTAA64.EFI")
+        );
+        assert!(Architecture::from_nixos_system("riscv64-linux").is_err());
+    }
+
+    #[test]
+    fn round_trip_pe_machine() {
+        for arch in [Architecture::X86, Architecture::AArch64] {
+            assert_eq!(
+                Architecture::from_pe_machine(arch.pe_machine()).unwrap(),
+                arch
+            );
+        }
+        // i386
+        assert!(Architecture::from_pe_machine(0x014c).is_err());
+    }
 }
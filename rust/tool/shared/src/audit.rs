@@ -0,0 +1,418 @@
+//! An offline audit of the boot chain on an ESP.
+//!
+//! Security teams want to know whether the binaries on an ESP boot on a machine with a given db
+//! before the ESP is rolled out. [`audit_esp`] checks every boot loader and UKI without the
+//! firmware:
+//!
+//! - Their signatures have to chain to a certificate in db.
+//! - Their SBAT generations must not be revoked by the given SBAT level.
+//! - UKIs need the sections the stub boots from, the files they refer to must match their hashes
+//!   and the policies in `.pcrsig` must match the predicted value of PCR 11.
+//!
+//! The result is an [`AuditReport`] with a pass or fail for every check.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use x509_cert::Certificate;
+
+use crate::authenticode::{read_certificate, verify_signatures};
+use crate::manifest::{extend, predict_pcr11};
+use crate::pe::read_section_data;
+use crate::utils::{file_hash, Hash};
+
+/// The sections a UKI needs to boot.
+const REQUIRED_SECTIONS: [&str; 3] = [".osrel", ".cmdline", ".linux"];
+
+/// The sections that refer to a file on the ESP, with the section holding its hash.
+const FILE_SECTIONS: [(&str, &str); 2] = [(".linux", ".linuxh"), (".initrd", ".initrdh")];
+
+/// The phases systemd-pcrphase measures into PCR 11 after the stub, in order. systemd-measure
+/// signs a policy for every prefix of them.
+const BOOT_PHASES: [&str; 4] = ["enter-initrd", "leave-initrd", "sysinit", "ready"];
+
+/// `TPM2_CC_PolicyPCR`.
+const TPM2_CC_POLICY_PCR: u32 = 0x17f;
+/// `TPM2_ALG_SHA256`.
+const TPM2_ALG_SHA256: u16 = 0x000b;
+
+/// The directories of the ESP with boot loaders.
+const BOOT_LOADER_DIRECTORIES: [&str; 2] = ["EFI/BOOT", "EFI/systemd"];
+/// The directory of the ESP with UKIs.
+const UKI_DIRECTORY: &str = "EFI/Linux";
+
+/// What the binaries on the ESP are checked against.
+pub struct AuditPolicy {
+    /// The certificates in db.
+    pub db: Vec<Certificate>,
+    /// The revoked SBAT generations. Without them, SBAT is not checked.
+    pub sbat_level: Option<SbatLevel>,
+}
+
+impl AuditPolicy {
+    /// Read the db certificates in PEM format and an SBAT level in the CSV format of shim.
+    pub fn read(db: &[PathBuf], sbat_level: Option<&Path>) -> Result<Self> {
+        Ok(Self {
+            db: db
+                .iter()
+                .map(|path| read_certificate(path))
+                .collect::<Result<_>>()?,
+            sbat_level: sbat_level
+                .map(|path| {
+                    let level = fs::read_to_string(path)
+                        .with_context(|| format!("Failed to read SBAT level {path:?}"))?;
+                    SbatLevel::parse(&level)
+                        .with_context(|| format!("Failed to parse SBAT level {path:?}"))
+                })
+                .transpose()?,
+        })
+    }
+}
+
+/// The lowest generation of every component that is not revoked, e.g. from
+/// `SbatLevel_Variable.txt` of shim.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SbatLevel {
+    generations: BTreeMap<String, u32>,
+}
+
+impl SbatLevel {
+    /// Parse lines of `component,generation`. Further fields, like the date on the first line,
+    /// are ignored.
+    pub fn parse(level: &str) -> Result<Self> {
+        Ok(Self {
+            generations: parse_sbat(level)?.into_iter().collect(),
+        })
+    }
+
+    /// The components of the `.sbat` section `sbat` whose generation is revoked.
+    pub fn revoked(&self, sbat: &str) -> Result<Vec<String>> {
+        Ok(parse_sbat(sbat)?
+            .into_iter()
+            .filter(|(component, generation)| {
+                self.generations
+                    .get(component)
+                    .is_some_and(|minimum| generation < minimum)
+            })
+            .map(|(component, generation)| format!("{component},{generation}"))
+            .collect())
+    }
+}
+
+/// The components and generations of SBAT entries in CSV format.
+fn parse_sbat(sbat: &str) -> Result<Vec<(String, u32)>> {
+    sbat.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let mut fields = line.split(',');
+            let component = fields.next().unwrap_or_default();
+            let generation = fields
+                .next()
+                .and_then(|generation| generation.trim().parse().ok())
+                .with_context(|| format!("Invalid SBAT entry {line:?}"))?;
+            Ok((component.trim().to_string(), generation))
+        })
+        .collect()
+}
+
+/// The result of a single check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Fail(String),
+    /// The check does not apply, for the given reason.
+    Skip(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pass => write!(f, "pass"),
+            Self::Fail(reason) => write!(f, "FAIL ({reason})"),
+            Self::Skip(reason) => write!(f, "skipped ({reason})"),
+        }
+    }
+}
+
+impl From<Result<()>> for Outcome {
+    fn from(result: Result<()>) -> Self {
+        match result {
+            Ok(()) => Self::Pass,
+            Err(err) => Self::Fail(format!("{err:#}")),
+        }
+    }
+}
+
+/// The checks of a single binary on the ESP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryReport {
+    /// The path of the binary relative to the ESP.
+    pub path: PathBuf,
+    /// The name and the outcome of every check, in the order they ran.
+    pub checks: Vec<(&'static str, Outcome)>,
+}
+
+impl EntryReport {
+    pub fn passed(&self) -> bool {
+        !self
+            .checks
+            .iter()
+            .any(|(_, outcome)| matches!(outcome, Outcome::Fail(_)))
+    }
+}
+
+/// The checks of all binaries on the ESP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditReport {
+    pub entries: Vec<EntryReport>,
+}
+
+impl AuditReport {
+    pub fn passed(&self) -> bool {
+        self.entries.iter().all(EntryReport::passed)
+    }
+
+    pub fn failed(&self) -> usize {
+        self.entries.iter().filter(|entry| !entry.passed()).count()
+    }
+}
+
+impl fmt::Display for AuditReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            let result = if entry.passed() { "pass" } else { "FAIL" };
+            writeln!(f, "{}: {result}", entry.path.display())?;
+            for (name, outcome) in &entry.checks {
+                writeln!(f, "  {name}: {outcome}")?;
+            }
+        }
+        writeln!(
+            f,
+            "{} of {} binaries passed.",
+            self.entries.len() - self.failed(),
+            self.entries.len()
+        )
+    }
+}
+
+/// Check every boot loader in `EFI/BOOT` and `EFI/systemd` and every UKI in `EFI/Linux` on the
+/// ESP against `policy`.
+pub fn audit_esp(esp: &Path, policy: &AuditPolicy) -> Result<AuditReport> {
+    let mut entries = Vec::new();
+    for directory in BOOT_LOADER_DIRECTORIES {
+        for path in efi_binaries(&esp.join(directory))? {
+            entries.push(audit_binary(esp, &path, false, policy)?);
+        }
+    }
+    for path in efi_binaries(&esp.join(UKI_DIRECTORY))? {
+        entries.push(audit_binary(esp, &path, true, policy)?);
+    }
+    Ok(AuditReport { entries })
+}
+
+/// The `*.efi` files in `directory`, sorted by name. A missing directory has none.
+fn efi_binaries(directory: &Path) -> Result<Vec<PathBuf>> {
+    if !directory.exists() {
+        return Ok(Vec::new());
+    }
+    let mut binaries = fs::read_dir(directory)
+        .with_context(|| format!("Failed to read {directory:?}"))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    binaries.retain(|path| {
+        path.is_file()
+            && path
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("efi"))
+    });
+    binaries.sort();
+    Ok(binaries)
+}
+
+fn audit_binary(esp: &Path, path: &Path, uki: bool, policy: &AuditPolicy) -> Result<EntryReport> {
+    let file_data = fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+    let mut checks = vec![
+        (
+            "signature",
+            verify_signatures(&file_data, &policy.db).into(),
+        ),
+        ("sbat", check_sbat(&file_data, policy.sbat_level.as_ref())),
+    ];
+    if uki {
+        checks.extend([
+            ("sections", check_sections(&file_data).into()),
+            ("files", check_files(esp, &file_data)),
+            ("pcrsig", check_pcrsig(&file_data)),
+        ]);
+    }
+    Ok(EntryReport {
+        path: path.strip_prefix(esp)?.to_path_buf(),
+        checks,
+    })
+}
+
+fn check_sbat(file_data: &[u8], level: Option<&SbatLevel>) -> Outcome {
+    let Some(level) = level else {
+        return Outcome::Skip("no SBAT level given".into());
+    };
+    let Some(sbat) = read_section_data(file_data, ".sbat") else {
+        return Outcome::Fail("no .sbat section, shim refuses to load the binary".into());
+    };
+    let result = level
+        .revoked(&String::from_utf8_lossy(sbat))
+        .and_then(|revoked| {
+            if revoked.is_empty() {
+                Ok(())
+            } else {
+                anyhow::bail!("revoked generations {}", revoked.join(", "))
+            }
+        });
+    result.into()
+}
+
+fn check_sections(file_data: &[u8]) -> Result<()> {
+    let missing = REQUIRED_SECTIONS
+        .into_iter()
+        .filter(|name| read_section_data(file_data, name).is_none())
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        anyhow::bail!("missing sections {}", missing.join(", "));
+    }
+    Ok(())
+}
+
+/// Check the kernel and the initrd that a UKI of the thin stub loads from the ESP.
+fn check_files(esp: &Path, file_data: &[u8]) -> Outcome {
+    let files = FILE_SECTIONS
+        .into_iter()
+        .filter_map(|(section, hash_section)| {
+            Some((
+                read_section_data(file_data, section)?,
+                read_section_data(file_data, hash_section)?,
+            ))
+        })
+        .collect::<Vec<_>>();
+    if files.is_empty() {
+        return Outcome::Skip("the kernel is embedded".into());
+    }
+
+    let result = files.into_iter().try_for_each(|(path, expected)| {
+        // The section may be padded with NUL bytes.
+        let path = String::from_utf8_lossy(path);
+        let path = path.trim_end_matches('\0');
+        let file = esp.join(path.trim_start_matches('\\').replace('\\', "/"));
+        if !file.exists() {
+            anyhow::bail!("{path} does not exist");
+        }
+        if file_hash(&file)?.as_slice() != expected {
+            anyhow::bail!("{path} does not match its hash");
+        }
+        Ok(())
+    });
+    result.into()
+}
+
+fn check_pcrsig(file_data: &[u8]) -> Outcome {
+    let Some(pcrsig) = read_section_data(file_data, ".pcrsig") else {
+        return Outcome::Skip("no .pcrsig section".into());
+    };
+    verify_pcrsig(file_data, pcrsig).into()
+}
+
+/// Check that every SHA256 policy in the `.pcrsig` section covers a value of PCR 11 that the UKI
+/// and the boot phases yield, and that it is signed with the key in `.pcrpkey`.
+fn verify_pcrsig(file_data: &[u8], pcrsig: &[u8]) -> Result<()> {
+    let pcrsig: Value = serde_json::from_slice(pcrsig).context("Invalid JSON in .pcrsig")?;
+    let policies = pcrsig
+        .get("sha256")
+        .and_then(Value::as_array)
+        .filter(|policies| !policies.is_empty())
+        .context("no SHA256 policies in .pcrsig")?;
+
+    let mut pcr = predict_pcr11(file_data)?;
+    let mut expected = vec![policy_digest(&pcr)];
+    for phase in BOOT_PHASES {
+        pcr = extend(&pcr, phase.as_bytes());
+        expected.push(policy_digest(&pcr));
+    }
+    let key_fingerprint = read_section_data(file_data, ".pcrpkey")
+        .map(|pcrpkey| -> Result<Hash> {
+            let (_, der) = x509_cert::der::pem::decode_vec(pcrpkey)
+                .map_err(|err| anyhow::anyhow!("Invalid public key in .pcrpkey: {err}"))?;
+            Ok(Sha256::digest(der))
+        })
+        .transpose()?;
+
+    for policy in policies {
+        if policy.get("pcrs") != Some(&serde_json::json!([11])) {
+            anyhow::bail!("a policy covers PCRs other than 11, which cannot be predicted");
+        }
+        let digest = policy
+            .get("pol")
+            .and_then(Value::as_str)
+            .context("a policy has no digest")?;
+        if !expected
+            .iter()
+            .any(|expected| format!("{expected:x}") == digest.to_ascii_lowercase())
+        {
+            anyhow::bail!("policy {digest} does not match the predicted PCR 11");
+        }
+        if let Some(key_fingerprint) = &key_fingerprint {
+            let fingerprint = policy.get("pkfp").and_then(Value::as_str);
+            if fingerprint != Some(&format!("{key_fingerprint:x}")) {
+                anyhow::bail!("policy {digest} is not signed with the key in .pcrpkey");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The digest of a TPM2_PolicyPCR policy over PCR 11 in the SHA256 bank with the value `pcr`.
+fn policy_digest(pcr: &Hash) -> Hash {
+    // A TPML_PCR_SELECTION with a single bank and PCR 11 set in its 3 byte bitmap.
+    let mut selection = Vec::new();
+    selection.extend(1u32.to_be_bytes());
+    selection.extend(TPM2_ALG_SHA256.to_be_bytes());
+    selection.extend([3, 0, 1 << 3, 0]);
+
+    Sha256::new()
+        .chain_update([0; 32])
+        .chain_update(TPM2_CC_POLICY_PCR.to_be_bytes())
+        .chain_update(selection)
+        .chain_update(Sha256::digest(pcr))
+        .finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEVEL: &str = "sbat,1,2023012900\nshim,2\ngrub,3\n";
+
+    #[test]
+    fn detect_revoked_sbat_generation() -> Result<()> {
+        let level = SbatLevel::parse(LEVEL)?;
+        let sbat = "sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md\n\
+                    grub,2,Free Software Foundation,grub,2.06,https://www.gnu.org/software/grub/\n";
+
+        assert_eq!(level.revoked(sbat)?, ["grub,2"]);
+        Ok(())
+    }
+
+    #[test]
+    fn accept_current_sbat_generation() -> Result<()> {
+        let level = SbatLevel::parse(LEVEL)?;
+        let sbat = "sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md\n\
+                    systemd,1,The systemd Developers,systemd,255,https://systemd.io/\n";
+
+        assert!(level.revoked(sbat)?.is_empty());
+        assert!(SbatLevel::parse("shim\n").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,856 @@
+//! Authenticode signatures created in-process.
+//!
+//! Signing with sbsign spawns a process per file and loads the key every time. [`NativeSigner`]
+//! loads the key once and creates the same kind of signature as sbsign: a PKCS#7 SignedData
+//! structure over the Authenticode digest of the binary, embedded in its certificate table.
+//!
+//! [`RemoteSigner`] leaves the key on a signing service and only sends it the digest.
+//!
+//! [`signer_chains`] reads these structures back to show who signed a binary.
+//! [`attach_and_verify`] embeds such a structure that was created elsewhere, after checking it.
+//! [`verify_signatures`] checks the embedded structures against trusted certificates.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs1v15::{self, SigningKey, VerifyingKey};
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::signature::{SignatureEncoding, Signer as _, Verifier};
+use rsa::RsaPrivateKey;
+use sha2::{Digest, Sha256};
+use url::{Host, Url};
+use x509_cert::der::asn1::ObjectIdentifier;
+use x509_cert::der::{Decode, DecodePem, Encode};
+use x509_cert::Certificate;
+
+use crate::pe::{self, AuthenticodeLayout, CERTIFICATE_TABLE_ALIGNMENT};
+use crate::pki::public_key;
+use crate::signature::{KeyPair, Signer};
+use crate::utils::Hash;
+
+const ID_SIGNED_DATA: &str = "1.2.840.113549.1.7.2";
+const ID_CONTENT_TYPE: &str = "1.2.840.113549.1.9.3";
+const ID_MESSAGE_DIGEST: &str = "1.2.840.113549.1.9.4";
+const ID_SHA256: &str = "2.16.840.1.101.3.4.2.1";
+const RSA_ENCRYPTION: &str = "1.2.840.113549.1.1.1";
+const SHA256_WITH_RSA_ENCRYPTION: &str = "1.2.840.113549.1.1.11";
+const SPC_INDIRECT_DATA_OBJID: &str = "1.3.6.1.4.1.311.2.1.4";
+const SPC_SP_OPUS_INFO_OBJID: &str = "1.3.6.1.4.1.311.2.1.12";
+const SPC_PE_IMAGE_DATAOBJ: &str = "1.3.6.1.4.1.311.2.1.15";
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_NULL: u8 = 0x05;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_CONTEXT_0: u8 = 0xa0;
+const TAG_CONTEXT_2: u8 = 0xa2;
+const TAG_CONTEXT_0_PRIMITIVE: u8 = 0x80;
+
+/// Revision of the `WIN_CERTIFICATE` structure.
+const WIN_CERT_REVISION_2_0: u16 = 0x0200;
+/// Certificate type of a PKCS#7 SignedData structure.
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 0x0002;
+/// Size of the header of a `WIN_CERTIFICATE` structure.
+const WIN_CERTIFICATE_HEADER_SIZE: usize = 8;
+
+/// How long to wait for a remote signing service.
+const REMOTE_SIGNER_TIMEOUT: Duration = Duration::from_secs(60);
+/// The most that is read from a remote signing service. Signatures with a certificate chain are
+/// a few kilobytes.
+const MAX_REMOTE_SIGNATURE_SIZE: u64 = 1024 * 1024;
+
+/// Signs PE binaries with a key that is loaded once.
+pub struct NativeSigner {
+    signing_key: SigningKey<Sha256>,
+    /// The DER encoded certificate.
+    certificate: Vec<u8>,
+    /// The DER encoded issuer and serial number of the certificate.
+    issuer_and_serial_number: Vec<u8>,
+}
+
+impl NativeSigner {
+    /// Load the private key (PKCS#8 or PKCS#1) and the certificate of a key pair in PEM format.
+    pub fn new(key_pair: &KeyPair) -> Result<Self> {
+        let private_key = read_private_key(&key_pair.private_key)?;
+        let certificate = read_certificate(&key_pair.public_key)?;
+        let tbs_certificate = &certificate.tbs_certificate;
+        let issuer_and_serial_number = sequence(&[
+            &tbs_certificate.issuer.to_der()?,
+            &tbs_certificate.serial_number.to_der()?,
+        ]);
+
+        Ok(Self {
+            signing_key: SigningKey::new(private_key),
+            certificate: certificate.to_der()?,
+            issuer_and_serial_number,
+        })
+    }
+
+    /// Create the PKCS#7 SignedData structure for an Authenticode digest.
+    pub fn signed_data(&self, digest: &[u8]) -> Vec<u8> {
+        let sha256 = algorithm_identifier(ID_SHA256);
+
+        // The file link is obsolete, but still expected to be present.
+        let obsolete = "<<<Obsolete>>>"
+            .encode_utf16()
+            .flat_map(u16::to_be_bytes)
+            .collect::<Vec<_>>();
+        let pe_image_data = sequence(&[
+            &tlv(TAG_BIT_STRING, &[0]),
+            &tlv(
+                TAG_CONTEXT_0,
+                &tlv(TAG_CONTEXT_2, &tlv(TAG_CONTEXT_0_PRIMITIVE, &obsolete)),
+            ),
+        ]);
+        let indirect_data_contents = [
+            sequence(&[&oid(SPC_PE_IMAGE_DATAOBJ), &pe_image_data]),
+            sequence(&[&sha256, &tlv(TAG_OCTET_STRING, digest)]),
+        ]
+        .concat();
+
+        // The message digest covers the contents of the SpcIndirectDataContent without its tag
+        // and length.
+        let mut signed_attributes = [
+            attribute(ID_CONTENT_TYPE, &oid(SPC_INDIRECT_DATA_OBJID)),
+            attribute(
+                ID_MESSAGE_DIGEST,
+                &tlv(TAG_OCTET_STRING, &Sha256::digest(&indirect_data_contents)),
+            ),
+            attribute(SPC_SP_OPUS_INFO_OBJID, &sequence(&[])),
+        ];
+        // DER requires the elements of a SET OF to be sorted by their encoding.
+        signed_attributes.sort();
+        let signed_attributes = signed_attributes.concat();
+        let signature = self
+            .signing_key
+            .sign(&tlv(TAG_SET, &signed_attributes))
+            .to_vec();
+
+        let signer_info = sequence(&[
+            &integer_one(),
+            &self.issuer_and_serial_number,
+            &sha256,
+            &tlv(TAG_CONTEXT_0, &signed_attributes),
+            &algorithm_identifier(RSA_ENCRYPTION),
+            &tlv(TAG_OCTET_STRING, &signature),
+        ]);
+        let signed_data = sequence(&[
+            &integer_one(),
+            &tlv(TAG_SET, &sha256),
+            &sequence(&[
+                &oid(SPC_INDIRECT_DATA_OBJID),
+                &tlv(TAG_CONTEXT_0, &tlv(TAG_SEQUENCE, &indirect_data_contents)),
+            ]),
+            &tlv(TAG_CONTEXT_0, &self.certificate),
+            &tlv(TAG_SET, &signer_info),
+        ]);
+
+        sequence(&[&oid(ID_SIGNED_DATA), &tlv(TAG_CONTEXT_0, &signed_data)])
+    }
+}
+
+impl Signer for NativeSigner {
+    fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        sign_file(from, to, false, |digest| Ok(self.signed_data(digest)))
+    }
+
+    fn add_signature(&self, from: &Path, to: &Path) -> Result<()> {
+        sign_file(from, to, true, |digest| Ok(self.signed_data(digest)))
+    }
+}
+
+/// Signs PE binaries with a key that stays on a remote signing service.
+///
+/// Only the Authenticode digest of a binary is sent to the service: its 32 bytes are the body of
+/// a POST request, authorized with a bearer token if there is one. The service answers with the
+/// DER encoded PKCS#7 SignedData structure over the digest, as [`NativeSigner::signed_data`]
+/// creates it. The answer is checked against the certificate of the key before it is embedded.
+pub struct RemoteSigner {
+    url: String,
+    token: Option<String>,
+    certificate: Certificate,
+    agent: ureq::Agent,
+}
+
+impl RemoteSigner {
+    /// Sign with the service at `url`, whose key belongs to the certificate in PEM format at
+    /// `certificate`.
+    ///
+    /// The service must be reached over HTTPS, so that the token cannot be read on the way. Plain
+    /// HTTP is only accepted for services on the loopback interface.
+    pub fn new(url: &str, token: Option<&str>, certificate: &Path) -> Result<Self> {
+        ensure_secure_url(url)?;
+        Ok(Self {
+            url: url.to_string(),
+            token: token.map(str::to_string),
+            certificate: read_certificate(certificate)?,
+            // A redirect could lead to a URL that is not checked above.
+            agent: ureq::AgentBuilder::new()
+                .timeout(REMOTE_SIGNER_TIMEOUT)
+                .redirects(0)
+                .build(),
+        })
+    }
+
+    /// Request the PKCS#7 SignedData structure for an Authenticode digest from the service.
+    fn signed_data(&self, digest: &[u8]) -> Result<Vec<u8>> {
+        let mut request = self
+            .agent
+            .post(&self.url)
+            .set("Content-Type", "application/octet-stream")
+            .set("Accept", "application/pkcs7-signature");
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", &format!("Bearer {token}"));
+        }
+        let response = request
+            .send_bytes(digest)
+            .with_context(|| format!("Failed to request a signature from {}", self.url))?;
+        let mut signature = Vec::new();
+        response
+            .into_reader()
+            .take(MAX_REMOTE_SIGNATURE_SIZE)
+            .read_to_end(&mut signature)
+            .with_context(|| format!("Failed to read the signature from {}", self.url))?;
+
+        let (_, rest) = split_element(&signature)
+            .with_context(|| format!("Failed to parse the signature from {}", self.url))?;
+        signature.truncate(signature.len() - rest.len());
+        verify_signed_data(&SignedData::parse(&signature)?, digest, &self.certificate)
+            .with_context(|| format!("Rejected the signature from {}", self.url))?;
+        Ok(signature)
+    }
+}
+
+/// Refuse URLs of signing services that are neither HTTPS nor on the loopback interface.
+fn ensure_secure_url(url: &str) -> Result<()> {
+    let parsed = Url::parse(url).with_context(|| format!("Invalid signing service URL {url}"))?;
+    let loopback = match parsed.host() {
+        Some(Host::Domain(domain)) => domain == "localhost",
+        Some(Host::Ipv4(address)) => address.is_loopback(),
+        Some(Host::Ipv6(address)) => address.is_loopback(),
+        None => false,
+    };
+    match parsed.scheme() {
+        "https" => Ok(()),
+        "http" if loopback => Ok(()),
+        scheme => anyhow::bail!(
+            "Refusing to use the signing service at {url}: {scheme} is not HTTPS and the service is not on the loopback interface."
+        ),
+    }
+}
+
+impl Signer for RemoteSigner {
+    fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        sign_file(from, to, false, |digest| self.signed_data(digest))
+    }
+
+    fn add_signature(&self, from: &Path, to: &Path) -> Result<()> {
+        sign_file(from, to, true, |digest| self.signed_data(digest))
+    }
+}
+
+/// Sign the PE binary `from` into `to` with the SignedData structure that `signed_data` creates for
+/// its Authenticode digest. The existing signatures are replaced, unless `keep_signatures` is set.
+///
+/// The binary is streamed from `from` to `to` and hashed in chunks, so it is never read into
+/// memory as a whole.
+fn sign_file(
+    from: &Path,
+    to: &Path,
+    keep_signatures: bool,
+    signed_data: impl FnOnce(&[u8]) -> Result<Vec<u8>>,
+) -> Result<()> {
+    let mut from_file = File::open(from).with_context(|| format!("Failed to open {from:?}"))?;
+    copy_and_sign(&mut from_file, to, keep_signatures, signed_data)
+        .with_context(|| format!("Failed to sign {from:?}"))
+}
+
+fn copy_and_sign(
+    from: &mut File,
+    to: &Path,
+    keep_signatures: bool,
+    signed_data: impl FnOnce(&[u8]) -> Result<Vec<u8>>,
+) -> Result<()> {
+    let mut output = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(to)
+        .with_context(|| format!("Failed to create {to:?}"))?;
+    let (layout, address) = pe::copy_unsigned(from, &mut output)?;
+
+    // The digest does not cover the certificate table, so the existing signatures stay valid
+    // when they are put back in front of the new one.
+    let mut certificate_table = Vec::new();
+    if let Some(table) = layout
+        .certificate_table
+        .as_ref()
+        .filter(|_| keep_signatures)
+    {
+        from.seek(SeekFrom::Start(table.start))?;
+        from.take(table.end - table.start)
+            .read_to_end(&mut certificate_table)
+            .context("Failed to read the certificate table")?;
+        certificate_table.resize(
+            certificate_table
+                .len()
+                .next_multiple_of(CERTIFICATE_TABLE_ALIGNMENT as usize),
+            0,
+        );
+    }
+
+    let digest = pe::authenticode_digest_reader(&mut output)?;
+    let signed_data = signed_data(&digest)?;
+    append_certificate(
+        &mut output,
+        &layout,
+        address,
+        certificate_table,
+        &signed_data,
+    )
+}
+
+/// Write `certificate_table` with a `WIN_CERTIFICATE` entry for `signed_data` appended at file
+/// offset `address` and point the certificate table data directory entry at it.
+fn append_certificate(
+    output: &mut (impl Write + Seek),
+    layout: &AuthenticodeLayout,
+    address: u64,
+    mut certificate_table: Vec<u8>,
+    signed_data: &[u8],
+) -> Result<()> {
+    let length = (WIN_CERTIFICATE_HEADER_SIZE + signed_data.len())
+        .next_multiple_of(CERTIFICATE_TABLE_ALIGNMENT as usize);
+    let mut certificate = Vec::with_capacity(length);
+    certificate.extend(u32::try_from(length)?.to_le_bytes());
+    certificate.extend(WIN_CERT_REVISION_2_0.to_le_bytes());
+    certificate.extend(WIN_CERT_TYPE_PKCS_SIGNED_DATA.to_le_bytes());
+    certificate.extend(signed_data);
+    certificate.resize(length, 0);
+    certificate_table.extend(certificate);
+    output.seek(SeekFrom::Start(address))?;
+    output.write_all(&certificate_table)?;
+
+    write_certificate_table_entry(
+        output,
+        layout,
+        u32::try_from(address)?,
+        u32::try_from(certificate_table.len())?,
+    )
+}
+
+/// Point the certificate table data directory entry at `size` bytes at file offset `address`.
+fn write_certificate_table_entry(
+    file: &mut (impl Write + Seek),
+    layout: &AuthenticodeLayout,
+    address: u32,
+    size: u32,
+) -> Result<()> {
+    file.seek(SeekFrom::Start(layout.certificate_table_entry))?;
+    file.write_all(&[address.to_le_bytes(), size.to_le_bytes()].concat())?;
+    Ok(())
+}
+
+/// Read an RSA private key in PEM format, either PKCS#8 or PKCS#1.
+pub(crate) fn read_private_key(path: &Path) -> Result<RsaPrivateKey> {
+    let private_key =
+        fs::read_to_string(path).with_context(|| format!("Failed to read private key {path:?}"))?;
+    RsaPrivateKey::from_pkcs8_pem(&private_key)
+        .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&private_key))
+        .with_context(|| format!("Failed to parse private key {path:?}"))
+}
+
+/// Read a certificate in PEM format.
+pub(crate) fn read_certificate(path: &Path) -> Result<Certificate> {
+    let certificate =
+        fs::read(path).with_context(|| format!("Failed to read certificate {path:?}"))?;
+    Certificate::from_pem(certificate)
+        .with_context(|| format!("Failed to parse certificate {path:?}"))
+}
+
+/// A certificate in the chain of the signer of an Authenticode signature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainCertificate {
+    /// The subject of the certificate in RFC 4514 format.
+    pub subject: String,
+    /// The issuer of the certificate in RFC 4514 format.
+    pub issuer: String,
+    pub not_before: String,
+    pub not_after: String,
+    /// The SHA256 hash of the DER encoded certificate.
+    pub fingerprint: Hash,
+}
+
+impl ChainCertificate {
+    fn new(certificate: &Certificate) -> Result<Self> {
+        let tbs_certificate = &certificate.tbs_certificate;
+        Ok(Self {
+            subject: tbs_certificate.subject.to_string(),
+            issuer: tbs_certificate.issuer.to_string(),
+            not_before: tbs_certificate.validity.not_before.to_string(),
+            not_after: tbs_certificate.validity.not_after.to_string(),
+            fingerprint: Sha256::digest(certificate.to_der()?),
+        })
+    }
+}
+
+impl fmt::Display for ChainCertificate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Subject: {}", self.subject)?;
+        writeln!(f, "Issuer: {}", self.issuer)?;
+        writeln!(f, "Valid: {} to {}", self.not_before, self.not_after)?;
+        write!(f, "SHA256 fingerprint: {:x}", self.fingerprint)
+    }
+}
+
+/// The certificate chains of all signers of a PE binary, in the order of the certificate table.
+///
+/// Every chain starts with the certificate of the signer, followed by the certificates embedded in
+/// the signature that issued it, as far as they are embedded. Signatures that are not PKCS#7
+/// SignedData are skipped.
+pub fn signer_chains(file_data: &[u8]) -> Result<Vec<Vec<ChainCertificate>>> {
+    let mut chains = Vec::new();
+    for entry in signed_data_entries(file_data)? {
+        chains.extend(signed_data_chains(entry).context("Failed to parse the signature")?);
+    }
+    Ok(chains)
+}
+
+/// Check that a signature of a PE binary covers its Authenticode digest and that its signer
+/// chains to one of the `trusted` certificates, e.g. those in db.
+pub fn verify_signatures(file_data: &[u8], trusted: &[Certificate]) -> Result<()> {
+    let entries = signed_data_entries(file_data)?;
+    if entries.is_empty() {
+        anyhow::bail!("The binary is not signed");
+    }
+    let digest = pe::authenticode_digest(file_data)?;
+
+    let mut last_error = None;
+    for entry in entries {
+        let signed_data = SignedData::parse(entry).context("Failed to parse the signature")?;
+        for certificate in trusted {
+            match verify_signed_data(&signed_data, &digest, certificate) {
+                Ok(()) => return Ok(()),
+                Err(err) => last_error = Some(err),
+            }
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| anyhow::anyhow!("No trusted certificates given"))
+        .context("No signature chains to a trusted certificate"))
+}
+
+/// The PKCS#7 SignedData structures in the certificate table of a PE binary, in the order of the
+/// table. Entries of other types are skipped.
+fn signed_data_entries(file_data: &[u8]) -> Result<Vec<&[u8]>> {
+    let layout = AuthenticodeLayout::read(&mut Cursor::new(file_data))?;
+    let Some(table) = layout.certificate_table else {
+        return Ok(Vec::new());
+    };
+    let mut table = &file_data[usize::try_from(table.start)?..usize::try_from(table.end)?];
+
+    let mut entries = Vec::new();
+    while !table.is_empty() {
+        let header = table
+            .get(..WIN_CERTIFICATE_HEADER_SIZE)
+            .context("Truncated certificate table entry")?;
+        let length = u32::from_le_bytes(header[..4].try_into()?) as usize;
+        let certificate_type = u16::from_le_bytes(header[6..8].try_into()?);
+        let entry = table
+            .get(WIN_CERTIFICATE_HEADER_SIZE..length)
+            .context("Truncated certificate table entry")?;
+        if certificate_type == WIN_CERT_TYPE_PKCS_SIGNED_DATA {
+            entries.push(entry);
+        }
+        table = table
+            .get(length.next_multiple_of(CERTIFICATE_TABLE_ALIGNMENT as usize)..)
+            .unwrap_or_default();
+    }
+    Ok(entries)
+}
+
+/// The certificate chains of the signers of a PKCS#7 SignedData structure.
+fn signed_data_chains(content_info: &[u8]) -> Result<Vec<Vec<ChainCertificate>>> {
+    let signed_data = SignedData::parse(content_info)?;
+    let mut chains = Vec::new();
+    for signer_info in &signed_data.signer_infos {
+        let (signer_info, _) = element(signer_info, TAG_SEQUENCE)?;
+        let signer_id = *elements(signer_info)?
+            .get(1)
+            .context("Malformed SignerInfo")?;
+        let signer = signed_data.signer_certificate(signer_id)?;
+        chains.push(
+            signed_data
+                .chain(signer)
+                .into_iter()
+                .map(ChainCertificate::new)
+                .collect::<Result<_>>()?,
+        );
+    }
+    Ok(chains)
+}
+
+/// The parts of a PKCS#7 SignedData structure.
+struct SignedData<'a> {
+    /// The encoded EncapsulatedContentInfo.
+    encapsulated_content_info: &'a [u8],
+    /// The certificates embedded in the structure.
+    certificates: Vec<Certificate>,
+    /// The encoded SignerInfo structures.
+    signer_infos: Vec<&'a [u8]>,
+}
+
+impl<'a> SignedData<'a> {
+    /// Parse a ContentInfo structure that contains SignedData.
+    fn parse(content_info: &'a [u8]) -> Result<Self> {
+        // The entry may be padded after the structure.
+        let (content_info, _) = element(content_info, TAG_SEQUENCE)?;
+        let [content_type, content] = elements(content_info)?[..] else {
+            anyhow::bail!("Malformed ContentInfo");
+        };
+        if content_type != oid(ID_SIGNED_DATA) {
+            anyhow::bail!("The signature is not a SignedData structure");
+        }
+        let (content, _) = element(content, TAG_CONTEXT_0)?;
+        let (signed_data, _) = element(content, TAG_SEQUENCE)?;
+        let signed_data = elements(signed_data)?;
+        let encapsulated_content_info = *signed_data.get(2).context("Malformed SignedData")?;
+
+        // The certificates are the only field with this tag.
+        let mut certificates = Vec::new();
+        if let Some(embedded) = signed_data.iter().find(|e| e[0] == TAG_CONTEXT_0) {
+            let (embedded, _) = element(embedded, TAG_CONTEXT_0)?;
+            for certificate in elements(embedded)? {
+                certificates.push(
+                    Certificate::from_der(certificate).context("Failed to parse certificate")?,
+                );
+            }
+        }
+        let signer_infos = signed_data.last().context("Malformed SignedData")?;
+        let (signer_infos, _) = element(signer_infos, TAG_SET)?;
+
+        Ok(Self {
+            encapsulated_content_info,
+            certificates,
+            signer_infos: elements(signer_infos)?,
+        })
+    }
+
+    /// The contents of the SpcIndirectDataContent that the signature covers, without its tag and
+    /// length.
+    fn indirect_data_content(&self) -> Result<&'a [u8]> {
+        let (content_info, _) = element(self.encapsulated_content_info, TAG_SEQUENCE)?;
+        let [content_type, content] = elements(content_info)?[..] else {
+            anyhow::bail!("The signature does not contain an Authenticode digest");
+        };
+        if content_type != oid(SPC_INDIRECT_DATA_OBJID) {
+            anyhow::bail!("The signature does not contain an Authenticode digest");
+        }
+        let (content, _) = element(content, TAG_CONTEXT_0)?;
+        let (indirect_data_content, _) = element(content, TAG_SEQUENCE)?;
+        Ok(indirect_data_content)
+    }
+
+    /// The embedded certificate identified by the encoded IssuerAndSerialNumber of a SignerInfo.
+    fn signer_certificate(&self, signer_id: &[u8]) -> Result<&Certificate> {
+        self.certificates
+            .iter()
+            .find(|certificate| {
+                let tbs_certificate = &certificate.tbs_certificate;
+                let (Ok(issuer), Ok(serial_number)) = (
+                    tbs_certificate.issuer.to_der(),
+                    tbs_certificate.serial_number.to_der(),
+                ) else {
+                    return false;
+                };
+                sequence(&[&issuer, &serial_number]) == signer_id
+            })
+            .context("The certificate of the signer is not embedded in the signature")
+    }
+
+    /// `signer`, followed by the embedded certificates that issued it, as far as they are
+    /// embedded.
+    fn chain<'b>(&'b self, signer: &'b Certificate) -> Vec<&'b Certificate> {
+        let mut chain = vec![signer];
+        while let Some(issuer) = self.certificates.iter().find(|certificate| {
+            certificate.tbs_certificate.subject == chain[chain.len() - 1].tbs_certificate.issuer
+                && !chain.contains(certificate)
+        }) {
+            chain.push(issuer);
+        }
+        chain
+    }
+}
+
+/// Embed the detached PKCS#7 signature in `p7s` into the certificate table of the PE binary
+/// `uki`, e.g. one that was created on an offline signing machine.
+///
+/// The signature must cover the Authenticode digest of `uki` and its signer must be
+/// `certificate` or be issued by it through the certificates embedded in the signature. The
+/// existing signatures of `uki` are replaced. If the signature is rejected, `uki` is left alone.
+/// `p7s` is read as DER or PEM.
+pub fn attach_and_verify(uki: &Path, p7s: &Path, certificate: &Path) -> Result<()> {
+    let file_data = fs::read(uki).with_context(|| format!("Failed to read {uki:?}"))?;
+    let signature = read_detached_signature(p7s)?;
+    let trusted = read_certificate(certificate)?;
+
+    let mut output = Cursor::new(Vec::new());
+    let (layout, address) = pe::copy_unsigned(&mut Cursor::new(&file_data), &mut output)
+        .with_context(|| format!("Failed to remove the signatures of {uki:?}"))?;
+    // The digest covers the padding in front of the certificate table, as for a signature that
+    // is created in-process.
+    let digest = pe::authenticode_digest_reader(&mut output)?;
+    verify_signed_data(&SignedData::parse(&signature)?, &digest, &trusted)
+        .with_context(|| format!("Failed to verify {p7s:?} against {uki:?}"))?;
+
+    append_certificate(&mut output, &layout, address, Vec::new(), &signature)?;
+    fs::write(uki, output.into_inner()).with_context(|| format!("Failed to write {uki:?}"))
+}
+
+/// Read a detached PKCS#7 signature in DER or PEM format. Padding after the structure is dropped.
+fn read_detached_signature(path: &Path) -> Result<Vec<u8>> {
+    let data = fs::read(path).with_context(|| format!("Failed to read signature {path:?}"))?;
+    let data = if data.starts_with(b"-----BEGIN") {
+        let (_, der) = x509_cert::der::pem::decode_vec(&data)
+            .map_err(|err| anyhow::anyhow!("Failed to parse signature {path:?}: {err}"))?;
+        der
+    } else {
+        data
+    };
+    let (_, rest) =
+        split_element(&data).with_context(|| format!("Failed to parse signature {path:?}"))?;
+    Ok(data[..data.len() - rest.len()].to_vec())
+}
+
+/// Check that `signed_data` signs the Authenticode digest `digest` and that its signer chains to
+/// `trusted`.
+fn verify_signed_data(
+    signed_data: &SignedData,
+    digest: &[u8],
+    trusted: &Certificate,
+) -> Result<()> {
+    let indirect_data_content = signed_data.indirect_data_content()?;
+    let [_, digest_info] = elements(indirect_data_content)?[..] else {
+        anyhow::bail!("Malformed SpcIndirectDataContent");
+    };
+    let (digest_info, _) = element(digest_info, TAG_SEQUENCE)?;
+    let [algorithm, signed_digest] = elements(digest_info)?[..] else {
+        anyhow::bail!("Malformed DigestInfo");
+    };
+    ensure_sha256(algorithm)?;
+    let (signed_digest, _) = element(signed_digest, TAG_OCTET_STRING)?;
+    if signed_digest != digest {
+        anyhow::bail!("The signature does not cover the Authenticode digest of the binary");
+    }
+
+    let [signer_info] = signed_data.signer_infos[..] else {
+        anyhow::bail!(
+            "Expected exactly one signer, found {}",
+            signed_data.signer_infos.len()
+        );
+    };
+    let (signer_info, _) = element(signer_info, TAG_SEQUENCE)?;
+    let [_, signer_id, digest_algorithm, signed_attributes, _, signature, ..] =
+        elements(signer_info)?[..]
+    else {
+        anyhow::bail!("The signature has no signed attributes");
+    };
+    ensure_sha256(digest_algorithm)?;
+    let (signed_attributes, _) = element(signed_attributes, TAG_CONTEXT_0)?;
+    let message_digest = elements(signed_attributes)?
+        .into_iter()
+        .find_map(|attribute| {
+            let (attribute, _) = element(attribute, TAG_SEQUENCE).ok()?;
+            let [attribute_type, values] = elements(attribute).ok()?[..] else {
+                return None;
+            };
+            (attribute_type == oid(ID_MESSAGE_DIGEST)).then_some(values)
+        })
+        .context("The signature has no message digest")?;
+    let (message_digest, _) = element(message_digest, TAG_SET)?;
+    let (message_digest, _) = element(message_digest, TAG_OCTET_STRING)?;
+    if message_digest != Sha256::digest(indirect_data_content).as_slice() {
+        anyhow::bail!("The message digest does not match the signed content");
+    }
+
+    let signer = signed_data.signer_certificate(signer_id)?;
+    let (signature, _) = element(signature, TAG_OCTET_STRING)?;
+    // The signature covers the signed attributes encoded as a SET OF.
+    VerifyingKey::<Sha256>::new(public_key(signer)?)
+        .verify(
+            &tlv(TAG_SET, signed_attributes),
+            &pkcs1v15::Signature::try_from(signature)?,
+        )
+        .context("The signature was not created by the key of its signer")?;
+
+    let trusted_der = trusted.to_der()?;
+    let chain = signed_data.chain(signer);
+    for (index, certificate) in chain.iter().enumerate() {
+        if certificate.to_der()? == trusted_der
+            || (certificate.tbs_certificate.issuer == trusted.tbs_certificate.subject
+                && verify_certificate(certificate, trusted).is_ok())
+        {
+            return Ok(());
+        }
+        if let Some(issuer) = chain.get(index + 1) {
+            verify_certificate(certificate, issuer)?;
+        }
+    }
+    anyhow::bail!(
+        "The signer does not chain to {}",
+        trusted.tbs_certificate.subject
+    )
+}
+
+/// Check that `certificate` was signed by the key of `issuer`.
+fn verify_certificate(certificate: &Certificate, issuer: &Certificate) -> Result<()> {
+    if certificate.signature_algorithm.oid
+        != ObjectIdentifier::new_unwrap(SHA256_WITH_RSA_ENCRYPTION)
+    {
+        anyhow::bail!(
+            "Unsupported signature algorithm {} of {}",
+            certificate.signature_algorithm.oid,
+            certificate.tbs_certificate.subject
+        );
+    }
+    VerifyingKey::<Sha256>::new(public_key(issuer)?)
+        .verify(
+            &certificate.tbs_certificate.to_der()?,
+            &pkcs1v15::Signature::try_from(certificate.signature.raw_bytes())?,
+        )
+        .with_context(|| {
+            format!(
+                "{} was not issued by {}",
+                certificate.tbs_certificate.subject, issuer.tbs_certificate.subject
+            )
+        })
+}
+
+/// Check that an encoded AlgorithmIdentifier names SHA-256, the only digest that is supported.
+fn ensure_sha256(algorithm: &[u8]) -> Result<()> {
+    let (algorithm, _) = element(algorithm, TAG_SEQUENCE)?;
+    if elements(algorithm)?.first() != Some(&&oid(ID_SHA256)[..]) {
+        anyhow::bail!("Only SHA-256 digests are supported");
+    }
+    Ok(())
+}
+
+/// Split the DER element with `tag` off the start of `input`. Returns its contents and the rest
+/// of `input`.
+fn element(input: &[u8], tag: u8) -> Result<(&[u8], &[u8])> {
+    let (contents, rest) = split_element(input)?;
+    if input[0] != tag {
+        anyhow::bail!("Expected DER tag {tag:#04x}, found {:#04x}", input[0]);
+    }
+    Ok((contents, rest))
+}
+
+/// The encoded DER elements in the contents of a SEQUENCE or SET.
+fn elements(mut contents: &[u8]) -> Result<Vec<&[u8]>> {
+    let mut elements = Vec::new();
+    while !contents.is_empty() {
+        let (_, rest) = split_element(contents)?;
+        elements.push(&contents[..contents.len() - rest.len()]);
+        contents = rest;
+    }
+    Ok(elements)
+}
+
+/// Split a DER element off the start of `input` into its contents and the rest of `input`.
+fn split_element(input: &[u8]) -> Result<(&[u8], &[u8])> {
+    let [_, first, rest @ ..] = input else {
+        anyhow::bail!("Truncated DER element");
+    };
+    let (length, rest) = if *first < 0x80 {
+        (usize::from(*first), rest)
+    } else {
+        let count = usize::from(first & 0x7f);
+        if count > std::mem::size_of::<usize>() || rest.len() < count {
+            anyhow::bail!("Invalid DER length");
+        }
+        let (length, rest) = rest.split_at(count);
+        (
+            length
+                .iter()
+                .fold(0, |length, b| length << 8 | usize::from(*b)),
+            rest,
+        )
+    };
+    if rest.len() < length {
+        anyhow::bail!("Truncated DER element");
+    }
+    Ok(rest.split_at(length))
+}
+
+/// Encode a DER value from its tag and contents.
+fn tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    if contents.len() < 0x80 {
+        encoded.push(contents.len() as u8);
+    } else {
+        let length = contents.len().to_be_bytes();
+        let length = &length[length.iter().take_while(|b| **b == 0).count()..];
+        encoded.push(0x80 | length.len() as u8);
+        encoded.extend(length);
+    }
+    encoded.extend(contents);
+    encoded
+}
+
+fn sequence(elements: &[&[u8]]) -> Vec<u8> {
+    tlv(TAG_SEQUENCE, &elements.concat())
+}
+
+fn oid(oid: &str) -> Vec<u8> {
+    ObjectIdentifier::new_unwrap(oid)
+        .to_der()
+        .expect("Failed to encode object identifier")
+}
+
+fn integer_one() -> Vec<u8> {
+    tlv(TAG_INTEGER, &[1])
+}
+
+fn algorithm_identifier(algorithm: &str) -> Vec<u8> {
+    sequence(&[&oid(algorithm), &tlv(TAG_NULL, &[])])
+}
+
+fn attribute(attribute_type: &str, value: &[u8]) -> Vec<u8> {
+    sequence(&[&oid(attribute_type), &tlv(TAG_SET, value)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_long_lengths() {
+        assert_eq!(tlv(TAG_OCTET_STRING, &[7; 3]), [0x04, 0x03, 7, 7, 7]);
+
+        let encoded = tlv(TAG_OCTET_STRING, &[0; 0x1234]);
+        assert_eq!(encoded[..4], [0x04, 0x82, 0x12, 0x34]);
+        assert_eq!(encoded.len(), 4 + 0x1234);
+    }
+
+    #[test]
+    fn split_encoded_elements() -> Result<()> {
+        let long = tlv(TAG_OCTET_STRING, &[7; 0x100]);
+        let encoded = sequence(&[&integer_one(), &long]);
+        let padded = [&encoded[..], &[0, 0]].concat();
+        let (contents, rest) = element(&padded, TAG_SEQUENCE)?;
+        assert_eq!(rest, [0, 0]);
+        assert_eq!(elements(contents)?, [&integer_one()[..], &long[..]]);
+
+        assert!(element(&encoded, TAG_SET).is_err());
+        assert!(element(&encoded[..encoded.len() - 1], TAG_SEQUENCE).is_err());
+        Ok(())
+    }
+}
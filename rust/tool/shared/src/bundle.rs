@@ -0,0 +1,246 @@
+//! Bundles of the files that lzbt installs on an ESP.
+//!
+//! When many identical machines are provisioned, the contents of the ESP only need to be built
+//! and signed once. They are then exported as a bundle and applied to the ESP of every machine.
+//!
+//! A bundle is an uncompressed cpio archive in the "new ASCII" format that contains the files with
+//! paths relative to the ESP. Only regular files are stored, directories are created when the
+//! bundle is unpacked.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::initrd::{padding, CPIO_HEADER_SIZE, CPIO_TRAILER};
+
+/// Magic number of the cpio archives used for bundles ("new ASCII" format without checksums).
+const BUNDLE_MAGIC: &str = "070701";
+/// File type bits of the mode of a cpio entry.
+const S_IFMT: u32 = 0o170000;
+/// File type of regular files.
+const S_IFREG: u32 = 0o100000;
+/// Mode of the files in a bundle. The ESP is a FAT file system, which has no permissions anyway.
+const FILE_MODE: u32 = S_IFREG | 0o644;
+
+/// Writes a bundle.
+pub struct BundleWriter<W: Write> {
+    writer: W,
+    /// The inode number of the next entry. cpio uses them to detect hard links, so they must be
+    /// unique.
+    next_inode: u32,
+}
+
+impl<W: Write> BundleWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            next_inode: 1,
+        }
+    }
+
+    /// Add the file at `path` to the bundle as `name`, a path relative to the ESP.
+    pub fn add_file(&mut self, name: &Path, path: &Path) -> Result<()> {
+        let name = entry_name(name)?;
+        let mut file = File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+        let size = file
+            .metadata()
+            .with_context(|| format!("Failed to read metadata of {path:?}"))?
+            .len();
+
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.write_header(inode, FILE_MODE, size, name)?;
+        let copied = io::copy(&mut file, &mut self.writer)
+            .with_context(|| format!("Failed to add {path:?} to the bundle"))?;
+        if copied != size {
+            anyhow::bail!("{path:?} changed while it was added to the bundle");
+        }
+        self.write_padding(size)
+    }
+
+    /// Write the end of the archive and return the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.write_header(0, 0, 0, CPIO_TRAILER)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_header(&mut self, inode: u32, mode: u32, size: u64, name: &str) -> Result<()> {
+        let size = u32::try_from(size).context("Files in bundles must be smaller than 4 GiB")?;
+        // The name is NUL terminated.
+        let name_size = name.len() + 1;
+        // Inode, mode, uid, gid, nlink, mtime, file size, device major and minor, rdev major and
+        // minor, name size and checksum. The modification time is zero to make bundles
+        // reproducible.
+        let fields = [
+            inode,
+            mode,
+            0,
+            0,
+            1,
+            0,
+            size,
+            0,
+            0,
+            0,
+            0,
+            u32::try_from(name_size)?,
+            0,
+        ];
+        let header = fields
+            .iter()
+            .map(|field| format!("{field:08x}"))
+            .collect::<String>();
+        write!(self.writer, "{BUNDLE_MAGIC}{header}{name}\0")?;
+        self.write_padding((CPIO_HEADER_SIZE + name_size) as u64)
+    }
+
+    fn write_padding(&mut self, size: u64) -> Result<()> {
+        self.writer
+            .write_all(&[0; 3][..padding(size) as usize])
+            .context("Failed to write bundle")
+    }
+}
+
+/// Unpack a bundle into `dir` and return the paths of the unpacked files relative to `dir`.
+///
+/// Paths that would end up outside of `dir`, duplicate paths and anything but regular files are
+/// rejected.
+pub fn unpack_bundle(mut reader: impl Read, dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut seen = HashSet::new();
+    loop {
+        let mut header = [0; CPIO_HEADER_SIZE];
+        reader
+            .read_exact(&mut header)
+            .context("Unexpected end of bundle")?;
+        if !header.starts_with(BUNDLE_MAGIC.as_bytes()) {
+            anyhow::bail!("Invalid bundle: unknown cpio header");
+        }
+        // The header consists of 8 digit hexadecimal fields after the magic number.
+        let field = |index: usize| -> Result<u64> {
+            let start = BUNDLE_MAGIC.len() + index * 8;
+            let digits = std::str::from_utf8(&header[start..start + 8])?;
+            Ok(u64::from_str_radix(digits, 16)?)
+        };
+        let mode = u32::try_from(field(1)?)?;
+        let file_size = field(6)?;
+        let name_size = field(11)?;
+
+        let mut name = vec![0; usize::try_from(name_size)?];
+        reader
+            .read_exact(&mut name)
+            .context("Unexpected end of bundle")?;
+        let name = name
+            .strip_suffix(&[0])
+            .context("Invalid bundle: file name is not NUL terminated")?;
+        let name =
+            String::from_utf8(name.to_vec()).context("Invalid bundle: file name is not UTF-8")?;
+        skip(&mut reader, padding(CPIO_HEADER_SIZE as u64 + name_size))?;
+
+        if name == CPIO_TRAILER {
+            return Ok(files);
+        }
+        if mode & S_IFMT != S_IFREG {
+            anyhow::bail!("Invalid bundle: {name} is not a regular file");
+        }
+        let relative = PathBuf::from(entry_name(Path::new(&name))?);
+        if !seen.insert(relative.clone()) {
+            anyhow::bail!("Invalid bundle: {name} is contained more than once");
+        }
+
+        let path = dir.join(&relative);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {parent:?}"))?;
+        }
+        let mut file = File::create(&path).with_context(|| format!("Failed to create {path:?}"))?;
+        let copied = io::copy(&mut (&mut reader).take(file_size), &mut file)
+            .with_context(|| format!("Failed to write {path:?}"))?;
+        if copied != file_size {
+            anyhow::bail!("Unexpected end of bundle");
+        }
+        skip(&mut reader, padding(file_size))?;
+        files.push(relative);
+    }
+}
+
+/// Check that `name` is a relative path without `.` or `..` components and convert it to the name
+/// of a cpio entry.
+fn entry_name(name: &Path) -> Result<&str> {
+    let valid = name.components().next().is_some()
+        && name
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if !valid {
+        anyhow::bail!("Invalid path in bundle: {name:?}");
+    }
+    name.to_str()
+        .with_context(|| format!("Invalid path in bundle: {name:?} is not UTF-8"))
+}
+
+fn skip(reader: &mut impl Read, size: u64) -> Result<()> {
+    let skipped = io::copy(&mut reader.take(size), &mut io::sink())?;
+    if skipped != size {
+        anyhow::bail!("Unexpected end of bundle");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let source = TempDir::new()?;
+        fs::write(source.path().join("a"), "loader")?;
+        fs::write(source.path().join("b"), "")?;
+
+        let mut writer = BundleWriter::new(Vec::new());
+        writer.add_file(Path::new("loader/loader.conf"), &source.path().join("a"))?;
+        writer.add_file(Path::new("EFI/Linux/empty.efi"), &source.path().join("b"))?;
+        let bundle = writer.finish()?;
+        assert_eq!(bundle.len() % 4, 0);
+
+        let target = TempDir::new()?;
+        let files = unpack_bundle(bundle.as_slice(), target.path())?;
+        assert_eq!(
+            files,
+            [
+                PathBuf::from("loader/loader.conf"),
+                PathBuf::from("EFI/Linux/empty.efi")
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(target.path().join("loader/loader.conf"))?,
+            "loader"
+        );
+        assert_eq!(fs::read(target.path().join("EFI/Linux/empty.efi"))?, b"");
+        Ok(())
+    }
+
+    #[test]
+    fn reject_paths_outside_of_the_esp() -> Result<()> {
+        let source = TempDir::new()?;
+        fs::write(source.path().join("a"), "")?;
+        let mut writer = BundleWriter::new(Vec::new());
+        for name in ["../escape", "/etc/passwd", "EFI/../..", ""] {
+            assert!(writer
+                .add_file(Path::new(name), &source.path().join("a"))
+                .is_err());
+        }
+
+        // A bundle written by someone else is not trusted either.
+        let mut bundle = Vec::new();
+        BundleWriter::new(&mut bundle).write_header(1, FILE_MODE, 0, "../escape")?;
+        let error = unpack_bundle(bundle.as_slice(), source.path()).unwrap_err();
+        assert_eq!(error.to_string(), "Invalid path in bundle: \"../escape\"");
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+//! A cache of intermediate files that survives between installations.
+//!
+//! Some steps of an installation are expensive, but always produce the same output for the same
+//! input, e.g. compressing an initrd. Their outputs are stored in a directory under a key that is
+//! derived from everything that goes into them. For large fleets, the entries can be compressed
+//! to save space in the cache directory.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::compression::Compression;
+use crate::utils::tmpname;
+
+/// A directory of cached files, each compressed with the same compression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cache {
+    dir: PathBuf,
+    compression: Compression,
+}
+
+impl Cache {
+    /// Use `dir` as cache, creating it if it does not exist.
+    pub fn new(dir: &Path, compression: Compression) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create cache {dir:?}"))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            compression,
+        })
+    }
+
+    /// The path of the entry for `key`.
+    ///
+    /// The compression is part of the name, so that entries written with another compression are
+    /// never decompressed incorrectly.
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.{}", self.compression))
+    }
+
+    /// Write the cached contents for `key` to `writer`.
+    ///
+    /// Returns false without writing anything if there is no entry for `key`.
+    pub fn get(&self, key: &str, writer: impl Write) -> Result<bool> {
+        let path = self.entry_path(key);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e).with_context(|| format!("Failed to open {path:?}")),
+        };
+        self.compression
+            .decompress(file, writer)
+            .with_context(|| format!("Failed to read cache entry {path:?}"))?;
+        Ok(true)
+    }
+
+    /// Store everything from `reader` as entry for `key`, replacing an existing entry.
+    ///
+    /// The entry is written to a temporary file first, so that an interrupted write never leaves
+    /// a truncated entry behind.
+    pub fn put(&self, key: &str, reader: impl Read) -> Result<()> {
+        let path = self.entry_path(key);
+        let tmp_path = self.dir.join(tmpname());
+        let write = || -> Result<()> {
+            let file = File::create(&tmp_path)
+                .with_context(|| format!("Failed to create {tmp_path:?}"))?;
+            self.compression
+                .compress(reader, &file)
+                .with_context(|| format!("Failed to write cache entry {path:?}"))?;
+            file.sync_all()
+                .with_context(|| format!("Failed to sync {tmp_path:?}"))?;
+            fs::rename(&tmp_path, &path)
+                .with_context(|| format!("Failed to move {tmp_path:?} to {path:?}"))
+        };
+        write().map_err(|err| {
+            let _ = fs::remove_file(&tmp_path);
+            err
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    fn contents() -> Vec<u8> {
+        (0..100_000).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn round_trip_entries() -> Result<()> {
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let dir = tempdir()?;
+            let cache = Cache::new(&dir.path().join("cache"), compression)?;
+
+            let mut missing = Vec::new();
+            assert!(!cache.get("initrd-zstd-abc", &mut missing)?);
+            assert!(missing.is_empty());
+
+            cache.put("initrd-zstd-abc", &contents()[..])?;
+            let mut cached = Vec::new();
+            assert!(cache.get("initrd-zstd-abc", &mut cached)?);
+            assert_eq!(cached, contents(), "{compression}");
+
+            let stored = fs::metadata(cache.entry_path("initrd-zstd-abc"))?.len();
+            if compression != Compression::None {
+                assert!(stored < contents().len() as u64, "{compression}");
+            }
+            // Only the entry remains, the temporary file was renamed.
+            assert_eq!(fs::read_dir(dir.path().join("cache"))?.count(), 1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_entries_with_other_compression() -> Result<()> {
+        let dir = tempdir()?;
+        Cache::new(dir.path(), Compression::Gzip)?.put("key", &contents()[..])?;
+
+        let cache = Cache::new(dir.path(), Compression::Zstd)?;
+        assert!(!cache.get("key", io::sink())?);
+
+        cache.put("key", &b"new"[..])?;
+        let mut cached = Vec::new();
+        assert!(cache.get("key", &mut cached)?);
+        assert_eq!(cached, b"new");
+        Ok(())
+    }
+}
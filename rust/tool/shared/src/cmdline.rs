@@ -0,0 +1,301 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+
+/// The sections that contain the command lines of the boot menu of the stub, by index.
+///
+/// Index 0 is the default command line. The names of the other sections are abbreviated because
+/// section names of images are limited to 8 characters.
+pub const CMDLINE_SECTIONS: [&str; 10] = [
+    ".cmdline", ".cmdl1", ".cmdl2", ".cmdl3", ".cmdl4", ".cmdl5", ".cmdl6", ".cmdl7", ".cmdl8",
+    ".cmdl9",
+];
+
+/// How many command line variants a UKI can contain besides the default command line.
+pub const MAX_CMDLINE_VARIANTS: usize = CMDLINE_SECTIONS.len() - 1;
+
+/// The label of the default command line in the boot menu of the stub.
+pub const DEFAULT_CMDLINE_LABEL: &str = "Default";
+
+/// An additional command line the stub offers in its boot menu.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CmdlineVariant {
+    /// What the menu shows for this variant.
+    pub label: String,
+    /// Parameters appended to the command line of the generation.
+    pub extra_params: String,
+}
+
+impl FromStr for CmdlineVariant {
+    type Err = anyhow::Error;
+
+    /// Parse a variant given as `LABEL=PARAMS`, e.g. `Debug=loglevel=7`.
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((label, extra_params)) = s.split_once('=') else {
+            anyhow::bail!("Expected LABEL=PARAMS, got {s}");
+        };
+        if label.is_empty() || label.contains('\n') {
+            anyhow::bail!("The label of a command line variant must be a non-empty single line");
+        }
+        Ok(Self {
+            label: label.to_string(),
+            extra_params: extra_params.to_string(),
+        })
+    }
+}
+
+/// Assemble the kernel command line of a generation.
+///
+/// The command line starts with the `init=` parameter pointing at the generation's init followed
+/// by the kernel parameters from the bootspec in their original order.
+pub fn assemble_kernel_cmdline(init: &Path, kernel_params: Vec<String>) -> Vec<String> {
+    let init_string = String::from(
+        init.to_str()
+            .expect("Failed to convert init path to string"),
+    );
+    let mut kernel_cmdline: Vec<String> = vec![format!("init={}", init_string)];
+    kernel_cmdline.extend(kernel_params);
+    kernel_cmdline
+}
+
+/// Append `common_params` to the command line of a generation.
+///
+/// Parameters whose key the generation already sets are skipped, so the generation takes
+/// precedence: with `loglevel=7` in the generation, a common `loglevel=4` is not added.
+pub fn merge_common_params(mut cmdline: Vec<String>, common_params: &[String]) -> Vec<String> {
+    let common_params = common_params
+        .iter()
+        .filter(|common| {
+            !cmdline
+                .iter()
+                .any(|param| param_key(param) == param_key(common))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    cmdline.extend(common_params);
+    cmdline
+}
+
+/// The key of a parameter, i.e. everything before the first `=`.
+fn param_key(param: &str) -> &str {
+    param.split_once('=').map_or(param, |(key, _)| key)
+}
+
+/// The kernel command line parameters that may be embedded in a UKI.
+///
+/// Every line of an allowlist file permits one parameter:
+///
+/// - `quiet` permits `quiet` and `quiet=` with any value.
+/// - `loglevel=4` only permits exactly `loglevel=4`.
+/// - `init=/nix/store/*` permits every value that starts with `/nix/store/`.
+///
+/// Empty lines and lines starting with `#` are ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CmdlineAllowlist {
+    entries: Vec<AllowlistEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AllowlistEntry {
+    AnyValue(String),
+    Exact(String),
+    Prefix(String),
+}
+
+impl AllowlistEntry {
+    fn permits(&self, param: &str) -> bool {
+        match self {
+            Self::AnyValue(key) => param_key(param) == key,
+            Self::Exact(exact) => param == exact,
+            Self::Prefix(prefix) => param.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+impl CmdlineAllowlist {
+    /// Read an allowlist file.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the command line allowlist {path:?}"))?;
+        contents
+            .parse()
+            .with_context(|| format!("Failed to parse the command line allowlist {path:?}"))
+    }
+
+    /// Fail unless every parameter of `cmdline` is permitted.
+    pub fn check(&self, cmdline: &[String]) -> Result<()> {
+        let disallowed = cmdline
+            .iter()
+            .flat_map(|params| params.split_whitespace())
+            .filter(|param| !self.entries.iter().any(|entry| entry.permits(param)))
+            .map(redact_param)
+            .collect::<Vec<_>>();
+        if !disallowed.is_empty() {
+            anyhow::bail!(
+                "The command line contains parameters that are not in the allowlist: {}",
+                disallowed.join(" ")
+            );
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for CmdlineAllowlist {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let entries = s
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                if line.split_whitespace().nth(1).is_some() {
+                    anyhow::bail!("Expected a single parameter per line, got {line:?}");
+                }
+                Ok(match (line.strip_suffix('*'), line.contains('=')) {
+                    (Some(prefix), true) => AllowlistEntry::Prefix(prefix.to_string()),
+                    (_, true) => AllowlistEntry::Exact(line.to_string()),
+                    (_, false) => AllowlistEntry::AnyValue(line.to_string()),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { entries })
+    }
+}
+
+/// Parameters whose values are masked by [`redact_cmdline`].
+///
+/// The stub has the same list for its diagnostics.
+const SENSITIVE_PARAMS: [&str; 2] = ["rd.luks.key", "luks.key"];
+
+/// Parameters that pass systemd credentials as `NAME:VALUE`. Only the value is masked.
+const CREDENTIAL_PARAMS: [&str; 2] = ["systemd.set_credential", "systemd.set_credential_binary"];
+
+/// What masked values are replaced with.
+const REDACTED: &str = "***";
+
+/// Mask the value of a parameter if it may contain a secret.
+pub fn redact_param(param: &str) -> String {
+    let Some((key, value)) = param.split_once('=') else {
+        return param.to_string();
+    };
+    if SENSITIVE_PARAMS.contains(&key) {
+        format!("{key}={REDACTED}")
+    } else if CREDENTIAL_PARAMS.contains(&key) {
+        match value.split_once(':') {
+            Some((name, _)) => format!("{key}={name}:{REDACTED}"),
+            None => format!("{key}={REDACTED}"),
+        }
+    } else {
+        param.to_string()
+    }
+}
+
+/// Mask the secrets in a command line so that it can be logged or shown.
+///
+/// This is only for display, the command line embedded in a UKI is never redacted.
+pub fn redact_cmdline(cmdline: &str) -> String {
+    cmdline
+        .split_whitespace()
+        .map(redact_param)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn init_comes_first() {
+        let cmdline = assemble_kernel_cmdline(
+            Path::new("/nix/store/init"),
+            vec!["quiet".into(), "loglevel=4".into()],
+        );
+        assert_eq!(cmdline, ["init=/nix/store/init", "quiet", "loglevel=4"]);
+    }
+
+    #[test]
+    fn merge_common_params_after_generation_params() {
+        let cmdline = merge_common_params(
+            vec!["init=/init".into(), "loglevel=7".into(), "quiet".into()],
+            &[
+                "console=ttyS0".into(),
+                "loglevel=4".into(),
+                "quiet".into(),
+                "init=/bin/sh".into(),
+                "console=tty0".into(),
+            ],
+        );
+        assert_eq!(
+            cmdline,
+            [
+                "init=/init",
+                "loglevel=7",
+                "quiet",
+                "console=ttyS0",
+                "console=tty0"
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_cmdline_variant() {
+        assert_eq!(
+            "Debug=loglevel=7 debug".parse::<CmdlineVariant>().unwrap(),
+            CmdlineVariant {
+                label: "Debug".into(),
+                extra_params: "loglevel=7 debug".into(),
+            }
+        );
+        assert!("Debug".parse::<CmdlineVariant>().is_err());
+        assert!("=single".parse::<CmdlineVariant>().is_err());
+    }
+
+    #[test]
+    fn redact_secrets() {
+        assert_eq!(
+            redact_cmdline(
+                "init=/init rd.luks.key=/keyfile:UUID=1234 quiet \
+                 systemd.set_credential=passwd.hashed-password.root:$y$secret \
+                 systemd.set_credential_binary=token luks.key=/other loglevel=4"
+            ),
+            "init=/init rd.luks.key=*** quiet \
+             systemd.set_credential=passwd.hashed-password.root:*** \
+             systemd.set_credential_binary=*** luks.key=*** loglevel=4"
+        );
+    }
+
+    #[test]
+    fn check_cmdline_against_allowlist() {
+        let allowlist: CmdlineAllowlist = "# Comment\n\ninit=/nix/store/*\nquiet\nloglevel=4\n"
+            .parse()
+            .unwrap();
+        let cmdline = |params: &[&str]| params.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+
+        assert!(allowlist
+            .check(&cmdline(&["init=/nix/store/init", "quiet=1", "loglevel=4"]))
+            .is_ok());
+        let error = allowlist
+            .check(&cmdline(&[
+                "init=/bin/sh",
+                "loglevel=7",
+                "rd.luks.key=/secret",
+            ]))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The command line contains parameters that are not in the allowlist: init=/bin/sh \
+             loglevel=7 rd.luks.key=***"
+        );
+        assert!("quiet loglevel=4".parse::<CmdlineAllowlist>().is_err());
+    }
+
+    #[test]
+    fn leave_other_params_intact() {
+        let cmdline = "init=/init rd.luks.uuid=1234 luks.keyfoo=bar quiet";
+        assert_eq!(redact_cmdline(cmdline), cmdline);
+    }
+}
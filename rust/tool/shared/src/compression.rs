@@ -0,0 +1,204 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+
+/// Magic numbers of the compression formats the kernel can unpack an initrd from.
+const COMPRESSION_MAGICS: [&[u8]; 7] = [
+    // gzip
+    &[0x1f, 0x8b],
+    // zstd
+    &[0x28, 0xb5, 0x2f, 0xfd],
+    // xz
+    &[0xfd, b'7', b'z', b'X', b'Z', 0x00],
+    // lz4 (legacy format used by the kernel)
+    &[0x02, 0x21, 0x4c, 0x18],
+    // bzip2
+    b"BZh",
+    // lzma
+    &[0x5d, 0x00, 0x00],
+    // lzop
+    &[0x89, b'L', b'Z', b'O'],
+];
+
+/// The largest uncompressed initrd that is installed, in bytes.
+///
+/// An uncompressed initrd is stored on the FAT-formatted ESP, whose files cannot be larger than
+/// 4 GiB - 1 bytes. Compressing it must not be a way around this limit: the stub does not
+/// decompress the initrd, the kernel unpacks it into memory early during boot.
+pub const MAX_INITRD_SIZE: u64 = u32::MAX as u64;
+
+/// Compression applied to an initrd before it is installed.
+///
+/// The kernel recognizes compressed initrds by their magic number and unpacks them itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Self::None),
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            _ => anyhow::bail!("Unknown compression {s}, expected one of none, gzip or zstd"),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        })
+    }
+}
+
+impl Compression {
+    /// Compress everything from `reader` into `writer`.
+    pub fn compress(&self, mut reader: impl Read, mut writer: impl Write) -> io::Result<()> {
+        match self {
+            Self::None => {
+                io::copy(&mut reader, &mut writer)?;
+            }
+            Self::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(writer, flate2::Compression::best());
+                io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()?;
+            }
+            Self::Zstd => {
+                // This is the level used by NixOS for initrds.
+                let mut encoder = zstd::Encoder::new(writer, 19)?;
+                io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decompress everything from `reader`, which was compressed with [`Compression::compress`],
+    /// into `writer`.
+    pub fn decompress(&self, mut reader: impl Read, mut writer: impl Write) -> io::Result<()> {
+        match self {
+            Self::None => io::copy(&mut reader, &mut writer)?,
+            Self::Gzip => io::copy(&mut flate2::read::GzDecoder::new(reader), &mut writer)?,
+            Self::Zstd => io::copy(&mut zstd::Decoder::new(reader)?, &mut writer)?,
+        };
+        Ok(())
+    }
+}
+
+/// Check that the initrd at `path` is not larger than [`MAX_INITRD_SIZE`].
+pub fn check_initrd_size(path: &Path) -> Result<()> {
+    let size = path
+        .metadata()
+        .with_context(|| format!("Failed to read the metadata of {path:?}"))?
+        .len();
+    if size > MAX_INITRD_SIZE {
+        anyhow::bail!(
+            "The initrd {path:?} has {size} bytes, but at most {MAX_INITRD_SIZE} are supported"
+        );
+    }
+    Ok(())
+}
+
+/// Check whether a file starts with the magic number of a compression format.
+///
+/// The kernel only unpacks one level of compression, so already compressed initrds must not be
+/// compressed again.
+pub fn is_compressed(path: &Path) -> Result<bool> {
+    let mut header = Vec::with_capacity(6);
+    File::open(path)
+        .with_context(|| format!("Failed to open {path:?}"))?
+        .take(6)
+        .read_to_end(&mut header)
+        .with_context(|| format!("Failed to read {path:?}"))?;
+    Ok(COMPRESSION_MAGICS
+        .iter()
+        .any(|magic| header.starts_with(magic)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    fn initrd() -> Vec<u8> {
+        // An uncompressed initrd is a cpio archive.
+        let mut initrd = b"070701".to_vec();
+        initrd.extend((0..100_000).map(|i| (i % 251) as u8));
+        initrd
+    }
+
+    fn round_trip(compression: Compression, decompress: impl FnOnce(&[u8]) -> Vec<u8>) {
+        let dir = tempdir().unwrap();
+        let compressed = dir.path().join("initrd");
+        let mut compressed_data = Vec::new();
+
+        compression
+            .compress(&initrd()[..], &mut compressed_data)
+            .unwrap();
+        std::fs::write(&compressed, &compressed_data).unwrap();
+
+        assert!(compressed_data.len() < initrd().len());
+        assert!(is_compressed(&compressed).unwrap());
+        assert_eq!(decompress(&compressed_data), initrd());
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        round_trip(Compression::Gzip, |data| {
+            let mut decompressed = Vec::new();
+            flate2::read::GzDecoder::new(data)
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            decompressed
+        });
+    }
+
+    #[test]
+    fn zstd_round_trip() {
+        round_trip(Compression::Zstd, |data| zstd::decode_all(data).unwrap());
+    }
+
+    #[test]
+    fn detect_uncompressed_initrd() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("initrd");
+        std::fs::write(&path, initrd()).unwrap();
+
+        assert!(!is_compressed(&path).unwrap());
+    }
+
+    #[test]
+    fn check_initrd_size_limit() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("initrd");
+        // Sparse files, so that the test does not need 4 GiB of disk space.
+        let file = File::create(&path).unwrap();
+
+        file.set_len(MAX_INITRD_SIZE).unwrap();
+        assert!(check_initrd_size(&path).is_ok());
+        file.set_len(MAX_INITRD_SIZE + 1).unwrap();
+        assert!(check_initrd_size(&path).is_err());
+    }
+
+    #[test]
+    fn parse_compression() {
+        assert_eq!("zstd".parse::<Compression>().unwrap(), Compression::Zstd);
+        assert!("xz".parse::<Compression>().is_err());
+    }
+}
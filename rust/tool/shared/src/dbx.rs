@@ -0,0 +1,153 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::utils::{explain_io_error, Hash};
+
+/// The path of the dbx variable in efivarfs.
+pub const EFIVARFS_DBX: &str = "/sys/firmware/efi/efivars/dbx-d719b2cb-3d3a-4596-a3bc-dad00e67656f";
+
+/// `EFI_CERT_SHA256_GUID` in its on-disk (mixed-endian) representation.
+const EFI_CERT_SHA256_GUID: [u8; 16] = [
+    0x26, 0x16, 0xc4, 0xc1, 0x4c, 0x50, 0x92, 0x40, 0xac, 0xa9, 0x41, 0xf9, 0x36, 0x93, 0x43, 0x28,
+];
+
+/// Size of the fixed part of an `EFI_SIGNATURE_LIST`.
+const SIGNATURE_LIST_HEADER_SIZE: usize = 16 + 3 * 4;
+
+/// Size of the owner GUID that precedes every `EFI_SIGNATURE_DATA` entry.
+const SIGNATURE_OWNER_SIZE: usize = 16;
+
+/// The SHA 256 hashes contained in a signature database such as dbx.
+///
+/// A signature database is a sequence of `EFI_SIGNATURE_LIST`s as defined in the UEFI
+/// specification. Lists of other signature types (e.g. X.509 certificates) are skipped, because
+/// only hashes can be compared against the Authenticode digest of a binary.
+#[derive(Debug, Default)]
+pub struct SignatureDatabase {
+    sha256_hashes: Vec<[u8; 32]>,
+}
+
+impl SignatureDatabase {
+    /// Parse a signature database from the raw contents of the variable.
+    pub fn parse(mut data: &[u8]) -> Result<Self> {
+        let mut sha256_hashes = Vec::new();
+
+        while !data.is_empty() {
+            if data.len() < SIGNATURE_LIST_HEADER_SIZE {
+                bail!("Truncated EFI signature list header");
+            }
+            let signature_type = &data[0..16];
+            let list_size = read_u32(data, 16) as usize;
+            let header_size = read_u32(data, 20) as usize;
+            let signature_size = read_u32(data, 24) as usize;
+
+            if list_size < SIGNATURE_LIST_HEADER_SIZE + header_size || list_size > data.len() {
+                bail!("Invalid EFI signature list size: {list_size}");
+            }
+            let signatures = &data[SIGNATURE_LIST_HEADER_SIZE + header_size..list_size];
+
+            if signature_type == EFI_CERT_SHA256_GUID {
+                if signature_size != SIGNATURE_OWNER_SIZE + 32 {
+                    bail!("Invalid size of SHA 256 signature: {signature_size}");
+                }
+                for signature in signatures.chunks_exact(signature_size) {
+                    let hash = signature[SIGNATURE_OWNER_SIZE..]
+                        .try_into()
+                        .expect("Signature size was checked above");
+                    sha256_hashes.push(hash);
+                }
+            }
+
+            data = &data[list_size..];
+        }
+
+        Ok(Self { sha256_hashes })
+    }
+
+    /// Read a signature database from efivarfs.
+    ///
+    /// Variables in efivarfs are prefixed with their 4 byte attributes which are skipped.
+    pub fn from_efivarfs(path: &Path) -> Result<Self> {
+        let data = fs::read(path).map_err(|e| explain_io_error(e, "read", path))?;
+        let contents = data
+            .get(4..)
+            .with_context(|| format!("EFI variable {path:?} is too short"))?;
+        Self::parse(contents)
+            .with_context(|| format!("Failed to parse signature database {path:?}"))
+    }
+}
+
+/// Check whether an Authenticode digest is revoked by the given dbx.
+pub fn is_revoked(hash: &Hash, dbx: &SignatureDatabase) -> bool {
+    dbx.sha256_hashes.iter().any(|h| h == hash.as_slice())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    /// `EFI_CERT_X509_GUID` in its on-disk representation.
+    const EFI_CERT_X509_GUID: [u8; 16] = [
+        0xa1, 0x59, 0xc0, 0xa5, 0xe4, 0x94, 0xa7, 0x4a, 0x87, 0xb5, 0xab, 0x15, 0x5c, 0x2b, 0xf0,
+        0x72,
+    ];
+
+    fn signature_list(signature_type: [u8; 16], signatures: &[&[u8]]) -> Vec<u8> {
+        let signature_size = SIGNATURE_OWNER_SIZE + signatures.first().map_or(0, |s| s.len());
+        let list_size = SIGNATURE_LIST_HEADER_SIZE + signatures.len() * signature_size;
+
+        let mut list = Vec::new();
+        list.extend_from_slice(&signature_type);
+        list.extend_from_slice(&(list_size as u32).to_le_bytes());
+        list.extend_from_slice(&0u32.to_le_bytes());
+        list.extend_from_slice(&(signature_size as u32).to_le_bytes());
+        for signature in signatures {
+            list.extend_from_slice(&[0x42; SIGNATURE_OWNER_SIZE]);
+            list.extend_from_slice(signature);
+        }
+        list
+    }
+
+    #[test]
+    fn detect_revoked_hash() -> Result<()> {
+        let stub_hash = Sha256::digest(b"stub");
+        let other_hash = Sha256::digest(b"other");
+
+        let mut data = signature_list(EFI_CERT_X509_GUID, &[b"not a hash"]);
+        data.extend(signature_list(
+            EFI_CERT_SHA256_GUID,
+            &[&other_hash, &stub_hash],
+        ));
+        let dbx = SignatureDatabase::parse(&data)?;
+
+        assert!(is_revoked(&stub_hash, &dbx));
+        Ok(())
+    }
+
+    #[test]
+    fn accept_hash_not_in_dbx() -> Result<()> {
+        let stub_hash = Sha256::digest(b"stub");
+        let other_hash = Sha256::digest(b"other");
+
+        let data = signature_list(EFI_CERT_SHA256_GUID, &[&other_hash]);
+        let dbx = SignatureDatabase::parse(&data)?;
+
+        assert!(!is_revoked(&stub_hash, &dbx));
+        assert!(!is_revoked(&stub_hash, &SignatureDatabase::default()));
+        Ok(())
+    }
+
+    #[test]
+    fn reject_truncated_signature_list() {
+        let other_hash = Sha256::digest(b"other");
+        let data = signature_list(EFI_CERT_SHA256_GUID, &[&other_hash]);
+        assert!(SignatureDatabase::parse(&data[..data.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+//! Build metadata for A/B update schemes.
+//!
+//! The `.deploy` section tells the stub which slot a UKI was deployed to and which version it
+//! contains, so that an update agent and boot counting can agree on what was booted.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::Result;
+use serde_json::json;
+
+/// One of the two slots of an A/B update scheme.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl FromStr for Slot {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "a" => Ok(Self::A),
+            "b" => Ok(Self::B),
+            _ => anyhow::bail!("Unknown slot {s}, expected a or b"),
+        }
+    }
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::A => "a",
+            Self::B => "b",
+        })
+    }
+}
+
+/// The contents of the `.deploy` section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeployInfo {
+    pub slot: Slot,
+    /// The version of the deployed image, as understood by the update agent.
+    pub version: String,
+}
+
+impl DeployInfo {
+    pub fn new(slot: Slot, version: impl Into<String>) -> Self {
+        Self {
+            slot,
+            version: version.into(),
+        }
+    }
+
+    /// Serialize to the JSON object that is embedded in the `.deploy` section.
+    ///
+    /// The stub ignores unknown keys, so keys can be added without breaking older stubs.
+    pub fn to_json(&self) -> String {
+        json!({
+            "slot": self.slot.to_string(),
+            "version": self.version,
+        })
+        .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_slot() {
+        assert_eq!("a".parse::<Slot>().unwrap(), Slot::A);
+        assert_eq!("b".parse::<Slot>().unwrap(), Slot::B);
+        assert!("c".parse::<Slot>().is_err());
+    }
+
+    #[test]
+    fn serialize_deploy_info() {
+        let json: serde_json::Value =
+            serde_json::from_str(&DeployInfo::new(Slot::B, "2024.1 \"beta\"").to_json()).unwrap();
+
+        assert_eq!(json["slot"], "b");
+        assert_eq!(json["version"], "2024.1 \"beta\"");
+    }
+}
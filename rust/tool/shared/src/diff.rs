@@ -0,0 +1,287 @@
+use std::fmt;
+
+use anyhow::{Context, Result};
+use goblin::pe::PE;
+use sha2::{Digest, Sha256};
+
+use crate::cmdline::redact_param;
+use crate::pe::read_section_data;
+use crate::utils::Hash;
+
+/// Sections whose contents are shown as text when they differ.
+///
+/// The command line is split into its parameters, everything else into lines. `.linux` and
+/// `.initrd` are the paths of the kernel and initrd in lanzaboote images. Other UKIs embed the
+/// files themselves, which are too large for a text diff, see [`MAX_TEXT_DIFF_SIZE`].
+const TEXT_SECTIONS: [&str; 5] = [".cmdline", ".osrel", ".uname", ".linux", ".initrd"];
+
+/// Sections larger than this are only compared by their hashes.
+///
+/// The line diff takes time and memory proportional to the product of the number of lines.
+const MAX_TEXT_DIFF_SIZE: usize = 64 * 1024;
+
+/// Size and hash of the contents of a section.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SectionSummary {
+    pub size: usize,
+    pub hash: Hash,
+}
+
+impl SectionSummary {
+    pub(crate) fn new(data: &[u8]) -> Self {
+        Self {
+            size: data.len(),
+            hash: Sha256::digest(data),
+        }
+    }
+}
+
+impl fmt::Display for SectionSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes, sha256 {:x}", self.size, self.hash)
+    }
+}
+
+/// A line (or command line parameter) of a textual diff.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DiffLine {
+    Removed(String),
+    Added(String),
+}
+
+impl fmt::Display for DiffLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Removed(line) => write!(f, "- {line}"),
+            Self::Added(line) => write!(f, "+ {line}"),
+        }
+    }
+}
+
+/// How a section differs between two PE binaries.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SectionDiff {
+    Added(SectionSummary),
+    Removed(SectionSummary),
+    Changed {
+        old: SectionSummary,
+        new: SectionSummary,
+        /// Only available for text sections.
+        text: Option<Vec<DiffLine>>,
+    },
+}
+
+/// The differences between the sections of two PE binaries, in the order of the sections in the
+/// first binary followed by sections only present in the second one.
+#[derive(Debug, PartialEq, Eq)]
+pub struct UkiDiff(pub Vec<(String, SectionDiff)>);
+
+impl UkiDiff {
+    pub fn new(old: &[u8], new: &[u8]) -> Result<Self> {
+        let old_names = section_names(old).context("Failed to parse first PE binary")?;
+        let new_names = section_names(new).context("Failed to parse second PE binary")?;
+
+        let mut names = old_names.clone();
+        names.extend(new_names.into_iter().filter(|n| !old_names.contains(n)));
+
+        let diffs = names
+            .into_iter()
+            .filter_map(|name| {
+                let diff = section_diff(
+                    &name,
+                    read_section_data(old, &name),
+                    read_section_data(new, &name),
+                )?;
+                Some((name, diff))
+            })
+            .collect();
+
+        Ok(Self(diffs))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The name of the first section that differs, in the order of [`Self::new`].
+    pub fn first_section(&self) -> Option<&str> {
+        self.0.first().map(|(name, _)| name.as_str())
+    }
+}
+
+impl fmt::Display for UkiDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, diff) in &self.0 {
+            match diff {
+                SectionDiff::Added(new) => writeln!(f, "{name}: added ({new})")?,
+                SectionDiff::Removed(old) => writeln!(f, "{name}: removed ({old})")?,
+                SectionDiff::Changed { old, new, text } => {
+                    writeln!(f, "{name}: changed ({old} -> {new})")?;
+                    for line in text.iter().flatten() {
+                        writeln!(f, "  {line}")?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn section_names(file_data: &[u8]) -> Result<Vec<String>> {
+    let pe = PE::parse(file_data)?;
+    pe.sections
+        .iter()
+        .map(|s| Ok(s.name()?.to_string()))
+        .collect()
+}
+
+fn section_diff(name: &str, old: Option<&[u8]>, new: Option<&[u8]>) -> Option<SectionDiff> {
+    match (old, new) {
+        (Some(old), Some(new)) if old == new => None,
+        (Some(old), Some(new)) => Some(SectionDiff::Changed {
+            old: SectionSummary::new(old),
+            new: SectionSummary::new(new),
+            text: text_diff(name, old, new),
+        }),
+        (Some(old), None) => Some(SectionDiff::Removed(SectionSummary::new(old))),
+        (None, Some(new)) => Some(SectionDiff::Added(SectionSummary::new(new))),
+        (None, None) => None,
+    }
+}
+
+fn text_diff(name: &str, old: &[u8], new: &[u8]) -> Option<Vec<DiffLine>> {
+    if !TEXT_SECTIONS.contains(&name)
+        || old.len() > MAX_TEXT_DIFF_SIZE
+        || new.len() > MAX_TEXT_DIFF_SIZE
+    {
+        return None;
+    }
+    // Some sections are NUL terminated.
+    let old = std::str::from_utf8(old).ok()?.trim_end_matches('\0');
+    let new = std::str::from_utf8(new).ok()?.trim_end_matches('\0');
+
+    if name == ".cmdline" {
+        let old = old.split_whitespace().collect::<Vec<_>>();
+        let new = new.split_whitespace().collect::<Vec<_>>();
+        // The parameters are compared with their secrets, but only shown without them.
+        let diff = diff_lines(&old, &new)
+            .into_iter()
+            .map(|line| match line {
+                DiffLine::Removed(param) => DiffLine::Removed(redact_param(&param)),
+                DiffLine::Added(param) => DiffLine::Added(redact_param(&param)),
+            })
+            .collect();
+        return Some(diff);
+    }
+
+    Some(diff_lines(
+        &old.lines().collect::<Vec<_>>(),
+        &new.lines().collect::<Vec<_>>(),
+    ))
+}
+
+/// Compute a minimal line diff based on the longest common subsequence.
+pub fn diff_lines(old: &[&str], new: &[&str]) -> Vec<DiffLine> {
+    // lcs[i][j] is the length of the longest common subsequence of old[i..] and new[j..].
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            diff.push(DiffLine::Removed(old[i].to_string()));
+            i += 1;
+        } else {
+            diff.push(DiffLine::Added(new[j].to_string()));
+            j += 1;
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_changed_parameters() {
+        let old = ["init=/init", "quiet", "loglevel=4"];
+        let new = ["init=/init", "loglevel=7", "console=ttyS0"];
+
+        assert_eq!(
+            diff_lines(&old, &new),
+            vec![
+                DiffLine::Removed("quiet".to_string()),
+                DiffLine::Removed("loglevel=4".to_string()),
+                DiffLine::Added("loglevel=7".to_string()),
+                DiffLine::Added("console=ttyS0".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_cmdline_into_parameters() {
+        let diff = section_diff(".cmdline", Some(b"init=/init quiet"), Some(b"init=/init"));
+
+        assert_eq!(
+            diff,
+            Some(SectionDiff::Changed {
+                old: SectionSummary::new(b"init=/init quiet"),
+                new: SectionSummary::new(b"init=/init"),
+                text: Some(vec![DiffLine::Removed("quiet".to_string())]),
+            })
+        );
+    }
+
+    #[test]
+    fn redact_changed_secrets() {
+        let diff = section_diff(
+            ".cmdline",
+            Some(b"init=/init rd.luks.key=/old"),
+            Some(b"init=/init rd.luks.key=/new"),
+        );
+
+        assert!(matches!(
+            diff,
+            Some(SectionDiff::Changed { text: Some(text), .. }) if text == vec![
+                DiffLine::Removed("rd.luks.key=***".to_string()),
+                DiffLine::Added("rd.luks.key=***".to_string()),
+            ]
+        ));
+    }
+
+    #[test]
+    fn do_not_diff_large_sections() {
+        // An embedded initrd of text files, as in UKIs that are not built by lanzaboote.
+        let old = "file\n".repeat(MAX_TEXT_DIFF_SIZE);
+        let new = "other file\n".repeat(MAX_TEXT_DIFF_SIZE);
+        let diff = section_diff(".initrd", Some(old.as_bytes()), Some(new.as_bytes()));
+
+        assert!(matches!(
+            diff,
+            Some(SectionDiff::Changed { text: None, .. })
+        ));
+    }
+
+    #[test]
+    fn do_not_diff_binary_sections() {
+        let diff = section_diff(".initrdh", Some(&[0; 32]), Some(&[1; 32]));
+
+        assert!(matches!(
+            diff,
+            Some(SectionDiff::Changed { text: None, .. })
+        ));
+    }
+}
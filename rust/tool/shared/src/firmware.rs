@@ -0,0 +1,74 @@
+//! The oldest firmware the stub boots on.
+//!
+//! Some firmware has bugs that break LoadFile2 or Secure Boot. The `.fwmin` section tells the
+//! stub the oldest firmware revision that is known to work, so that it can point the user to a
+//! firmware update instead of failing in obscure ways.
+
+use anyhow::{Context, Result};
+
+/// The contents of the `.fwmin` section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirmwarePolicy {
+    /// The oldest firmware revision that is known to work.
+    pub min_revision: u32,
+    /// Only check firmware that reports this vendor, because the meaning of the revision depends
+    /// on the vendor.
+    pub vendor: Option<String>,
+    /// Stop the boot on older firmware instead of only warning.
+    pub refuse: bool,
+}
+
+impl FirmwarePolicy {
+    /// Serialize to the `key=value` lines that are embedded in the `.fwmin` section.
+    pub fn to_section(&self) -> String {
+        let mut section = format!("revision={:#x}\n", self.min_revision);
+        if let Some(vendor) = &self.vendor {
+            section.push_str(&format!("vendor={vendor}\n"));
+        }
+        let action = if self.refuse { "refuse" } else { "warn" };
+        section.push_str(&format!("action={action}\n"));
+        section
+    }
+}
+
+/// Parse a firmware revision given in decimal or, prefixed with `0x`, in hexadecimal.
+pub fn parse_revision(value: &str) -> Result<u32> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .with_context(|| format!("Invalid firmware revision {value}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_revisions() {
+        assert_eq!(parse_revision("65538").unwrap(), 0x10002);
+        assert_eq!(parse_revision("0x10002").unwrap(), 0x10002);
+        assert!(parse_revision("0x100000000").is_err());
+        assert!(parse_revision("v1").is_err());
+    }
+
+    #[test]
+    fn serialize_firmware_policy() {
+        let policy = FirmwarePolicy {
+            min_revision: 0x10002,
+            vendor: Some("EDK II".into()),
+            refuse: true,
+        };
+        assert_eq!(
+            policy.to_section(),
+            "revision=0x10002\nvendor=EDK II\naction=refuse\n"
+        );
+
+        let policy = FirmwarePolicy {
+            vendor: None,
+            refuse: false,
+            ..policy
+        };
+        assert_eq!(policy.to_section(), "revision=0x10002\naction=warn\n");
+    }
+}
@@ -34,7 +34,8 @@ impl Roots {
         }
     }
 
-    pub fn collect_garbage(&self, directory: impl AsRef<Path>) -> Result<()> {
+    /// Delete all unused paths in `directory` and return the number of bytes that were freed.
+    pub fn collect_garbage(&self, directory: impl AsRef<Path>) -> Result<u64> {
         self.collect_garbage_with_filter(directory, |_| true)
     }
 
@@ -43,41 +44,78 @@ impl Roots {
     /// The filter function takes a &Path and returns a bool. The paths for which the filter
     /// function returns true are considered for garbage collection. This means that _only_ files
     /// that are unused AND for which the filter function returns true are deleted.
+    ///
+    /// Returns the number of bytes that were freed, i.e. the total size of the deleted files.
     pub fn collect_garbage_with_filter<P>(
         &self,
         directory: impl AsRef<Path>,
-        mut predicate: P,
-    ) -> Result<()>
+        predicate: P,
+    ) -> Result<u64>
     where
         P: FnMut(&Path) -> bool,
     {
-        // Find all the paths not used anymore.
-        let entries_not_in_use = WalkDir::new(directory.as_ref())
-            .into_iter()
-            .filter(|e| !self.in_use(e.as_ref().ok()))
-            .filter(|e| match e.as_ref().ok() {
-                Some(e) => predicate(e.path()),
-                None => false,
-            });
-
+        let mut freed = 0;
         // Remove all entries not in use.
-        for e in entries_not_in_use {
-            let entry = e?;
-            let path = entry.path();
+        for path in self.garbage_with_filter(directory, predicate) {
             log::debug!("Garbage collecting {path:?}...");
 
             if path.is_dir() {
                 // If a directory is marked as unused all its children can be deleted too.
-                fs::remove_dir_all(path)
+                let size = disk_usage(&path);
+                fs::remove_dir_all(&path)
                     .with_context(|| format!("Failed to remove directory: {:?}", path))?;
+                freed += size;
             } else {
+                let size = fs::symlink_metadata(&path).map_or(0, |m| m.len());
                 // Ignore failing to remove path because the parent directory might have been removed before.
-                fs::remove_file(path).ok();
+                if fs::remove_file(&path).is_ok() {
+                    freed += size;
+                }
             };
         }
 
-        Ok(())
+        Ok(freed)
     }
+
+    /// Find the paths that [`Self::collect_garbage_with_filter`] would delete, without deleting
+    /// them.
+    ///
+    /// The children of an unused directory are not listed, because they are deleted with it.
+    pub fn garbage_with_filter<P>(
+        &self,
+        directory: impl AsRef<Path>,
+        mut predicate: P,
+    ) -> Vec<PathBuf>
+    where
+        P: FnMut(&Path) -> bool,
+    {
+        let mut garbage = Vec::new();
+        let mut entries = WalkDir::new(directory.as_ref()).into_iter();
+        while let Some(entry) = entries.next() {
+            let Ok(entry) = entry else {
+                continue;
+            };
+            if self.in_use(Some(&entry)) || !predicate(entry.path()) {
+                continue;
+            }
+            if entry.file_type().is_dir() {
+                entries.skip_current_dir();
+            }
+            garbage.push(entry.into_path());
+        }
+        garbage
+    }
+}
+
+/// The total size of the files in `directory`.
+fn disk_usage(directory: &Path) -> u64 {
+    WalkDir::new(directory)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum()
 }
 
 impl Default for Roots {
@@ -187,7 +225,7 @@ mod tests {
         roots.collect_garbage_with_filter(&rootdir, |p| {
             p.file_name()
                 .and_then(|n| n.to_str())
-                .map_or(false, |n| n.starts_with("prefix_"))
+                .is_some_and(|n| n.starts_with("prefix_"))
         })?;
 
         assert!(unused_file.exists());
@@ -195,6 +233,45 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn list_garbage_without_children_of_unused_directories() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let rootdir = create_dir(tmpdir.path().join("root"))?;
+
+        let used_file = create_file(rootdir.join("used_file"))?;
+        let unused_file = create_file(rootdir.join("unused_file"))?;
+        let unused_directory = create_dir(rootdir.join("unused_directory"))?;
+        create_file(unused_directory.join("unused_file_in_directory"))?;
+
+        let mut roots = Roots::new();
+        roots.extend(vec![&rootdir, &used_file]);
+        let mut garbage = roots.garbage_with_filter(&rootdir, |_| true);
+        garbage.sort();
+
+        assert_eq!(garbage, [unused_directory.clone(), unused_file.clone()]);
+        // Nothing is deleted.
+        assert!(unused_file.exists());
+        assert!(unused_directory.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn count_freed_bytes() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let rootdir = create_dir(tmpdir.path().join("root"))?;
+
+        let used_file = rootdir.join("used_file");
+        fs::write(&used_file, [0; 100])?;
+        fs::write(rootdir.join("unused_file"), [0; 10])?;
+        let unused_directory = create_dir(rootdir.join("unused_directory"))?;
+        fs::write(unused_directory.join("unused_file_in_directory"), [0; 5])?;
+
+        let mut roots = Roots::new();
+        roots.extend(vec![&rootdir, &used_file]);
+        assert_eq!(roots.collect_garbage(&rootdir)?, 15);
+        Ok(())
+    }
+
     fn create_file(path: PathBuf) -> Result<PathBuf> {
         fs::File::create(&path)?;
         Ok(path)
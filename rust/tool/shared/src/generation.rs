@@ -9,6 +9,15 @@ use bootspec::BootSpec;
 use bootspec::SpecialisationName;
 use time::Date;
 
+/// The prefix of the names of all files lzbt installs to `EFI/Linux`.
+///
+/// The directory is shared with other distributions, so only files with this prefix are garbage
+/// collected there.
+pub const ENTRY_FILENAME_PREFIX: &str = "nixos-";
+
+/// The characters FAT does not allow in file names, in addition to control characters.
+const FAT_RESERVED_CHARACTERS: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
 /// (Possibly) extended Bootspec.
 ///
 /// This struct currently does not have any extensions. We keep it around so that extension becomes
@@ -30,6 +39,8 @@ pub struct ExtendedBootJson {
 pub struct Generation {
     /// Profile symlink index
     pub version: u64,
+    /// Name of the profile, e.g. `system`
+    pub profile: String,
     /// Build time
     pub build_time: Option<Date>,
     /// Top-level specialisation name
@@ -51,6 +62,7 @@ impl Generation {
 
         Ok(Self {
             version: link.version,
+            profile: link.profile.clone(),
             build_time: link.build_time,
             specialisation_name: None,
             spec: ExtendedBootJson { bootspec },
@@ -97,10 +109,34 @@ impl Generation {
         )
     }
 
+    /// The version of the kernel, from the modules in the toplevel.
+    pub fn kernel_version(&self) -> Option<String> {
+        let modules = self
+            .spec
+            .bootspec
+            .bootspec
+            .toplevel
+            .0
+            .join("kernel-modules/lib/modules");
+        fs::read_dir(modules)
+            .ok()?
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .min()
+    }
+
     /// A unique short identifier.
     pub fn version_tag(&self) -> String {
         format!("{}{}", self.version, self.describe_specialisation(),)
     }
+
+    /// The name of the UKI of this generation on the ESP, see [`entry_filename`].
+    pub fn entry_filename(&self) -> String {
+        entry_filename(
+            &self.profile,
+            self.version,
+            self.specialisation_name.as_ref().map(|s| s.0.as_str()),
+        )
+    }
 }
 
 impl fmt::Display for Generation {
@@ -109,6 +145,42 @@ impl fmt::Display for Generation {
     }
 }
 
+/// The name of the UKI of a generation on the ESP, without the `.efi` extension.
+///
+/// The names follow the entries of the systemd-boot installer of NixOS, e.g.
+/// `nixos-generation-3-specialisation-gaming` or, for profiles other than `system`,
+/// `nixos-work-generation-3`. Characters in the profile and specialisation names that FAT does
+/// not allow are replaced by `_`.
+///
+/// The installer appends a hash of the options that determine the contents of the UKI to this
+/// name.
+pub fn entry_filename(profile: &str, generation: u64, specialisation: Option<&str>) -> String {
+    let mut name = String::from(ENTRY_FILENAME_PREFIX);
+    if profile != "system" {
+        name.push_str(&sanitize_for_fat(profile));
+        name.push('-');
+    }
+    name.push_str(&format!("generation-{generation}"));
+    if let Some(specialisation) = specialisation {
+        name.push_str("-specialisation-");
+        name.push_str(&sanitize_for_fat(specialisation));
+    }
+    name
+}
+
+/// Replace the characters that are not allowed in FAT file names by `_`.
+fn sanitize_for_fat(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_control() || FAT_RESERVED_CHARACTERS.contains(&c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
 fn read_build_time(path: &Path) -> Result<Date> {
     let build_time =
         time::OffsetDateTime::from_unix_timestamp(fs::symlink_metadata(path)?.mtime())?.date();
@@ -122,6 +194,7 @@ fn read_build_time(path: &Path) -> Result<Date> {
 #[derive(Debug)]
 pub struct GenerationLink {
     pub version: u64,
+    pub profile: String,
     pub path: PathBuf,
     pub build_time: Option<Date>,
 }
@@ -130,6 +203,7 @@ impl GenerationLink {
     pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
         Ok(Self {
             version: parse_version(&path).context("Failed to parse version")?,
+            profile: parse_profile(&path).context("Failed to parse profile")?,
             path: PathBuf::from(path.as_ref()),
             build_time: read_build_time(path.as_ref()).ok(),
         })
@@ -151,6 +225,20 @@ fn parse_version(path: impl AsRef<Path>) -> Result<u64> {
     Ok(generation_version)
 }
 
+/// Parse the profile name from a path.
+///
+/// Expects a path in the format of "{profile}-{version}-link".
+fn parse_profile(path: impl AsRef<Path>) -> Result<String> {
+    let profile = path
+        .as_ref()
+        .file_name()
+        .and_then(|x| x.to_str())
+        .and_then(|x| x.rsplitn(3, '-').nth(2))
+        .with_context(|| format!("Failed to extract profile from: {:?}", path.as_ref()))?;
+
+    Ok(profile.to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -161,4 +249,37 @@ mod tests {
         let parsed_version = parse_version(path).unwrap();
         assert_eq!(parsed_version, 2,);
     }
+
+    #[test]
+    fn entry_filenames() {
+        assert_eq!(entry_filename("system", 3, None), "nixos-generation-3");
+        assert_eq!(
+            entry_filename("system", 3, Some("gaming")),
+            "nixos-generation-3-specialisation-gaming"
+        );
+        assert_eq!(entry_filename("work", 3, None), "nixos-work-generation-3");
+        assert_eq!(
+            entry_filename("work", 3, Some("gaming")),
+            "nixos-work-generation-3-specialisation-gaming"
+        );
+    }
+
+    #[test]
+    fn sanitize_entry_filenames_for_fat() {
+        assert_eq!(
+            entry_filename("a/b\\c", 1, Some("x:y*z?\"<>|\t")),
+            "nixos-a_b_c-generation-1-specialisation-x_y_z______"
+        );
+        // Everything else FAT allows, including spaces and non-ASCII characters.
+        assert_eq!(
+            entry_filename("system", 1, Some("my spécial.1")),
+            "nixos-generation-1-specialisation-my spécial.1"
+        );
+    }
+
+    #[test]
+    fn parse_profile_correctly() {
+        assert_eq!(parse_profile("system-2-link").unwrap(), "system");
+        assert_eq!(parse_profile("my-laptop-2-link").unwrap(), "my-laptop");
+    }
 }
@@ -0,0 +1,225 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+/// Magic number of cpio archives in the "new ASCII" format, with and without checksums.
+const CPIO_MAGICS: [&[u8]; 2] = [b"070701", b"070702"];
+const CPIO_MAGIC_SIZE: usize = 6;
+/// Size of a cpio header, including the magic number.
+pub(crate) const CPIO_HEADER_SIZE: usize = 110;
+/// Name of the last entry of a cpio archive.
+pub(crate) const CPIO_TRAILER: &str = "TRAILER!!!";
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Collect the kernel versions that an initrd contains modules for, i.e. the names of the
+/// `lib/modules/<version>` directories.
+///
+/// An initrd is a concatenation of cpio archives, each of which may be compressed. Only gzip and
+/// zstd compressed archives are looked into. Everything after an archive compressed with another
+/// format is ignored.
+pub fn module_versions(initrd: &Path) -> Result<BTreeSet<String>> {
+    let file = File::open(initrd).with_context(|| format!("Failed to open {initrd:?}"))?;
+    let mut versions = BTreeSet::new();
+    read_archives(Box::new(BufReader::new(file)), &mut versions)
+        .with_context(|| format!("Failed to read initrd {initrd:?}"))?;
+    Ok(versions)
+}
+
+fn read_archives(mut reader: Box<dyn BufRead + '_>, versions: &mut BTreeSet<String>) -> Result<()> {
+    loop {
+        // Archives are padded with zeroes.
+        let Some(first) = next_non_zero_byte(&mut reader)? else {
+            return Ok(());
+        };
+        let mut magic = [first; CPIO_MAGIC_SIZE];
+        reader.read_exact(&mut magic[1..])?;
+
+        if CPIO_MAGICS.contains(&&magic[..]) {
+            read_cpio_archive(&mut reader, versions)?;
+            continue;
+        }
+
+        // The decoders consume the rest of the initrd, which may contain further archives.
+        let rest = io::Cursor::new(magic).chain(reader);
+        if magic.starts_with(GZIP_MAGIC) {
+            let decoder = flate2::read::MultiGzDecoder::new(rest);
+            return read_archives(Box::new(BufReader::new(decoder)), versions);
+        }
+        if magic.starts_with(ZSTD_MAGIC) {
+            let decoder = zstd::Decoder::new(rest)?;
+            return read_archives(Box::new(BufReader::new(decoder)), versions);
+        }
+        return Ok(());
+    }
+}
+
+fn next_non_zero_byte(reader: &mut impl BufRead) -> Result<Option<u8>> {
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            return Ok(None);
+        }
+        match buffer.iter().position(|b| *b != 0) {
+            Some(position) => {
+                let byte = buffer[position];
+                reader.consume(position + 1);
+                return Ok(Some(byte));
+            }
+            None => {
+                let length = buffer.len();
+                reader.consume(length);
+            }
+        }
+    }
+}
+
+/// Read the entries of a cpio archive whose magic number has already been consumed.
+fn read_cpio_archive(reader: &mut impl Read, versions: &mut BTreeSet<String>) -> Result<()> {
+    let mut first_entry = true;
+    loop {
+        if !first_entry {
+            let mut magic = [0; CPIO_MAGIC_SIZE];
+            reader.read_exact(&mut magic)?;
+            if !CPIO_MAGICS.contains(&&magic[..]) {
+                anyhow::bail!("Invalid cpio header");
+            }
+        }
+        first_entry = false;
+
+        let mut header = [0; CPIO_HEADER_SIZE - CPIO_MAGIC_SIZE];
+        reader.read_exact(&mut header)?;
+        // The header consists of 8 digit hexadecimal fields. The file size is the 7th field,
+        // the name size the 12th.
+        let field = |index: usize| -> Result<u64> {
+            let digits = std::str::from_utf8(&header[index * 8..(index + 1) * 8])?;
+            Ok(u64::from_str_radix(digits, 16)?)
+        };
+        let file_size = field(6)?;
+        let name_size = field(11)?;
+
+        // The name is NUL terminated and, together with the header, padded to 4 bytes.
+        let mut name = vec![0; usize::try_from(name_size)?];
+        reader.read_exact(&mut name)?;
+        skip(reader, padding(CPIO_HEADER_SIZE as u64 + name_size))?;
+        let name = String::from_utf8_lossy(name.strip_suffix(&[0]).unwrap_or(&name));
+
+        if name == CPIO_TRAILER {
+            return Ok(());
+        }
+        if let Some(version) = module_version(&name) {
+            versions.insert(version.to_string());
+        }
+
+        skip(reader, file_size + padding(file_size))?;
+    }
+}
+
+/// Extract the kernel version from a path below `lib/modules`.
+fn module_version(path: &str) -> Option<&str> {
+    let path = path.trim_start_matches("./").trim_start_matches('/');
+    let version = path.strip_prefix("lib/modules/")?.split('/').next()?;
+    Some(version).filter(|v| !v.is_empty())
+}
+
+pub(crate) fn padding(size: u64) -> u64 {
+    (4 - size % 4) % 4
+}
+
+fn skip(reader: &mut impl Read, size: u64) -> Result<()> {
+    let skipped = io::copy(&mut reader.take(size), &mut io::sink())?;
+    if skipped != size {
+        anyhow::bail!("Unexpected end of cpio archive");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use tempfile::tempdir;
+
+    fn cpio_entry(archive: &mut Vec<u8>, name: &str, contents: &[u8]) {
+        let name_size = name.len() + 1;
+        archive.extend(b"070701");
+        for field in [
+            0,
+            0o100644,
+            0,
+            0,
+            1,
+            0,
+            contents.len(),
+            0,
+            0,
+            0,
+            0,
+            name_size,
+            0,
+        ] {
+            archive.extend(format!("{field:08x}").as_bytes());
+        }
+        archive.extend(name.as_bytes());
+        archive.push(0);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+        archive.extend(contents);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+    }
+
+    fn cpio_archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        for (name, contents) in entries {
+            cpio_entry(&mut archive, name, contents);
+        }
+        cpio_entry(&mut archive, CPIO_TRAILER, &[]);
+        archive
+    }
+
+    fn versions_of(initrd: &[u8]) -> BTreeSet<String> {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("initrd");
+        std::fs::write(&path, initrd).unwrap();
+        module_versions(&path).unwrap()
+    }
+
+    #[test]
+    fn find_module_versions() {
+        let initrd = cpio_archive(&[
+            ("init", b"#!/bin/sh"),
+            ("lib/modules/6.1.1/kernel/fs/ext4.ko", b"ext4"),
+            ("./lib/modules/6.1.2", b""),
+            ("lib/firmware/x.bin", b"firmware"),
+        ]);
+
+        assert_eq!(
+            versions_of(&initrd),
+            BTreeSet::from(["6.1.1".to_string(), "6.1.2".to_string()])
+        );
+    }
+
+    #[test]
+    fn find_module_versions_in_compressed_archive() {
+        let microcode = cpio_archive(&[("kernel/x86/microcode/GenuineIntel.bin", b"ucode")]);
+        let main = cpio_archive(&[("lib/modules/6.1.1/modules.dep", b"")]);
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&main).unwrap();
+
+        let mut initrd = microcode;
+        initrd.extend([0; 512]);
+        initrd.extend(encoder.finish().unwrap());
+
+        assert_eq!(versions_of(&initrd), BTreeSet::from(["6.1.1".to_string()]));
+    }
+
+    #[test]
+    fn ignore_non_cpio_data() {
+        assert!(versions_of(b"\xfd7zXZ\x00 rest of an xz archive").is_empty());
+    }
+}
@@ -1,8 +1,25 @@
 pub mod architecture;
+pub mod audit;
+pub mod authenticode;
+pub mod bundle;
+pub mod cache;
+pub mod cmdline;
+pub mod compression;
+pub mod dbx;
+pub mod deploy;
+pub mod diff;
 pub mod esp;
+pub mod firmware;
 pub mod gc;
 pub mod generation;
+pub mod initrd;
+pub mod manifest;
 pub mod os_release;
 pub mod pe;
+pub mod pki;
+pub mod release;
+pub mod selftest;
 pub mod signature;
+pub mod title;
+pub mod uki;
 pub mod utils;
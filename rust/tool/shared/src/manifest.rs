@@ -0,0 +1,206 @@
+//! A machine readable description of the installed UKIs.
+//!
+//! The manifest allows e.g. CI pipelines to verify what was produced by an installation without
+//! inspecting the ESP themselves.
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use goblin::pe::PE;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::authenticode::read_certificate;
+use crate::pe;
+use crate::signature::PublicKey;
+use crate::utils::Hash;
+
+/// Version of the manifest format. Increment on incompatible changes.
+const MANIFEST_VERSION: u32 = 1;
+
+/// The PCR the stub measures the UKI sections into.
+const PCR_KERNEL_IMAGE: u32 = 11;
+
+/// The sections the stub measures, in the order of the sections of the UKI.
+///
+/// This mirrors `UnifiedSection::should_be_measured` of the stub, plus the devicetree overlays.
+const MEASURED_SECTIONS: [&str; 8] = [
+    ".linux", ".osrel", ".cmdline", ".initrd", ".splash", ".dtb", ".dtbo", ".pcrpkey",
+];
+
+/// The certificate the UKIs are signed with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignerIdentity {
+    /// The subject of the certificate in RFC 4514 format.
+    pub subject: String,
+    /// The SHA256 hash of the DER encoded certificate.
+    pub fingerprint: Hash,
+}
+
+impl SignerIdentity {
+    pub fn from_public_key(public_key: &PublicKey) -> Result<Self> {
+        let certificate = read_certificate(&public_key.path)?;
+        let der = x509_cert::der::Encode::to_der(&certificate)?;
+
+        Ok(Self {
+            subject: certificate.tbs_certificate.subject.to_string(),
+            fingerprint: Sha256::digest(der),
+        })
+    }
+}
+
+/// Everything a manifest records about a single UKI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// The UEFI path of the UKI relative to the ESP.
+    pub path: String,
+    /// The SHA256 hash of the whole file.
+    pub sha256: Hash,
+    /// The Authenticode digest, i.e. the hash the firmware checks against db and dbx.
+    pub authenticode: Hash,
+    /// When the UKI was signed, in seconds since the Unix epoch.
+    ///
+    /// Signatures do not contain a timestamp, so this is the time the signed UKI was written.
+    pub signed_at: i64,
+    /// The value of PCR 11 in the SHA256 bank after the stub has measured the UKI, assuming
+    /// nothing was measured into it before.
+    pub pcr11: Hash,
+}
+
+impl ManifestEntry {
+    /// Describe an installed UKI.
+    pub fn from_uki(esp: &Path, uki: &Path) -> Result<Self> {
+        let file_data = fs::read(uki).with_context(|| format!("Failed to read {uki:?}"))?;
+        let signed_at = fs::metadata(uki)
+            .with_context(|| format!("Failed to read metadata of {uki:?}"))?
+            .mtime();
+
+        Ok(Self {
+            path: pe::esp_relative_uefi_path(esp, uki)?,
+            sha256: Sha256::digest(&file_data),
+            authenticode: pe::authenticode_digest(&file_data)
+                .with_context(|| format!("Failed to compute Authenticode digest of {uki:?}"))?,
+            signed_at,
+            pcr11: predict_pcr11(&file_data)
+                .with_context(|| format!("Failed to predict PCR 11 of {uki:?}"))?,
+        })
+    }
+}
+
+/// A description of all UKIs produced by an installation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub signer: SignerIdentity,
+    pub ukis: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn to_json(&self) -> serde_json::Value {
+        let ukis = self
+            .ukis
+            .iter()
+            .map(|uki| {
+                json!({
+                    "path": uki.path,
+                    "sha256": format!("{:x}", uki.sha256),
+                    "authenticode": format!("{:x}", uki.authenticode),
+                    "signer": format!("{:x}", self.signer.fingerprint),
+                    "signed_at": uki.signed_at,
+                    "pcrs": {
+                        PCR_KERNEL_IMAGE.to_string(): format!("{:x}", uki.pcr11),
+                    },
+                })
+            })
+            .collect::<Vec<_>>();
+
+        json!({
+            "version": MANIFEST_VERSION,
+            "signers": {
+                format!("{:x}", self.signer.fingerprint): {
+                    "subject": self.signer.subject,
+                },
+            },
+            "ukis": ukis,
+        })
+    }
+
+    /// Write the manifest as pretty-printed JSON.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(&self.to_json())?;
+        fs::write(path, json + "\n").with_context(|| format!("Failed to write manifest {path:?}"))
+    }
+}
+
+/// Compute the value of PCR 11 after the stub measured the sections of a UKI.
+///
+/// The stub extends the PCR with the hash of each measured section in the order they appear in
+/// the UKI. This includes every `.dtbo` section, whether or not the stub applies the overlay.
+pub fn predict_pcr11(file_data: &[u8]) -> Result<Hash> {
+    let pe = PE::parse(file_data).context("Failed to parse PE binary")?;
+    let mut pcr = Hash::default();
+    for section in &pe.sections {
+        let name = section.name()?;
+        if MEASURED_SECTIONS.contains(&name) {
+            let data = pe::section_data(file_data, section)
+                .with_context(|| format!("Failed to read section {name}"))?;
+            pcr = extend(&pcr, data);
+        }
+    }
+    Ok(pcr)
+}
+
+/// Extend a SHA256 PCR with the measurement of `data`.
+pub(crate) fn extend(pcr: &Hash, data: &[u8]) -> Hash {
+    Sha256::new()
+        .chain_update(pcr)
+        .chain_update(Sha256::digest(data))
+        .finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, contents: &[u8]) -> ManifestEntry {
+        ManifestEntry {
+            path: path.to_string(),
+            sha256: Sha256::digest(contents),
+            authenticode: Sha256::digest([contents, b"authenticode"].concat()),
+            signed_at: 1_700_000_000,
+            pcr11: extend(&Hash::default(), contents),
+        }
+    }
+
+    #[test]
+    fn serialize_manifest() {
+        let signer = SignerIdentity {
+            subject: "CN=Lanzaboote".to_string(),
+            fingerprint: Sha256::digest(b"certificate"),
+        };
+        let fingerprint = format!("{:x}", signer.fingerprint);
+        let manifest = Manifest {
+            signer,
+            ukis: vec![
+                entry("\\EFI\\Linux\\nixos-generation-1.efi", b"1"),
+                entry("\\EFI\\Linux\\nixos-generation-2.efi", b"2"),
+            ],
+        };
+
+        let json = manifest.to_json();
+
+        assert_eq!(json["version"], MANIFEST_VERSION);
+        assert_eq!(json["signers"][&fingerprint]["subject"], "CN=Lanzaboote");
+        let ukis = json["ukis"].as_array().unwrap();
+        assert_eq!(ukis.len(), 2);
+        for (uki, entry) in ukis.iter().zip(&manifest.ukis) {
+            assert_eq!(uki["path"], entry.path);
+            assert_eq!(uki["sha256"], format!("{:x}", entry.sha256));
+            assert_eq!(uki["authenticode"], format!("{:x}", entry.authenticode));
+            assert_eq!(uki["signer"], fingerprint);
+            assert_eq!(uki["signed_at"], 1_700_000_000);
+            assert_eq!(uki["pcrs"]["11"], format!("{:x}", entry.pcr11));
+        }
+    }
+}
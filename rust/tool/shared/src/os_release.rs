@@ -1,9 +1,13 @@
 use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
 use std::{collections::BTreeMap, str::FromStr};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
 use crate::generation::Generation;
+use crate::title::TitleTemplate;
 
 /// An os-release file represented by a BTreeMap.
 ///
@@ -15,8 +19,77 @@ use crate::generation::Generation;
 /// for testing. Ordered keys allow using snapshot tests.
 pub struct OsRelease(pub BTreeMap<String, String>);
 
+/// What happens if there is no os-release to embed in the `.osrel` section of a UKI, e.g. for a
+/// hand-built system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MissingOsRelease {
+    /// Log a warning and embed a minimal os-release instead.
+    #[default]
+    Warn,
+    /// Refuse to build the UKI.
+    Fail,
+}
+
+impl FromStr for MissingOsRelease {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "warn" => Ok(Self::Warn),
+            "fail" => Ok(Self::Fail),
+            _ => anyhow::bail!("Unknown policy {s}, expected warn or fail"),
+        }
+    }
+}
+
+impl fmt::Display for MissingOsRelease {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Warn => "warn",
+            Self::Fail => "fail",
+        })
+    }
+}
+
+/// The keys of the os-release of a generation that are copied to its `.osrel` section.
+///
+/// systemd-boot shows the first of `IMAGE_VERSION`, `VERSION` and `VERSION_ID` that is set as the
+/// version of an entry.
+const GENERATION_KEYS: [&str; 6] = [
+    "NAME",
+    "VERSION",
+    "VERSION_CODENAME",
+    "BUILD_ID",
+    "IMAGE_ID",
+    "IMAGE_VERSION",
+];
+
 impl OsRelease {
-    pub fn from_generation(generation: &Generation) -> Result<Self> {
+    /// The os-release embedded in a UKI that has none. It only has the keys systemd-boot needs to
+    /// show the UKI.
+    pub fn minimal() -> Self {
+        Self(BTreeMap::from([
+            ("ID".into(), "lanza".into()),
+            ("NAME".into(), "Linux".into()),
+            ("PRETTY_NAME".into(), "Linux".into()),
+        ]))
+    }
+
+    /// Read the os-release of a toplevel. Returns `None` if it has none.
+    pub fn from_toplevel(toplevel: &Path) -> Result<Option<Self>> {
+        let path = toplevel.join("etc/os-release");
+        match fs::read_to_string(&path) {
+            Ok(contents) => Ok(Some(Self::from_str(&contents)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Failed to read {path:?}")),
+        }
+    }
+
+    /// Describe a generation. Its title is rendered from `title_template` if one is given.
+    pub fn from_generation(
+        generation: &Generation,
+        title_template: Option<&TitleTemplate>,
+    ) -> Result<Self> {
         let mut map = BTreeMap::new();
 
         // Because of a null pointer dereference, `bootctl` segfaults when no ID field is present
@@ -32,17 +105,38 @@ impl OsRelease {
         // user experience.
         //
         // See #220.
-        map.insert(
-            "PRETTY_NAME".into(),
-            format!(
+        let pretty_name = match title_template {
+            Some(template) => template.render(generation),
+            None => format!(
                 "{} ({})",
                 generation.spec.bootspec.bootspec.label,
                 generation.describe()
             ),
-        );
+        };
+        map.insert("PRETTY_NAME".into(), pretty_name);
 
         map.insert("VERSION_ID".into(), generation.describe());
 
+        // Every generation can run a different version of NixOS, so the version is taken from the
+        // os-release of the generation itself and not from the system that installs it. The
+        // generation can still be booted without it, so an os-release that cannot be read does
+        // not stop the installation.
+        match Self::from_toplevel(&generation.spec.bootspec.bootspec.toplevel.0) {
+            Ok(Some(os_release)) => {
+                for key in GENERATION_KEYS {
+                    // NixOS sets IMAGE_ID and IMAGE_VERSION to empty strings. systemd-boot would
+                    // show the empty IMAGE_VERSION instead of VERSION.
+                    if let Some(value) = os_release.0.get(key).filter(|value| !value.is_empty()) {
+                        map.insert(key.into(), value.clone());
+                    }
+                }
+            }
+            Ok(None) => (),
+            Err(err) => log::warn!(
+                "Failed to read the os-release of generation {generation}, its version is not shown: {err:#}"
+            ),
+        }
+
         Ok(Self(map))
     }
 }
@@ -54,7 +148,8 @@ impl FromStr for OsRelease {
     /// **Beware before reusing this function!**
     ///
     /// This parser might not parse all valid os-release files correctly. It is only designed to
-    /// read the `VERSION` key from the os-release of a systemd-boot binary.
+    /// read the `VERSION` key from the os-release of a systemd-boot binary and the version keys
+    /// from the os-release of a generation.
     fn from_str(value: &str) -> Result<Self> {
         let mut map = BTreeMap::new();
 
@@ -247,6 +342,59 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn read_os_release_of_toplevel() -> Result<()> {
+        let toplevel = tempfile::tempdir()?;
+        assert!(OsRelease::from_toplevel(toplevel.path())?.is_none());
+
+        fs::create_dir(toplevel.path().join("etc"))?;
+        fs::write(
+            toplevel.path().join("etc/os-release"),
+            "ID=nixos\nVERSION=\"23.11 (Tapir)\"\n",
+        )?;
+        let os_release = OsRelease::from_toplevel(toplevel.path())?.unwrap();
+        assert_eq!(os_release.0["VERSION"], "23.11 (Tapir)");
+        Ok(())
+    }
+
+    #[test]
+    fn parse_os_release_of_nixos() -> Result<()> {
+        // The etc/os-release of a NixOS 24.05 toplevel with a comment added.
+        let os_release = OsRelease::from_str(
+            r#"# Generated by NixOS
+ANSI_COLOR="1;34"
+BUG_REPORT_URL="https://github.com/NixOS/nixpkgs/issues"
+BUILD_ID="24.05.20240712.c0d0be0"
+DOCUMENTATION_URL="https://nixos.org/learn.html"
+HOME_URL="https://nixos.org/"
+ID=nixos
+IMAGE_ID=""
+IMAGE_VERSION=""
+LOGO="nix-snowflake"
+NAME=NixOS
+PRETTY_NAME="NixOS 24.05 (Uakari)"
+SUPPORT_URL="https://nixos.org/community.html"
+VERSION="24.05 (Uakari)"
+VERSION_CODENAME=uakari
+VERSION_ID="24.05"
+"#,
+        )?;
+
+        assert_eq!(os_release.0.len(), 15);
+        assert_eq!(os_release.0["ANSI_COLOR"], "1;34");
+        assert_eq!(
+            os_release.0["BUG_REPORT_URL"],
+            "https://github.com/NixOS/nixpkgs/issues"
+        );
+        assert_eq!(os_release.0["BUILD_ID"], "24.05.20240712.c0d0be0");
+        assert_eq!(os_release.0["IMAGE_VERSION"], "");
+        assert_eq!(os_release.0["NAME"], "NixOS");
+        assert_eq!(os_release.0["VERSION"], "24.05 (Uakari)");
+        assert_eq!(os_release.0["VERSION_CODENAME"], "uakari");
+
+        Ok(())
+    }
+
     #[test]
     fn escaping_works() -> Result<()> {
         let teststring = r#"
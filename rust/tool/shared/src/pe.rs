@@ -1,91 +1,950 @@
+use std::collections::HashSet;
 use std::ffi::OsString;
-use std::fs;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use anyhow::{Context, Result};
+use goblin::pe::characteristic::{IMAGE_FILE_EXECUTABLE_IMAGE, IMAGE_FILE_LARGE_ADDRESS_AWARE};
+use goblin::pe::section_table::SectionTable;
 use goblin::pe::PE;
+use sha2::{Digest, Sha256};
 use tempfile::TempDir;
 
-use crate::utils::{file_hash, tmpname, SecureTempDirExt};
+use crate::architecture::Architecture;
+use crate::cmdline::{CMDLINE_SECTIONS, DEFAULT_CMDLINE_LABEL, MAX_CMDLINE_VARIANTS};
+use crate::signature::Signer;
+use crate::uki::UkiConfig;
+use crate::utils::{create_tempdir, file_hash, tmpname, Hash, SecureTempDirExt};
 
-/// Assemble a lanzaboote image.
-#[allow(clippy::too_many_arguments)]
+/// Size of the PE signature (`PE\0\0`) and the COFF file header preceding the optional header.
+const COFF_HEADER_END: usize = 4 + 20;
+/// Offset of the data directories in a PE32+ optional header.
+const DATA_DIRECTORIES_OFFSET_PE32_PLUS: usize = 112;
+/// Offset of the data directories in a PE32 optional header.
+const DATA_DIRECTORIES_OFFSET_PE32: usize = 96;
+/// Index of the certificate table in the data directories.
+const CERTIFICATE_TABLE_INDEX: usize = 4;
+/// Offset of the `VirtualSize` field in a section header.
+const SECTION_VIRTUAL_SIZE_OFFSET: usize = 8;
+/// The `Subsystem` of EFI applications in the optional header.
+const IMAGE_SUBSYSTEM_EFI_APPLICATION: u16 = 10;
+/// The image can be relocated at load time.
+const IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE: u16 = 0x0040;
+/// The image is compatible with data execution prevention.
+const IMAGE_DLLCHARACTERISTICS_NX_COMPAT: u16 = 0x0100;
+/// Offset of the `Characteristics` field in the COFF file header, from the PE signature.
+const CHARACTERISTICS_OFFSET: usize = 4 + 18;
+/// Offset of the `TimeDateStamp` field in the COFF file header, from the PE signature.
+const TIME_DATE_STAMP_OFFSET: usize = 4 + 4;
+/// Offset of the `Subsystem` field in the optional header. It is the same for PE32 and PE32+.
+const SUBSYSTEM_OFFSET: usize = 68;
+/// Offset of the `DllCharacteristics` field in the optional header.
+const DLL_CHARACTERISTICS_OFFSET: usize = 70;
+/// Size of the header of a block in the base relocation table.
+const BASE_RELOCATION_BLOCK_HEADER_SIZE: usize = 8;
+/// Size of the DOS header.
+const DOS_HEADER_SIZE: usize = 0x40;
+/// Offset of the pointer to the PE signature (`e_lfanew`) in the DOS header.
+const PE_POINTER_OFFSET: usize = 0x3c;
+/// Offset of the `CheckSum` field in the optional header. It is the same for PE32 and PE32+.
+const CHECKSUM_OFFSET: usize = 64;
+/// The certificate table and its entries are aligned to 8 bytes.
+pub(crate) const CERTIFICATE_TABLE_ALIGNMENT: u64 = 8;
+/// Size of the chunks in which binaries are read to compute their Authenticode digest.
+const AUTHENTICODE_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// The name of the zeroed section that moves an aligned section to the right file offset.
+const PADDING_SECTION: &str = ".pad";
+
+/// The size of a section name in an entry of the `.sechash` section.
+const SECTION_HASH_NAME_SIZE: usize = 8;
+/// The size of an entry of the `.sechash` section: the section name followed by its SHA 256 hash.
+const SECTION_HASH_ENTRY_SIZE: usize = SECTION_HASH_NAME_SIZE + 32;
+
+/// Sections covered by the `.selfh` section, in the order in which they are hashed.
+///
+/// The stub recomputes the hash over the same sections of its image in memory. It includes this
+/// list from `linux-bootloader/src/self_hash_sections.rs`, which is generated from it, see
+/// `self_hash_sections_match_stub`. Sections that are relocated by the firmware cannot be covered.
+const SELF_HASH_SECTIONS: [&str; 35] = [
+    ".text", ".osrel", ".cmdline", ".initrd", ".linux", ".initrdh", ".linuxh", ".serial",
+    ".timeout", ".initrdv", ".deploy", ".cmdlbl", ".cmdl1", ".cmdl2", ".cmdl3", ".cmdl4", ".cmdl5",
+    ".cmdl6", ".cmdl7", ".cmdl8", ".cmdl9", ".fwmin", ".measure", ".smbios", ".confirm", ".chain",
+    ".loadopt", ".fwsetup", ".cmdlchk", ".addons", ".initrdr", ".kfail", ".tpmnv", ".pcrshow",
+    ".irdpath",
+];
+
+/// The header fields of an assembled image that firmware looks at.
+///
+/// They are set explicitly instead of being inherited from the stub, because some firmware
+/// refuses to load images whose header does not describe an EFI application.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeHeaderFields {
+    /// The `Subsystem` of the optional header.
+    pub subsystem: u16,
+    /// Bits that are set in the `DllCharacteristics` of the optional header. The other bits are
+    /// kept as they are in the stub.
+    ///
+    /// `IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE` is cleared for images without base relocations,
+    /// because these cannot be loaded at any other address.
+    pub dll_characteristics: u16,
+    /// Bits that are set in the `Characteristics` of the COFF file header. The other bits are
+    /// kept as they are in the stub.
+    pub characteristics: u16,
+}
+
+impl Default for PeHeaderFields {
+    fn default() -> Self {
+        Self {
+            subsystem: IMAGE_SUBSYSTEM_EFI_APPLICATION,
+            dll_characteristics: IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE
+                | IMAGE_DLLCHARACTERISTICS_NX_COMPAT,
+            characteristics: IMAGE_FILE_EXECUTABLE_IMAGE | IMAGE_FILE_LARGE_ADDRESS_AWARE,
+        }
+    }
+}
+
+/// Assemble the lanzaboote image described by `config`, embedding `os_release` as its `.osrel`
+/// section.
 pub fn lanzaboote_image(
     // Because the returned path of this function is inside the tempdir as well, the tempdir must
     // live longer than the function. This is why it cannot be created inside the function.
     tempdir: &TempDir,
-    lanzaboote_stub: &Path,
+    config: &UkiConfig,
     os_release: &Path,
-    kernel_cmdline: &[String],
-    kernel_source: &Path,
-    kernel_target: &Path,
-    initrd_source: &Path,
-    initrd_target: &Path,
-    esp: &Path,
 ) -> Result<PathBuf> {
+    let options = &config.stub_options;
+    let kernel_cmdline = &config.kernel_cmdline;
+    let cmdline_variants = &config.cmdline_variants;
+    let esp = config.esp.as_path();
+    let initrd = match (&config.initrd, &config.initrd_target) {
+        (Some(initrd), Some(initrd_target)) => Some((initrd.as_path(), initrd_target.as_path())),
+        (None, None) => None,
+        _ => anyhow::bail!("The initrd and where it is installed must be set together"),
+    };
+
     // objcopy can only copy files into the PE binary. That's why we
     // have to write the contents of some bootspec properties to disk.
     let kernel_cmdline_file = tempdir.write_secure_file(kernel_cmdline.join(" "))?;
 
     let kernel_path_file =
-        tempdir.write_secure_file(esp_relative_uefi_path(esp, kernel_target)?)?;
-    let kernel_hash_file = tempdir.write_secure_file(file_hash(kernel_source)?.as_slice())?;
-
-    let initrd_path_file =
-        tempdir.write_secure_file(esp_relative_uefi_path(esp, initrd_target)?)?;
-    let initrd_hash_file = tempdir.write_secure_file(file_hash(initrd_source)?.as_slice())?;
-
-    let os_release_offs = stub_offset(lanzaboote_stub)?;
-    let kernel_cmdline_offs = os_release_offs + file_size(os_release)?;
-    let initrd_path_offs = kernel_cmdline_offs + file_size(&kernel_cmdline_file)?;
-    let kernel_path_offs = initrd_path_offs + file_size(&initrd_path_file)?;
-    let initrd_hash_offs = kernel_path_offs + file_size(&kernel_path_file)?;
-    let kernel_hash_offs = initrd_hash_offs + file_size(&initrd_hash_file)?;
-
-    let sections = vec![
-        s(".osrel", os_release, os_release_offs),
-        s(".cmdline", kernel_cmdline_file, kernel_cmdline_offs),
-        s(".initrd", initrd_path_file, initrd_path_offs),
-        s(".linux", kernel_path_file, kernel_path_offs),
-        s(".initrdh", initrd_hash_file, initrd_hash_offs),
-        s(".linuxh", kernel_hash_file, kernel_hash_offs),
-    ];
+        tempdir.write_secure_file(esp_relative_uefi_path(esp, &config.kernel_target)?)?;
+    let kernel_hash_file = tempdir.write_secure_file(file_hash(&config.kernel)?.as_slice())?;
+
+    // Without the initrd sections, the stub boots the kernel without an initrd.
+    let (initrd_path_file, initrd_hash_file) = initrd
+        .map(|(initrd_source, initrd_target)| -> Result<_> {
+            Ok((
+                tempdir.write_secure_file(esp_relative_uefi_path(esp, initrd_target)?)?,
+                tempdir.write_secure_file(file_hash(initrd_source)?.as_slice())?,
+            ))
+        })
+        .transpose()?
+        .unzip();
+
+    let mut contents = [
+        Some((".osrel", os_release.to_path_buf())),
+        Some((".cmdline", kernel_cmdline_file)),
+        initrd_path_file.map(|file| (".initrd", file)),
+        Some((".linux", kernel_path_file)),
+        initrd_hash_file.map(|file| (".initrdh", file)),
+        Some((".linuxh", kernel_hash_file)),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+
+    // The stub offers the variants in a boot menu. `.cmdlbl` lists the labels of all command
+    // lines, starting with the default one in `.cmdline`.
+    if !cmdline_variants.is_empty() {
+        if cmdline_variants.len() > MAX_CMDLINE_VARIANTS {
+            anyhow::bail!("At most {MAX_CMDLINE_VARIANTS} command line variants are supported");
+        }
+        let labels = std::iter::once(DEFAULT_CMDLINE_LABEL)
+            .chain(cmdline_variants.iter().map(|v| v.label.as_str()))
+            .collect::<Vec<_>>();
+        contents.push((".cmdlbl", tempdir.write_secure_file(labels.join("\n"))?));
+        for (index, variant) in cmdline_variants.iter().enumerate() {
+            let cmdline = format!("{} {}", kernel_cmdline.join(" "), variant.extra_params);
+            contents.push((
+                CMDLINE_SECTIONS[index + 1],
+                tempdir.write_secure_file(cmdline)?,
+            ));
+        }
+    }
+
+    // The stub duplicates its log output to the serial device named in this section.
+    if let Some(serial_console) = &options.serial_console {
+        contents.push((".serial", tempdir.write_secure_file(serial_console)?));
+    }
+
+    // The stub waits for this many seconds before booting.
+    if let Some(countdown) = options.countdown {
+        contents.push((
+            ".timeout",
+            tempdir.write_secure_file(countdown.to_string())?,
+        ));
+    }
+
+    // The stub always verifies the initrd if Secure Boot is enabled. Without this section, it
+    // also does so if Secure Boot is disabled.
+    if options.skip_initrd_verification {
+        contents.push((".initrdv", tempdir.write_secure_file("secure-boot")?));
+    }
+
+    // The stub stops the boot if measuring into the TPM fails instead of continuing.
+    if options.strict_measurement {
+        contents.push((".measure", tempdir.write_secure_file("strict")?));
+    }
+
+    // The stub exports the slot and version for update agents and boot counting.
+    if let Some(deploy) = &options.deploy {
+        contents.push((".deploy", tempdir.write_secure_file(deploy.to_json())?));
+    }
+
+    // The stub warns about or refuses firmware older than this.
+    if let Some(firmware_policy) = &options.firmware_policy {
+        contents.push((
+            ".fwmin",
+            tempdir.write_secure_file(firmware_policy.to_section())?,
+        ));
+    }
+
+    // Without Secure Boot, the stub appends the SMBIOS OEM string with this prefix to the command
+    // line.
+    if let Some(prefix) = &options.smbios_cmdline_prefix {
+        contents.push((".smbios", tempdir.write_secure_file(prefix)?));
+    }
+
+    // Without Secure Boot, the stub only boots after the user confirmed it.
+    if options.confirm_insecure_boot {
+        contents.push((".confirm", tempdir.write_secure_file("secure-boot-off")?));
+    }
+
+    // The stub starts this boot loader first, unless it was started by a boot loader itself.
+    if let Some(path) = &config.chainload {
+        contents.push((".chain", tempdir.write_secure_file(path)?));
+    }
+
+    // The stub passes the path of the UKI to the kernel in front of the command line.
+    if options.load_options_image_path {
+        contents.push((".loadopt", tempdir.write_secure_file("image-path")?));
+    }
+
+    // Pressing this key while the stub starts reboots into the firmware setup.
+    if let Some(key) = &options.firmware_setup_key {
+        contents.push((".fwsetup", tempdir.write_secure_file(key)?));
+    }
+
+    // The stub refuses a command line with control characters or beyond the length the kernel
+    // accepts instead of sanitizing it.
+    if options.strict_cmdline {
+        contents.push((".cmdlchk", tempdir.write_secure_file("strict")?));
+    }
+
+    // The stub appends the command lines of signed addons on the ESP.
+    if options.cmdline_addons {
+        contents.push((".addons", tempdir.write_secure_file("cmdline")?));
+    }
+
+    // The stub reads and verifies the initrd a second time if the firmware refused the first
+    // attempt.
+    if options.retry_initrd_verification {
+        contents.push((".initrdr", tempdir.write_secure_file("once")?));
+    }
+
+    // The stub appends this file on the ESP to the initrd after verifying it against its hash.
+    if let Some(initrd_path) = &options.initrd_path {
+        contents.push((".irdpath", tempdir.write_secure_file(initrd_path)?));
+    }
+
+    // If the kernel fails to start, the stub asks systemd-boot to boot the next entry once and
+    // resets.
+    if options.next_entry_on_kernel_failure {
+        contents.push((".kfail", tempdir.write_secure_file("next-entry")?));
+    }
+
+    // The stub reads boot parameters from this TPM NV index, which can only restrict the embedded
+    // configuration.
+    if let Some(index) = options.tpm_nv_index {
+        contents.push((
+            ".tpmnv",
+            tempdir.write_secure_file(format!("{index:#010x}"))?,
+        ));
+    }
+
+    // For debugging measured boot, the stub prints what it measured before starting the kernel.
+    if options.show_measurements {
+        contents.push((".pcrshow", tempdir.write_secure_file("show")?));
+    }
+
+    // Like `.selfh` below, the manifest is a placeholder that is filled in once all other sections
+    // are in place. It has an entry for every section covered by the self hash, including the
+    // `.text` section of the stub.
+    if config.section_hashes {
+        let covered = SELF_HASH_SECTIONS
+            .iter()
+            .filter(|&&name| name == ".text" || contents.iter().any(|(n, _)| *n == name))
+            .count();
+        contents.push((
+            ".sechash",
+            tempdir.write_secure_file(vec![0; covered * SECTION_HASH_ENTRY_SIZE])?,
+        ));
+    }
+
+    // The hash can only be computed once all other sections are in place, so a placeholder is
+    // added first and filled in afterwards. Like `.linuxh` and `.initrdh`, the name is
+    // abbreviated because section names of images are limited to 8 characters.
+    if config.self_hash {
+        contents.push((
+            ".selfh",
+            tempdir.write_secure_file(Hash::default().as_slice())?,
+        ));
+    }
 
     let image_path = tempdir.path().join(tmpname());
-    wrap_in_pe(lanzaboote_stub, sections, &image_path)?;
+    let sections = match config.linux_alignment {
+        Some(alignment) => wrap_in_pe_aligned(
+            tempdir,
+            &config.stub,
+            contents,
+            (".linux", alignment),
+            &image_path,
+        )?,
+        None => {
+            let sections = lay_out_sections(&config.stub, contents, None)?;
+            wrap_in_pe(&config.stub, &sections, &image_path).with_context(|| {
+                format!(
+                    "Failed to assemble the image with the section layout {}",
+                    describe_layout(&sections)
+                )
+            })?;
+            sections
+        }
+    };
+    log::debug!("Section layout: {}", describe_layout(&sections));
+    set_header_fields(&image_path, config.header_fields)
+        .context("Failed to set the header fields of the image")?;
+    if config.section_hashes {
+        embed_section_hashes(&image_path)
+            .context("Failed to embed the hashes of the sections of the image")?;
+    }
+    if config.self_hash {
+        embed_self_hash(&image_path).context("Failed to embed the hash of the image")?;
+    }
     Ok(image_path)
 }
 
+/// Compute the hash of a lanzaboote image that the stub verifies before booting.
+///
+/// This is the SHA 256 hash over the contents of all [`SELF_HASH_SECTIONS`] that are present.
+pub fn self_hash(file_data: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    for name in SELF_HASH_SECTIONS {
+        if let Some(data) = read_section_data(file_data, name) {
+            hasher.update(data);
+        }
+    }
+    hasher.finalize()
+}
+
+/// Compute the contents of the `.sechash` section of a lanzaboote image.
+///
+/// There is an entry for each of the [`SELF_HASH_SECTIONS`] that is present: the section name,
+/// padded with zeros to 8 bytes, followed by the SHA 256 hash of its contents. The stub verifies
+/// every section listed in the manifest before booting.
+pub fn section_hashes(file_data: &[u8]) -> Vec<u8> {
+    let mut manifest = Vec::new();
+    for name in SELF_HASH_SECTIONS {
+        if let Some(data) = read_section_data(file_data, name) {
+            let mut padded_name = [0; SECTION_HASH_NAME_SIZE];
+            padded_name[..name.len()].copy_from_slice(name.as_bytes());
+            manifest.extend_from_slice(&padded_name);
+            manifest.extend_from_slice(&Sha256::digest(data));
+        }
+    }
+    manifest
+}
+
+/// Fill in the `.selfh` section of an image.
+fn embed_self_hash(image: &Path) -> Result<()> {
+    let mut file_data = fs::read(image).with_context(|| format!("Failed to read {image:?}"))?;
+    ensure_not_relocated(&file_data, &SELF_HASH_SECTIONS)?;
+
+    let hash = self_hash(&file_data);
+    fill_section(&mut file_data, ".selfh", &hash)?;
+    fs::write(image, &file_data).with_context(|| format!("Failed to write {image:?}"))
+}
+
+/// Fill in the `.sechash` section of an image.
+fn embed_section_hashes(image: &Path) -> Result<()> {
+    let mut file_data = fs::read(image).with_context(|| format!("Failed to read {image:?}"))?;
+    ensure_not_relocated(&file_data, &SELF_HASH_SECTIONS)?;
+
+    let manifest = section_hashes(&file_data);
+    fill_section(&mut file_data, ".sechash", &manifest)?;
+    fs::write(image, &file_data).with_context(|| format!("Failed to write {image:?}"))
+}
+
+/// Overwrite the placeholder contents of a section with `data` of the same size.
+fn fill_section(file_data: &mut [u8], name: &str, data: &[u8]) -> Result<()> {
+    let pe = PE::parse(file_data).context("Failed to parse PE binary")?;
+    let section = pe
+        .sections
+        .iter()
+        .find(|s| s.name().ok() == Some(name))
+        .with_context(|| format!("PE binary has no {name} section"))?;
+    if usize::try_from(section.virtual_size)? != data.len() {
+        anyhow::bail!(
+            "The {name} section has {} bytes instead of {}",
+            section.virtual_size,
+            data.len()
+        );
+    }
+    let start = usize::try_from(section.pointer_to_raw_data)?;
+
+    file_data[start..start + data.len()].copy_from_slice(data);
+    Ok(())
+}
+
+/// Set the header fields of a PE binary and clear the ones that differ between builds.
+fn set_header_fields(image: &Path, fields: PeHeaderFields) -> Result<()> {
+    let mut file_data = fs::read(image).with_context(|| format!("Failed to read {image:?}"))?;
+    let pe = PE::parse(&file_data).context("Failed to parse PE binary")?;
+    let optional_header = pe
+        .header
+        .optional_header
+        .context("PE binary has no optional header")?;
+
+    let characteristics = pe.header.coff_header.characteristics | fields.characteristics;
+    let mut dll_characteristics =
+        optional_header.windows_fields.dll_characteristics | fields.dll_characteristics;
+    if optional_header
+        .data_directories
+        .get_base_relocation_table()
+        .map_or(true, |table| table.size == 0)
+    {
+        dll_characteristics &= !IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE;
+    }
+
+    let coff_header = pe.header.dos_header.pe_pointer as usize;
+    let optional_header = optional_header_offset(&pe);
+    for (offset, value) in [
+        (coff_header + CHARACTERISTICS_OFFSET, characteristics),
+        (optional_header + SUBSYSTEM_OFFSET, fields.subsystem),
+        (
+            optional_header + DLL_CHARACTERISTICS_OFFSET,
+            dll_characteristics,
+        ),
+    ] {
+        file_data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+    // objcopy records the time of the build, which makes images differ between builds. The
+    // checksum covers the timestamp. Firmware does not verify it, so it is cleared as well.
+    for offset in [
+        coff_header + TIME_DATE_STAMP_OFFSET,
+        optional_header + CHECKSUM_OFFSET,
+    ] {
+        file_data[offset..offset + 4].copy_from_slice(&0u32.to_le_bytes());
+    }
+
+    fs::write(image, &file_data).with_context(|| format!("Failed to write {image:?}"))
+}
+
+/// Refuse to continue if the firmware applies base relocations to any of the given sections.
+///
+/// The contents of such sections differ between the file and the image in memory.
+fn ensure_not_relocated(file_data: &[u8], section_names: &[&str]) -> Result<()> {
+    let pe = PE::parse(file_data).context("Failed to parse PE binary")?;
+    let Some(relocation_table) = pe
+        .header
+        .optional_header
+        .and_then(|h| *h.data_directories.get_base_relocation_table())
+    else {
+        return Ok(());
+    };
+
+    let section_of = |rva: u32| {
+        pe.sections
+            .iter()
+            .find(|s| s.virtual_address <= rva && rva - s.virtual_address < s.virtual_size)
+    };
+    let table_section = section_of(relocation_table.virtual_address)
+        .context("The base relocation table is not part of any section")?;
+    let start = usize::try_from(
+        table_section.pointer_to_raw_data + relocation_table.virtual_address
+            - table_section.virtual_address,
+    )?;
+    let table = file_data
+        .get(start..start + usize::try_from(relocation_table.size)?)
+        .context("The base relocation table is out of bounds")?;
+
+    let mut blocks = table;
+    while blocks.len() >= BASE_RELOCATION_BLOCK_HEADER_SIZE {
+        let page = u32::from_le_bytes(blocks[0..4].try_into()?);
+        let block_size = usize::try_from(u32::from_le_bytes(blocks[4..8].try_into()?))?;
+        let block = blocks
+            .get(BASE_RELOCATION_BLOCK_HEADER_SIZE..block_size)
+            .context("Invalid base relocation block")?;
+
+        for entry in block.chunks_exact(2) {
+            let entry = u16::from_le_bytes([entry[0], entry[1]]);
+            // Entries of type IMAGE_REL_BASED_ABSOLUTE are only padding.
+            if entry >> 12 == 0 {
+                continue;
+            }
+            let relocated = section_of(page + u32::from(entry & 0xfff))
+                .and_then(|s| s.name().ok())
+                .filter(|name| section_names.contains(name));
+            if let Some(name) = relocated {
+                anyhow::bail!("The {name} section of the PE binary contains base relocations");
+            }
+        }
+        blocks = &blocks[block_size..];
+    }
+
+    Ok(())
+}
+
 /// Take a PE binary stub and attach sections to it.
 ///
 /// The resulting binary is then written to a newly created file at the provided output path.
-fn wrap_in_pe(stub: &Path, sections: Vec<Section>, output: &Path) -> Result<()> {
+fn wrap_in_pe(stub: &Path, sections: &[Section], output: &Path) -> Result<()> {
+    ensure_unique_section_names(sections)?;
+
     let mut args: Vec<OsString> = sections.iter().flat_map(Section::to_objcopy).collect();
 
     [stub.as_os_str(), output.as_os_str()]
         .iter()
         .for_each(|a| args.push(a.into()));
 
+    run_objcopy(&args).context("Failed to wrap in pe")?;
+    ensure_aligned(output)
+}
+
+/// Attach sections to a PE binary like [`wrap_in_pe`], but start the section named in `aligned`
+/// at a multiple of the given alignment, both in the file and in memory.
+///
+/// objcopy decides where the contents of the sections go in the file, so a zeroed padding
+/// section is inserted in front of the aligned section and grown until the aligned section ends
+/// up at the right file offset. The padding is a section of its own instead of a gap between
+/// sections, because Authenticode implementations disagree on whether gaps are hashed.
+fn wrap_in_pe_aligned(
+    tempdir: &TempDir,
+    stub: &Path,
+    contents: Vec<(&'static str, PathBuf)>,
+    aligned: (&'static str, u64),
+    output: &Path,
+) -> Result<Vec<Section>> {
+    let (name, alignment) = aligned;
+    let stub_data = fs::read(stub).context("Failed to read PE binary file")?;
+    let stub_alignments =
+        alignments(&PE::parse(&stub_data).context("Failed to parse PE binary file")?)?;
+    let minimum = u64::from(stub_alignments.section.max(stub_alignments.file));
+    if !alignment.is_power_of_two() || alignment < minimum {
+        anyhow::bail!(
+            "The alignment of the {name} section must be a power of two of at least {minimum:#x}, not {alignment:#x}"
+        );
+    }
+
+    let mut padding = 0;
+    // The first attempt shows how far off the section is. Adding the padding section can grow
+    // the headers, which the third attempt corrects.
+    for _ in 0..3 {
+        let mut contents = contents.clone();
+        if padding > 0 {
+            let index = contents
+                .iter()
+                .position(|(n, _)| *n == name)
+                .with_context(|| format!("There is no {name} section to align"))?;
+            let padding_file = tempdir.write_secure_file(vec![0; usize::try_from(padding)?])?;
+            contents.insert(index, (PADDING_SECTION, padding_file));
+        }
+        let sections = lay_out_sections(stub, contents, Some(aligned))?;
+        wrap_in_pe(stub, &sections, output).with_context(|| {
+            format!(
+                "Failed to assemble the image with the section layout {}",
+                describe_layout(&sections)
+            )
+        })?;
+
+        let file_data = fs::read(output).with_context(|| format!("Failed to read {output:?}"))?;
+        let pe = PE::parse(&file_data).context("Failed to parse PE binary")?;
+        let file_offset = pe
+            .sections
+            .iter()
+            .find(|s| s.name().ok() == Some(name))
+            .with_context(|| format!("There is no {name} section to align"))?
+            .pointer_to_raw_data;
+        let misalignment = u64::from(file_offset) % alignment;
+        if misalignment == 0 {
+            return Ok(sections);
+        }
+        padding += alignment - misalignment;
+    }
+    anyhow::bail!("Failed to align the {name} section to {alignment:#x} in the file")
+}
+
+/// Place sections after the last section of a PE binary.
+///
+/// Each section starts at a multiple of the section alignment declared by the binary, and the
+/// section named in `aligned` at a multiple of the given alignment. objcopy takes care of
+/// aligning the section contents in the file.
+fn lay_out_sections(
+    binary: &Path,
+    contents: Vec<(&'static str, PathBuf)>,
+    aligned: Option<(&str, u64)>,
+) -> Result<Vec<Section>> {
+    let pe_binary = fs::read(binary).context("Failed to read PE binary file")?;
+    let pe = PE::parse(&pe_binary).context("Failed to parse PE binary file")?;
+    let section_alignment = u64::from(alignments(&pe)?.section);
+
+    place_sections(
+        stub_offset(&pe),
+        image_base(&pe),
+        section_alignment,
+        contents,
+        aligned,
+    )
+}
+
+/// Place sections one after the other, starting at the virtual address `offset`.
+///
+/// Virtual addresses in a PE binary are 32 bit offsets from the image base, so every section
+/// has to end within 4 GiB of it.
+fn place_sections(
+    mut offset: u64,
+    image_base: u64,
+    section_alignment: u64,
+    contents: Vec<(&'static str, PathBuf)>,
+    aligned: Option<(&str, u64)>,
+) -> Result<Vec<Section>> {
+    let mut sections = Vec::new();
+    for (name, file_path) in contents {
+        let alignment = match aligned {
+            Some((aligned_name, alignment)) if aligned_name == name => alignment,
+            _ => section_alignment,
+        };
+        // Alignments apply to the address relative to the image base.
+        offset = image_base + (offset - image_base).next_multiple_of(alignment);
+        let size = file_size(&file_path)?;
+        if offset + size - image_base > u64::from(u32::MAX) {
+            anyhow::bail!(
+                "The {name} section of {size:#x} bytes does not fit into the image after the \
+                section layout {}",
+                describe_layout(&sections)
+            );
+        }
+        sections.push(s(name, file_path, offset, size));
+        offset += size;
+    }
+    Ok(sections)
+}
+
+/// Describe where sections are placed, for error messages.
+fn describe_layout(sections: &[Section]) -> String {
+    if sections.is_empty() {
+        return String::from("(no sections)");
+    }
+    sections
+        .iter()
+        .map(|section| {
+            format!(
+                "{} at {:#x} ({:#x} bytes)",
+                section.name, section.offset, section.size
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Refuse PE binaries whose sections are not aligned as declared in the optional header.
+///
+/// Strict firmware refuses to load such binaries.
+fn ensure_aligned(binary: &Path) -> Result<()> {
+    let pe_binary = fs::read(binary).context("Failed to read PE binary file")?;
+    let pe = PE::parse(&pe_binary).context("Failed to parse PE binary file")?;
+    let alignments = alignments(&pe)?;
+
+    for section in &pe.sections {
+        let name = section.name().unwrap_or_default();
+        if section.virtual_address % alignments.section != 0 {
+            anyhow::bail!(
+                "The {name} section of {binary:?} is not aligned to the section alignment of {:#x}",
+                alignments.section
+            );
+        }
+        if section.size_of_raw_data != 0 && section.pointer_to_raw_data % alignments.file != 0 {
+            anyhow::bail!(
+                "The {name} section of {binary:?} is not aligned to the file alignment of {:#x}",
+                alignments.file
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The alignments declared in the optional header of a PE binary.
+struct Alignments {
+    section: u32,
+    file: u32,
+}
+
+fn alignments(pe: &PE) -> Result<Alignments> {
+    let windows_fields = pe
+        .header
+        .optional_header
+        .context("PE binary has no optional header")?
+        .windows_fields;
+    if windows_fields.section_alignment == 0 || windows_fields.file_alignment == 0 {
+        anyhow::bail!("PE binary declares an alignment of zero");
+    }
+    Ok(Alignments {
+        section: windows_fields.section_alignment,
+        file: windows_fields.file_alignment,
+    })
+}
+
+/// Refuse to add the same section more than once.
+///
+/// The stub uses the first section with a given name, so any further sections with the same name
+/// would be silently ignored.
+fn ensure_unique_section_names(sections: &[Section]) -> Result<()> {
+    let mut names = HashSet::new();
+    for section in sections {
+        if !names.insert(section.name) {
+            anyhow::bail!(
+                "Refusing to assemble a PE binary with duplicate {} sections",
+                section.name
+            );
+        }
+    }
+    Ok(())
+}
+
+fn run_objcopy(args: &[OsString]) -> Result<()> {
     let status = Command::new("objcopy")
-        .args(&args)
+        .args(args)
         .status()
         .context("Failed to run objcopy. Most likely, the binary is not on PATH.")?;
     if !status.success() {
         return Err(anyhow::anyhow!(
-            "Failed to wrap in pe with args `{:?}`",
-            &args
+            "Failed to run objcopy with args `{:?}`",
+            args
         ));
     }
 
     Ok(())
 }
 
+/// Replace the `.cmdline` section of an existing (possibly signed) UKI and sign the result.
+///
+/// The signature of the input is discarded. If the new command line fits into the existing
+/// section, the section is rewritten in place, padded with zeroes. Otherwise, the old section is
+/// removed and a new one is appended after the last section, which leaves all other sections
+/// untouched. Intermediate files are kept in `work_dir`, see [`create_tempdir`].
+pub fn set_cmdline(
+    signer: &impl Signer,
+    uki: &Path,
+    new_cmdline: &str,
+    out: &Path,
+    work_dir: Option<&Path>,
+) -> Result<()> {
+    let tempdir = create_tempdir(work_dir)?;
+    let mut file_data = fs::read(uki).with_context(|| format!("Failed to read UKI {uki:?}"))?;
+    remove_certificate_table(&mut file_data)?;
+
+    let unsigned_image = if replace_section_in_place(&mut file_data, ".cmdline", new_cmdline)? {
+        tempdir.write_secure_file(&file_data)?
+    } else {
+        let unsigned_uki = tempdir.write_secure_file(&file_data)?;
+        let cmdline_file = tempdir.write_secure_file(new_cmdline)?;
+        let sections = lay_out_sections(&unsigned_uki, vec![(".cmdline", cmdline_file)], None)?;
+
+        let image_path = tempdir.path().join(tmpname());
+        let mut args: Vec<OsString> = vec!["--remove-section".into(), ".cmdline".into()];
+        args.extend(sections.iter().flat_map(Section::to_objcopy));
+        args.extend([unsigned_uki.into_os_string(), image_path.clone().into()]);
+        run_objcopy(&args)?;
+        ensure_aligned(&image_path)?;
+        image_path
+    };
+
+    signer
+        .sign_and_copy(&unsigned_image, out)
+        .with_context(|| format!("Failed to sign UKI with new command line to {out:?}"))
+}
+
+/// Build a signed command line addon from the addon stub of systemd.
+///
+/// An addon is a PE binary without a kernel whose `.cmdline` section the stub appends to the
+/// command line of the UKIs it boots. The `.sbat` section of the addon stub is kept and the
+/// entries in `sbat` are appended to it, so that the addon can be revoked on its own.
+/// Intermediate files are kept in `work_dir`, see [`create_tempdir`].
+pub fn build_addon(
+    signer: &dyn Signer,
+    addon_stub: &Path,
+    cmdline: &str,
+    sbat: Option<&str>,
+    out: &Path,
+    work_dir: Option<&Path>,
+) -> Result<()> {
+    let tempdir = create_tempdir(work_dir)?;
+    let mut stub_data = fs::read(addon_stub)
+        .with_context(|| format!("Failed to read addon stub {addon_stub:?}"))?;
+    // A signature of the stub would no longer match once the sections are added.
+    remove_certificate_table(&mut stub_data)?;
+    for section in [".linux", ".cmdline"] {
+        if read_section_data(&stub_data, section).is_some() {
+            anyhow::bail!("The addon stub {addon_stub:?} already has a {section} section");
+        }
+    }
+    let stub_sbat = read_section_data(&stub_data, ".sbat").map(|data| {
+        String::from_utf8_lossy(data)
+            .trim_end_matches('\0')
+            .to_owned()
+    });
+
+    let mut args: Vec<OsString> = Vec::new();
+    let mut contents = vec![(".cmdline", tempdir.write_secure_file(cmdline)?)];
+    match (stub_sbat, sbat) {
+        (Some(stub_sbat), Some(sbat)) => {
+            args.extend(["--remove-section".into(), ".sbat".into()]);
+            contents.push((
+                ".sbat",
+                tempdir.write_secure_file(merge_sbat(&stub_sbat, sbat))?,
+            ));
+        }
+        (Some(_), None) => {}
+        (None, _) => anyhow::bail!(
+            "The addon stub {addon_stub:?} has no .sbat section, shim refuses to load the addon"
+        ),
+    }
+
+    let unsigned_stub = tempdir.write_secure_file(&stub_data)?;
+    let sections = lay_out_sections(&unsigned_stub, contents, None)?;
+    let image_path = tempdir.path().join(tmpname());
+    args.extend(sections.iter().flat_map(Section::to_objcopy));
+    args.extend([unsigned_stub.into_os_string(), image_path.clone().into()]);
+    run_objcopy(&args).context("Failed to assemble the addon")?;
+    ensure_aligned(&image_path)?;
+
+    signer
+        .sign_and_copy(&image_path, out)
+        .with_context(|| format!("Failed to sign addon to {out:?}"))
+}
+
+/// Append the SBAT entries in `extra` to those in `base`.
+///
+/// The `sbat,` line stating the version of the format is only kept from `base`, and every entry
+/// ends with a newline.
+fn merge_sbat(base: &str, extra: &str) -> String {
+    base.lines()
+        .chain(extra.lines().filter(|line| !line.starts_with("sbat,")))
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| format!("{line}\n"))
+        .collect()
+}
+
+/// Overwrite the contents of a section if the new contents are not larger than the old ones.
+///
+/// Returns whether the section was replaced.
+fn replace_section_in_place(file_data: &mut [u8], name: &str, contents: &str) -> Result<bool> {
+    let pe = PE::parse(file_data).context("Failed to parse PE binary")?;
+    let (index, section) = pe
+        .sections
+        .iter()
+        .enumerate()
+        .find(|(_, s)| s.name().ok() == Some(name))
+        .with_context(|| format!("PE binary has no {name} section"))?;
+
+    // Only the raw data is stored in the file, the rest of the section is zero-filled by the
+    // loader.
+    let capacity = usize::try_from(section.virtual_size.min(section.size_of_raw_data))?;
+    if contents.len() > capacity {
+        return Ok(false);
+    }
+    let start = usize::try_from(section.pointer_to_raw_data)?;
+    let header = section_header_offset(&pe, index) + SECTION_VIRTUAL_SIZE_OFFSET;
+
+    let data = start
+        .checked_add(capacity)
+        .and_then(|end| file_data.get_mut(start..end))
+        .with_context(|| format!("The {name} section extends beyond the end of the file"))?;
+    data.fill(0);
+    data[..contents.len()].copy_from_slice(contents.as_bytes());
+
+    file_data[header..header + 4].copy_from_slice(&u32::try_from(contents.len())?.to_le_bytes());
+    Ok(true)
+}
+
+/// Strip the Authenticode signature from a PE binary.
+///
+/// The certificate table is expected at the end of the file, where signing tools put it.
+pub(crate) fn remove_certificate_table(file_data: &mut Vec<u8>) -> Result<()> {
+    let pe = PE::parse(file_data).context("Failed to parse PE binary")?;
+    let optional_header = pe
+        .header
+        .optional_header
+        .context("PE binary has no optional header")?;
+    let Some(certificate_table) = optional_header.data_directories.get_certificate_table() else {
+        return Ok(());
+    };
+
+    let start = usize::try_from(certificate_table.virtual_address)?;
+    let end = start + usize::try_from(certificate_table.size)?;
+    if end != file_data.len() {
+        anyhow::bail!("The certificate table is not located at the end of the PE binary");
+    }
+
+    set_certificate_table(file_data, 0, 0)?;
+    file_data.truncate(start);
+    Ok(())
+}
+
+/// Point the certificate table data directory entry at `size` bytes at file offset `address`.
+pub(crate) fn set_certificate_table(file_data: &mut [u8], address: u32, size: u32) -> Result<()> {
+    let pe = PE::parse(file_data).context("Failed to parse PE binary")?;
+    let optional_header = pe
+        .header
+        .optional_header
+        .context("PE binary has no optional header")?;
+
+    let data_directories_offset =
+        if optional_header.standard_fields.magic == goblin::pe::optional_header::MAGIC_64 {
+            DATA_DIRECTORIES_OFFSET_PE32_PLUS
+        } else {
+            DATA_DIRECTORIES_OFFSET_PE32
+        };
+    let entry = optional_header_offset(&pe)
+        + data_directories_offset
+        + CERTIFICATE_TABLE_INDEX * goblin::pe::data_directories::SIZEOF_DATA_DIRECTORY;
+
+    file_data[entry..entry + 4].copy_from_slice(&address.to_le_bytes());
+    file_data[entry + 4..entry + 8].copy_from_slice(&size.to_le_bytes());
+    Ok(())
+}
+
+fn optional_header_offset(pe: &PE) -> usize {
+    pe.header.dos_header.pe_pointer as usize + COFF_HEADER_END
+}
+
+fn section_header_offset(pe: &PE, index: usize) -> usize {
+    optional_header_offset(pe)
+        + usize::from(pe.header.coff_header.size_of_optional_header)
+        + index * goblin::pe::section_table::SIZEOF_SECTION_TABLE
+}
+
+#[derive(Debug)]
 struct Section {
     name: &'static str,
     file_path: PathBuf,
     offset: u64,
+    size: u64,
 }
 
 impl Section {
@@ -106,16 +965,27 @@ impl Section {
     }
 }
 
-fn s(name: &'static str, file_path: impl AsRef<Path>, offset: u64) -> Section {
+fn s(name: &'static str, file_path: impl AsRef<Path>, offset: u64, size: u64) -> Section {
     Section {
         name,
         file_path: file_path.as_ref().into(),
         offset,
+        size,
     }
 }
 
+/// The contents of the `.irdpath` section for `file` on the ESP: its UEFI path and its SHA-256
+/// hash in hex.
+pub fn initrd_path_section(esp: &Path, file: &Path) -> Result<String> {
+    let hash = file_hash(file)?
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    Ok(format!("{}\n{hash}", esp_relative_uefi_path(esp, file)?))
+}
+
 /// Convert a path to an UEFI path relative to the specified ESP.
-fn esp_relative_uefi_path(esp: &Path, path: &Path) -> Result<String> {
+pub fn esp_relative_uefi_path(esp: &Path, path: &Path) -> Result<String> {
     let relative_path = path
         .strip_prefix(esp)
         .with_context(|| format!("Failed to strip esp prefix: {:?} from: {:?}", esp, path))?;
@@ -134,20 +1004,17 @@ fn uefi_path(path: &Path) -> Result<String> {
         .with_context(|| format!("Failed to convert {:?} to an UEFI path", path))
 }
 
-fn stub_offset(binary: &Path) -> Result<u64> {
-    let pe_binary = fs::read(binary).context("Failed to read PE binary file")?;
-    let pe = PE::parse(&pe_binary).context("Failed to parse PE binary file")?;
-
-    let image_base = image_base(&pe);
+fn stub_offset(pe: &PE) -> u64 {
+    let image_base = image_base(pe);
 
     // The Virtual Memory Address (VMA) is relative to the image base, aka the image base
     // needs to be added to the virtual address to get the actual (but still virtual address)
-    Ok(u64::from(
+    u64::from(
         pe.sections
             .last()
             .map(|s| s.virtual_size + s.virtual_address)
             .expect("Failed to calculate offset"),
-    ) + image_base)
+    ) + image_base
 }
 
 fn image_base(pe: &PE) -> u64 {
@@ -179,18 +1046,295 @@ pub fn read_section_data<'a>(file_data: &'a [u8], section_name: &str) -> Option<
         .sections
         .iter()
         .find(|s| s.name().unwrap() == section_name)
-        .and_then(|s| {
-            let section_start: usize = s.pointer_to_raw_data.try_into().ok()?;
-            assert!(s.virtual_size <= s.size_of_raw_data);
-            let section_end: usize = section_start + usize::try_from(s.virtual_size).ok()?;
-            Some(&file_data[section_start..section_end])
+        .and_then(|s| section_data(file_data, s))
+}
+
+/// Read the data of the section with the header `section` from a PE binary.
+///
+/// Unlike [`read_section_data`], this also works for sections whose name is not unique.
+pub fn section_data<'a>(file_data: &'a [u8], section: &SectionTable) -> Option<&'a [u8]> {
+    let section_start: usize = section.pointer_to_raw_data.try_into().ok()?;
+    assert!(section.virtual_size <= section.size_of_raw_data);
+    let section_end: usize = section_start + usize::try_from(section.virtual_size).ok()?;
+    Some(&file_data[section_start..section_end])
+}
+
+/// Make sure that a kernel can be started by the stub, i.e. that it was built with
+/// `CONFIG_EFI_STUB`.
+///
+/// Such kernels are PE binaries with the EFI application subsystem. The kernel also has to be
+/// built for the architecture the stub is installed for.
+pub fn ensure_efi_kernel(kernel: &Path, arch: Architecture) -> Result<()> {
+    let file_data =
+        fs::read(kernel).with_context(|| format!("Failed to read kernel {kernel:?}"))?;
+    let pe = PE::parse(&file_data).with_context(|| {
+        format!("The kernel {kernel:?} is not a PE binary. Is it built with CONFIG_EFI_STUB?")
+    })?;
+    let subsystem = pe
+        .header
+        .optional_header
+        .with_context(|| format!("The kernel {kernel:?} has no optional header"))?
+        .windows_fields
+        .subsystem;
+    if subsystem != IMAGE_SUBSYSTEM_EFI_APPLICATION {
+        anyhow::bail!(
+            "The kernel {kernel:?} is not an EFI application (subsystem {subsystem}, expected {IMAGE_SUBSYSTEM_EFI_APPLICATION})"
+        );
+    }
+    let kernel_arch = arch_of_pe(&file_data)
+        .with_context(|| format!("Unsupported architecture of the kernel {kernel:?}"))?;
+    if kernel_arch != arch {
+        anyhow::bail!("The kernel {kernel:?} is built for {kernel_arch:?}, not for {arch:?}");
+    }
+    Ok(())
+}
+
+/// Make sure that the stub is built for the architecture it is installed for.
+///
+/// A stub for another architecture would be signed and installed, but the firmware could not
+/// start it.
+pub fn ensure_stub_arch(stub: &Path, arch: Architecture) -> Result<()> {
+    let file_data = fs::read(stub).with_context(|| format!("Failed to read stub {stub:?}"))?;
+    let stub_arch = arch_of_pe(&file_data)
+        .with_context(|| format!("Failed to determine the architecture of the stub {stub:?}"))?;
+    if stub_arch != arch {
+        anyhow::bail!("The stub {stub:?} is built for {stub_arch:?}, not for {arch:?}");
+    }
+    Ok(())
+}
+
+/// Determine the architecture of a PE binary from the `Machine` field of its COFF header.
+pub fn arch_of_pe(file_data: &[u8]) -> Result<Architecture> {
+    let pe = PE::parse(file_data).context("Failed to parse PE binary")?;
+    Architecture::from_pe_machine(pe.header.coff_header.machine)
+}
+
+/// Compute the Authenticode digest of a PE binary.
+///
+/// This is the SHA 256 hash over the whole binary except for the checksum, the certificate table
+/// data directory entry and the certificate table itself. It does not change when a binary is
+/// (re-)signed and is the hash that the firmware compares against the entries of db and dbx.
+pub fn authenticode_digest(file_data: &[u8]) -> Result<Hash> {
+    authenticode_digest_reader(Cursor::new(file_data))
+}
+
+/// Compute the Authenticode digest of the PE binary at `path` without reading it into memory as
+/// a whole. See [`authenticode_digest`].
+pub fn file_authenticode_digest(path: &Path) -> Result<Hash> {
+    let file = File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+    authenticode_digest_reader(file)
+        .with_context(|| format!("Failed to compute Authenticode digest of {path:?}"))
+}
+
+/// Compute the Authenticode digest of a PE binary, reading it in chunks. See
+/// [`authenticode_digest`].
+pub fn authenticode_digest_reader(mut reader: impl Read + Seek) -> Result<Hash> {
+    let layout = AuthenticodeLayout::read(&mut reader)?;
+    let mut hasher = Sha256::new();
+    layout.hash(&mut reader, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// The location of the parts of a PE binary that are not covered by its Authenticode digest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct AuthenticodeLayout {
+    /// File offset of the `CheckSum` field of the optional header.
+    checksum: u64,
+    /// File offset of the certificate table data directory entry.
+    pub(crate) certificate_table_entry: u64,
+    /// The certificate table, if there is one.
+    pub(crate) certificate_table: Option<Range<u64>>,
+    /// Size of the whole binary.
+    pub(crate) len: u64,
+}
+
+impl AuthenticodeLayout {
+    /// Read the layout from the headers of a PE binary.
+    pub(crate) fn read(reader: &mut (impl Read + Seek)) -> Result<Self> {
+        let len = reader
+            .seek(SeekFrom::End(0))
+            .context("Failed to determine size of PE binary")?;
+
+        let mut dos_header = [0; DOS_HEADER_SIZE];
+        read_at(reader, 0, &mut dos_header).context("Failed to read DOS header")?;
+        if dos_header[..2] != *b"MZ" {
+            anyhow::bail!("Not a PE binary: the DOS signature is missing");
+        }
+        let pe_pointer = u64::from(u32::from_le_bytes(
+            dos_header[PE_POINTER_OFFSET..PE_POINTER_OFFSET + 4].try_into()?,
+        ));
+
+        let mut header = [0; COFF_HEADER_END + 2];
+        read_at(reader, pe_pointer, &mut header).context("Failed to read PE header")?;
+        if header[..4] != *b"PE\0\0" {
+            anyhow::bail!("Not a PE binary: the PE signature is missing");
+        }
+        let optional_header = pe_pointer + COFF_HEADER_END as u64;
+        let magic = u16::from_le_bytes([header[COFF_HEADER_END], header[COFF_HEADER_END + 1]]);
+        let data_directories = optional_header
+            + match magic {
+                goblin::pe::optional_header::MAGIC_64 => DATA_DIRECTORIES_OFFSET_PE32_PLUS,
+                goblin::pe::optional_header::MAGIC_32 => DATA_DIRECTORIES_OFFSET_PE32,
+                _ => anyhow::bail!("Unknown optional header magic {magic:#x}"),
+            } as u64;
+
+        // `NumberOfRvaAndSizes` directly precedes the data directories.
+        let mut count = [0; 4];
+        read_at(reader, data_directories - 4, &mut count)
+            .context("Failed to read number of data directories")?;
+        let certificate_table_entry = data_directories
+            + (CERTIFICATE_TABLE_INDEX * goblin::pe::data_directories::SIZEOF_DATA_DIRECTORY)
+                as u64;
+        let mut entry = [0; 8];
+        read_at(reader, certificate_table_entry, &mut entry)
+            .context("Failed to read certificate table data directory entry")?;
+        let address = u64::from(u32::from_le_bytes(entry[..4].try_into()?));
+        let size = u64::from(u32::from_le_bytes(entry[4..].try_into()?));
+
+        let has_certificate_table = u32::from_le_bytes(count) as usize > CERTIFICATE_TABLE_INDEX
+            && (address, size) != (0, 0);
+        let certificate_table = has_certificate_table.then_some(address..address + size);
+        if let Some(table) = &certificate_table {
+            if table.start < certificate_table_entry + 8 || table.end > len {
+                anyhow::bail!("The certificate table is outside of the PE binary");
+            }
+        }
+
+        Ok(Self {
+            checksum: optional_header + CHECKSUM_OFFSET as u64,
+            certificate_table_entry,
+            certificate_table,
+            len,
         })
+    }
+
+    /// The ranges of the binary that are covered by the digest, in order.
+    fn ranges(&self) -> [Range<u64>; 4] {
+        let (before, after) = match &self.certificate_table {
+            Some(table) => (table.start, table.end),
+            None => (self.len, self.len),
+        };
+        [
+            0..self.checksum,
+            self.checksum + 4..self.certificate_table_entry,
+            self.certificate_table_entry + 8..before,
+            after..self.len,
+        ]
+    }
+
+    /// Feed the parts of the binary that are covered by the digest to `hasher`.
+    pub(crate) fn hash(&self, reader: &mut (impl Read + Seek), hasher: &mut Sha256) -> Result<()> {
+        let mut buffer = vec![0; AUTHENTICODE_CHUNK_SIZE];
+        for range in self.ranges() {
+            reader.seek(SeekFrom::Start(range.start))?;
+            let mut remaining = range.end - range.start;
+            while remaining > 0 {
+                let chunk = &mut buffer[..remaining.min(AUTHENTICODE_CHUNK_SIZE as u64) as usize];
+                reader
+                    .read_exact(chunk)
+                    .context("Failed to read PE binary")?;
+                hasher.update(&chunk);
+                remaining -= chunk.len() as u64;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Copy the PE binary `from` to `to` without its certificate table, i.e. without any signatures.
+///
+/// The copy is padded to the alignment of certificate tables and its certificate table data
+/// directory entry is zeroed, so that a new certificate table can be appended right away. Returns
+/// the layout of `from` and the size of the copy.
+///
+/// Signing tools put the certificate table at the end of the binary. Binaries with data after
+/// the certificate table are rejected, because removing the table from them would shift that
+/// data.
+pub(crate) fn copy_unsigned(
+    from: &mut (impl Read + Seek),
+    to: &mut (impl Write + Seek),
+) -> Result<(AuthenticodeLayout, u64)> {
+    let layout = AuthenticodeLayout::read(from)?;
+    let content_len = match &layout.certificate_table {
+        Some(table) if table.end != layout.len => anyhow::bail!(
+            "The certificate table at {:#x}..{:#x} is not located at the end of the PE binary ({:#x} bytes)",
+            table.start,
+            table.end,
+            layout.len
+        ),
+        Some(table) => table.start,
+        None => layout.len,
+    };
+
+    from.seek(SeekFrom::Start(0))?;
+    let copied = io::copy(&mut from.take(content_len), to)?;
+    if copied != content_len {
+        anyhow::bail!("The PE binary changed while it was copied");
+    }
+    // The padding in front of a certificate table is covered by the digest.
+    let len = content_len.next_multiple_of(CERTIFICATE_TABLE_ALIGNMENT);
+    to.write_all(&vec![0; (len - content_len) as usize])?;
+    to.seek(SeekFrom::Start(layout.certificate_table_entry))?;
+    to.write_all(&[0; 8])?;
+    to.seek(SeekFrom::Start(len))?;
+    Ok((layout, len))
+}
+
+/// Fill `buffer` with the bytes at `offset`.
+fn read_at(reader: &mut (impl Read + Seek), offset: u64, buffer: &mut [u8]) -> Result<()> {
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(buffer)?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// The list of sections covered by `.selfh` that the stub includes.
+    fn stub_self_hash_sections() -> String {
+        let sections = SELF_HASH_SECTIONS
+            .iter()
+            .map(|name| format!("    \"{name}\",\n"))
+            .collect::<String>();
+        format!(
+            "//! Sections covered by the optional `.selfh` section.\n\
+             //!\n\
+             //! Generated from the list in lzbt by the test `self_hash_sections_match_stub` in\n\
+             //! `rust/tool/shared/src/pe.rs`. Do not edit, run it with\n\
+             //! `UPDATE_SELF_HASH_SECTIONS=1` instead.\n\
+             \n\
+             /// Sections covered by the optional `.selfh` section, in the order in\n\
+             /// which they are hashed.\n\
+             #[rustfmt::skip]\n\
+             pub const SELF_HASH_SECTIONS: [&str; {}] = [\n\
+             {sections}\
+             ];\n",
+            SELF_HASH_SECTIONS.len()
+        )
+    }
+
+    #[test]
+    fn self_hash_sections_match_stub() {
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        // lzbt can be built without the rest of the repository, e.g. by Nix, which has no stub.
+        if !manifest_dir.join("../../../flake.nix").exists() {
+            return;
+        }
+        let path = manifest_dir.join("../../uefi/linux-bootloader/src/self_hash_sections.rs");
+        let expected = stub_self_hash_sections();
+        if std::env::var_os("UPDATE_SELF_HASH_SECTIONS").is_some() {
+            fs::write(&path, &expected).unwrap();
+        }
+
+        let stub_sections = fs::read_to_string(&path)
+            .unwrap_or_else(|err| panic!("Failed to read {path:?}: {err}"));
+        assert_eq!(
+            stub_sections, expected,
+            "{path:?} is out of date, run this test with UPDATE_SELF_HASH_SECTIONS=1"
+        );
+    }
+
     #[test]
     fn convert_to_valid_uefi_path_relative_to_esp() {
         let esp = Path::new("esp");
@@ -200,6 +1344,111 @@ mod tests {
         assert_eq!(converted_path, expected_path);
     }
 
+    #[test]
+    fn initrd_path_section_has_path_and_hash() {
+        let esp = tempfile::tempdir().unwrap();
+        let file = esp.path().join("initrd");
+        fs::write(&file, "test").unwrap();
+
+        assert_eq!(
+            initrd_path_section(esp.path(), &file).unwrap(),
+            "\\initrd\n9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+        );
+    }
+
+    #[test]
+    fn append_sbat_entries_of_addon() {
+        let base = "sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md\n\
+            systemd-stub,1,The systemd Developers,systemd,256,https://systemd.io/\n";
+        let extra = "sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md\n\
+            addon.nixos,1,NixOS,addon,1,https://nixos.org/\n\n";
+        assert_eq!(
+            merge_sbat(base, extra),
+            "sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md\n\
+            systemd-stub,1,The systemd Developers,systemd,256,https://systemd.io/\n\
+            addon.nixos,1,NixOS,addon,1,https://nixos.org/\n"
+        );
+    }
+
+    #[test]
+    fn reject_duplicate_sections() {
+        let sections = vec![
+            s(".initrd", "initrd-1", 0x1000, 0x10),
+            s(".linux", "kernel", 0x2000, 0x10),
+            s(".initrd", "initrd-2", 0x3000, 0x10),
+        ];
+        let error = wrap_in_pe(Path::new("stub"), &sections, Path::new("out")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Refusing to assemble a PE binary with duplicate .initrd sections"
+        );
+    }
+
+    #[test]
+    fn describe_layout_of_oversized_section() {
+        let dir = TempDir::new().unwrap();
+        let osrel = dir.path().join("osrel");
+        fs::write(&osrel, "ID=lanza\n").unwrap();
+        // A sparse file does not take up any space.
+        let initrd = dir.path().join("initrd");
+        File::create(&initrd)
+            .unwrap()
+            .set_len(u64::from(u32::MAX))
+            .unwrap();
+
+        let error = place_sections(
+            0x1_4000_0000,
+            0x1_4000_0000,
+            0x1000,
+            vec![(".osrel", osrel), (".initrd", initrd)],
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The .initrd section of 0xffffffff bytes does not fit into the image after the \
+            section layout .osrel at 0x140000000 (0x9 bytes)"
+        );
+    }
+
+    #[test]
+    fn align_section_relative_to_image_base() {
+        let dir = TempDir::new().unwrap();
+        let osrel = dir.path().join("osrel");
+        fs::write(&osrel, "ID=lanza\n").unwrap();
+
+        let sections = place_sections(
+            0x1_4000_3000,
+            0x1_4000_1000,
+            0x1000,
+            vec![
+                (".osrel", osrel.clone()),
+                (".linux", osrel.clone()),
+                (".initrd", osrel),
+            ],
+            Some((".linux", 0x20_0000)),
+        )
+        .unwrap();
+        let offsets = sections.iter().map(|s| s.offset).collect::<Vec<_>>();
+        assert_eq!(offsets, [0x1_4000_3000, 0x1_4020_1000, 0x1_4020_2000]);
+    }
+
+    #[test]
+    fn reject_non_pe_kernel() {
+        let dir = TempDir::new().unwrap();
+        let kernel = dir.path().join("bzImage");
+        fs::write(&kernel, b"\xb8\xc0\x07\x8e\xd8 not a PE binary").unwrap();
+
+        let error = ensure_efi_kernel(&kernel, Architecture::X86).unwrap_err();
+        assert!(error.to_string().contains("CONFIG_EFI_STUB"));
+    }
+
+    #[test]
+    fn reject_non_pe_binary_for_arch() {
+        let error = arch_of_pe(b"MZ not a PE binary").unwrap_err();
+        assert!(error.to_string().contains("Failed to parse PE binary"));
+    }
+
     #[test]
     fn convert_to_valid_uefi_path() {
         let path = Path::new("lanzaboote/is/great.txt");
@@ -0,0 +1,115 @@
+//! Consistency checks of the key pair used for signing.
+//!
+//! The firmware neither checks the validity period nor the key usage of the certificates in db,
+//! but other tools (e.g. sbverify or shim) may. A private key that does not belong to the
+//! certificate produces signatures that nothing accepts.
+
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rsa::pkcs8::DecodePublicKey;
+use rsa::RsaPublicKey;
+use x509_cert::der::asn1::ObjectIdentifier;
+use x509_cert::der::{Decode, Encode};
+use x509_cert::ext::pkix::{ExtendedKeyUsage, KeyUsage};
+use x509_cert::Certificate;
+
+use crate::authenticode::{read_certificate, read_private_key};
+use crate::signature::KeyPair;
+
+const ID_CE_KEY_USAGE: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.5.29.15");
+const ID_CE_EXT_KEY_USAGE: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.5.29.37");
+const ANY_EXTENDED_KEY_USAGE: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.5.29.37.0");
+const ID_KP_CODE_SIGNING: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.5.5.7.3.3");
+
+/// Something that is wrong with a key pair.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// The private key does not belong to the certificate.
+    KeyMismatch,
+    /// The certificate is not valid yet.
+    NotYetValid,
+    /// The certificate has expired.
+    Expired,
+    /// The key usage extension does not allow digital signatures.
+    NoDigitalSignatureUsage,
+    /// The extended key usage extension does not allow code signing.
+    NoCodeSigningUsage,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::KeyMismatch => "The private key does not match the certificate",
+            Self::NotYetValid => "The certificate is not valid yet",
+            Self::Expired => "The certificate has expired",
+            Self::NoDigitalSignatureUsage => {
+                "The key usage of the certificate does not include digital signatures"
+            }
+            Self::NoCodeSigningUsage => {
+                "The extended key usage of the certificate does not include code signing"
+            }
+        })
+    }
+}
+
+/// Check that the private key belongs to the certificate and that the certificate can be used for
+/// code signing at `now` (the time since the Unix epoch).
+///
+/// Returns all problems that were found. Errors are only returned if the files cannot be read or parsed.
+pub fn check_key_pair(key_pair: &KeyPair, now: Duration) -> Result<Vec<Problem>> {
+    let private_key = read_private_key(&key_pair.private_key)?;
+    let certificate = read_certificate(&key_pair.public_key)?;
+    let public_key = public_key(&certificate)
+        .with_context(|| format!("Failed to read the public key of {:?}", key_pair.public_key))?;
+
+    let mut problems = Vec::new();
+    if private_key.to_public_key() != public_key {
+        problems.push(Problem::KeyMismatch);
+    }
+    problems.extend(check_validity(&certificate, now));
+    problems.extend(
+        check_key_usage(&certificate).with_context(|| {
+            format!("Failed to read the extensions of {:?}", key_pair.public_key)
+        })?,
+    );
+    Ok(problems)
+}
+
+pub(crate) fn public_key(certificate: &Certificate) -> Result<RsaPublicKey> {
+    let spki = certificate
+        .tbs_certificate
+        .subject_public_key_info
+        .to_der()?;
+    RsaPublicKey::from_public_key_der(&spki).context("Only RSA keys are supported")
+}
+
+fn check_validity(certificate: &Certificate, now: Duration) -> Option<Problem> {
+    let validity = &certificate.tbs_certificate.validity;
+    if now < validity.not_before.to_unix_duration() {
+        Some(Problem::NotYetValid)
+    } else if now > validity.not_after.to_unix_duration() {
+        Some(Problem::Expired)
+    } else {
+        None
+    }
+}
+
+/// Check the key usage extensions. Without an extension, its usage is unrestricted.
+fn check_key_usage(certificate: &Certificate) -> Result<Vec<Problem>> {
+    let mut problems = Vec::new();
+    for extension in certificate.tbs_certificate.extensions.iter().flatten() {
+        let value = extension.extn_value.as_bytes();
+        if extension.extn_id == ID_CE_KEY_USAGE && !KeyUsage::from_der(value)?.digital_signature() {
+            problems.push(Problem::NoDigitalSignatureUsage);
+        }
+        if extension.extn_id == ID_CE_EXT_KEY_USAGE {
+            let usages = ExtendedKeyUsage::from_der(value)?.0;
+            if !usages.contains(&ID_KP_CODE_SIGNING) && !usages.contains(&ANY_EXTENDED_KEY_USAGE) {
+                problems.push(Problem::NoCodeSigningUsage);
+            }
+        }
+    }
+    Ok(problems)
+}
@@ -0,0 +1,112 @@
+//! Verification of release signatures of generations.
+//!
+//! Some organizations require a release manager to approve a system before the machine signs it
+//! for Secure Boot. The release manager signs the hash of the toplevel store path of the system,
+//! e.g. with `printf %s <hash> | gpg --detach-sign > <hash>.sig`. Because the store path hash
+//! covers the whole closure, this approves everything that ends up in the UKI.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result};
+
+/// The length of the hash part of a Nix store path.
+const STORE_PATH_HASH_LENGTH: usize = 32;
+
+/// The characters of the base32 alphabet Nix uses for store path hashes.
+const NIX_BASE32_CHARS: &str = "0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Extract the hash part of a toplevel store path, e.g.
+/// `/nix/store/<hash>-nixos-system-<name>-<version>`.
+pub fn toplevel_hash(toplevel: &Path) -> Result<&str> {
+    let hash = toplevel
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.get(..STORE_PATH_HASH_LENGTH))
+        .filter(|hash| hash.chars().all(|c| NIX_BASE32_CHARS.contains(c)))
+        .with_context(|| format!("{toplevel:?} is not a store path"))?;
+    Ok(hash)
+}
+
+/// Verifies detached GPG signatures of toplevels with gpgv.
+#[derive(Debug, Clone)]
+pub struct ReleaseVerifier {
+    /// A keyring with the public keys of the release managers, as exported by `gpg --export`.
+    pub keyring: PathBuf,
+    /// The directory that contains the signature of each toplevel as `<hash>.sig`.
+    pub signatures: PathBuf,
+}
+
+impl ReleaseVerifier {
+    pub fn new(keyring: &Path, signatures: &Path) -> Self {
+        Self {
+            keyring: keyring.into(),
+            signatures: signatures.into(),
+        }
+    }
+
+    /// Check that the hash of `toplevel` is signed by a key in the keyring.
+    pub fn verify(&self, toplevel: &Path) -> Result<()> {
+        let hash = toplevel_hash(toplevel)?;
+        let signature = self.signatures.join(format!("{hash}.sig"));
+        if !signature.exists() {
+            anyhow::bail!("There is no release signature {signature:?} for {toplevel:?}");
+        }
+        // gpgv looks up keyrings without a slash in its home directory.
+        let keyring = fs::canonicalize(&self.keyring)
+            .with_context(|| format!("Failed to find release keyring {:?}", self.keyring))?;
+
+        let mut child = Command::new("gpgv")
+            .arg("--keyring")
+            .arg(keyring)
+            .arg(&signature)
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Failed to run gpgv. Most likely, the binary is not on PATH.")?;
+        child
+            .stdin
+            .take()
+            .context("Failed to open stdin of gpgv")?
+            .write_all(hash.as_bytes())
+            .context("Failed to pass the toplevel hash to gpgv")?;
+        let output = child
+            .wait_with_output()
+            .context("Failed to wait for gpgv")?;
+
+        if !output.status.success() {
+            log::debug!("gpgv failed: {}", String::from_utf8_lossy(&output.stderr));
+            anyhow::bail!(
+                "The release signature {signature:?} of {toplevel:?} is not valid for the keyring {:?}",
+                self.keyring
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_toplevel_hash() {
+        assert_eq!(
+            toplevel_hash(Path::new(
+                "/nix/store/0123456789abcdfghijklmnpqrsvwxyz-nixos-system-lanzaos-23.05"
+            ))
+            .unwrap(),
+            "0123456789abcdfghijklmnpqrsvwxyz"
+        );
+        assert!(toplevel_hash(Path::new("/tmp/toplevel-abcdefgh")).is_err());
+        // `e`, `o`, `u` and `t` are not part of the alphabet.
+        assert!(toplevel_hash(Path::new(
+            "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-nixos-system"
+        ))
+        .is_err());
+    }
+}
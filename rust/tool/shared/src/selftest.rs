@@ -0,0 +1,135 @@
+//! The result of the firmware self-test.
+//!
+//! The self-test image of the stub probes the firmware once on the target machine and writes what
+//! it found to [`RESULT_PATH`] on the ESP. This cannot be checked from the machine that builds the
+//! system, so lzbt reads the result before installing.
+
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+
+/// Where the self-test writes its result, relative to the ESP.
+pub const RESULT_PATH: &str = "loader/lanzaboote-selftest.txt";
+
+/// The version of the result format that is understood.
+const RESULT_VERSION: &str = "1";
+
+/// What the firmware of the target machine supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelfTestResult {
+    /// Linux can find an initrd served with the LoadFile2 protocol. Without this, no generation
+    /// can boot.
+    pub load_file2: bool,
+    /// A TPM is available via the TCG2 protocol, which is needed for measured boot.
+    pub tcg2: bool,
+    /// A graphical console is available via the Graphics Output Protocol.
+    pub gop: bool,
+}
+
+impl SelfTestResult {
+    /// Read the result from the ESP mounted at `esp`.
+    pub fn from_esp(esp: &Path) -> Result<Self> {
+        let path = esp.join(RESULT_PATH);
+        let contents = fs::read_to_string(&path).with_context(|| {
+            format!("Failed to read the self-test result {path:?}. Run the self-test image on this machine first.")
+        })?;
+        contents
+            .parse()
+            .with_context(|| format!("Failed to parse the self-test result {path:?}"))
+    }
+}
+
+impl FromStr for SelfTestResult {
+    type Err = anyhow::Error;
+
+    /// Parse `key=value` lines. Unknown keys are ignored so that newer self-tests can add
+    /// capabilities.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut version = None;
+        let (mut load_file2, mut tcg2, mut gop) = (None, None, None);
+
+        for line in s.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let (key, value) = line
+                .split_once('=')
+                .with_context(|| format!("Expected key=value, got {line}"))?;
+            let target = match key {
+                "version" => {
+                    version = Some(value);
+                    continue;
+                }
+                "load-file2" => &mut load_file2,
+                "tcg2" => &mut tcg2,
+                "gop" => &mut gop,
+                _ => continue,
+            };
+            *target = Some(match value {
+                "yes" => true,
+                "no" => false,
+                _ => anyhow::bail!("Expected yes or no for {key}, got {value}"),
+            });
+        }
+
+        match version {
+            Some(RESULT_VERSION) => (),
+            Some(version) => anyhow::bail!("Unsupported self-test result version {version}"),
+            None => anyhow::bail!("The self-test result has no version"),
+        }
+        let required = |value: Option<bool>, key| {
+            value.with_context(|| format!("The self-test result does not contain {key}"))
+        };
+        Ok(Self {
+            load_file2: required(load_file2, "load-file2")?,
+            tcg2: required(tcg2, "tcg2")?,
+            gop: required(gop, "gop")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_result() {
+        assert_eq!(
+            "version=1\nload-file2=yes\ntcg2=no\ngop=yes\n"
+                .parse::<SelfTestResult>()
+                .unwrap(),
+            SelfTestResult {
+                load_file2: true,
+                tcg2: false,
+                gop: true,
+            }
+        );
+    }
+
+    #[test]
+    fn ignore_unknown_keys_and_blank_lines() {
+        assert_eq!(
+            "version=1\r\n\r\ngop=no\nsomething-new=yes\ntcg2=yes\nload-file2=no\n"
+                .parse::<SelfTestResult>()
+                .unwrap(),
+            SelfTestResult {
+                load_file2: false,
+                tcg2: true,
+                gop: false,
+            }
+        );
+    }
+
+    #[test]
+    fn reject_malformed_results() {
+        for result in [
+            "",
+            "load-file2=yes\ntcg2=yes\ngop=yes\n",
+            "version=2\nload-file2=yes\ntcg2=yes\ngop=yes\n",
+            "version=1\nload-file2=yes\ntcg2=yes\n",
+            "version=1\nload-file2=maybe\ntcg2=yes\ngop=yes\n",
+            "version=1\nload-file2\ntcg2=yes\ngop=yes\n",
+        ] {
+            assert!(result.parse::<SelfTestResult>().is_err(), "{result:?}");
+        }
+    }
+}
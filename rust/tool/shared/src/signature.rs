@@ -1,13 +1,92 @@
 use std::ffi::OsString;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use anyhow::{Context, Result};
+use tempfile::NamedTempFile;
 
+use crate::pe::{self, AuthenticodeLayout};
+use crate::utils::create_tempfile;
+
+/// Something that can create Authenticode signatures.
+pub trait Signer {
+    /// Sign the PE binary at `from` and write the signed binary to `to`.
+    fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Add a signature to the PE binary at `from`, keeping its existing signatures, and write the
+    /// signed binary to `to`.
+    fn add_signature(&self, from: &Path, to: &Path) -> Result<()>;
+}
+
+/// Signs with several keys, so that the binary is accepted by firmware that trusts any of them.
+///
+/// This is needed while db is migrated to a new key: the firmware of some machines may only
+/// contain the old certificate, others only the new one. Every key adds its own Authenticode
+/// signature to the certificate table.
+pub struct MultiSigner {
+    signers: Vec<Box<dyn Signer>>,
+    work_dir: Option<PathBuf>,
+}
+
+impl MultiSigner {
+    /// Sign with `primary`, then add the signatures of `additional` in order.
+    pub fn new(primary: Box<dyn Signer>, additional: Vec<Box<dyn Signer>>) -> Self {
+        let mut signers = vec![primary];
+        signers.extend(additional);
+        Self {
+            signers,
+            work_dir: None,
+        }
+    }
+
+    /// Keep the binaries signed by only some of the keys in `work_dir` instead of `TMPDIR`.
+    pub fn with_work_dir(mut self, work_dir: Option<&Path>) -> Self {
+        self.work_dir = work_dir.map(Path::to_path_buf);
+        self
+    }
+
+    /// Let the signers add their signatures in turn. If `replace` is set, the first one replaces
+    /// the existing signatures.
+    fn sign_in_turn(&self, from: &Path, to: &Path, replace: bool) -> Result<()> {
+        let mut signed: Option<NamedTempFile> = None;
+        for (index, signer) in self.signers.iter().enumerate() {
+            let input = signed.as_ref().map_or(from, |file| file.path());
+            let output = create_tempfile(self.work_dir.as_deref())?;
+            if index == 0 && replace {
+                signer.sign_and_copy(input, output.path())?;
+            } else {
+                signer.add_signature(input, output.path())?;
+            }
+            signed = Some(output);
+        }
+        let signed = signed.context("No keys to sign with")?;
+        fs::copy(signed.path(), to).with_context(|| format!("Failed to write {to:?}"))?;
+        Ok(())
+    }
+}
+
+impl Signer for MultiSigner {
+    fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        self.sign_in_turn(from, to, true)
+    }
+
+    fn add_signature(&self, from: &Path, to: &Path) -> Result<()> {
+        self.sign_in_turn(from, to, false)
+    }
+}
+
+/// A key pair used for signing with sbsign.
+///
+/// Each signature spawns a separate sbsign process. See
+/// [`NativeSigner`](crate::authenticode::NativeSigner) to sign many files with the same key.
 pub struct KeyPair {
     pub private_key: PathBuf,
     pub public_key: PathBuf,
+    /// Where a signed binary is copied without its signatures before it is signed again, `TMPDIR`
+    /// if it is `None`.
+    pub work_dir: Option<PathBuf>,
 }
 
 impl KeyPair {
@@ -15,56 +94,188 @@ impl KeyPair {
         Self {
             public_key: public_key.into(),
             private_key: private_key.into(),
+            work_dir: None,
         }
     }
 
-    pub fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
-        let args: Vec<OsString> = vec![
+    /// Copy signed binaries without their signatures into `work_dir` instead of `TMPDIR`.
+    pub fn with_work_dir(mut self, work_dir: Option<&Path>) -> Self {
+        self.work_dir = work_dir.map(Path::to_path_buf);
+        self
+    }
+
+    /// The certificate of the key pair.
+    pub fn public(&self) -> PublicKey {
+        PublicKey::new(&self.public_key)
+    }
+
+    /// Verify the signature of a PE binary. Return true if the signature was verified.
+    pub fn verify(&self, path: &Path) -> bool {
+        self.public().verify(path)
+    }
+
+    fn sbsign_args(&self) -> Vec<OsString> {
+        vec![
             OsString::from("--key"),
             self.private_key.clone().into(),
             OsString::from("--cert"),
             self.public_key.clone().into(),
-            from.as_os_str().to_owned(),
-            OsString::from("--output"),
-            to.as_os_str().to_owned(),
-        ];
-
-        let output = Command::new("sbsign")
-            .args(&args)
-            .output()
-            .context("Failed to run sbsign. Most likely, the binary is not on PATH.")?;
-
-        if !output.status.success() {
-            std::io::stderr()
-                .write_all(&output.stderr)
-                .context("Failed to write output of sbsign to stderr.")?;
-            log::debug!("sbsign failed with args: `{args:?}`.");
-            return Err(anyhow::anyhow!("Failed to sign {to:?}."));
-        }
+        ]
+    }
+}
 
-        Ok(())
+/// The certificate of a key pair whose private key may be kept elsewhere, e.g. in an OpenSSL
+/// engine or on a remote signing service.
+///
+/// It is enough to verify signatures, but cannot create them.
+#[derive(Debug, Clone)]
+pub struct PublicKey {
+    pub path: PathBuf,
+}
+
+impl PublicKey {
+    pub fn new(path: &Path) -> Self {
+        Self { path: path.into() }
     }
 
     /// Verify the signature of a PE binary. Return true if the signature was verified.
     pub fn verify(&self, path: &Path) -> bool {
-        let args: Vec<OsString> = vec![
+        verify_signature(&self.path, path).unwrap_or_else(|e| panic!("{e:#}"))
+    }
+}
+
+/// Verify that a PE binary is signed with `certificate` using sbverify.
+pub fn verify_signature(certificate: &Path, path: &Path) -> Result<bool> {
+    let args: Vec<OsString> = vec![
+        OsString::from("--cert"),
+        certificate.into(),
+        path.as_os_str().to_owned(),
+    ];
+
+    let output = Command::new("sbverify")
+        .args(&args)
+        .output()
+        .context("Failed to run sbverify. Most likely, the binary is not on PATH.")?;
+
+    if !output.status.success() {
+        if std::io::stderr().write_all(&output.stderr).is_err() {
+            return Ok(false);
+        };
+        log::debug!("sbverify failed with args: `{args:?}`.");
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+impl Signer for KeyPair {
+    fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        let unsigned = unsigned_copy(from, self.work_dir.as_deref())?;
+        run_sbsign(&self.sbsign_args(), unsigned_path(&unsigned, from), to)
+    }
+
+    fn add_signature(&self, from: &Path, to: &Path) -> Result<()> {
+        run_sbsign(&self.sbsign_args(), from, to)
+    }
+}
+
+/// A key held by an OpenSSL engine (e.g. a TPM via tpm2-openssl) used for signing with sbsign.
+///
+/// The private key never leaves the engine, sbsign only passes the key identifier to it.
+pub struct EngineKey {
+    /// The name of the OpenSSL engine.
+    pub engine: String,
+    /// The identifier of the key in the engine.
+    pub key_id: String,
+    pub public_key: PathBuf,
+    /// Where a signed binary is copied without its signatures before it is signed again, `TMPDIR`
+    /// if it is `None`.
+    pub work_dir: Option<PathBuf>,
+}
+
+impl EngineKey {
+    pub fn new(engine: &str, key_id: &str, public_key: &Path) -> Self {
+        Self {
+            engine: engine.into(),
+            key_id: key_id.into(),
+            public_key: public_key.into(),
+            work_dir: None,
+        }
+    }
+
+    /// Copy signed binaries without their signatures into `work_dir` instead of `TMPDIR`.
+    pub fn with_work_dir(mut self, work_dir: Option<&Path>) -> Self {
+        self.work_dir = work_dir.map(Path::to_path_buf);
+        self
+    }
+
+    fn sbsign_args(&self) -> Vec<OsString> {
+        vec![
+            OsString::from("--engine"),
+            self.engine.clone().into(),
+            OsString::from("--key"),
+            self.key_id.clone().into(),
             OsString::from("--cert"),
             self.public_key.clone().into(),
-            path.as_os_str().to_owned(),
-        ];
-
-        let output = Command::new("sbverify")
-            .args(&args)
-            .output()
-            .expect("Failed to run sbverify. Most likely, the binary is not on PATH.");
-
-        if !output.status.success() {
-            if std::io::stderr().write_all(&output.stderr).is_err() {
-                return false;
-            };
-            log::debug!("sbverify failed with args: `{args:?}`.");
-            return false;
-        }
-        true
+        ]
+    }
+}
+
+impl Signer for EngineKey {
+    fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        let unsigned = unsigned_copy(from, self.work_dir.as_deref())?;
+        run_sbsign(&self.sbsign_args(), unsigned_path(&unsigned, from), to)
     }
+
+    fn add_signature(&self, from: &Path, to: &Path) -> Result<()> {
+        run_sbsign(&self.sbsign_args(), from, to)
+    }
+}
+
+/// Run sbsign with the key given by `key_args` to sign `from` into `to`.
+///
+/// sbsign adds its signature to the existing ones. To replace them, pass a copy made with
+/// [`unsigned_copy`].
+fn run_sbsign(key_args: &[OsString], from: &Path, to: &Path) -> Result<()> {
+    let mut args = key_args.to_vec();
+    args.extend([
+        from.as_os_str().to_owned(),
+        OsString::from("--output"),
+        to.as_os_str().to_owned(),
+    ]);
+
+    let output = Command::new("sbsign")
+        .args(&args)
+        .output()
+        .context("Failed to run sbsign. Most likely, the binary is not on PATH.")?;
+
+    if !output.status.success() {
+        std::io::stderr()
+            .write_all(&output.stderr)
+            .context("Failed to write output of sbsign to stderr.")?;
+        log::debug!("sbsign failed with args: `{args:?}`.");
+        return Err(anyhow::anyhow!("Failed to sign {to:?}."));
+    }
+
+    Ok(())
+}
+
+/// The path of the copy made by [`unsigned_copy`], or `path` if it had no signatures.
+fn unsigned_path<'a>(copy: &'a Option<NamedTempFile>, path: &'a Path) -> &'a Path {
+    copy.as_ref().map_or(path, |copy| copy.path())
+}
+
+/// Copy the PE binary at `path` without its signatures into `work_dir`, if it has any.
+fn unsigned_copy(path: &Path, work_dir: Option<&Path>) -> Result<Option<NamedTempFile>> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+    let layout = AuthenticodeLayout::read(&mut file)
+        .with_context(|| format!("Failed to read the headers of {path:?}"))?;
+    if layout.certificate_table.is_none() {
+        return Ok(None);
+    }
+
+    log::debug!("Removing the existing signatures of {path:?} before signing it...");
+    let mut copy = create_tempfile(work_dir)?;
+    pe::copy_unsigned(&mut file, copy.as_file_mut())
+        .with_context(|| format!("Failed to remove the signatures of {path:?}"))?;
+    Ok(Some(copy))
 }
@@ -0,0 +1,193 @@
+//! Templates for the titles of boot entries.
+//!
+//! systemd-boot shows the `PRETTY_NAME` of the `.osrel` section of a UKI as its title. By default,
+//! this is the label of the generation followed by its description, e.g. `NixOS (Generation 42,
+//! 2023-05-01)`. A [`TitleTemplate`] replaces this with a custom format such as `NixOS
+//! {generation} ({date})`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::Result;
+
+use crate::generation::Generation;
+
+/// A field of a generation that can be substituted into a title.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    /// The generation number, including the specialisation, e.g. `42-debug`.
+    Generation,
+    /// The profile of the generation, e.g. `system`.
+    Profile,
+    /// The build date of the generation.
+    Date,
+    /// The version of the kernel.
+    Kernel,
+    /// The label from the bootspec, e.g. `NixOS 23.05`.
+    Label,
+}
+
+impl Field {
+    const ALL: [Self; 5] = [
+        Self::Generation,
+        Self::Profile,
+        Self::Date,
+        Self::Kernel,
+        Self::Label,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Generation => "generation",
+            Self::Profile => "profile",
+            Self::Date => "date",
+            Self::Kernel => "kernel",
+            Self::Label => "label",
+        }
+    }
+
+    fn render(self, generation: &Generation) -> String {
+        match self {
+            Self::Generation => generation.version_tag(),
+            Self::Profile => generation.profile.clone(),
+            Self::Date => generation
+                .build_time
+                .map(|date| date.to_string())
+                .unwrap_or_else(|| String::from("Unknown")),
+            Self::Kernel => generation
+                .kernel_version()
+                .unwrap_or_else(|| String::from("Unknown")),
+            Self::Label => generation.spec.bootspec.bootspec.label.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+/// A template for the title of a boot entry.
+///
+/// Fields are written in braces, e.g. `{generation}`. Literal braces are written as `{{` and `}}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TitleTemplate {
+    template: String,
+    parts: Vec<Part>,
+}
+
+impl TitleTemplate {
+    /// Render the title of a generation.
+    pub fn render(&self, generation: &Generation) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(literal) => literal.clone(),
+                Part::Field(field) => field.render(generation),
+            })
+            .collect()
+    }
+
+    /// Whether the title contains the generation number.
+    ///
+    /// Without it, all generations of a profile usually end up with the same title.
+    pub fn has_generation(&self) -> bool {
+        self.parts.contains(&Part::Field(Field::Generation))
+    }
+}
+
+impl FromStr for TitleTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(template: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let Some(end) = rest.find('}') else {
+                        anyhow::bail!("Unclosed placeholder in title template {template:?}");
+                    };
+                    let name = &rest[..end];
+                    let field = Field::ALL
+                        .into_iter()
+                        .find(|field| field.name() == name)
+                        .ok_or_else(|| {
+                            let names = Field::ALL.map(|field| format!("{{{}}}", field.name()));
+                            anyhow::anyhow!(
+                                "Unknown placeholder {{{name}}} in title template {template:?}, expected one of {}",
+                                names.join(", ")
+                            )
+                        })?;
+                    chars = rest[end + 1..].chars();
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field(field));
+                }
+                '}' => anyhow::bail!("Unmatched }} in title template {template:?}"),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Self {
+            template: template.to_string(),
+            parts,
+        })
+    }
+}
+
+impl fmt::Display for TitleTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.template)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_fields_and_escaped_braces() {
+        let template: TitleTemplate = "NixOS {generation} {{{date}}}".parse().unwrap();
+        assert_eq!(
+            template.parts,
+            [
+                Part::Literal("NixOS ".into()),
+                Part::Field(Field::Generation),
+                Part::Literal(" {".into()),
+                Part::Field(Field::Date),
+                Part::Literal("}".into()),
+            ]
+        );
+        assert!(template.has_generation());
+        assert!(!"{label}".parse::<TitleTemplate>().unwrap().has_generation());
+    }
+
+    #[test]
+    fn reject_invalid_placeholders() {
+        let error = "NixOS {version}".parse::<TitleTemplate>().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unknown placeholder {version} in title template \"NixOS {version}\", expected one of \
+            {generation}, {profile}, {date}, {kernel}, {label}"
+        );
+        assert!("NixOS {generation".parse::<TitleTemplate>().is_err());
+        assert!("NixOS }".parse::<TitleTemplate>().is_err());
+    }
+}
@@ -0,0 +1,227 @@
+//! Build, sign, inspect and verify UKIs from other programs.
+//!
+//! `lzbt` installs UKIs with these functions, so programs that embed them produce the same images
+//! without shelling out to it.
+//!
+//! ```no_run
+//! use std::path::{Path, PathBuf};
+//!
+//! use lanzaboote_tool::signature::KeyPair;
+//! use lanzaboote_tool::uki::{build_uki, inspect_uki, sign_pe, verify_uki, UkiConfig};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let config = UkiConfig {
+//!     stub: "/run/current-system/lanzaboote-stub.efi".into(),
+//!     os_release: "/etc/os-release".into(),
+//!     kernel_cmdline: vec!["init=/nix/var/nix/profiles/system/init".into()],
+//!     kernel: "/run/current-system/kernel".into(),
+//!     kernel_target: "/boot/EFI/nixos/kernel.efi".into(),
+//!     initrd: Some("/run/current-system/initrd".into()),
+//!     initrd_target: Some("/boot/EFI/nixos/initrd.efi".into()),
+//!     esp: "/boot".into(),
+//!     ..Default::default()
+//! };
+//! build_uki(&config, Path::new("unsigned.efi"))?;
+//!
+//! let key_pair = KeyPair::new(Path::new("db.pem"), Path::new("db.key"));
+//! sign_pe(&key_pair, Path::new("unsigned.efi"), Path::new("signed.efi"))?;
+//! assert!(verify_uki(Path::new("signed.efi"), Path::new("db.pem"))?);
+//!
+//! let info = inspect_uki(Path::new("signed.efi"))?;
+//! println!("{:?}", info.redacted_cmdline());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use goblin::pe::PE;
+
+use crate::cmdline::{redact_cmdline, CmdlineVariant};
+use crate::deploy::DeployInfo;
+use crate::diff::SectionSummary;
+use crate::firmware::FirmwarePolicy;
+use crate::os_release::{MissingOsRelease, OsRelease};
+use crate::pe::{self, PeHeaderFields};
+use crate::signature::{self, Signer};
+use crate::utils::{create_tempdir, Hash, SecureTempDirExt};
+
+/// Everything that goes into a lanzaboote UKI.
+///
+/// The kernel and initrd are not embedded. The UKI refers to them by their location on the ESP
+/// and contains their hashes.
+#[derive(Debug, Clone, Default)]
+pub struct UkiConfig {
+    /// The lanzaboote stub.
+    pub stub: PathBuf,
+    /// The os-release that is embedded as the `.osrel` section.
+    pub os_release: PathBuf,
+    /// What happens if `os_release` does not exist.
+    pub missing_os_release: MissingOsRelease,
+    pub kernel_cmdline: Vec<String>,
+    /// Additional command lines offered in the boot menu of the stub.
+    pub cmdline_variants: Vec<CmdlineVariant>,
+    /// The kernel that is installed to `kernel_target`.
+    pub kernel: PathBuf,
+    /// Where the kernel is installed on the ESP.
+    pub kernel_target: PathBuf,
+    /// The initrd that is installed to `initrd_target`. Without one, the kernel boots without an
+    /// initrd.
+    pub initrd: Option<PathBuf>,
+    /// Where the initrd is installed on the ESP. Set if and only if `initrd` is.
+    pub initrd_target: Option<PathBuf>,
+    /// The mountpoint of the ESP.
+    pub esp: PathBuf,
+    /// The options of the stub that are embedded as sections.
+    pub stub_options: StubOptions,
+    /// The UEFI path of a boot loader on the ESP that the stub starts instead of the kernel, e.g.
+    /// systemd-boot.
+    pub chainload: Option<String>,
+    /// Start the `.linux` section at a multiple of this many bytes, both in the file and in
+    /// memory, e.g. for kernels that are executed in place. By default, it only has the alignment
+    /// that the stub requires for all sections.
+    pub linux_alignment: Option<u64>,
+    /// Embed a hash of the UKI that the stub verifies before booting.
+    pub self_hash: bool,
+    /// Embed the hashes of the sections of the UKI that the stub verifies before booting.
+    pub section_hashes: bool,
+    pub header_fields: PeHeaderFields,
+    /// Where intermediate files are written. Defaults to `TMPDIR`.
+    pub work_dir: Option<PathBuf>,
+}
+
+/// The options of the stub that are embedded as sections of a UKI.
+///
+/// Without a section, the stub keeps its default behaviour.
+#[derive(Debug, Clone, Default)]
+pub struct StubOptions {
+    /// The serial device the stub duplicates its log output to.
+    pub serial_console: Option<String>,
+    /// The seconds the stub waits before booting.
+    pub countdown: Option<u32>,
+    /// Make the stub skip verifying the initrd if Secure Boot is disabled.
+    pub skip_initrd_verification: bool,
+    /// Make the stub refuse to boot if measuring the UKI into the TPM fails.
+    pub strict_measurement: bool,
+    /// The A/B slot and version the UKI is deployed as.
+    pub deploy: Option<DeployInfo>,
+    /// The oldest firmware the stub boots on without complaining.
+    pub firmware_policy: Option<FirmwarePolicy>,
+    /// The prefix of the SMBIOS OEM string the stub appends to the command line without Secure
+    /// Boot.
+    pub smbios_cmdline_prefix: Option<String>,
+    /// Make the stub ask for confirmation before booting without Secure Boot.
+    pub confirm_insecure_boot: bool,
+    /// Make the stub pass the path of the UKI to the kernel in front of the command line.
+    pub load_options_image_path: bool,
+    /// The key that makes the stub reboot into the firmware setup when pressed while it starts.
+    pub firmware_setup_key: Option<String>,
+    /// Make the stub refuse a command line with control characters or beyond the length the kernel
+    /// accepts instead of sanitizing it.
+    pub strict_cmdline: bool,
+    /// Make the stub append the command lines of the signed addons in `loader/addons` and next
+    /// to the UKI.
+    pub cmdline_addons: bool,
+    /// Make the stub read and verify the initrd a second time if the first verification fails with
+    /// `SECURITY_VIOLATION`, for firmware that returns wrong data on the first read.
+    pub retry_initrd_verification: bool,
+    /// The `.irdpath` section that makes the stub append a file on the ESP to the initrd after
+    /// verifying it against its hash, see [`pe::initrd_path_section`].
+    pub initrd_path: Option<String>,
+    /// Make the stub boot the next entry of systemd-boot once and reset if the kernel fails to
+    /// start, instead of returning to the firmware.
+    pub next_entry_on_kernel_failure: bool,
+    /// Make the stub read boot parameters from this TPM NV index, which can deny the command line
+    /// passed by the boot loader and the command line from SMBIOS and addons.
+    pub tpm_nv_index: Option<u32>,
+    /// Make the stub print the PCR indices and digests it measured and the resulting PCR values
+    /// before starting the kernel, to compare them with the predictions in `.pcrsig`.
+    pub show_measurements: bool,
+}
+
+/// Assemble an unsigned UKI and write it to `output`.
+pub fn build_uki(config: &UkiConfig, output: &Path) -> Result<()> {
+    let tempdir = create_tempdir(config.work_dir.as_deref())?;
+    let os_release = if config.os_release.exists() {
+        config.os_release.clone()
+    } else {
+        match config.missing_os_release {
+            MissingOsRelease::Fail => {
+                bail!("The os-release {:?} does not exist", config.os_release)
+            }
+            MissingOsRelease::Warn => {
+                log::warn!(
+                    "The os-release {:?} does not exist, embedding a minimal one.",
+                    config.os_release
+                );
+                tempdir.write_secure_file(OsRelease::minimal().to_string())?
+            }
+        }
+    };
+    let image = pe::lanzaboote_image(&tempdir, config, &os_release)?;
+    fs::copy(&image, output).with_context(|| format!("Failed to write UKI to {output:?}"))?;
+    Ok(())
+}
+
+/// Sign the PE binary at `from` and write the signed binary to `to`.
+pub fn sign_pe(signer: &dyn Signer, from: &Path, to: &Path) -> Result<()> {
+    signer
+        .sign_and_copy(from, to)
+        .with_context(|| format!("Failed to sign {from:?}"))
+}
+
+/// What a UKI contains.
+#[derive(Debug, PartialEq, Eq)]
+pub struct UkiInfo {
+    /// The sections in the order they appear in the UKI.
+    pub sections: Vec<(String, SectionSummary)>,
+    /// The embedded command line. Use [`UkiInfo::redacted_cmdline`] to show it.
+    pub cmdline: Option<String>,
+    /// The UEFI path of the kernel relative to the ESP.
+    pub kernel: Option<String>,
+    /// The UEFI path of the initrd relative to the ESP.
+    pub initrd: Option<String>,
+    /// The hash the firmware checks against db and dbx.
+    pub authenticode: Hash,
+}
+
+impl UkiInfo {
+    /// The command line with secrets masked, for logging.
+    pub fn redacted_cmdline(&self) -> Option<String> {
+        self.cmdline.as_deref().map(redact_cmdline)
+    }
+}
+
+/// Describe the UKI at `path`.
+pub fn inspect_uki(path: &Path) -> Result<UkiInfo> {
+    let file_data = fs::read(path).with_context(|| format!("Failed to read UKI {path:?}"))?;
+    let pe = PE::parse(&file_data).with_context(|| format!("Failed to parse UKI {path:?}"))?;
+
+    let mut sections = Vec::new();
+    for section in &pe.sections {
+        let name = section.name()?;
+        if let Some(data) = pe::read_section_data(&file_data, name) {
+            sections.push((name.to_string(), SectionSummary::new(data)));
+        }
+    }
+    let text = |name| {
+        pe::read_section_data(&file_data, name).map(|data| String::from_utf8_lossy(data).into())
+    };
+
+    Ok(UkiInfo {
+        sections,
+        cmdline: text(".cmdline"),
+        kernel: text(".linux"),
+        initrd: text(".initrd"),
+        authenticode: pe::authenticode_digest(&file_data)?,
+    })
+}
+
+/// Check whether the UKI at `path` is signed with `certificate`.
+///
+/// This requires sbverify.
+pub fn verify_uki(path: &Path, certificate: &Path) -> Result<bool> {
+    signature::verify_signature(certificate, path)
+}
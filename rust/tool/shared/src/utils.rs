@@ -1,13 +1,13 @@
 use std::ffi::OsString;
 use std::fs;
-use std::io::Write;
+use std::io::{self, Read, Write};
 use std::iter::repeat_with;
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
 use sha2::{Digest, Sha256};
-use tempfile::TempDir;
+use tempfile::{NamedTempFile, TempDir};
 
 /// The number of random alphanumeric characters in the tempfiles.
 const TEMPFILE_RANDOM_LENGTH: usize = 32;
@@ -16,6 +16,7 @@ const TEMPFILE_RANDOM_LENGTH: usize = 32;
 pub trait SecureTempDirExt {
     fn create_secure_file(&self, path: &Path) -> Result<fs::File>;
     fn write_secure_file(&self, contents: impl AsRef<[u8]>) -> Result<PathBuf>;
+    fn copy_secure_file(&self, from: &Path) -> Result<PathBuf>;
 }
 
 /// This implementation has three useful properties:
@@ -34,6 +35,7 @@ impl SecureTempDirExt for TempDir {
     fn create_secure_file(&self, path: &Path) -> Result<fs::File> {
         fs::OpenOptions::new()
             .create(true)
+            .truncate(true)
             .write(true)
             .mode(0o600)
             .open(path)
@@ -51,6 +53,21 @@ impl SecureTempDirExt for TempDir {
 
         Ok(path)
     }
+
+    /// Create a temporary file and copy the contents of another file into it.
+    ///
+    /// The contents are streamed, so the source file is never held in memory as a whole.
+    fn copy_secure_file(&self, from: &Path) -> Result<PathBuf> {
+        let path = self.path().join(tmpname());
+        let mut tmpfile = self.create_secure_file(&path)?;
+        let mut source =
+            fs::File::open(from).with_context(|| format!("Failed to open file {from:?}"))?;
+
+        io::copy(&mut source, &mut tmpfile)
+            .with_context(|| format!("Failed to copy {from:?} to tempfile {path:?}"))?;
+
+        Ok(path)
+    }
 }
 
 /// Generate a random (but not cryptographically secure) name for a temporary file.
@@ -66,11 +83,154 @@ pub fn tmpname() -> OsString {
     buf
 }
 
-type Hash = sha2::digest::Output<Sha256>;
+pub type Hash = sha2::digest::Output<Sha256>;
 
 /// Compute the SHA 256 hash of a file.
+///
+/// The file is streamed through the hasher, so memory usage does not depend on the file size.
 pub fn file_hash(file: &Path) -> Result<Hash> {
-    Ok(Sha256::digest(fs::read(file).with_context(|| {
-        format!("Failed to read file to hash: {file:?}")
-    })?))
+    let file_handle =
+        fs::File::open(file).with_context(|| format!("Failed to open file to hash: {file:?}"))?;
+    reader_hash(file_handle).with_context(|| format!("Failed to read file to hash: {file:?}"))
+}
+
+/// Compute the SHA 256 hash of everything that can be read from a reader.
+pub fn reader_hash(mut reader: impl Read) -> io::Result<Hash> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// Describe a failure to `action` (e.g. "read") `path`.
+///
+/// Writing to the ESP and accessing EFI variables usually requires root, so permission errors
+/// come with a hint to run as root instead of only the raw OS error.
+pub fn explain_io_error(error: io::Error, action: &str, path: &Path) -> anyhow::Error {
+    let message = if error.kind() == io::ErrorKind::PermissionDenied {
+        format!("Permission denied to {action} {path:?}. Run as root, e.g. with sudo")
+    } else {
+        format!("Failed to {action} {path:?}")
+    };
+    anyhow::Error::new(error).context(message)
+}
+
+/// Create a temporary directory for intermediate files in `work_dir`, or in `TMPDIR` if it is
+/// `None`.
+///
+/// The directory and everything in it is deleted when the returned value is dropped, so
+/// intermediates are also removed when an error is returned early.
+pub fn create_tempdir(work_dir: Option<&Path>) -> Result<TempDir> {
+    match work_dir {
+        Some(dir) => TempDir::new_in(dir)
+            .with_context(|| format!("Failed to create temporary directory in {dir:?}.")),
+        None => TempDir::new().context("Failed to create temporary directory."),
+    }
+}
+
+/// Create a temporary file for an intermediate in `work_dir`, or in `TMPDIR` if it is `None`.
+///
+/// Like [`create_tempdir`], the file is deleted when the returned value is dropped.
+pub fn create_tempfile(work_dir: Option<&Path>) -> Result<NamedTempFile> {
+    match work_dir {
+        Some(dir) => NamedTempFile::new_in(dir)
+            .with_context(|| format!("Failed to create temporary file in {dir:?}.")),
+        None => NamedTempFile::new().context("Failed to create temporary file."),
+    }
+}
+
+/// Check that files can be created in `dir`.
+///
+/// This fails before anything is changed instead of in the middle of an installation.
+pub fn ensure_writable(dir: &Path) -> Result<()> {
+    tempfile::tempfile_in(dir)
+        .map(drop)
+        .map_err(|e| explain_io_error(e, "write to", dir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A reader that records the largest buffer it was ever asked to fill.
+    struct RecordingReader<R> {
+        inner: R,
+        largest_read: usize,
+    }
+
+    impl<R: Read> Read for RecordingReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.largest_read = self.largest_read.max(buf.len());
+            self.inner.read(buf)
+        }
+    }
+
+    #[test]
+    fn hash_reader_matches_digest() -> io::Result<()> {
+        let data = vec![0xaa; 1 << 20];
+        assert_eq!(reader_hash(data.as_slice())?, Sha256::digest(&data));
+        Ok(())
+    }
+
+    #[test]
+    fn hash_reader_reads_in_bounded_chunks() -> io::Result<()> {
+        let data = vec![0x55; 1 << 20];
+        let mut reader = RecordingReader {
+            inner: data.as_slice(),
+            largest_read: 0,
+        };
+        reader_hash(&mut reader)?;
+        assert!(
+            reader.largest_read < data.len() / 16,
+            "Hashing read {} bytes at once",
+            reader.largest_read
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn copy_secure_file_preserves_contents() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let source = tempdir.write_secure_file(b"initrd contents")?;
+        let copy = tempdir.copy_secure_file(&source)?;
+        assert_eq!(fs::read(copy)?, b"initrd contents");
+        Ok(())
+    }
+
+    #[test]
+    fn hint_to_run_as_root_on_permission_denied() {
+        let error = io::Error::from(io::ErrorKind::PermissionDenied);
+
+        let message = format!(
+            "{:#}",
+            explain_io_error(error, "write to", Path::new("/boot"))
+        );
+
+        assert!(message.starts_with("Permission denied to write to \"/boot\""));
+        assert!(message.contains("Run as root, e.g. with sudo"));
+    }
+
+    #[test]
+    fn no_hint_on_other_errors() {
+        let error = io::Error::from(io::ErrorKind::NotFound);
+
+        let message = format!("{:#}", explain_io_error(error, "read", Path::new("/boot")));
+
+        assert!(message.starts_with("Failed to read \"/boot\""));
+        assert!(!message.contains("root"));
+    }
+
+    #[test]
+    fn ensure_writable_accepts_writable_dir() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        ensure_writable(dir.path())?;
+        assert_eq!(fs::read_dir(dir.path())?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn ensure_writable_rejects_missing_dir() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        assert!(ensure_writable(&dir.path().join("missing")).is_err());
+        Ok(())
+    }
 }
@@ -5,6 +5,7 @@ use lanzaboote_tool::architecture::Architecture;
 /// Systemd-specific architecture helpers
 pub trait SystemdArchitectureExt {
     fn systemd_stub_filename(&self) -> PathBuf;
+    fn systemd_addon_stub_filename(&self) -> PathBuf;
     fn systemd_filename(&self) -> PathBuf;
 }
 
@@ -13,7 +14,44 @@ impl SystemdArchitectureExt for Architecture {
         format!("linux{}.efi.stub", self.efi_representation()).into()
     }
 
+    fn systemd_addon_stub_filename(&self) -> PathBuf {
+        format!("addon{}.efi.stub", self.efi_representation()).into()
+    }
+
     fn systemd_filename(&self) -> PathBuf {
         format!("systemd-boot{}.efi", self.efi_representation()).into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_architectures_to_systemd_filenames() {
+        assert_eq!(
+            Architecture::X86.systemd_filename(),
+            PathBuf::from("systemd-bootx64.efi")
+        );
+        assert_eq!(
+            Architecture::X86.systemd_stub_filename(),
+            PathBuf::from("linuxx64.efi.stub")
+        );
+        assert_eq!(
+            Architecture::X86.systemd_addon_stub_filename(),
+            PathBuf::from("addonx64.efi.stub")
+        );
+        assert_eq!(
+            Architecture::AArch64.systemd_filename(),
+            PathBuf::from("systemd-bootaa64.efi")
+        );
+        assert_eq!(
+            Architecture::AArch64.systemd_stub_filename(),
+            PathBuf::from("linuxaa64.efi.stub")
+        );
+        assert_eq!(
+            Architecture::AArch64.systemd_addon_stub_filename(),
+            PathBuf::from("addonaa64.efi.stub")
+        );
+    }
+}
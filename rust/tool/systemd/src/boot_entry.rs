@@ -0,0 +1,350 @@
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context, Result};
+
+/// A firmware boot entry (`Boot####` variable).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootEntry {
+    pub number: u16,
+    pub label: String,
+    /// The file part of the device path, e.g. `\EFI\Linux\nixos.efi`.
+    pub loader: String,
+}
+
+impl fmt::Display for BootEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Boot{:04X} ({}: {})",
+            self.number, self.label, self.loader
+        )
+    }
+}
+
+/// Storage of firmware boot entries and the boot order.
+pub trait BootEntryStore {
+    fn entries(&self) -> Result<Vec<BootEntry>>;
+    fn boot_order(&self) -> Result<Vec<u16>>;
+    /// Create an entry for a loader on the ESP and return its number.
+    fn create(&mut self, label: &str, loader: &str) -> Result<u16>;
+    fn delete(&mut self, number: u16) -> Result<()>;
+    fn set_boot_order(&mut self, boot_order: &[u16]) -> Result<()>;
+}
+
+/// Make sure there is exactly one boot entry for `loader` and that it is the first in BootOrder.
+///
+/// Entries are matched on the file part of their device path, case-insensitively because the ESP
+/// is a FAT file system. Existing entries with the same label that point to a different loader
+/// (e.g. the stub of an older generation) are replaced. Returns the number of the entry.
+pub fn ensure_boot_entry(
+    store: &mut impl BootEntryStore,
+    label: &str,
+    loader: &str,
+) -> Result<u16> {
+    let entries = store.entries()?;
+    let mut boot_order = store.boot_order()?;
+
+    let number = match entries
+        .iter()
+        .find(|e| e.loader.eq_ignore_ascii_case(loader))
+    {
+        Some(entry) => entry.number,
+        None => {
+            for stale in entries.iter().filter(|e| e.label == label) {
+                log::info!("Removing stale boot entry {stale}...");
+                store.delete(stale.number)?;
+                boot_order.retain(|n| *n != stale.number);
+            }
+            log::info!("Creating boot entry {label} for {loader}...");
+            store.create(label, loader)?
+        }
+    };
+
+    if boot_order.first() != Some(&number) {
+        boot_order.retain(|n| *n != number);
+        boot_order.insert(0, number);
+        store.set_boot_order(&boot_order)?;
+    }
+
+    Ok(number)
+}
+
+/// Boot entries managed by the `efibootmgr` tool.
+pub struct Efibootmgr {
+    disk: PathBuf,
+    partition: u32,
+}
+
+impl Efibootmgr {
+    /// Manage boot entries for loaders on the partition mounted at `esp`.
+    pub fn for_esp(esp: &Path) -> Result<Self> {
+        let dev = fs::metadata(esp)
+            .with_context(|| format!("Failed to read metadata of the ESP at {esp:?}"))?
+            .dev();
+        let (major, minor) = (nix::sys::stat::major(dev), nix::sys::stat::minor(dev));
+
+        // The sysfs directory of a partition is a subdirectory of the one of its disk.
+        let sysfs = fs::canonicalize(format!("/sys/dev/block/{major}:{minor}"))
+            .with_context(|| format!("Failed to find the block device of the ESP at {esp:?}"))?;
+        let partition = fs::read_to_string(sysfs.join("partition"))
+            .with_context(|| format!("The ESP at {esp:?} is not on a partition"))?
+            .trim()
+            .parse()
+            .context("Failed to parse partition number")?;
+        let disk = sysfs
+            .parent()
+            .and_then(Path::file_name)
+            .map(|name| Path::new("/dev").join(name))
+            .with_context(|| format!("Failed to find the disk containing the ESP at {esp:?}"))?;
+
+        Ok(Self { disk, partition })
+    }
+
+    fn run(&self, args: &[&str]) -> Result<String> {
+        let output = Command::new("efibootmgr")
+            .args(args)
+            .output()
+            .context("Failed to run efibootmgr. Most likely, the binary is not on PATH.")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "efibootmgr failed with args `{args:?}`: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        String::from_utf8(output.stdout).context("Failed to decode efibootmgr output")
+    }
+}
+
+impl BootEntryStore for Efibootmgr {
+    fn entries(&self) -> Result<Vec<BootEntry>> {
+        Ok(parse_entries(&self.run(&["--verbose"])?))
+    }
+
+    fn boot_order(&self) -> Result<Vec<u16>> {
+        parse_boot_order(&self.run(&[])?)
+    }
+
+    fn create(&mut self, label: &str, loader: &str) -> Result<u16> {
+        let disk = self.disk.to_str().context("Disk path is not valid UTF-8")?;
+        self.run(&[
+            "--create-only",
+            "--disk",
+            disk,
+            "--part",
+            &self.partition.to_string(),
+            "--label",
+            label,
+            "--loader",
+            loader,
+        ])?;
+        self.entries()?
+            .into_iter()
+            .find(|e| e.loader.eq_ignore_ascii_case(loader))
+            .map(|e| e.number)
+            .with_context(|| format!("Failed to find newly created boot entry for {loader}"))
+    }
+
+    fn delete(&mut self, number: u16) -> Result<()> {
+        self.run(&["--delete-bootnum", "--bootnum", &format!("{number:04X}")])?;
+        Ok(())
+    }
+
+    fn set_boot_order(&mut self, boot_order: &[u16]) -> Result<()> {
+        let boot_order = boot_order
+            .iter()
+            .map(|n| format!("{n:04X}"))
+            .collect::<Vec<_>>()
+            .join(",");
+        self.run(&["--bootorder", &boot_order])?;
+        Ok(())
+    }
+}
+
+/// Parse the `Boot####` lines of `efibootmgr --verbose`.
+///
+/// Entries without a file in their device path (e.g. firmware applications) are skipped.
+fn parse_entries(output: &str) -> Vec<BootEntry> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let rest = line.strip_prefix("Boot")?;
+            let number = u16::from_str_radix(rest.get(..4)?, 16).ok()?;
+            // The number is followed by `*` for active entries and a space.
+            let (label, device_path) = rest.get(4..)?.trim_start_matches('*').split_once('\t')?;
+            Some(BootEntry {
+                number,
+                label: label.trim().to_string(),
+                loader: loader_of_device_path(device_path)?,
+            })
+        })
+        .collect()
+}
+
+/// Extract the file from a device path as printed by `efibootmgr`.
+///
+/// Older versions print the file as `File(\EFI\...)`, newer ones print it as a bare path.
+fn loader_of_device_path(device_path: &str) -> Option<String> {
+    if let Some((_, file)) = device_path.split_once("/File(") {
+        return file.split_once(')').map(|(file, _)| file.to_string());
+    }
+    let (_, file) = device_path.split_once("/\\")?;
+    let file = file.split_whitespace().next().unwrap_or_default();
+    Some(format!("\\{file}"))
+}
+
+fn parse_boot_order(output: &str) -> Result<Vec<u16>> {
+    let Some(boot_order) = output.lines().find_map(|l| l.strip_prefix("BootOrder:")) else {
+        return Ok(Vec::new());
+    };
+    boot_order
+        .trim()
+        .split(',')
+        .filter(|n| !n.is_empty())
+        .map(|n| u16::from_str_radix(n, 16).with_context(|| format!("Invalid boot number {n}")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct MockStore {
+        entries: Vec<BootEntry>,
+        boot_order: Vec<u16>,
+        created: usize,
+    }
+
+    impl BootEntryStore for MockStore {
+        fn entries(&self) -> Result<Vec<BootEntry>> {
+            Ok(self.entries.clone())
+        }
+
+        fn boot_order(&self) -> Result<Vec<u16>> {
+            Ok(self.boot_order.clone())
+        }
+
+        fn create(&mut self, label: &str, loader: &str) -> Result<u16> {
+            let number = (0..)
+                .find(|n| self.entries.iter().all(|e| e.number != *n))
+                .unwrap();
+            self.entries.push(BootEntry {
+                number,
+                label: label.to_string(),
+                loader: loader.to_string(),
+            });
+            self.created += 1;
+            Ok(number)
+        }
+
+        fn delete(&mut self, number: u16) -> Result<()> {
+            self.entries.retain(|e| e.number != number);
+            Ok(())
+        }
+
+        fn set_boot_order(&mut self, boot_order: &[u16]) -> Result<()> {
+            self.boot_order = boot_order.to_vec();
+            Ok(())
+        }
+    }
+
+    fn entry(number: u16, label: &str, loader: &str) -> BootEntry {
+        BootEntry {
+            number,
+            label: label.to_string(),
+            loader: loader.to_string(),
+        }
+    }
+
+    #[test]
+    fn create_missing_entry_first_in_boot_order() {
+        let mut store = MockStore {
+            entries: vec![entry(0, "UEFI Shell", "\\EFI\\shell.efi")],
+            boot_order: vec![0],
+            ..Default::default()
+        };
+
+        let number = ensure_boot_entry(&mut store, "NixOS", "\\EFI\\Linux\\nixos.efi").unwrap();
+
+        assert_eq!(number, 1);
+        assert_eq!(store.boot_order, vec![1, 0]);
+    }
+
+    #[test]
+    fn do_not_duplicate_entry_on_repeated_installs() {
+        let mut store = MockStore::default();
+
+        ensure_boot_entry(&mut store, "NixOS", "\\EFI\\Linux\\nixos.efi").unwrap();
+        ensure_boot_entry(&mut store, "NixOS", "\\EFI\\Linux\\nixos.efi").unwrap();
+
+        assert_eq!(store.created, 1);
+        assert_eq!(store.entries.len(), 1);
+        assert_eq!(store.boot_order, vec![0]);
+    }
+
+    #[test]
+    fn match_loader_case_insensitively() {
+        let mut store = MockStore {
+            entries: vec![entry(3, "Something", "\\efi\\linux\\NIXOS.EFI")],
+            boot_order: vec![0, 3],
+            ..Default::default()
+        };
+
+        let number = ensure_boot_entry(&mut store, "NixOS", "\\EFI\\Linux\\nixos.efi").unwrap();
+
+        assert_eq!(number, 3);
+        assert_eq!(store.created, 0);
+        assert_eq!(store.boot_order, vec![3, 0]);
+    }
+
+    #[test]
+    fn replace_stale_entry_with_same_label() {
+        let mut store = MockStore {
+            entries: vec![
+                entry(0, "UEFI Shell", "\\EFI\\shell.efi"),
+                entry(1, "NixOS", "\\EFI\\Linux\\nixos-generation-1.efi"),
+            ],
+            boot_order: vec![1, 0],
+            ..Default::default()
+        };
+
+        let number =
+            ensure_boot_entry(&mut store, "NixOS", "\\EFI\\Linux\\nixos-generation-2.efi").unwrap();
+
+        assert_eq!(
+            store.entries,
+            vec![
+                entry(0, "UEFI Shell", "\\EFI\\shell.efi"),
+                entry(number, "NixOS", "\\EFI\\Linux\\nixos-generation-2.efi"),
+            ]
+        );
+        assert_eq!(store.boot_order, vec![number, 0]);
+    }
+
+    #[test]
+    fn parse_efibootmgr_output() {
+        let output = "BootCurrent: 0001\n\
+            Timeout: 0 seconds\n\
+            BootOrder: 0001,0000,000A\n\
+            Boot0000* UiApp\tFvVol(7cb8bdc9-f8eb-4f34-aaea-3ee4af6516a1)/FvFile(462caa21-7614-4503-836e-8ab6f4662331)\n\
+            Boot0001* Linux Boot Manager\tHD(1,GPT,0c8d0b3a-1d7c-4b5e-8a03-59a5d4c6e5f2,0x800,0x100000)/File(\\EFI\\systemd\\systemd-bootx64.efi)\n\
+            Boot000A  NixOS\tHD(1,GPT,0c8d0b3a-1d7c-4b5e-8a03-59a5d4c6e5f2,0x800,0x100000)/\\EFI\\Linux\\nixos.efi\n";
+
+        assert_eq!(
+            parse_entries(output),
+            vec![
+                entry(
+                    1,
+                    "Linux Boot Manager",
+                    "\\EFI\\systemd\\systemd-bootx64.efi"
+                ),
+                entry(10, "NixOS", "\\EFI\\Linux\\nixos.efi"),
+            ]
+        );
+        assert_eq!(parse_boot_order(output).unwrap(), vec![1, 0, 10]);
+    }
+}
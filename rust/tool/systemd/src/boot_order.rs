@@ -0,0 +1,431 @@
+//! The boot options of the firmware, i.e. the `BootNext`, `BootOrder` and `Boot####` EFI
+//! variables.
+//!
+//! The firmware boots the option in `BootNext` once, if it is set, and otherwise the first active
+//! option in `BootOrder`. Each `Boot####` variable holds an `EFI_LOAD_OPTION`, whose device path
+//! usually ends in the path of a file on the ESP.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use lanzaboote_tool::pe;
+use lanzaboote_tool::utils::explain_io_error;
+
+/// Vendor GUID of the variables defined by the UEFI specification.
+const EFI_GLOBAL_VARIABLE_GUID: &str = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+
+/// The `LOAD_OPTION_ACTIVE` attribute of a load option.
+const LOAD_OPTION_ACTIVE: u32 = 0x1;
+
+/// Device path node types and subtypes.
+const MEDIA_DEVICE_PATH: u8 = 0x04;
+const MEDIA_FILEPATH_DP: u8 = 0x04;
+const END_DEVICE_PATH_TYPE: u8 = 0x7f;
+
+/// A parsed `EFI_LOAD_OPTION`, the contents of a `Boot####` variable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadOption {
+    pub attributes: u32,
+    /// The description shown in the boot menu of the firmware.
+    pub description: String,
+    /// The path of the file on its volume, e.g. `\EFI\systemd\systemd-bootx64.efi`. Options
+    /// without one, e.g. of network boot, boot whatever the device provides.
+    pub file_path: Option<String>,
+}
+
+impl LoadOption {
+    /// Parse an `EFI_LOAD_OPTION`.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < 6 {
+            bail!("Load option is too short");
+        }
+        let attributes = u32::from_le_bytes(data[0..4].try_into().unwrap());
+        let file_path_list_length = usize::from(u16::from_le_bytes([data[4], data[5]]));
+        let (description, description_size) =
+            parse_utf16_nul(&data[6..]).context("Failed to parse the description")?;
+        let file_path_list = data
+            .get(6 + description_size..)
+            .and_then(|rest| rest.get(..file_path_list_length))
+            .context("Load option is shorter than its device path")?;
+
+        Ok(Self {
+            attributes,
+            description,
+            file_path: parse_file_path(file_path_list)?,
+        })
+    }
+
+    /// Whether the firmware boots the option if it is in `BootOrder`.
+    pub fn is_active(&self) -> bool {
+        self.attributes & LOAD_OPTION_ACTIVE != 0
+    }
+}
+
+/// The concatenated file path nodes of the first device path in a device path list.
+fn parse_file_path(mut device_path: &[u8]) -> Result<Option<String>> {
+    let mut file_path: Option<String> = None;
+    loop {
+        let Some(&[node_type, subtype, l1, l2]) = device_path.get(..4) else {
+            bail!("Device path is not terminated");
+        };
+        let length = usize::from(u16::from_le_bytes([l1, l2]));
+        if length < 4 {
+            bail!("Device path node is too short");
+        }
+        let node = device_path
+            .get(4..length)
+            .context("Device path node is truncated")?;
+
+        match (node_type, subtype) {
+            // Both the end of the whole device path and the end of its first instance.
+            (END_DEVICE_PATH_TYPE, _) => return Ok(file_path),
+            (MEDIA_DEVICE_PATH, MEDIA_FILEPATH_DP) => {
+                let (path, _) = parse_utf16_nul(node).context("Failed to parse a file path")?;
+                file_path.get_or_insert_with(String::new).push_str(&path);
+            }
+            _ => {}
+        }
+        device_path = &device_path[length..];
+    }
+}
+
+/// Parse a NUL-terminated UTF-16LE string. Returns the string and its size including the NUL.
+fn parse_utf16_nul(data: &[u8]) -> Option<(String, usize)> {
+    let code_units = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect::<Vec<_>>();
+    let length = code_units.iter().position(|c| *c == 0)?;
+    let value = String::from_utf16(&code_units[..length]).ok()?;
+    Some((value, 2 * (length + 1)))
+}
+
+/// Why the firmware skips an option.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Skipped {
+    /// There is no `Boot####` variable for the option.
+    Missing,
+    /// The option is not active, e.g. it was disabled in the setup of the firmware.
+    Inactive,
+}
+
+impl fmt::Display for Skipped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Missing => "does not exist",
+            Self::Inactive => "is not active",
+        })
+    }
+}
+
+/// The option the firmware boots and the options it skips before.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Resolution<'a> {
+    pub selected: Option<(u16, &'a LoadOption)>,
+    pub skipped: Vec<(u16, Skipped)>,
+}
+
+/// The boot related EFI variables of the firmware.
+#[derive(Debug, Default)]
+pub struct BootOptions {
+    pub boot_next: Option<u16>,
+    pub boot_order: Vec<u16>,
+    pub options: BTreeMap<u16, LoadOption>,
+}
+
+impl BootOptions {
+    /// Read `BootNext`, `BootOrder` and the options they reference from the EFI variables in
+    /// `efivars`, e.g. efivarfs or a copy of it.
+    pub fn read(efivars: &Path) -> Result<Self> {
+        let boot_next = read_global_variable(efivars, "BootNext")?
+            .map(|data| match data[..] {
+                [b1, b2] => Ok(u16::from_le_bytes([b1, b2])),
+                _ => bail!("BootNext is not a 16 bit number"),
+            })
+            .transpose()?;
+        let boot_order = match read_global_variable(efivars, "BootOrder")? {
+            Some(data) if data.len() % 2 == 0 => data
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect(),
+            Some(_) => bail!("BootOrder is not a list of 16 bit numbers"),
+            None => Vec::new(),
+        };
+
+        let mut options = BTreeMap::new();
+        for number in boot_next.iter().chain(&boot_order) {
+            let name = boot_option_name(*number);
+            if let Some(data) = read_global_variable(efivars, &name)? {
+                let option = LoadOption::parse(&data)
+                    .with_context(|| format!("Failed to parse the load option {name}"))?;
+                options.insert(*number, option);
+            }
+        }
+
+        Ok(Self {
+            boot_next,
+            boot_order,
+            options,
+        })
+    }
+
+    /// Determine the option the firmware boots.
+    ///
+    /// `BootNext` is booted even if the option is not active. Otherwise, the options in
+    /// `BootOrder` are tried in order and the first active one is booted.
+    pub fn resolve(&self) -> Resolution<'_> {
+        let mut skipped = Vec::new();
+        if let Some(number) = self.boot_next {
+            match self.options.get(&number) {
+                Some(option) => {
+                    return Resolution {
+                        selected: Some((number, option)),
+                        skipped,
+                    }
+                }
+                None => skipped.push((number, Skipped::Missing)),
+            }
+        }
+        for &number in &self.boot_order {
+            match self.options.get(&number) {
+                Some(option) if option.is_active() => {
+                    return Resolution {
+                        selected: Some((number, option)),
+                        skipped,
+                    }
+                }
+                Some(_) => skipped.push((number, Skipped::Inactive)),
+                None => skipped.push((number, Skipped::Missing)),
+            }
+        }
+        Resolution {
+            selected: None,
+            skipped,
+        }
+    }
+}
+
+/// The name of the variable of a boot option, e.g. `Boot0001`.
+pub fn boot_option_name(number: u16) -> String {
+    format!("Boot{number:04X}")
+}
+
+/// Read a variable of the firmware from efivarfs, without its attributes.
+fn read_global_variable(efivars: &Path, name: &str) -> Result<Option<Vec<u8>>> {
+    let path = efivars.join(format!("{name}-{EFI_GLOBAL_VARIABLE_GUID}"));
+    match fs::read(&path) {
+        // efivarfs prefixes the contents with the 4 byte attributes of the variable.
+        Ok(data) if data.len() >= 4 => Ok(Some(data[4..].to_vec())),
+        Ok(_) => bail!("Failed to parse EFI variable {path:?}"),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(explain_io_error(e, "read", &path)),
+    }
+}
+
+/// What a boot option starts.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BootTarget {
+    /// A UKI with the lanzaboote stub.
+    LanzabooteStub,
+    /// systemd-boot, which then boots its default entry.
+    SystemdBoot,
+    /// Any other EFI binary.
+    Other,
+    /// The file does not exist on the ESP.
+    Missing,
+}
+
+impl fmt::Display for BootTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::LanzabooteStub => "lanzaboote stub",
+            Self::SystemdBoot => "systemd-boot",
+            Self::Other => "not a lanzaboote stub",
+            Self::Missing => "missing on the ESP",
+        })
+    }
+}
+
+impl BootTarget {
+    /// Classify the file at `file_path`, as stored in a load option, on the ESP.
+    pub fn classify(esp: &Path, file_path: &str) -> Result<Self> {
+        let Some(path) = find_on_esp(esp, file_path)? else {
+            return Ok(Self::Missing);
+        };
+        let data = fs::read(&path).with_context(|| format!("Failed to read {path:?}"))?;
+        Ok(if pe::read_section_data(&data, ".linuxh").is_some() {
+            Self::LanzabooteStub
+        } else if pe::read_section_data(&data, ".sdmagic").is_some() {
+            Self::SystemdBoot
+        } else {
+            Self::Other
+        })
+    }
+}
+
+/// Find the file at the UEFI path `file_path` on the ESP.
+///
+/// The ESP is a FAT file system, so the components of the path are matched case insensitively,
+/// like the firmware does.
+fn find_on_esp(esp: &Path, file_path: &str) -> Result<Option<PathBuf>> {
+    let mut path = esp.to_path_buf();
+    for component in file_path.split('\\').filter(|c| !c.is_empty()) {
+        let entries = match fs::read_dir(&path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(explain_io_error(e, "read", &path)),
+        };
+        let mut found = None;
+        for entry in entries {
+            let entry = entry.with_context(|| format!("Failed to read {path:?}"))?;
+            if entry
+                .file_name()
+                .to_string_lossy()
+                .eq_ignore_ascii_case(component)
+            {
+                found = Some(entry.path());
+                break;
+            }
+        }
+        match found {
+            Some(found) => path = found,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    fn utf16_nul(value: &str) -> Vec<u8> {
+        value
+            .encode_utf16()
+            .chain([0])
+            .flat_map(u16::to_le_bytes)
+            .collect()
+    }
+
+    fn load_option(attributes: u32, description: &str, file_path: &str) -> Vec<u8> {
+        let mut device_path = Vec::new();
+        // A hard drive node of a GPT partition.
+        device_path.extend([0x04, 0x01, 42, 0]);
+        device_path.extend([0; 38]);
+        let path = utf16_nul(file_path);
+        device_path.extend([MEDIA_DEVICE_PATH, MEDIA_FILEPATH_DP]);
+        device_path.extend(u16::try_from(4 + path.len()).unwrap().to_le_bytes());
+        device_path.extend(path);
+        device_path.extend([END_DEVICE_PATH_TYPE, 0xff, 4, 0]);
+
+        let mut data = attributes.to_le_bytes().to_vec();
+        data.extend(u16::try_from(device_path.len()).unwrap().to_le_bytes());
+        data.extend(utf16_nul(description));
+        data.extend(device_path);
+        // Optional data, e.g. the arguments of Windows Boot Manager.
+        data.extend(b"WINDOWS");
+        data
+    }
+
+    fn write_global_variable(efivars: &Path, name: &str, value: &[u8]) {
+        let mut data = vec![0x07, 0x00, 0x00, 0x00];
+        data.extend(value);
+        fs::write(
+            efivars.join(format!("{name}-{EFI_GLOBAL_VARIABLE_GUID}")),
+            data,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn parse_load_option() {
+        let data = load_option(
+            LOAD_OPTION_ACTIVE,
+            "Linux Boot Manager",
+            "\\EFI\\systemd\\systemd-bootx64.efi",
+        );
+
+        let option = LoadOption::parse(&data).unwrap();
+
+        assert_eq!(option.description, "Linux Boot Manager");
+        assert_eq!(
+            option.file_path.as_deref(),
+            Some("\\EFI\\systemd\\systemd-bootx64.efi")
+        );
+        assert!(option.is_active());
+        assert!(LoadOption::parse(&data[..data.len() - 12]).is_err());
+    }
+
+    #[test]
+    fn resolve_sample_boot_order() {
+        let efivars = tempdir().unwrap();
+        // Windows was disabled, Boot0003 was removed but is still in the order.
+        write_global_variable(efivars.path(), "BootOrder", &[0, 0, 3, 0, 1, 0, 2, 0]);
+        write_global_variable(
+            efivars.path(),
+            "Boot0000",
+            &load_option(
+                0,
+                "Windows Boot Manager",
+                "\\EFI\\Microsoft\\Boot\\bootmgfw.efi",
+            ),
+        );
+        write_global_variable(
+            efivars.path(),
+            "Boot0001",
+            &load_option(
+                LOAD_OPTION_ACTIVE,
+                "Linux Boot Manager",
+                "\\EFI\\systemd\\systemd-bootx64.efi",
+            ),
+        );
+        write_global_variable(
+            efivars.path(),
+            "Boot0002",
+            &load_option(LOAD_OPTION_ACTIVE, "UEFI Shell", "\\shellx64.efi"),
+        );
+
+        let options = BootOptions::read(efivars.path()).unwrap();
+        let resolution = options.resolve();
+
+        assert_eq!(resolution.selected.map(|(number, _)| number), Some(1));
+        assert_eq!(
+            resolution.skipped,
+            [(0, Skipped::Inactive), (3, Skipped::Missing)]
+        );
+
+        // BootNext is booted once, even if it is not active.
+        write_global_variable(efivars.path(), "BootNext", &[0, 0]);
+        let options = BootOptions::read(efivars.path()).unwrap();
+        assert_eq!(
+            options.resolve().selected.map(|(number, _)| number),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn find_file_on_esp_case_insensitively() {
+        let esp = tempdir().unwrap();
+        fs::create_dir_all(esp.path().join("EFI/systemd")).unwrap();
+        fs::write(esp.path().join("EFI/systemd/systemd-bootx64.efi"), b"").unwrap();
+
+        assert_eq!(
+            find_on_esp(esp.path(), "\\EFI\\SYSTEMD\\SYSTEMD-BOOTX64.EFI").unwrap(),
+            Some(esp.path().join("EFI/systemd/systemd-bootx64.efi"))
+        );
+        assert_eq!(
+            BootTarget::classify(esp.path(), "\\EFI\\BOOT\\BOOTX64.EFI").unwrap(),
+            BootTarget::Missing
+        );
+        assert_eq!(
+            BootTarget::classify(esp.path(), "\\EFI\\systemd\\systemd-bootx64.efi").unwrap(),
+            BootTarget::Other
+        );
+    }
+}
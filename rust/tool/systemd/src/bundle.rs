@@ -0,0 +1,166 @@
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use base32ct::{Base32Unpadded, Encoding};
+use nix::unistd::syncfs;
+
+use crate::esp::SystemdEspPaths;
+use crate::install::set_permission_bits;
+use lanzaboote_tool::architecture::Architecture;
+use lanzaboote_tool::bundle::{unpack_bundle, BundleWriter};
+use lanzaboote_tool::esp::EspPaths;
+use lanzaboote_tool::gc::Roots;
+use lanzaboote_tool::generation::ENTRY_FILENAME_PREFIX;
+use lanzaboote_tool::signature::verify_signature;
+use lanzaboote_tool::utils::{file_hash, tmpname};
+
+/// Content-addressed kernels and initrds. Their names end with the hash of their contents.
+const NIXOS_DIR: &str = "EFI/nixos";
+/// The UKIs of the generations.
+const LINUX_DIR: &str = "EFI/Linux";
+/// systemd-boot.
+const SYSTEMD_DIR: &str = "EFI/systemd";
+/// The fallback copy of systemd-boot.
+const FALLBACK_DIR: &str = "EFI/BOOT";
+const LOADER_CONFIG: &str = "loader/loader.conf";
+
+/// Write the files that lzbt installed on `esp` to a bundle at `output`.
+///
+/// The ESP is usually a staging directory that was only ever installed to, so that the bundle
+/// contains exactly the files of one installation.
+pub fn export_bundle(esp: &Path, arch: Architecture, output: &Path) -> Result<()> {
+    let esp_paths = SystemdEspPaths::new(esp, arch);
+    let mut files = Vec::new();
+    for (dir, prefix) in [
+        (&esp_paths.nixos, ""),
+        (&esp_paths.linux, ENTRY_FILENAME_PREFIX),
+    ] {
+        let entries = fs::read_dir(dir).with_context(|| format!("Failed to read {dir:?}"))?;
+        let mut dir_files = entries
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>>>()?;
+        dir_files.retain(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(prefix))
+        });
+        dir_files.sort();
+        files.extend(dir_files);
+    }
+    files.extend([
+        esp_paths.systemd_boot.clone(),
+        esp_paths.efi_fallback.clone(),
+        esp_paths.systemd_boot_loader_config.clone(),
+    ]);
+
+    // Write to a temporary file first so that no partial bundle is left behind.
+    let output_tmp = output.with_file_name(tmpname());
+    let result = (|| {
+        let file = File::create(&output_tmp)
+            .with_context(|| format!("Failed to create {output_tmp:?}"))?;
+        let mut writer = BundleWriter::new(BufWriter::new(file));
+        for file in &files {
+            let name = file
+                .strip_prefix(esp)
+                .context("Failed to strip the ESP prefix from a bundled file.")?;
+            writer.add_file(name, file)?;
+        }
+        writer.finish()?;
+        fs::rename(&output_tmp, output)
+            .with_context(|| format!("Failed to move {output_tmp:?} to {output:?}"))
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&output_tmp);
+    }
+    result
+}
+
+/// Verify the bundle at `bundle` and install its files to `esp`.
+///
+/// The bundle is unpacked to a directory on the ESP first. Only if every binary in it is signed
+/// with `certificate` and every content-addressed file matches its hash, the files are renamed to
+/// their final location. Renaming is atomic, so every file on the ESP is either the old or the new
+/// one. The loader config is moved last. Files of generations that are not in the bundle are
+/// removed afterwards.
+pub fn apply_bundle(bundle: &Path, esp: &Path, certificate: &Path) -> Result<()> {
+    let staging = tempfile::Builder::new()
+        .prefix(".lzbt-bundle-")
+        .tempdir_in(esp)
+        .with_context(|| format!("Failed to create a staging directory on {esp:?}"))?;
+    let bundle_file = File::open(bundle).with_context(|| format!("Failed to open {bundle:?}"))?;
+    let mut files = unpack_bundle(std::io::BufReader::new(bundle_file), staging.path())
+        .with_context(|| format!("Failed to unpack {bundle:?}"))?;
+
+    for file in &files {
+        verify_bundled_file(staging.path(), file, certificate)?;
+    }
+
+    // Kernels and initrds have to be in place before the UKIs that refer to them.
+    files.sort_by_key(|file| {
+        let rank = [NIXOS_DIR, LINUX_DIR, SYSTEMD_DIR, FALLBACK_DIR]
+            .iter()
+            .position(|dir| file.starts_with(dir));
+        (rank.unwrap_or(usize::MAX), file.clone())
+    });
+    let mut gc_roots = Roots::new();
+    let (nixos, linux) = (esp.join(NIXOS_DIR), esp.join(LINUX_DIR));
+    gc_roots.extend([&nixos, &linux]);
+    for file in &files {
+        let from = staging.path().join(file);
+        let to = esp.join(file);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {parent:?}"))?;
+        }
+        set_permission_bits(&from, 0o755)?;
+        log::debug!("Installing {to:?}...");
+        fs::rename(&from, &to).with_context(|| format!("Failed to move {from:?} to {to:?}"))?;
+        gc_roots.extend([&to]);
+    }
+
+    let esp_dir = File::open(esp).context("Failed to open ESP root directory.")?;
+    syncfs(esp_dir.as_raw_fd()).context("Failed to sync ESP filesystem.")?;
+
+    log::info!("Collecting garbage...");
+    gc_roots.collect_garbage(&nixos)?;
+    gc_roots.collect_garbage_with_filter(&linux, |p| {
+        p.file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.starts_with(ENTRY_FILENAME_PREFIX))
+    })?;
+    Ok(())
+}
+
+/// Check a file of a bundle that was unpacked to `staging`.
+fn verify_bundled_file(staging: &Path, file: &Path, certificate: &Path) -> Result<()> {
+    let path = staging.join(file);
+    if file == Path::new(LOADER_CONFIG) {
+        return Ok(());
+    }
+    if file.parent() == Some(Path::new(NIXOS_DIR)) {
+        let expected_hash = file
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.rsplit('-').next())
+            .with_context(|| format!("The bundled file {file:?} is not content-addressed"))?;
+        let hash = Base32Unpadded::encode_string(&file_hash(&path)?);
+        if hash != expected_hash {
+            anyhow::bail!("The bundled file {file:?} does not match its hash");
+        }
+        return Ok(());
+    }
+    let signed_dirs = [LINUX_DIR, SYSTEMD_DIR, FALLBACK_DIR];
+    if !signed_dirs
+        .iter()
+        .any(|dir| file.parent() == Some(Path::new(dir)))
+    {
+        anyhow::bail!("The bundle contains {file:?}, which lzbt does not install");
+    }
+    if !verify_signature(certificate, &path)? {
+        anyhow::bail!("The bundled file {file:?} is not signed with {certificate:?}");
+    }
+    Ok(())
+}
@@ -0,0 +1,82 @@
+//! Canonical forms of the files that lzbt writes for systemd-boot.
+//!
+//! Files on the ESP are only rewritten when their contents change. Rendering them canonically
+//! makes their contents depend only on their meaning, so that the ESP is byte for byte the same
+//! after every installation of the same generations.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use lanzaboote_tool::utils::SecureTempDirExt;
+use tempfile::TempDir;
+
+/// The canonical form of a value in a loader entry or loader.conf: runs of whitespace, including
+/// newlines, become a single space and leading and trailing whitespace is removed.
+///
+/// Both formats are line based, so a value with a newline would otherwise start a new key.
+pub fn canonical_value(value: &str) -> String {
+    value.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The canonical form of the contents of a loader.conf.
+///
+/// Comments and empty lines are removed. systemd-boot uses the last occurrence of a key, so only
+/// that one is kept. The keys are sorted and separated from their values by a single space.
+/// Keys without a value are dropped, systemd-boot ignores them.
+pub fn canonical_loader_conf(contents: &str) -> String {
+    let mut keys = BTreeMap::new();
+    for line in contents.lines().map(str::trim) {
+        if line.starts_with('#') {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once(char::is_whitespace) {
+            keys.insert(key, canonical_value(value));
+        }
+    }
+    keys.into_iter()
+        .map(|(key, value)| format!("{key} {value}\n"))
+        .collect()
+}
+
+/// Write the canonical form of the loader.conf at `path` to a file in `tempdir`.
+pub fn write_canonical_loader_conf(path: &Path, tempdir: &TempDir) -> Result<PathBuf> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read loader.conf {path:?}"))?;
+    tempdir
+        .write_secure_file(canonical_loader_conf(&contents))
+        .context("Failed to write the canonical loader.conf")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collapse_whitespace_in_values() {
+        assert_eq!(
+            canonical_value("  init=/init\n\tquiet  loglevel=4 "),
+            "init=/init quiet loglevel=4"
+        );
+    }
+
+    #[test]
+    fn sort_loader_conf_and_keep_last_occurrence() {
+        let contents = "# Written by NixOS\n\
+                        timeout 5\n\
+                        \n\
+                        editor   no\n\
+                        console-mode keep\n\
+                        timeout\t3 \n\
+                        default\n";
+        assert_eq!(
+            canonical_loader_conf(contents),
+            "console-mode keep\neditor no\ntimeout 3\n"
+        );
+        assert_eq!(
+            canonical_loader_conf(&canonical_loader_conf(contents)),
+            canonical_loader_conf(contents)
+        );
+    }
+}
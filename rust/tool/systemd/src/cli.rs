@@ -1,11 +1,45 @@
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
 
-use crate::install;
+use crate::architecture::SystemdArchitectureExt;
+use crate::boot_order::{boot_option_name, BootOptions, BootTarget};
+use crate::bundle::{apply_bundle, export_bundle};
+use crate::canonical::write_canonical_loader_conf;
+use crate::esp::SystemdEspPaths;
+use crate::export::EntryLayout;
+use crate::install::{self, InstallerConfig};
+use crate::loader_entries::{
+    detect_mixed_installation, remove_stale_entries, scan_loader_entries, EntryKind,
+};
+use crate::loader_state::{LoaderState, EFIVARFS};
+use crate::lock::EspLock;
 use lanzaboote_tool::architecture::Architecture;
-use lanzaboote_tool::signature::KeyPair;
+use lanzaboote_tool::audit::{audit_esp, AuditPolicy};
+use lanzaboote_tool::authenticode::{attach_and_verify, signer_chains, NativeSigner, RemoteSigner};
+use lanzaboote_tool::cache::Cache;
+use lanzaboote_tool::cmdline::{
+    assemble_kernel_cmdline, merge_common_params, CmdlineAllowlist, CmdlineVariant,
+};
+use lanzaboote_tool::compression::Compression;
+use lanzaboote_tool::dbx::{SignatureDatabase, EFIVARFS_DBX};
+use lanzaboote_tool::deploy::{DeployInfo, Slot};
+use lanzaboote_tool::diff::UkiDiff;
+use lanzaboote_tool::esp::EspPaths;
+use lanzaboote_tool::firmware::{parse_revision, FirmwarePolicy};
+use lanzaboote_tool::generation::{Generation, GenerationLink};
+use lanzaboote_tool::os_release::MissingOsRelease;
+use lanzaboote_tool::pe;
+use lanzaboote_tool::pki::check_key_pair;
+use lanzaboote_tool::release::ReleaseVerifier;
+use lanzaboote_tool::selftest::SelfTestResult;
+use lanzaboote_tool::signature::{EngineKey, KeyPair, MultiSigner, PublicKey, Signer};
+use lanzaboote_tool::title::TitleTemplate;
+use lanzaboote_tool::uki::StubOptions;
+use lanzaboote_tool::utils::create_tempdir;
 
 /// The default log level.
 ///
@@ -26,7 +60,41 @@ pub struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    Install(InstallCommand),
+    Install(Box<InstallCommand>),
+    /// Remove the files lzbt installed to the ESP and install all generations from scratch. Files
+    /// of other operating systems on the ESP are kept
+    Reinit(Box<InstallCommand>),
+    /// Print the kernel command line that would be embedded for a generation
+    PrintDefaultCmdline(PrintDefaultCmdlineCommand),
+    /// Show which sections differ between two UKIs
+    Diff(DiffCommand),
+    /// Print the boot entry that systemd-boot boots by default
+    PrintDefaultEntry(PrintDefaultEntryCommand),
+    /// Print the boot option the firmware boots according to BootNext and BootOrder and whether
+    /// it starts lanzaboote
+    SimulateBoot(SimulateBootCommand),
+    /// Check that the signing key matches its certificate and that the certificate can be used
+    CheckPki(CheckPkiCommand),
+    /// Print the certificate chain of every Authenticode signature of a signed file
+    ShowCert(ShowCertCommand),
+    /// Embed a detached PKCS#7 signature of a UKI, e.g. from an offline signing machine, after
+    /// verifying that it covers the UKI and chains to a certificate
+    AttachSignature(AttachSignatureCommand),
+    /// Build a signed addon whose command line the stub appends to that of the UKIs it boots, for
+    /// loader/addons or the .extra.d directory of a UKI
+    BuildAddon(BuildAddonCommand),
+    /// Write the signed UKIs, systemd-boot and loader.conf installed on an ESP to a bundle
+    ExportBundle(ExportBundleCommand),
+    /// Verify the signatures in a bundle and install its files to an ESP
+    ApplyBundle(ApplyBundleCommand),
+    /// List the boot loader entries in loader/entries that lzbt did not install, e.g. the stale
+    /// ones left behind after migrating from systemd-boot, and warn if generations of both are
+    /// still installed
+    CheckLoaderEntries(CheckLoaderEntriesCommand),
+    /// Check offline that the boot loaders and UKIs on an ESP would boot: their signatures chain
+    /// to db, their SBAT generations are not revoked and the UKIs are complete and match their
+    /// PCR signatures
+    Audit(AuditCommand),
 }
 
 #[derive(Parser)]
@@ -43,25 +111,502 @@ struct InstallCommand {
     #[arg(long)]
     systemd_boot_loader_config: PathBuf,
 
+    /// Install the loader config in a canonical form: without comments, with the keys sorted and
+    /// only the last occurrence of each key. The installed file then only changes when the
+    /// configuration does
+    #[arg(long)]
+    canonical_loader_conf: bool,
+
     /// sbsign Public Key
     #[arg(long)]
     public_key: PathBuf,
 
     /// sbsign Private Key
-    #[arg(long)]
-    private_key: PathBuf,
+    #[arg(long, required_unless_present_any = ["engine", "sign_url"])]
+    private_key: Option<PathBuf>,
+
+    /// OpenSSL engine that holds the private key (e.g. tpm2tss). Requires --key-id and the sbsign
+    /// signer
+    #[arg(long, requires = "key_id", conflicts_with = "private_key")]
+    engine: Option<String>,
+
+    /// Identifier of the private key in the OpenSSL engine
+    #[arg(long, requires = "engine")]
+    key_id: Option<String>,
+
+    /// HTTPS URL of a remote signing service that holds the private key of --public-key. Only the
+    /// Authenticode digests of the installed binaries are sent to it. Plain HTTP is only accepted
+    /// for services on the loopback interface
+    #[arg(long, conflicts_with_all = ["private_key", "engine"])]
+    sign_url: Option<String>,
+
+    /// Bearer token that authorizes the requests to the remote signing service
+    #[arg(long, env = "LZBT_SIGN_TOKEN", hide_env_values = true)]
+    sign_token: Option<String>,
+
+    /// How to sign the installed binaries
+    #[arg(long, value_enum, default_value_t = SignerBackend::Sbsign)]
+    signer: SignerBackend,
+
+    /// Private key of a second key pair that signs every installed binary as well, so that
+    /// firmware trusting either certificate accepts them (e.g. while db is migrated to a new
+    /// key). Requires --additional-cert
+    #[arg(long, requires = "additional_cert")]
+    additional_key: Option<PathBuf>,
+
+    /// Certificate of the key pair given with --additional-key
+    #[arg(long, requires = "additional_key")]
+    additional_cert: Option<PathBuf>,
 
     /// Configuration limit
     #[arg(long, default_value_t = 1)]
     configuration_limit: usize,
 
+    /// Signature database in efivarfs format used to refuse installing revoked binaries
+    /// (defaults to the dbx of the running system)
+    #[arg(long)]
+    dbx: Option<PathBuf>,
+
+    /// Duplicate the log output of the stub to this serial device (index of the device or `off`)
+    #[arg(long, value_parser = parse_serial_console)]
+    serial_console: Option<String>,
+
+    /// Make the stub wait this many seconds before booting. Enter boots right away, any other key
+    /// pauses the countdown
+    #[arg(long)]
+    countdown: Option<u32>,
+
+    /// Make the stub skip verifying the initrd if Secure Boot is disabled, which speeds up booting
+    /// with large initrds. With Secure Boot, the initrd is always verified
+    #[arg(long)]
+    skip_initrd_verification_without_secure_boot: bool,
+
+    /// Make the stub refuse to boot if measuring the UKI into the TPM fails. By default, the
+    /// failure is logged and the boot continues
+    #[arg(long)]
+    strict_measurement: bool,
+
+    /// Record in the stub that it is deployed to this slot (a or b) of an A/B update scheme.
+    /// Requires --deploy-version
+    #[arg(long, requires = "deploy_version")]
+    deploy_slot: Option<Slot>,
+
+    /// Version of the deployed system recorded in the stub together with --deploy-slot
+    #[arg(long, requires = "deploy_slot")]
+    deploy_version: Option<String>,
+
+    /// Make the stub warn if the firmware revision is older than this, given in decimal or
+    /// prefixed with 0x in hexadecimal, because older firmware is known to be broken
+    #[arg(long, value_parser = parse_revision)]
+    min_firmware_revision: Option<u32>,
+
+    /// Only apply --min-firmware-revision to firmware of this vendor, as the meaning of the
+    /// revision differs between vendors
+    #[arg(long, requires = "min_firmware_revision")]
+    min_firmware_vendor: Option<String>,
+
+    /// Make the stub refuse to boot instead of only warning if the firmware is older than
+    /// --min-firmware-revision
+    #[arg(long, requires = "min_firmware_revision")]
+    refuse_old_firmware: bool,
+
+    /// Make the stub append the SMBIOS Type 11 OEM string that starts with this prefix to the
+    /// command line, e.g. io.systemd.stub.kernel-cmdline-extra=. This is only done if Secure Boot
+    /// is disabled, because anyone who can change the SMBIOS tables could inject parameters
+    #[arg(long)]
+    smbios_cmdline_prefix: Option<String>,
+
+    /// Make the stub wait for the user to confirm with a key press before booting if Secure Boot
+    /// is disabled, so that a machine whose Secure Boot was turned off by accident does not
+    /// silently boot unverified. Machines without a keyboard do not boot without Secure Boot
+    #[arg(long)]
+    confirm_insecure_boot: bool,
+
+    /// Make the stub start systemd-boot from the ESP instead of the kernel, so that the stub can
+    /// be booted by the firmware in front of the systemd-boot menu. When systemd-boot starts the
+    /// stub in turn, or systemd-boot is missing, the stub boots the kernel
+    #[arg(long)]
+    chainload_systemd_boot: bool,
+
+    /// Make the stub pass the path of the UKI to the kernel in front of the command line in its
+    /// LoadOptions, like the UEFI shell does, for kernels and userspace that expect it there
+    #[arg(long)]
+    load_options_image_path: bool,
+
+    /// Make the stub reboot into the firmware setup instead of booting if this key (F1 to F12,
+    /// Escape, Delete or a single character) is pressed while it starts, for machines on which
+    /// the key of the firmware is hard to hit in time. Requires firmware that supports
+    /// OsIndications
+    #[arg(long, value_parser = parse_firmware_setup_key)]
+    firmware_setup_key: Option<String>,
+
+    /// Make the stub refuse to boot if the embedded command line contains control characters
+    /// (e.g. newlines) or is longer than the kernel accepts. By default, the stub replaces control
+    /// characters with spaces and only warns about the length
+    #[arg(long)]
+    strict_cmdline: bool,
+
+    /// Make the stub append the .cmdline of the systemd addons (*.addon.efi) in loader/addons on
+    /// the ESP and in the directory <UKI>.extra.d next to the UKI. With Secure Boot, only addons
+    /// signed with a trusted key are applied
+    #[arg(long)]
+    cmdline_addons: bool,
+
+    /// Make the stub read and verify the initrd once more before it stops the boot because the
+    /// initrd does not match its hash. Works around firmware that returns wrong data on the first
+    /// read. An initrd that does not match twice still stops the boot
+    #[arg(long)]
+    retry_initrd_verification: bool,
+
+    /// Make the stub append this file on the ESP to the initrd of every generation, e.g. a large
+    /// initrd shared by all generations. The stub verifies the file against its hash in the UKI,
+    /// so the generations are reinstalled when it changes. The file must not be in EFI/nixos or
+    /// EFI/Linux, where lzbt deletes all files it did not install
+    #[arg(long)]
+    initrd_file: Option<PathBuf>,
+
+    /// Make the stub boot the next boot entry of systemd-boot, usually the previous generation,
+    /// once and reset if the kernel fails to start, instead of returning to the firmware
+    #[arg(long)]
+    fallback_on_kernel_failure: bool,
+
+    /// Make the stub read boot parameters from this TPM NV index, given in hexadecimal prefixed
+    /// with 0x (e.g. 0x01500016). A line cmdline-override=deny in the index ignores the command
+    /// line passed by the boot loader, cmdline-extra=deny the command line from SMBIOS and addons.
+    /// Without the index, the stub boots with its embedded configuration
+    #[arg(long, value_parser = parse_tpm_nv_index)]
+    tpm_nv_index: Option<u32>,
+
+    /// Make the stub print the PCRs it extended, the digests it extended them with and their
+    /// resulting values before starting the kernel, e.g. to compare them with the predictions in
+    /// .pcrsig when debugging measured boot
+    #[arg(long)]
+    show_measurements: bool,
+
+    /// Offer an additional command line in a boot menu of the stub, given as LABEL=PARAMS, where
+    /// PARAMS are appended to the command line of the generation (e.g. Debug=loglevel=7). Can be
+    /// given up to 9 times. The menu boots the default command line after --countdown seconds (5 by default)
+    #[arg(long = "cmdline-variant")]
+    cmdline_variants: Vec<CmdlineVariant>,
+
+    /// Kernel parameters added to the command line of every generation, separated by spaces
+    /// (e.g. "quiet console=ttyS0"). Parameters whose key a generation already sets are skipped
+    #[arg(long, default_value = "")]
+    common_cmdline: String,
+
+    /// Create or update a firmware boot entry with this label for the newest generation (requires
+    /// efibootmgr)
+    #[arg(long)]
+    efi_boot_entry: Option<String>,
+
+    /// Title of the boot entries, with the placeholders {generation}, {profile}, {date}, {kernel}
+    /// and {label} (e.g. "NixOS {generation} ({date})"). Defaults to the label followed by the
+    /// generation number and date
+    #[arg(long)]
+    title_template: Option<TitleTemplate>,
+
+    /// What to do if a generation has no etc/os-release to take its version from: warn and embed
+    /// an os-release that only describes the generation, or fail
+    #[arg(long, default_value_t = MissingOsRelease::Warn)]
+    missing_os_release: MissingOsRelease,
+
+    /// File listing the kernel command line parameters that may be embedded, one per line (e.g.
+    /// `quiet`, `loglevel=4` or `init=/nix/store/*`). Generations whose command line or command
+    /// line variants contain other parameters are not signed and the installation fails
+    #[arg(long)]
+    cmdline_allowlist: Option<PathBuf>,
+
+    /// Generation numbers that are never installed (e.g. known broken ones)
+    #[arg(long, value_delimiter = ',')]
+    exclude: Vec<u64>,
+
+    /// Compress the initrd before installing it (none, gzip or zstd). Initrds that are already
+    /// compressed are installed as is
+    #[arg(long, default_value_t = Compression::None)]
+    initrd_compression: Compression,
+
+    /// Directory that keeps the compressed initrds between installations, so that unchanged
+    /// initrds are not compressed again. Initrds with secrets are never cached
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// Compress the entries of --cache-dir (none, gzip or zstd), to save space when many
+    /// generations are cached
+    #[arg(long, default_value_t = Compression::None, requires = "cache_dir")]
+    cache_compression: Compression,
+
+    /// Embed a hash of the stub that it verifies before booting, to detect corruption in memory
+    #[arg(long)]
+    self_hash: bool,
+
+    /// Embed the hash of every section of the stub in a `.sechash` section. The stub verifies
+    /// each section against it before booting, to detect which section was tampered with
+    #[arg(long)]
+    section_hashes: bool,
+
+    /// Executable to run after a successful installation. It receives the ESP in LANZABOOTE_ESP
+    /// and the installed UKIs, separated by newlines, in LANZABOOTE_UKIS
+    #[arg(long)]
+    post_hook: Option<PathBuf>,
+
+    /// Write a JSON manifest with the digests, signer, signature time and predicted PCR 11 value
+    /// of every installed UKI to this path
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+
+    /// Record the installed UKIs on the ESP and only assemble, sign and write the generations that
+    /// are new or whose UKI changed since the last installation with this flag
+    #[arg(long)]
+    incremental: bool,
+
+    /// Only sign and install systemd-boot, even if it is up to date, and leave the installed UKIs
+    /// untouched
+    #[arg(long)]
+    stub_only: bool,
+
+    /// Also write a Type #1 boot loader entry for every UKI to loader/entries, for tooling that
+    /// does not understand UKIs. `split` entries boot the kernel and initrd directly, without
+    /// the verification by the stub, and do not boot with Secure Boot
+    #[arg(long, value_enum)]
+    type1_entries: Option<EntryLayout>,
+
+    /// Instead of installing, write loader.conf, the list of UKIs and the boot entries that would
+    /// be on the ESP to this directory. Nothing is built or signed
+    #[arg(long, conflicts_with = "stub_only")]
+    export: Option<PathBuf>,
+
+    /// Instead of installing, rebuild the UKIs and compare them byte for byte with the ones
+    /// installed to this ESP (e.g. by another build machine). Fails if any of them differ
+    #[arg(long, conflicts_with_all = ["stub_only", "export"])]
+    reproduce: Option<PathBuf>,
+
+    /// Instead of installing, print the files on the ESP that the installation would add, update
+    /// or remove and why. Nothing is built, signed or written. Fails if any changes are pending
+    #[arg(long, conflicts_with_all = ["stub_only", "export", "reproduce"])]
+    plan: bool,
+
+    /// Do not lock the ESP against other instances installing to it at the same time
+    #[arg(long)]
+    no_lock: bool,
+
+    /// Refuse to install unless the self-test image reported on this machine that its firmware
+    /// supports LoadFile2
+    #[arg(long)]
+    require_self_test: bool,
+
+    /// Directory for intermediate files, which are removed afterwards, also on failure (defaults
+    /// to TMPDIR)
+    #[arg(long)]
+    work_dir: Option<PathBuf>,
+
+    /// GPG keyring (as exported by `gpg --export`) with the keys of release managers. Every
+    /// generation must carry a valid release signature from one of them to be installed
+    #[arg(long, requires = "release_signatures")]
+    release_keyring: Option<PathBuf>,
+
+    /// Directory with the detached release signatures of the toplevels, named `<hash>.sig` after
+    /// the hash of the toplevel store path
+    #[arg(long, requires = "release_keyring")]
+    release_signatures: Option<PathBuf>,
+
     /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(long, env = "LANZABOOTE_ESP")]
     esp: PathBuf,
 
     /// List of generation links (e.g. /nix/var/nix/profiles/system-*-link)
     generations: Vec<PathBuf>,
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum SignerBackend {
+    /// Run sbsign for every binary
+    Sbsign,
+    /// Sign in-process, loading the key only once
+    Native,
+}
+
+#[derive(Parser)]
+struct PrintDefaultCmdlineCommand {
+    /// Print the command line of this specialisation instead of the generation itself
+    #[arg(long)]
+    specialisation: Option<String>,
+
+    /// Kernel parameters added to the command line of every generation, separated by spaces
+    /// (e.g. "quiet console=ttyS0"). Parameters whose key a generation already sets are skipped
+    #[arg(long, default_value = "")]
+    common_cmdline: String,
+
+    /// Generation link (e.g. /nix/var/nix/profiles/system-1-link)
+    generation: PathBuf,
+}
+
+#[derive(Parser)]
+struct DiffCommand {
+    /// The first UKI
+    old: PathBuf,
+
+    /// The second UKI
+    new: PathBuf,
+}
+
+#[derive(Parser)]
+struct PrintDefaultEntryCommand {
+    /// Directory containing the EFI variables
+    #[arg(long, default_value = EFIVARFS)]
+    efivars: PathBuf,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(env = "LANZABOOTE_ESP")]
+    esp: PathBuf,
+}
+
+#[derive(Parser)]
+struct SimulateBootCommand {
+    /// Directory containing the EFI variables, e.g. efivarfs or a copy of it
+    #[arg(long, default_value = EFIVARFS)]
+    efivars: PathBuf,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(env = "LANZABOOTE_ESP")]
+    esp: PathBuf,
+}
+
+#[derive(Parser)]
+struct CheckPkiCommand {
+    /// sbsign Public Key
+    #[arg(long)]
+    public_key: PathBuf,
+
+    /// sbsign Private Key
+    #[arg(long)]
+    private_key: PathBuf,
+}
+
+#[derive(Parser)]
+struct ShowCertCommand {
+    /// Signed PE binary (e.g. a UKI or systemd-boot)
+    file: PathBuf,
+}
+
+#[derive(Parser)]
+struct AttachSignatureCommand {
+    /// PE binary (e.g. a UKI) that is signed in place
+    file: PathBuf,
+
+    /// Detached PKCS#7 signature of the binary in DER or PEM format (e.g. a .p7s file)
+    #[arg(long)]
+    signature: PathBuf,
+
+    /// Certificate the signer has to chain to
+    #[arg(long)]
+    certificate: PathBuf,
+}
+
+#[derive(Parser)]
+struct BuildAddonCommand {
+    /// System for lanzaboote binaries, e.g. defines the addon stub of systemd
+    #[arg(long)]
+    system: String,
+
+    /// Systemd path
+    #[arg(long)]
+    systemd: PathBuf,
+
+    /// Kernel parameters to append, separated by spaces
+    #[arg(long, value_parser = parse_addon_cmdline)]
+    cmdline: String,
+
+    /// File with SBAT entries (CSV) of the addon, appended to those of the addon stub
+    #[arg(long)]
+    sbat: Option<PathBuf>,
+
+    /// sbsign Public Key
+    #[arg(long)]
+    public_key: PathBuf,
+
+    /// sbsign Private Key
+    #[arg(long)]
+    private_key: PathBuf,
+
+    /// How to sign the addon
+    #[arg(long, value_enum, default_value_t = SignerBackend::Sbsign)]
+    signer: SignerBackend,
+
+    /// Path of the addon to write, named *.addon.efi
+    #[arg(long)]
+    out: PathBuf,
+
+    /// Directory for intermediate files, which are removed afterwards, also on failure (defaults
+    /// to TMPDIR)
+    #[arg(long)]
+    work_dir: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+struct ExportBundleCommand {
+    /// System the ESP was installed for, e.g. defines the EFI fallback path
+    #[arg(long)]
+    system: String,
+
+    /// Path of the bundle to write
+    #[arg(long)]
+    output: PathBuf,
+
+    /// ESP (usually a staging directory) that lzbt installed to
+    esp: PathBuf,
+}
+
+#[derive(Parser)]
+struct ApplyBundleCommand {
+    /// Certificate that the binaries in the bundle must be signed with
+    #[arg(long)]
+    public_key: PathBuf,
+
+    /// Do not lock the ESP against other instances installing to it at the same time
+    #[arg(long)]
+    no_lock: bool,
+
+    /// The bundle created by export-bundle
+    bundle: PathBuf,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(env = "LANZABOOTE_ESP")]
+    esp: PathBuf,
+}
+
+#[derive(Parser)]
+struct AuditCommand {
+    /// Certificate in db in PEM format that the binaries may be signed with. Can be given
+    /// multiple times
+    #[arg(long = "db", required = true)]
+    db: Vec<PathBuf>,
+
+    /// SBAT level in the CSV format of shim (e.g. SbatLevel_Variable.txt) whose revocations the
+    /// binaries are checked against. Without it, SBAT is not checked
+    #[arg(long)]
+    sbat_level: Option<PathBuf>,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(env = "LANZABOOTE_ESP")]
+    esp: PathBuf,
+}
+
+#[derive(Parser)]
+struct CheckLoaderEntriesCommand {
+    /// Remove the foreign entries that were left behind by the systemd-boot installer of NixOS.
+    /// Entries of other operating systems are never removed
+    #[arg(long)]
+    clean_foreign_entries: bool,
+
+    /// Do not lock the ESP against other instances installing to it at the same time
+    #[arg(long)]
+    no_lock: bool,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    #[arg(env = "LANZABOOTE_ESP")]
+    esp: PathBuf,
+}
+
 impl Cli {
     pub fn call(self, module: &str) {
         stderrlog::new()
@@ -82,26 +627,511 @@ impl Cli {
 impl Commands {
     pub fn call(self) -> Result<()> {
         match self {
-            Commands::Install(args) => install(args),
+            Commands::Install(args) => install(*args, false),
+            Commands::Reinit(args) => install(*args, true),
+            Commands::PrintDefaultCmdline(args) => print_default_cmdline(args),
+            Commands::Diff(args) => diff(args),
+            Commands::PrintDefaultEntry(args) => print_default_entry(args),
+            Commands::SimulateBoot(args) => simulate_boot(args),
+            Commands::CheckPki(args) => check_pki(args),
+            Commands::ShowCert(args) => show_cert(args),
+            Commands::AttachSignature(args) => {
+                attach_and_verify(&args.file, &args.signature, &args.certificate)
+            }
+            Commands::BuildAddon(args) => build_addon(args),
+            Commands::ExportBundle(args) => export_bundle(
+                &args.esp,
+                Architecture::from_nixos_system(&args.system)?,
+                &args.output,
+            ),
+            Commands::ApplyBundle(args) => {
+                let _lock = if args.no_lock {
+                    None
+                } else {
+                    Some(EspLock::acquire(&args.esp)?)
+                };
+                apply_bundle(&args.bundle, &args.esp, &args.public_key)
+            }
+            Commands::CheckLoaderEntries(args) => check_loader_entries(args),
+            Commands::Audit(args) => audit(args),
         }
     }
 }
 
-fn install(args: InstallCommand) -> Result<()> {
+fn install(args: InstallCommand, reinit: bool) -> Result<()> {
+    if reinit && (args.stub_only || args.export.is_some() || args.reproduce.is_some() || args.plan)
+    {
+        bail!("reinit cannot be combined with --stub-only, --export, --reproduce or --plan");
+    }
+
     let lanzaboote_stub =
         std::env::var("LANZABOOTE_STUB").context("Failed to read LANZABOOTE_STUB env variable")?;
 
+    let signer: Box<dyn Signer> = if let (Some(engine), Some(key_id)) = (&args.engine, &args.key_id)
+    {
+        if matches!(args.signer, SignerBackend::Native) {
+            bail!("The native signer cannot use keys in OpenSSL engines, use sbsign instead");
+        }
+        Box::new(
+            EngineKey::new(engine, key_id, &args.public_key)
+                .with_work_dir(args.work_dir.as_deref()),
+        )
+    } else if let Some(sign_url) = &args.sign_url {
+        Box::new(RemoteSigner::new(
+            sign_url,
+            args.sign_token.as_deref(),
+            &args.public_key,
+        )?)
+    } else {
+        let private_key = args
+            .private_key
+            .as_deref()
+            .context("Either --private-key or --engine is required")?;
+        let key_pair =
+            KeyPair::new(&args.public_key, private_key).with_work_dir(args.work_dir.as_deref());
+        match args.signer {
+            SignerBackend::Sbsign => Box::new(key_pair),
+            SignerBackend::Native => Box::new(NativeSigner::new(&key_pair)?),
+        }
+    };
+    let signer: Box<dyn Signer> = if let (Some(private_key), Some(public_key)) =
+        (&args.additional_key, &args.additional_cert)
+    {
+        let additional_key_pair =
+            KeyPair::new(public_key, private_key).with_work_dir(args.work_dir.as_deref());
+        let additional: Box<dyn Signer> = match args.signer {
+            SignerBackend::Sbsign => Box::new(additional_key_pair),
+            SignerBackend::Native => Box::new(NativeSigner::new(&additional_key_pair)?),
+        };
+        Box::new(MultiSigner::new(signer, vec![additional]).with_work_dir(args.work_dir.as_deref()))
+    } else {
+        signer
+    };
+
+    let dbx = match &args.dbx {
+        Some(dbx) => SignatureDatabase::from_efivarfs(dbx)?,
+        None => read_system_dbx(),
+    };
+
+    if let Some(template) = &args.title_template {
+        if !template.has_generation() {
+            log::warn!("The title template \"{template}\" does not contain {{generation}}, so the boot entries of different generations may be indistinguishable.");
+        }
+    }
+
+    if args.type1_entries == Some(EntryLayout::Split) {
+        log::warn!("The Type #1 entries with the split layout boot the kernel and initrd without verifying them and do not boot with Secure Boot.");
+    }
+
+    let cmdline_allowlist = args
+        .cmdline_allowlist
+        .as_deref()
+        .map(CmdlineAllowlist::from_file)
+        .transpose()?;
+
+    if args.require_self_test {
+        check_self_test(&args.esp)?;
+    }
+
+    // Exporting, reproducing and planning do not write to the ESP, so they do not need the lock.
+    let _lock = if args.no_lock || args.export.is_some() || args.reproduce.is_some() || args.plan {
+        None
+    } else {
+        Some(EspLock::acquire(&args.esp)?)
+    };
+
+    // The canonical loader config is kept until the installation is done.
+    let _loader_conf_dir;
+    let systemd_boot_loader_config = if args.canonical_loader_conf {
+        _loader_conf_dir = create_tempdir(args.work_dir.as_deref())?;
+        write_canonical_loader_conf(&args.systemd_boot_loader_config, &_loader_conf_dir)?
+    } else {
+        args.systemd_boot_loader_config
+    };
+
+    let cache = args
+        .cache_dir
+        .as_deref()
+        .map(|dir| Cache::new(dir, args.cache_compression))
+        .transpose()?;
+
+    let initrd_path = args
+        .initrd_file
+        .as_deref()
+        .map(|file| pe::initrd_path_section(&args.esp, file))
+        .transpose()?;
+
+    let mut installer = install::Installer::new(InstallerConfig {
+        lanzaboote_stub: PathBuf::from(lanzaboote_stub),
+        arch: Architecture::from_nixos_system(&args.system)?,
+        systemd: args.systemd,
+        systemd_boot_loader_config,
+        public_key: PublicKey::new(&args.public_key),
+        signer,
+        additional_certificate: args.additional_cert,
+        configuration_limit: args.configuration_limit,
+        esp: args.esp,
+        generation_links: args.generations,
+        dbx,
+        stub_options: StubOptions {
+            serial_console: args.serial_console,
+            countdown: args.countdown,
+            skip_initrd_verification: args.skip_initrd_verification_without_secure_boot,
+            strict_measurement: args.strict_measurement,
+            deploy: args
+                .deploy_slot
+                .zip(args.deploy_version)
+                .map(|(slot, version)| DeployInfo::new(slot, version)),
+            firmware_policy: args
+                .min_firmware_revision
+                .map(|min_revision| FirmwarePolicy {
+                    min_revision,
+                    vendor: args.min_firmware_vendor,
+                    refuse: args.refuse_old_firmware,
+                }),
+            smbios_cmdline_prefix: args.smbios_cmdline_prefix,
+            confirm_insecure_boot: args.confirm_insecure_boot,
+            load_options_image_path: args.load_options_image_path,
+            firmware_setup_key: args.firmware_setup_key,
+            strict_cmdline: args.strict_cmdline,
+            cmdline_addons: args.cmdline_addons,
+            retry_initrd_verification: args.retry_initrd_verification,
+            initrd_path,
+            next_entry_on_kernel_failure: args.fallback_on_kernel_failure,
+            tpm_nv_index: args.tpm_nv_index,
+            show_measurements: args.show_measurements,
+        },
+        chainload_systemd_boot: args.chainload_systemd_boot,
+        cmdline_variants: args.cmdline_variants,
+        boot_entry_label: args.efi_boot_entry,
+        excluded_gens: args.exclude.into_iter().collect(),
+        initrd_compression: args.initrd_compression,
+        self_hash: args.self_hash,
+        section_hashes: args.section_hashes,
+        post_hook: args.post_hook,
+        manifest: args.manifest,
+        incremental: args.incremental,
+        work_dir: args.work_dir,
+        release_verifier: args
+            .release_keyring
+            .zip(args.release_signatures)
+            .map(|(keyring, signatures)| ReleaseVerifier::new(&keyring, &signatures)),
+        title_template: args.title_template,
+        missing_os_release: args.missing_os_release,
+        cmdline_allowlist,
+        common_cmdline: split_params(&args.common_cmdline),
+        type1_entries: args.type1_entries,
+        cache,
+    });
+    if let Some(dir) = &args.export {
+        installer.export(dir)
+    } else if let Some(reference) = &args.reproduce {
+        installer.reproduce(reference)
+    } else if args.plan {
+        let plan = installer.plan()?;
+        print!("{plan}");
+        if !plan.is_empty() {
+            bail!("{} changes to the ESP are pending", plan.changes().len());
+        }
+        log::info!("The ESP is up to date.");
+        Ok(())
+    } else if args.stub_only {
+        installer.install_stub()
+    } else if reinit {
+        installer.reinit()
+    } else {
+        installer.install()
+    }
+}
+
+/// Split a command line into its parameters.
+fn split_params(cmdline: &str) -> Vec<String> {
+    cmdline.split_whitespace().map(String::from).collect()
+}
+
+/// Check that a serial console is in the format understood by the stub.
+fn parse_serial_console(value: &str) -> Result<String, String> {
+    if value == "off" || value.parse::<usize>().is_ok() {
+        Ok(value.to_string())
+    } else {
+        Err(format!(
+            "expected the index of a serial device or `off`, got `{value}`"
+        ))
+    }
+}
+
+/// Check that a firmware setup key is one that the stub recognizes.
+fn parse_firmware_setup_key(value: &str) -> Result<String, String> {
+    let function_key = value
+        .strip_prefix('F')
+        .and_then(|number| number.parse::<u8>().ok())
+        .is_some_and(|number| (1..=12).contains(&number));
+    let character = value.len() == 1 && value.chars().all(|c| c.is_ascii_graphic());
+    if function_key || character || value == "Escape" || value == "Delete" {
+        Ok(value.to_string())
+    } else {
+        Err(format!(
+            "expected F1 to F12, Escape, Delete or a single character, got `{value}`"
+        ))
+    }
+}
+
+/// Check that an addon command line can be embedded as it is.
+fn parse_addon_cmdline(value: &str) -> Result<String, String> {
+    // The stub replaces control characters in addons with spaces, so the addon would not boot
+    // with the command line that was asked for.
+    if value.chars().any(char::is_control) {
+        return Err("the command line must not contain control characters".into());
+    }
+    Ok(value.trim().to_owned())
+}
+
+/// Check that a TPM handle is in the format understood by the stub and refers to an NV index.
+fn parse_tpm_nv_index(value: &str) -> Result<u32, String> {
+    let index = value
+        .strip_prefix("0x")
+        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        .ok_or_else(|| {
+            format!("expected a handle in hexadecimal prefixed with 0x, got `{value}`")
+        })?;
+    // The most significant byte of a handle is its type, 0x01 for NV indexes.
+    if index >> 24 != 0x01 {
+        return Err(format!("`{value}` is not the handle of an NV index"));
+    }
+    Ok(index)
+}
+
+/// Check the result of the self-test on the ESP.
+///
+/// Only a missing LoadFile2 makes every generation unbootable. The other capabilities are optional.
+fn check_self_test(esp: &Path) -> Result<()> {
+    let result = SelfTestResult::from_esp(esp)?;
+    if !result.load_file2 {
+        bail!("The self-test reported that the firmware does not support LoadFile2, so the stub cannot hand the initrd to Linux");
+    }
+    if !result.tcg2 {
+        log::warn!("The self-test found no TPM, measured boot is not available.");
+    }
+    if !result.gop {
+        log::warn!("The self-test found no graphical console.");
+    }
+    Ok(())
+}
+
+/// Read the dbx of the running system.
+///
+/// Failing to read the dbx is not fatal because there are systems without one, e.g. when Secure
+/// Boot is not set up yet.
+fn read_system_dbx() -> SignatureDatabase {
+    let path = Path::new(EFIVARFS_DBX);
+    if !path.exists() {
+        return SignatureDatabase::default();
+    }
+    SignatureDatabase::from_efivarfs(path).unwrap_or_else(|e| {
+        log::warn!(
+            "Failed to read the dbx of this system, not checking for revoked binaries: {e:#}"
+        );
+        SignatureDatabase::default()
+    })
+}
+
+fn print_default_cmdline(args: PrintDefaultCmdlineCommand) -> Result<()> {
+    let link = GenerationLink::from_path(&args.generation)?;
+    let generation = Generation::from_link(&link)
+        .with_context(|| format!("Failed to build generation from link: {link:?}"))?;
+
+    let bootspec = match &args.specialisation {
+        Some(name) => {
+            &generation
+                .spec
+                .bootspec
+                .specialisations
+                .iter()
+                .find_map(|(n, s)| (n.0 == *name).then_some(s))
+                .with_context(|| format!("Generation {generation} has no specialisation {name}"))?
+                .bootspec
+        }
+        None => &generation.spec.bootspec.bootspec,
+    };
+
+    let kernel_cmdline = merge_common_params(
+        assemble_kernel_cmdline(&bootspec.init, bootspec.kernel_params.clone()),
+        &split_params(&args.common_cmdline),
+    );
+    println!("{}", kernel_cmdline.join(" "));
+
+    Ok(())
+}
+
+fn diff(args: DiffCommand) -> Result<()> {
+    let old = fs::read(&args.old).with_context(|| format!("Failed to read {:?}", args.old))?;
+    let new = fs::read(&args.new).with_context(|| format!("Failed to read {:?}", args.new))?;
+
+    let diff = UkiDiff::new(&old, &new)?;
+    if diff.is_empty() {
+        println!("All sections are identical.");
+    } else {
+        print!("{diff}");
+    }
+
+    Ok(())
+}
+
+fn print_default_entry(args: PrintDefaultEntryCommand) -> Result<()> {
+    let state = LoaderState::read(&args.esp.join("loader/loader.conf"), &args.efivars)?;
+    let (entry, source) = state
+        .effective_default()
+        .context("No default boot entry is configured")?;
+    println!("{entry}");
+    log::info!("The default boot entry is taken from the {source}.");
+
+    Ok(())
+}
+
+fn simulate_boot(args: SimulateBootCommand) -> Result<()> {
+    let options = BootOptions::read(&args.efivars)?;
+    let resolution = options.resolve();
+    for (number, reason) in &resolution.skipped {
+        log::info!(
+            "Skipping {} because it {reason}.",
+            boot_option_name(*number)
+        );
+    }
+    let (number, option) = resolution.selected.context(
+        "No option in BootNext or BootOrder can be booted, the firmware falls back to its default \
+         boot behavior",
+    )?;
+
+    let name = boot_option_name(number);
+    let Some(file_path) = &option.file_path else {
+        println!("{name} {}: no file path", option.description);
+        return Ok(());
+    };
+    let target = BootTarget::classify(&args.esp, file_path)?;
+    println!("{name} {}: {file_path} ({target})", option.description);
+    if target == BootTarget::SystemdBoot {
+        log::info!("systemd-boot boots its default entry, see print-default-entry.");
+    }
+
+    Ok(())
+}
+
+fn check_pki(args: CheckPkiCommand) -> Result<()> {
     let key_pair = KeyPair::new(&args.public_key, &args.private_key);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("The system time is before the Unix epoch")?;
+
+    let problems = check_key_pair(&key_pair, now)?;
+    if problems.is_empty() {
+        println!("The key pair is valid.");
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{problem}.");
+    }
+    anyhow::bail!("Found {} problem(s) with the key pair", problems.len());
+}
+
+fn show_cert(args: ShowCertCommand) -> Result<()> {
+    let file_data =
+        fs::read(&args.file).with_context(|| format!("Failed to read {:?}", args.file))?;
+    let chains = signer_chains(&file_data)
+        .with_context(|| format!("Failed to read the signatures of {:?}", args.file))?;
+    if chains.is_empty() {
+        bail!("{:?} is not signed", args.file);
+    }
 
-    install::Installer::new(
-        PathBuf::from(lanzaboote_stub),
-        Architecture::from_nixos_system(&args.system)?,
-        args.systemd,
-        args.systemd_boot_loader_config,
-        key_pair,
-        args.configuration_limit,
-        args.esp,
-        args.generations,
-    )
-    .install()
+    for (index, chain) in chains.iter().enumerate() {
+        if index > 0 {
+            println!();
+        }
+        println!("Signature {}:", index + 1);
+        for (position, certificate) in chain.iter().enumerate() {
+            if position > 0 {
+                println!();
+            }
+            for line in certificate.to_string().lines() {
+                println!("  {line}");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn build_addon(args: BuildAddonCommand) -> Result<()> {
+    let system = Architecture::from_nixos_system(&args.system)?;
+    let addon_stub = args
+        .systemd
+        .join("lib/systemd/boot/efi")
+        .join(system.systemd_addon_stub_filename());
+    let sbat = args
+        .sbat
+        .as_ref()
+        .map(|path| fs::read_to_string(path).with_context(|| format!("Failed to read {path:?}")))
+        .transpose()?;
+    let key_pair =
+        KeyPair::new(&args.public_key, &args.private_key).with_work_dir(args.work_dir.as_deref());
+    let signer: Box<dyn Signer> = match args.signer {
+        SignerBackend::Sbsign => Box::new(key_pair),
+        SignerBackend::Native => Box::new(NativeSigner::new(&key_pair)?),
+    };
+
+    pe::build_addon(
+        signer.as_ref(),
+        &addon_stub,
+        &args.cmdline,
+        sbat.as_deref(),
+        &args.out,
+        args.work_dir.as_deref(),
+    )?;
+    log::info!("Built addon {:?}.", args.out);
+    Ok(())
+}
+
+fn check_loader_entries(args: CheckLoaderEntriesCommand) -> Result<()> {
+    // The entries do not depend on the architecture.
+    let esp_paths = SystemdEspPaths::new(&args.esp, Architecture::X86);
+    let _lock = if args.no_lock || !args.clean_foreign_entries {
+        None
+    } else {
+        Some(EspLock::acquire(&args.esp)?)
+    };
+
+    let entries = scan_loader_entries(&esp_paths)?;
+    for entry in &entries {
+        let name = entry.path.file_name().unwrap_or_default().to_string_lossy();
+        println!("{name}: {}", entry.kind);
+    }
+
+    let stale = entries
+        .iter()
+        .filter(|e| e.kind == EntryKind::Stale)
+        .count();
+    if args.clean_foreign_entries {
+        remove_stale_entries(&entries)?;
+    } else if let Some(mixed) = detect_mixed_installation(&esp_paths, &entries)? {
+        log::warn!("{mixed}");
+    } else if stale > 0 {
+        log::warn!("Found {stale} stale boot entries, remove them with --clean-foreign-entries.");
+    }
+    Ok(())
+}
+
+fn audit(args: AuditCommand) -> Result<()> {
+    let policy = AuditPolicy::read(&args.db, args.sbat_level.as_deref())?;
+    let report = audit_esp(&args.esp, &policy)?;
+    if report.entries.is_empty() {
+        bail!("Found no boot loaders or UKIs on {:?}", args.esp);
+    }
+
+    print!("{report}");
+    if !report.passed() {
+        bail!(
+            "{} of {} binaries failed the audit",
+            report.failed(),
+            report.entries.len()
+        );
+    }
+    Ok(())
 }
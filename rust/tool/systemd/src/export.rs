@@ -0,0 +1,130 @@
+use std::fmt;
+
+use anyhow::Result;
+use clap::ValueEnum;
+
+use lanzaboote_tool::generation::Generation;
+use lanzaboote_tool::os_release::OsRelease;
+use lanzaboote_tool::title::TitleTemplate;
+
+use crate::canonical::canonical_value;
+
+/// How the Type #1 entries written by `--type1-entries` boot a generation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EntryLayout {
+    /// Start the UKI with an `efi` line
+    Uki,
+    /// Let systemd-boot start the kernel and initrd installed for the UKI with `linux` and
+    /// `initrd` lines. This bypasses the verification by the stub and does not boot with Secure
+    /// Boot
+    Split,
+}
+
+/// What an entry boots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryImage {
+    /// The path of a UKI relative to the ESP.
+    Uki(String),
+    /// The paths of a kernel and its initrd, if any, relative to the ESP.
+    Split {
+        linux: String,
+        initrd: Option<String>,
+    },
+}
+
+/// The boot entry that systemd-boot shows for an installed UKI, in the format of a [Type #1 boot
+/// loader entry](https://uapi-group.org/specifications/specs/boot_loader_specification/).
+///
+/// systemd-boot derives the entry from the `.osrel` and `.cmdline` sections of the UKI. Rendering
+/// it as a file makes it easy to inspect and back up, and it can be installed for tooling that
+/// only understands Type #1 entries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoaderEntry {
+    pub title: String,
+    pub version: String,
+    pub image: EntryImage,
+    pub options: String,
+}
+
+impl LoaderEntry {
+    /// Render the entry for a generation that boots `image` with `kernel_cmdline`.
+    pub fn new(
+        generation: &Generation,
+        image: EntryImage,
+        title_template: Option<&TitleTemplate>,
+        kernel_cmdline: &[String],
+    ) -> Result<Self> {
+        let mut os_release = OsRelease::from_generation(generation, title_template)?.0;
+        let mut field = |key: &str| os_release.remove(key).unwrap_or_default();
+        Ok(Self {
+            title: field("PRETTY_NAME"),
+            version: field("VERSION_ID"),
+            image,
+            options: kernel_cmdline.join(" "),
+        })
+    }
+}
+
+impl fmt::Display for LoaderEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The keys are always written in the same order and the values are canonical, so that the
+        // same entry is rendered to the same bytes.
+        writeln!(f, "title {}", canonical_value(&self.title))?;
+        writeln!(f, "version {}", canonical_value(&self.version))?;
+        match &self.image {
+            EntryImage::Uki(efi) => writeln!(f, "efi {efi}")?,
+            EntryImage::Split { linux, initrd } => {
+                writeln!(f, "linux {linux}")?;
+                if let Some(initrd) = initrd {
+                    writeln!(f, "initrd {initrd}")?;
+                }
+            }
+        }
+        writeln!(f, "options {}", canonical_value(&self.options))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_loader_entry() {
+        let entry = LoaderEntry {
+            title: "LanzaOS (Generation 1, 1970-01-01)".into(),
+            version: "Generation 1, 1970-01-01".into(),
+            image: EntryImage::Uki("/EFI/Linux/nixos-generation-1.efi".into()),
+            options: "init=/init quiet".into(),
+        };
+
+        assert_eq!(
+            entry.to_string(),
+            "title LanzaOS (Generation 1, 1970-01-01)\n\
+             version Generation 1, 1970-01-01\n\
+             efi /EFI/Linux/nixos-generation-1.efi\n\
+             options init=/init quiet\n"
+        );
+    }
+
+    #[test]
+    fn render_split_loader_entry() {
+        let entry = LoaderEntry {
+            title: "LanzaOS".into(),
+            version: "Generation 1".into(),
+            image: EntryImage::Split {
+                linux: "/EFI/nixos/kernel-6.1-abc.efi".into(),
+                initrd: Some("/EFI/nixos/initrd-6.1-def.efi".into()),
+            },
+            options: "init=/init".into(),
+        };
+
+        assert_eq!(
+            entry.to_string(),
+            "title LanzaOS\n\
+             version Generation 1\n\
+             linux /EFI/nixos/kernel-6.1-abc.efi\n\
+             initrd /EFI/nixos/initrd-6.1-def.efi\n\
+             options init=/init\n"
+        );
+    }
+}
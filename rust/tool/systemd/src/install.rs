@@ -1,4 +1,4 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashSet};
 use std::ffi::OsStr;
 use std::fs::{self, File};
 use std::os::fd::AsRawFd;
@@ -9,110 +9,190 @@ use std::string::ToString;
 
 use anyhow::{anyhow, Context, Result};
 use base32ct::{Base32Unpadded, Encoding};
+use nix::sys::statvfs::statvfs;
 use nix::unistd::syncfs;
 use sha2::{Digest, Sha256};
 use tempfile::TempDir;
 
 use crate::architecture::SystemdArchitectureExt;
+use crate::boot_entry::{ensure_boot_entry, Efibootmgr};
 use crate::esp::SystemdEspPaths;
+use crate::export::{EntryImage, EntryLayout, LoaderEntry};
+use crate::install_state::{InstallState, InstalledUki, STATE_FILENAME};
+use crate::loader_entries::{
+    detect_mixed_installation, is_lzbt_entry, scan_loader_entries, LZBT_ENTRY_MARKER,
+};
+use crate::plan::{Action, Plan};
 use crate::version::SystemdVersion;
 use lanzaboote_tool::architecture::Architecture;
+use lanzaboote_tool::cache::Cache;
+use lanzaboote_tool::cmdline::{
+    assemble_kernel_cmdline, merge_common_params, CmdlineAllowlist, CmdlineVariant,
+};
+use lanzaboote_tool::compression::{check_initrd_size, is_compressed, Compression};
+use lanzaboote_tool::dbx::{is_revoked, SignatureDatabase};
+use lanzaboote_tool::deploy::DeployInfo;
+use lanzaboote_tool::diff::UkiDiff;
 use lanzaboote_tool::esp::EspPaths;
+use lanzaboote_tool::firmware::FirmwarePolicy;
 use lanzaboote_tool::gc::Roots;
-use lanzaboote_tool::generation::{Generation, GenerationLink};
-use lanzaboote_tool::os_release::OsRelease;
+use lanzaboote_tool::generation::{Generation, GenerationLink, ENTRY_FILENAME_PREFIX};
+use lanzaboote_tool::initrd;
+use lanzaboote_tool::manifest::{Manifest, ManifestEntry, SignerIdentity};
+use lanzaboote_tool::os_release::{MissingOsRelease, OsRelease};
 use lanzaboote_tool::pe;
-use lanzaboote_tool::signature::KeyPair;
-use lanzaboote_tool::utils::{file_hash, SecureTempDirExt};
+use lanzaboote_tool::release::ReleaseVerifier;
+use lanzaboote_tool::signature::{verify_signature, PublicKey, Signer};
+use lanzaboote_tool::title::TitleTemplate;
+use lanzaboote_tool::uki::{build_uki, StubOptions, UkiConfig};
+use lanzaboote_tool::utils::{
+    create_tempdir, ensure_writable, file_hash, tmpname, SecureTempDirExt,
+};
+
+/// What [`Installer`] installs and how.
+pub struct InstallerConfig {
+    pub lanzaboote_stub: PathBuf,
+    pub arch: Architecture,
+    pub systemd: PathBuf,
+    pub systemd_boot_loader_config: PathBuf,
+    /// The certificate `signer` signs with.
+    pub public_key: PublicKey,
+    pub signer: Box<dyn Signer>,
+    /// The certificate of the key that adds a second signature, if there is one.
+    pub additional_certificate: Option<PathBuf>,
+    pub configuration_limit: usize,
+    pub esp: PathBuf,
+    pub generation_links: Vec<PathBuf>,
+    pub dbx: SignatureDatabase,
+    /// The options embedded in the stub of every generation.
+    pub stub_options: StubOptions,
+    /// Make the stub start systemd-boot first, unless it was started by a boot loader itself.
+    pub chainload_systemd_boot: bool,
+    pub cmdline_variants: Vec<CmdlineVariant>,
+    pub boot_entry_label: Option<String>,
+    pub excluded_gens: BTreeSet<u64>,
+    pub initrd_compression: Compression,
+    pub self_hash: bool,
+    pub section_hashes: bool,
+    pub post_hook: Option<PathBuf>,
+    pub manifest: Option<PathBuf>,
+    pub incremental: bool,
+    pub work_dir: Option<PathBuf>,
+    pub release_verifier: Option<ReleaseVerifier>,
+    pub title_template: Option<TitleTemplate>,
+    pub missing_os_release: MissingOsRelease,
+    pub cmdline_allowlist: Option<CmdlineAllowlist>,
+    /// Parameters added to the command line of every generation that does not set them itself.
+    pub common_cmdline: Vec<String>,
+    /// Write a Type #1 entry for every UKI to `loader/entries`.
+    pub type1_entries: Option<EntryLayout>,
+    /// Compressed initrds from earlier installations.
+    pub cache: Option<Cache>,
+}
 
 pub struct Installer {
+    config: InstallerConfig,
     broken_gens: BTreeSet<u64>,
     gc_roots: Roots,
-    lanzaboote_stub: PathBuf,
-    systemd: PathBuf,
-    systemd_boot_loader_config: PathBuf,
-    key_pair: KeyPair,
-    configuration_limit: usize,
+    /// Content-addressed files that were already installed during this run.
+    ///
+    /// Only the target paths (which encode the content hash) are kept, so that deduplication does
+    /// not require keeping any file contents in memory no matter how many generations there are.
+    installed_ca_files: HashSet<PathBuf>,
     esp_paths: SystemdEspPaths,
-    generation_links: Vec<PathBuf>,
-    arch: Architecture,
+    /// The UKIs recorded by the last incremental installation.
+    previous_state: InstallState,
+    /// The UKIs installed (or kept) during this run, recorded for the next one.
+    install_state: InstallState,
+    /// The number of UKIs that were kept because they did not change since the last run.
+    unchanged_ukis: usize,
+    /// The stubs of all generations installed (or kept) during this run.
+    installed_stubs: Vec<PathBuf>,
 }
 
 impl Installer {
-    #[allow(clippy::too_many_arguments)]
-    pub fn new(
-        lanzaboote_stub: PathBuf,
-        arch: Architecture,
-        systemd: PathBuf,
-        systemd_boot_loader_config: PathBuf,
-        key_pair: KeyPair,
-        configuration_limit: usize,
-        esp: PathBuf,
-        generation_links: Vec<PathBuf>,
-    ) -> Self {
+    pub fn new(config: InstallerConfig) -> Self {
         let mut gc_roots = Roots::new();
-        let esp_paths = SystemdEspPaths::new(esp, arch);
+        let esp_paths = SystemdEspPaths::new(config.esp.clone(), config.arch);
         gc_roots.extend(esp_paths.iter());
 
         Self {
+            config,
             broken_gens: BTreeSet::new(),
             gc_roots,
-            lanzaboote_stub,
-            systemd,
-            systemd_boot_loader_config,
-            key_pair,
-            configuration_limit,
+            installed_ca_files: HashSet::new(),
             esp_paths,
-            generation_links,
-            arch,
+            previous_state: InstallState::default(),
+            install_state: InstallState::default(),
+            unchanged_ukis: 0,
+            installed_stubs: Vec::new(),
         }
     }
 
     pub fn install(&mut self) -> Result<()> {
         log::info!("Installing Lanzaboote to {:?}...", self.esp_paths.esp);
 
-        let mut links = self
-            .generation_links
-            .iter()
-            .map(GenerationLink::from_path)
-            .collect::<Result<Vec<GenerationLink>>>()?;
+        // Fail with a helpful message if not run as root instead of after some files are written.
+        ensure_writable(&self.esp_paths.esp)?;
 
-        // Sort the links by version, so that the limit actually skips the oldest generations.
-        links.sort_by_key(|l| l.version);
+        let state_path = self.esp_paths.nixos.join(STATE_FILENAME);
+        if self.config.incremental {
+            self.previous_state = InstallState::read(&state_path).unwrap_or_else(|e| {
+                log::warn!("{e:#}. Checking all generations instead.");
+                InstallState::default()
+            });
+            self.gc_roots.extend([&state_path]);
+        }
 
-        // A configuration limit of 0 means there is no limit.
-        if self.configuration_limit > 0 {
-            // Only install the number of generations configured. Reverse the list to only take the
-            // latest generations and then, after taking them, reverse the list again so that the
-            // generations are installed from oldest to newest, i.e. from smallest to largest
-            // generation version.
-            links = links
-                .into_iter()
-                .rev()
-                .take(self.configuration_limit)
-                .rev()
-                .collect()
-        };
-        self.install_generations_from_links(&links)?;
+        let links = self.selected_links()?;
+        let newest_stub = self.install_generations_from_links(&links)?;
+
+        if self.config.incremental {
+            log::info!(
+                "{} of {} UKIs did not change since the last installation.",
+                self.unchanged_ukis,
+                self.installed_stubs.len()
+            );
+            self.install_state.write(&state_path)?;
+        }
 
-        self.install_systemd_boot()?;
+        self.install_systemd_boot(false)?;
+
+        if let Some(label) = &self.config.boot_entry_label {
+            let loader = pe::esp_relative_uefi_path(&self.esp_paths.esp, &newest_stub)?;
+            let mut store = Efibootmgr::for_esp(&self.esp_paths.esp)?;
+            ensure_boot_entry(&mut store, label, &loader)
+                .context("Failed to create firmware boot entry.")?;
+        }
 
         if self.broken_gens.is_empty() {
             log::info!("Collecting garbage...");
-            // Only collect garbage in these two directories. This way, no files that do not belong to
-            // the NixOS installation are deleted. Lanzatool takes full control over the esp/EFI/nixos
-            // directory and deletes ALL files that it doesn't know about. Dual- or multiboot setups
-            // that need files in this directory will NOT work.
-            self.gc_roots.collect_garbage(&self.esp_paths.nixos)?;
+            // Only collect garbage in these three directories. This way, no files that do not
+            // belong to the NixOS installation are deleted. Lanzatool takes full control over the
+            // esp/EFI/nixos directory and deletes ALL files that it doesn't know about. Dual- or
+            // multiboot setups that need files in this directory will NOT work.
+            let mut freed = self.gc_roots.collect_garbage(&self.esp_paths.nixos)?;
             // The esp/EFI/Linux directory is assumed to be potentially shared with other distros.
-            // Thus, only files that start with "nixos-" are garbage collected (i.e. potentially
-            // deleted).
-            self.gc_roots
-                .collect_garbage_with_filter(&self.esp_paths.linux, |p| {
-                    p.file_name()
-                        .and_then(|n| n.to_str())
-                        .map_or(false, |n| n.starts_with("nixos-"))
-                })?;
+            // Thus, only files that start with ENTRY_FILENAME_PREFIX are garbage collected (i.e.
+            // potentially deleted).
+            freed += self
+                .gc_roots
+                .collect_garbage_with_filter(&self.esp_paths.linux, is_generation_entry)?;
+            // The esp/loader/entries directory is shared with systemd-boot and other distros.
+            // Thus, only entries that lzbt wrote itself, i.e. that start with LZBT_ENTRY_MARKER,
+            // are garbage collected. Other entries are never touched.
+            freed += self
+                .gc_roots
+                .collect_garbage_with_filter(self.type1_entries_dir(), is_lzbt_entry)?;
+            match free_space(&self.esp_paths.esp) {
+                Ok(free) => {
+                    log::info!("Reclaimed {freed} bytes on the ESP, {free} bytes are free.")
+                }
+                Err(e) => {
+                    log::info!("Reclaimed {freed} bytes on the ESP.");
+                    log::warn!("{e:#}");
+                }
+            }
         } else {
             // This might produce a ridiculous message if you have a lot of malformed generations.
             let warning = indoc::formatdoc! {"
@@ -125,12 +205,529 @@ impl Installer {
             log::warn!("{warning}");
         };
 
+        self.warn_about_mixed_installation();
         log::info!("Successfully installed Lanzaboote.");
+
+        if let Some(manifest) = &self.config.manifest {
+            self.write_manifest(manifest)?;
+        }
+
+        if let Some(post_hook) = &self.config.post_hook {
+            // The installation is complete at this point, so a failing hook is not fatal.
+            if let Err(e) = self.run_post_hook(post_hook) {
+                log::warn!("{e:#}");
+            }
+        }
         Ok(())
     }
 
-    /// Install all generations from the provided `GenerationLinks`.
-    fn install_generations_from_links(&mut self, links: &[GenerationLink]) -> Result<()> {
+    /// Warn if the generations of the systemd-boot installer of NixOS are still on the ESP next to
+    /// those of lzbt.
+    fn warn_about_mixed_installation(&self) {
+        let mixed = scan_loader_entries(&self.esp_paths)
+            .and_then(|entries| detect_mixed_installation(&self.esp_paths, &entries));
+        match mixed {
+            Ok(Some(mixed)) => log::warn!("{mixed}"),
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to check the ESP for generations of systemd-boot: {e:#}"),
+        }
+    }
+
+    /// Only (re-)install and sign systemd-boot, e.g. while developing it.
+    ///
+    /// Unlike [`Self::install`], systemd-boot is always replaced and installed generations are
+    /// neither touched nor garbage collected.
+    pub fn install_stub(&mut self) -> Result<()> {
+        log::info!("Installing systemd-boot to {:?}...", self.esp_paths.esp);
+
+        ensure_writable(&self.esp_paths.esp)?;
+        self.install_systemd_boot(true)?;
+
+        log::info!("Successfully installed systemd-boot.");
+        Ok(())
+    }
+
+    /// Remove everything that lzbt installed to the ESP and install all generations from scratch,
+    /// e.g. to recover from files that were corrupted on the ESP.
+    ///
+    /// All generations are built and signed into a staging directory on the ESP first, so that a
+    /// failure leaves the installed files as they are. Only then are the new files renamed to
+    /// their final location and the files that garbage collection considers owned by lzbt, but
+    /// that were not just installed, removed: everything else in `EFI/nixos`, the UKIs in
+    /// `EFI/Linux` named after generations and the Type #1 entries that lzbt wrote. Finally,
+    /// systemd-boot is reinstalled in place and the rest of [`Self::install`] runs, which keeps
+    /// the UKIs that were just installed.
+    pub fn reinit(&mut self) -> Result<()> {
+        log::info!("Reinitializing Lanzaboote on {:?}...", self.esp_paths.esp);
+
+        ensure_writable(&self.esp_paths.esp)?;
+        let links = self.selected_links()?;
+
+        let staging = tempfile::Builder::new()
+            .prefix(".lzbt-reinit-")
+            .tempdir_in(&self.esp_paths.esp)
+            .with_context(|| {
+                format!(
+                    "Failed to create a staging directory on {:?}",
+                    self.esp_paths.esp
+                )
+            })?;
+        let esp_paths = std::mem::replace(
+            &mut self.esp_paths,
+            SystemdEspPaths::new(staging.path(), self.config.arch),
+        );
+        let result = self.install_generations_from_links(&links);
+        let staging_paths = std::mem::replace(&mut self.esp_paths, esp_paths);
+        result.context("Failed to rebuild the generations, the ESP was not changed.")?;
+
+        // The installation below starts over with the files renamed to the ESP.
+        self.gc_roots = Roots::new();
+        self.gc_roots.extend(self.esp_paths.iter());
+        self.installed_ca_files.clear();
+        self.installed_stubs.clear();
+        self.install_state = InstallState::default();
+
+        let type1_entries_dir = self.type1_entries_dir();
+        let mut roots = Roots::new();
+        roots.extend([
+            &self.esp_paths.nixos,
+            &self.esp_paths.linux,
+            &type1_entries_dir,
+        ]);
+        // Kernels and initrds have to be in place before the UKIs that refer to them.
+        for (from, to) in [
+            (&staging_paths.nixos, &self.esp_paths.nixos),
+            (&staging_paths.linux, &self.esp_paths.linux),
+            (&staging_paths.loader.join("entries"), &type1_entries_dir),
+        ] {
+            roots.extend(&move_dir_contents(from, to)?);
+        }
+        let boot = File::open(&self.esp_paths.esp).context("Failed to open ESP root directory.")?;
+        syncfs(boot.as_raw_fd()).context("Failed to sync ESP filesystem.")?;
+
+        let mut freed = roots.collect_garbage(&self.esp_paths.nixos)?;
+        freed += roots.collect_garbage_with_filter(&self.esp_paths.linux, is_generation_entry)?;
+        freed += roots.collect_garbage_with_filter(&type1_entries_dir, is_lzbt_entry)?;
+        log::info!("Removed {freed} bytes of previously installed files.");
+
+        self.install_systemd_boot(true)?;
+        self.install()
+    }
+
+    /// Render the files that [`Self::install`] would place on the ESP into `dir` instead, without
+    /// building or signing anything.
+    ///
+    /// `dir` receives a copy of `loader/loader.conf`, the list of UKIs in `ukis` (one path
+    /// relative to the ESP per line, from oldest to newest) and the boot entry that systemd-boot
+    /// shows for each UKI in `entries/<name>.conf`.
+    pub fn export(&mut self, dir: &Path) -> Result<()> {
+        log::info!(
+            "Exporting the files for {:?} to {dir:?}...",
+            self.esp_paths.esp
+        );
+
+        let links = self.selected_links()?;
+        let generations = self.read_generations(&links)?;
+
+        let entries_dir = dir.join("entries");
+        fs::create_dir_all(&entries_dir)
+            .with_context(|| format!("Failed to create directory {entries_dir:?}"))?;
+        let mut ukis = String::new();
+        for generation in generations {
+            let specialisations = generation
+                .spec
+                .bootspec
+                .specialisations
+                .iter()
+                .map(|(name, bootspec)| generation.specialise(name, bootspec))
+                .collect::<Vec<_>>();
+            for generation in std::iter::once(&generation).chain(&specialisations) {
+                let stub_name = self.stub_name(generation)?;
+                let stub = self.esp_paths.linux.join(&stub_name);
+                let relative = stub
+                    .strip_prefix(&self.esp_paths.esp)
+                    .context("Failed to strip the ESP prefix from the stub.")?;
+                let relative = relative
+                    .to_str()
+                    .context("The stub path is not valid UTF-8.")?;
+                ukis.push_str(relative);
+                ukis.push('\n');
+
+                let entry = LoaderEntry::new(
+                    generation,
+                    EntryImage::Uki(format!("/{relative}")),
+                    self.config.title_template.as_ref(),
+                    &self.kernel_cmdline(generation),
+                )?;
+                let entry_path = entries_dir.join(stub_name.with_extension("conf"));
+                fs::write(&entry_path, entry.to_string())
+                    .with_context(|| format!("Failed to write {entry_path:?}"))?;
+            }
+        }
+        let ukis_path = dir.join("ukis");
+        fs::write(&ukis_path, ukis).with_context(|| format!("Failed to write {ukis_path:?}"))?;
+
+        let loader_conf = dir.join("loader/loader.conf");
+        ensure_parent_dir(&loader_conf);
+        fs::copy(&self.config.systemd_boot_loader_config, &loader_conf)
+            .with_context(|| format!("Failed to write {loader_conf:?}"))?;
+
+        log::info!("Successfully exported the files.");
+        Ok(())
+    }
+
+    /// Rebuild the UKIs of the generations and compare them byte for byte with the UKIs that were
+    /// installed to the ESP at `reference`.
+    ///
+    /// The UKIs are built for a temporary ESP, so nothing is written to the configured ESP. For
+    /// every UKI that differs, the first differing section is reported.
+    pub fn reproduce(&mut self, reference: &Path) -> Result<()> {
+        log::info!("Rebuilding the UKIs to compare them with the ones on {reference:?}...");
+
+        let links = self.selected_links()?;
+        let generations = self.read_generations(&links)?;
+
+        let esp = create_tempdir(self.config.work_dir.as_deref())?;
+        let reference_paths = SystemdEspPaths::new(reference, self.config.arch);
+        let esp_paths = std::mem::replace(
+            &mut self.esp_paths,
+            SystemdEspPaths::new(esp.path(), self.config.arch),
+        );
+        let result = generations
+            .iter()
+            .flat_map(|generation| {
+                let specialisations = generation
+                    .spec
+                    .bootspec
+                    .specialisations
+                    .iter()
+                    .map(|(name, bootspec)| generation.specialise(name, bootspec));
+                std::iter::once(generation.clone()).chain(specialisations)
+            })
+            .map(|generation| {
+                self.install_generation(&generation)
+                    .with_context(|| format!("Failed to rebuild generation {generation}."))?;
+                let stub_name = self.stub_name(&generation)?;
+                compare_uki(
+                    &self.esp_paths.linux.join(&stub_name),
+                    &reference_paths.linux.join(&stub_name),
+                )
+            })
+            .collect::<Result<Vec<bool>>>();
+        self.esp_paths = esp_paths;
+
+        let results = result?;
+        let differing = results
+            .iter()
+            .filter(|&&reproducible| !reproducible)
+            .count();
+        if differing > 0 {
+            anyhow::bail!("{differing} of {} UKIs are not reproducible", results.len());
+        }
+        log::info!("All {} UKIs are reproducible.", results.len());
+        Ok(())
+    }
+
+    /// Compute the changes that [`Self::install`] would make to the ESP, without writing anything.
+    ///
+    /// Nothing is built or signed either: a UKI that has to be (re-)installed is reported as such
+    /// even if rebuilding it would produce the same file. Initrds with secrets are not covered
+    /// because the secrets are only appended during the installation.
+    pub fn plan(&mut self) -> Result<Plan> {
+        log::info!("Planning the installation to {:?}...", self.esp_paths.esp);
+
+        let state_path = self.esp_paths.nixos.join(STATE_FILENAME);
+        if self.config.incremental {
+            self.previous_state = InstallState::read(&state_path).unwrap_or_else(|e| {
+                log::warn!("{e:#}. Checking all generations instead.");
+                InstallState::default()
+            });
+            self.gc_roots.extend([&state_path]);
+        }
+
+        let links = self.selected_links()?;
+        let generations = self.read_generations(&links)?;
+
+        let mut plan = Plan::default();
+        let tempdir = create_tempdir(self.config.work_dir.as_deref())?;
+        for generation in generations {
+            let specialisations = generation
+                .spec
+                .bootspec
+                .specialisations
+                .iter()
+                .map(|(name, bootspec)| generation.specialise(name, bootspec))
+                .collect::<Vec<_>>();
+            for generation in std::iter::once(&generation).chain(&specialisations) {
+                self.plan_generation(generation, &tempdir, &mut plan)
+                    .with_context(|| format!("Failed to plan generation {generation}."))?;
+                self.plan_type1_entry(generation, &mut plan)?;
+            }
+        }
+
+        if self.config.incremental
+            && (!plan.is_empty()
+                || self.install_state != self.previous_state
+                || !state_path.exists())
+        {
+            let action = if state_path.exists() {
+                Action::Update
+            } else {
+                Action::Add
+            };
+            plan.push(
+                &self.esp_paths.esp,
+                action,
+                &state_path,
+                "the installed UKIs changed",
+            );
+        }
+
+        self.plan_systemd_boot(&mut plan)?;
+
+        if self.broken_gens.is_empty() {
+            let garbage = self
+                .gc_roots
+                .garbage_with_filter(&self.esp_paths.nixos, |_| true)
+                .into_iter()
+                .chain(
+                    self.gc_roots
+                        .garbage_with_filter(&self.esp_paths.linux, is_generation_entry),
+                )
+                .chain(
+                    self.gc_roots
+                        .garbage_with_filter(self.type1_entries_dir(), is_lzbt_entry),
+                );
+            for path in garbage {
+                plan.push(&self.esp_paths.esp, Action::Remove, &path, "no longer used");
+            }
+        } else {
+            log::warn!("Garbage collection is disabled because you have malformed NixOS generations, so the plan does not remove anything.");
+        }
+
+        Ok(plan)
+    }
+
+    /// Record the changes that [`Self::install_generation`] would make for a generation.
+    fn plan_generation(
+        &mut self,
+        generation: &Generation,
+        tempdir: &TempDir,
+        plan: &mut Plan,
+    ) -> Result<()> {
+        let stub_name = self.stub_name(generation)?;
+        let stub_target = self.esp_paths.linux.join(&stub_name);
+        let reason = if let Some(previous) = self.previous_state.get(&stub_name.to_string_lossy()) {
+            let previous = previous.clone();
+            if self.register_unchanged_uki(&stub_name, previous)? {
+                return Ok(());
+            }
+            format!("generation {generation} changed since the last installation")
+        } else if self.register_installed_generation(generation).is_ok() {
+            return Ok(());
+        } else if stub_target.exists() {
+            format!("the installed UKI of generation {generation} is incomplete")
+        } else {
+            format!("generation {generation} is not installed")
+        };
+        let action = if stub_target.exists() {
+            Action::Update
+        } else {
+            Action::Add
+        };
+        plan.push(&self.esp_paths.esp, action, &stub_target, reason);
+        self.gc_roots.extend([&stub_target]);
+
+        let bootspec = &generation.spec.bootspec.bootspec;
+        let kernel_version = kernel_version(&bootspec.kernel)?;
+        let kernel_target =
+            self.nixos_ca_target(&bootspec.kernel, &format!("kernel-{kernel_version}"))?;
+        self.plan_nixos_ca(
+            &kernel_target,
+            &format!("kernel of generation {generation}"),
+            plan,
+        );
+
+        let Some(initrd) = bootspec.initrd.clone() else {
+            return Ok(());
+        };
+        if bootspec.initrd_secrets.is_some() {
+            log::warn!("The initrd of generation {generation} contains secrets, its name is only known after installing it.");
+            return Ok(());
+        }
+        // The initrd is only read, so it does not need to be copied.
+        let initrd = self.compress_initrd(tempdir, initrd, true)?;
+        let initrd_target = self.nixos_ca_target(&initrd, &format!("initrd-{kernel_version}"))?;
+        self.plan_nixos_ca(
+            &initrd_target,
+            &format!("initrd of generation {generation}"),
+            plan,
+        );
+        Ok(())
+    }
+
+    /// Record that the Type #1 entry of a generation is added unless it is already installed.
+    ///
+    /// The entry is named after the UKI, whose name covers everything the entry refers to.
+    fn plan_type1_entry(&mut self, generation: &Generation, plan: &mut Plan) -> Result<()> {
+        if self.config.type1_entries.is_none() {
+            return Ok(());
+        }
+        let entry_path = self.type1_entry_path(&self.stub_name(generation)?);
+        self.gc_roots.extend([&entry_path]);
+        if !entry_path.exists() {
+            plan.push(
+                &self.esp_paths.esp,
+                Action::Add,
+                &entry_path,
+                format!("Type #1 entry of generation {generation}"),
+            );
+        }
+        Ok(())
+    }
+
+    /// Record that a content-addressed file is added unless it is already installed.
+    fn plan_nixos_ca(&mut self, target: &Path, reason: &str, plan: &mut Plan) {
+        let target = target.to_path_buf();
+        self.gc_roots.extend([&target]);
+        if !target.exists() && self.installed_ca_files.insert(target.clone()) {
+            plan.push(&self.esp_paths.esp, Action::Add, &target, reason);
+        }
+    }
+
+    /// Record the changes that [`Self::install_systemd_boot`] would make.
+    fn plan_systemd_boot(&self, plan: &mut Plan) -> Result<()> {
+        let systemd_boot = self.systemd_boot_binary();
+        for to in [&self.esp_paths.efi_fallback, &self.esp_paths.systemd_boot] {
+            let (action, reason) = if !to.exists() {
+                (Action::Add, "systemd-boot is not installed")
+            } else if newer_systemd_boot(&systemd_boot, to)? {
+                (Action::Update, "a newer systemd-boot is available")
+            } else if !self.systemd_boot_is_signed(to) {
+                (Action::Update, "systemd-boot is not signed")
+            } else {
+                continue;
+            };
+            plan.push(&self.esp_paths.esp, action, to, reason);
+        }
+
+        let loader_config = &self.esp_paths.systemd_boot_loader_config;
+        if !loader_config.exists() {
+            plan.push(
+                &self.esp_paths.esp,
+                Action::Add,
+                loader_config,
+                "loader.conf is not installed",
+            );
+        } else if needs_update(&self.config.systemd_boot_loader_config, loader_config)? {
+            plan.push(
+                &self.esp_paths.esp,
+                Action::Update,
+                loader_config,
+                "loader.conf changed",
+            );
+        }
+        Ok(())
+    }
+
+    /// Write a manifest describing the installed stubs.
+    fn write_manifest(&self, path: &Path) -> Result<()> {
+        log::info!("Writing manifest to {path:?}...");
+        let ukis = self
+            .installed_stubs
+            .iter()
+            .map(|stub| ManifestEntry::from_uki(&self.esp_paths.esp, stub))
+            .collect::<Result<Vec<_>>>()?;
+        let manifest = Manifest {
+            signer: SignerIdentity::from_public_key(&self.config.public_key)?,
+            ukis,
+        };
+        manifest.write(path)
+    }
+
+    /// Run the post-install hook.
+    ///
+    /// The hook receives the ESP in `LANZABOOTE_ESP` and the installed stubs, separated by
+    /// newlines, in `LANZABOOTE_UKIS`.
+    fn run_post_hook(&self, post_hook: &Path) -> Result<()> {
+        log::info!("Running post-install hook {post_hook:?}...");
+        let ukis = self
+            .installed_stubs
+            .iter()
+            .map(|p| p.as_os_str())
+            .collect::<Vec<_>>()
+            .join(OsStr::new("\n"));
+        let status = Command::new(post_hook)
+            .env("LANZABOOTE_ESP", &self.esp_paths.esp)
+            .env("LANZABOOTE_UKIS", ukis)
+            .status()
+            .with_context(|| format!("Failed to run post-install hook {post_hook:?}"))?;
+        if !status.success() {
+            anyhow::bail!("Post-install hook {post_hook:?} failed with {status}");
+        }
+        Ok(())
+    }
+
+    /// The links of the generations to install, from oldest to newest.
+    ///
+    /// Excluded generations, generations whose toplevel is gone and generations beyond the
+    /// configuration limit are skipped.
+    fn selected_links(&self) -> Result<Vec<GenerationLink>> {
+        let mut links = self
+            .config
+            .generation_links
+            .iter()
+            .map(GenerationLink::from_path)
+            .collect::<Result<Vec<GenerationLink>>>()?;
+
+        // Excluded generations are dropped before applying the limit so that they do not take up
+        // any slots. As they are never registered as garbage collector roots, previously installed
+        // copies are removed.
+        links.retain(|l| {
+            let excluded = self.config.excluded_gens.contains(&l.version);
+            if excluded {
+                log::info!("Skipping excluded generation {}.", l.version);
+            }
+            !excluded
+        });
+
+        // A generation link whose toplevel was garbage collected by Nix cannot be booted anymore.
+        // Unlike malformed generations, this is unambiguous, so garbage collection stays enabled.
+        links.retain(|l| {
+            let exists = l.path.exists();
+            if !exists {
+                log::warn!(
+                    "Skipping generation {}: its toplevel {:?} does not exist.",
+                    l.version,
+                    fs::read_link(&l.path).unwrap_or_else(|_| l.path.clone())
+                );
+            }
+            exists
+        });
+
+        // Sort the links by version, so that the limit actually skips the oldest generations.
+        links.sort_by_key(|l| l.version);
+
+        // A configuration limit of 0 means there is no limit.
+        if self.config.configuration_limit > 0 {
+            // Only install the number of generations configured. Reverse the list to only take the
+            // latest generations and then, after taking them, reverse the list again so that the
+            // generations are installed from oldest to newest, i.e. from smallest to largest
+            // generation version.
+            links = links
+                .into_iter()
+                .rev()
+                .take(self.config.configuration_limit)
+                .rev()
+                .collect()
+        };
+        Ok(links)
+    }
+
+    /// Read the generations from `links`.
+    ///
+    /// Malformed generations are skipped and recorded in `broken_gens`.
+    fn read_generations(&mut self, links: &[GenerationLink]) -> Result<Vec<Generation>> {
         let generations = links
             .iter()
             .filter_map(|link| {
@@ -157,15 +754,34 @@ impl Installer {
             return Err(anyhow!("No bootable generations found! Aborting to avoid unbootable system. Please check for Lanzaboote updates!"));
         }
 
+        Ok(generations)
+    }
+
+    /// Install all generations from the provided `GenerationLinks`.
+    ///
+    /// Returns the path to the stub of the newest generation.
+    fn install_generations_from_links(&mut self, links: &[GenerationLink]) -> Result<PathBuf> {
+        let generations = self.read_generations(links)?;
+
+        let mut newest_stub = PathBuf::new();
         for generation in generations {
             // The kernels and initrds are content-addressed.
             // Thus, this cannot overwrite files of old generation with different content.
             self.install_generation(&generation)
                 .context("Failed to install generation.")?;
+            newest_stub = self.esp_paths.linux.join(self.stub_name(&generation)?);
+            self.install_type1_entry(&generation, &newest_stub)?;
+            self.installed_stubs.push(newest_stub.clone());
             for (name, bootspec) in &generation.spec.bootspec.specialisations {
                 let specialised_generation = generation.specialise(name, bootspec);
                 self.install_generation(&specialised_generation)
                     .context("Failed to install specialisation.")?;
+                let stub = self
+                    .esp_paths
+                    .linux
+                    .join(self.stub_name(&specialised_generation)?);
+                self.install_type1_entry(&specialised_generation, &stub)?;
+                self.installed_stubs.push(stub);
             }
         }
 
@@ -175,7 +791,7 @@ impl Installer {
         let boot = File::open(&self.esp_paths.esp).context("Failed to open ESP root directory.")?;
         syncfs(boot.as_raw_fd()).context("Failed to sync ESP filesystem.")?;
 
-        Ok(())
+        Ok(newest_stub)
     }
 
     /// Install the given `Generation`.
@@ -184,123 +800,513 @@ impl Installer {
     /// Hence, this function cannot overwrite files of other generations with different contents.
     /// All installed files are added as garbage collector roots.
     fn install_generation(&mut self, generation: &Generation) -> Result<()> {
-        // If the generation is already properly installed, don't overwrite it.
-        if self.register_installed_generation(generation).is_ok() {
+        // This also covers generations that were signed before the allowlist was configured.
+        if let Some(allowlist) = &self.config.cmdline_allowlist {
+            allowlist
+                .check(&self.cmdline_with_variants(generation))
+                .with_context(|| format!("Refusing to install generation {generation}."))?;
+        }
+
+        // Nothing of an unapproved generation is installed or signed. Like the allowlist, this
+        // also covers generations that are already installed.
+        if let Some(release_verifier) = &self.config.release_verifier {
+            release_verifier
+                .verify(&generation.spec.bootspec.bootspec.toplevel.0)
+                .with_context(|| format!("Refusing to install generation {generation}."))?;
+        }
+
+        // With --incremental, the state of the last installation tells whether the UKI changed.
+        // Otherwise, a generation that is already properly installed is not overwritten.
+        let stub_name = self.stub_name(generation)?;
+        if let Some(previous) = self.previous_state.get(&stub_name.to_string_lossy()) {
+            let previous = previous.clone();
+            if self.register_unchanged_uki(&stub_name, previous)? {
+                return Ok(());
+            }
+            log::info!("The UKI of generation {generation} changed since the last installation.");
+        } else if self.register_installed_generation(generation).is_ok() {
             return Ok(());
         }
 
-        let tempdir = TempDir::new().context("Failed to create temporary directory.")?;
+        let tempdir = create_tempdir(self.config.work_dir.as_deref())?;
         let bootspec = &generation.spec.bootspec.bootspec;
 
-        // The kernel is a file in /nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-linux-<version>/.
-        // (On x86, that file is called bzImage, but other architectures may differ.)
-        let kernel_dirname = bootspec
-            .kernel
-            .parent()
-            .and_then(Path::file_name)
-            .and_then(OsStr::to_str)
-            .context("Failed to extract the kernel directory name.")?;
-        let kernel_version = kernel_dirname
-            .rsplit('-')
-            .next()
-            .context("Failed to extract the kernel version.")?;
+        let kernel_version = kernel_version(&bootspec.kernel)?;
+
+        pe::ensure_stub_arch(&self.config.lanzaboote_stub, self.config.arch)?;
+        pe::ensure_efi_kernel(&bootspec.kernel, self.config.arch)?;
 
         // Install the kernel and record its path on the ESP.
         let kernel_target = self
             .install_nixos_ca(&bootspec.kernel, &format!("kernel-{}", kernel_version))
             .context("Failed to install the kernel.")?;
 
-        // Assemble and install the initrd, and record its path on the ESP.
-        let initrd_location = tempdir
-            .write_secure_file(
-                fs::read(
-                    bootspec
-                        .initrd
-                        .as_ref()
-                        .context("Lanzaboote does not support missing initrd yet.")?,
-                )
-                .context("Failed to read the initrd.")?,
-            )
-            .context("Failed to copy the initrd to the temporary directory.")?;
-        if let Some(initrd_secrets_script) = &bootspec.initrd_secrets {
-            append_initrd_secrets(initrd_secrets_script, &initrd_location, generation.version)?;
-        }
-        let initrd_target = self
-            .install_nixos_ca(&initrd_location, &format!("initrd-{}", kernel_version))
-            .context("Failed to install the initrd.")?;
+        // Assemble and install the initrd, and record its path on the ESP. Generations without an
+        // initrd boot their kernel directly.
+        let (initrd_location, initrd_target) = match &bootspec.initrd {
+            Some(initrd) => {
+                let (location, target) = self.install_initrd(&tempdir, generation, initrd)?;
+                (Some(location), Some(target))
+            }
+            None if bootspec.initrd_secrets.is_some() => anyhow::bail!(
+                "Generation {generation} has initrd secrets but no initrd to append them to."
+            ),
+            None => {
+                log::info!("Generation {generation} has no initrd.");
+                (None, None)
+            }
+        };
 
         // Assemble, sign and install the Lanzaboote stub.
-        let os_release = OsRelease::from_generation(generation)
-            .context("Failed to build OsRelease from generation.")?;
+        let toplevel = &generation.spec.bootspec.bootspec.toplevel.0;
+        // An os-release that cannot be read is reported by OsRelease::from_generation.
+        if matches!(OsRelease::from_toplevel(toplevel), Ok(None)) {
+            let message = format!("Generation {generation} has no etc/os-release");
+            match self.config.missing_os_release {
+                MissingOsRelease::Fail => anyhow::bail!("{message}."),
+                MissingOsRelease::Warn => {
+                    log::warn!("{message}, embedding one without its version.")
+                }
+            }
+        }
+        let os_release =
+            OsRelease::from_generation(generation, self.config.title_template.as_ref())
+                .context("Failed to build OsRelease from generation.")?;
         let os_release_path = tempdir
             .write_secure_file(os_release.to_string().as_bytes())
             .context("Failed to write os-release file.")?;
-        let kernel_cmdline =
-            assemble_kernel_cmdline(&bootspec.init, bootspec.kernel_params.clone());
-        let lanzaboote_image = pe::lanzaboote_image(
-            &tempdir,
-            &self.lanzaboote_stub,
-            &os_release_path,
-            &kernel_cmdline,
-            &bootspec.kernel,
-            &kernel_target,
-            &initrd_location,
-            &initrd_target,
-            &self.esp_paths.esp,
-        )
-        .context("Failed to assemble lanzaboote image.")?;
-        let stub_target = self
-            .esp_paths
-            .linux
-            .join(stub_name(generation, &self.key_pair.public_key)?);
+        let kernel_cmdline = self.kernel_cmdline(generation);
+        let chainload = self
+            .config
+            .chainload_systemd_boot
+            .then(|| pe::esp_relative_uefi_path(&self.esp_paths.esp, &self.esp_paths.systemd_boot))
+            .transpose()?;
+        let uki_config = UkiConfig {
+            stub: self.config.lanzaboote_stub.clone(),
+            os_release: os_release_path,
+            missing_os_release: self.config.missing_os_release,
+            kernel_cmdline,
+            cmdline_variants: self.config.cmdline_variants.clone(),
+            kernel: bootspec.kernel.clone(),
+            kernel_target,
+            initrd: initrd_location,
+            initrd_target,
+            esp: self.esp_paths.esp.clone(),
+            stub_options: self.config.stub_options.clone(),
+            chainload,
+            // The `.linux` section only holds the path of the kernel on the ESP.
+            linux_alignment: None,
+            self_hash: self.config.self_hash,
+            section_hashes: self.config.section_hashes,
+            header_fields: pe::PeHeaderFields::default(),
+            work_dir: self.config.work_dir.clone(),
+        };
+        let lanzaboote_image = tempdir.path().join(tmpname());
+        build_uki(&uki_config, &lanzaboote_image)
+            .context("Failed to assemble lanzaboote image.")?;
+        let stub_target = self.esp_paths.linux.join(self.stub_name(generation)?);
         self.gc_roots.extend([&stub_target]);
-        install_signed(&self.key_pair, &lanzaboote_image, &stub_target)
+        ensure_not_revoked(&self.config.dbx, &lanzaboote_image)?;
+        install_signed(self.config.signer.as_ref(), &lanzaboote_image, &stub_target)
             .context("Failed to install the Lanzaboote stub.")?;
+        if self.config.incremental {
+            self.record_installed_uki(&stub_name, &fs::read(&stub_target)?)?;
+        }
 
         Ok(())
     }
 
+    /// Write the Type #1 entry for the installed UKI `stub` of a generation, if enabled.
+    ///
+    /// The entry is only written if it changed and is added to the garbage collector roots.
+    fn install_type1_entry(&mut self, generation: &Generation, stub: &Path) -> Result<()> {
+        let Some(layout) = self.config.type1_entries else {
+            return Ok(());
+        };
+        let esp_path = |uefi_path: &str| uefi_path.replace('\\', "/");
+        let image = match layout {
+            EntryLayout::Uki => EntryImage::Uki(esp_path(&pe::esp_relative_uefi_path(
+                &self.esp_paths.esp,
+                stub,
+            )?)),
+            EntryLayout::Split => {
+                let stub_data =
+                    fs::read(stub).with_context(|| format!("Failed to read {stub:?}"))?;
+                let section = |name| -> Result<String> {
+                    let data = pe::read_section_data(&stub_data, name)
+                        .with_context(|| format!("Missing {name} section in {stub:?}."))?;
+                    Ok(esp_path(std::str::from_utf8(data)?))
+                };
+                EntryImage::Split {
+                    linux: section(".linux")?,
+                    initrd: pe::read_section_data(&stub_data, ".initrd")
+                        .map(|_| section(".initrd"))
+                        .transpose()?,
+                }
+            }
+        };
+        let entry = LoaderEntry::new(
+            generation,
+            image,
+            self.config.title_template.as_ref(),
+            &self.kernel_cmdline(generation),
+        )?;
+
+        let file_name = stub.file_name().context("The stub has no file name.")?;
+        let entry_path = self.type1_entry_path(Path::new(file_name));
+        self.gc_roots.extend([&entry_path]);
+        let contents = format!("{LZBT_ENTRY_MARKER}\n{entry}");
+        if fs::read_to_string(&entry_path).is_ok_and(|installed| installed == contents) {
+            return Ok(());
+        }
+        log::debug!("Installing {entry_path:?}...");
+        ensure_parent_dir(&entry_path);
+        fs::write(&entry_path, contents).with_context(|| format!("Failed to write {entry_path:?}"))
+    }
+
+    /// The directory of the Type #1 entries.
+    fn type1_entries_dir(&self) -> PathBuf {
+        self.esp_paths.loader.join("entries")
+    }
+
+    /// The path of the Type #1 entry for the UKI called `stub_name`.
+    fn type1_entry_path(&self, stub_name: &Path) -> PathBuf {
+        self.type1_entries_dir()
+            .join(stub_name.with_extension("conf"))
+    }
+
+    /// The command line embedded in the UKI of a generation.
+    fn kernel_cmdline(&self, generation: &Generation) -> Vec<String> {
+        let bootspec = &generation.spec.bootspec.bootspec;
+        merge_common_params(
+            assemble_kernel_cmdline(&bootspec.init, bootspec.kernel_params.clone()),
+            &self.config.common_cmdline,
+        )
+    }
+
+    /// The default command line of a generation followed by the command line of each variant.
+    fn cmdline_with_variants(&self, generation: &Generation) -> Vec<String> {
+        let default = self.kernel_cmdline(generation);
+        let variants = self
+            .config
+            .cmdline_variants
+            .iter()
+            .map(|variant| variant.extra_params.clone());
+        default.into_iter().chain(variants).collect()
+    }
+
     /// Register the files of an already installed generation as garbage collection roots.
     ///
     /// An error should not be considered fatal; the generation should be (re-)installed instead.
     fn register_installed_generation(&mut self, generation: &Generation) -> Result<()> {
-        let stub_target = self
-            .esp_paths
-            .linux
-            .join(stub_name(generation, &self.key_pair.public_key)?);
+        let stub_target = self.esp_paths.linux.join(self.stub_name(generation)?);
         let stub = fs::read(&stub_target)?;
         let kernel_path = resolve_efi_path(
             &self.esp_paths.esp,
             pe::read_section_data(&stub, ".linux").context("Missing kernel path.")?,
         )?;
-        let initrd_path = resolve_efi_path(
-            &self.esp_paths.esp,
-            pe::read_section_data(&stub, ".initrd").context("Missing initrd path.")?,
-        )?;
+        let initrd_path = pe::read_section_data(&stub, ".initrd")
+            .map(|initrd| resolve_efi_path(&self.esp_paths.esp, initrd))
+            .transpose()?;
 
-        if !kernel_path.exists() && !initrd_path.exists() {
+        if !kernel_path.exists() && !initrd_path.as_ref().is_some_and(|path| path.exists()) {
             anyhow::bail!("Missing kernel or initrd.");
         }
-        self.gc_roots
-            .extend([&stub_target, &kernel_path, &initrd_path]);
+        self.gc_roots.extend([&stub_target, &kernel_path]);
+        self.gc_roots.extend(&initrd_path);
+        if self.config.incremental {
+            self.record_installed_uki(&self.stub_name(generation)?, &stub)?;
+        }
+
+        Ok(())
+    }
+
+    /// Register the files of a UKI recorded by the last incremental installation as garbage
+    /// collection roots, if neither the UKI nor its kernel and initrd changed.
+    ///
+    /// Returns false if the UKI has to be reinstalled.
+    fn register_unchanged_uki(&mut self, stub_name: &Path, previous: InstalledUki) -> Result<bool> {
+        let stub_target = self.esp_paths.linux.join(stub_name);
+        let kernel_path = resolve_efi_path(&self.esp_paths.esp, previous.kernel.as_bytes())?;
+        let initrd_path = previous
+            .initrd
+            .as_ref()
+            .map(|initrd| resolve_efi_path(&self.esp_paths.esp, initrd.as_bytes()))
+            .transpose()?;
+        if !kernel_path.exists() || initrd_path.as_ref().is_some_and(|path| !path.exists()) {
+            return Ok(false);
+        }
+        if file_hash(&stub_target).ok() != Some(previous.sha256) {
+            return Ok(false);
+        }
+
+        self.gc_roots.extend([&stub_target, &kernel_path]);
+        self.gc_roots.extend(&initrd_path);
+        self.install_state
+            .insert(stub_name.to_string_lossy().into_owned(), previous);
+        self.unchanged_ukis += 1;
+        Ok(true)
+    }
 
+    /// Record an installed UKI with the contents `stub` for the next incremental installation.
+    fn record_installed_uki(&mut self, stub_name: &Path, stub: &[u8]) -> Result<()> {
+        let uki = InstalledUki::from_stub(stub)
+            .with_context(|| format!("Failed to describe the installed UKI {stub_name:?}"))?;
+        self.install_state
+            .insert(stub_name.to_string_lossy().into_owned(), uki);
         Ok(())
     }
 
+    /// Compute the file name to be used for the stub of a certain generation.
+    ///
+    /// The generated name is input-addressed by the toplevel corresponding to the generation, the
+    /// public part of the signing key and the options that change the contents of the stub.
+    fn stub_name(&self, generation: &Generation) -> Result<PathBuf> {
+        let bootspec = &generation.spec.bootspec.bootspec;
+        let options = &self.config.stub_options;
+        let public_key = fs::read(&self.config.public_key.path)?;
+        let additional_public_key = self
+            .config
+            .additional_certificate
+            .as_ref()
+            .map(fs::read)
+            .transpose()?;
+        let initrd_compression = self.config.initrd_compression.to_string();
+        let countdown = options.countdown.map(|c| c.to_string());
+        let deploy = options.deploy.as_ref().map(DeployInfo::to_json);
+        let firmware_policy = options
+            .firmware_policy
+            .as_ref()
+            .map(FirmwarePolicy::to_section);
+        let cmdline_variants = self
+            .config
+            .cmdline_variants
+            .iter()
+            .map(|v| format!("{}={}", v.label, v.extra_params))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let title_template = self.config.title_template.as_ref().map(ToString::to_string);
+        let common_cmdline = self.config.common_cmdline.join(" ");
+        let mut stub_inputs = vec![
+            // Generation numbers can be reused if the latest generation was deleted.
+            // To detect this, the stub path depends on the actual toplevel used.
+            ("toplevel", bootspec.toplevel.0.as_os_str().as_bytes()),
+            // If the key is rotated, the signed stubs must be re-generated.
+            // So we make their path depend on the public key used for signature.
+            ("public_key", &public_key),
+        ];
+        // Options are only included when set so that the names of existing stubs stay the same.
+        if let Some(additional_public_key) = &additional_public_key {
+            stub_inputs.push(("additional_public_key", additional_public_key));
+        }
+        if let Some(serial_console) = &options.serial_console {
+            stub_inputs.push(("serial_console", serial_console.as_bytes()));
+        }
+        if let Some(countdown) = &countdown {
+            stub_inputs.push(("countdown", countdown.as_bytes()));
+        }
+        if self.config.initrd_compression != Compression::None {
+            stub_inputs.push(("initrd_compression", initrd_compression.as_bytes()));
+        }
+        if options.skip_initrd_verification {
+            stub_inputs.push(("skip_initrd_verification", b"1"));
+        }
+        if options.strict_measurement {
+            stub_inputs.push(("strict_measurement", b"1"));
+        }
+        if self.config.self_hash {
+            stub_inputs.push(("self_hash", b"1"));
+        }
+        if self.config.section_hashes {
+            stub_inputs.push(("section_hashes", b"1"));
+        }
+        if let Some(deploy) = &deploy {
+            stub_inputs.push(("deploy", deploy.as_bytes()));
+        }
+        if let Some(firmware_policy) = &firmware_policy {
+            stub_inputs.push(("firmware_policy", firmware_policy.as_bytes()));
+        }
+        if let Some(prefix) = &options.smbios_cmdline_prefix {
+            stub_inputs.push(("smbios_cmdline_prefix", prefix.as_bytes()));
+        }
+        if options.confirm_insecure_boot {
+            stub_inputs.push(("confirm_insecure_boot", b"1"));
+        }
+        if self.config.chainload_systemd_boot {
+            stub_inputs.push(("chainload_systemd_boot", b"1"));
+        }
+        if options.load_options_image_path {
+            stub_inputs.push(("load_options_image_path", b"1"));
+        }
+        if let Some(key) = &options.firmware_setup_key {
+            stub_inputs.push(("firmware_setup_key", key.as_bytes()));
+        }
+        if options.strict_cmdline {
+            stub_inputs.push(("strict_cmdline", b"1"));
+        }
+        if options.cmdline_addons {
+            stub_inputs.push(("cmdline_addons", b"1"));
+        }
+        if options.retry_initrd_verification {
+            stub_inputs.push(("retry_initrd_verification", b"1"));
+        }
+        if let Some(initrd_path) = &options.initrd_path {
+            stub_inputs.push(("initrd_path", initrd_path.as_bytes()));
+        }
+        if options.next_entry_on_kernel_failure {
+            stub_inputs.push(("fallback_on_kernel_failure", b"1"));
+        }
+        let tpm_nv_index = options.tpm_nv_index.map(|index| format!("{index:#010x}"));
+        if let Some(tpm_nv_index) = &tpm_nv_index {
+            stub_inputs.push(("tpm_nv_index", tpm_nv_index.as_bytes()));
+        }
+        if options.show_measurements {
+            stub_inputs.push(("show_measurements", b"1"));
+        }
+        if !self.config.cmdline_variants.is_empty() {
+            stub_inputs.push(("cmdline_variants", cmdline_variants.as_bytes()));
+        }
+        if let Some(title_template) = &title_template {
+            stub_inputs.push(("title_template", title_template.as_bytes()));
+        }
+        if !self.config.common_cmdline.is_empty() {
+            stub_inputs.push(("common_cmdline", common_cmdline.as_bytes()));
+        }
+        let stub_input_hash = Base32Unpadded::encode_string(&Sha256::digest(
+            serde_json::to_string(&stub_inputs).unwrap(),
+        ));
+        Ok(PathBuf::from(format!(
+            "{}-{}.efi",
+            generation.entry_filename(),
+            stub_input_hash
+        )))
+    }
+
+    /// Assemble and install the initrd of a generation. Returns the assembled initrd and where it
+    /// is installed on the ESP.
+    ///
+    /// The initrd is streamed into the temporary directory instead of being read into memory, so
+    /// that only a single initrd is ever processed at a time.
+    fn install_initrd(
+        &mut self,
+        tempdir: &TempDir,
+        generation: &Generation,
+        initrd: &Path,
+    ) -> Result<(PathBuf, PathBuf)> {
+        let bootspec = &generation.spec.bootspec.bootspec;
+        let kernel_version = kernel_version(&bootspec.kernel)?;
+        let initrd_location = tempdir
+            .copy_secure_file(initrd)
+            .context("Failed to copy the initrd to the temporary directory.")?;
+        warn_on_module_version_mismatch(generation, &initrd_location);
+        if let Some(initrd_secrets_script) = &bootspec.initrd_secrets {
+            append_initrd_secrets(initrd_secrets_script, &initrd_location, generation.version)?;
+        }
+        // Initrds with secrets must never end up in the cache.
+        let cacheable = bootspec.initrd_secrets.is_none();
+        let initrd_location = self.compress_initrd(tempdir, initrd_location, cacheable)?;
+        let initrd_target = self
+            .install_nixos_ca(&initrd_location, &format!("initrd-{}", kernel_version))
+            .context("Failed to install the initrd.")?;
+        Ok((initrd_location, initrd_target))
+    }
+
+    /// Compress the initrd with the configured compression.
+    ///
+    /// The kernel only unpacks a single layer of compression, so initrds that are already
+    /// compressed are left alone. Initrds larger than
+    /// [`MAX_INITRD_SIZE`](lanzaboote_tool::compression::MAX_INITRD_SIZE) are refused, whether
+    /// they would be compressed or not.
+    fn compress_initrd(
+        &self,
+        tempdir: &TempDir,
+        initrd: PathBuf,
+        cacheable: bool,
+    ) -> Result<PathBuf> {
+        check_initrd_size(&initrd)?;
+        if self.config.initrd_compression == Compression::None {
+            return Ok(initrd);
+        }
+        if is_compressed(&initrd)? {
+            log::warn!(
+                "Not compressing the initrd with {} because it is already compressed.",
+                self.config.initrd_compression
+            );
+            return Ok(initrd);
+        }
+
+        let compressed = tempdir.path().join(tmpname());
+        let cache = self.config.cache.as_ref().filter(|_| cacheable);
+        let key = match cache {
+            Some(cache) => {
+                let key = format!(
+                    "initrd-{}-{}",
+                    self.config.initrd_compression,
+                    Base32Unpadded::encode_string(&file_hash(&initrd)?)
+                );
+                if cache.get(&key, tempdir.create_secure_file(&compressed)?)? {
+                    log::debug!("Using the cached compressed initrd {key}.");
+                    return Ok(compressed);
+                }
+                Some(key)
+            }
+            None => None,
+        };
+
+        let reader = File::open(&initrd).with_context(|| format!("Failed to open {initrd:?}"))?;
+        self.config
+            .initrd_compression
+            .compress(reader, tempdir.create_secure_file(&compressed)?)
+            .with_context(|| format!("Failed to compress the initrd {initrd:?}"))?;
+        if let (Some(cache), Some(key)) = (cache, key) {
+            let reader = File::open(&compressed)
+                .with_context(|| format!("Failed to open {compressed:?}"))?;
+            cache.put(&key, reader)?;
+        }
+        Ok(compressed)
+    }
+
     /// Install a content-addressed file to the `EFI/nixos` directory on the ESP.
     ///
     /// It is automatically added to the garbage collector roots.
     /// The full path to the target file is returned.
     fn install_nixos_ca(&mut self, from: &Path, label: &str) -> Result<PathBuf> {
+        let to = self.nixos_ca_target(from, label)?;
+        self.gc_roots.extend([&to]);
+        if self.installed_ca_files.insert(to.clone()) {
+            install(from, &to)?;
+        }
+        Ok(to)
+    }
+
+    /// The path of a content-addressed file in the `EFI/nixos` directory on the ESP.
+    fn nixos_ca_target(&self, from: &Path, label: &str) -> Result<PathBuf> {
         let hash = file_hash(from).context("Failed to read the source file.")?;
-        let to = self.esp_paths.nixos.join(format!(
+        Ok(self.esp_paths.nixos.join(format!(
             "{}-{}.efi",
             label,
             Base32Unpadded::encode_string(&hash)
-        ));
-        self.gc_roots.extend([&to]);
-        install(from, &to)?;
-        Ok(to)
+        )))
+    }
+
+    /// The systemd-boot binary for the architecture.
+    fn systemd_boot_binary(&self) -> PathBuf {
+        self.config
+            .systemd
+            .join("lib/systemd/boot/efi")
+            .join(self.config.arch.systemd_filename())
+    }
+
+    /// Whether the installed systemd-boot at `to` is signed with all keys.
+    fn systemd_boot_is_signed(&self, to: &Path) -> bool {
+        self.config.public_key.verify(to)
+            && self
+                .config
+                .additional_certificate
+                .as_ref()
+                .map_or(true, |certificate| {
+                    verify_signature(certificate, to).unwrap_or(false)
+                })
     }
 
     /// Install systemd-boot to ESP.
@@ -311,11 +1317,10 @@ impl Installer {
     /// to the ESP.
     ///
     /// Checking for the version also allows us to skip buggy systemd versions in the future.
-    fn install_systemd_boot(&self) -> Result<()> {
-        let systemd_boot = self
-            .systemd
-            .join("lib/systemd/boot/efi")
-            .join(self.arch.systemd_filename());
+    ///
+    /// With `force`, systemd-boot is replaced regardless.
+    fn install_systemd_boot(&self, force: bool) -> Result<()> {
+        let systemd_boot = self.systemd_boot_binary();
 
         let paths = [
             (&systemd_boot, &self.esp_paths.efi_fallback),
@@ -327,19 +1332,20 @@ impl Installer {
             if newer_systemd_boot_available {
                 log::info!("Updating {to:?}...")
             };
-            let systemd_boot_is_signed = &self.key_pair.verify(to);
+            let systemd_boot_is_signed = self.systemd_boot_is_signed(to);
             if !systemd_boot_is_signed {
                 log::warn!("${to:?} is not signed. Replacing it with a signed binary...")
             };
 
-            if newer_systemd_boot_available || !systemd_boot_is_signed {
-                install_signed(&self.key_pair, from, to)
+            if force || newer_systemd_boot_available || !systemd_boot_is_signed {
+                ensure_not_revoked(&self.config.dbx, from)?;
+                install_signed(self.config.signer.as_ref(), from, to)
                     .with_context(|| format!("Failed to install systemd-boot binary to: {to:?}"))?;
             }
         }
 
         install(
-            &self.systemd_boot_loader_config,
+            &self.config.systemd_boot_loader_config,
             &self.esp_paths.systemd_boot_loader_config,
         )
         .with_context(|| {
@@ -353,38 +1359,84 @@ impl Installer {
     }
 }
 
+/// Rename every file in `from` to `to` and return their new paths.
+fn move_dir_contents(from: &Path, to: &Path) -> Result<Vec<PathBuf>> {
+    if !from.exists() {
+        return Ok(Vec::new());
+    }
+    fs::create_dir_all(to).with_context(|| format!("Failed to create directory {to:?}"))?;
+    let mut moved = Vec::new();
+    for entry in fs::read_dir(from).with_context(|| format!("Failed to read {from:?}"))? {
+        let from = entry?.path();
+        let to = to.join(from.file_name().context("Directory entry without a name")?);
+        log::debug!("Installing {to:?}...");
+        fs::rename(&from, &to).with_context(|| format!("Failed to move {from:?} to {to:?}"))?;
+        moved.push(to);
+    }
+    Ok(moved)
+}
+
 /// Translate an EFI path to an absolute path on the mounted ESP.
-fn resolve_efi_path(esp: &Path, efi_path: &[u8]) -> Result<PathBuf> {
-    Ok(esp.join(std::str::from_utf8(&efi_path[1..])?.replace('\\', "/")))
+/// Compare a rebuilt UKI with the reference copy and report whether they are identical.
+fn compare_uki(rebuilt: &Path, reference: &Path) -> Result<bool> {
+    let name = rebuilt
+        .file_name()
+        .context("The rebuilt UKI has no file name.")?;
+    let Ok(reference_data) = fs::read(reference) else {
+        log::warn!("{name:?} is not reproducible: {reference:?} does not exist.");
+        return Ok(false);
+    };
+    let rebuilt_data =
+        fs::read(rebuilt).with_context(|| format!("Failed to read the rebuilt UKI {rebuilt:?}"))?;
+    if rebuilt_data == reference_data {
+        log::info!("{name:?} is reproducible.");
+        return Ok(true);
+    }
+
+    let diff = UkiDiff::new(&reference_data, &rebuilt_data)?;
+    match diff.first_section() {
+        Some(section) => {
+            log::warn!("{name:?} is not reproducible, the first differing section is {section}:\n{diff}")
+        }
+        None => log::warn!(
+            "{name:?} is not reproducible, all sections are identical but the headers or the signature differ."
+        ),
+    }
+    Ok(false)
+}
+
+/// The kernel version of a kernel in /nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-linux-<version>/.
+/// (On x86, that file is called bzImage, but other architectures may differ.)
+fn kernel_version(kernel: &Path) -> Result<&str> {
+    let kernel_dirname = kernel
+        .parent()
+        .and_then(Path::file_name)
+        .and_then(OsStr::to_str)
+        .context("Failed to extract the kernel directory name.")?;
+    kernel_dirname
+        .rsplit('-')
+        .next()
+        .context("Failed to extract the kernel version.")
 }
 
-/// Compute the file name to be used for the stub of a certain generation, signed with the given key.
+/// Whether a file in `EFI/Linux` belongs to a NixOS generation and may be garbage collected.
 ///
-/// The generated name is input-addressed by the toplevel corresponding to the generation and the public part of the signing key.
-fn stub_name(generation: &Generation, public_key: &Path) -> Result<PathBuf> {
-    let bootspec = &generation.spec.bootspec.bootspec;
-    let stub_inputs = [
-        // Generation numbers can be reused if the latest generation was deleted.
-        // To detect this, the stub path depends on the actual toplevel used.
-        ("toplevel", bootspec.toplevel.0.as_os_str().as_bytes()),
-        // If the key is rotated, the signed stubs must be re-generated.
-        // So we make their path depend on the public key used for signature.
-        ("public_key", &fs::read(public_key)?),
-    ];
-    let stub_input_hash = Base32Unpadded::encode_string(&Sha256::digest(
-        serde_json::to_string(&stub_inputs).unwrap(),
-    ));
-    if let Some(specialisation_name) = &generation.specialisation_name {
-        Ok(PathBuf::from(format!(
-            "nixos-generation-{}-specialisation-{}-{}.efi",
-            generation, specialisation_name, stub_input_hash
-        )))
-    } else {
-        Ok(PathBuf::from(format!(
-            "nixos-generation-{}-{}.efi",
-            generation, stub_input_hash
-        )))
-    }
+/// The directory is assumed to be potentially shared with other distros.
+fn is_generation_entry(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with(ENTRY_FILENAME_PREFIX))
+}
+
+/// The number of bytes available to unprivileged users on the file system of `esp`.
+fn free_space(esp: &Path) -> Result<u64> {
+    let stat =
+        statvfs(esp).with_context(|| format!("Failed to query the free space on {esp:?}"))?;
+    Ok(stat.blocks_available() * stat.fragment_size())
+}
+
+fn resolve_efi_path(esp: &Path, efi_path: &[u8]) -> Result<PathBuf> {
+    Ok(esp.join(std::str::from_utf8(&efi_path[1..])?.replace('\\', "/")))
 }
 
 /// Install a PE file. The PE gets signed in the process.
@@ -394,31 +1446,94 @@ fn stub_name(generation: &Generation, public_key: &Path) -> Result<PathBuf> {
 /// This is implemented as an atomic write. The file is first written to the destination with a
 /// `.tmp` suffix and then renamed to its final name. This is atomic, because a rename is an atomic
 /// operation on POSIX platforms.
-fn install_signed(key_pair: &KeyPair, from: &Path, to: &Path) -> Result<()> {
+fn install_signed(signer: &dyn Signer, from: &Path, to: &Path) -> Result<()> {
     log::debug!("Signing and installing {to:?}...");
     let to_tmp = to.with_extension(".tmp");
     ensure_parent_dir(&to_tmp);
-    key_pair
+    let result = signer
         .sign_and_copy(from, &to_tmp)
-        .with_context(|| format!("Failed to copy and sign file from {from:?} to {to:?}"))?;
-    fs::rename(&to_tmp, to).with_context(|| {
-        format!("Failed to move temporary file {to_tmp:?} to final location {to:?}")
-    })?;
+        .with_context(|| format!("Failed to copy and sign file from {from:?} to {to:?}"))
+        .and_then(|()| {
+            fs::rename(&to_tmp, to).with_context(|| {
+                format!("Failed to move temporary file {to_tmp:?} to final location {to:?}")
+            })
+        });
+    if result.is_err() {
+        // Do not leave a partially written file behind on the ESP.
+        let _ = fs::remove_file(&to_tmp);
+    }
+    result
+}
+
+/// Refuse to install a PE binary whose Authenticode digest is revoked by the dbx.
+///
+/// The firmware refuses to start revoked binaries, so installing one guarantees an unbootable
+/// system.
+fn ensure_not_revoked(dbx: &SignatureDatabase, path: &Path) -> Result<()> {
+    let digest = pe::file_authenticode_digest(path)?;
+    if is_revoked(&digest, dbx) {
+        anyhow::bail!(
+            "Refusing to install {path:?}: its Authenticode hash {digest:x} is revoked by the dbx. \
+            The firmware would refuse to boot it. Update Lanzaboote to a version that is not revoked."
+        );
+    }
     Ok(())
 }
 
+/// Warn if the initrd of a generation contains kernel modules only for other kernel versions.
+///
+/// The versions of the kernel are taken from the `kernel-modules` of the toplevel, which are
+/// built for the kernel of the generation. A mismatch usually means the initrd won't be able to
+/// load any modules. This is only a best-effort check, so errors are ignored.
+fn warn_on_module_version_mismatch(generation: &Generation, initrd: &Path) {
+    let bootspec = &generation.spec.bootspec.bootspec;
+    let Ok(entries) = fs::read_dir(bootspec.toplevel.0.join("kernel-modules/lib/modules")) else {
+        return;
+    };
+    let kernel_versions = entries
+        .filter_map(|e| e.ok()?.file_name().into_string().ok())
+        .collect::<BTreeSet<_>>();
+
+    let initrd_versions = match initrd::module_versions(initrd) {
+        Ok(versions) => versions,
+        Err(e) => {
+            log::debug!("Failed to read the module versions of the initrd: {e:#}");
+            return;
+        }
+    };
+
+    if !kernel_versions.is_empty()
+        && !initrd_versions.is_empty()
+        && initrd_versions.is_disjoint(&kernel_versions)
+    {
+        let join = |versions: BTreeSet<String>| versions.into_iter().collect::<Vec<_>>().join(", ");
+        log::warn!(
+            "The initrd of generation {generation} contains modules for kernel {}, but its kernel is {}.",
+            join(initrd_versions),
+            join(kernel_versions),
+        );
+    }
+}
+
 /// Install an arbitrary file.
 ///
-/// The file is only copied if
-///     (1) it doesn't exist at the destination or,
-///     (2) the hash of the file at the destination does not match the hash of the source file.
+/// The file is only copied if it [`needs_update`], which avoids needless writes to the ESP.
 fn install(from: &Path, to: &Path) -> Result<()> {
-    if !to.exists() || file_hash(from)? != file_hash(to)? {
+    if needs_update(from, to)? {
         force_install(from, to)?;
+    } else {
+        log::debug!("{to:?} is up to date.");
     }
     Ok(())
 }
 
+/// Check whether a file has to be copied to its destination, i.e. whether
+///     (1) it doesn't exist at the destination or,
+///     (2) the hash of the file at the destination does not match the hash of the source file.
+fn needs_update(from: &Path, to: &Path) -> Result<bool> {
+    Ok(!to.exists() || file_hash(from)? != file_hash(to)?)
+}
+
 /// Forcibly install an arbitrary file.
 ///
 /// If the file already exists at the destination, it is overwritten.
@@ -455,16 +1570,6 @@ pub fn append_initrd_secrets(
     Ok(())
 }
 
-fn assemble_kernel_cmdline(init: &Path, kernel_params: Vec<String>) -> Vec<String> {
-    let init_string = String::from(
-        init.to_str()
-            .expect("Failed to convert init path to string"),
-    );
-    let mut kernel_cmdline: Vec<String> = vec![format!("init={}", init_string)];
-    kernel_cmdline.extend(kernel_params);
-    kernel_cmdline
-}
-
 /// Atomically copy a file.
 ///
 /// First, the content is written to a temporary file (with a `.tmp` extension).
@@ -493,7 +1598,7 @@ fn atomic_copy(from: &Path, to: &Path) -> Result<()> {
 }
 
 /// Set the octal permission bits of the specified file.
-fn set_permission_bits(path: &Path, permission_bits: u32) -> Result<()> {
+pub(crate) fn set_permission_bits(path: &Path, permission_bits: u32) -> Result<()> {
     let mut perms = fs::metadata(path)
         .with_context(|| format!("File {path:?} doesn't have any metadata"))?
         .permissions();
@@ -534,3 +1639,26 @@ fn newer_systemd_boot(from: &Path, to: &Path) -> Result<bool> {
 
     Ok(from_version > to_version)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn update_missing_or_changed_files() {
+        let dir = tempdir().unwrap();
+        let from = dir.path().join("from");
+        let to = dir.path().join("to");
+        fs::write(&from, b"systemd-boot").unwrap();
+
+        assert!(needs_update(&from, &to).unwrap());
+
+        fs::write(&to, b"systemd-boot").unwrap();
+        assert!(!needs_update(&from, &to).unwrap());
+
+        fs::write(&to, b"systemd-boot, but different").unwrap();
+        assert!(needs_update(&from, &to).unwrap());
+    }
+}
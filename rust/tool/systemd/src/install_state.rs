@@ -0,0 +1,176 @@
+//! The UKIs written by the last incremental installation.
+//!
+//! With `--incremental`, lzbt records every UKI it installed in a state file on the ESP, together
+//! with its hash and the kernel and initrd it refers to. The next installation compares the
+//! generations against this record: a UKI whose name and hash are unchanged is kept as it is, only
+//! new or changed generations are assembled, signed and written.
+//! Generations that are gone are garbage collected as usual.
+//!
+//! The name of a UKI already identifies the generation and every option that changes its
+//! contents, so the hash only has to catch UKIs that were modified on the ESP. The state is a
+//! cache: if it is missing or unreadable, everything is checked as without `--incremental`.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use lanzaboote_tool::pe;
+use lanzaboote_tool::utils::Hash;
+
+/// Version of the state format. Increment on incompatible changes.
+const STATE_VERSION: u64 = 1;
+
+/// The name of the state file in `EFI/nixos`.
+pub const STATE_FILENAME: &str = "lanzaboote-state.json";
+
+/// What is recorded about an installed UKI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstalledUki {
+    /// The SHA256 hash of the whole file.
+    pub sha256: Hash,
+    /// The UEFI path of the kernel, as embedded in the `.linux` section.
+    pub kernel: String,
+    /// The UEFI path of the initrd, as embedded in the `.initrd` section, if the UKI has one.
+    pub initrd: Option<String>,
+}
+
+impl InstalledUki {
+    /// Describe the UKI with the contents `stub`.
+    pub fn from_stub(stub: &[u8]) -> Result<Self> {
+        let text = |data| -> Result<String> { Ok(std::str::from_utf8(data)?.to_string()) };
+
+        Ok(Self {
+            sha256: Sha256::digest(stub),
+            kernel: text(
+                pe::read_section_data(stub, ".linux").context("Missing .linux section.")?,
+            )?,
+            initrd: pe::read_section_data(stub, ".initrd")
+                .map(text)
+                .transpose()?,
+        })
+    }
+}
+
+/// The installed UKIs, by file name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InstallState {
+    ukis: BTreeMap<String, InstalledUki>,
+}
+
+impl InstallState {
+    /// Read the state from `path`. A missing file is an empty state.
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {path:?}")),
+        };
+        let json: Value = serde_json::from_slice(&contents)
+            .with_context(|| format!("Failed to parse {path:?}"))?;
+        Self::from_json(&json).with_context(|| format!("Failed to parse {path:?}"))
+    }
+
+    fn from_json(json: &Value) -> Result<Self> {
+        if json["version"].as_u64() != Some(STATE_VERSION) {
+            bail!("Unsupported version {}", json["version"]);
+        }
+        let mut ukis = BTreeMap::new();
+        for (name, uki) in json["ukis"].as_object().context("Missing UKIs")? {
+            let field = |key| {
+                uki[key]
+                    .as_str()
+                    .with_context(|| format!("Missing {key} of {name}"))
+            };
+            let sha256 = parse_hash(field("sha256")?)
+                .with_context(|| format!("Invalid sha256 of {name}"))?;
+            ukis.insert(
+                name.clone(),
+                InstalledUki {
+                    sha256,
+                    kernel: field("kernel")?.to_string(),
+                    initrd: uki["initrd"].as_str().map(str::to_string),
+                },
+            );
+        }
+        Ok(Self { ukis })
+    }
+
+    fn to_json(&self) -> Value {
+        let ukis = self
+            .ukis
+            .iter()
+            .map(|(name, uki)| {
+                let uki = json!({
+                    "sha256": format!("{:x}", uki.sha256),
+                    "kernel": uki.kernel,
+                    "initrd": uki.initrd,
+                });
+                (name.clone(), uki)
+            })
+            .collect::<serde_json::Map<_, _>>();
+
+        json!({
+            "version": STATE_VERSION,
+            "ukis": ukis,
+        })
+    }
+
+    /// Write the state to `path`.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(&self.to_json())?;
+        fs::write(path, json + "\n").with_context(|| format!("Failed to write {path:?}"))
+    }
+
+    /// The record of the UKI called `name`.
+    pub fn get(&self, name: &str) -> Option<&InstalledUki> {
+        self.ukis.get(name)
+    }
+
+    pub fn insert(&mut self, name: String, uki: InstalledUki) {
+        self.ukis.insert(name, uki);
+    }
+}
+
+/// Parse a hex encoded SHA256 hash.
+fn parse_hash(hex: &str) -> Option<Hash> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(Hash::clone_from_slice(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_state() -> Result<()> {
+        let mut state = InstallState::default();
+        state.insert(
+            "nixos-generation-1-abc.efi".into(),
+            InstalledUki {
+                sha256: Sha256::digest(b"stub"),
+                kernel: "\\EFI\\nixos\\kernel.efi".into(),
+                initrd: Some("\\EFI\\nixos\\initrd.efi".into()),
+            },
+        );
+
+        assert_eq!(InstallState::from_json(&state.to_json())?, state);
+        assert!(InstallState::from_json(&json!({"version": 2, "ukis": {}})).is_err());
+        assert!(InstallState::from_json(&json!({
+            "version": 1,
+            "ukis": {"a.efi": {"sha256": "00", "kernel": "", "initrd": ""}},
+        }))
+        .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,339 @@
+//! The [Type #1 boot loader
+//! entries](https://uapi-group.org/specifications/specs/boot_loader_specification/) in
+//! `loader/entries`.
+//!
+//! systemd-boot finds the UKIs in `EFI/Linux` by itself, so lzbt only writes entries there with
+//! `--type1-entries`. These entries start with [`LZBT_ENTRY_MARKER`] and are garbage collected
+//! like the UKIs. The systemd-boot installer of NixOS, however, writes an entry for every
+//! generation. After migrating to lzbt, these entries are left behind: they show up next to the
+//! entries of the UKIs and refer to kernels in `EFI/nixos`, which lzbt garbage collects.
+//!
+//! [`detect_mixed_installation`] recognizes an ESP that holds the generations of both, e.g. after
+//! an interrupted migration, by the marker of the entries written by lzbt, the names of its UKIs
+//! and the entries and bookkeeping files of the systemd-boot installer.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::esp::SystemdEspPaths;
+use lanzaboote_tool::generation::ENTRY_FILENAME_PREFIX;
+
+/// The directory in `EFI/nixos` in which the systemd-boot installer of NixOS records the extra
+/// files it copied to the ESP.
+const SYSTEMD_BOOT_EXTRA_FILES: &str = ".extra-files";
+
+/// The first line of the entries written by lzbt.
+pub const LZBT_ENTRY_MARKER: &str = "# Installed by lzbt. Changes are overwritten.";
+
+/// Whether `path` is an entry written by lzbt.
+pub fn is_lzbt_entry(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "conf")
+        && fs::read_to_string(path).is_ok_and(|contents| contents.starts_with(LZBT_ENTRY_MARKER))
+}
+
+/// Who an entry belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// The entry boots a UKI installed by lzbt.
+    Lanzaboote,
+    /// The entry was written by lzbt itself.
+    Installed,
+    /// The entry was left behind by the systemd-boot installer of NixOS.
+    Stale,
+    /// The entry belongs to something else, e.g. another operating system.
+    Foreign,
+}
+
+impl fmt::Display for EntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Lanzaboote => "boots a UKI installed by lzbt",
+            Self::Installed => "installed by lzbt",
+            Self::Stale => "stale, left behind by the systemd-boot installer of NixOS",
+            Self::Foreign => "foreign, not touched by lzbt",
+        })
+    }
+}
+
+/// An entry file in `loader/entries`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryFile {
+    pub path: PathBuf,
+    pub kind: EntryKind,
+}
+
+/// Read and classify the entries in `loader/entries`, sorted by file name.
+pub fn scan_loader_entries(esp_paths: &SystemdEspPaths) -> Result<Vec<EntryFile>> {
+    let dir = esp_paths.loader.join("entries");
+    let dir_entries = match fs::read_dir(&dir) {
+        Ok(dir_entries) => dir_entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {dir:?}")),
+    };
+
+    let mut entries = Vec::new();
+    for dir_entry in dir_entries {
+        let path = dir_entry?.path();
+        // systemd-boot only reads files ending in .conf.
+        if !path.is_file() || path.extension().map_or(true, |ext| ext != "conf") {
+            continue;
+        }
+        let contents =
+            fs::read_to_string(&path).with_context(|| format!("Failed to read {path:?}"))?;
+        let kind = classify_entry(esp_paths, &path, &contents);
+        entries.push(EntryFile { path, kind });
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// Determine who the entry at `path` with `contents` belongs to.
+///
+/// Only entries that refer to files in the directories managed by lzbt or that are named like the
+/// entries of the systemd-boot installer of NixOS are considered stale. Everything else is
+/// foreign.
+fn classify_entry(esp_paths: &SystemdEspPaths, path: &Path, contents: &str) -> EntryKind {
+    if contents.starts_with(LZBT_ENTRY_MARKER) {
+        return EntryKind::Installed;
+    }
+
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    // The installer names the entries of profiles other than `system` like
+    // `nixos-work-generation-3.conf`, the same way lzbt names the UKIs.
+    let mut kind = if name.starts_with(ENTRY_FILENAME_PREFIX) && name.contains("generation-") {
+        EntryKind::Stale
+    } else {
+        EntryKind::Foreign
+    };
+
+    for (key, value) in contents
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.starts_with('#'))
+        .filter_map(|l| l.split_once(char::is_whitespace))
+    {
+        if key != "linux" && key != "efi" {
+            continue;
+        }
+        // FAT is case-insensitive and the NixOS installer writes `/efi/nixos/...`.
+        let value = value
+            .trim()
+            .trim_start_matches(['/', '\\'])
+            .replace('\\', "/");
+        let lower = value.to_lowercase();
+        if key == "efi"
+            && lower.starts_with("efi/linux/nixos-")
+            && esp_paths.esp.join(&value).is_file()
+        {
+            return EntryKind::Lanzaboote;
+        }
+        if lower.starts_with("efi/nixos/") || lower.starts_with("efi/linux/nixos-") {
+            kind = EntryKind::Stale;
+        }
+    }
+    kind
+}
+
+/// The files of both lzbt and the systemd-boot installer of NixOS on one ESP.
+///
+/// systemd-boot shows the entries of both, so it may boot a generation that lzbt did not sign or
+/// whose kernel lzbt already garbage collected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MixedInstallation {
+    /// The UKIs and entries that belong to lzbt.
+    pub lzbt: Vec<PathBuf>,
+    /// The entries and files that belong to the systemd-boot installer.
+    pub systemd_boot: Vec<PathBuf>,
+}
+
+impl fmt::Display for MixedInstallation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "The ESP holds generations installed by both lzbt and the systemd-boot installer of \
+             NixOS, so systemd-boot may boot the wrong one."
+        )?;
+        writeln!(f, "Installed by lzbt:")?;
+        for path in &self.lzbt {
+            writeln!(f, "  {}", path.display())?;
+        }
+        writeln!(f, "Installed by systemd-boot:")?;
+        for path in &self.systemd_boot {
+            writeln!(f, "  {}", path.display())?;
+        }
+        write!(
+            f,
+            "Run `reinit` to install all generations with lzbt and `check-loader-entries \
+             --clean-foreign-entries` to remove the entries of systemd-boot."
+        )
+    }
+}
+
+/// Detect whether both lzbt and the systemd-boot installer of NixOS installed generations to the
+/// ESP, given the `entries` in `loader/entries`.
+pub fn detect_mixed_installation(
+    esp_paths: &SystemdEspPaths,
+    entries: &[EntryFile],
+) -> Result<Option<MixedInstallation>> {
+    let mut lzbt = Vec::new();
+    let mut systemd_boot = Vec::new();
+    for entry in entries {
+        match entry.kind {
+            EntryKind::Lanzaboote | EntryKind::Installed => lzbt.push(entry.path.clone()),
+            EntryKind::Stale => systemd_boot.push(entry.path.clone()),
+            EntryKind::Foreign => {}
+        }
+    }
+
+    match fs::read_dir(&esp_paths.linux) {
+        Ok(dir_entries) => {
+            for dir_entry in dir_entries {
+                let path = dir_entry?.path();
+                let name = path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .unwrap_or_default();
+                if path.is_file()
+                    && name.starts_with(ENTRY_FILENAME_PREFIX)
+                    && name.ends_with(".efi")
+                {
+                    lzbt.push(path);
+                }
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", esp_paths.linux)),
+    }
+    let extra_files = esp_paths.nixos.join(SYSTEMD_BOOT_EXTRA_FILES);
+    if extra_files.is_dir() {
+        systemd_boot.push(extra_files);
+    }
+
+    if lzbt.is_empty() || systemd_boot.is_empty() {
+        return Ok(None);
+    }
+    lzbt.sort();
+    systemd_boot.sort();
+    Ok(Some(MixedInstallation { lzbt, systemd_boot }))
+}
+
+/// Remove the stale entries among `entries`. Other entries are never removed.
+pub fn remove_stale_entries(entries: &[EntryFile]) -> Result<()> {
+    for entry in entries.iter().filter(|e| e.kind == EntryKind::Stale) {
+        log::info!("Removing {:?}...", entry.path);
+        fs::remove_file(&entry.path)
+            .with_context(|| format!("Failed to remove {:?}", entry.path))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use lanzaboote_tool::architecture::Architecture;
+    use lanzaboote_tool::esp::EspPaths;
+    use tempfile::tempdir;
+
+    #[test]
+    fn classify_entries() -> Result<()> {
+        let esp = tempdir()?;
+        let esp_paths = SystemdEspPaths::new(esp.path(), Architecture::X86);
+        fs::create_dir_all(&esp_paths.linux)?;
+        fs::write(esp_paths.linux.join("nixos-generation-1-abc.efi"), "")?;
+        let classify = |name: &str, contents: &str| {
+            classify_entry(
+                &esp_paths,
+                &esp_paths.loader.join("entries").join(name),
+                contents,
+            )
+        };
+
+        assert_eq!(
+            classify(
+                "nixos-generation-1.conf",
+                "title NixOS\nlinux /efi/nixos/abc-linux-6.1-bzImage.efi\n"
+            ),
+            EntryKind::Stale
+        );
+        // Named differently, but referring to a kernel in EFI/nixos.
+        assert_eq!(
+            classify("custom.conf", "linux \\EFI\\nixos\\kernel.efi\n"),
+            EntryKind::Stale
+        );
+        assert_eq!(
+            classify("uki.conf", "efi /EFI/Linux/nixos-generation-1-abc.efi\n"),
+            EntryKind::Lanzaboote
+        );
+        // The UKI lzbt installed for it is gone.
+        assert_eq!(
+            classify("uki.conf", "efi /EFI/Linux/nixos-generation-2-abc.efi\n"),
+            EntryKind::Stale
+        );
+        // Written by lzbt with --type1-entries, even though it refers to a kernel in EFI/nixos.
+        assert_eq!(
+            classify(
+                "nixos-generation-1-abc.conf",
+                &format!("{LZBT_ENTRY_MARKER}\nlinux /EFI/nixos/kernel.efi\n")
+            ),
+            EntryKind::Installed
+        );
+        assert_eq!(
+            classify(
+                "debian.conf",
+                "# linux /efi/nixos/kernel.efi\nlinux /vmlinuz-6.1\ninitrd /initrd.img-6.1\n"
+            ),
+            EntryKind::Foreign
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn detect_mixed_installations() -> Result<()> {
+        let esp = tempdir()?;
+        let esp_paths = SystemdEspPaths::new(esp.path(), Architecture::X86);
+        let entries_dir = esp_paths.loader.join("entries");
+        fs::create_dir_all(&entries_dir)?;
+        fs::create_dir_all(&esp_paths.linux)?;
+        let detect = || -> Result<Option<MixedInstallation>> {
+            detect_mixed_installation(&esp_paths, &scan_loader_entries(&esp_paths)?)
+        };
+
+        // Only systemd-boot.
+        fs::create_dir_all(esp_paths.nixos.join(SYSTEMD_BOOT_EXTRA_FILES))?;
+        fs::write(
+            entries_dir.join("nixos-generation-1.conf"),
+            "linux /efi/nixos/kernel.efi\n",
+        )?;
+        assert_eq!(detect()?, None);
+
+        // A UKI of lzbt next to the entries of systemd-boot.
+        let uki = esp_paths.linux.join("nixos-generation-2-abc.efi");
+        fs::write(&uki, "")?;
+        assert_eq!(
+            detect()?,
+            Some(MixedInstallation {
+                lzbt: vec![uki.clone()],
+                systemd_boot: vec![
+                    esp_paths.nixos.join(SYSTEMD_BOOT_EXTRA_FILES),
+                    entries_dir.join("nixos-generation-1.conf"),
+                ],
+            })
+        );
+
+        // Only lzbt and another operating system.
+        fs::remove_dir(esp_paths.nixos.join(SYSTEMD_BOOT_EXTRA_FILES))?;
+        fs::remove_file(entries_dir.join("nixos-generation-1.conf"))?;
+        fs::write(entries_dir.join("debian.conf"), "linux /vmlinuz-6.1\n")?;
+        fs::write(esp_paths.linux.join("debian.efi"), "")?;
+        assert_eq!(detect()?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use lanzaboote_tool::utils::explain_io_error;
+
+/// The directory where the kernel exposes EFI variables.
+pub const EFIVARFS: &str = "/sys/firmware/efi/efivars";
+
+/// Vendor GUID of the variables shared between systemd-boot and the OS.
+const LOADER_GUID: &str = "4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
+
+/// Where the effective default boot entry was taken from.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DefaultEntrySource {
+    /// The `LoaderEntryDefault` EFI variable, e.g. set by `bootctl set-default`.
+    EfiVariable,
+    /// The `default` key of `loader/loader.conf`.
+    LoaderConf,
+    /// The `LoaderEntrySelected` EFI variable, i.e. the entry that was booted.
+    Selected,
+}
+
+impl fmt::Display for DefaultEntrySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::EfiVariable => "LoaderEntryDefault EFI variable",
+            Self::LoaderConf => "loader.conf",
+            Self::Selected => "LoaderEntrySelected EFI variable",
+        })
+    }
+}
+
+/// The boot entry related state of systemd-boot.
+#[derive(Debug, Default)]
+pub struct LoaderState {
+    pub entry_default: Option<String>,
+    pub loader_conf_default: Option<String>,
+    pub entry_selected: Option<String>,
+}
+
+impl LoaderState {
+    /// Read the state from the loader.conf on the ESP and the EFI variables in `efivars`.
+    pub fn read(loader_conf: &Path, efivars: &Path) -> Result<Self> {
+        let loader_conf_default = match fs::read_to_string(loader_conf) {
+            Ok(contents) => parse_loader_conf_default(&contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {loader_conf:?}")),
+        };
+
+        Ok(Self {
+            entry_default: read_loader_variable(efivars, "LoaderEntryDefault")?,
+            loader_conf_default,
+            entry_selected: read_loader_variable(efivars, "LoaderEntrySelected")?,
+        })
+    }
+
+    /// Return the name of the entry systemd-boot boots by default.
+    ///
+    /// Like systemd-boot, the `LoaderEntryDefault` EFI variable takes precedence over loader.conf.
+    /// Without either of them, the entry that was booted is assumed to be the default.
+    pub fn effective_default(&self) -> Option<(&str, DefaultEntrySource)> {
+        [
+            (&self.entry_default, DefaultEntrySource::EfiVariable),
+            (&self.loader_conf_default, DefaultEntrySource::LoaderConf),
+            (&self.entry_selected, DefaultEntrySource::Selected),
+        ]
+        .into_iter()
+        .find_map(|(entry, source)| Some((entry.as_deref()?, source)))
+    }
+}
+
+/// Extract the value of the `default` key from the contents of a loader.conf.
+fn parse_loader_conf_default(contents: &str) -> Option<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.starts_with('#'))
+        .filter_map(|l| l.split_once(char::is_whitespace))
+        // The last occurrence wins.
+        .rfind(|(key, _)| *key == "default")
+        .map(|(_, value)| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Read a string variable of systemd-boot from efivarfs.
+fn read_loader_variable(efivars: &Path, name: &str) -> Result<Option<String>> {
+    let path = efivars.join(format!("{name}-{LOADER_GUID}"));
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(explain_io_error(e, "read", &path)),
+    };
+
+    // efivarfs prefixes the contents with the 4 byte attributes of the variable.
+    let value = data
+        .get(4..)
+        .and_then(parse_efi_string)
+        .with_context(|| format!("Failed to parse EFI variable {path:?}"))?;
+    Ok(Some(value).filter(|v| !v.is_empty()))
+}
+
+/// Parse a NUL-terminated UTF-16LE string as used by EFI variables.
+fn parse_efi_string(data: &[u8]) -> Option<String> {
+    let chunks = data.chunks_exact(2);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+    let code_units = chunks
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect::<Vec<_>>();
+    String::from_utf16(&code_units).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    fn write_loader_variable(efivars: &Path, name: &str, value: &str) {
+        let mut data = vec![0x06, 0x00, 0x00, 0x00];
+        for code_unit in value.encode_utf16().chain([0]) {
+            data.extend(code_unit.to_le_bytes());
+        }
+        fs::write(efivars.join(format!("{name}-{LOADER_GUID}")), data).unwrap();
+    }
+
+    #[test]
+    fn parse_default_from_loader_conf() {
+        let loader_conf = "timeout 3\n# default ignored.conf\ndefault  nixos-*\nconsole-mode 1\n";
+        assert_eq!(
+            parse_loader_conf_default(loader_conf),
+            Some("nixos-*".to_string())
+        );
+        assert_eq!(parse_loader_conf_default("timeout 3\n"), None);
+    }
+
+    #[test]
+    fn prefer_efi_variable_over_loader_conf() {
+        let esp = tempdir().unwrap();
+        let efivars = tempdir().unwrap();
+        let loader_conf = esp.path().join("loader.conf");
+        fs::write(&loader_conf, "default nixos-generation-1.efi\n").unwrap();
+        write_loader_variable(
+            efivars.path(),
+            "LoaderEntryDefault",
+            "nixos-generation-2.efi",
+        );
+        write_loader_variable(
+            efivars.path(),
+            "LoaderEntrySelected",
+            "nixos-generation-3.efi",
+        );
+
+        let state = LoaderState::read(&loader_conf, efivars.path()).unwrap();
+
+        assert_eq!(
+            state.effective_default(),
+            Some(("nixos-generation-2.efi", DefaultEntrySource::EfiVariable))
+        );
+    }
+
+    #[test]
+    fn prefer_loader_conf_over_selected_entry() {
+        let esp = tempdir().unwrap();
+        let efivars = tempdir().unwrap();
+        let loader_conf = esp.path().join("loader.conf");
+        fs::write(&loader_conf, "default nixos-generation-1.efi\n").unwrap();
+        write_loader_variable(
+            efivars.path(),
+            "LoaderEntrySelected",
+            "nixos-generation-3.efi",
+        );
+
+        let state = LoaderState::read(&loader_conf, efivars.path()).unwrap();
+
+        assert_eq!(
+            state.effective_default(),
+            Some(("nixos-generation-1.efi", DefaultEntrySource::LoaderConf))
+        );
+    }
+
+    #[test]
+    fn fall_back_to_selected_entry() {
+        let esp = tempdir().unwrap();
+        let efivars = tempdir().unwrap();
+        write_loader_variable(
+            efivars.path(),
+            "LoaderEntrySelected",
+            "nixos-generation-3.efi",
+        );
+
+        let state = LoaderState::read(&esp.path().join("loader.conf"), efivars.path()).unwrap();
+
+        assert_eq!(
+            state.effective_default(),
+            Some(("nixos-generation-3.efi", DefaultEntrySource::Selected))
+        );
+        assert_eq!(LoaderState::default().effective_default(), None);
+    }
+}
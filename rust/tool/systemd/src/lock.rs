@@ -0,0 +1,31 @@
+use std::fs::File;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+
+/// An advisory lock that keeps other instances from installing to the same ESP at the same time.
+///
+/// The lock is taken on the mountpoint of the ESP itself, so no lock file has to be created on the
+/// ESP. It is released when the value is dropped, or when the process exits.
+pub struct EspLock {
+    _lock: Flock<File>,
+}
+
+impl EspLock {
+    /// Take the lock without waiting, failing if another instance holds it.
+    pub fn acquire(esp: &Path) -> Result<Self> {
+        let dir = File::open(esp).with_context(|| format!("Failed to open the ESP at {esp:?}"))?;
+        match Flock::lock(dir, FlockArg::LockExclusiveNonblock) {
+            Ok(lock) => Ok(Self { _lock: lock }),
+            Err((_, Errno::EWOULDBLOCK)) => anyhow::bail!(
+                "Another instance of lzbt is installing to {esp:?}. Wait for it to finish or pass \
+                --no-lock if you are sure that it is not running."
+            ),
+            Err((_, errno)) => {
+                Err(errno).with_context(|| format!("Failed to lock the ESP at {esp:?}"))
+            }
+        }
+    }
+}
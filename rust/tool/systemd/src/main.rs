@@ -1,12 +1,22 @@
-mod architecture;
+mod boot_entry;
+mod boot_order;
+mod bundle;
+mod canonical;
 mod cli;
 mod esp;
+mod export;
 mod install;
+mod install_state;
+mod loader_entries;
+mod loader_state;
+mod lock;
+mod plan;
 mod version;
 
 use clap::Parser;
 
 use cli::Cli;
+use lzbt_systemd::architecture;
 
 fn main() {
     Cli::parse().call(module_path!())
@@ -0,0 +1,115 @@
+//! The changes an installation would make to the ESP.
+//!
+//! The plan is computed from the same information the installer uses to decide what to write:
+//! the names of the UKIs, the state of the last incremental installation, the installed kernels
+//! and initrds and the garbage collector roots. Nothing is built, signed or written.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// What happens to a file on the ESP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Add,
+    Update,
+    Remove,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            Self::Add => "add",
+            Self::Update => "update",
+            Self::Remove => "remove",
+        };
+        f.pad(action)
+    }
+}
+
+/// A change to a single file or directory on the ESP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub action: Action,
+    /// The path relative to the ESP.
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// The changes an installation would make, in the order it makes them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Plan {
+    changes: Vec<Change>,
+}
+
+impl Plan {
+    /// Record a change to `path`, which is either absolute on the ESP `esp` or relative to it.
+    pub fn push(&mut self, esp: &Path, action: Action, path: &Path, reason: impl Into<String>) {
+        let path = path.strip_prefix(esp).unwrap_or(path).to_path_buf();
+        self.changes.push(Change {
+            action,
+            path,
+            reason: reason.into(),
+        });
+    }
+
+    pub fn changes(&self) -> &[Change] {
+        &self.changes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for change in &self.changes {
+            writeln!(
+                f,
+                "{:<6} {} ({})",
+                change.action,
+                change.path.display(),
+                change.reason
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_plan() {
+        let esp = Path::new("/boot");
+        let mut plan = Plan::default();
+        assert!(plan.is_empty());
+        plan.push(
+            esp,
+            Action::Add,
+            Path::new("/boot/EFI/Linux/nixos-generation-2.efi"),
+            "new generation 2",
+        );
+        plan.push(
+            esp,
+            Action::Update,
+            Path::new("loader/loader.conf"),
+            "loader.conf changed",
+        );
+        plan.push(
+            esp,
+            Action::Remove,
+            Path::new("/boot/EFI/Linux/nixos-generation-1.efi"),
+            "no longer used",
+        );
+
+        assert_eq!(plan.changes()[1].path, Path::new("loader/loader.conf"));
+        assert_eq!(
+            plan.to_string(),
+            "add    EFI/Linux/nixos-generation-2.efi (new generation 2)\n\
+             update loader/loader.conf (loader.conf changed)\n\
+             remove EFI/Linux/nixos-generation-1.efi (no longer used)\n"
+        );
+    }
+}
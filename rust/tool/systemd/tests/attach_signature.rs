@@ -0,0 +1,110 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use assert_cmd::Command;
+use sha2::{Digest, Sha256};
+use tempfile::tempdir;
+use x509_cert::der::{DecodePem, Encode};
+use x509_cert::Certificate;
+
+use lanzaboote_tool::authenticode::{attach_and_verify, signer_chains, NativeSigner};
+use lanzaboote_tool::pe::authenticode_digest;
+use lanzaboote_tool::signature::{KeyPair, Signer};
+
+const FIXTURE_UKI: &str = "tests/fixtures/authenticode/uki.efi";
+const DB_CERTIFICATE: &str = "tests/fixtures/uefi-keys/db.pem";
+
+/// Sign `binary` with the db key and return the PKCS#7 structure of the signature, like an
+/// offline signing machine would hand it out.
+fn detached_signature(binary: &Path) -> Result<Vec<u8>> {
+    let out = tempdir()?;
+    let signed = out.path().join("signed.efi");
+    let key_pair = KeyPair::new(
+        Path::new(DB_CERTIFICATE),
+        Path::new("tests/fixtures/uefi-keys/db.key"),
+    );
+    NativeSigner::new(&key_pair)?.sign_and_copy(binary, &signed)?;
+
+    let signed = fs::read(&signed)?;
+    let pe = goblin::pe::PE::parse(&signed)?;
+    let certificate_table = pe
+        .header
+        .optional_header
+        .and_then(|h| *h.data_directories.get_certificate_table())
+        .context("Signed binary has no certificate table")?;
+    let start = usize::try_from(certificate_table.virtual_address)?;
+    let end = start + usize::try_from(certificate_table.size)?;
+    // Skip the header of the WIN_CERTIFICATE structure.
+    Ok(signed[start + 8..end].to_vec())
+}
+
+#[test]
+fn attach_valid_detached_signature() -> Result<()> {
+    let out = tempdir()?;
+    let uki = out.path().join("uki.efi");
+    fs::copy(FIXTURE_UKI, &uki)?;
+    let p7s = out.path().join("uki.efi.p7s");
+    fs::write(&p7s, detached_signature(Path::new(FIXTURE_UKI))?)?;
+    let unsigned_digest = authenticode_digest(&fs::read(&uki)?)?;
+
+    let output = Command::cargo_bin("lzbt-systemd")?
+        .arg("attach-signature")
+        .arg(&uki)
+        .arg("--signature")
+        .arg(&p7s)
+        .arg("--certificate")
+        .arg(DB_CERTIFICATE)
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+
+    let signed = fs::read(&uki)?;
+    assert_eq!(authenticode_digest(&signed)?, unsigned_digest);
+    let chains = signer_chains(&signed)?;
+    assert_eq!(chains.len(), 1);
+    let certificate = Certificate::from_pem(fs::read(DB_CERTIFICATE)?)?;
+    assert_eq!(
+        chains[0][0].fingerprint,
+        Sha256::digest(certificate.to_der()?)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn reject_signature_of_another_binary() -> Result<()> {
+    let out = tempdir()?;
+    let other = out.path().join("other.efi");
+    let mut other_data = fs::read(FIXTURE_UKI)?;
+    let middle = other_data.len() / 2;
+    other_data[middle] ^= 0xff;
+    fs::write(&other, other_data)?;
+    let p7s = out.path().join("other.efi.p7s");
+    fs::write(&p7s, detached_signature(&other)?)?;
+
+    let uki = out.path().join("uki.efi");
+    fs::copy(FIXTURE_UKI, &uki)?;
+    let err = attach_and_verify(&uki, &p7s, Path::new(DB_CERTIFICATE)).unwrap_err();
+    assert!(
+        format!("{err:#}").contains("does not cover the Authenticode digest"),
+        "{err:#}"
+    );
+    assert_eq!(fs::read(&uki)?, fs::read(FIXTURE_UKI)?);
+
+    Ok(())
+}
+
+#[test]
+fn reject_signature_of_untrusted_signer() -> Result<()> {
+    let out = tempdir()?;
+    let uki = out.path().join("uki.efi");
+    fs::copy(FIXTURE_UKI, &uki)?;
+    let p7s = out.path().join("uki.efi.p7s");
+    fs::write(&p7s, detached_signature(Path::new(FIXTURE_UKI))?)?;
+
+    let err = attach_and_verify(&uki, &p7s, Path::new("tests/fixtures/pki/good.pem")).unwrap_err();
+    assert!(format!("{err:#}").contains("does not chain"), "{err:#}");
+    assert_eq!(fs::read(&uki)?, fs::read(FIXTURE_UKI)?);
+
+    Ok(())
+}
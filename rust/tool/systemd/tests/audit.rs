@@ -0,0 +1,290 @@
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use anyhow::{Context, Result};
+use assert_cmd::Command;
+use sha2::{Digest, Sha256};
+use tempfile::tempdir;
+
+use lanzaboote_tool::authenticode::NativeSigner;
+use lanzaboote_tool::manifest::predict_pcr11;
+use lanzaboote_tool::signature::{KeyPair, Signer};
+
+const FIXTURE_UKI: &str = "tests/fixtures/authenticode/uki.efi";
+const DB_CERTIFICATE: &str = "tests/fixtures/uefi-keys/db.pem";
+const SBAT: &str = "sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md\n\
+                    systemd,2,The systemd Developers,systemd,255,https://systemd.io/\n";
+const SBAT_LEVEL: &str = "sbat,1,2024010900\nshim,4\nsystemd,2\n";
+
+fn key_pair(name: &str) -> KeyPair {
+    KeyPair::new(
+        Path::new(&format!("tests/fixtures/{name}.pem")),
+        Path::new(&format!("tests/fixtures/{name}.key")),
+    )
+}
+
+/// Copy `from` to `to` with objcopy, which is given `args`.
+fn objcopy(from: &Path, to: &Path, args: &[String]) -> Result<()> {
+    let status = process::Command::new("objcopy")
+        .args(args)
+        .arg(from)
+        .arg(to)
+        .status()
+        .context("Failed to run objcopy")?;
+    anyhow::ensure!(status.success(), "objcopy failed with {status}");
+    Ok(())
+}
+
+/// Add the sections in `sections` to `uki` behind the existing ones.
+fn add_sections(uki: &Path, sections: &[(&str, &[u8])]) -> Result<()> {
+    let mut args = Vec::new();
+    for (index, (name, data)) in sections.iter().enumerate() {
+        let file = uki.with_extension(&name[1..]);
+        fs::write(&file, data)?;
+        args.push("--add-section".into());
+        args.push(format!("{name}={}", file.display()));
+        args.push("--change-section-vma".into());
+        args.push(format!("{name}={:#x}", 0x10000 + 0x1000 * index));
+    }
+    let unsigned = uki.with_extension("unsigned");
+    fs::copy(uki, &unsigned)?;
+    objcopy(&unsigned, uki, &args)
+}
+
+/// The `.pcrsig` section systemd-measure would create for a single policy over PCR 11 with the
+/// value `pcr`.
+fn pcrsig(pcr: &[u8]) -> Vec<u8> {
+    let policy = Sha256::new()
+        .chain_update([0; 32])
+        .chain_update(0x17fu32.to_be_bytes())
+        .chain_update([0, 0, 0, 1, 0, 0x0b, 3, 0, 0x08, 0])
+        .chain_update(Sha256::digest(pcr))
+        .finalize();
+    serde_json::json!({
+        "sha256": [{
+            "pcrs": [11],
+            "pkfp": "",
+            "pol": format!("{policy:x}"),
+            "sig": "",
+        }],
+    })
+    .to_string()
+    .into_bytes()
+}
+
+/// The fixture without its certificate table, which objcopy would not move behind the added
+/// sections.
+fn unsigned_fixture() -> Result<Vec<u8>> {
+    let mut data = fs::read(FIXTURE_UKI)?;
+    let pe = goblin::pe::PE::parse(&data)?;
+    let certificate_table = pe
+        .header
+        .optional_header
+        .and_then(|h| *h.data_directories.get_certificate_table())
+        .context("The fixture has no certificate table")?;
+    // The certificate table is the fifth data directory of the PE32+ optional header.
+    let entry = pe.header.dos_header.pe_pointer as usize + 24 + 112 + 4 * 8;
+    data[entry..entry + 8].fill(0);
+    data.truncate(usize::try_from(certificate_table.virtual_address)?);
+    Ok(data)
+}
+
+/// Build a UKI from the fixture with `sections` added and sign it with the key pair `signer`, if
+/// any.
+fn uki(esp: &Path, name: &str, sections: &[(&str, &[u8])], signer: Option<&str>) -> Result<()> {
+    let work = tempdir()?;
+    let unsigned = work.path().join(Path::new(name).file_name().unwrap());
+    fs::write(&unsigned, unsigned_fixture()?)?;
+    add_sections(&unsigned, sections)?;
+
+    let target = esp.join(name);
+    fs::create_dir_all(target.parent().unwrap())?;
+    match signer {
+        Some(signer) => NativeSigner::new(&key_pair(signer))?.sign_and_copy(&unsigned, &target)?,
+        None => {
+            fs::copy(&unsigned, &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn predict_overlays_in_file_order() -> Result<()> {
+    let work = tempdir()?;
+    let uki = work.path().join("uki.efi");
+    fs::write(&uki, unsigned_fixture()?)?;
+    // objcopy refuses to add sections with the same name, so they are renamed afterwards.
+    add_sections(&uki, &[(".dtbo1", b"first"), (".dtbo2", b"second")])?;
+    let mut data = fs::read(&uki)?;
+    for name in [b".dtbo1\0\0", b".dtbo2\0\0"] {
+        let header = data
+            .windows(name.len())
+            .position(|window| window == name)
+            .context("The section was not added")?;
+        data[header..header + name.len()].copy_from_slice(b".dtbo\0\0\0");
+    }
+
+    let expected = [b"first".as_slice(), b"second"].iter().fold(
+        predict_pcr11(&unsigned_fixture()?)?,
+        |pcr, overlay| {
+            Sha256::new()
+                .chain_update(pcr)
+                .chain_update(Sha256::digest(overlay))
+                .finalize()
+        },
+    );
+    assert_eq!(predict_pcr11(&data)?, expected);
+    Ok(())
+}
+
+#[test]
+fn report_mixed_good_and_bad_entries() -> Result<()> {
+    let esp = tempdir()?;
+    let esp = esp.path();
+    let work = tempdir()?;
+
+    // The policy only depends on the measured sections, which .sbat and .pcrsig are not.
+    let pcr = predict_pcr11(&fs::read(FIXTURE_UKI)?)?;
+    let good_pcrsig = pcrsig(&pcr);
+    let stale_pcrsig = pcrsig(&Sha256::digest(b"another kernel"));
+
+    uki(
+        esp,
+        "EFI/BOOT/BOOTX64.EFI",
+        &[(".sbat", SBAT.as_bytes())],
+        Some("uefi-keys/db"),
+    )?;
+    uki(
+        esp,
+        "EFI/Linux/good.efi",
+        &[(".sbat", SBAT.as_bytes()), (".pcrsig", &good_pcrsig)],
+        Some("uefi-keys/db"),
+    )?;
+    uki(
+        esp,
+        "EFI/Linux/unsigned.efi",
+        &[(".sbat", SBAT.as_bytes())],
+        None,
+    )?;
+    uki(
+        esp,
+        "EFI/Linux/untrusted.efi",
+        &[(".sbat", SBAT.as_bytes())],
+        Some("pki/good"),
+    )?;
+    uki(
+        esp,
+        "EFI/Linux/revoked.efi",
+        &[(".sbat", SBAT.replace("systemd,2,", "systemd,1,").as_bytes())],
+        Some("uefi-keys/db"),
+    )?;
+    uki(
+        esp,
+        "EFI/Linux/stale-pcrsig.efi",
+        &[(".sbat", SBAT.as_bytes()), (".pcrsig", &stale_pcrsig)],
+        Some("uefi-keys/db"),
+    )?;
+
+    // A UKI of the thin stub whose kernel on the ESP was replaced.
+    fs::create_dir_all(esp.join("EFI/nixos"))?;
+    fs::write(esp.join("EFI/nixos/kernel.efi"), b"replaced kernel")?;
+    let fat = work.path().join("fat.efi");
+    fs::write(&fat, unsigned_fixture()?)?;
+    let thin = work.path().join("thin.efi");
+    let path_file = work.path().join("path");
+    fs::write(&path_file, "\\EFI\\nixos\\kernel.efi")?;
+    objcopy(
+        &fat,
+        &thin,
+        &[
+            "--update-section".into(),
+            format!(".linux={}", path_file.display()),
+        ],
+    )?;
+    add_sections(
+        &thin,
+        &[
+            (".sbat", SBAT.as_bytes()),
+            (".linuxh", &Sha256::digest(b"original kernel")),
+        ],
+    )?;
+    NativeSigner::new(&key_pair("uefi-keys/db"))?
+        .sign_and_copy(&thin, &esp.join("EFI/Linux/thin.efi"))?;
+
+    let sbat_level = work.path().join("SbatLevel.txt");
+    fs::write(&sbat_level, SBAT_LEVEL)?;
+
+    let output = Command::cargo_bin("lzbt-systemd")?
+        .arg("audit")
+        .arg("--db")
+        .arg(DB_CERTIFICATE)
+        .arg("--sbat-level")
+        .arg(&sbat_level)
+        .arg(esp)
+        .output()?;
+    assert!(!output.status.success(), "{output:?}");
+    let report = String::from_utf8(output.stdout)?;
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(
+        stderr.contains("5 of 7 binaries failed the audit"),
+        "{stderr}"
+    );
+
+    let expected = [
+        "EFI/BOOT/BOOTX64.EFI: pass",
+        "  signature: pass",
+        "  sbat: pass",
+        "EFI/Linux/good.efi: pass",
+        "  sections: pass",
+        "  files: skipped (the kernel is embedded)",
+        "  pcrsig: pass",
+        "EFI/Linux/revoked.efi: FAIL",
+        "  sbat: FAIL (revoked generations systemd,1)",
+        "EFI/Linux/stale-pcrsig.efi: FAIL",
+        "EFI/Linux/thin.efi: FAIL",
+        "  files: FAIL (\\EFI\\nixos\\kernel.efi does not match its hash)",
+        "  pcrsig: skipped (no .pcrsig section)",
+        "EFI/Linux/unsigned.efi: FAIL",
+        "  signature: FAIL (The binary is not signed)",
+        "EFI/Linux/untrusted.efi: FAIL",
+        "2 of 7 binaries passed.",
+    ];
+    for line in expected {
+        assert!(
+            report.lines().any(|l| l == line),
+            "Missing {line:?} in:\n{report}"
+        );
+    }
+    assert!(
+        report.contains("policy") && report.contains("does not match the predicted PCR 11"),
+        "{report}"
+    );
+    assert!(
+        report.contains("No signature chains to a trusted certificate"),
+        "{report}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn skip_sbat_without_level() -> Result<()> {
+    let esp = tempdir()?;
+    uki(esp.path(), "EFI/Linux/good.efi", &[], Some("uefi-keys/db"))?;
+
+    let output = Command::cargo_bin("lzbt-systemd")?
+        .arg("audit")
+        .arg("--db")
+        .arg(DB_CERTIFICATE)
+        .arg(esp.path())
+        .output()?;
+    assert!(output.status.success(), "{output:?}");
+    let report = String::from_utf8(output.stdout)?;
+    assert!(
+        report.contains("  sbat: skipped (no SBAT level given)"),
+        "{report}"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,282 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+use rsa::pkcs1v15::{Signature, VerifyingKey};
+use rsa::pkcs8::DecodePublicKey;
+use rsa::signature::Verifier;
+use rsa::RsaPublicKey;
+use sha2::{Digest, Sha256};
+use tempfile::tempdir;
+use x509_cert::der::{DecodePem, Encode};
+use x509_cert::Certificate;
+
+use lanzaboote_tool::authenticode::NativeSigner;
+use lanzaboote_tool::pe::{authenticode_digest, file_authenticode_digest};
+use lanzaboote_tool::signature::{KeyPair, MultiSigner, Signer};
+
+mod common;
+
+/// A minimal signed UKI with `.osrel`, `.cmdline` and `.linux` sections, a non-zero checksum and
+/// a dummy certificate table.
+const FIXTURE_UKI: &str = "tests/fixtures/authenticode/uki.efi";
+
+/// The digest of the fixture, computed independently over the ranges specified by Authenticode.
+const FIXTURE_UKI_DIGEST: &str = "2c5d16d67bacf8fee1c4486e5c10e963a843e49747534f1e763d883ebdd070e3";
+
+/// Compute the digest from the ranges goblin considers part of it.
+fn goblin_digest(file_data: &[u8]) -> Result<Vec<u8>> {
+    let pe = goblin::pe::PE::parse(file_data)?;
+    let mut hasher = Sha256::new();
+    for range in pe.authenticode_ranges() {
+        hasher.update(range);
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+#[test]
+fn streaming_digest_matches_known_good_digest() -> Result<()> {
+    let digest = file_authenticode_digest(Path::new(FIXTURE_UKI))?;
+    assert_eq!(format!("{digest:x}"), FIXTURE_UKI_DIGEST);
+    assert_eq!(digest.to_vec(), goblin_digest(&fs::read(FIXTURE_UKI)?)?);
+    Ok(())
+}
+
+#[test]
+fn streaming_digest_matches_goblin_for_systemd_boot() -> Result<()> {
+    let systemd_boot = common::systemd_boot_binary()?;
+    assert_eq!(
+        file_authenticode_digest(&systemd_boot)?.to_vec(),
+        goblin_digest(&fs::read(&systemd_boot)?)?
+    );
+    Ok(())
+}
+
+#[test]
+fn reject_certificate_table_outside_of_binary() -> Result<()> {
+    let mut file_data = fs::read(FIXTURE_UKI)?;
+    file_data.truncate(file_data.len() - 8);
+
+    let error = authenticode_digest(&file_data).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "The certificate table is outside of the PE binary"
+    );
+    Ok(())
+}
+
+/// The `WIN_CERTIFICATE` structures in the certificate table of a PE binary.
+fn certificates(file_data: &[u8]) -> Result<Vec<&[u8]>> {
+    let pe = goblin::pe::PE::parse(file_data)?;
+    let Some(table) = pe
+        .header
+        .optional_header
+        .and_then(|h| *h.data_directories.get_certificate_table())
+    else {
+        return Ok(Vec::new());
+    };
+    let start = usize::try_from(table.virtual_address)?;
+    let mut table = &file_data[start..start + usize::try_from(table.size)?];
+
+    let mut certificates = Vec::new();
+    while !table.is_empty() {
+        let length = u32::from_le_bytes(table[..4].try_into()?) as usize;
+        certificates.push(table.get(..length).context("Truncated certificate")?);
+        // Every entry is aligned to 8 bytes.
+        table = table.get(length.next_multiple_of(8)..).unwrap_or_default();
+    }
+    Ok(certificates)
+}
+
+fn key_pair(name: &str) -> KeyPair {
+    KeyPair::new(
+        Path::new(&format!("tests/fixtures/{name}.pem")),
+        Path::new(&format!("tests/fixtures/{name}.key")),
+    )
+}
+
+/// Split a DER element into its tag, its contents and the rest of `input`.
+fn der_element(input: &[u8]) -> Result<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first().context("Missing tag")?;
+    let (&first, mut input) = input.split_first().context("Missing length")?;
+    let length = if first < 0x80 {
+        usize::from(first)
+    } else {
+        let (bytes, rest) = input.split_at(usize::from(first & 0x7f));
+        input = rest;
+        bytes
+            .iter()
+            .fold(0, |length, &b| length << 8 | usize::from(b))
+    };
+    let contents = input.get(..length).context("Truncated element")?;
+    Ok((tag, contents, &input[length..]))
+}
+
+/// The contents of the DER elements in the SEQUENCE or SET `contents`.
+fn der_elements(mut contents: &[u8]) -> Result<Vec<(u8, &[u8])>> {
+    let mut elements = Vec::new();
+    while !contents.is_empty() {
+        let (tag, element, rest) = der_element(contents)?;
+        elements.push((tag, element));
+        contents = rest;
+    }
+    Ok(elements)
+}
+
+/// Verify the PKCS#7 SignedData in a `WIN_CERTIFICATE` independently of the signer: it must be
+/// signed by `certificate` and cover `digest`.
+fn verify_certificate(win_certificate: &[u8], certificate: &Path, digest: &[u8]) -> Result<()> {
+    let (_, content_info, _) = der_element(&win_certificate[8..])?;
+    let (_, signed_data) = der_elements(content_info)?[1];
+    let (_, signed_data, _) = der_element(signed_data)?;
+    let signed_data = der_elements(signed_data)?;
+
+    // The SpcIndirectDataContent that contains the Authenticode digest.
+    let (_, encap_content_info) = signed_data[2];
+    let (_, indirect_data) = der_elements(encap_content_info)?[1];
+    let (_, indirect_data, _) = der_element(indirect_data)?;
+    let (_, digest_info) = der_elements(indirect_data)?[1];
+    let (_, embedded_digest) = der_elements(digest_info)?[1];
+    ensure!(
+        embedded_digest == digest,
+        "The signature covers another digest"
+    );
+
+    let certificate = Certificate::from_pem(fs::read(certificate)?)?;
+    let (_, embedded_certificate) = signed_data[3];
+    ensure!(
+        embedded_certificate == certificate.to_der()?,
+        "The signature contains another certificate"
+    );
+
+    let (_, signer_infos) = signed_data[4];
+    let (_, signer_info) = der_elements(signer_infos)?[0];
+    let signer_info = der_elements(signer_info)?;
+    let (_, signed_attributes) = signer_info[3];
+    let (_, signature) = signer_info[5];
+    // The message digest attribute covers the SpcIndirectDataContent without its tag and length.
+    let message_digest = Sha256::digest(indirect_data);
+    ensure!(
+        signed_attributes
+            .windows(message_digest.len())
+            .any(|w| w == message_digest.as_slice()),
+        "The signed attributes do not contain the message digest"
+    );
+
+    // The signature covers the signed attributes as a SET.
+    let length = signed_attributes.len().to_be_bytes();
+    let length = &length[length.iter().take_while(|&&b| b == 0).count()..];
+    let mut signed = vec![0x31];
+    if signed_attributes.len() >= 0x80 {
+        signed.push(0x80 | length.len() as u8);
+    }
+    signed.extend(length);
+    signed.extend(signed_attributes);
+    let public_key = RsaPublicKey::from_public_key_der(
+        &certificate
+            .tbs_certificate
+            .subject_public_key_info
+            .to_der()?,
+    )?;
+    VerifyingKey::<Sha256>::new(public_key).verify(&signed, &Signature::try_from(signature)?)?;
+    Ok(())
+}
+
+#[test]
+fn resign_signed_binary() -> Result<()> {
+    let db = NativeSigner::new(&key_pair("uefi-keys/db"))?;
+    let other = NativeSigner::new(&key_pair("pki/good"))?;
+    let out = tempdir()?;
+    let signed = out.path().join("signed.efi");
+    let resigned = out.path().join("resigned.efi");
+    let resigned_directly = out.path().join("resigned-directly.efi");
+
+    // The fixture already carries a (dummy) signature, which is replaced.
+    db.sign_and_copy(Path::new(FIXTURE_UKI), &signed)?;
+    other.sign_and_copy(&signed, &resigned)?;
+    other.sign_and_copy(Path::new(FIXTURE_UKI), &resigned_directly)?;
+
+    let resigned = fs::read(&resigned)?;
+    let certificates = certificates(&resigned)?;
+    assert_eq!(certificates.len(), 1);
+    let certificate = certificates[0];
+    // The certificate table is at the end of the binary.
+    assert!(resigned.ends_with(certificate));
+
+    // The signature covers the digest of the unsigned binary.
+    let digest = authenticode_digest(&resigned)?;
+    assert_eq!(format!("{digest:x}"), FIXTURE_UKI_DIGEST);
+    assert!(certificate
+        .windows(digest.len())
+        .any(|w| w == digest.as_slice()));
+
+    // Nothing of the earlier signatures is left.
+    assert_eq!(resigned, fs::read(&resigned_directly)?);
+    Ok(())
+}
+
+#[test]
+fn refuse_to_resign_data_after_certificate_table() -> Result<()> {
+    let out = tempdir()?;
+    let appended = out.path().join("appended.efi");
+    let mut file_data = fs::read(FIXTURE_UKI)?;
+    file_data.extend([0; 8]);
+    fs::write(&appended, file_data)?;
+
+    let signer = NativeSigner::new(&KeyPair::new(
+        Path::new("tests/fixtures/uefi-keys/db.pem"),
+        Path::new("tests/fixtures/uefi-keys/db.key"),
+    ))?;
+    let error = signer
+        .sign_and_copy(&appended, &out.path().join("signed.efi"))
+        .unwrap_err();
+    assert!(
+        format!("{error:#}").contains("is not located at the end of the PE binary"),
+        "{error:#}"
+    );
+    Ok(())
+}
+
+#[test]
+fn dual_sign_binary() -> Result<()> {
+    let out = tempdir()?;
+    let dual_signed = out.path().join("dual-signed.efi");
+    let db_signed = out.path().join("db-signed.efi");
+    let signer = MultiSigner::new(
+        Box::new(NativeSigner::new(&key_pair("uefi-keys/db"))?),
+        vec![Box::new(NativeSigner::new(&key_pair("pki/good"))?)],
+    );
+
+    // Signing twice replaces both signatures instead of adding two more.
+    signer.sign_and_copy(Path::new(FIXTURE_UKI), &dual_signed)?;
+    signer.sign_and_copy(&dual_signed, &dual_signed)?;
+    NativeSigner::new(&key_pair("uefi-keys/db"))?
+        .sign_and_copy(Path::new(FIXTURE_UKI), &db_signed)?;
+
+    let file_data = fs::read(&dual_signed)?;
+    let table = certificates(&file_data)?;
+    assert_eq!(table.len(), 2);
+    let digest = authenticode_digest(&file_data)?;
+    assert_eq!(format!("{digest:x}"), FIXTURE_UKI_DIGEST);
+
+    // Each signature is valid on its own, so firmware that trusts only one of the certificates
+    // accepts the binary.
+    verify_certificate(
+        table[0],
+        Path::new("tests/fixtures/uefi-keys/db.pem"),
+        &digest,
+    )?;
+    verify_certificate(table[1], Path::new("tests/fixtures/pki/good.pem"), &digest)?;
+    assert!(verify_certificate(
+        table[1],
+        Path::new("tests/fixtures/uefi-keys/db.pem"),
+        &digest
+    )
+    .is_err());
+
+    // The second signature is appended to the first one.
+    let db_signed = fs::read(&db_signed)?;
+    assert_eq!(table[0], certificates(&db_signed)?[0]);
+    assert!(file_data.ends_with(table[1]));
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+use lanzaboote_tool::pe::read_section_data;
+
+mod common;
+
+use common::{systemd_location_from_env, verify_signature, SYSTEM};
+
+const ADDON_SBAT: &str = "addon.nixos,1,NixOS,addon,1,https://nixos.org/\n";
+
+fn build_addon(systemd: &Path, cmdline: &str, extra_args: &[&str], out: &Path) -> Result<bool> {
+    let output = Command::cargo_bin("lzbt-systemd")?
+        .args(["build-addon", "--system", SYSTEM, "--systemd"])
+        .arg(systemd)
+        .args(["--cmdline", cmdline])
+        .args(["--public-key", "tests/fixtures/uefi-keys/db.pem"])
+        .args(["--private-key", "tests/fixtures/uefi-keys/db.key"])
+        .arg("--out")
+        .arg(out)
+        .args(extra_args)
+        .output()?;
+    print!("{}", String::from_utf8(output.stderr)?);
+    Ok(output.status.success())
+}
+
+#[test]
+fn build_signed_cmdline_addon() -> Result<()> {
+    let dir = tempdir()?;
+    let sbat = dir.path().join("sbat.csv");
+    fs::write(&sbat, ADDON_SBAT)?;
+    let addon = dir.path().join("debug.addon.efi");
+
+    assert!(build_addon(
+        Path::new(&systemd_location_from_env()?),
+        "loglevel=7 systemd.log_level=debug",
+        &["--sbat", sbat.to_str().unwrap()],
+        &addon
+    )?);
+    assert!(verify_signature(&addon)?);
+
+    let data = fs::read(&addon)?;
+    assert_eq!(
+        read_section_data(&data, ".cmdline"),
+        Some(&b"loglevel=7 systemd.log_level=debug"[..])
+    );
+    assert_eq!(read_section_data(&data, ".linux"), None);
+    let sbat = String::from_utf8_lossy(read_section_data(&data, ".sbat").expect("Missing .sbat"))
+        .into_owned();
+    assert!(sbat.starts_with("sbat,1,"));
+    assert!(sbat.ends_with(ADDON_SBAT));
+    Ok(())
+}
+
+#[test]
+fn refuse_control_characters_in_addon_cmdline() -> Result<()> {
+    let dir = tempdir()?;
+    let addon = dir.path().join("evil.addon.efi");
+
+    // The command line is checked before the addon stub is read.
+    assert!(!build_addon(
+        Path::new("/nonexistent"),
+        "quiet\ninit=/bin/sh",
+        &[],
+        &addon
+    )?);
+    assert!(!addon.exists());
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+/// All files below `dir`, relative to it.
+fn files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in walkdir::WalkDir::new(dir) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            files.push(entry.path().strip_prefix(dir)?.to_path_buf());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn export_bundle(esp: &Path, bundle: &Path) -> Result<()> {
+    let output = Command::cargo_bin("lzbt-systemd")?
+        .arg("export-bundle")
+        .arg("--system")
+        .arg("x86_64-linux")
+        .arg("--output")
+        .arg(bundle)
+        .arg(esp)
+        .output()?;
+    print!("{}", String::from_utf8(output.stderr)?);
+    assert!(output.status.success());
+    Ok(())
+}
+
+fn apply_bundle(bundle: &Path, esp: &Path, certificate: &str) -> Result<std::process::Output> {
+    Ok(Command::cargo_bin("lzbt-systemd")?
+        .arg("apply-bundle")
+        .arg("--public-key")
+        .arg(certificate)
+        .arg(bundle)
+        .arg(esp)
+        .output()?)
+}
+
+#[test]
+fn export_and_apply_bundle() -> Result<()> {
+    let staging = tempdir()?;
+    let esp = tempdir()?;
+    let bundles = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links = [
+        common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?,
+        common::setup_generation_link(tmpdir.path(), profiles.path(), 2)?,
+    ];
+    let output = common::lanzaboote_install(0, staging.path(), generation_links)?;
+    assert!(output.status.success());
+
+    let bundle = bundles.path().join("esp.bundle");
+    export_bundle(staging.path(), &bundle)?;
+
+    // A UKI of an old generation is replaced, other distributions are left alone.
+    let linux = esp.path().join("EFI/Linux");
+    fs::create_dir_all(&linux)?;
+    fs::write(linux.join("nixos-generation-99-old.efi"), "old")?;
+    fs::write(linux.join("other-distro.efi"), "other")?;
+
+    let output = apply_bundle(&bundle, esp.path(), "tests/fixtures/uefi-keys/db.pem")?;
+    print!("{}", String::from_utf8(output.stderr)?);
+    assert!(output.status.success());
+
+    let mut expected = files(staging.path())?;
+    expected.push(PathBuf::from("EFI/Linux/other-distro.efi"));
+    expected.sort();
+    assert_eq!(files(esp.path())?, expected);
+    for file in files(staging.path())? {
+        assert_eq!(
+            fs::read(esp.path().join(&file))?,
+            fs::read(staging.path().join(&file))?,
+            "{file:?} differs"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn reject_bundle_signed_with_other_key() -> Result<()> {
+    let staging = tempdir()?;
+    let esp = tempdir()?;
+    let bundles = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+    let output = common::lanzaboote_install(0, staging.path(), [generation_link])?;
+    assert!(output.status.success());
+
+    let bundle = bundles.path().join("esp.bundle");
+    export_bundle(staging.path(), &bundle)?;
+
+    let output = apply_bundle(&bundle, esp.path(), "tests/fixtures/pki/good.pem")?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(stderr.contains("is not signed with"));
+    // Nothing is installed and the staging directory is removed.
+    assert_eq!(common::count_files(esp.path())?, 0);
+
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+/// Paths of the files in `dir`, sorted.
+fn files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    files.sort();
+    Ok(files)
+}
+
+/// The installed initrd on the ESP.
+fn installed_initrd(esp: &Path) -> Result<Vec<u8>> {
+    let initrds = files(&esp.join("EFI/nixos"))?
+        .into_iter()
+        .filter(|path| {
+            path.file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with("initrd-")
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(initrds.len(), 1);
+    Ok(fs::read(&initrds[0])?)
+}
+
+#[test]
+fn reuse_compressed_initrd_from_cache() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+    let cache = tempdir()?;
+    let cache_dir = cache.path().join("cache");
+    let args = [
+        "--initrd-compression",
+        "zstd",
+        "--cache-dir",
+        cache_dir.to_str().unwrap(),
+        "--cache-compression",
+        "gzip",
+    ];
+
+    let esp = tempdir()?;
+    let output = common::lanzaboote_install_with_args(0, esp.path(), [&generation_link], args)?;
+    assert!(output.status.success());
+
+    let entries = files(&cache_dir)?;
+    assert_eq!(entries.len(), 1);
+    let entry = entries[0]
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .to_string();
+    assert!(entry.starts_with("initrd-zstd-"), "{entry}");
+    assert!(entry.ends_with(".gzip"), "{entry}");
+    // The entry is compressed for the cache on top of the compression of the initrd.
+    assert_eq!(fs::read(&entries[0])?[..2], [0x1f, 0x8b]);
+
+    // A second installation gets the same initrd out of the cache.
+    let other_esp = tempdir()?;
+    let output =
+        common::lanzaboote_install_with_args(0, other_esp.path(), [&generation_link], args)?;
+    assert!(output.status.success());
+    assert_eq!(files(&cache_dir)?, entries);
+    assert_eq!(
+        installed_initrd(other_esp.path())?,
+        installed_initrd(esp.path())?
+    );
+
+    // The initrd is taken from the cache instead of being compressed again.
+    let none_entry = entries[0].with_extension("none");
+    fs::write(&none_entry, b"cached initrd")?;
+    let args = [
+        "--initrd-compression",
+        "zstd",
+        "--cache-dir",
+        cache_dir.to_str().unwrap(),
+    ];
+    let cached_esp = tempdir()?;
+    let output =
+        common::lanzaboote_install_with_args(0, cached_esp.path(), [&generation_link], args)?;
+    assert!(output.status.success());
+    assert_eq!(installed_initrd(cached_esp.path())?, b"cached initrd");
+
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use std::process::Output;
+
+use anyhow::Result;
+use assert_cmd::Command;
+
+fn check_pki(public_key: &str, private_key: &str) -> Result<Output> {
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    let output = cmd
+        .arg("check-pki")
+        .arg("--public-key")
+        .arg(format!("tests/fixtures/pki/{public_key}"))
+        .arg("--private-key")
+        .arg(format!("tests/fixtures/pki/{private_key}"))
+        .output()?;
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+    Ok(output)
+}
+
+#[test]
+fn accept_valid_key_pair() -> Result<()> {
+    let output = check_pki("good.pem", "good.key")?;
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        "The key pair is valid.\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn reject_mismatched_key_pair() -> Result<()> {
+    let output = check_pki("good.pem", "other.key")?;
+
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        "The private key does not match the certificate.\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn reject_expired_certificate() -> Result<()> {
+    let output = check_pki("expired.pem", "expired.key")?;
+
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        "The certificate has expired.\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn reject_certificate_without_code_signing_usage() -> Result<()> {
+    let output = check_pki("server.pem", "good.key")?;
+
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        "The extended key usage of the certificate does not include code signing.\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn fail_on_unreadable_key() -> Result<()> {
+    let output = check_pki("good.pem", "missing.key")?;
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("Failed to read private key"));
+
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+/// Permits every parameter of the command line of the test generations.
+const ALLOWLIST: &str = "\
+# The init of the test generations is not in the Nix store.
+init=init-v*
+amd_iommu
+iommu=pt
+kvm.ignore_msrs=1
+kvm.report_ignored_msrs=0
+udev.log_priority=3
+systemd.unified_cgroup_hierarchy=1
+loglevel=4
+";
+
+#[test]
+fn install_permitted_cmdline() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+    let allowlist = tmpdir.path().join("allowlist");
+    fs::write(&allowlist, ALLOWLIST)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--cmdline-allowlist".as_ref(), allowlist.as_os_str()],
+    )?;
+    assert!(output.status.success());
+    assert_eq!(common::count_files(&esp.path().join("EFI/Linux"))?, 1);
+
+    Ok(())
+}
+
+#[test]
+fn refuse_disallowed_parameter() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+    let allowlist = tmpdir.path().join("allowlist");
+    fs::write(&allowlist, ALLOWLIST)?;
+
+    // The parameters of a command line variant are checked as well.
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        [
+            "--cmdline-allowlist".as_ref(),
+            allowlist.as_os_str(),
+            "--cmdline-variant".as_ref(),
+            "Rescue=loglevel=4 init=/bin/sh".as_ref(),
+        ],
+    )?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(
+        stderr.contains("not in the allowlist: init=/bin/sh"),
+        "{stderr}"
+    );
+    assert!(!esp.path().join("EFI/Linux").exists());
+
+    Ok(())
+}
@@ -143,6 +143,179 @@ pub fn lanzaboote_install(
     config_limit: u64,
     esp_mountpoint: &Path,
     generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    lanzaboote_install_with_args(
+        config_limit,
+        esp_mountpoint,
+        generation_links,
+        Vec::<&OsStr>::new(),
+    )
+}
+
+/// Call the `lanzaboote install` command with additional arguments.
+///
+/// The additional arguments are passed to the install command before the positional arguments.
+pub fn lanzaboote_install_with_args(
+    config_limit: u64,
+    esp_mountpoint: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    extra_args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    run_lanzaboote_install(config_limit, esp_mountpoint, generation_links, |cmd| {
+        cmd.arg("--private-key")
+            .arg("tests/fixtures/uefi-keys/db.key")
+            .args(extra_args);
+    })
+}
+
+/// Call the `lanzaboote reinit` command.
+pub fn lanzaboote_reinit(
+    config_limit: u64,
+    esp_mountpoint: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    run_lanzaboote(
+        "reinit",
+        config_limit,
+        esp_mountpoint,
+        PassEsp::Flag,
+        generation_links,
+        |cmd| {
+            cmd.arg("--private-key")
+                .arg("tests/fixtures/uefi-keys/db.key");
+        },
+    )
+}
+
+/// Call the `lanzaboote reinit` command with `path` as PATH, e.g. to provide a failing sbsign.
+pub fn lanzaboote_reinit_with_path(
+    config_limit: u64,
+    esp_mountpoint: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    path: &OsStr,
+) -> Result<Output> {
+    run_lanzaboote(
+        "reinit",
+        config_limit,
+        esp_mountpoint,
+        PassEsp::Flag,
+        generation_links,
+        |cmd| {
+            cmd.env("PATH", path)
+                .arg("--private-key")
+                .arg("tests/fixtures/uefi-keys/db.key");
+        },
+    )
+}
+
+/// Call the `lanzaboote install` command with additional arguments and `path` as PATH, e.g. to
+/// provide a wrapper around sbsign.
+pub fn lanzaboote_install_with_path(
+    config_limit: u64,
+    esp_mountpoint: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    path: &OsStr,
+    extra_args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    run_lanzaboote_install(config_limit, esp_mountpoint, generation_links, |cmd| {
+        cmd.env("PATH", path)
+            .arg("--private-key")
+            .arg("tests/fixtures/uefi-keys/db.key")
+            .args(extra_args);
+    })
+}
+
+/// Call the `lanzaboote install` command with a private key in an OpenSSL engine.
+///
+/// `path` is used as PATH, e.g. to provide an sbsign that supports the engine.
+pub fn lanzaboote_install_with_engine(
+    config_limit: u64,
+    esp_mountpoint: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    engine: &str,
+    key_id: &str,
+    path: &OsStr,
+) -> Result<Output> {
+    run_lanzaboote_install(config_limit, esp_mountpoint, generation_links, |cmd| {
+        cmd.env("PATH", path)
+            .arg("--engine")
+            .arg(engine)
+            .arg("--key-id")
+            .arg(key_id);
+    })
+}
+
+/// Call the `lanzaboote install` command with the private key on the remote signing service at
+/// `url`.
+pub fn lanzaboote_install_with_remote_signer(
+    config_limit: u64,
+    esp_mountpoint: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    url: &str,
+    token: &str,
+) -> Result<Output> {
+    run_lanzaboote_install(config_limit, esp_mountpoint, generation_links, |cmd| {
+        cmd.env("LZBT_SIGN_TOKEN", token).arg("--sign-url").arg(url);
+    })
+}
+
+/// Run the `lanzaboote install` command with the test certificate.
+///
+/// `configure` adds the private key and any other arguments.
+fn run_lanzaboote_install(
+    config_limit: u64,
+    esp_mountpoint: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    configure: impl FnOnce(&mut Command),
+) -> Result<Output> {
+    run_lanzaboote(
+        "install",
+        config_limit,
+        esp_mountpoint,
+        PassEsp::Flag,
+        generation_links,
+        configure,
+    )
+}
+
+/// Call the `lanzaboote install` command with the ESP mountpoint in LANZABOOTE_ESP instead of
+/// `--esp`.
+pub fn lanzaboote_install_with_esp_from_env(
+    config_limit: u64,
+    esp_mountpoint: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    run_lanzaboote(
+        "install",
+        config_limit,
+        esp_mountpoint,
+        PassEsp::Env,
+        generation_links,
+        |cmd| {
+            cmd.arg("--private-key")
+                .arg("tests/fixtures/uefi-keys/db.key");
+        },
+    )
+}
+
+/// How the ESP mountpoint is passed to lzbt.
+#[derive(Clone, Copy)]
+enum PassEsp {
+    /// With `--esp`.
+    Flag,
+    /// In the LANZABOOTE_ESP environment variable.
+    Env,
+}
+
+/// Run `subcommand`, which takes the arguments of `lanzaboote install`, with the test
+/// certificate.
+fn run_lanzaboote(
+    subcommand: &str,
+    config_limit: u64,
+    esp_mountpoint: &Path,
+    pass_esp: PassEsp,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    configure: impl FnOnce(&mut Command),
 ) -> Result<Output> {
     // To simplify the test setup, we use the systemd stub here instead of the lanzaboote stub. See
     // the comment in setup_toplevel for details.
@@ -159,10 +332,9 @@ pub fn lanzaboote_install(
     fs::write(test_loader_config_path.path(), test_loader_config)?;
 
     let mut cmd = Command::cargo_bin("lzbt-systemd")?;
-    let output = cmd
-        .env("LANZABOOTE_STUB", test_systemd_stub)
+    cmd.env("LANZABOOTE_STUB", test_systemd_stub)
         .arg("-vv")
-        .arg("install")
+        .arg(subcommand)
         .arg("--system")
         .arg(SYSTEM)
         .arg("--systemd")
@@ -171,13 +343,14 @@ pub fn lanzaboote_install(
         .arg(test_loader_config_path.path())
         .arg("--public-key")
         .arg("tests/fixtures/uefi-keys/db.pem")
-        .arg("--private-key")
-        .arg("tests/fixtures/uefi-keys/db.key")
         .arg("--configuration-limit")
-        .arg(config_limit.to_string())
-        .arg(esp_mountpoint)
-        .args(generation_links)
-        .output()?;
+        .arg(config_limit.to_string());
+    configure(&mut cmd);
+    match pass_esp {
+        PassEsp::Flag => cmd.arg("--esp").arg(esp_mountpoint),
+        PassEsp::Env => cmd.env("LANZABOOTE_ESP", esp_mountpoint),
+    };
+    let output = cmd.args(generation_links).output()?;
 
     // Print debugging output.
     // This is a weird hack to make cargo test capture the output.
@@ -193,8 +366,16 @@ pub fn lanzaboote_install(
     Ok(output)
 }
 
+/// Path of the systemd-boot binary that is installed by lanzaboote in the tests.
+pub fn systemd_boot_binary() -> Result<PathBuf> {
+    let system = Architecture::from_nixos_system(SYSTEM)?;
+    Ok(PathBuf::from(systemd_location_from_env()?)
+        .join("lib/systemd/boot/efi")
+        .join(system.systemd_filename()))
+}
+
 /// Read location of systemd installation from an environment variable.
-fn systemd_location_from_env() -> Result<String> {
+pub fn systemd_location_from_env() -> Result<String> {
     let error_msg = "TEST_SYSTEMD environment variable is not set. TEST_SYSTEMD has to point to a systemd installation.
 On a system with Nix installed, you can set it with: export TEST_SYSTEMD=$(nix-build '<nixpkgs>' -A systemd)";
     std::env::var("TEST_SYSTEMD").context(error_msg)
@@ -0,0 +1,48 @@
+use std::fs;
+
+use anyhow::{Context, Result};
+use tempfile::tempdir;
+
+use lanzaboote_tool::pe::read_section_data;
+
+mod common;
+
+#[test]
+fn embed_common_cmdline_in_every_generation() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links = [
+        common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?,
+        common::setup_generation_link(tmpdir.path(), profiles.path(), 2)?,
+    ];
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        &generation_links,
+        ["--common-cmdline", "quiet iommu=off console=ttyS0,115200"],
+    )?;
+    assert!(output.status.success());
+
+    let stubs = fs::read_dir(esp.path().join("EFI/Linux"))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(stubs.len(), 2);
+    for stub in stubs {
+        let data = fs::read(&stub)?;
+        let cmdline = read_section_data(&data, ".cmdline").context("Missing .cmdline")?;
+        let cmdline = std::str::from_utf8(cmdline)?;
+        // iommu=pt of the generations takes precedence.
+        assert!(
+            cmdline.ends_with(
+                " iommu=pt kvm.ignore_msrs=1 kvm.report_ignored_msrs=0 udev.log_priority=3 \
+                 systemd.unified_cgroup_hierarchy=1 loglevel=4 quiet console=ttyS0,115200"
+            ),
+            "{cmdline}"
+        );
+        assert!(!cmdline.contains("iommu=off"), "{cmdline}");
+    }
+
+    Ok(())
+}
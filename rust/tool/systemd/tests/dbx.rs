@@ -0,0 +1,77 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use lanzaboote_tool::pe::authenticode_digest;
+
+mod common;
+
+/// `EFI_CERT_SHA256_GUID` in its on-disk representation.
+const EFI_CERT_SHA256_GUID: [u8; 16] = [
+    0x26, 0x16, 0xc4, 0xc1, 0x4c, 0x50, 0x92, 0x40, 0xac, 0xa9, 0x41, 0xf9, 0x36, 0x93, 0x43, 0x28,
+];
+
+/// Write a dbx in efivarfs format that revokes the given SHA 256 hashes.
+fn write_dbx(path: &Path, hashes: &[&[u8]]) -> Result<()> {
+    let signature_size = 16 + 32;
+    let list_size = 28 + hashes.len() * signature_size;
+
+    // efivarfs prefixes the variable with its attributes.
+    let mut dbx = 0x27u32.to_le_bytes().to_vec();
+    dbx.extend_from_slice(&EFI_CERT_SHA256_GUID);
+    dbx.extend_from_slice(&(list_size as u32).to_le_bytes());
+    dbx.extend_from_slice(&0u32.to_le_bytes());
+    dbx.extend_from_slice(&(signature_size as u32).to_le_bytes());
+    for hash in hashes {
+        dbx.extend_from_slice(&[0; 16]);
+        dbx.extend_from_slice(hash);
+    }
+    fs::write(path, dbx)?;
+    Ok(())
+}
+
+#[test]
+fn refuse_to_install_revoked_systemd_boot() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let revoked = authenticode_digest(&fs::read(common::systemd_boot_binary()?)?)?;
+    let dbx = tmpdir.path().join("dbx");
+    write_dbx(&dbx, &[&[0xaa; 32], &revoked])?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![generation_link],
+        vec![Path::new("--dbx"), &dbx],
+    )?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("revoked by the dbx"));
+
+    Ok(())
+}
+
+#[test]
+fn install_binaries_not_in_dbx() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let dbx = tmpdir.path().join("dbx");
+    write_dbx(&dbx, &[&[0xaa; 32]])?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![generation_link],
+        vec![Path::new("--dbx"), &dbx],
+    )?;
+    assert!(output.status.success());
+
+    Ok(())
+}
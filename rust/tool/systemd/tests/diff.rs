@@ -0,0 +1,72 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+use lanzaboote_tool::pe::{read_section_data, set_cmdline};
+use lanzaboote_tool::signature::KeyPair;
+
+mod common;
+
+fn lanzaboote_diff(old: &Path, new: &Path) -> Result<String> {
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    let output = cmd.arg("diff").arg(old).arg(new).output()?;
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+    assert!(output.status.success());
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+#[test]
+fn show_changed_cmdline() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install(0, esp.path(), [generation_link])?;
+    assert!(output.status.success());
+    let old = fs::read_dir(esp.path().join("EFI/Linux"))?
+        .next()
+        .expect("No stub installed")?
+        .path();
+
+    let old_cmdline = String::from_utf8(
+        read_section_data(&fs::read(&old)?, ".cmdline")
+            .expect("Missing .cmdline")
+            .to_vec(),
+    )?;
+    let new_cmdline = old_cmdline.replace("loglevel=4", "loglevel=7 quiet");
+    let new = tmpdir.path().join("new.efi");
+    let key_pair = KeyPair::new(
+        Path::new("tests/fixtures/uefi-keys/db.pem"),
+        Path::new("tests/fixtures/uefi-keys/db.key"),
+    );
+    set_cmdline(&key_pair, &old, &new_cmdline, &new, None)?;
+
+    let diff = lanzaboote_diff(&old, &new)?;
+    let lines = diff.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 4, "Unexpected diff:\n{diff}");
+    assert!(lines[0].starts_with(&format!(
+        ".cmdline: changed ({} bytes, sha256 ",
+        old_cmdline.len()
+    )));
+    assert!(lines[0].contains(&format!(" -> {} bytes, sha256 ", new_cmdline.len())));
+    assert_eq!(
+        lines[1..],
+        ["  - loglevel=4", "  + loglevel=7", "  + quiet"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn report_identical_ukis() -> Result<()> {
+    let systemd_boot = common::systemd_boot_binary()?;
+
+    let diff = lanzaboote_diff(&systemd_boot, &systemd_boot)?;
+    assert_eq!(diff, "All sections are identical.\n");
+
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use tempfile::tempdir;
+
+mod common;
+
+use common::verify_signature;
+
+/// Install an sbsign wrapper into `bin` that stands in for an OpenSSL engine keeping its keys in
+/// software.
+///
+/// The wrapper only accepts signing requests for the `soft` engine. It appends its arguments as a
+/// line to `bin/sbsign.log`, resolves the key id to a key file in the fixtures and signs with the
+/// real sbsign.
+fn install_software_engine(bin: &Path) -> Result<()> {
+    let sbsign = env::split_paths(&env::var_os("PATH").unwrap_or_default())
+        .map(|dir| dir.join("sbsign"))
+        .find(|path| path.is_file())
+        .context("sbsign is not on PATH")?;
+    let keys = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/uefi-keys");
+
+    let wrapper = bin.join("sbsign");
+    let log = bin.join("sbsign.log");
+    fs::write(
+        &wrapper,
+        format!(
+            r#"#!/bin/sh
+echo "$@" >> {log}
+if [ "$1" != --engine ] || [ "$2" != soft ] || [ "$3" != --key ]; then
+    echo "sbsign was not asked to use the soft engine" >&2
+    exit 1
+fi
+key_id="$4"
+shift 4
+exec {sbsign} --key {keys}/"$key_id".key "$@"
+"#,
+            sbsign = sbsign.display(),
+            keys = keys.display(),
+            log = log.display(),
+        ),
+    )?;
+    fs::set_permissions(&wrapper, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[test]
+fn sign_with_engine_key() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let bin = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    install_software_engine(bin.path())?;
+    let path = env::join_paths(
+        [bin.path().to_path_buf()]
+            .into_iter()
+            .chain(env::split_paths(&env::var_os("PATH").unwrap_or_default())),
+    )?;
+
+    let output = common::lanzaboote_install_with_engine(
+        0,
+        esp.path(),
+        [generation_link],
+        "soft",
+        "db",
+        &path,
+    )?;
+    assert!(output.status.success());
+
+    let stubs = fs::read_dir(esp.path().join("EFI/Linux"))?.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(stubs.len(), 1);
+    assert!(verify_signature(&stubs[0].path())?);
+
+    // The key id and the certificate are passed on as they are, the key id is never used as a
+    // path.
+    let invocations = fs::read_to_string(bin.path().join("sbsign.log"))?;
+    assert!(!invocations.is_empty());
+    for invocation in invocations.lines() {
+        let args = invocation.split(' ').collect::<Vec<_>>();
+        assert_eq!(
+            args[..6],
+            [
+                "--engine",
+                "soft",
+                "--key",
+                "db",
+                "--cert",
+                "tests/fixtures/uefi-keys/db.pem"
+            ],
+            "{invocation}"
+        );
+        assert_eq!(args.len(), 9, "{invocation}");
+        assert_eq!(args[7], "--output", "{invocation}");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn export_file_set() -> Result<()> {
+    let esp = tempdir()?;
+    let export_esp = tempdir()?;
+    let export = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links = [
+        common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?,
+        common::setup_generation_link(tmpdir.path(), profiles.path(), 2)?,
+    ];
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        export_esp.path(),
+        &generation_links,
+        ["--export".as_ref(), export.path().as_os_str()],
+    )?;
+    assert!(output.status.success());
+    // Nothing is installed.
+    assert_eq!(common::count_files(export_esp.path())?, 0);
+
+    // The UKIs are the ones an actual installation places on the ESP.
+    let output = common::lanzaboote_install(0, esp.path(), &generation_links)?;
+    assert!(output.status.success());
+    let mut stubs = fs::read_dir(esp.path().join("EFI/Linux"))?
+        .map(|entry| Ok(entry?.file_name().into_string().unwrap()))
+        .collect::<Result<Vec<_>>>()?;
+    stubs.sort();
+    assert_eq!(stubs.len(), 2);
+
+    let ukis = stubs
+        .iter()
+        .map(|stub| format!("EFI/Linux/{stub}\n"))
+        .collect::<String>();
+    assert_eq!(fs::read_to_string(export.path().join("ukis"))?, ukis);
+
+    for (version, stub) in [1, 2].into_iter().zip(&stubs) {
+        let expected = format!(
+            "title LanzaOS (Generation {version}, 1970-01-01)\n\
+             version Generation {version}, 1970-01-01\n\
+             efi /EFI/Linux/{stub}\n\
+             options init=init-v{version} amd_iommu=on amd_iommu=pt iommu=pt kvm.ignore_msrs=1 \
+             kvm.report_ignored_msrs=0 udev.log_priority=3 systemd.unified_cgroup_hierarchy=1 \
+             loglevel=4\n"
+        );
+        let entry = export
+            .path()
+            .join("entries")
+            .join(stub.replace(".efi", ".conf"));
+        assert_eq!(fs::read_to_string(entry)?, expected);
+    }
+
+    assert_eq!(
+        fs::read(export.path().join("loader/loader.conf"))?,
+        fs::read(esp.path().join("loader/loader.conf"))?
+    );
+    // Two entries, the list of UKIs and loader.conf.
+    let files = walkdir::WalkDir::new(export.path())
+        .into_iter()
+        .filter(|entry| entry.as_ref().is_ok_and(|e| e.file_type().is_file()))
+        .count();
+    assert_eq!(files, 4);
+
+    Ok(())
+}
@@ -1,13 +1,27 @@
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
 use tempfile::tempdir;
+use walkdir::WalkDir;
 
 mod common;
 
 use common::count_files;
 
+/// The size of every file on the ESP.
+fn file_sizes(esp: &Path) -> Result<BTreeMap<PathBuf, u64>> {
+    let mut sizes = BTreeMap::new();
+    for entry in WalkDir::new(esp) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            sizes.insert(entry.path().to_path_buf(), entry.metadata()?.len());
+        }
+    }
+    Ok(sizes)
+}
+
 #[test]
 fn keep_only_configured_number_of_generations() -> Result<()> {
     let esp_mountpoint = tempdir()?;
@@ -124,3 +138,50 @@ fn keep_unrelated_files_on_esp() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn report_reclaimed_space() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2, 3]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), generation_links.clone())?;
+    assert!(output0.status.success());
+    fs::write(
+        esp_mountpoint.path().join("EFI/nixos/kernel-garbage.efi"),
+        [0; 1234],
+    )?;
+    fs::create_dir(esp_mountpoint.path().join("EFI/nixos/garbage"))?;
+    fs::write(
+        esp_mountpoint.path().join("EFI/nixos/garbage/initrd.efi"),
+        [0; 567],
+    )?;
+    let before = file_sizes(esp_mountpoint.path())?;
+
+    // The stub of generation 1 and the garbage files are removed.
+    let output1 = common::lanzaboote_install(2, esp_mountpoint.path(), generation_links)?;
+    assert!(output1.status.success());
+    let after = file_sizes(esp_mountpoint.path())?;
+    let removed = before
+        .iter()
+        .filter(|(path, _)| !after.contains_key(*path))
+        .collect::<Vec<_>>();
+    assert_eq!(removed.len(), 3, "{removed:?}");
+    let reclaimed: u64 = removed.iter().map(|(_, size)| **size).sum();
+
+    let stderr = String::from_utf8(output1.stderr)?;
+    assert!(
+        stderr.contains(&format!("Reclaimed {reclaimed} bytes on the ESP, ")),
+        "{stderr}"
+    );
+    assert!(stderr.contains(" bytes are free."), "{stderr}");
+
+    Ok(())
+}
@@ -0,0 +1,118 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use filetime::FileTime;
+use tempfile::tempdir;
+
+mod common;
+
+/// The stubs in `EFI/Linux`, sorted by name.
+fn stubs(esp: &Path) -> Result<Vec<PathBuf>> {
+    let mut stubs = fs::read_dir(esp.join("EFI/Linux"))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    stubs.sort();
+    Ok(stubs)
+}
+
+/// Mark all stubs as written at the epoch, so that rewritten stubs stand out.
+fn reset_mtimes(stubs: &[PathBuf]) -> Result<()> {
+    for stub in stubs {
+        filetime::set_file_mtime(stub, FileTime::zero())?;
+    }
+    Ok(())
+}
+
+#[test]
+fn only_write_new_generation() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let mut generation_links = vec![
+        common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?,
+        common::setup_generation_link(tmpdir.path(), profiles.path(), 2)?,
+    ];
+
+    let output =
+        common::lanzaboote_install_with_args(0, esp.path(), &generation_links, ["--incremental"])?;
+    assert!(output.status.success());
+    let baseline = stubs(esp.path())?;
+    assert_eq!(baseline.len(), 2);
+    assert!(esp.path().join("EFI/nixos/lanzaboote-state.json").exists());
+    reset_mtimes(&baseline)?;
+
+    generation_links.push(common::setup_generation_link(
+        tmpdir.path(),
+        profiles.path(),
+        3,
+    )?);
+    let output =
+        common::lanzaboote_install_with_args(0, esp.path(), &generation_links, ["--incremental"])?;
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(
+        stderr.contains("2 of 3 UKIs did not change since the last installation."),
+        "{stderr}"
+    );
+
+    // Exactly one new UKI was written, the others are untouched.
+    let stubs = stubs(esp.path())?;
+    assert_eq!(stubs.len(), 3);
+    let written = stubs
+        .iter()
+        .filter(|stub| common::mtime(stub) != 0)
+        .collect::<Vec<_>>();
+    assert_eq!(written.len(), 1);
+    assert!(!baseline.contains(written[0]));
+    assert!(written[0]
+        .file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with("nixos-generation-3-")));
+
+    // The state survives garbage collection and records all three UKIs.
+    let state = fs::read_to_string(esp.path().join("EFI/nixos/lanzaboote-state.json"))?;
+    for stub in &stubs {
+        let name = stub.file_name().and_then(|n| n.to_str()).unwrap();
+        assert!(state.contains(name), "{name} is missing in {state}");
+    }
+    Ok(())
+}
+
+#[test]
+fn rewrite_changed_uki_and_remove_deleted_generation() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link1 = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+    let generation_link2 = common::setup_generation_link(tmpdir.path(), profiles.path(), 2)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link1, &generation_link2],
+        ["--incremental"],
+    )?;
+    assert!(output.status.success());
+    let baseline = stubs(esp.path())?;
+    reset_mtimes(&baseline)?;
+
+    // The UKI of generation 2 is modified on the ESP, generation 1 is deleted.
+    let mut stub = fs::read(&baseline[1])?;
+    stub.extend(b"modified");
+    fs::write(&baseline[1], &stub)?;
+    filetime::set_file_mtime(&baseline[1], FileTime::zero())?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link2],
+        ["--incremental"],
+    )?;
+    assert!(output.status.success());
+
+    assert_eq!(stubs(esp.path())?, [baseline[1].clone()]);
+    assert_ne!(common::mtime(&baseline[1]), 0);
+    assert!(!fs::read(&baseline[1])?.ends_with(b"modified"));
+    Ok(())
+}
@@ -1,5 +1,9 @@
+use std::ffi::OsStr;
+use std::os::unix::fs::PermissionsExt;
+
 use anyhow::Result;
 use base32ct::{Base32Unpadded, Encoding};
+use sha2::{Digest, Sha256};
 use tempfile::tempdir;
 
 mod common;
@@ -121,3 +125,938 @@ fn content_addressing_works() -> Result<()> {
 
     Ok(())
 }
+
+/// Install generations with large initrds and check that lzbt never holds one in memory.
+#[test]
+fn install_large_initrds_with_bounded_memory() -> Result<()> {
+    const INITRD_SIZE: u64 = 64 * 1024 * 1024;
+
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+
+    let mut generation_links = Vec::new();
+    for version in 1..=2 {
+        let toplevel = common::setup_toplevel(tmpdir.path())?;
+        let initrd = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/initrd");
+        let mut data = vec![0; usize::try_from(INITRD_SIZE)?];
+        data[0] = u8::try_from(version)?;
+        std::fs::write(&initrd, data)?;
+        generation_links.push(setup_generation_link_from_toplevel(
+            &toplevel,
+            profiles.path(),
+            version,
+        )?);
+    }
+
+    let output = common::lanzaboote_install(0, esp.path(), generation_links)?;
+    assert!(output.status.success());
+
+    // This covers every child of the test process that has finished, including the ones of
+    // other tests. None of them handles anything nearly as large as the initrds.
+    let max_rss =
+        nix::sys::resource::getrusage(nix::sys::resource::UsageWho::RUSAGE_CHILDREN)?.max_rss();
+    let max_rss_bytes = u64::try_from(max_rss)? * 1024;
+    assert!(
+        max_rss_bytes < INITRD_SIZE,
+        "lzbt used {max_rss_bytes} bytes of memory"
+    );
+
+    Ok(())
+}
+
+/// Install a large number of generations with distinct toplevels.
+///
+/// Every generation has to be processed on its own, so this must work no matter how many
+/// generations there are. Kernels and initrds that are shared between generations are still
+/// deduplicated.
+#[test]
+fn install_many_generations() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+
+    let generation_links = (1..=32)
+        .map(|version| common::setup_generation_link(tmpdir.path(), profiles.path(), version))
+        .collect::<Result<Vec<_>>>()?;
+
+    let output = common::lanzaboote_install(0, esp.path(), generation_links)?;
+    assert!(output.status.success());
+    assert_eq!(count_files(&esp.path().join("EFI/Linux"))?, 32);
+    assert_eq!(count_files(&esp.path().join("EFI/nixos"))?, 2);
+
+    Ok(())
+}
+
+/// Stub options that are embedded as a section of the stub: the flags, the section and its
+/// expected contents.
+const STUB_OPTIONS: &[(&[&str], &str, &[u8])] = &[
+    (&["--serial-console", "1"], ".serial", b"1"),
+    (&["--countdown", "5"], ".timeout", b"5"),
+    (
+        &["--skip-initrd-verification-without-secure-boot"],
+        ".initrdv",
+        b"secure-boot",
+    ),
+    (&["--strict-measurement"], ".measure", b"strict"),
+    (
+        &[
+            "--smbios-cmdline-prefix",
+            "io.systemd.stub.kernel-cmdline-extra=",
+        ],
+        ".smbios",
+        b"io.systemd.stub.kernel-cmdline-extra=",
+    ),
+    (&["--confirm-insecure-boot"], ".confirm", b"secure-boot-off"),
+    (&["--load-options-image-path"], ".loadopt", b"image-path"),
+    (&["--firmware-setup-key", "F2"], ".fwsetup", b"F2"),
+    (&["--strict-cmdline"], ".cmdlchk", b"strict"),
+    (&["--cmdline-addons"], ".addons", b"cmdline"),
+    (
+        &[
+            "--min-firmware-revision",
+            "65538",
+            "--min-firmware-vendor",
+            "EDK II",
+            "--refuse-old-firmware",
+        ],
+        ".fwmin",
+        b"revision=0x10002\nvendor=EDK II\naction=refuse\n",
+    ),
+    (&["--retry-initrd-verification"], ".initrdr", b"once"),
+    (&["--fallback-on-kernel-failure"], ".kfail", b"next-entry"),
+    (&["--tpm-nv-index", "0x1500016"], ".tpmnv", b"0x01500016"),
+    (&["--show-measurements"], ".pcrshow", b"show"),
+];
+
+#[test]
+fn embed_stub_options() -> Result<()> {
+    for (args, section, expected) in STUB_OPTIONS {
+        let esp = tempdir()?;
+        let tmpdir = tempdir()?;
+        let profiles = tempdir()?;
+        let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+        let output = common::lanzaboote_install_with_args(0, esp.path(), [generation_link], *args)?;
+        assert!(output.status.success(), "{args:?}");
+
+        let stubs =
+            std::fs::read_dir(esp.path().join("EFI/Linux"))?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(stubs.len(), 1);
+        let stub = std::fs::read(stubs[0].path())?;
+        assert_eq!(
+            lanzaboote_tool::pe::read_section_data(&stub, section),
+            Some(*expected),
+            "{args:?}"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn embed_initrd_file() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+    let initrd_file = esp.path().join("EFI/extra/initrd");
+    std::fs::create_dir_all(initrd_file.parent().unwrap())?;
+
+    for contents in ["a large initrd", "a modified initrd"] {
+        std::fs::write(&initrd_file, contents)?;
+        let output = common::lanzaboote_install_with_args(
+            0,
+            esp.path(),
+            [&generation_link],
+            [OsStr::new("--initrd-file"), initrd_file.as_os_str()],
+        )?;
+        assert!(output.status.success());
+
+        // A changed file is a changed UKI, the old one is collected.
+        let stubs =
+            std::fs::read_dir(esp.path().join("EFI/Linux"))?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(stubs.len(), 1);
+        let stub = std::fs::read(stubs[0].path())?;
+        let hash = Sha256::digest(contents)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        assert_eq!(
+            lanzaboote_tool::pe::read_section_data(&stub, ".irdpath"),
+            Some(format!("\\EFI\\extra\\initrd\n{hash}").as_bytes())
+        );
+    }
+    assert!(initrd_file.exists());
+
+    Ok(())
+}
+
+/// Without `--esp`, the ESP is read from LANZABOOTE_ESP and all positional arguments are
+/// generations.
+#[test]
+fn install_with_esp_from_env() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links = (1..=2)
+        .map(|version| common::setup_generation_link(tmpdir.path(), profiles.path(), version))
+        .collect::<Result<Vec<_>>>()?;
+
+    let output = common::lanzaboote_install_with_esp_from_env(0, esp.path(), generation_links)?;
+    assert!(output.status.success());
+    assert_eq!(count_files(&esp.path().join("EFI/Linux"))?, 2);
+
+    Ok(())
+}
+
+#[test]
+fn set_default_header_fields() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install(0, esp.path(), vec![generation_link])?;
+    assert!(output.status.success());
+
+    let stubs = std::fs::read_dir(esp.path().join("EFI/Linux"))?.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(stubs.len(), 1);
+    let stub = std::fs::read(stubs[0].path())?;
+    let pe = goblin::pe::PE::parse(&stub)?;
+    let optional_header = pe.header.optional_header.unwrap();
+
+    // The kernel of the mock toplevel is a copy of the systemd stub used in the tests.
+    let original_stub =
+        std::fs::read(toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/kernel"))?;
+    let original_pe = goblin::pe::PE::parse(&original_stub)?;
+    let original_optional_header = original_pe.header.optional_header.unwrap();
+
+    // The systemd stub used in the tests has base relocations, so all default bits are set. Bits
+    // that the stub sets on its own are kept.
+    let defaults = lanzaboote_tool::pe::PeHeaderFields::default();
+    assert_eq!(optional_header.windows_fields.subsystem, defaults.subsystem);
+    assert_eq!(
+        optional_header.windows_fields.dll_characteristics,
+        original_optional_header.windows_fields.dll_characteristics | defaults.dll_characteristics
+    );
+    assert_eq!(
+        pe.header.coff_header.characteristics,
+        original_pe.header.coff_header.characteristics | defaults.characteristics
+    );
+
+    Ok(())
+}
+
+#[test]
+fn dual_sign_with_additional_key() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        [
+            "--signer",
+            "native",
+            "--additional-key",
+            "tests/fixtures/pki/good.key",
+            "--additional-cert",
+            "tests/fixtures/pki/good.pem",
+        ],
+    )?;
+    assert!(output.status.success());
+
+    let stubs = std::fs::read_dir(esp.path().join("EFI/Linux"))?.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(stubs.len(), 1);
+    let stub = std::fs::read(stubs[0].path())?;
+    let pe = goblin::pe::PE::parse(&stub)?;
+    let table = pe
+        .header
+        .optional_header
+        .and_then(|h| *h.data_directories.get_certificate_table())
+        .expect("No certificate table");
+    let start = usize::try_from(table.virtual_address)?;
+    let first_length = u32::from_le_bytes(stub[start..start + 4].try_into()?);
+    // A second WIN_CERTIFICATE follows the first one.
+    assert!(table.size > first_length);
+
+    Ok(())
+}
+
+#[test]
+fn reject_additional_key_without_cert() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--additional-key", "tests/fixtures/pki/good.key"],
+    )?;
+    assert!(!output.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn reject_unknown_firmware_setup_key() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--firmware-setup-key", "F13"],
+    )?;
+    assert!(!output.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn embed_chainload_path() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--chainload-systemd-boot"],
+    )?;
+    assert!(output.status.success());
+
+    let stubs = std::fs::read_dir(esp.path().join("EFI/Linux"))?.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(stubs.len(), 1);
+    let stub = std::fs::read(stubs[0].path())?;
+    assert_eq!(
+        lanzaboote_tool::pe::read_section_data(&stub, ".chain"),
+        Some(&b"\\EFI\\systemd\\systemd-bootx64.efi"[..])
+    );
+    assert!(esp.path().join("EFI/systemd/systemd-bootx64.efi").exists());
+
+    Ok(())
+}
+
+#[test]
+fn embed_deploy_info() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--deploy-slot", "b", "--deploy-version", "24.05.1"],
+    )?;
+    assert!(output.status.success());
+
+    let stubs = std::fs::read_dir(esp.path().join("EFI/Linux"))?.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(stubs.len(), 1);
+    let stub = std::fs::read(stubs[0].path())?;
+    let deploy: serde_json::Value = serde_json::from_slice(
+        lanzaboote_tool::pe::read_section_data(&stub, ".deploy").expect("Missing .deploy section"),
+    )?;
+    assert_eq!(deploy["slot"], "b");
+    assert_eq!(deploy["version"], "24.05.1");
+
+    Ok(())
+}
+
+#[test]
+fn reject_deploy_slot_without_version() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--deploy-slot", "a"],
+    )?;
+    assert!(!output.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn reject_firmware_vendor_without_revision() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--min-firmware-vendor", "EDK II"],
+    )?;
+    assert!(!output.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn embed_cmdline_variants() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        [
+            "--cmdline-variant",
+            "Debug=loglevel=7",
+            "--cmdline-variant",
+            "Single user=single",
+        ],
+    )?;
+    assert!(output.status.success());
+
+    let stubs = std::fs::read_dir(esp.path().join("EFI/Linux"))?.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(stubs.len(), 1);
+    let stub = std::fs::read(stubs[0].path())?;
+    let section = |name| {
+        String::from_utf8(
+            lanzaboote_tool::pe::read_section_data(&stub, name)
+                .unwrap_or_else(|| panic!("Missing {name} section"))
+                .to_vec(),
+        )
+        .unwrap()
+    };
+    let cmdline = section(".cmdline");
+    assert_eq!(section(".cmdlbl"), "Default\nDebug\nSingle user");
+    assert_eq!(section(".cmdl1"), format!("{cmdline} loglevel=7"));
+    assert_eq!(section(".cmdl2"), format!("{cmdline} single"));
+    assert_eq!(
+        lanzaboote_tool::pe::read_section_data(&stub, ".cmdl3"),
+        None
+    );
+
+    Ok(())
+}
+
+#[test]
+fn reject_invalid_serial_console() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--serial-console", "ttyS0"],
+    )?;
+    assert!(!output.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn skip_excluded_generations() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevels = (0..4)
+        .map(|_| common::setup_toplevel(tmpdir.path()))
+        .collect::<Result<Vec<_>>>()?;
+    let generation_links = (1..=4)
+        .map(|version| {
+            setup_generation_link_from_toplevel(
+                &toplevels[version - 1],
+                profiles.path(),
+                version as u64,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let images = (1..=4)
+        .map(|version| common::image_path(&esp, version as u64, &toplevels[version - 1]))
+        .collect::<Result<Vec<_>>>()?;
+
+    let output1 = common::lanzaboote_install(0, esp.path(), generation_links.clone())?;
+    assert!(output1.status.success());
+    assert!(images.iter().all(|image| image.exists()));
+
+    // Excluded generations do not count towards the configuration limit.
+    let output2 = common::lanzaboote_install_with_args(
+        2,
+        esp.path(),
+        generation_links,
+        ["--exclude", "2,4"],
+    )?;
+    assert!(output2.status.success());
+    assert!(images[0].exists());
+    assert!(!images[1].exists());
+    assert!(images[2].exists());
+    assert!(!images[3].exists());
+
+    Ok(())
+}
+
+#[test]
+fn compress_initrd() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--initrd-compression", "zstd"],
+    )?;
+    assert!(output.status.success());
+
+    let initrds = std::fs::read_dir(esp.path().join("EFI/nixos"))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter(|path| {
+            path.file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with("initrd-")
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(initrds.len(), 1);
+    let initrd = std::fs::read(&initrds[0])?;
+    assert_eq!(initrd[..4], [0x28, 0xb5, 0x2f, 0xfd]);
+
+    // The stub verifies the compressed initrd as it is, the kernel unpacks it.
+    let stubs = std::fs::read_dir(esp.path().join("EFI/Linux"))?.collect::<Result<Vec<_>, _>>()?;
+    let stub = std::fs::read(stubs[0].path())?;
+    assert_eq!(
+        lanzaboote_tool::pe::read_section_data(&stub, ".initrdh"),
+        Some(Sha256::digest(&initrd).as_slice())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn embed_self_hash() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output =
+        common::lanzaboote_install_with_args(0, esp.path(), [generation_link], ["--self-hash"])?;
+    assert!(output.status.success());
+
+    let stubs = std::fs::read_dir(esp.path().join("EFI/Linux"))?.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(stubs.len(), 1);
+    let stub = std::fs::read(stubs[0].path())?;
+
+    let mut hasher = Sha256::new();
+    for section in [
+        ".text", ".osrel", ".cmdline", ".initrd", ".linux", ".initrdh", ".linuxh",
+    ] {
+        hasher.update(lanzaboote_tool::pe::read_section_data(&stub, section).unwrap());
+    }
+    assert_eq!(
+        lanzaboote_tool::pe::read_section_data(&stub, ".selfh"),
+        Some(hasher.finalize().as_slice())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn embed_section_hashes() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--section-hashes"],
+    )?;
+    assert!(output.status.success());
+
+    let stubs = std::fs::read_dir(esp.path().join("EFI/Linux"))?.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(stubs.len(), 1);
+    let stub = std::fs::read(stubs[0].path())?;
+
+    let mut expected = Vec::new();
+    for section in [
+        ".text", ".osrel", ".cmdline", ".initrd", ".linux", ".initrdh", ".linuxh",
+    ] {
+        let mut name = [0; 8];
+        name[..section.len()].copy_from_slice(section.as_bytes());
+        expected.extend_from_slice(&name);
+        expected.extend_from_slice(&Sha256::digest(
+            lanzaboote_tool::pe::read_section_data(&stub, section).unwrap(),
+        ));
+    }
+    assert_eq!(
+        lanzaboote_tool::pe::read_section_data(&stub, ".sechash"),
+        Some(expected.as_slice())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn reject_non_efi_kernel() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    std::fs::write(
+        toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/kernel"),
+        b"not an EFI kernel",
+    )?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install(0, esp.path(), [generation_link])?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("CONFIG_EFI_STUB"));
+
+    Ok(())
+}
+
+#[test]
+fn install_generation_without_initrd() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+    let bootspec_path = generation_link.join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&std::fs::read(&bootspec_path)?)?;
+    bootspec["org.nixos.bootspec.v1"]
+        .as_object_mut()
+        .unwrap()
+        .remove("initrd");
+    std::fs::write(&bootspec_path, serde_json::to_vec(&bootspec)?)?;
+    filetime::set_file_mtime(&generation_link, filetime::FileTime::zero())?;
+
+    let output = common::lanzaboote_install(0, esp.path(), [&generation_link])?;
+    assert!(output.status.success());
+
+    let stubs = std::fs::read_dir(esp.path().join("EFI/Linux"))?.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(stubs.len(), 1);
+    let stub = std::fs::read(stubs[0].path())?;
+    goblin::pe::PE::parse(&stub)?;
+    assert!(lanzaboote_tool::pe::read_section_data(&stub, ".linux").is_some());
+    assert!(lanzaboote_tool::pe::read_section_data(&stub, ".initrd").is_none());
+    assert!(lanzaboote_tool::pe::read_section_data(&stub, ".initrdh").is_none());
+    // Only the kernel is installed.
+    assert_eq!(common::count_files(&esp.path().join("EFI/nixos"))?, 1);
+
+    // The installed generation is recognized without an initrd.
+    let output = common::lanzaboote_install(0, esp.path(), [&generation_link])?;
+    assert!(output.status.success());
+    assert_eq!(common::count_files(&esp.path().join("EFI/nixos"))?, 1);
+
+    Ok(())
+}
+
+#[test]
+fn reject_kernel_for_other_architecture() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let kernel_path = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/kernel");
+    let mut kernel = std::fs::read(&kernel_path)?;
+    let pe_offset = u32::from_le_bytes(kernel[0x3c..0x40].try_into()?) as usize;
+    let machine = pe_offset + 4;
+    let other_machine = if kernel[machine..machine + 2] == 0x8664u16.to_le_bytes() {
+        0xaa64u16
+    } else {
+        0x8664u16
+    };
+    kernel[machine..machine + 2].copy_from_slice(&other_machine.to_le_bytes());
+    std::fs::write(&kernel_path, kernel)?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install(0, esp.path(), [generation_link])?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(stderr.contains("is built for"), "{stderr}");
+
+    Ok(())
+}
+
+#[test]
+fn align_sections() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--serial-console", "0", "--self-hash"],
+    )?;
+    assert!(output.status.success());
+
+    let stubs = std::fs::read_dir(esp.path().join("EFI/Linux"))?.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(stubs.len(), 1);
+    let stub = std::fs::read(stubs[0].path())?;
+    let pe = goblin::pe::PE::parse(&stub)?;
+    let windows_fields = pe.header.optional_header.unwrap().windows_fields;
+
+    for section in &pe.sections {
+        assert_eq!(
+            section.pointer_to_raw_data % windows_fields.file_alignment,
+            0,
+            "{} is not aligned in the file",
+            section.name()?
+        );
+        assert_eq!(
+            section.virtual_address % windows_fields.section_alignment,
+            0,
+            "{} is not aligned in memory",
+            section.name()?
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn run_post_hook() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let hook_output = tmpdir.path().join("hook-output");
+    let hook = tmpdir.path().join("hook");
+    std::fs::write(
+        &hook,
+        format!(
+            "#!/bin/sh\nprintf '%s\\n%s\\n' \"$LANZABOOTE_ESP\" \"$LANZABOOTE_UKIS\" > {}\n",
+            hook_output.display()
+        ),
+    )?;
+    std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755))?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        [OsStr::new("--post-hook"), hook.as_os_str()],
+    )?;
+    assert!(output.status.success());
+
+    let stubs = std::fs::read_dir(esp.path().join("EFI/Linux"))?.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(stubs.len(), 1);
+    assert_eq!(
+        std::fs::read_to_string(hook_output)?,
+        format!("{}\n{}\n", esp.path().display(), stubs[0].path().display())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn ignore_failing_post_hook() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--post-hook", "false"],
+    )?;
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("Post-install hook \"false\" failed"));
+
+    Ok(())
+}
+
+/// Build an uncompressed cpio archive (newc format) with empty files at the given paths.
+fn cpio_archive(paths: &[&str]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    for path in paths.iter().chain(&["TRAILER!!!"]) {
+        archive.extend(b"070701");
+        for field in [0, 0o100644, 0, 0, 1, 0, 0, 0, 0, 0, 0, path.len() + 1, 0] {
+            archive.extend(format!("{field:08x}").as_bytes());
+        }
+        archive.extend(path.as_bytes());
+        archive.push(0);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+    }
+    archive
+}
+
+#[test]
+fn warn_on_module_version_mismatch() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    std::fs::write(
+        toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/initrd"),
+        cpio_archive(&["init", "lib/modules/5.15.0/kernel/fs/ext4.ko"]),
+    )?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install(0, esp.path(), [generation_link])?;
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr)?
+        .contains("contains modules for kernel 5.15.0, but its kernel is 6.1.1"));
+
+    Ok(())
+}
+
+#[test]
+fn accept_matching_module_version() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    std::fs::write(
+        toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1/initrd"),
+        cpio_archive(&["init", "lib/modules/6.1.1/kernel/fs/ext4.ko"]),
+    )?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install(0, esp.path(), [generation_link])?;
+    assert!(output.status.success());
+    assert!(!String::from_utf8(output.stderr)?.contains("contains modules for kernel"));
+
+    Ok(())
+}
+
+#[test]
+fn write_manifest() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link1 = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+    let generation_link2 = common::setup_generation_link(tmpdir.path(), profiles.path(), 2)?;
+    let manifest = tmpdir.path().join("manifest.json");
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link1, generation_link2],
+        [OsStr::new("--manifest"), manifest.as_os_str()],
+    )?;
+    assert!(output.status.success());
+
+    let manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(manifest)?)?;
+    assert_eq!(manifest["version"], 1);
+    let ukis = manifest["ukis"].as_array().unwrap();
+    assert_eq!(ukis.len(), 2);
+
+    for uki in ukis {
+        let path = uki["path"].as_str().unwrap();
+        let stub_path = esp
+            .path()
+            .join(path.trim_start_matches('\\').replace('\\', "/"));
+        let stub = std::fs::read(&stub_path)?;
+
+        assert!(path.starts_with("\\EFI\\Linux\\nixos-generation-"));
+        assert_eq!(uki["sha256"], format!("{:x}", hash_file(&stub_path)));
+        assert!(uki["signed_at"].as_i64().unwrap() > 0);
+        let signer = uki["signer"].as_str().unwrap();
+        assert!(manifest["signers"][signer]["subject"].is_string());
+
+        // The stub measures its sections into PCR 11 in the order they appear in.
+        let mut pcr = [0; 32];
+        for section in goblin::pe::PE::parse(&stub)?.sections {
+            let name = section.name()?;
+            if [".linux", ".osrel", ".cmdline", ".initrd"].contains(&name) {
+                let data = lanzaboote_tool::pe::read_section_data(&stub, name).unwrap();
+                pcr = Sha256::new()
+                    .chain_update(pcr)
+                    .chain_update(Sha256::digest(data))
+                    .finalize()
+                    .into();
+            }
+        }
+        assert_ne!(pcr, [0; 32]);
+        let pcr = pcr.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        assert_eq!(uki["pcrs"]["11"], pcr);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn skip_generations_with_missing_toplevel() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link1 = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+    let generation_link3 = common::setup_generation_link(tmpdir.path(), profiles.path(), 3)?;
+    // The toplevel of this generation was garbage collected.
+    let generation_link2 = profiles.path().join("system-2-link");
+    std::os::unix::fs::symlink(tmpdir.path().join("collected-toplevel"), &generation_link2)?;
+
+    let output = common::lanzaboote_install(
+        0,
+        esp.path(),
+        [generation_link1, generation_link2, generation_link3],
+    )?;
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(stderr.contains("Skipping generation 2"));
+    // Garbage collection stays enabled because the generation is not malformed.
+    assert!(!stderr.contains("Garbage collection is disabled"));
+
+    let stubs = std::fs::read_dir(esp.path().join("EFI/Linux"))?
+        .map(|entry| Ok(entry?.file_name().into_string().unwrap()))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(stubs.len(), 2);
+    assert!(stubs.iter().any(|s| s.starts_with("nixos-generation-1-")));
+    assert!(stubs.iter().any(|s| s.starts_with("nixos-generation-3-")));
+
+    Ok(())
+}
+
+#[test]
+fn refuse_tpm_handle_that_is_not_an_nv_index() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--tpm-nv-index", "0x81000001"],
+    )?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("is not the handle of an NV index"));
+
+    Ok(())
+}
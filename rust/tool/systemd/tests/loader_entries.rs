@@ -0,0 +1,136 @@
+use std::fs;
+use std::path::Path;
+use std::process::Output;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+fn run_check_loader_entries(esp: &Path, clean: bool) -> Result<Output> {
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    cmd.arg("check-loader-entries");
+    if clean {
+        cmd.arg("--clean-foreign-entries");
+    }
+    let output = cmd.arg(esp).output()?;
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+    assert!(output.status.success());
+    Ok(output)
+}
+
+fn check_loader_entries(esp: &Path, clean: bool) -> Result<String> {
+    Ok(String::from_utf8(
+        run_check_loader_entries(esp, clean)?.stdout,
+    )?)
+}
+
+/// An ESP with a UKI installed by lzbt and `entries` in loader/entries, given as their names and
+/// contents.
+fn fixture_esp(esp: &Path, entries: &[(&str, &str)]) -> Result<()> {
+    fs::create_dir_all(esp.join("EFI/Linux"))?;
+    fs::write(esp.join("EFI/Linux/nixos-generation-2-abc.efi"), "")?;
+    fs::create_dir_all(esp.join("loader/entries"))?;
+    for (name, contents) in entries {
+        fs::write(esp.join("loader/entries").join(name), contents)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn only_clean_stale_entries() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+    let output = common::lanzaboote_install(0, esp.path(), [generation_link])?;
+    assert!(output.status.success());
+
+    let uki = fs::read_dir(esp.path().join("EFI/Linux"))?
+        .next()
+        .expect("No UKI installed")?
+        .file_name();
+    let entries = esp.path().join("loader/entries");
+    fs::create_dir_all(&entries)?;
+    fs::write(
+        entries.join("nixos-generation-1.conf"),
+        "title NixOS\nlinux /efi/nixos/abc-linux-6.1-bzImage.efi\ninitrd /efi/nixos/abc-initrd.efi\n",
+    )?;
+    fs::write(
+        entries.join("debian.conf"),
+        "title Debian\nlinux /vmlinuz-6.1\ninitrd /initrd.img-6.1\n",
+    )?;
+    fs::write(
+        entries.join("uki.conf"),
+        format!("efi /EFI/Linux/{}\n", uki.to_string_lossy()),
+    )?;
+
+    let stdout = check_loader_entries(esp.path(), false)?;
+    assert_eq!(
+        stdout,
+        "debian.conf: foreign, not touched by lzbt\n\
+         nixos-generation-1.conf: stale, left behind by the systemd-boot installer of NixOS\n\
+         uki.conf: boots a UKI installed by lzbt\n"
+    );
+    assert_eq!(common::count_files(&entries)?, 3);
+
+    check_loader_entries(esp.path(), true)?;
+    assert!(!entries.join("nixos-generation-1.conf").exists());
+    assert!(entries.join("debian.conf").exists());
+    assert!(entries.join("uki.conf").exists());
+
+    Ok(())
+}
+
+#[test]
+fn accept_missing_entries_directory() -> Result<()> {
+    let esp = tempdir()?;
+
+    assert_eq!(check_loader_entries(esp.path(), true)?, "");
+
+    Ok(())
+}
+
+#[test]
+fn warn_about_generations_of_systemd_boot_and_lzbt() -> Result<()> {
+    let esp = tempdir()?;
+    fixture_esp(
+        esp.path(),
+        &[(
+            "nixos-generation-1.conf",
+            "title NixOS\nlinux /efi/nixos/abc-linux-6.1-bzImage.efi\n",
+        )],
+    )?;
+
+    let stderr = String::from_utf8(run_check_loader_entries(esp.path(), false)?.stderr)?;
+    assert!(
+        stderr.contains("installed by both lzbt and the systemd-boot installer"),
+        "{stderr}"
+    );
+    assert!(stderr.contains("nixos-generation-1.conf"), "{stderr}");
+    assert!(stderr.contains("nixos-generation-2-abc.efi"), "{stderr}");
+    assert!(
+        stderr.contains("`reinit`") && stderr.contains("--clean-foreign-entries"),
+        "{stderr}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn no_warning_for_lzbt_only() -> Result<()> {
+    let esp = tempdir()?;
+    fixture_esp(
+        esp.path(),
+        &[(
+            "debian.conf",
+            "title Debian\nlinux /vmlinuz-6.1\ninitrd /initrd.img-6.1\n",
+        )],
+    )?;
+
+    let stderr = String::from_utf8(run_check_loader_entries(esp.path(), false)?.stderr)?;
+    assert!(!stderr.contains("systemd-boot installer"), "{stderr}");
+
+    Ok(())
+}
@@ -0,0 +1,35 @@
+use std::fs::File;
+
+use anyhow::Result;
+use nix::fcntl::{Flock, FlockArg};
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn refuse_to_install_while_locked() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    // Stand in for another instance that is installing right now.
+    let lock = Flock::lock(File::open(esp.path())?, FlockArg::LockExclusiveNonblock)
+        .map_err(|(_, errno)| errno)?;
+
+    let output = common::lanzaboote_install(0, esp.path(), [&generation_link])?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(stderr.contains("Another instance of lzbt is installing"));
+    assert_eq!(common::count_files(esp.path())?, 0);
+
+    let output =
+        common::lanzaboote_install_with_args(0, esp.path(), [&generation_link], ["--no-lock"])?;
+    assert!(output.status.success());
+
+    drop(lock);
+    let output = common::lanzaboote_install(0, esp.path(), [&generation_link])?;
+    assert!(output.status.success());
+
+    Ok(())
+}
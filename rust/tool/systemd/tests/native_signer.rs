@@ -0,0 +1,58 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use lanzaboote_tool::authenticode::NativeSigner;
+use lanzaboote_tool::pe::authenticode_digest;
+use lanzaboote_tool::signature::{KeyPair, Signer};
+
+mod common;
+
+/// Sign many binaries without spawning a single process.
+///
+/// This is the only test in this file because it clears PATH for the whole test process, which
+/// makes every attempt to spawn sbsign fail.
+#[test]
+fn sign_many_binaries_in_process() -> Result<()> {
+    let systemd_boot = common::systemd_boot_binary()?;
+    let unsigned_digest = authenticode_digest(&fs::read(&systemd_boot)?)?;
+    let key_pair = KeyPair::new(
+        Path::new("tests/fixtures/uefi-keys/db.pem"),
+        Path::new("tests/fixtures/uefi-keys/db.key"),
+    );
+    let signer = NativeSigner::new(&key_pair)?;
+
+    let empty_path = tempdir()?;
+    std::env::set_var("PATH", empty_path.path());
+    let out = tempdir()?;
+    assert!(key_pair
+        .sign_and_copy(&systemd_boot, &out.path().join("sbsign.efi"))
+        .is_err());
+
+    for i in 0..8 {
+        let signed = out.path().join(format!("signed-{i}.efi"));
+        signer.sign_and_copy(&systemd_boot, &signed)?;
+
+        let signed = fs::read(&signed)?;
+        let pe = goblin::pe::PE::parse(&signed)?;
+        let certificate_table = pe
+            .header
+            .optional_header
+            .and_then(|h| *h.data_directories.get_certificate_table())
+            .expect("Signed binary has no certificate table");
+        let start = usize::try_from(certificate_table.virtual_address)?;
+        let end = start + usize::try_from(certificate_table.size)?;
+        assert_eq!(end, signed.len());
+
+        // Signing does not change the Authenticode digest and the signature covers it.
+        let digest = authenticode_digest(&signed)?;
+        assert_eq!(digest, unsigned_digest);
+        assert!(signed[start..end]
+            .windows(digest.len())
+            .any(|w| w == digest.as_slice()));
+    }
+
+    Ok(())
+}
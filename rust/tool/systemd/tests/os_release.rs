@@ -36,6 +36,148 @@ fn generate_expected_os_release() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn take_version_from_os_release_of_generation() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+
+    let mut generations = Vec::new();
+    for (version, nixos_version) in [(1, "23.05 (Stoat)"), (2, "23.11 (Tapir)")] {
+        let toplevel = common::setup_toplevel(tmpdir.path())?;
+        fs::create_dir(toplevel.join("etc"))?;
+        fs::write(
+            toplevel.join("etc/os-release"),
+            format!(
+                "ID=nixos\nNAME=NixOS\nVERSION=\"{nixos_version}\"\nBUILD_ID=build-{version}\n"
+            ),
+        )?;
+        let generation_link =
+            common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), version)?;
+        generations.push((version, toplevel, generation_link));
+    }
+
+    let output0 = common::lanzaboote_install(
+        0,
+        esp_mountpoint.path(),
+        generations.iter().map(|(_, _, link)| link),
+    )?;
+    assert!(output0.status.success());
+
+    let mut os_releases = Vec::new();
+    for (version, toplevel, _) in &generations {
+        let stub_data = fs::read(common::image_path(&esp_mountpoint, *version, toplevel)?)?;
+        let os_release_section =
+            pe_section(&stub_data, ".osrel").context("Failed to read .osrelease PE section.")?;
+        os_releases.push(String::from_utf8(os_release_section.to_owned())?);
+    }
+
+    let expected = expect![[r#"
+        BUILD_ID=build-1
+        ID=lanza
+        NAME=NixOS
+        PRETTY_NAME=LanzaOS (Generation 1, 1970-01-01)
+        VERSION=23.05 (Stoat)
+        VERSION_ID=Generation 1, 1970-01-01
+    "#]];
+    expected.assert_eq(&os_releases[0]);
+    assert!(os_releases[1].contains("VERSION=23.11 (Tapir)\n"));
+    assert!(os_releases[1].contains("BUILD_ID=build-2\n"));
+    assert_ne!(os_releases[0], os_releases[1]);
+
+    Ok(())
+}
+
+/// The os-release of a real NixOS toplevel sets IMAGE_VERSION to an empty string, which is not
+/// copied. An os-release that cannot be parsed does not stop the installation.
+#[test]
+fn skip_empty_and_unreadable_os_release() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+
+    let mut generations = Vec::new();
+    for (version, os_release) in [
+        (
+            1,
+            &b"ANSI_COLOR=\"1;34\"\nHOME_URL=\"https://nixos.org/\"\nID=nixos\nIMAGE_ID=\"\"\nIMAGE_VERSION=\"\"\nNAME=NixOS\nVERSION=\"24.05 (Uakari)\"\n"[..],
+        ),
+        (2, &b"VERSION=\"\xff\"\n"[..]),
+    ] {
+        let toplevel = common::setup_toplevel(tmpdir.path())?;
+        fs::create_dir(toplevel.join("etc"))?;
+        fs::write(toplevel.join("etc/os-release"), os_release)?;
+        let generation_link =
+            common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), version)?;
+        generations.push((version, toplevel, generation_link));
+    }
+
+    let output0 = common::lanzaboote_install(
+        0,
+        esp_mountpoint.path(),
+        generations.iter().map(|(_, _, link)| link),
+    )?;
+    assert!(output0.status.success());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(
+        stderr.contains("Failed to read the os-release of generation"),
+        "{stderr}"
+    );
+
+    let mut os_releases = Vec::new();
+    for (version, toplevel, _) in &generations {
+        let stub_data = fs::read(common::image_path(&esp_mountpoint, *version, toplevel)?)?;
+        let os_release_section =
+            pe_section(&stub_data, ".osrel").context("Failed to read .osrelease PE section.")?;
+        os_releases.push(String::from_utf8(os_release_section.to_owned())?);
+    }
+
+    let expected = expect![[r#"
+        ID=lanza
+        NAME=NixOS
+        PRETTY_NAME=LanzaOS (Generation 1, 1970-01-01)
+        VERSION=24.05 (Uakari)
+        VERSION_ID=Generation 1, 1970-01-01
+    "#]];
+    expected.assert_eq(&os_releases[0]);
+    let expected = expect![[r#"
+        ID=lanza
+        PRETTY_NAME=LanzaOS (Generation 2, 1970-01-01)
+        VERSION_ID=Generation 2, 1970-01-01
+    "#]];
+    expected.assert_eq(&os_releases[1]);
+
+    Ok(())
+}
+
+#[test]
+fn refuse_generation_without_os_release_when_failing() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        [&generation_link],
+        ["--missing-os-release", "fail"],
+    )?;
+    assert!(!output0.status.success());
+    let stderr = String::from_utf8(output0.stderr)?;
+    assert!(stderr.contains("has no etc/os-release"), "{stderr}");
+
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        [&generation_link],
+        ["--missing-os-release", "warn"],
+    )?;
+    assert!(output1.status.success());
+
+    Ok(())
+}
+
 fn pe_section<'a>(file_data: &'a [u8], section_name: &str) -> Option<&'a [u8]> {
     let pe_binary = goblin::pe::PE::parse(file_data).ok()?;
 
@@ -0,0 +1,145 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use tempfile::{tempdir, TempDir};
+use walkdir::WalkDir;
+
+mod common;
+
+/// Every file on the ESP with its contents, sorted by path.
+fn esp_contents(esp: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut contents = Vec::new();
+    for entry in WalkDir::new(esp).sort_by_file_name() {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            let path = entry.path().strip_prefix(esp)?.display().to_string();
+            contents.push((path, fs::read(entry.path())?));
+        }
+    }
+    Ok(contents)
+}
+
+/// The path of the UKI of a generation relative to the ESP.
+fn relative_image_path(esp: &TempDir, version: u64, toplevel: &Path) -> Result<String> {
+    let image = common::image_path(esp, version, toplevel)?;
+    Ok(image.strip_prefix(esp.path())?.display().to_string())
+}
+
+#[test]
+fn plan_new_and_deleted_generations() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link1 =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let generation_link2 =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+    let generation_link3 =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 3)?;
+
+    let output = common::lanzaboote_install(0, esp.path(), [&generation_link1, &generation_link2])?;
+    assert!(output.status.success());
+    let before = esp_contents(esp.path())?;
+
+    // A stale file in EFI/nixos is removed by the installation.
+    fs::write(esp.path().join("EFI/nixos/stale.efi"), b"stale")?;
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link2, &generation_link3],
+        ["--plan"],
+    )?;
+    let stdout = String::from_utf8(output.stdout)?;
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(!output.status.success(), "{stderr}");
+    assert!(
+        stderr.contains("3 changes to the ESP are pending"),
+        "{stderr}"
+    );
+    assert_eq!(
+        stdout,
+        format!(
+            "add    {} (generation 3 is not installed)\n\
+             remove EFI/nixos/stale.efi (no longer used)\n\
+             remove {} (no longer used)\n",
+            relative_image_path(&esp, 3, &toplevel)?,
+            relative_image_path(&esp, 1, &toplevel)?,
+        )
+    );
+
+    // Nothing was written.
+    fs::remove_file(esp.path().join("EFI/nixos/stale.efi"))?;
+    assert_eq!(esp_contents(esp.path())?, before);
+
+    // After installing, nothing is pending anymore.
+    let output = common::lanzaboote_install(0, esp.path(), [&generation_link2, &generation_link3])?;
+    assert!(output.status.success());
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link2, &generation_link3],
+        ["--plan"],
+    )?;
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("The ESP is up to date."), "{stderr}");
+    assert!(output.stdout.is_empty());
+    Ok(())
+}
+
+#[test]
+fn plan_updates_with_incremental_state() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output =
+        common::lanzaboote_install_with_args(0, esp.path(), [&generation_link], ["--incremental"])?;
+    assert!(output.status.success());
+
+    // The UKI, its kernel and loader.conf are modified or deleted on the ESP.
+    let image = common::image_path(&esp, 1, &toplevel)?;
+    let mut stub = fs::read(&image)?;
+    stub.extend(b"modified");
+    fs::write(&image, stub)?;
+    fs::write(esp.path().join("loader/loader.conf"), b"timeout 0\n")?;
+    let kernel = fs::read_dir(esp.path().join("EFI/nixos"))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .find(|path| {
+            path.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with("kernel-"))
+        })
+        .expect("No kernel was installed");
+    fs::remove_file(&kernel)?;
+    let before = esp_contents(esp.path())?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link],
+        ["--incremental", "--plan"],
+    )?;
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(!output.status.success());
+    assert_eq!(
+        stdout,
+        format!(
+            "update {} (generation 1 changed since the last installation)\n\
+             add    {} (kernel of generation 1)\n\
+             update EFI/nixos/lanzaboote-state.json (the installed UKIs changed)\n\
+             update loader/loader.conf (loader.conf changed)\n",
+            relative_image_path(&esp, 1, &toplevel)?,
+            kernel.strip_prefix(esp.path())?.display(),
+        )
+    );
+    assert_eq!(esp_contents(esp.path())?, before);
+    Ok(())
+}
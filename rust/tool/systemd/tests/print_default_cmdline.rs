@@ -0,0 +1,72 @@
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn print_resolved_cmdline_of_generation() -> Result<()> {
+    let profiles = tempdir()?;
+    let toplevel = tempdir()?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(toplevel.path(), profiles.path(), 1)?;
+
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    let output = cmd
+        .arg("print-default-cmdline")
+        .arg(&generation_link)
+        .output()?;
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+    assert!(output.status.success());
+
+    let expected = "init=init-v1 amd_iommu=on amd_iommu=pt iommu=pt kvm.ignore_msrs=1 \
+                    kvm.report_ignored_msrs=0 udev.log_priority=3 \
+                    systemd.unified_cgroup_hierarchy=1 loglevel=4\n";
+    assert_eq!(String::from_utf8(output.stdout)?, expected);
+
+    Ok(())
+}
+
+#[test]
+fn fail_on_unknown_specialisation() -> Result<()> {
+    let profiles = tempdir()?;
+    let toplevel = tempdir()?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(toplevel.path(), profiles.path(), 1)?;
+
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    let output = cmd
+        .arg("print-default-cmdline")
+        .arg("--specialisation")
+        .arg("does-not-exist")
+        .arg(&generation_link)
+        .output()?;
+    assert!(!output.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn merge_common_cmdline() -> Result<()> {
+    let profiles = tempdir()?;
+    let toplevel = tempdir()?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(toplevel.path(), profiles.path(), 1)?;
+
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    let output = cmd
+        .arg("print-default-cmdline")
+        .arg("--common-cmdline")
+        .arg("quiet loglevel=7 console=ttyS0")
+        .arg(&generation_link)
+        .output()?;
+    assert!(output.status.success());
+
+    // The generation already sets loglevel.
+    let expected = "init=init-v1 amd_iommu=on amd_iommu=pt iommu=pt kvm.ignore_msrs=1 \
+                    kvm.report_ignored_msrs=0 udev.log_priority=3 \
+                    systemd.unified_cgroup_hierarchy=1 loglevel=4 quiet console=ttyS0\n";
+    assert_eq!(String::from_utf8(output.stdout)?, expected);
+
+    Ok(())
+}
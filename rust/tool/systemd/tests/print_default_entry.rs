@@ -0,0 +1,75 @@
+use std::fs;
+use std::path::Path;
+use std::process::Output;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::{tempdir, TempDir};
+
+/// Create an ESP whose loader.conf has `default` as default entry.
+fn setup_esp(default: &str) -> Result<TempDir> {
+    let esp = tempdir()?;
+    fs::create_dir(esp.path().join("loader"))?;
+    fs::write(
+        esp.path().join("loader/loader.conf"),
+        format!("default {default}\n"),
+    )?;
+    Ok(esp)
+}
+
+/// Call `print-default-entry` without any EFI variables.
+fn print_default_entry(esp_arg: Option<&Path>, esp_env: Option<&Path>) -> Result<Output> {
+    let efivars = tempdir()?;
+    let mut cmd = Command::cargo_bin("lzbt-systemd")?;
+    cmd.env_remove("LANZABOOTE_ESP")
+        .arg("print-default-entry")
+        .arg("--efivars")
+        .arg(efivars.path());
+    if let Some(esp) = esp_env {
+        cmd.env("LANZABOOTE_ESP", esp);
+    }
+    if let Some(esp) = esp_arg {
+        cmd.arg(esp);
+    }
+    let output = cmd.output()?;
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+    Ok(output)
+}
+
+#[test]
+fn read_esp_from_environment() -> Result<()> {
+    let esp = setup_esp("nixos-generation-1.efi")?;
+
+    let output = print_default_entry(None, Some(esp.path()))?;
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        "nixos-generation-1.efi\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn prefer_esp_argument_over_environment() -> Result<()> {
+    let env_esp = setup_esp("nixos-generation-1.efi")?;
+    let arg_esp = setup_esp("nixos-generation-2.efi")?;
+
+    let output = print_default_entry(Some(arg_esp.path()), Some(env_esp.path()))?;
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        "nixos-generation-2.efi\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn require_esp() -> Result<()> {
+    let output = print_default_entry(None, None)?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("<ESP>"));
+
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::{hash_file, lanzaboote_install, lanzaboote_reinit, lanzaboote_reinit_with_path};
+
+#[test]
+fn reinit_removes_only_owned_files() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output = lanzaboote_install(0, esp.path(), [&generation_link])?;
+    assert!(output.status.success());
+    let stub = common::image_path(&esp, 1, &toplevel)?;
+    let stub_hash = hash_file(&stub);
+
+    // Files that lzbt owns: a corrupted UKI and leftovers of an old installation.
+    fs::write(&stub, b"corrupted")?;
+    let stale_uki = esp.path().join("EFI/Linux/nixos-generation-99-stale.efi");
+    fs::write(&stale_uki, b"stale")?;
+    let stale_nixos_file = esp.path().join("EFI/nixos/unknown.efi");
+    fs::write(&stale_nixos_file, b"stale")?;
+    let stale_entry = esp.path().join("loader/entries/nixos-generation-99.conf");
+    fs::create_dir_all(stale_entry.parent().unwrap())?;
+    fs::write(
+        &stale_entry,
+        "# Installed by lzbt. Changes are overwritten.\ntitle Stale\n",
+    )?;
+
+    // Files of other operating systems on a shared ESP.
+    let foreign_files = [
+        esp.path().join("EFI/Linux/fedora.efi"),
+        esp.path().join("EFI/Microsoft/Boot/bootmgfw.efi"),
+        esp.path().join("loader/entries/fedora.conf"),
+    ];
+    for file in &foreign_files {
+        fs::create_dir_all(file.parent().unwrap())?;
+        fs::write(file, b"foreign")?;
+    }
+
+    let output = lanzaboote_reinit(0, esp.path(), [&generation_link])?;
+    assert!(output.status.success());
+
+    assert_eq!(hash_file(&stub), stub_hash, "The UKI was not reinstalled");
+    assert!(common::verify_signature(&stub)?);
+    for file in [&stale_uki, &stale_nixos_file, &stale_entry] {
+        assert!(!file.exists(), "{file:?} was not removed");
+    }
+    for file in &foreign_files {
+        assert_eq!(fs::read(file)?, b"foreign", "{file:?} was touched");
+    }
+    // The UKI next to the foreign one, and the kernel and initrd of the generation.
+    assert_eq!(common::count_files(&esp.path().join("EFI/Linux"))?, 2);
+    assert_eq!(common::count_files(&esp.path().join("EFI/nixos"))?, 2);
+    Ok(())
+}
+
+/// Read every file below `dir`, keyed by its path.
+fn snapshot(dir: &Path) -> Result<BTreeMap<PathBuf, Vec<u8>>> {
+    let mut files = BTreeMap::new();
+    for entry in walkdir::WalkDir::new(dir) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            files.insert(entry.path().to_path_buf(), fs::read(entry.path())?);
+        }
+    }
+    Ok(files)
+}
+
+#[test]
+fn reinit_keeps_the_esp_if_rebuilding_fails() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let bin = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = lanzaboote_install(0, esp.path(), [&generation_link])?;
+    assert!(output.status.success());
+    let stale_uki = esp.path().join("EFI/Linux/nixos-generation-99-stale.efi");
+    fs::write(&stale_uki, b"stale")?;
+    let before = snapshot(esp.path())?;
+
+    let sbsign = bin.path().join("sbsign");
+    fs::write(&sbsign, "#!/bin/sh\nexit 1\n")?;
+    fs::set_permissions(&sbsign, fs::Permissions::from_mode(0o755))?;
+    let path = env::join_paths(
+        [bin.path().to_path_buf()]
+            .into_iter()
+            .chain(env::split_paths(&env::var_os("PATH").unwrap_or_default())),
+    )?;
+
+    let output = lanzaboote_reinit_with_path(0, esp.path(), [&generation_link], &path)?;
+    assert!(!output.status.success());
+
+    assert_eq!(snapshot(esp.path())?, before, "The ESP was changed");
+    for entry in fs::read_dir(esp.path())? {
+        let name = entry?.file_name();
+        assert!(
+            !name.to_string_lossy().starts_with(".lzbt-reinit-"),
+            "The staging directory {name:?} was left behind"
+        );
+    }
+    Ok(())
+}
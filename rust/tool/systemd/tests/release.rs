@@ -0,0 +1,143 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+/// The hash the release signatures in the fixtures are made over.
+const TOPLEVEL_HASH: &str = "0123456789abcdfghijklmnpqrsvwxyz";
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/release-keys")
+        .join(name)
+}
+
+/// Set up a generation whose toplevel looks like a store path with [`TOPLEVEL_HASH`] and a
+/// signature directory that contains `signature` for it.
+fn setup_signed_generation(
+    tmpdir: &Path,
+    profiles: &Path,
+    signatures: &Path,
+    signature: &str,
+) -> Result<PathBuf> {
+    let toplevel = common::setup_toplevel(tmpdir)?;
+    let store_path = tmpdir.join(format!("{TOPLEVEL_HASH}-nixos-system-lanzaos-23.05"));
+    fs::rename(toplevel, &store_path)?;
+    fs::copy(
+        fixture(signature),
+        signatures.join(format!("{TOPLEVEL_HASH}.sig")),
+    )?;
+    common::setup_generation_link_from_toplevel(&store_path, profiles, 1)
+}
+
+fn release_args(signatures: &Path) -> Vec<PathBuf> {
+    vec![
+        "--release-keyring".into(),
+        fixture("release.gpg"),
+        "--release-signatures".into(),
+        signatures.into(),
+    ]
+}
+
+#[test]
+fn install_generation_with_valid_release_signature() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let signatures = tempdir()?;
+    let generation_link = setup_signed_generation(
+        tmpdir.path(),
+        profiles.path(),
+        signatures.path(),
+        "valid.sig",
+    )?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        release_args(signatures.path()),
+    )?;
+    assert!(output.status.success());
+    assert_eq!(common::count_files(&esp.path().join("EFI/Linux"))?, 1);
+
+    Ok(())
+}
+
+#[test]
+fn refuse_generation_with_invalid_release_signature() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let signatures = tempdir()?;
+    // Made over the same hash, but with a key that is not in the release keyring.
+    let generation_link = setup_signed_generation(
+        tmpdir.path(),
+        profiles.path(),
+        signatures.path(),
+        "invalid.sig",
+    )?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        release_args(signatures.path()),
+    )?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(stderr.contains("is not valid for the keyring"), "{stderr}");
+    assert!(!esp.path().join("EFI/Linux").exists());
+
+    Ok(())
+}
+
+#[test]
+fn refuse_generation_without_release_signature() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let signatures = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        release_args(signatures.path()),
+    )?;
+    assert!(!output.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn refuse_installed_generation_without_release_signature() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let signatures = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install(0, esp.path(), [&generation_link])?;
+    assert!(output.status.success());
+
+    // The generation was installed before the verifier was configured.
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link],
+        release_args(signatures.path()),
+    )?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(
+        stderr.contains("Refusing to install generation"),
+        "{stderr}"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,190 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::Result;
+use tempfile::tempdir;
+use x509_cert::der::DecodePem;
+use x509_cert::Certificate;
+
+use lanzaboote_tool::authenticode::{verify_signatures, NativeSigner, RemoteSigner};
+use lanzaboote_tool::pe::authenticode_digest;
+use lanzaboote_tool::signature::{KeyPair, Signer};
+
+mod common;
+
+use common::verify_signature;
+
+const FIXTURE_UKI: &str = "tests/fixtures/authenticode/uki.efi";
+const DB_CERTIFICATE: &str = "tests/fixtures/uefi-keys/db.pem";
+const TOKEN: &str = "secret";
+
+/// A signing service that signs every digest with `key_pair` and records the request bodies.
+///
+/// Requests without the bearer token [`TOKEN`] are refused.
+struct MockSigner {
+    url: String,
+    requests: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl MockSigner {
+    fn start(key_pair: &str) -> Result<Self> {
+        let signer = NativeSigner::new(&KeyPair::new(
+            Path::new(&format!("tests/fixtures/{key_pair}.pem")),
+            Path::new(&format!("tests/fixtures/{key_pair}.key")),
+        ))?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/sign", listener.local_addr()?);
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.expect("Failed to accept connection");
+                Self::respond(&signer, stream, &recorded).expect("Failed to respond");
+            }
+        });
+        Ok(Self { url, requests })
+    }
+
+    fn respond(
+        signer: &NativeSigner,
+        mut stream: TcpStream,
+        requests: &Mutex<Vec<Vec<u8>>>,
+    ) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut content_length = 0;
+        let mut authorized = false;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let Some((name, value)) = line.split_once(": ") else {
+                continue;
+            };
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.parse()?,
+                "authorization" => authorized = value == format!("Bearer {TOKEN}"),
+                _ => (),
+            }
+        }
+        let mut digest = vec![0; content_length];
+        reader.read_exact(&mut digest)?;
+        requests.lock().unwrap().push(digest.clone());
+
+        let (status, body) = if authorized {
+            ("200 OK", signer.signed_data(&digest))
+        } else {
+            ("401 Unauthorized", Vec::new())
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {status}\r\nContent-Type: application/pkcs7-signature\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )?;
+        stream.write_all(&body)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn sign_with_remote_signer() -> Result<()> {
+    let server = MockSigner::start("uefi-keys/db")?;
+    let out = tempdir()?;
+    let signed = out.path().join("uki.efi");
+
+    RemoteSigner::new(&server.url, Some(TOKEN), Path::new(DB_CERTIFICATE))?
+        .sign_and_copy(Path::new(FIXTURE_UKI), &signed)?;
+
+    let signed = fs::read(&signed)?;
+    let trusted = Certificate::from_pem(fs::read(DB_CERTIFICATE)?)?;
+    verify_signatures(&signed, &[trusted])?;
+    // Only the digest was sent to the service, not the binary.
+    let requests = server.requests.lock().unwrap();
+    assert_eq!(*requests, [authenticode_digest(&signed)?.to_vec()]);
+
+    Ok(())
+}
+
+#[test]
+fn reject_request_without_token() -> Result<()> {
+    let server = MockSigner::start("uefi-keys/db")?;
+    let out = tempdir()?;
+    let signed = out.path().join("uki.efi");
+
+    let err = RemoteSigner::new(&server.url, None, Path::new(DB_CERTIFICATE))?
+        .sign_and_copy(Path::new(FIXTURE_UKI), &signed)
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("401"), "{err:#}");
+
+    Ok(())
+}
+
+#[test]
+fn reject_signature_of_another_key() -> Result<()> {
+    let server = MockSigner::start("pki/good")?;
+    let out = tempdir()?;
+    let signed = out.path().join("uki.efi");
+
+    let err = RemoteSigner::new(&server.url, Some(TOKEN), Path::new(DB_CERTIFICATE))?
+        .sign_and_copy(Path::new(FIXTURE_UKI), &signed)
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("does not chain"), "{err:#}");
+
+    Ok(())
+}
+
+#[test]
+fn reject_plain_http_to_other_hosts() -> Result<()> {
+    for url in ["http://signer.example.com/sign", "ftp://127.0.0.1/sign"] {
+        let err = RemoteSigner::new(url, Some(TOKEN), Path::new(DB_CERTIFICATE))
+            .err()
+            .unwrap_or_else(|| panic!("{url} was accepted"));
+        assert!(format!("{err:#}").contains("is not HTTPS"), "{err:#}");
+    }
+    for url in [
+        "https://signer.example.com/sign",
+        "http://localhost:8080/sign",
+        "http://[::1]/sign",
+    ] {
+        RemoteSigner::new(url, Some(TOKEN), Path::new(DB_CERTIFICATE))?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn install_with_remote_signer() -> Result<()> {
+    let server = MockSigner::start("uefi-keys/db")?;
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install_with_remote_signer(
+        0,
+        esp.path(),
+        [generation_link],
+        &server.url,
+        TOKEN,
+    )?;
+    assert!(output.status.success());
+
+    let stubs = fs::read_dir(esp.path().join("EFI/Linux"))?.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(stubs.len(), 1);
+    assert!(verify_signature(&stubs[0].path())?);
+    assert!(server
+        .requests
+        .lock()
+        .unwrap()
+        .iter()
+        .all(|digest| digest.len() == 32));
+
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use std::fs;
+
+use anyhow::{Context, Result};
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn report_reproducible_ukis() -> Result<()> {
+    let reference = tempdir()?;
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install(0, reference.path(), [&generation_link])?;
+    assert!(output.status.success());
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link],
+        ["--reproduce".as_ref(), reference.path().as_os_str()],
+    )?;
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("All 1 UKIs are reproducible."), "{stderr}");
+    // Nothing is installed.
+    assert_eq!(common::count_files(esp.path())?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn report_first_differing_section() -> Result<()> {
+    let reference = tempdir()?;
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install(0, reference.path(), [&generation_link])?;
+    assert!(output.status.success());
+
+    // Change the title in the .osrel section of the reference UKI without changing its size.
+    let uki = fs::read_dir(reference.path().join("EFI/Linux"))?
+        .next()
+        .context("No UKI was installed")??
+        .path();
+    let mut data = fs::read(&uki)?;
+    let title = data
+        .windows(7)
+        .position(|window| window == b"LanzaOS")
+        .context("The UKI does not contain the title")?;
+    data[title..title + 7].copy_from_slice(b"LanzaXX");
+    fs::write(&uki, data)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link],
+        ["--reproduce".as_ref(), reference.path().as_os_str()],
+    )?;
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("is not reproducible, the first differing section is .osrel"),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("1 of 1 UKIs are not reproducible"),
+        "{stderr}"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,41 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn require_self_test_result() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+    let install = || {
+        common::lanzaboote_install_with_args(
+            0,
+            esp.path(),
+            [&generation_link],
+            ["--require-self-test"],
+        )
+    };
+
+    let output = install()?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("Run the self-test image"));
+
+    let result = esp.path().join("loader/lanzaboote-selftest.txt");
+    fs::create_dir_all(result.parent().unwrap())?;
+    fs::write(&result, "version=1\nload-file2=no\ntcg2=yes\ngop=yes\n")?;
+    let output = install()?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("does not support LoadFile2"));
+    assert!(!esp.path().join("EFI").exists());
+
+    fs::write(&result, "version=1\nload-file2=yes\ntcg2=no\ngop=yes\n")?;
+    let output = install()?;
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr)?.contains("found no TPM"));
+
+    Ok(())
+}
@@ -0,0 +1,152 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+use lanzaboote_tool::pe::{read_section_data, set_cmdline};
+use lanzaboote_tool::signature::KeyPair;
+
+mod common;
+
+use common::verify_signature;
+
+fn key_pair() -> KeyPair {
+    KeyPair::new(
+        Path::new("tests/fixtures/uefi-keys/db.pem"),
+        Path::new("tests/fixtures/uefi-keys/db.key"),
+    )
+}
+
+/// Install a single generation and return the path to its stub.
+fn install_stub(esp: &Path) -> Result<PathBuf> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install(0, esp, [generation_link])?;
+    assert!(output.status.success());
+
+    let stub = fs::read_dir(esp.join("EFI/Linux"))?
+        .next()
+        .expect("No stub installed")?
+        .path();
+    Ok(stub)
+}
+
+/// Replace the command line of the stub and return the new command line with the old and the new
+/// stub.
+fn replace_cmdline(
+    new_cmdline: impl FnOnce(&[u8]) -> String,
+) -> Result<(String, Vec<u8>, Vec<u8>)> {
+    let esp = tempdir()?;
+    let stub = install_stub(esp.path())?;
+    let out = esp.path().join("new.efi");
+    let old = fs::read(&stub)?;
+    let new_cmdline = new_cmdline(read_section_data(&old, ".cmdline").expect("Missing .cmdline"));
+
+    set_cmdline(&key_pair(), &stub, &new_cmdline, &out, None)?;
+    assert!(verify_signature(&out)?);
+
+    Ok((new_cmdline, old, fs::read(out)?))
+}
+
+fn assert_unchanged_sections(old: &[u8], new: &[u8]) {
+    for section in [".osrel", ".linux", ".initrd", ".linuxh", ".initrdh"] {
+        assert_eq!(
+            read_section_data(old, section),
+            read_section_data(new, section),
+            "Section {section} changed"
+        );
+    }
+}
+
+#[test]
+fn replace_cmdline_of_same_size() -> Result<()> {
+    let (new_cmdline, old, new) =
+        replace_cmdline(|old| format!("{:<width$}", "init=init-v1 quiet", width = old.len()))?;
+
+    assert_eq!(
+        read_section_data(&old, ".cmdline").map(<[u8]>::len),
+        Some(new_cmdline.len())
+    );
+    assert_eq!(
+        read_section_data(&new, ".cmdline"),
+        Some(new_cmdline.as_bytes())
+    );
+    assert_unchanged_sections(&old, &new);
+
+    Ok(())
+}
+
+#[test]
+fn replace_cmdline_with_smaller_one() -> Result<()> {
+    let (_, old, new) = replace_cmdline(|_| "init=init-v1".to_string())?;
+
+    assert_eq!(
+        read_section_data(&new, ".cmdline"),
+        Some(&b"init=init-v1"[..])
+    );
+    assert_unchanged_sections(&old, &new);
+
+    Ok(())
+}
+
+#[test]
+fn replace_cmdline_with_larger_one() -> Result<()> {
+    let (new_cmdline, old, new) = replace_cmdline(|old| {
+        format!(
+            "{} {}",
+            String::from_utf8_lossy(old),
+            "console=ttyS0 ".repeat(64)
+        )
+    })?;
+
+    assert_eq!(
+        read_section_data(&new, ".cmdline"),
+        Some(new_cmdline.as_bytes())
+    );
+    assert_unchanged_sections(&old, &new);
+
+    Ok(())
+}
+
+/// A `.cmdline` section whose raw data extends beyond the end of the file is refused instead of
+/// being written out of bounds.
+#[test]
+fn reject_cmdline_section_beyond_end_of_file() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let uki = tmpdir.path().join("uki.efi");
+    let mut file_data = fs::read("tests/fixtures/authenticode/uki.efi")?;
+
+    let pe = goblin::pe::PE::parse(&file_data)?;
+    let index = pe
+        .sections
+        .iter()
+        .position(|section| section.name().ok() == Some(".cmdline"))
+        .expect("Missing .cmdline");
+    let pointer_to_raw_data = usize::try_from(pe.header.dos_header.pe_pointer)?
+        + 24
+        + usize::from(pe.header.coff_header.size_of_optional_header)
+        + 40 * index
+        + 20;
+    let past_end = u32::try_from(file_data.len())? - 4;
+    file_data[pointer_to_raw_data..pointer_to_raw_data + 4]
+        .copy_from_slice(&past_end.to_le_bytes());
+    fs::write(&uki, &file_data)?;
+
+    let err = set_cmdline(
+        &key_pair(),
+        &uki,
+        "quiet",
+        &tmpdir.path().join("new.efi"),
+        None,
+    )
+    .unwrap_err();
+    assert!(
+        format!("{err:#}").contains("extends beyond the end of the file"),
+        "{err:#}"
+    );
+
+    Ok(())
+}
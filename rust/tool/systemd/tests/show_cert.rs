@@ -0,0 +1,87 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use sha2::{Digest, Sha256};
+use tempfile::tempdir;
+use x509_cert::der::{DecodePem, Encode};
+use x509_cert::Certificate;
+
+use lanzaboote_tool::authenticode::{signer_chains, NativeSigner};
+use lanzaboote_tool::signature::{KeyPair, MultiSigner, Signer};
+
+const FIXTURE_UKI: &str = "tests/fixtures/authenticode/uki.efi";
+
+fn key_pair(name: &str) -> KeyPair {
+    KeyPair::new(
+        Path::new(&format!("tests/fixtures/{name}.pem")),
+        Path::new(&format!("tests/fixtures/{name}.key")),
+    )
+}
+
+fn certificate(name: &str) -> Result<Certificate> {
+    Ok(Certificate::from_pem(fs::read(format!(
+        "tests/fixtures/{name}.pem"
+    ))?)?)
+}
+
+#[test]
+fn extract_signer_certificates() -> Result<()> {
+    let out = tempdir()?;
+    let signed = out.path().join("signed.efi");
+    let signer = MultiSigner::new(
+        Box::new(NativeSigner::new(&key_pair("uefi-keys/db"))?),
+        vec![Box::new(NativeSigner::new(&key_pair("pki/good"))?)],
+    );
+    signer.sign_and_copy(Path::new(FIXTURE_UKI), &signed)?;
+
+    let chains = signer_chains(&fs::read(&signed)?)?;
+    assert_eq!(chains.len(), 2);
+    for (chain, name) in chains.iter().zip(["uefi-keys/db", "pki/good"]) {
+        let certificate = certificate(name)?;
+        assert_eq!(chain.len(), 1);
+        assert_eq!(
+            chain[0].subject,
+            certificate.tbs_certificate.subject.to_string()
+        );
+        assert_eq!(
+            chain[0].issuer,
+            certificate.tbs_certificate.issuer.to_string()
+        );
+        assert_eq!(chain[0].fingerprint, Sha256::digest(certificate.to_der()?));
+    }
+
+    let output = Command::cargo_bin("lzbt-systemd")?
+        .arg("show-cert")
+        .arg(&signed)
+        .output()?;
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout)?;
+    let db = certificate("uefi-keys/db")?;
+    assert!(stdout.starts_with(&format!(
+        "Signature 1:\n  Subject: {}\n",
+        db.tbs_certificate.subject
+    )));
+    assert!(stdout.contains(&format!(
+        "  SHA256 fingerprint: {:x}\n",
+        Sha256::digest(db.to_der()?)
+    )));
+    assert!(stdout.contains("\nSignature 2:\n"));
+
+    Ok(())
+}
+
+#[test]
+fn refuse_unsigned_file() -> Result<()> {
+    let out = tempdir()?;
+    let unsigned = out.path().join("unsigned.efi");
+    fs::copy(FIXTURE_UKI, &unsigned)?;
+
+    let output = Command::cargo_bin("lzbt-systemd")?
+        .arg("show-cert")
+        .arg(&unsigned)
+        .output()?;
+    assert!(!output.status.success());
+    Ok(())
+}
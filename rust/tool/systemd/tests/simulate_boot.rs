@@ -0,0 +1,94 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+const EFI_GLOBAL_VARIABLE_GUID: &str = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+
+fn utf16_nul(value: &str) -> Vec<u8> {
+    value
+        .encode_utf16()
+        .chain([0])
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+/// Write an EFI variable of the firmware in efivarfs format.
+fn write_variable(efivars: &Path, name: &str, value: &[u8]) -> Result<()> {
+    let mut data = vec![0x07, 0x00, 0x00, 0x00];
+    data.extend(value);
+    fs::write(
+        efivars.join(format!("{name}-{EFI_GLOBAL_VARIABLE_GUID}")),
+        data,
+    )?;
+    Ok(())
+}
+
+/// An active `EFI_LOAD_OPTION` that boots `file_path`.
+fn load_option(description: &str, file_path: &str) -> Vec<u8> {
+    let path = utf16_nul(file_path);
+    let mut device_path = vec![0x04, 0x04];
+    device_path.extend(u16::try_from(4 + path.len()).unwrap().to_le_bytes());
+    device_path.extend(path);
+    device_path.extend([0x7f, 0xff, 4, 0]);
+
+    let mut data = 1u32.to_le_bytes().to_vec();
+    data.extend(u16::try_from(device_path.len()).unwrap().to_le_bytes());
+    data.extend(utf16_nul(description));
+    data.extend(device_path);
+    data
+}
+
+fn simulate_boot(efivars: &Path, esp: &Path) -> Result<String> {
+    let output = Command::cargo_bin("lzbt-systemd")?
+        .arg("simulate-boot")
+        .arg("--efivars")
+        .arg(efivars)
+        .arg(esp)
+        .output()?;
+    print!("{}", String::from_utf8(output.stderr)?);
+    assert!(output.status.success());
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+#[test]
+fn resolve_boot_order_to_lanzaboote_stub() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+    let output = common::lanzaboote_install(0, esp.path(), [generation_link])?;
+    assert!(output.status.success());
+    let uki = fs::read_dir(esp.path().join("EFI/Linux"))?
+        .next()
+        .unwrap()?
+        .file_name();
+    let uki_path = format!("\\EFI\\LINUX\\{}", uki.to_string_lossy());
+
+    let efivars = tempdir()?;
+    write_variable(efivars.path(), "BootOrder", &[2, 0, 1, 0])?;
+    write_variable(efivars.path(), "Boot0001", &load_option("NixOS", &uki_path))?;
+    assert_eq!(
+        simulate_boot(efivars.path(), esp.path())?,
+        format!("Boot0001 NixOS: {uki_path} (lanzaboote stub)\n")
+    );
+
+    write_variable(
+        efivars.path(),
+        "Boot0002",
+        &load_option(
+            "Windows Boot Manager",
+            "\\EFI\\Microsoft\\Boot\\bootmgfw.efi",
+        ),
+    )?;
+    assert_eq!(
+        simulate_boot(efivars.path(), esp.path())?,
+        "Boot0002 Windows Boot Manager: \\EFI\\Microsoft\\Boot\\bootmgfw.efi (missing on the ESP)\n"
+    );
+
+    Ok(())
+}
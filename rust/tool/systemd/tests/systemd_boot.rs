@@ -1,4 +1,5 @@
 use std::fs;
+use std::os::unix::fs::MetadataExt;
 use std::path::PathBuf;
 
 use anyhow::Result;
@@ -114,6 +115,42 @@ fn overwrite_unsigned_systemd_boot_binaries() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn update_only_changed_loader_config() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+    let loader_config_path = esp.path().join("loader/loader.conf");
+
+    let output0 = common::lanzaboote_install(0, esp.path(), vec![&generation_link])?;
+    assert!(output0.status.success());
+    let loader_config = fs::read(&loader_config_path)?;
+
+    // Files are installed by renaming a new file into place, so an unchanged inode means that the
+    // file was not written again.
+    let inode0 = fs::metadata(&loader_config_path)?.ino();
+    let output1 = common::lanzaboote_install(0, esp.path(), vec![&generation_link])?;
+    assert!(output1.status.success());
+    assert_eq!(
+        inode0,
+        fs::metadata(&loader_config_path)?.ino(),
+        "Unchanged loader.conf was written again."
+    );
+
+    fs::write(&loader_config_path, "timeout 10\n")?;
+    let output2 = common::lanzaboote_install(0, esp.path(), vec![generation_link])?;
+    assert!(output2.status.success());
+    assert_eq!(
+        fs::read(&loader_config_path)?,
+        loader_config,
+        "Changed loader.conf was not replaced."
+    );
+
+    Ok(())
+}
+
 fn systemd_boot_path(esp: &tempfile::TempDir) -> PathBuf {
     let arch = Architecture::from_nixos_system(SYSTEM).unwrap();
     esp.path()
@@ -127,3 +164,43 @@ fn systemd_boot_fallback_path(esp: &tempfile::TempDir) -> PathBuf {
         .join("EFI/BOOT/")
         .join(arch.efi_fallback_filename())
 }
+
+#[test]
+fn stub_only_leaves_ukis_untouched() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let systemd_boot_path = systemd_boot_path(&esp);
+    let systemd_boot_fallback_path = systemd_boot_fallback_path(&esp);
+
+    let output0 = common::lanzaboote_install(0, esp.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let linux = esp.path().join("EFI/Linux");
+    let ukis0 = fs::read_dir(&linux)?
+        .map(|e| e.map(|e| (e.path(), hash_file(&e.path()))))
+        .collect::<Result<Vec<_>, _>>()?;
+    fs::remove_file(&systemd_boot_path)?;
+    fs::remove_file(&systemd_boot_fallback_path)?;
+
+    // Without generations, a full installation would garbage collect the UKIs.
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        Vec::<PathBuf>::new(),
+        ["--stub-only"],
+    )?;
+    assert!(output1.status.success());
+
+    assert!(verify_signature(&systemd_boot_path)?);
+    assert!(verify_signature(&systemd_boot_fallback_path)?);
+    let ukis1 = fs::read_dir(&linux)?
+        .map(|e| e.map(|e| (e.path(), hash_file(&e.path()))))
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(ukis0, ukis1, "UKIs were modified by --stub-only.");
+
+    Ok(())
+}
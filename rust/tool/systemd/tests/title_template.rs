@@ -0,0 +1,101 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+/// Render the title of generation 3 with `template` by exporting its boot entry.
+fn render_title(generation_link: &Path, template: &str) -> Result<String> {
+    let esp = tempdir()?;
+    let export = tempdir()?;
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        [
+            "--export".as_ref(),
+            export.path().as_os_str(),
+            "--title-template".as_ref(),
+            template.as_ref(),
+        ],
+    )?;
+    assert!(output.status.success());
+
+    let entry = fs::read_dir(export.path().join("entries"))?
+        .next()
+        .expect("No boot entry was exported")?;
+    let entry = fs::read_to_string(entry.path())?;
+    let title = entry
+        .lines()
+        .find_map(|line| line.strip_prefix("title "))
+        .expect("The boot entry has no title");
+    Ok(title.to_string())
+}
+
+#[test]
+fn render_titles_from_templates() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 3)?;
+
+    for (template, title) in [
+        ("NixOS {generation} ({date})", "NixOS 3 (1970-01-01)"),
+        ("{label} - {profile} {generation}", "LanzaOS - system 3"),
+        ("Linux {kernel}", "Linux 6.1.1"),
+        ("{{{generation}}}", "{3}"),
+    ] {
+        assert_eq!(render_title(&generation_link, template)?, title);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn title_template_changes_installed_title() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link],
+        ["--title-template", "NixOS {generation} ({date})"],
+    )?;
+    assert!(output.status.success());
+
+    let stub = fs::read_dir(esp.path().join("EFI/Linux"))?
+        .next()
+        .expect("No stub was installed")?;
+    let stub_data = fs::read(stub.path())?;
+    let os_release = lanzaboote_tool::pe::read_section_data(&stub_data, ".osrel")
+        .expect("The stub has no .osrel section");
+    assert!(String::from_utf8_lossy(os_release).contains("PRETTY_NAME=NixOS 1 (1970-01-01)\n"));
+
+    Ok(())
+}
+
+#[test]
+fn reject_unknown_placeholder() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [generation_link],
+        ["--title-template", "NixOS {version}"],
+    )?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(stderr.contains("Unknown placeholder {version}"));
+    // Nothing is installed.
+    assert_eq!(common::count_files(esp.path())?, 0);
+
+    Ok(())
+}
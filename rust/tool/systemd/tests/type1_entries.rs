@@ -0,0 +1,169 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use filetime::FileTime;
+use tempfile::tempdir;
+
+mod common;
+
+/// The file in `EFI/nixos` whose name starts with `prefix`.
+fn nixos_file(esp: &Path, prefix: &str) -> Result<String> {
+    fs::read_dir(esp.join("EFI/nixos"))?
+        .map(|entry| Ok(entry?.file_name().into_string().unwrap()))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .find(|name| name.starts_with(prefix))
+        .with_context(|| format!("No {prefix} file was installed"))
+}
+
+/// The Type #1 entries in `loader/entries`, sorted by name.
+fn entries(esp: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = fs::read_dir(esp.join("loader/entries"))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    entries.sort();
+    Ok(entries)
+}
+
+#[test]
+fn write_split_entry() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link],
+        ["--type1-entries", "split"],
+    )?;
+    assert!(output.status.success());
+
+    let image = common::image_path(&esp, 1, &toplevel)?;
+    let entry = esp
+        .path()
+        .join("loader/entries")
+        .join(image.with_extension("conf").file_name().unwrap());
+    let kernel = nixos_file(esp.path(), "kernel-")?;
+    let initrd = nixos_file(esp.path(), "initrd-")?;
+    assert_eq!(
+        fs::read_to_string(entry)?,
+        format!(
+            "# Installed by lzbt. Changes are overwritten.\n\
+             title LanzaOS (Generation 1, 1970-01-01)\n\
+             version Generation 1, 1970-01-01\n\
+             linux /EFI/nixos/{kernel}\n\
+             initrd /EFI/nixos/{initrd}\n\
+             options init=init-v1 amd_iommu=on amd_iommu=pt iommu=pt kvm.ignore_msrs=1 \
+             kvm.report_ignored_msrs=0 udev.log_priority=3 systemd.unified_cgroup_hierarchy=1 \
+             loglevel=4\n"
+        )
+    );
+    // The UKI is installed as well.
+    assert!(image.exists());
+    Ok(())
+}
+
+#[test]
+fn collect_uki_entries_of_removed_generations() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link1 =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let generation_link2 =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link1, &generation_link2],
+        ["--type1-entries", "uki"],
+    )?;
+    assert!(output.status.success());
+    let image2 = common::image_path(&esp, 2, &toplevel)?;
+    let entry2 = esp
+        .path()
+        .join("loader/entries")
+        .join(image2.with_extension("conf").file_name().unwrap());
+    assert_eq!(entries(esp.path())?.len(), 2);
+    let contents = fs::read_to_string(&entry2)?;
+    assert!(
+        contents.contains(&format!(
+            "\nefi /{}\n",
+            image2.strip_prefix(esp.path())?.display()
+        )),
+        "{contents}"
+    );
+
+    // Entries that lzbt did not write are left alone.
+    let foreign = esp.path().join("loader/entries/debian.conf");
+    fs::write(&foreign, "title Debian\nlinux /vmlinuz\n")?;
+
+    let output = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link2],
+        ["--type1-entries", "uki"],
+    )?;
+    assert!(output.status.success());
+    assert_eq!(entries(esp.path())?, [foreign, entry2]);
+    Ok(())
+}
+
+/// The contents of the files in `loader`, by path relative to the ESP.
+fn loader_files(esp: &Path) -> Result<Vec<(PathBuf, Vec<u8>)>> {
+    let mut files = entries(esp)?;
+    files.push(esp.join("loader/loader.conf"));
+    files
+        .into_iter()
+        .map(|path| Ok((path.strip_prefix(esp)?.to_path_buf(), fs::read(&path)?)))
+        .collect()
+}
+
+#[test]
+fn render_entries_deterministically() -> Result<()> {
+    let esp1 = tempdir()?;
+    let esp2 = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_links = [1, 2]
+        .into_iter()
+        .map(|version| {
+            common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), version)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let args = ["--type1-entries", "uki", "--canonical-loader-conf"];
+
+    let output = common::lanzaboote_install_with_args(0, esp1.path(), &generation_links, args)?;
+    assert!(output.status.success());
+    let files = loader_files(esp1.path())?;
+    assert_eq!(files.len(), 3);
+    for (path, _) in &files {
+        filetime::set_file_mtime(esp1.path().join(path), FileTime::zero())?;
+    }
+
+    // Installing the same generations again does not touch the files.
+    let output = common::lanzaboote_install_with_args(0, esp1.path(), &generation_links, args)?;
+    assert!(output.status.success());
+    assert_eq!(loader_files(esp1.path())?, files);
+    for (path, _) in &files {
+        assert_eq!(
+            common::mtime(&esp1.path().join(path)),
+            0,
+            "{path:?} was rewritten"
+        );
+    }
+
+    // Another ESP gets the same files.
+    let output = common::lanzaboote_install_with_args(0, esp2.path(), &generation_links, args)?;
+    assert!(output.status.success());
+    assert_eq!(loader_files(esp2.path())?, files);
+    Ok(())
+}
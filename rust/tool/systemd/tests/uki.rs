@@ -0,0 +1,155 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use lanzaboote_tool::os_release::MissingOsRelease;
+use lanzaboote_tool::pe;
+use lanzaboote_tool::signature::KeyPair;
+use lanzaboote_tool::uki::{build_uki, inspect_uki, sign_pe, verify_uki, UkiConfig};
+use tempfile::tempdir;
+
+mod common;
+
+#[test]
+fn build_sign_and_verify_uki() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let esp = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let store_path = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1");
+    let os_release = tmpdir.path().join("os-release");
+    fs::write(&os_release, "ID=nixos\n")?;
+
+    // The kernel of the fixture is a copy of the systemd stub, which can stand in for the
+    // lanzaboote stub.
+    let config = UkiConfig {
+        stub: store_path.join("kernel"),
+        os_release,
+        kernel_cmdline: vec!["init=/init".into(), "quiet".into()],
+        kernel: store_path.join("kernel"),
+        kernel_target: esp.path().join("EFI/nixos/kernel.efi"),
+        initrd: Some(store_path.join("initrd")),
+        initrd_target: Some(esp.path().join("EFI/nixos/initrd.efi")),
+        esp: esp.path().to_path_buf(),
+        ..Default::default()
+    };
+    let unsigned = tmpdir.path().join("unsigned.efi");
+    let signed = tmpdir.path().join("signed.efi");
+    let certificate = Path::new("tests/fixtures/uefi-keys/db.pem");
+
+    build_uki(&config, &unsigned)?;
+    assert!(!verify_uki(&unsigned, certificate)?);
+
+    let key_pair = KeyPair::new(certificate, Path::new("tests/fixtures/uefi-keys/db.key"));
+    sign_pe(&key_pair, &unsigned, &signed)?;
+    assert!(verify_uki(&signed, certificate)?);
+    assert!(!verify_uki(
+        &signed,
+        Path::new("tests/fixtures/pki/good.pem")
+    )?);
+
+    let info = inspect_uki(&signed)?;
+    assert_eq!(info.cmdline.as_deref(), Some("init=/init quiet"));
+    assert_eq!(info.kernel.as_deref(), Some("\\EFI\\nixos\\kernel.efi"));
+    assert_eq!(info.initrd.as_deref(), Some("\\EFI\\nixos\\initrd.efi"));
+    assert!(info.sections.iter().any(|(name, _)| name == ".osrel"));
+
+    Ok(())
+}
+
+#[test]
+fn align_linux_section() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let esp = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let store_path = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1");
+    let os_release = tmpdir.path().join("os-release");
+    fs::write(&os_release, "ID=nixos\n")?;
+    let alignment = 2 * 1024 * 1024;
+
+    let config = UkiConfig {
+        stub: store_path.join("kernel"),
+        os_release,
+        kernel_cmdline: vec!["init=/init".into()],
+        kernel: store_path.join("kernel"),
+        kernel_target: esp.path().join("EFI/nixos/kernel.efi"),
+        initrd: Some(store_path.join("initrd")),
+        initrd_target: Some(esp.path().join("EFI/nixos/initrd.efi")),
+        esp: esp.path().to_path_buf(),
+        linux_alignment: Some(alignment),
+        ..Default::default()
+    };
+    let unsigned = tmpdir.path().join("unsigned.efi");
+    build_uki(&config, &unsigned)?;
+
+    let file_data = fs::read(&unsigned)?;
+    let pe = goblin::pe::PE::parse(&file_data)?;
+    let linux = pe
+        .sections
+        .iter()
+        .find(|s| s.name().ok() == Some(".linux"))
+        .unwrap();
+    assert_eq!(u64::from(linux.pointer_to_raw_data) % alignment, 0);
+    assert_eq!(u64::from(linux.virtual_address) % alignment, 0);
+    // The contents are not affected by the padding in front of the section.
+    assert_eq!(
+        inspect_uki(&unsigned)?.kernel.as_deref(),
+        Some("\\EFI\\nixos\\kernel.efi")
+    );
+
+    // Sections must not overlap in memory.
+    let mut ranges = pe
+        .sections
+        .iter()
+        .map(|s| (s.virtual_address, s.virtual_address + s.virtual_size))
+        .collect::<Vec<_>>();
+    ranges.sort();
+    assert!(ranges.windows(2).all(|w| w[0].1 <= w[1].0));
+
+    let config = UkiConfig {
+        linux_alignment: Some(3 * 1024 * 1024),
+        ..config
+    };
+    let error = build_uki(&config, &unsigned).unwrap_err();
+    assert!(
+        format!("{error:#}").contains("must be a power of two"),
+        "{error:#}"
+    );
+    Ok(())
+}
+
+#[test]
+fn synthesize_missing_os_release() -> Result<()> {
+    let tmpdir = tempdir()?;
+    let esp = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let store_path = toplevel.join("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-6.1.1");
+
+    let config = UkiConfig {
+        stub: store_path.join("kernel"),
+        os_release: tmpdir.path().join("missing-os-release"),
+        kernel_cmdline: vec!["init=/init".into()],
+        kernel: store_path.join("kernel"),
+        kernel_target: esp.path().join("EFI/nixos/kernel.efi"),
+        esp: esp.path().to_path_buf(),
+        ..Default::default()
+    };
+    let unsigned = tmpdir.path().join("unsigned.efi");
+    build_uki(&config, &unsigned)?;
+
+    let file_data = fs::read(&unsigned)?;
+    let os_release = pe::read_section_data(&file_data, ".osrel").unwrap();
+    assert_eq!(
+        std::str::from_utf8(os_release)?,
+        "ID=lanza\nNAME=Linux\nPRETTY_NAME=Linux\n"
+    );
+
+    let config = UkiConfig {
+        missing_os_release: MissingOsRelease::Fail,
+        ..config
+    };
+    fs::remove_file(&unsigned)?;
+    let error = build_uki(&config, &unsigned).unwrap_err();
+    assert!(format!("{error:#}").contains("does not exist"), "{error:#}");
+    assert!(!unsigned.exists());
+    Ok(())
+}
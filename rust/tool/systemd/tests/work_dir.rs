@@ -0,0 +1,220 @@
+use std::cell::RefCell;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use anyhow::{Context, Result};
+use tempfile::tempdir;
+
+use lanzaboote_tool::pe::set_cmdline;
+use lanzaboote_tool::signature::{MultiSigner, Signer};
+
+mod common;
+
+/// Install an sbsign wrapper into `bin` that logs its arguments to `log`.
+///
+/// With `fail`, the wrapper fails after writing the signed file.
+fn install_logging_sbsign(bin: &Path, log: &Path, fail: bool) -> Result<OsString> {
+    let sbsign = env::split_paths(&env::var_os("PATH").unwrap_or_default())
+        .map(|dir| dir.join("sbsign"))
+        .find(|path| path.is_file())
+        .context("sbsign is not on PATH")?;
+
+    let wrapper = bin.join("sbsign");
+    let run = if fail {
+        format!(r#"{} "$@"; exit 1"#, sbsign.display())
+    } else {
+        format!(r#"exec {} "$@""#, sbsign.display())
+    };
+    fs::write(
+        &wrapper,
+        format!(
+            "#!/bin/sh\nprintf '%s\\n' \"$@\" >> {}\n{run}\n",
+            log.display()
+        ),
+    )?;
+    fs::set_permissions(&wrapper, fs::Permissions::from_mode(0o755))?;
+
+    Ok(env::join_paths([bin.to_path_buf()].into_iter().chain(
+        env::split_paths(&env::var_os("PATH").unwrap_or_default()),
+    ))?)
+}
+
+/// Whether sbsign was asked to sign a file in `dir`.
+fn signed_file_in(log: &Path, dir: &Path) -> Result<bool> {
+    Ok(fs::read_to_string(log)?
+        .lines()
+        .any(|arg| Path::new(arg).starts_with(dir)))
+}
+
+/// The absolute paths sbsign was given that are in none of `dirs`.
+fn paths_outside(log: &Path, dirs: &[&Path]) -> Result<Vec<String>> {
+    Ok(fs::read_to_string(log)?
+        .lines()
+        .filter(|arg| arg.starts_with('/'))
+        .filter(|arg| !dirs.iter().any(|dir| Path::new(arg).starts_with(dir)))
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Copies binaries instead of signing them and records which ones it was asked to sign.
+#[derive(Clone, Default)]
+struct RecordingSigner(Rc<RefCell<Vec<PathBuf>>>);
+
+impl RecordingSigner {
+    fn signed(&self) -> Vec<PathBuf> {
+        self.0.borrow().clone()
+    }
+}
+
+impl Signer for RecordingSigner {
+    fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
+        self.0.borrow_mut().push(from.to_path_buf());
+        fs::copy(from, to)?;
+        Ok(())
+    }
+
+    fn add_signature(&self, from: &Path, to: &Path) -> Result<()> {
+        self.sign_and_copy(from, to)
+    }
+}
+
+#[test]
+fn keep_intermediates_in_work_dir() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let bin = tempdir()?;
+    let work_dir = tempdir()?;
+    let log = bin.path().join("sbsign.log");
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let path = install_logging_sbsign(bin.path(), &log, false)?;
+    let output = common::lanzaboote_install_with_path(
+        0,
+        esp.path(),
+        [generation_link],
+        &path,
+        [OsString::from("--work-dir"), work_dir.path().into()],
+    )?;
+    assert!(output.status.success());
+
+    assert!(signed_file_in(&log, work_dir.path())?);
+    assert_eq!(fs::read_dir(work_dir.path())?.count(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn remove_intermediates_on_failure() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let bin = tempdir()?;
+    let work_dir = tempdir()?;
+    let log = bin.path().join("sbsign.log");
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let path = install_logging_sbsign(bin.path(), &log, true)?;
+    let output = common::lanzaboote_install_with_path(
+        0,
+        esp.path(),
+        [generation_link],
+        &path,
+        [OsString::from("--work-dir"), work_dir.path().into()],
+    )?;
+    assert!(!output.status.success());
+
+    assert!(signed_file_in(&log, work_dir.path())?);
+    assert_eq!(fs::read_dir(work_dir.path())?.count(), 0);
+    // The partially signed stub is not left behind on the ESP either.
+    assert_eq!(common::count_files(&esp.path().join("EFI/Linux"))?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn keep_intermediates_of_additional_signatures_in_work_dir() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let bin = tempdir()?;
+    let work_dir = tempdir()?;
+    let log = bin.path().join("sbsign.log");
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let path = install_logging_sbsign(bin.path(), &log, false)?;
+    let output = common::lanzaboote_install_with_path(
+        0,
+        esp.path(),
+        [generation_link],
+        &path,
+        [
+            OsString::from("--work-dir"),
+            work_dir.path().into(),
+            "--additional-key".into(),
+            "tests/fixtures/pki/good.key".into(),
+            "--additional-cert".into(),
+            "tests/fixtures/pki/good.pem".into(),
+        ],
+    )?;
+    assert!(output.status.success());
+
+    // Besides the inputs of the generation, systemd-boot and the ESP, sbsign is only given
+    // intermediates. The binaries signed by only the first key are intermediates as well.
+    let systemd = PathBuf::from(common::systemd_location_from_env()?);
+    let inputs = [tmpdir.path(), &systemd, esp.path(), work_dir.path()];
+    assert_eq!(paths_outside(&log, &inputs)?, Vec::<String>::new());
+    assert_eq!(fs::read_dir(work_dir.path())?.count(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn keep_partially_signed_binaries_in_work_dir() -> Result<()> {
+    let dir = tempdir()?;
+    let work_dir = tempdir()?;
+    let uki = Path::new("tests/fixtures/authenticode/uki.efi");
+    let (first, second) = (RecordingSigner::default(), RecordingSigner::default());
+    let signer = MultiSigner::new(Box::new(first.clone()), vec![Box::new(second.clone())])
+        .with_work_dir(Some(work_dir.path()));
+
+    signer.sign_and_copy(uki, &dir.path().join("signed.efi"))?;
+
+    assert_eq!(first.signed(), [uki]);
+    let [partially_signed] = &second.signed()[..] else {
+        panic!("Expected a single binary signed by the second key");
+    };
+    assert!(partially_signed.starts_with(work_dir.path()));
+    assert_eq!(fs::read_dir(work_dir.path())?.count(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn keep_set_cmdline_intermediates_in_work_dir() -> Result<()> {
+    let dir = tempdir()?;
+    let work_dir = tempdir()?;
+    let signer = RecordingSigner::default();
+
+    // The command line does not fit into the existing section, so the UKI is rebuilt.
+    let cmdline = "quiet ".repeat(1000);
+    set_cmdline(
+        &signer,
+        Path::new("tests/fixtures/authenticode/uki.efi"),
+        &cmdline,
+        &dir.path().join("new.efi"),
+        Some(work_dir.path()),
+    )?;
+
+    let [unsigned] = &signer.signed()[..] else {
+        panic!("Expected a single signed binary");
+    };
+    assert!(unsigned.starts_with(work_dir.path()));
+    assert_eq!(fs::read_dir(work_dir.path())?.count(), 0);
+
+    Ok(())
+}
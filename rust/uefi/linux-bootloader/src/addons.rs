@@ -0,0 +1,226 @@
+//! Append the command lines of signed addons on the ESP.
+//!
+//! systemd defines addons: PE binaries, signed separately from the UKI,
+//! whose `.cmdline` section is appended to the command line. They let
+//! users add kernel parameters without rebuilding the UKI. A UKI with an
+//! `.addons` section picks up the addons named `*.addon.efi` in
+//! `\loader\addons`, which apply to all UKIs, followed by those in
+//! `<UKI>.extra.d` next to the UKI, which only apply to it. Within a
+//! directory, addons are applied in the order of their names.
+//!
+//! Every addon is loaded with the firmware's `LoadImage`, which verifies
+//! its signature if Secure Boot is enabled. Addons that fail to load are
+//! ignored, as are addons built for another architecture. So are UKIs,
+//! which have a `.linux` section: their command line is only meant to
+//! boot their own kernel.
+//!
+//! The command line of every addon is measured into PCR 12 before it is
+//! appended, see [`measure_addon_cmdlines`].
+//!
+//! [`addon_cmdlines`] does not depend on the firmware, [`UefiAddons`]
+//! implements [`AddonServices`] with the boot services.
+
+use alloc::{format, string::String, string::ToString, vec::Vec};
+
+use log::{info, warn};
+use uefi::{
+    cstr16,
+    fs::FileSystem,
+    prelude::*,
+    proto::{loaded_image::LoadedImage, media::file::FileAttribute},
+    table::boot::LoadImageSource,
+    CStr16, CString16, Result,
+};
+
+use crate::cmdline::{check_cmdline, CmdlinePolicy};
+use crate::measure::{Measurements, Measurer, TPM_PCR_INDEX_KERNEL_CONFIG};
+use crate::pe_section::{pe_section, NATIVE_MACHINE};
+use crate::uefi_helpers::image_volume_path;
+
+/// The directory of the addons that apply to all UKIs.
+pub const GLOBAL_ADDONS_DIRECTORY: &CStr16 = cstr16!("\\loader\\addons");
+
+/// The suffix of the file names of addons, compared case-insensitively.
+const ADDON_SUFFIX: &str = ".addon.efi";
+
+/// The sections of an addon that the stub looks at.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Addon {
+    /// The contents of the `.cmdline` section.
+    pub cmdline: Option<String>,
+    /// Whether the addon has a `.linux` section, i.e. is a UKI.
+    pub has_kernel: bool,
+}
+
+/// What the firmware does to find and load addons.
+pub trait AddonServices {
+    /// The names of the files in `directory` on the volume of the
+    /// running image. Fails with `NOT_FOUND` if there is no such
+    /// directory.
+    fn list_files(&self, directory: &CStr16) -> Result<Vec<CString16>>;
+
+    /// Load and verify the addon at `path` on the volume of the running
+    /// image, read its sections and unload it again.
+    fn load_addon(&self, path: &CStr16) -> Result<Addon>;
+}
+
+/// Whether the `.addons` section enables addons.
+pub fn addons_enabled(section: Option<&str>) -> bool {
+    section.map(str::trim) == Some("cmdline")
+}
+
+/// The directories that addons are picked up from, in the order in
+/// which they are applied.
+///
+/// `image_path` is the path of the running image, e.g.
+/// `\EFI\Linux\nixos-generation-1.efi`. If it is unknown, only the
+/// global addons are picked up.
+pub fn addon_directories(image_path: Option<&CStr16>) -> Vec<CString16> {
+    let mut directories = Vec::from([CString16::from(GLOBAL_ADDONS_DIRECTORY)]);
+    if let Some(directory) =
+        image_path.and_then(|path| CString16::try_from(format!("{path}.extra.d").as_str()).ok())
+    {
+        directories.push(directory);
+    }
+    directories
+}
+
+/// The paths of the addons in `directory`, in the order of their names.
+fn addon_paths(services: &impl AddonServices, directory: &CStr16) -> Vec<CString16> {
+    let mut names = match services.list_files(directory) {
+        Ok(names) => names,
+        Err(err) if err.status() == Status::NOT_FOUND => return Vec::new(),
+        Err(err) => {
+            warn!("Failed to list the addons in {directory}: {err:?}");
+            return Vec::new();
+        }
+    };
+    names.retain(|name| {
+        name.to_string()
+            .to_ascii_lowercase()
+            .ends_with(ADDON_SUFFIX)
+    });
+    names.sort_by_key(|name| name.to_string());
+    names
+        .iter()
+        .filter_map(|name| CString16::try_from(format!("{directory}\\{name}").as_str()).ok())
+        .collect()
+}
+
+/// The command lines of all addons that apply to the running image, in
+/// the order in which they are appended.
+pub fn addon_cmdlines(services: &impl AddonServices, image_path: Option<&CStr16>) -> Vec<String> {
+    let mut cmdlines = Vec::new();
+    for directory in addon_directories(image_path) {
+        for path in addon_paths(services, &directory) {
+            let addon = match services.load_addon(&path) {
+                Ok(addon) => addon,
+                Err(err) => {
+                    warn!("Ignoring addon {path} that failed to load: {err:?}");
+                    continue;
+                }
+            };
+            if addon.has_kernel {
+                warn!("Ignoring addon {path}, it is a UKI.");
+                continue;
+            }
+            // The kernel only needs spaces between parameters, the newline
+            // at the end of the section is dropped.
+            let Some(Ok(cmdline)) = addon
+                .cmdline
+                .map(|cmdline| check_cmdline(cmdline.trim(), CmdlinePolicy::Sanitize))
+            else {
+                continue;
+            };
+            if !cmdline.is_empty() {
+                info!("Appending {cmdline:?} from addon {path} to the command line.");
+                cmdlines.push(cmdline);
+            }
+        }
+    }
+    cmdlines
+}
+
+/// Measure the addon command lines in `cmdlines` into PCR 12, in the
+/// order in which they are appended.
+pub fn measure_addon_cmdlines(
+    cmdlines: &[String],
+    measurer: &mut impl Measurer,
+    measurements: &mut Measurements,
+) -> Result<()> {
+    for cmdline in cmdlines {
+        measurements.measure(
+            &mut *measurer,
+            TPM_PCR_INDEX_KERNEL_CONFIG,
+            cmdline.as_bytes(),
+            "addon .cmdline",
+        )?;
+    }
+    Ok(())
+}
+
+/// Finds addons with the simple file system protocol and loads them
+/// with the boot services.
+pub struct UefiAddons<'a> {
+    boot_services: &'a BootServices,
+}
+
+impl<'a> UefiAddons<'a> {
+    pub fn new(boot_services: &'a BootServices) -> Self {
+        Self { boot_services }
+    }
+
+    /// Read the sections of a loaded addon.
+    fn read_addon(&self, image: Handle) -> Result<Addon> {
+        let loaded_image = self
+            .boot_services
+            .open_protocol_exclusive::<LoadedImage>(image)?;
+        let (image_base, image_size) = loaded_image.info();
+        // SAFETY: The firmware keeps the image mapped until it is
+        // unloaded, which happens after the protocol is closed.
+        let data = unsafe {
+            core::slice::from_raw_parts(
+                image_base as *const u8,
+                usize::try_from(image_size).map_err(|_| Status::INVALID_PARAMETER)?,
+            )
+        };
+        Ok(Addon {
+            cmdline: pe_section(data, ".cmdline", Some(NATIVE_MACHINE))?
+                .map(|section| String::from_utf8_lossy(section).into_owned()),
+            has_kernel: pe_section(data, ".linux", None)?.is_some(),
+        })
+    }
+}
+
+impl AddonServices for UefiAddons<'_> {
+    fn list_files(&self, directory: &CStr16) -> Result<Vec<CString16>> {
+        let file_system = self
+            .boot_services
+            .get_image_file_system(self.boot_services.image_handle())?;
+        let mut file_system = FileSystem::new(file_system);
+        let entries = file_system
+            .read_dir(directory)
+            .map_err(|_| Status::NOT_FOUND)?;
+        Ok(entries
+            .filter_map(|entry| entry.ok())
+            .filter(|info| !info.attribute().contains(FileAttribute::DIRECTORY))
+            .map(|info| CString16::from(info.file_name()))
+            .collect())
+    }
+
+    fn load_addon(&self, path: &CStr16) -> Result<Addon> {
+        let mut buffer = Vec::new();
+        let device_path = image_volume_path(self.boot_services, path, &mut buffer)?;
+        let image = self.boot_services.load_image(
+            self.boot_services.image_handle(),
+            LoadImageSource::FromDevicePath {
+                device_path,
+                from_boot_manager: false,
+            },
+        )?;
+
+        let addon = self.read_addon(image);
+        self.boot_services.unload_image(image)?;
+        addon
+    }
+}
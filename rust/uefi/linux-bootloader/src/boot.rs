@@ -0,0 +1,380 @@
+//! Verify and load the kernel and initrd referenced by the thin stub.
+//!
+//! The thin stub only embeds the paths of the kernel and initrd on the
+//! ESP and their hashes. [`boot`] reads this configuration from the
+//! running image, reads and verifies the kernel and initrd, composes the
+//! command line and loads the kernel, but does not start it. The file
+//! referenced by a `.irdpath` section is appended to the initrd, see
+//! [`crate::initrd_path`].
+//!
+//! [`boot`] does not depend on the firmware. Everything it needs from
+//! the firmware goes through [`BootFirmware`], which the stub implements
+//! with the boot services.
+
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
+use log::{error, info};
+use sha2::{Digest, Sha256};
+use uefi::{CStr16, CString16, Result, Status};
+
+use crate::addons::addons_enabled;
+use crate::cmdline::{check_cmdline, CmdlinePolicy};
+use crate::initrd_path::{load_initrd_path, measure_initrd_path, InitrdFiles, InitrdPath};
+use crate::initrd_verification::{
+    check_digest, verify_with_retry, InitrdVerification, VerificationRetry,
+};
+use crate::linux_loader::{InitrdFile, InitrdSource};
+use crate::load_options::LoadOptionsMode;
+use crate::measure::{Measurements, Measurer};
+use crate::pe_section::{check_pe_machine, pe_section, pe_section_as_string, NATIVE_MACHINE};
+use crate::section_hashes::SectionHashes;
+use crate::self_hash_sections::SELF_HASH_SECTIONS;
+use crate::tpm_nv::parse_nv_index;
+
+/// The size of a SHA-256 hash.
+const HASH_SIZE: usize = 32;
+
+/// The configuration that is embedded at build time.
+///
+/// After the stub is built, lzbt needs to embed configuration into the binary by adding PE
+/// sections. This struct represents that information.
+pub struct EmbeddedConfiguration {
+    /// The filename of the kernel to be booted. This filename is
+    /// relative to the root of the volume that contains the
+    /// lanzaboote binary.
+    pub kernel_filename: CString16,
+
+    /// The cryptographic hash of the kernel.
+    pub kernel_hash: [u8; HASH_SIZE],
+
+    /// The filename of the initrd to be passed to the kernel and its
+    /// cryptographic hash. See `kernel_filename` for how to interpret
+    /// these filenames. The hash is computed over the whole PE binary,
+    /// not only the embedded initrd.
+    ///
+    /// This is `None` for kernels that boot without an initrd.
+    pub initrd: Option<(CString16, [u8; HASH_SIZE])>,
+
+    /// Whether the initrd is verified without Secure Boot.
+    pub initrd_verification: InitrdVerification,
+
+    /// Whether a failed verification of the initrd is retried.
+    pub initrd_retry: VerificationRetry,
+
+    /// The file that is appended to the initrd, see
+    /// [`crate::initrd_path`].
+    pub initrd_path: Option<InitrdPath>,
+
+    /// The prefix of the SMBIOS OEM string to append to the command
+    /// line without Secure Boot.
+    pub smbios_prefix: Option<String>,
+
+    /// What the load options of the kernel contain.
+    pub load_options: LoadOptionsMode,
+
+    /// Whether the command lines of addons on the ESP are appended.
+    pub addons: bool,
+
+    /// The TPM NV index with boot parameters, if any.
+    pub tpm_nv_index: Option<u32>,
+
+    /// The kernel command-line, from the section chosen in the boot
+    /// menu.
+    pub cmdline: CString16,
+}
+
+impl EmbeddedConfiguration {
+    /// Read the configuration, with the command line from
+    /// `cmdline_section`.
+    pub fn new(file_data: &[u8], cmdline_section: &str) -> Result<Self> {
+        Ok(Self {
+            kernel_filename: extract_string(file_data, ".linux")?,
+            kernel_hash: extract_hash(file_data, ".linuxh")?,
+
+            initrd: match pe_section(file_data, ".initrd", Some(NATIVE_MACHINE))? {
+                Some(_) => Some((
+                    extract_string(file_data, ".initrd")?,
+                    extract_hash(file_data, ".initrdh")?,
+                )),
+                None => None,
+            },
+            initrd_verification: pe_section_as_string(file_data, ".initrdv")
+                .and_then(|value| InitrdVerification::parse(&value))
+                .unwrap_or_default(),
+            initrd_retry: pe_section_as_string(file_data, ".initrdr")
+                .and_then(|value| VerificationRetry::parse(&value))
+                .unwrap_or_default(),
+            initrd_path: pe_section_as_string(file_data, ".irdpath")
+                .map(|section| InitrdPath::parse(&section))
+                .transpose()?,
+            smbios_prefix: pe_section_as_string(file_data, ".smbios"),
+            load_options: pe_section_as_string(file_data, ".loadopt")
+                .and_then(|value| LoadOptionsMode::parse(&value))
+                .unwrap_or_default(),
+            addons: addons_enabled(pe_section_as_string(file_data, ".addons").as_deref()),
+            tpm_nv_index: pe_section_as_string(file_data, ".tpmnv")
+                .and_then(|section| parse_nv_index(&section)),
+
+            cmdline: extract_cmdline(file_data, cmdline_section)?,
+        })
+    }
+}
+
+/// What the firmware does to boot the kernel.
+///
+/// The file referenced by `.irdpath` is read with [`InitrdFiles`] and
+/// measured with [`Measurer`].
+pub trait BootFirmware: InitrdFiles + Measurer {
+    /// A kernel that is loaded and ready to be started.
+    type Kernel;
+
+    /// Whether Secure Boot is active.
+    fn secure_boot(&self) -> bool;
+
+    /// Read the file at `path` on the volume of the running image.
+    fn read_file(&mut self, path: &CStr16) -> Result<Vec<u8>>;
+
+    /// Open the file at `path` on the volume of the running image to
+    /// read it later, and return it with its size.
+    fn open_file(&mut self, path: &CStr16) -> Result<(Box<dyn InitrdFile>, usize)>;
+
+    /// Compose the command line of the kernel from the one in `config`
+    /// and what the firmware adds to it, e.g. addons. Configuration from
+    /// outside the image is measured with `measurements`.
+    fn cmdline(
+        &mut self,
+        config: &EmbeddedConfiguration,
+        secure_boot: bool,
+        measurements: &mut Measurements,
+    ) -> Result<Vec<u8>>;
+
+    /// Load `kernel`, which was verified, and serve `initrd` to it.
+    fn load_kernel(
+        &mut self,
+        kernel: &[u8],
+        cmdline: Vec<u8>,
+        initrd: InitrdSource,
+    ) -> Result<Self::Kernel>;
+}
+
+/// Extract a string, stored as UTF-8, from a PE section.
+pub fn extract_string(pe_data: &[u8], section: &str) -> Result<CString16> {
+    let string = pe_section_as_string(pe_data, section).ok_or(Status::INVALID_PARAMETER)?;
+
+    Ok(CString16::try_from(string.as_str()).map_err(|_| Status::INVALID_PARAMETER)?)
+}
+
+/// Extract the command line from `section`, checked according to the
+/// `.cmdlchk` section.
+///
+/// A command line that the policy refuses fails with
+/// `SECURITY_VIOLATION`.
+pub fn extract_cmdline(pe_data: &[u8], section: &str) -> Result<CString16> {
+    let cmdline = pe_section_as_string(pe_data, section).ok_or(Status::INVALID_PARAMETER)?;
+    let policy = pe_section_as_string(pe_data, ".cmdlchk")
+        .and_then(|value| CmdlinePolicy::parse(&value))
+        .unwrap_or_default();
+    let cmdline = check_cmdline(&cmdline, policy).map_err(|problem| {
+        error!("Refusing to boot, {problem}.");
+        Status::SECURITY_VIOLATION
+    })?;
+
+    Ok(CString16::try_from(cmdline.as_str()).map_err(|_| Status::INVALID_PARAMETER)?)
+}
+
+/// Extract a SHA256 hash from a PE section.
+fn extract_hash(pe_data: &[u8], section: &str) -> Result<[u8; HASH_SIZE]> {
+    Ok(pe_section(pe_data, section, Some(NATIVE_MACHINE))?
+        .ok_or(Status::INVALID_PARAMETER)?
+        .try_into()
+        .map_err(|_| Status::INVALID_PARAMETER)?)
+}
+
+/// Verify some data against its expected hash.
+///
+/// See [`check_digest`] for what happens on a mismatch.
+fn check_hash(
+    data: &[u8],
+    expected_hash: &[u8; HASH_SIZE],
+    name: &str,
+    secure_boot: bool,
+) -> Result<()> {
+    check_digest(&Sha256::digest(data), expected_hash, name, secure_boot)
+}
+
+/// Verify the running image against the hash in its `.selfh` section.
+///
+/// The section is only present if lzbt was asked to embed it. A
+/// mismatch means that the image was corrupted in memory, so the boot
+/// is stopped regardless of whether Secure Boot is active.
+fn check_self_hash(image: &[u8]) -> Result<()> {
+    let Ok(expected_hash) = extract_hash(image, ".selfh") else {
+        return Ok(());
+    };
+
+    let mut hasher = Sha256::new();
+    for section in SELF_HASH_SECTIONS {
+        if let Some(data) = pe_section(image, section, Some(NATIVE_MACHINE))? {
+            hasher.update(data);
+        }
+    }
+
+    if hasher.finalize()[..] != expected_hash {
+        error!("Image hash does not match! The stub is corrupted.");
+        return Err(Status::SECURITY_VIOLATION.into());
+    }
+    Ok(())
+}
+
+/// Verify the sections of the running image against its `.sechash`
+/// section.
+///
+/// Like `.selfh`, the section is only present if lzbt was asked to embed
+/// it, and a mismatch stops the boot regardless of whether Secure Boot
+/// is active.
+fn check_section_hashes(image: &[u8]) -> Result<()> {
+    let Some(manifest) = pe_section(image, ".sechash", Some(NATIVE_MACHINE))? else {
+        return Ok(());
+    };
+
+    // The machine of the image was checked above.
+    if let Err(err) = SectionHashes::parse(manifest)
+        .and_then(|hashes| hashes.verify(|name| pe_section(image, name, None).ok().flatten()))
+    {
+        error!("Failed to verify the sections of the image: {err}.");
+        return Err(Status::SECURITY_VIOLATION.into());
+    }
+    Ok(())
+}
+
+/// Hash a file without reading it into memory as a whole.
+fn file_hash(file: &mut dyn InitrdFile) -> Result<[u8; HASH_SIZE]> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1024 * 1024];
+
+    file.set_position(0)?;
+    loop {
+        match file.read(&mut buffer)? {
+            0 => break,
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+/// Verify and load the kernel and initrd referenced by `image`, the
+/// running image, with the command line from `cmdline_section`.
+///
+/// `dynamic_initrds` are appended to the initrd. Without them and a
+/// `.irdpath` section, the initrd is served from the ESP instead of
+/// memory if `lazy_initrd` is set. Configuration from outside the
+/// image, e.g. addons, is measured with `measurements`.
+pub fn boot<F: BootFirmware>(
+    firmware: &mut F,
+    image: &[u8],
+    cmdline_section: &str,
+    dynamic_initrds: Vec<Vec<u8>>,
+    lazy_initrd: bool,
+    measurements: &mut Measurements,
+) -> Result<F::Kernel> {
+    check_self_hash(image)?;
+    check_section_hashes(image)?;
+    let config = EmbeddedConfiguration::new(image, cmdline_section).map_err(|err| {
+        error!("Failed to extract configuration from binary. Did you run lzbt?");
+        err
+    })?;
+
+    let secure_boot_enabled = firmware.secure_boot();
+
+    let verify_initrd = config.initrd_verification.required(secure_boot_enabled);
+    if !verify_initrd {
+        info!("Secure Boot is disabled, skipping initrd verification.");
+    }
+
+    // The initrd can only be served from the ESP if nothing is appended to it.
+    let lazy_initrd = lazy_initrd && dynamic_initrds.is_empty() && config.initrd_path.is_none();
+
+    let kernel_data = firmware.read_file(&config.kernel_filename)?;
+
+    let cmdline = firmware.cmdline(&config, secure_boot_enabled, measurements)?;
+
+    check_hash(
+        &kernel_data,
+        &config.kernel_hash,
+        "Kernel",
+        secure_boot_enabled,
+    )?;
+    // Firmware with an emulator would start a kernel of another
+    // architecture.
+    check_pe_machine(&kernel_data, Some(NATIVE_MACHINE))?;
+
+    // The initrd is read as part of its verification, so that a read
+    // that the firmware refuses is retried as well.
+    let initrd = match &config.initrd {
+        Some((initrd_filename, initrd_hash)) if lazy_initrd => {
+            // The whole file is verified once here. It is read again
+            // when Linux asks for it and refused if it changed in
+            // between.
+            let (initrd_file, size) = verify_with_retry(config.initrd_retry, "the initrd", |_| {
+                let (mut initrd_file, size) = firmware.open_file(initrd_filename)?;
+                if verify_initrd {
+                    check_digest(
+                        &file_hash(&mut *initrd_file)?,
+                        initrd_hash,
+                        "Initrd",
+                        secure_boot_enabled,
+                    )?;
+                }
+                Ok((initrd_file, size))
+            })?;
+            InitrdSource::File {
+                file: initrd_file,
+                size,
+                expected_hash: secure_boot_enabled.then_some(*initrd_hash),
+            }
+        }
+        initrd => {
+            let mut initrd_data = match initrd {
+                Some((initrd_filename, initrd_hash)) => {
+                    verify_with_retry(config.initrd_retry, "the initrd", |_| {
+                        let initrd_data = firmware.read_file(initrd_filename)?;
+                        if verify_initrd {
+                            check_hash(&initrd_data, initrd_hash, "Initrd", secure_boot_enabled)?;
+                        }
+                        Ok(initrd_data)
+                    })?
+                }
+                None => Vec::new(),
+            };
+
+            if let Some(initrd_path) = &config.initrd_path {
+                let mut initrd_file =
+                    load_initrd_path(&*firmware, initrd_path, secure_boot_enabled).map_err(
+                        |err| {
+                            error!("Failed to load the initrd file: {err:?}");
+                            err
+                        },
+                    )?;
+                measure_initrd_path(
+                    pe_section(image, ".irdpath", Some(NATIVE_MACHINE))?.unwrap_or_default(),
+                    &initrd_file,
+                    &mut *firmware,
+                    measurements,
+                )?;
+                initrd_data.append(&mut initrd_file);
+            }
+
+            // Correctness: dynamic initrds are supposed to be validated by caller,
+            // i.e. they are system extension images or credentials
+            // that are supposedly measured in TPM2.
+            // Therefore, it is normal to not verify their hashes against a configuration.
+            for mut extra_initrd in dynamic_initrds {
+                initrd_data.append(&mut extra_initrd);
+            }
+
+            InitrdSource::Memory(initrd_data)
+        }
+    };
+
+    firmware.load_kernel(&kernel_data, cmdline, initrd)
+}
@@ -0,0 +1,129 @@
+//! Boot the next entry of systemd-boot if the kernel fails to start.
+//!
+//! The kernel only returns to the stub if it failed to take over, e.g.
+//! because it is broken or does not support the machine. The stub
+//! handles a kernel or initrd that cannot be loaded, e.g. because a
+//! file on the ESP is missing, the same way. Without
+//! another boot loader in the loop, the machine then sits in the
+//! firmware. A UKI with `next-entry` in its `.kfail` section instead
+//! asks systemd-boot to boot the entry after its own one exactly once,
+//! through `LoaderEntryOneShot`, and resets. On NixOS, the entries are
+//! sorted from the newest generation to the oldest, so this is the
+//! previous generation.
+//!
+//! The one-shot entry is consumed by systemd-boot on the next boot, so a
+//! failing fallback moves on to the entry after it. Once there is no
+//! further entry, the stub returns the error of the kernel.
+//!
+//! [`start_with_fallback`] does not depend on the firmware, the
+//! variables are accessed through a [`VariableStore`].
+
+use alloc::{string::String, vec::Vec};
+
+use log::{error, info, warn};
+use uefi::{cstr16, table::runtime::VariableAttributes, CString16, Result, Status};
+
+use crate::efivars::{ucs2_bytes, VariableStore, BOOT_LOADER_VENDOR_UUID};
+
+/// What happens if the kernel fails to start, as stored in the `.kfail`
+/// PE section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KernelFailurePolicy {
+    /// Return the error to the firmware. This is the default without
+    /// the section.
+    #[default]
+    Return,
+    /// Boot the next entry of systemd-boot once.
+    NextEntry,
+}
+
+impl KernelFailurePolicy {
+    /// Parse the contents of the `.kfail` section: `return` or
+    /// `next-entry`.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "return" => Some(Self::Return),
+            "next-entry" => Some(Self::NextEntry),
+            _ => None,
+        }
+    }
+}
+
+/// Split a list of NUL terminated UCS-2 strings, like `LoaderEntries`.
+///
+/// A missing terminator after the last string is tolerated.
+pub fn parse_entry_list(data: &[u8]) -> Vec<String> {
+    let chars = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect::<Vec<_>>();
+    chars
+        .split(|&c| c == 0)
+        .filter(|entry| !entry.is_empty())
+        .map(String::from_utf16_lossy)
+        .collect()
+}
+
+/// The entry after `selected` in `entries`, if there is one.
+pub fn next_entry<'a>(entries: &'a [String], selected: &str) -> Option<&'a str> {
+    let position = entries.iter().position(|entry| entry == selected)?;
+    entries.get(position + 1).map(String::as_str)
+}
+
+/// Ask systemd-boot to boot the entry after the selected one on the
+/// next boot.
+///
+/// Returns the name of the entry. Fails with `NOT_FOUND` if the stub
+/// was not started by systemd-boot or the selected entry is the last
+/// one.
+pub fn request_next_entry(store: &mut impl VariableStore) -> Result<String> {
+    let entries =
+        parse_entry_list(&store.read(cstr16!("LoaderEntries"), &BOOT_LOADER_VENDOR_UUID)?);
+    let selected =
+        parse_entry_list(&store.read(cstr16!("LoaderEntrySelected"), &BOOT_LOADER_VENDOR_UUID)?);
+    let next = selected
+        .first()
+        .and_then(|selected| next_entry(&entries, selected))
+        .ok_or(Status::NOT_FOUND)?;
+    // Entry names with characters outside of UCS-2 cannot be stored.
+    CString16::try_from(next).map_err(|_| Status::INVALID_PARAMETER)?;
+
+    store.set(
+        cstr16!("LoaderEntryOneShot"),
+        &BOOT_LOADER_VENDOR_UUID,
+        VariableAttributes::NON_VOLATILE
+            | VariableAttributes::BOOTSERVICE_ACCESS
+            | VariableAttributes::RUNTIME_ACCESS,
+        &ucs2_bytes(next),
+    )?;
+    Ok(next.into())
+}
+
+/// Start the kernel with `start` and handle its failure according to
+/// `policy`.
+///
+/// With [`KernelFailurePolicy::NextEntry`], the next entry is requested
+/// and `reset` is called, which does not return on real firmware. If no
+/// entry can be requested, or `reset` returns, the status of the kernel
+/// is returned.
+pub fn start_with_fallback(
+    policy: KernelFailurePolicy,
+    store: &mut impl VariableStore,
+    start: impl FnOnce() -> Status,
+    reset: impl FnOnce(),
+) -> Status {
+    let status = start();
+    if !status.is_error() || policy != KernelFailurePolicy::NextEntry {
+        return status;
+    }
+
+    error!("The kernel failed to start: {status:?}");
+    match request_next_entry(store) {
+        Ok(entry) => {
+            info!("Rebooting into the next boot entry {entry}.");
+            reset();
+        }
+        Err(err) => warn!("Failed to request the next boot entry: {err:?}"),
+    }
+    status
+}
@@ -0,0 +1,100 @@
+//! Hand off to a boot loader on the ESP, e.g. systemd-boot.
+//!
+//! A UKI with a `.chain` section first starts the EFI binary at the path
+//! in the section, on the volume that contains the UKI. The binary is
+//! loaded with the firmware's `LoadImage`, which verifies its signature
+//! if Secure Boot is enabled. If the binary does not exist or fails to
+//! load, the UKI boots its own kernel.
+//!
+//! If the UKI was itself started by a boot loader, it boots its own
+//! kernel as well. Otherwise, systemd-boot would start the UKI again
+//! when its entry is selected.
+//!
+//! [`chainload_target`] and [`chainload`] do not depend on the firmware,
+//! [`UefiChainload`] implements [`ChainloadServices`] with the boot
+//! services.
+
+use alloc::{string::String, vec::Vec};
+
+use uefi::{prelude::*, table::boot::LoadImageSource, CStr16, CString16, Result};
+
+use crate::uefi_helpers::image_volume_path;
+
+/// What the firmware does to start a boot loader.
+pub trait ChainloadServices {
+    /// Load and verify the EFI binary at `path` on the volume of the
+    /// running image. Fails with `NOT_FOUND` if there is no such file.
+    fn load_image(&self, path: &CStr16) -> Result<Handle>;
+
+    /// Start a loaded image. Returns once the image exits.
+    fn start_image(&self, image: Handle) -> Result<()>;
+}
+
+/// The path of the boot loader to start, if any.
+///
+/// `section` is the contents of the `.chain` section, e.g.
+/// `\EFI\systemd\systemd-bootx64.efi`. Forward slashes are accepted as
+/// well. Paths that are not absolute or contain `.` or `..` components
+/// are ignored.
+pub fn chainload_target(section: Option<&str>, started_by_boot_loader: bool) -> Option<CString16> {
+    if started_by_boot_loader {
+        return None;
+    }
+    let path = section?.trim().replace('/', "\\");
+    let components = path.strip_prefix('\\')?.split('\\');
+    let mut normalized = String::new();
+    for component in components {
+        if component.is_empty() || component == "." || component == ".." {
+            return None;
+        }
+        normalized.push('\\');
+        normalized.push_str(component);
+    }
+    CString16::try_from(normalized.as_str()).ok()
+}
+
+/// Start the boot loader at `path`.
+///
+/// Returns `false` without starting anything if the boot loader does not
+/// exist, and `true` once it exited successfully. Errors of loading,
+/// including failed signature checks, and errors of the boot loader
+/// itself are returned.
+pub fn chainload(services: &impl ChainloadServices, path: &CStr16) -> Result<bool> {
+    let image = match services.load_image(path) {
+        Ok(image) => image,
+        Err(err) if err.status() == Status::NOT_FOUND => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    services.start_image(image)?;
+    Ok(true)
+}
+
+/// Starts images with the boot services.
+pub struct UefiChainload<'a> {
+    boot_services: &'a BootServices,
+}
+
+impl<'a> UefiChainload<'a> {
+    pub fn new(boot_services: &'a BootServices) -> Self {
+        Self { boot_services }
+    }
+}
+
+impl ChainloadServices for UefiChainload<'_> {
+    fn load_image(&self, path: &CStr16) -> Result<Handle> {
+        let mut buffer = Vec::new();
+        let device_path = image_volume_path(self.boot_services, path, &mut buffer)?;
+
+        self.boot_services.load_image(
+            self.boot_services.image_handle(),
+            LoadImageSource::FromDevicePath {
+                device_path,
+                from_boot_manager: false,
+            },
+        )
+    }
+
+    fn start_image(&self, image: Handle) -> Result<()> {
+        self.boot_services.start_image(image)
+    }
+}
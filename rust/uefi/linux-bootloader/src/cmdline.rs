@@ -0,0 +1,98 @@
+//! Check the embedded command line before it is handed to the kernel.
+//!
+//! Control characters like newlines or tabs in the `.cmdline` section
+//! confuse the parser of the kernel and anything that logs the command
+//! line, and a command line longer than the kernel accepts is silently
+//! truncated. By default, the stub replaces control characters with
+//! spaces, the only separator the kernel needs, and warns about the
+//! length. With [`CmdlinePolicy::Strict`], which lzbt selects by
+//! embedding `strict` in the `.cmdlchk` section, either stops the boot.
+
+use alloc::string::String;
+use core::fmt;
+
+use log::warn;
+
+/// The longest command line in bytes that the kernel accepts on all
+/// architectures the stub supports, i.e. `COMMAND_LINE_SIZE` of x86 and
+/// arm64 without the terminating NUL.
+pub const MAX_CMDLINE_LENGTH: usize = 2047;
+
+/// What to do with a command line that has problems, as stored in the
+/// `.cmdlchk` PE section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CmdlinePolicy {
+    /// Replace control characters with spaces and only warn about the
+    /// length. This is the default without the section.
+    #[default]
+    Sanitize,
+    /// Refuse the command line.
+    Strict,
+}
+
+impl CmdlinePolicy {
+    /// Parse the contents of the `.cmdlchk` section: `sanitize` or
+    /// `strict`.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "sanitize" => Some(Self::Sanitize),
+            "strict" => Some(Self::Strict),
+            _ => None,
+        }
+    }
+}
+
+/// Why a command line was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmdlineProblem {
+    /// The command line is this many bytes long, more than
+    /// [`MAX_CMDLINE_LENGTH`].
+    TooLong(usize),
+    /// The command line contains `character` at byte `offset`.
+    ControlCharacter { offset: usize, character: char },
+}
+
+impl fmt::Display for CmdlineProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLong(length) => write!(
+                f,
+                "the command line is {length} bytes long, at most {MAX_CMDLINE_LENGTH} are supported"
+            ),
+            Self::ControlCharacter { offset, character } => write!(
+                f,
+                "the command line contains the control character {character:?} at offset {offset}"
+            ),
+        }
+    }
+}
+
+/// Check `cmdline` according to `policy`. Returns the command line to
+/// boot with, or the first problem if the policy is strict.
+pub fn check_cmdline(cmdline: &str, policy: CmdlinePolicy) -> Result<String, CmdlineProblem> {
+    let control_character = cmdline
+        .char_indices()
+        .find(|(_, character)| character.is_control());
+
+    if policy == CmdlinePolicy::Strict {
+        if let Some((offset, character)) = control_character {
+            return Err(CmdlineProblem::ControlCharacter { offset, character });
+        }
+    }
+    if cmdline.len() > MAX_CMDLINE_LENGTH {
+        let problem = CmdlineProblem::TooLong(cmdline.len());
+        if policy == CmdlinePolicy::Strict {
+            return Err(problem);
+        }
+        warn!("The kernel may truncate the command line: {problem}.");
+    }
+
+    if control_character.is_none() {
+        return Ok(cmdline.into());
+    }
+    warn!("Replacing control characters in the command line with spaces.");
+    Ok(cmdline
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect())
+}
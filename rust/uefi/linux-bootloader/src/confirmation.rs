@@ -0,0 +1,107 @@
+//! Ask the user before booting without Secure Boot.
+//!
+//! Without Secure Boot, nothing the stub loads is verified. A UKI with a
+//! `.confirm` section does not boot silently in that case: it waits until
+//! the user confirms with `y` or declines with `n` or Escape.
+//!
+//! [`confirmation_required`] and [`ask_confirmation`] do not depend on
+//! the firmware, [`UefiConfirmationIo`] implements [`ConfirmationIo`] on
+//! the UEFI console.
+
+use core::fmt::Write;
+
+use uefi::{
+    prelude::*,
+    proto::console::text::{Key, ScanCode},
+    Char16, Result,
+};
+
+/// Something the user did while being asked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfirmationEvent {
+    /// `y` or `Y` was pressed.
+    Confirm,
+    /// `n`, `N` or Escape was pressed.
+    Decline,
+    /// Any other key was pressed.
+    OtherKey,
+}
+
+/// The input and output of the question.
+pub trait ConfirmationIo {
+    /// Block until the next key press.
+    fn next_event(&mut self) -> Result<ConfirmationEvent>;
+
+    /// Show the question to the user.
+    fn show(&mut self);
+}
+
+/// Whether the user has to confirm booting. This is the case if the UKI
+/// has a `.confirm` section and Secure Boot is disabled.
+pub fn confirmation_required(section: Option<&str>, secure_boot_enabled: bool) -> bool {
+    section.is_some() && !secure_boot_enabled
+}
+
+/// Ask until the user confirms or declines. Returns whether booting was
+/// confirmed.
+pub fn ask_confirmation(io: &mut impl ConfirmationIo) -> Result<bool> {
+    io.show();
+    loop {
+        match io.next_event()? {
+            ConfirmationEvent::Confirm => return Ok(true),
+            ConfirmationEvent::Decline => return Ok(false),
+            ConfirmationEvent::OtherKey => {}
+        }
+    }
+}
+
+/// Asks on the UEFI console.
+pub struct UefiConfirmationIo<'a> {
+    system_table: &'a mut SystemTable<Boot>,
+}
+
+impl<'a> UefiConfirmationIo<'a> {
+    pub fn new(system_table: &'a mut SystemTable<Boot>) -> Self {
+        Self { system_table }
+    }
+}
+
+impl ConfirmationIo for UefiConfirmationIo<'_> {
+    fn next_event(&mut self) -> Result<ConfirmationEvent> {
+        let key_event = self
+            .system_table
+            .stdin()
+            .wait_for_key_event()
+            .ok_or(Status::UNSUPPORTED)?;
+        // SAFETY: The event stays alive while it is waited for.
+        let mut events = [unsafe { key_event.unsafe_clone() }];
+        self.system_table
+            .boot_services()
+            .wait_for_event(&mut events)
+            .map_err(|err| err.to_err_without_payload())?;
+
+        Ok(match self.system_table.stdin().read_key()? {
+            Some(Key::Printable(c))
+                if c == Char16::try_from('y').unwrap() || c == Char16::try_from('Y').unwrap() =>
+            {
+                ConfirmationEvent::Confirm
+            }
+            Some(Key::Printable(c))
+                if c == Char16::try_from('n').unwrap() || c == Char16::try_from('N').unwrap() =>
+            {
+                ConfirmationEvent::Decline
+            }
+            Some(Key::Special(ScanCode::ESCAPE)) => ConfirmationEvent::Decline,
+            _ => ConfirmationEvent::OtherKey,
+        })
+    }
+
+    fn show(&mut self) {
+        // Errors are ignored, the keys work without output.
+        let _ = write!(
+            self.system_table.stdout(),
+            "Secure Boot is disabled, the system to boot cannot be verified.\r\n\
+             Boot anyway? Press y to boot or n to abort.\r\n"
+        );
+    }
+}
@@ -0,0 +1,188 @@
+//! Wait for a few seconds before booting automatically.
+//!
+//! While the countdown runs, Enter boots immediately and any other key
+//! pauses it, so that the message on screen can be read. Once paused,
+//! only Enter continues booting.
+//!
+//! The state machine in [`Countdown`] does not depend on the firmware.
+//! [`run_countdown`] drives it with any [`CountdownIo`], e.g. the UEFI
+//! console and a timer in [`UefiCountdownIo`].
+
+use alloc::{format, string::String};
+use core::fmt::Write;
+
+use uefi::{
+    prelude::*,
+    proto::console::text::Key,
+    table::boot::{EventType, TimerTrigger, Tpl},
+    Char16, Event, Result,
+};
+
+/// Something that happened while the countdown is running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CountdownEvent {
+    /// A second has passed.
+    Tick,
+    /// Enter was pressed.
+    Enter,
+    /// Any other key was pressed.
+    OtherKey,
+}
+
+/// What is shown to the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CountdownState {
+    /// Booting automatically after that many seconds.
+    Running(u32),
+    /// Waiting for Enter.
+    Paused,
+    /// The countdown is over, booting now.
+    Done,
+}
+
+/// The state machine of the countdown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Countdown {
+    state: CountdownState,
+}
+
+impl Countdown {
+    /// Start a countdown of `seconds`. A countdown of 0 seconds is
+    /// over immediately.
+    pub fn new(seconds: u32) -> Self {
+        let state = match seconds {
+            0 => CountdownState::Done,
+            seconds => CountdownState::Running(seconds),
+        };
+        Self { state }
+    }
+
+    pub fn state(&self) -> CountdownState {
+        self.state
+    }
+
+    /// Advance the state machine and return the new state.
+    pub fn handle(&mut self, event: CountdownEvent) -> CountdownState {
+        self.state = match (self.state, event) {
+            (CountdownState::Done, _) => CountdownState::Done,
+            (_, CountdownEvent::Enter) => CountdownState::Done,
+            (CountdownState::Running(1), CountdownEvent::Tick) => CountdownState::Done,
+            (CountdownState::Running(seconds), CountdownEvent::Tick) => {
+                CountdownState::Running(seconds - 1)
+            }
+            (CountdownState::Running(_), CountdownEvent::OtherKey) => CountdownState::Paused,
+            (CountdownState::Paused, _) => CountdownState::Paused,
+        };
+        self.state
+    }
+}
+
+/// The input and output of a countdown.
+pub trait CountdownIo {
+    /// Block until the next event.
+    fn next_event(&mut self) -> Result<CountdownEvent>;
+
+    /// Show the current state to the user.
+    fn show(&mut self, state: CountdownState);
+}
+
+/// Run a countdown of `seconds` until it is over.
+pub fn run_countdown(seconds: u32, io: &mut impl CountdownIo) -> Result<()> {
+    let mut countdown = Countdown::new(seconds);
+    while countdown.state() != CountdownState::Done {
+        io.show(countdown.state());
+        countdown.handle(io.next_event()?);
+    }
+    io.show(CountdownState::Done);
+    Ok(())
+}
+
+/// Runs a countdown on the UEFI console.
+pub struct UefiCountdownIo<'a> {
+    system_table: &'a mut SystemTable<Boot>,
+    timer: Event,
+}
+
+impl<'a> UefiCountdownIo<'a> {
+    pub fn new(system_table: &'a mut SystemTable<Boot>) -> Result<Self> {
+        let timer = create_second_timer(system_table.boot_services())?;
+        Ok(Self {
+            system_table,
+            timer,
+        })
+    }
+}
+
+impl Drop for UefiCountdownIo<'_> {
+    fn drop(&mut self) {
+        // SAFETY: The event is not used anymore.
+        let timer = unsafe { self.timer.unsafe_clone() };
+        let _ = self.system_table.boot_services().close_event(timer);
+    }
+}
+
+/// Block until `timer` fires or a key is pressed. Returns the key, or
+/// `None` if the timer fired.
+pub(crate) fn next_key_or_tick(
+    system_table: &mut SystemTable<Boot>,
+    timer: &Event,
+) -> Result<Option<Key>> {
+    // SAFETY: The events stay alive while they are waited for.
+    let mut events = unsafe {
+        [
+            timer.unsafe_clone(),
+            system_table
+                .stdin()
+                .wait_for_key_event()
+                .ok_or(Status::UNSUPPORTED)?
+                .unsafe_clone(),
+        ]
+    };
+    let index = system_table
+        .boot_services()
+        .wait_for_event(&mut events)
+        .map_err(|err| err.to_err_without_payload())?;
+
+    if index == 0 {
+        return Ok(None);
+    }
+    system_table.stdin().read_key()
+}
+
+/// Create a timer that fires every second.
+pub(crate) fn create_second_timer(boot_services: &BootServices) -> Result<Event> {
+    // SAFETY: The event has no notification function.
+    let timer =
+        unsafe { boot_services.create_event(EventType::TIMER, Tpl::APPLICATION, None, None)? };
+    // The timer period is given in units of 100ns.
+    boot_services.set_timer(&timer, TimerTrigger::Periodic(10_000_000))?;
+    Ok(timer)
+}
+
+impl CountdownIo for UefiCountdownIo<'_> {
+    fn next_event(&mut self) -> Result<CountdownEvent> {
+        Ok(match next_key_or_tick(self.system_table, &self.timer)? {
+            None => CountdownEvent::Tick,
+            Some(Key::Printable(c)) if c == Char16::try_from('\r').unwrap() => {
+                CountdownEvent::Enter
+            }
+            Some(_) => CountdownEvent::OtherKey,
+        })
+    }
+
+    fn show(&mut self, state: CountdownState) {
+        let message: String = match state {
+            CountdownState::Running(seconds) => format!(
+                "Booting in {seconds} s, press Enter to boot now or any other key to pause."
+            ),
+            CountdownState::Paused => "Paused, press Enter to boot.".into(),
+            CountdownState::Done => "Booting...".into(),
+        };
+        // Overwrite the previous message. Errors are ignored, the
+        // countdown works without output.
+        let _ = write!(self.system_table.stdout(), "\r{message:<78}");
+        if state == CountdownState::Done {
+            let _ = write!(self.system_table.stdout(), "\r\n");
+        }
+    }
+}
@@ -0,0 +1,372 @@
+//! Build metadata for A/B update schemes.
+//!
+//! The `.deploy` section contains a JSON object that names the slot
+//! (`"a"` or `"b"`) the image was deployed to and its version, e.g.
+//! `{"slot": "a", "version": "24.05.1"}`. Unknown keys are ignored.
+//!
+//! [`DeployInfo::parse`] does not depend on the firmware.
+//! [`export_deploy_variables`] makes the result available to the booted
+//! system, so that an update agent or boot counting can tell which slot
+//! was booted.
+
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+
+use uefi::{cstr16, prelude::RuntimeServices, table::runtime::VariableAttributes, Result};
+
+use crate::efivars::BOOT_LOADER_VENDOR_UUID;
+
+/// How deeply values may be nested in ignored keys.
+const MAX_DEPTH: usize = 32;
+
+/// One of the two slots of an A/B update scheme.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::A => "a",
+            Self::B => "b",
+        }
+    }
+}
+
+/// Why a `.deploy` section could not be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeployError {
+    /// The section is not valid JSON. Contains the byte offset of the
+    /// problem.
+    Syntax(usize),
+    /// The section is valid JSON, but not an object.
+    NotAnObject,
+    /// A key appears more than once.
+    DuplicateKey,
+    /// The slot is missing.
+    MissingSlot,
+    /// The slot is neither `"a"` nor `"b"`.
+    InvalidSlot,
+    /// The version is missing or not a string.
+    MissingVersion,
+}
+
+impl fmt::Display for DeployError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syntax(offset) => write!(f, "invalid JSON at byte {offset}"),
+            Self::NotAnObject => f.write_str("not a JSON object"),
+            Self::DuplicateKey => f.write_str("duplicate key"),
+            Self::MissingSlot => f.write_str("missing slot"),
+            Self::InvalidSlot => f.write_str("slot is neither \"a\" nor \"b\""),
+            Self::MissingVersion => f.write_str("missing version"),
+        }
+    }
+}
+
+/// The contents of the `.deploy` section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeployInfo {
+    pub slot: Slot,
+    /// The version of the image, as understood by the update agent.
+    pub version: String,
+}
+
+impl DeployInfo {
+    /// Parse the JSON object of a `.deploy` section.
+    pub fn parse(json: &str) -> core::result::Result<Self, DeployError> {
+        let mut parser = Parser {
+            bytes: json.as_bytes(),
+            pos: 0,
+        };
+        let mut slot = None;
+        let mut version = None;
+
+        parser.skip_whitespace();
+        if parser.peek() != Some(b'{') {
+            // Anything else that is valid JSON is still not what we want.
+            parser.skip_value(0)?;
+            parser.expect_end()?;
+            return Err(DeployError::NotAnObject);
+        }
+        parser.pos += 1;
+
+        parser.skip_whitespace();
+        if parser.peek() == Some(b'}') {
+            parser.pos += 1;
+        } else {
+            loop {
+                parser.skip_whitespace();
+                let key = parser.parse_string()?;
+                parser.skip_whitespace();
+                parser.expect(b':')?;
+                parser.skip_whitespace();
+
+                let target = match key.as_str() {
+                    "slot" => Some(&mut slot),
+                    "version" => Some(&mut version),
+                    _ => None,
+                };
+                match target {
+                    Some(target) => {
+                        if target.is_some() {
+                            return Err(DeployError::DuplicateKey);
+                        }
+                        // Values of the wrong type are rejected below.
+                        *target = Some(if parser.peek() == Some(b'"') {
+                            Some(parser.parse_string()?)
+                        } else {
+                            parser.skip_value(1)?;
+                            None
+                        });
+                    }
+                    None => parser.skip_value(1)?,
+                }
+
+                parser.skip_whitespace();
+                match parser.next() {
+                    Some(b',') => continue,
+                    Some(b'}') => break,
+                    _ => return Err(parser.error()),
+                }
+            }
+        }
+        parser.expect_end()?;
+
+        let slot = match slot.ok_or(DeployError::MissingSlot)?.as_deref() {
+            Some("a") => Slot::A,
+            Some("b") => Slot::B,
+            _ => return Err(DeployError::InvalidSlot),
+        };
+        let version = version.flatten().ok_or(DeployError::MissingVersion)?;
+        Ok(Self { slot, version })
+    }
+}
+
+/// A minimal JSON reader that only decodes strings and skips over all
+/// other values.
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self) -> DeployError {
+        DeployError::Syntax(self.pos)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn expect(&mut self, byte: u8) -> core::result::Result<(), DeployError> {
+        if self.peek() != Some(byte) {
+            return Err(self.error());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn expect_end(&mut self) -> core::result::Result<(), DeployError> {
+        self.skip_whitespace();
+        if self.pos != self.bytes.len() {
+            return Err(self.error());
+        }
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn parse_string(&mut self) -> core::result::Result<String, DeployError> {
+        self.expect(b'"')?;
+        let mut string = String::new();
+        loop {
+            let start = self.pos;
+            while !matches!(self.peek(), None | Some(b'"' | b'\\' | 0..=0x1f)) {
+                self.pos += 1;
+            }
+            // The input is a `str` and we only stop at ASCII characters,
+            // so this is always a valid UTF-8 boundary.
+            string.push_str(
+                core::str::from_utf8(&self.bytes[start..self.pos]).map_err(|_| self.error())?,
+            );
+
+            match self.peek() {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(string);
+                }
+                Some(b'\\') => self.pos += 1,
+                // Control characters must be escaped.
+                _ => return Err(self.error()),
+            }
+
+            let escaped = match self.next() {
+                Some(b'"') => '"',
+                Some(b'\\') => '\\',
+                Some(b'/') => '/',
+                Some(b'b') => '\u{8}',
+                Some(b'f') => '\u{c}',
+                Some(b'n') => '\n',
+                Some(b'r') => '\r',
+                Some(b't') => '\t',
+                Some(b'u') => self.parse_unicode_escape()?,
+                _ => return Err(self.error()),
+            };
+            string.push(escaped);
+        }
+    }
+
+    /// Parse the rest of a `\u` escape, including a second escape for
+    /// the low half of a surrogate pair.
+    fn parse_unicode_escape(&mut self) -> core::result::Result<char, DeployError> {
+        let high = self.parse_hex4()?;
+        let code = match high {
+            0xd800..=0xdbff => {
+                self.expect(b'\\')?;
+                self.expect(b'u')?;
+                let low = self.parse_hex4()?;
+                if !(0xdc00..=0xdfff).contains(&low) {
+                    return Err(self.error());
+                }
+                0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+            }
+            0xdc00..=0xdfff => return Err(self.error()),
+            _ => high,
+        };
+        char::from_u32(code).ok_or_else(|| self.error())
+    }
+
+    fn parse_hex4(&mut self) -> core::result::Result<u32, DeployError> {
+        let digits = self
+            .bytes
+            .get(self.pos..self.pos + 4)
+            .ok_or_else(|| self.error())?;
+        let digits = core::str::from_utf8(digits).map_err(|_| self.error())?;
+        if !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(self.error());
+        }
+        let value = u32::from_str_radix(digits, 16).map_err(|_| self.error())?;
+        self.pos += 4;
+        Ok(value)
+    }
+
+    fn skip_digits(&mut self) -> core::result::Result<(), DeployError> {
+        if !matches!(self.peek(), Some(b'0'..=b'9')) {
+            return Err(self.error());
+        }
+        while matches!(self.peek(), Some(b'0'..=b'9')) {
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    fn skip_number(&mut self) -> core::result::Result<(), DeployError> {
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        if self.peek() == Some(b'0') {
+            self.pos += 1;
+        } else {
+            self.skip_digits()?;
+        }
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            self.skip_digits()?;
+        }
+        if matches!(self.peek(), Some(b'e' | b'E')) {
+            self.pos += 1;
+            if matches!(self.peek(), Some(b'+' | b'-')) {
+                self.pos += 1;
+            }
+            self.skip_digits()?;
+        }
+        Ok(())
+    }
+
+    fn skip_literal(&mut self, literal: &[u8]) -> core::result::Result<(), DeployError> {
+        if !self.bytes[self.pos..].starts_with(literal) {
+            return Err(self.error());
+        }
+        self.pos += literal.len();
+        Ok(())
+    }
+
+    fn skip_value(&mut self, depth: usize) -> core::result::Result<(), DeployError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error());
+        }
+        match self.peek() {
+            Some(b'"') => self.parse_string().map(drop),
+            Some(b't') => self.skip_literal(b"true"),
+            Some(b'f') => self.skip_literal(b"false"),
+            Some(b'n') => self.skip_literal(b"null"),
+            Some(b'-' | b'0'..=b'9') => self.skip_number(),
+            Some(open @ (b'{' | b'[')) => {
+                let close = if open == b'{' { b'}' } else { b']' };
+                self.pos += 1;
+                self.skip_whitespace();
+                if self.peek() == Some(close) {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                loop {
+                    self.skip_whitespace();
+                    if open == b'{' {
+                        self.parse_string()?;
+                        self.skip_whitespace();
+                        self.expect(b':')?;
+                        self.skip_whitespace();
+                    }
+                    self.skip_value(depth + 1)?;
+                    self.skip_whitespace();
+                    match self.next() {
+                        Some(b',') => continue,
+                        Some(c) if c == close => return Ok(()),
+                        _ => return Err(self.error()),
+                    }
+                }
+            }
+            _ => Err(self.error()),
+        }
+    }
+}
+
+/// Export the slot and version as `StubDeploySlot` and
+/// `StubDeployVersion`.
+///
+/// The variables are volatile, so they always describe the current
+/// boot.
+pub fn export_deploy_variables(runtime_services: &RuntimeServices, info: &DeployInfo) -> Result {
+    let attributes = VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS;
+    let encode = |s: &str| {
+        s.encode_utf16()
+            .flat_map(|c| c.to_le_bytes())
+            .collect::<Vec<u8>>()
+    };
+
+    runtime_services.set_variable(
+        cstr16!("StubDeploySlot"),
+        &BOOT_LOADER_VENDOR_UUID,
+        attributes,
+        &encode(info.slot.as_str()),
+    )?;
+    runtime_services.set_variable(
+        cstr16!("StubDeployVersion"),
+        &BOOT_LOADER_VENDOR_UUID,
+        attributes,
+        &encode(&info.version),
+    )
+}
@@ -0,0 +1,583 @@
+//! Install the devicetree for the kernel.
+//!
+//! On boards where the firmware provides a devicetree, the `.dtbo`
+//! sections of the image are applied to it as overlays instead of
+//! replacing it. Otherwise, the `.dtb` section is installed as is.
+//!
+//! The `.dtbo` sections are measured into PCR 11 with the unified
+//! sections, in file order, see [`crate::measure`]. This includes the
+//! ones that are not applied, so PCR 11 only depends on the image.
+//!
+//! [`DeviceTree`] parses and serializes flattened devicetrees and
+//! applies overlays independently of the firmware. Overlays are
+//! expected in the format produced by `dtc -@`, i.e. with
+//! `__fixups__`, `__local_fixups__` and `__symbols__` nodes.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::ffi::c_void;
+
+use uefi::{guid, prelude::*, table::boot::MemoryType, Guid, Result};
+
+use crate::pe_section::{pe_section, pe_section_data, pe_sections, NATIVE_MACHINE};
+
+/// The name of the sections with devicetree overlays.
+pub const OVERLAY_SECTION: &str = ".dtbo";
+
+/// The configuration table the kernel looks for the devicetree in.
+pub const DTB_TABLE_GUID: Guid = guid!("b1b621d5-f19c-41a5-830b-d9152c69aae0");
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// A node of a devicetree.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Node {
+    pub name: String,
+    pub properties: Vec<(String, Vec<u8>)>,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn property(&self, name: &str) -> Option<&[u8]> {
+        self.properties
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn property_mut(&mut self, name: &str) -> Option<&mut Vec<u8>> {
+        self.properties
+            .iter_mut()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Set a property, replacing its previous value.
+    pub fn set_property(&mut self, name: &str, value: Vec<u8>) {
+        match self.property_mut(name) {
+            Some(existing) => *existing = value,
+            None => self.properties.push((name.into(), value)),
+        }
+    }
+
+    /// Find a child by its full name or, if `name` has no unit
+    /// address, by the name before the unit address.
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        let index = self.child_index(name)?;
+        Some(&self.children[index])
+    }
+
+    fn child_mut(&mut self, name: &str) -> Option<&mut Node> {
+        let index = self.child_index(name)?;
+        Some(&mut self.children[index])
+    }
+
+    fn child_index(&self, name: &str) -> Option<usize> {
+        self.children
+            .iter()
+            .position(|c| c.name == name)
+            .or_else(|| {
+                if name.contains('@') {
+                    return None;
+                }
+                self.children
+                    .iter()
+                    .position(|c| c.name.split('@').next() == Some(name))
+            })
+    }
+
+    fn phandle(&self) -> Option<u32> {
+        self.property("phandle")
+            .or_else(|| self.property("linux,phandle"))
+            .and_then(|value| Some(u32::from_be_bytes(value.try_into().ok()?)))
+    }
+}
+
+/// A parsed flattened devicetree.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceTree {
+    pub root: Node,
+    pub boot_cpuid: u32,
+    /// The memory reservation block as address and size pairs.
+    pub reserved_memory: Vec<(u64, u64)>,
+}
+
+impl DeviceTree {
+    /// Parse a flattened devicetree blob.
+    pub fn parse(blob: &[u8]) -> Result<Self> {
+        if be32(blob, 0)? != FDT_MAGIC {
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        let total_size = be32(blob, 4)? as usize;
+        let blob = blob.get(..total_size).ok_or(Status::INVALID_PARAMETER)?;
+        let struct_offset = be32(blob, 8)? as usize;
+        let strings_offset = be32(blob, 12)? as usize;
+        let reserved_memory_offset = be32(blob, 16)? as usize;
+        if be32(blob, 24)? > FDT_VERSION {
+            return Err(Status::INCOMPATIBLE_VERSION.into());
+        }
+        let boot_cpuid = be32(blob, 28)?;
+        let strings = blob
+            .get(strings_offset..)
+            .ok_or(Status::INVALID_PARAMETER)?;
+
+        let mut reserved_memory = Vec::new();
+        let mut offset = reserved_memory_offset;
+        loop {
+            let entry = (be64(blob, offset)?, be64(blob, offset + 8)?);
+            if entry == (0, 0) {
+                break;
+            }
+            reserved_memory.push(entry);
+            offset += 16;
+        }
+
+        let mut offset = struct_offset;
+        if next_token(blob, &mut offset)? != FDT_BEGIN_NODE {
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        let root = parse_node(blob, strings, &mut offset)?;
+        if next_token(blob, &mut offset)? != FDT_END {
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+
+        Ok(Self {
+            root,
+            boot_cpuid,
+            reserved_memory,
+        })
+    }
+
+    /// Serialize the devicetree into a flattened devicetree blob.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut structure = Vec::new();
+        let mut strings = StringTable::default();
+        write_node(&self.root, &mut structure, &mut strings);
+        structure.extend(FDT_END.to_be_bytes());
+
+        let mut reserved_memory = Vec::new();
+        for (address, size) in self.reserved_memory.iter().chain([&(0, 0)]) {
+            reserved_memory.extend(address.to_be_bytes());
+            reserved_memory.extend(size.to_be_bytes());
+        }
+
+        let reserved_memory_offset = FDT_HEADER_SIZE;
+        let struct_offset = reserved_memory_offset + reserved_memory.len();
+        let strings_offset = struct_offset + structure.len();
+        let total_size = strings_offset + strings.data.len();
+
+        let mut blob = Vec::with_capacity(total_size);
+        for field in [
+            FDT_MAGIC,
+            total_size as u32,
+            struct_offset as u32,
+            strings_offset as u32,
+            reserved_memory_offset as u32,
+            FDT_VERSION,
+            FDT_LAST_COMPATIBLE_VERSION,
+            self.boot_cpuid,
+            strings.data.len() as u32,
+            structure.len() as u32,
+        ] {
+            blob.extend(field.to_be_bytes());
+        }
+        blob.extend(reserved_memory);
+        blob.extend(structure);
+        blob.extend(strings.data);
+        blob
+    }
+
+    /// Find a node by its absolute path.
+    pub fn node(&self, path: &str) -> Option<&Node> {
+        path.split('/')
+            .filter(|c| !c.is_empty())
+            .try_fold(&self.root, |node, name| node.child(name))
+    }
+
+    fn node_mut(&mut self, path: &str) -> Option<&mut Node> {
+        path.split('/')
+            .filter(|c| !c.is_empty())
+            .try_fold(&mut self.root, |node, name| node.child_mut(name))
+    }
+
+    /// Apply an overlay to this devicetree.
+    ///
+    /// The phandles of the overlay are moved past the ones of this
+    /// tree, references to labels of this tree are resolved via its
+    /// `__symbols__` and the labels of the overlay are added to it.
+    pub fn apply_overlay(&mut self, overlay: &DeviceTree) -> Result<()> {
+        let mut overlay = overlay.root.clone();
+
+        let delta = max_phandle(&self.root);
+        shift_phandles(&mut overlay, delta)?;
+        if let Some(local_fixups) = overlay.child("__local_fixups__").cloned() {
+            apply_local_fixups(&mut overlay, &local_fixups, delta)?;
+        }
+        if let Some(fixups) = overlay.child("__fixups__").cloned() {
+            self.resolve_fixups(&mut overlay, &fixups)?;
+        }
+
+        // The overlay contents of each fragment and where they ended up.
+        let mut targets = Vec::new();
+        for fragment in overlay
+            .children
+            .iter()
+            .filter(|n| !n.name.starts_with("__"))
+        {
+            let Some(contents) = fragment.child("__overlay__") else {
+                continue;
+            };
+            let target = self.fragment_target(fragment)?;
+            let node = self.node_mut(&target).ok_or(Status::NOT_FOUND)?;
+            merge(node, contents);
+            targets.push((format!("/{}/__overlay__", fragment.name), target));
+        }
+
+        if let Some(symbols) = overlay.child("__symbols__") {
+            let mut resolved = Vec::new();
+            for (label, value) in &symbols.properties {
+                let path = string_property(value)?;
+                let Some(path) = targets.iter().find_map(|(prefix, target)| {
+                    let rest = path.strip_prefix(prefix.as_str())?;
+                    Some(format!("{}{rest}", target.trim_end_matches('/')))
+                }) else {
+                    continue;
+                };
+                resolved.push((label, path));
+            }
+
+            if !resolved.is_empty() && self.root.child("__symbols__").is_none() {
+                self.root.children.push(Node::new("__symbols__"));
+            }
+            if let Some(base_symbols) = self.root.child_mut("__symbols__") {
+                for (label, path) in resolved {
+                    let mut value = path.into_bytes();
+                    value.push(0);
+                    base_symbols.set_property(label, value);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the phandles of the nodes of this tree that the overlay
+    /// refers to by label into the overlay.
+    fn resolve_fixups(&self, overlay: &mut Node, fixups: &Node) -> Result<()> {
+        let symbols = self.node("/__symbols__").ok_or(Status::NOT_FOUND)?;
+        for (label, references) in &fixups.properties {
+            let path = string_property(symbols.property(label).ok_or(Status::NOT_FOUND)?)?;
+            let phandle = self
+                .node(path)
+                .and_then(Node::phandle)
+                .ok_or(Status::NOT_FOUND)?;
+
+            for reference in references.split(|b| *b == 0).filter(|r| !r.is_empty()) {
+                let reference =
+                    core::str::from_utf8(reference).map_err(|_| Status::INVALID_PARAMETER)?;
+                let mut parts = reference.rsplitn(3, ':');
+                let (Some(offset), Some(property), Some(path)) =
+                    (parts.next(), parts.next(), parts.next())
+                else {
+                    return Err(Status::INVALID_PARAMETER.into());
+                };
+                let offset: usize = offset.parse().map_err(|_| Status::INVALID_PARAMETER)?;
+
+                let node = path
+                    .split('/')
+                    .filter(|c| !c.is_empty())
+                    .try_fold(&mut *overlay, |node, name| node.child_mut(name))
+                    .ok_or(Status::NOT_FOUND)?;
+                let value = node.property_mut(property).ok_or(Status::NOT_FOUND)?;
+                set_be32(value, offset, phandle)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The path of the node a fragment of an overlay applies to.
+    fn fragment_target(&self, fragment: &Node) -> Result<String> {
+        if let Some(path) = fragment.property("target-path") {
+            let path = string_property(path)?;
+            self.node(path).ok_or(Status::NOT_FOUND)?;
+            return Ok(path.to_string());
+        }
+        let target = fragment
+            .property("target")
+            .and_then(|value| Some(u32::from_be_bytes(value.try_into().ok()?)))
+            .ok_or(Status::INVALID_PARAMETER)?;
+        let mut path = String::new();
+        if find_phandle(&self.root, target, &mut path) {
+            Ok(if path.is_empty() { "/".into() } else { path })
+        } else {
+            Err(Status::NOT_FOUND.into())
+        }
+    }
+}
+
+/// Install the devicetree for the kernel.
+///
+/// If the firmware already installed a devicetree, the `.dtbo`
+/// sections of `image` are applied to it. Otherwise, the `.dtb`
+/// section is installed, if there is one.
+pub fn install_devicetree(system_table: &SystemTable<Boot>, image: &[u8]) -> Result<()> {
+    let firmware_dtb = system_table
+        .config_table()
+        .iter()
+        .find(|entry| entry.guid == DTB_TABLE_GUID)
+        .map(|entry| entry.address as *const u8);
+
+    let Some(firmware_dtb) = firmware_dtb else {
+        return match pe_section(image, ".dtb", Some(NATIVE_MACHINE))? {
+            Some(dtb) => install_dtb(system_table.boot_services(), dtb),
+            None => Ok(()),
+        };
+    };
+
+    let sections = pe_sections(image).ok_or(Status::LOAD_ERROR)?;
+    let overlays: Vec<&[u8]> = sections
+        .iter()
+        .filter(|s| s.name().ok() == Some(OVERLAY_SECTION))
+        .filter_map(|s| pe_section_data(image, s))
+        .collect();
+    if overlays.is_empty() {
+        return Ok(());
+    }
+
+    // SAFETY: The firmware keeps the devicetree it installed in
+    // memory. Its size is given in its header.
+    let firmware_dtb = unsafe {
+        let header = core::slice::from_raw_parts(firmware_dtb, FDT_HEADER_SIZE);
+        let total_size = be32(header, 4)? as usize;
+        core::slice::from_raw_parts(firmware_dtb, total_size)
+    };
+    let mut devicetree = DeviceTree::parse(firmware_dtb)?;
+    for overlay in overlays {
+        devicetree.apply_overlay(&DeviceTree::parse(overlay)?)?;
+    }
+    install_dtb(system_table.boot_services(), &devicetree.to_bytes())
+}
+
+/// Copy a devicetree blob into memory that outlives the stub and
+/// install it as the configuration table.
+fn install_dtb(boot_services: &BootServices, dtb: &[u8]) -> Result<()> {
+    let memory = boot_services.allocate_pool(MemoryType::ACPI_RECLAIM, dtb.len())?;
+    // SAFETY: The allocation is large enough for the blob and is
+    // never freed, because the kernel reads the devicetree from it.
+    unsafe {
+        core::ptr::copy_nonoverlapping(dtb.as_ptr(), memory, dtb.len());
+        boot_services.install_configuration_table(&DTB_TABLE_GUID, memory as *const c_void)
+    }
+}
+
+/// The strings block of a devicetree, with each name stored once.
+#[derive(Default)]
+struct StringTable {
+    data: Vec<u8>,
+    offsets: Vec<(String, u32)>,
+}
+
+impl StringTable {
+    fn offset(&mut self, name: &str) -> u32 {
+        if let Some((_, offset)) = self.offsets.iter().find(|(n, _)| n == name) {
+            return *offset;
+        }
+        let offset = self.data.len() as u32;
+        self.data.extend(name.as_bytes());
+        self.data.push(0);
+        self.offsets.push((name.into(), offset));
+        offset
+    }
+}
+
+fn write_node(node: &Node, structure: &mut Vec<u8>, strings: &mut StringTable) {
+    structure.extend(FDT_BEGIN_NODE.to_be_bytes());
+    structure.extend(node.name.as_bytes());
+    structure.push(0);
+    pad(structure);
+
+    for (name, value) in &node.properties {
+        structure.extend(FDT_PROP.to_be_bytes());
+        structure.extend((value.len() as u32).to_be_bytes());
+        structure.extend(strings.offset(name).to_be_bytes());
+        structure.extend(value);
+        pad(structure);
+    }
+    for child in &node.children {
+        write_node(child, structure, strings);
+    }
+    structure.extend(FDT_END_NODE.to_be_bytes());
+}
+
+/// Parse a node whose `FDT_BEGIN_NODE` token was just read.
+fn parse_node(blob: &[u8], strings: &[u8], offset: &mut usize) -> Result<Node> {
+    let name = c_string(blob, *offset)?;
+    *offset = align(*offset + name.len() + 1);
+    let mut node = Node::new(name);
+
+    loop {
+        match next_token(blob, offset)? {
+            FDT_PROP => {
+                let len = be32(blob, *offset)? as usize;
+                let name_offset = be32(blob, *offset + 4)? as usize;
+                let start = *offset + 8;
+                let value = blob
+                    .get(start..start + len)
+                    .ok_or(Status::INVALID_PARAMETER)?;
+                node.properties
+                    .push((c_string(strings, name_offset)?.into(), value.to_vec()));
+                *offset = align(start + len);
+            }
+            FDT_BEGIN_NODE => node.children.push(parse_node(blob, strings, offset)?),
+            FDT_END_NODE => return Ok(node),
+            _ => return Err(Status::INVALID_PARAMETER.into()),
+        }
+    }
+}
+
+/// Read the next token, skipping `FDT_NOP`.
+fn next_token(blob: &[u8], offset: &mut usize) -> Result<u32> {
+    loop {
+        let token = be32(blob, *offset)?;
+        *offset += 4;
+        if token != FDT_NOP {
+            return Ok(token);
+        }
+    }
+}
+
+/// Merge the properties and children of `source` into `target`.
+fn merge(target: &mut Node, source: &Node) {
+    for (name, value) in &source.properties {
+        target.set_property(name, value.clone());
+    }
+    for child in &source.children {
+        match target.children.iter_mut().find(|c| c.name == child.name) {
+            Some(existing) => merge(existing, child),
+            None => target.children.push(child.clone()),
+        }
+    }
+}
+
+fn max_phandle(node: &Node) -> u32 {
+    node.children
+        .iter()
+        .map(max_phandle)
+        .chain(node.phandle())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Move all phandles defined by the overlay by `delta`.
+fn shift_phandles(node: &mut Node, delta: u32) -> Result<()> {
+    for (name, value) in &mut node.properties {
+        if name == "phandle" || name == "linux,phandle" {
+            let phandle = be32(value, 0)?;
+            set_be32(value, 0, phandle + delta)?;
+        }
+    }
+    for child in &mut node.children {
+        shift_phandles(child, delta)?;
+    }
+    Ok(())
+}
+
+/// Move the references of the overlay to its own phandles by `delta`.
+///
+/// `local_fixups` mirrors the structure of `node`. Its properties list
+/// the offsets of the references in the properties of `node`.
+fn apply_local_fixups(node: &mut Node, local_fixups: &Node, delta: u32) -> Result<()> {
+    for (name, offsets) in &local_fixups.properties {
+        let value = node.property_mut(name).ok_or(Status::NOT_FOUND)?;
+        for offset in offsets.chunks_exact(4) {
+            let offset = u32::from_be_bytes(offset.try_into().unwrap()) as usize;
+            let phandle = be32(value, offset)?;
+            set_be32(value, offset, phandle + delta)?;
+        }
+    }
+    for child in &local_fixups.children {
+        let node = node.child_mut(&child.name).ok_or(Status::NOT_FOUND)?;
+        apply_local_fixups(node, child, delta)?;
+    }
+    Ok(())
+}
+
+/// Find the path of the node with `phandle`, relative to `node`.
+fn find_phandle(node: &Node, phandle: u32, path: &mut String) -> bool {
+    if node.phandle() == Some(phandle) {
+        return true;
+    }
+    for child in &node.children {
+        let len = path.len();
+        path.push('/');
+        path.push_str(&child.name);
+        if find_phandle(child, phandle, path) {
+            return true;
+        }
+        path.truncate(len);
+    }
+    false
+}
+
+fn string_property(value: &[u8]) -> Result<&str> {
+    let value = value.strip_suffix(&[0]).unwrap_or(value);
+    core::str::from_utf8(value).map_err(|_| Status::INVALID_PARAMETER.into())
+}
+
+fn c_string(data: &[u8], offset: usize) -> Result<&str> {
+    let data = data.get(offset..).ok_or(Status::INVALID_PARAMETER)?;
+    let len = data
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or(Status::INVALID_PARAMETER)?;
+    core::str::from_utf8(&data[..len]).map_err(|_| Status::INVALID_PARAMETER.into())
+}
+
+fn be32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .ok_or(Status::INVALID_PARAMETER)?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn be64(data: &[u8], offset: usize) -> Result<u64> {
+    let bytes = data
+        .get(offset..offset + 8)
+        .ok_or(Status::INVALID_PARAMETER)?;
+    Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn set_be32(data: &mut [u8], offset: usize, value: u32) -> Result<()> {
+    data.get_mut(offset..offset + 4)
+        .ok_or(Status::INVALID_PARAMETER)?
+        .copy_from_slice(&value.to_be_bytes());
+    Ok(())
+}
+
+/// Round up to the 4 byte alignment of the structure block.
+fn align(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+fn pad(data: &mut Vec<u8>) {
+    data.resize(align(data.len()), 0);
+}
@@ -0,0 +1,342 @@
+//! A diagnostic page that summarizes what the stub knows about the
+//! system and the image it was booted from.
+//!
+//! [`Diagnostics::lines`] renders the page independently of the
+//! firmware. [`Diagnostics::collect`] gathers the inputs from UEFI and
+//! [`show_diagnostics`] prints the page and waits for a key.
+//!
+//! If the kernel fails to take over, e.g. because ExitBootServices
+//! failed with a stale memory map, [`log_memory_map`] logs the memory
+//! map as rendered by [`memory_map_lines`].
+
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::Write;
+
+use log::warn;
+use uefi::{prelude::*, proto::console::text::Key, table::boot::MemoryType, Result};
+
+use crate::pe_section::{pe_section_as_string, pe_sections};
+
+/// Size of a page in the memory map.
+const PAGE_SIZE: u64 = 4096;
+
+/// Command line parameters whose values are masked on the page.
+///
+/// This must match the list in lzbt.
+const SENSITIVE_PARAMS: [&str; 2] = ["rd.luks.key", "luks.key"];
+
+/// Parameters that pass systemd credentials as `NAME:VALUE`. Only the
+/// value is masked.
+const CREDENTIAL_PARAMS: [&str; 2] = ["systemd.set_credential", "systemd.set_credential_binary"];
+
+/// The memory map condensed to a few numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemorySummary {
+    /// The number of descriptors in the memory map.
+    pub descriptors: usize,
+    /// All pages in the memory map.
+    pub total_pages: u64,
+    /// Pages that are not used by anything yet.
+    pub free_pages: u64,
+}
+
+impl MemorySummary {
+    /// Summarize memory descriptors given as their type and number of
+    /// pages.
+    pub fn from_descriptors(descriptors: impl IntoIterator<Item = (MemoryType, u64)>) -> Self {
+        let mut summary = Self {
+            descriptors: 0,
+            total_pages: 0,
+            free_pages: 0,
+        };
+        for (ty, pages) in descriptors {
+            summary.descriptors += 1;
+            summary.total_pages += pages;
+            if ty == MemoryType::CONVENTIONAL {
+                summary.free_pages += pages;
+            }
+        }
+        summary
+    }
+}
+
+/// A range of the memory map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub ty: MemoryType,
+    pub phys_start: u64,
+    pub pages: u64,
+}
+
+/// Render the memory map: the regions, with adjacent descriptors of the
+/// same type merged, followed by the total size of each type.
+pub fn memory_map_lines(regions: &[MemoryRegion]) -> Vec<String> {
+    let mut sorted = regions.to_vec();
+    sorted.sort_by_key(|r| r.phys_start);
+    let mut merged: Vec<MemoryRegion> = Vec::new();
+    for region in sorted {
+        match merged.last_mut() {
+            Some(last)
+                if last.ty == region.ty
+                    && last.phys_start + last.pages * PAGE_SIZE == region.phys_start =>
+            {
+                last.pages += region.pages;
+            }
+            _ => merged.push(region),
+        }
+    }
+
+    let mut lines = Vec::new();
+    lines.push(format!(
+        "Memory map: {} descriptors in {} regions",
+        regions.len(),
+        merged.len()
+    ));
+    for region in &merged {
+        lines.push(format!(
+            "  {:#018x}-{:#018x} {:<20} {:>9}",
+            region.phys_start,
+            region.phys_start + region.pages * PAGE_SIZE - 1,
+            memory_type_name(region.ty),
+            format_size(region.pages * PAGE_SIZE)
+        ));
+    }
+
+    let mut totals: Vec<(MemoryType, u64)> = Vec::new();
+    for region in regions {
+        match totals.iter_mut().find(|(ty, _)| *ty == region.ty) {
+            Some((_, pages)) => *pages += region.pages,
+            None => totals.push((region.ty, region.pages)),
+        }
+    }
+    totals.sort_by_key(|(ty, _)| ty.0);
+    lines.push("Totals:".into());
+    for (ty, pages) in totals {
+        lines.push(format!(
+            "  {:<20} {:>9}",
+            memory_type_name(ty),
+            format_size(pages * PAGE_SIZE)
+        ));
+    }
+    lines
+}
+
+/// The name of a memory type as in the UEFI specification, without
+/// the `Efi` prefix and `Memory` suffix.
+fn memory_type_name(ty: MemoryType) -> String {
+    let name = match ty {
+        MemoryType::RESERVED => "Reserved",
+        MemoryType::LOADER_CODE => "LoaderCode",
+        MemoryType::LOADER_DATA => "LoaderData",
+        MemoryType::BOOT_SERVICES_CODE => "BootServicesCode",
+        MemoryType::BOOT_SERVICES_DATA => "BootServicesData",
+        MemoryType::RUNTIME_SERVICES_CODE => "RuntimeServicesCode",
+        MemoryType::RUNTIME_SERVICES_DATA => "RuntimeServicesData",
+        MemoryType::CONVENTIONAL => "Conventional",
+        MemoryType::UNUSABLE => "Unusable",
+        MemoryType::ACPI_RECLAIM => "ACPIReclaim",
+        MemoryType::ACPI_NON_VOLATILE => "ACPIMemoryNVS",
+        MemoryType::MMIO => "MemoryMappedIO",
+        MemoryType::MMIO_PORT_SPACE => "MemoryMappedIOPort",
+        MemoryType::PAL_CODE => "PalCode",
+        MemoryType::PERSISTENT_MEMORY => "Persistent",
+        other => return format!("{:#x}", other.0),
+    };
+    name.into()
+}
+
+/// Format a size in the largest binary unit it has at least one of,
+/// rounded down.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes / 1024;
+    let mut unit = 0;
+    while value >= 1024 && unit + 1 < UNITS.len() {
+        value /= 1024;
+        unit += 1;
+    }
+    format!("{value} {}", UNITS[unit])
+}
+
+/// Everything shown on the diagnostic page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostics {
+    pub secure_boot: bool,
+    pub firmware_vendor: String,
+    pub firmware_revision: u32,
+    /// The embedded kernel command line.
+    pub cmdline: Option<String>,
+    /// The names and sizes of the sections of the image.
+    pub sections: Vec<(String, u32)>,
+    /// `None` if the memory map could not be read.
+    pub memory: Option<MemorySummary>,
+}
+
+impl Diagnostics {
+    /// Gather the diagnostics from the firmware and the image in
+    /// memory.
+    pub fn collect(system_table: &SystemTable<Boot>, image: &[u8], secure_boot: bool) -> Self {
+        Self {
+            secure_boot,
+            firmware_vendor: format!("{}", system_table.firmware_vendor()),
+            firmware_revision: system_table.firmware_revision(),
+            cmdline: pe_section_as_string(image, ".cmdline"),
+            sections: image_sections(image),
+            memory: memory_summary(system_table.boot_services()).ok(),
+        }
+    }
+
+    /// Render the diagnostic page.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        lines.push("Lanzaboote diagnostics".into());
+        lines.push(format!(
+            "Secure Boot: {}",
+            if self.secure_boot {
+                "enabled"
+            } else {
+                "disabled"
+            }
+        ));
+        lines.push(format!(
+            "Firmware: {} (revision {:#x})",
+            self.firmware_vendor, self.firmware_revision
+        ));
+        lines.push(match &self.cmdline {
+            Some(cmdline) => format!("Command line: {}", redact_cmdline(cmdline)),
+            None => "Command line: (none)".into(),
+        });
+
+        lines.push("Sections:".into());
+        for (name, size) in &self.sections {
+            lines.push(format!("  {name:<8} {size:>10} bytes"));
+        }
+
+        lines.push(match self.memory {
+            Some(memory) => format!(
+                "Memory: {} MiB free of {} MiB in {} descriptors",
+                memory.free_pages * PAGE_SIZE / (1024 * 1024),
+                memory.total_pages * PAGE_SIZE / (1024 * 1024),
+                memory.descriptors
+            ),
+            None => "Memory: failed to read the memory map".into(),
+        });
+        lines
+    }
+}
+
+/// Mask the secrets in a command line, so that it can be shown on
+/// screen. The command line that is passed to the kernel is never
+/// redacted.
+pub fn redact_cmdline(cmdline: &str) -> String {
+    let mut redacted = String::new();
+    for param in cmdline.split_whitespace() {
+        if !redacted.is_empty() {
+            redacted.push(' ');
+        }
+        match param.split_once('=') {
+            Some((key, _)) if SENSITIVE_PARAMS.contains(&key) => {
+                let _ = write!(redacted, "{key}=***");
+            }
+            Some((key, value)) if CREDENTIAL_PARAMS.contains(&key) => match value.split_once(':') {
+                Some((name, _)) => {
+                    let _ = write!(redacted, "{key}={name}:***");
+                }
+                None => {
+                    let _ = write!(redacted, "{key}=***");
+                }
+            },
+            _ => redacted.push_str(param),
+        }
+    }
+    redacted
+}
+
+/// The names and sizes of all sections of a loaded PE image.
+pub fn image_sections(image: &[u8]) -> Vec<(String, u32)> {
+    let Some(sections) = pe_sections(image) else {
+        return Vec::new();
+    };
+    sections
+        .iter()
+        .map(|s| (String::from(s.name().unwrap_or("?")), s.virtual_size))
+        .collect()
+}
+
+/// Read the memory map.
+pub fn memory_regions(boot_services: &BootServices) -> Result<Vec<MemoryRegion>> {
+    let size = boot_services.memory_map_size();
+    // The map can grow by a few entries when the buffer is allocated.
+    let buffer_size = size.map_size + 8 * size.entry_size;
+    // The buffer must be aligned like a memory descriptor.
+    let mut buffer: Vec<u64> = alloc::vec![0; (buffer_size + 7) / 8];
+    // SAFETY: The byte slice covers exactly the allocation of `buffer`.
+    let bytes = unsafe {
+        core::slice::from_raw_parts_mut(buffer.as_mut_ptr().cast::<u8>(), buffer.len() * 8)
+    };
+    let map = boot_services.memory_map(bytes)?;
+
+    Ok(map
+        .entries()
+        .map(|d| MemoryRegion {
+            ty: d.ty,
+            phys_start: d.phys_start,
+            pages: d.page_count,
+        })
+        .collect())
+}
+
+/// Read and summarize the memory map.
+pub fn memory_summary(boot_services: &BootServices) -> Result<MemorySummary> {
+    Ok(MemorySummary::from_descriptors(
+        memory_regions(boot_services)?
+            .into_iter()
+            .map(|r| (r.ty, r.pages)),
+    ))
+}
+
+/// Log the memory map, to debug a kernel that failed to take over.
+///
+/// This is logged as warnings so that it also shows up in release
+/// builds and on the serial console.
+pub fn log_memory_map(boot_services: &BootServices) {
+    match memory_regions(boot_services) {
+        Ok(regions) => {
+            for line in memory_map_lines(&regions) {
+                warn!("{line}");
+            }
+        }
+        Err(err) => warn!("Failed to read the memory map: {err:?}"),
+    }
+}
+
+/// Print the diagnostic page and wait for a key before continuing.
+pub fn show_diagnostics(system_table: &mut SystemTable<Boot>, diagnostics: &Diagnostics) {
+    // Errors are ignored, there is nothing useful to do about them.
+    for line in diagnostics.lines() {
+        let _ = write!(system_table.stdout(), "{line}\r\n");
+    }
+    let _ = write!(
+        system_table.stdout(),
+        "Press any key to continue booting.\r\n"
+    );
+
+    let Some(key_event) = system_table.stdin().wait_for_key_event() else {
+        return;
+    };
+    // SAFETY: The event stays alive while it is waited for.
+    let mut events = [unsafe { key_event.unsafe_clone() }];
+    if system_table
+        .boot_services()
+        .wait_for_event(&mut events)
+        .is_ok()
+    {
+        let _ = system_table.stdin().read_key();
+    }
+}
+
+/// The key that was pressed since the stub started, if any, without
+/// waiting.
+pub fn pressed_key(system_table: &mut SystemTable<Boot>) -> Option<Key> {
+    system_table.stdin().read_key().ok().flatten()
+}
@@ -1,4 +1,9 @@
-use alloc::{format, string::ToString, vec, vec::Vec};
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
 use uefi::{
     cstr16, guid,
     prelude::{BootServices, RuntimeServices},
@@ -14,7 +19,7 @@ use uefi::{
         runtime::{VariableAttributes, VariableVendor},
         Boot, SystemTable,
     },
-    CStr16, Guid, Handle, Result,
+    CStr16, CString16, Guid, Handle, Result,
 };
 
 use bitflags::bitflags;
@@ -141,121 +146,200 @@ where
     Ok(())
 }
 
+/// Storage of EFI variables.
+pub trait VariableStore {
+    /// Whether the variable exists.
+    fn exists(&self, name: &CStr16, vendor: &VariableVendor) -> bool;
+
+    /// The contents of the variable. Fails with `NOT_FOUND` if it does
+    /// not exist.
+    fn read(&self, name: &CStr16, vendor: &VariableVendor) -> Result<Vec<u8>>;
+
+    /// Create or overwrite the variable.
+    fn set(
+        &mut self,
+        name: &CStr16,
+        vendor: &VariableVendor,
+        attributes: VariableAttributes,
+        data: &[u8],
+    ) -> Result;
+}
+
+/// The variables of the firmware.
+pub struct RuntimeVariables<'a>(pub &'a RuntimeServices);
+
+impl VariableStore for RuntimeVariables<'_> {
+    fn exists(&self, name: &CStr16, vendor: &VariableVendor) -> bool {
+        self.0.get_variable_size(name, vendor).is_ok()
+    }
+
+    fn read(&self, name: &CStr16, vendor: &VariableVendor) -> Result<Vec<u8>> {
+        let mut buffer = vec![0; self.0.get_variable_size(name, vendor)?];
+        let (data, _) = self.0.get_variable(name, vendor, &mut buffer)?;
+        Ok(data.to_vec())
+    }
+
+    fn set(
+        &mut self,
+        name: &CStr16,
+        vendor: &VariableVendor,
+        attributes: VariableAttributes,
+        data: &[u8],
+    ) -> Result {
+        self.0.set_variable(name, vendor, attributes, data)
+    }
+}
+
+/// The path of the running image on its partition as text, e.g.
+/// `\EFI\Linux\nixos-generation-1.efi`.
+pub fn image_identifier(boot_services: &BootServices) -> Option<CString16> {
+    let loaded_image = boot_services
+        .open_protocol_exclusive::<LoadedImage>(boot_services.image_handle())
+        .ok()?;
+    let dp_protocol = boot_services
+        .open_protocol_exclusive::<DevicePathToText>(
+            boot_services
+                .get_handle_for_protocol::<DevicePathToText>()
+                .ok()?,
+        )
+        .ok()?;
+    dp_protocol
+        .convert_device_path_to_text(
+            boot_services,
+            loaded_image.file_path()?,
+            uefi::proto::device_path::text::DisplayOnly(false),
+            uefi::proto::device_path::text::AllowShortcuts(false),
+        )
+        .ok()
+        .map(|text| CString16::from(&*text))
+}
+
+/// Where and on which firmware the stub was started, as reported in
+/// the variables of the [boot loader
+/// interface](https://systemd.io/BOOT_LOADER_INTERFACE/).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoaderInfo {
+    /// The partition the stub was loaded from.
+    pub device_part_uuid: Option<Guid>,
+    /// The path of the stub on that partition as text.
+    pub image_identifier: Option<CString16>,
+    pub firmware_vendor: CString16,
+    pub firmware_revision: u32,
+    pub uefi_revision: u32,
+}
+
+impl LoaderInfo {
+    /// Collect the information about the running stub.
+    pub fn probe(system_table: &SystemTable<Boot>) -> Result<Self> {
+        let boot_services = system_table.boot_services();
+        let loaded_image =
+            boot_services.open_protocol_exclusive::<LoadedImage>(boot_services.image_handle())?;
+
+        let device_part_uuid = loaded_image
+            .device()
+            .and_then(|device| disk_get_part_uuid(boot_services, device).ok());
+        drop(loaded_image);
+
+        Ok(Self {
+            device_part_uuid,
+            image_identifier: image_identifier(boot_services),
+            firmware_vendor: system_table.firmware_vendor().into(),
+            firmware_revision: system_table.firmware_revision(),
+            uefi_revision: system_table.uefi_revision().0,
+        })
+    }
+
+    /// The variables with their values, in the format systemd-boot
+    /// uses: NUL terminated UCS-2 strings.
+    pub fn variables(&self) -> Vec<(&'static CStr16, Vec<u8>)> {
+        let mut variables = Vec::new();
+        if let Some(uuid) = self.device_part_uuid {
+            variables.push((
+                cstr16!("LoaderDevicePartUUID"),
+                ucs2_bytes(&uuid.to_string()),
+            ));
+        }
+        if let Some(identifier) = &self.image_identifier {
+            variables.push((
+                cstr16!("LoaderImageIdentifier"),
+                cstr16_to_bytes(identifier).to_vec(),
+            ));
+        }
+        variables.push((
+            cstr16!("LoaderFirmwareInfo"),
+            ucs2_bytes(&format!(
+                "{} {}",
+                self.firmware_vendor,
+                revision_string(self.firmware_revision)
+            )),
+        ));
+        variables.push((
+            cstr16!("LoaderFirmwareType"),
+            ucs2_bytes(&format!("UEFI {}", revision_string(self.uefi_revision))),
+        ));
+        variables
+    }
+}
+
+/// Format a revision like systemd-boot: the major version in the
+/// upper 16 bits, followed by the minor version in the lower 16 bits
+/// with at least two digits.
+fn revision_string(revision: u32) -> String {
+    format!("{}.{:02}", revision >> 16, revision & 0xFFFF)
+}
+
+/// Encode a string as NUL terminated UCS-2.
+pub(crate) fn ucs2_bytes(value: &str) -> Vec<u8> {
+    value
+        .encode_utf16()
+        .chain(core::iter::once(0))
+        .flat_map(|c| c.to_le_bytes())
+        .collect()
+}
+
+/// Export the boot loader interface variables of `info`.
+///
+/// A boot loader like systemd-boot already sets them when it starts
+/// the stub. Existing variables are left alone, so they keep
+/// describing the boot loader.
+pub fn export_loader_variables(store: &mut impl VariableStore, info: &LoaderInfo) {
+    for (name, value) in info.variables() {
+        if !store.exists(name, &BOOT_LOADER_VENDOR_UUID) {
+            let _ = store.set(name, &BOOT_LOADER_VENDOR_UUID, default_attributes(), &value);
+        }
+    }
+}
+
+fn default_attributes() -> VariableAttributes {
+    VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS
+}
+
 /// Exports systemd-stub style EFI variables
 pub fn export_efi_variables(stub_info_name: &str, system_table: &SystemTable<Boot>) -> Result<()> {
-    let boot_services = system_table.boot_services();
-    let runtime_services = system_table.runtime_services();
+    let mut store = RuntimeVariables(system_table.runtime_services());
 
     let stub_features: EfiStubFeatures = EfiStubFeatures::ReportBootPartition;
 
-    let loaded_image =
-        boot_services.open_protocol_exclusive::<LoadedImage>(boot_services.image_handle())?;
-
-    let default_attributes =
-        VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS;
-
-    #[allow(unused_must_use)]
-    // LoaderDevicePartUUID
-    ensure_efi_variable(
-        runtime_services,
-        cstr16!("LoaderDevicePartUUID"),
-        &BOOT_LOADER_VENDOR_UUID,
-        default_attributes,
-        || {
-            disk_get_part_uuid(
-                boot_services,
-                loaded_image.device().ok_or(uefi::Status::NOT_FOUND)?,
-            )
-            .map(|guid| {
-                guid.to_string()
-                    .encode_utf16()
-                    .flat_map(|c| c.to_le_bytes())
-                    .collect::<Vec<u8>>()
-            })
-        },
-    )
-    .ok();
-    // LoaderImageIdentifier
-    ensure_efi_variable(
-        runtime_services,
-        cstr16!("LoaderImageIdentifier"),
-        &BOOT_LOADER_VENDOR_UUID,
-        default_attributes,
-        || {
-            if let Some(dp) = loaded_image.file_path() {
-                let dp_protocol = boot_services.open_protocol_exclusive::<DevicePathToText>(
-                    boot_services.get_handle_for_protocol::<DevicePathToText>()?,
-                )?;
-                dp_protocol
-                    .convert_device_path_to_text(
-                        boot_services,
-                        dp,
-                        uefi::proto::device_path::text::DisplayOnly(false),
-                        uefi::proto::device_path::text::AllowShortcuts(false),
-                    )
-                    .map(|ps| cstr16_to_bytes(&ps).to_vec())
-            } else {
-                // If we cannot retrieve the filepath of the loaded image
-                // Then, we cannot set `LoaderImageIdentifier`.
-                Err(uefi::Status::UNSUPPORTED.into())
-            }
-        },
-    )
-    .ok();
-    // LoaderFirmwareInfo
-    ensure_efi_variable(
-        runtime_services,
-        cstr16!("LoaderFirmwareInfo"),
-        &BOOT_LOADER_VENDOR_UUID,
-        default_attributes,
-        || {
-            Ok(format!(
-                "{} {}.{:02}",
-                system_table.firmware_vendor(),
-                system_table.firmware_revision() >> 16,
-                system_table.firmware_revision() & 0xFFFFF
-            )
-            .encode_utf16()
-            .flat_map(|c| c.to_le_bytes())
-            .collect::<Vec<u8>>())
-        },
-    )
-    .ok();
-    // LoaderFirmwareType
-    ensure_efi_variable(
-        runtime_services,
-        cstr16!("LoaderFirmwareType"),
-        &BOOT_LOADER_VENDOR_UUID,
-        default_attributes,
-        || {
-            Ok(format!("UEFI {:02}", system_table.uefi_revision())
-                .encode_utf16()
-                .flat_map(|c| c.to_le_bytes())
-                .collect::<Vec<u8>>())
-        },
-    )
-    .ok();
+    export_loader_variables(&mut store, &LoaderInfo::probe(system_table)?);
+
     // StubInfo
     // FIXME: ideally, no one should be able to overwrite `StubInfo`, but that would require
     // constructing an EFI authenticated variable payload. This seems overcomplicated for now.
-    runtime_services
-        .set_variable(
+    store
+        .set(
             cstr16!("StubInfo"),
             &BOOT_LOADER_VENDOR_UUID,
-            default_attributes,
-            &stub_info_name
-                .encode_utf16()
-                .flat_map(|c| c.to_le_bytes())
-                .collect::<Vec<u8>>(),
+            default_attributes(),
+            &ucs2_bytes(stub_info_name),
         )
         .ok();
 
     // StubFeatures
-    runtime_services
-        .set_variable(
+    store
+        .set(
             cstr16!("StubFeatures"),
             &BOOT_LOADER_VENDOR_UUID,
-            default_attributes,
+            default_attributes(),
             &stub_features.bits().to_le_bytes(),
         )
         .ok();
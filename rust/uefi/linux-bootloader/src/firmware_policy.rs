@@ -0,0 +1,159 @@
+//! Refuse to boot on firmware that is known to be too old.
+//!
+//! Some firmware has bugs that break LoadFile2 or Secure Boot, which
+//! are only fixed by a firmware update. The `.fwmin` section names the
+//! oldest firmware revision that is known to work, one `key=value` per
+//! line:
+//!
+//! ```text
+//! revision=0x10002
+//! vendor=EDK II
+//! action=refuse
+//! ```
+//!
+//! `revision` is required and may be given in decimal or, prefixed
+//! with `0x`, in hexadecimal. Its meaning depends on the vendor, so a
+//! `vendor` restricts the check to firmware that reports exactly that
+//! vendor. `action` is either `warn` (the default) or `refuse`.
+//!
+//! [`FirmwarePolicy::check`] does not depend on the firmware. It reads
+//! the revision from any [`FirmwareIdentity`], e.g. the system table.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+};
+use core::fmt;
+
+use uefi::prelude::*;
+
+/// What to do if the firmware is too old.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirmwareAction {
+    /// Log a warning and boot anyway.
+    Warn,
+    /// Stop the boot.
+    Refuse,
+}
+
+/// Why a `.fwmin` section could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FirmwarePolicyError {
+    /// A line is not of the form `key=value`.
+    Syntax(String),
+    /// A key is not known.
+    UnknownKey(String),
+    /// A key appears more than once.
+    DuplicateKey(String),
+    /// The revision is missing.
+    MissingRevision,
+    /// The revision is not a 32 bit number.
+    InvalidRevision(String),
+    /// The action is neither `warn` nor `refuse`.
+    InvalidAction(String),
+}
+
+impl fmt::Display for FirmwarePolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syntax(line) => write!(f, "expected key=value, got {line:?}"),
+            Self::UnknownKey(key) => write!(f, "unknown key {key:?}"),
+            Self::DuplicateKey(key) => write!(f, "duplicate key {key:?}"),
+            Self::MissingRevision => f.write_str("missing revision"),
+            Self::InvalidRevision(value) => write!(f, "invalid revision {value:?}"),
+            Self::InvalidAction(value) => {
+                write!(f, "action {value:?} is neither \"warn\" nor \"refuse\"")
+            }
+        }
+    }
+}
+
+/// The firmware the stub runs on.
+pub trait FirmwareIdentity {
+    fn firmware_vendor(&self) -> String;
+    fn firmware_revision(&self) -> u32;
+}
+
+impl FirmwareIdentity for SystemTable<Boot> {
+    fn firmware_vendor(&self) -> String {
+        SystemTable::firmware_vendor(self).to_string()
+    }
+
+    fn firmware_revision(&self) -> u32 {
+        SystemTable::firmware_revision(self)
+    }
+}
+
+/// The contents of the `.fwmin` section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirmwarePolicy {
+    /// The oldest revision that is known to work.
+    pub min_revision: u32,
+    /// Only check firmware of this vendor.
+    pub vendor: Option<String>,
+    pub action: FirmwareAction,
+}
+
+impl FirmwarePolicy {
+    /// Parse the contents of a `.fwmin` section.
+    pub fn parse(section: &str) -> core::result::Result<Self, FirmwarePolicyError> {
+        let mut min_revision = None;
+        let mut vendor = None;
+        let mut action = None;
+
+        for line in section.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| FirmwarePolicyError::Syntax(line.into()))?;
+            let (key, value) = (key.trim(), value.trim());
+            let duplicate = match key {
+                "revision" => min_revision.replace(parse_revision(value)?).is_some(),
+                "vendor" => vendor.replace(value.to_string()).is_some(),
+                "action" => action.replace(parse_action(value)?).is_some(),
+                _ => return Err(FirmwarePolicyError::UnknownKey(key.into())),
+            };
+            if duplicate {
+                return Err(FirmwarePolicyError::DuplicateKey(key.into()));
+            }
+        }
+
+        Ok(Self {
+            min_revision: min_revision.ok_or(FirmwarePolicyError::MissingRevision)?,
+            vendor,
+            action: action.unwrap_or(FirmwareAction::Warn),
+        })
+    }
+
+    /// Check `firmware` against the policy and return the message to
+    /// show if it is too old.
+    pub fn check(&self, firmware: &impl FirmwareIdentity) -> Option<String> {
+        let vendor = firmware.firmware_vendor();
+        if self.vendor.as_ref().map_or(false, |v| *v != vendor) {
+            return None;
+        }
+        let revision = firmware.firmware_revision();
+        if revision >= self.min_revision {
+            return None;
+        }
+        Some(format!(
+            "The firmware {vendor} is at revision {revision:#x}, which is older than the oldest known to work ({:#x}). Please update the firmware.",
+            self.min_revision
+        ))
+    }
+}
+
+fn parse_revision(value: &str) -> core::result::Result<u32, FirmwarePolicyError> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|_| FirmwarePolicyError::InvalidRevision(value.into()))
+}
+
+fn parse_action(value: &str) -> core::result::Result<FirmwareAction, FirmwarePolicyError> {
+    match value {
+        "warn" => Ok(FirmwareAction::Warn),
+        "refuse" => Ok(FirmwareAction::Refuse),
+        _ => Err(FirmwarePolicyError::InvalidAction(value.into())),
+    }
+}
@@ -0,0 +1,132 @@
+//! Reboot into the firmware setup on a key press.
+//!
+//! Some machines only enter their setup if a key is pressed in a short
+//! window after power-on. A UKI with a `.fwsetup` section names a key
+//! that can be pressed while the stub starts instead: the stub then asks
+//! the firmware to show its setup on the next boot through
+//! `OsIndications` and resets, like the "Reboot Into Firmware Interface"
+//! entry of systemd-boot.
+//!
+//! [`SetupKey`] and [`request_firmware_setup`] do not depend on the
+//! firmware, the variables are accessed through a [`VariableStore`].
+
+use uefi::{
+    cstr16,
+    proto::console::text::{Key, ScanCode},
+    table::runtime::{VariableAttributes, VariableVendor},
+    Result, Status,
+};
+
+use crate::efivars::VariableStore;
+
+/// The bit of `OsIndications` that requests the firmware setup on the
+/// next boot. The firmware sets the same bit in `OsIndicationsSupported`
+/// if it supports the request.
+pub const EFI_OS_INDICATIONS_BOOT_TO_FW_UI: u64 = 1 << 0;
+
+/// The scan codes of F1 to F12.
+const FUNCTION_KEYS: [ScanCode; 12] = [
+    ScanCode::FUNCTION_1,
+    ScanCode::FUNCTION_2,
+    ScanCode::FUNCTION_3,
+    ScanCode::FUNCTION_4,
+    ScanCode::FUNCTION_5,
+    ScanCode::FUNCTION_6,
+    ScanCode::FUNCTION_7,
+    ScanCode::FUNCTION_8,
+    ScanCode::FUNCTION_9,
+    ScanCode::FUNCTION_10,
+    ScanCode::FUNCTION_11,
+    ScanCode::FUNCTION_12,
+];
+
+/// The key named in the `.fwsetup` section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetupKey {
+    /// One of F1 to F12.
+    Function(u8),
+    Escape,
+    Delete,
+    /// A printable ASCII character, compared case-sensitively.
+    Char(char),
+}
+
+impl SetupKey {
+    /// Parse the contents of the `.fwsetup` section: `F1` to `F12`,
+    /// `Escape`, `Delete` or a single printable character.
+    pub fn parse(section: &str) -> Option<Self> {
+        let section = section.trim();
+        match section {
+            "Escape" => return Some(Self::Escape),
+            "Delete" => return Some(Self::Delete),
+            _ => {}
+        }
+        if let Some(number) = section
+            .strip_prefix('F')
+            .and_then(|number| number.parse::<u8>().ok())
+        {
+            return (1..=12).contains(&number).then_some(Self::Function(number));
+        }
+        let mut chars = section.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_graphic() => Some(Self::Char(c)),
+            _ => None,
+        }
+    }
+
+    /// Whether `key` is this key.
+    pub fn matches(&self, key: &Key) -> bool {
+        match (self, key) {
+            (Self::Function(number), Key::Special(scan_code)) => {
+                usize::from(*number)
+                    .checked_sub(1)
+                    .and_then(|index| FUNCTION_KEYS.get(index))
+                    == Some(scan_code)
+            }
+            (Self::Escape, Key::Special(scan_code)) => *scan_code == ScanCode::ESCAPE,
+            (Self::Delete, Key::Special(scan_code)) => *scan_code == ScanCode::DELETE,
+            (Self::Char(c), Key::Printable(printed)) => char::from(*printed) == *c,
+            _ => false,
+        }
+    }
+}
+
+/// Ask the firmware to show its setup on the next boot.
+///
+/// Other requests in `OsIndications` are kept. Fails with `UNSUPPORTED`
+/// if the firmware does not announce support in
+/// `OsIndicationsSupported`, because it would boot normally after the
+/// reset.
+pub fn request_firmware_setup(store: &mut impl VariableStore) -> Result {
+    let supported = store
+        .read(
+            cstr16!("OsIndicationsSupported"),
+            &VariableVendor::GLOBAL_VARIABLE,
+        )
+        .ok()
+        .and_then(|data| read_u64(&data))
+        .unwrap_or(0);
+    if supported & EFI_OS_INDICATIONS_BOOT_TO_FW_UI == 0 {
+        return Err(Status::UNSUPPORTED.into());
+    }
+
+    // Like systemd-boot, a missing or malformed variable is treated as
+    // no previous requests.
+    let indications = store
+        .read(cstr16!("OsIndications"), &VariableVendor::GLOBAL_VARIABLE)
+        .ok()
+        .and_then(|data| read_u64(&data))
+        .unwrap_or(0);
+    store.set(
+        cstr16!("OsIndications"),
+        &VariableVendor::GLOBAL_VARIABLE,
+        VariableAttributes::NON_VOLATILE
+            | VariableAttributes::BOOTSERVICE_ACCESS
+            | VariableAttributes::RUNTIME_ACCESS,
+        &(indications | EFI_OS_INDICATIONS_BOOT_TO_FW_UI).to_le_bytes(),
+    )
+}
+
+fn read_u64(data: &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(data.try_into().ok()?))
+}
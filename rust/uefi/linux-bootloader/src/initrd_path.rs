@@ -0,0 +1,191 @@
+//! Load an initrd from a file referenced by the `.irdpath` section.
+//!
+//! Very large initrds bloat every UKI that embeds them. Instead, the
+//! `.irdpath` section can reference a file, e.g. on the ESP, that is
+//! appended to the embedded initrd. The section has two lines: the
+//! device path of the file in its text representation and the SHA-256
+//! hash of the file in hex:
+//!
+//! ```text
+//! PciRoot(0x0)/Pci(0x1,0x1)/HD(1,GPT,...)/\EFI\nixos\initrd
+//! 9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08
+//! ```
+//!
+//! If the device path starts with the file path, e.g. `\EFI\nixos\initrd`,
+//! the file is read from the volume that contains the UKI. The section
+//! is covered by the signature of the UKI, so the file is verified
+//! against its hash like the `.initrdh` of the thin stub.
+//!
+//! Both the section and the file are measured into PCR 12 before the
+//! file is appended, see [`measure_initrd_path`]. Both stubs support
+//! the section, lzbt adds it with `--initrd-file`.
+//!
+//! [`InitrdPath`] and [`load_initrd_path`] do not depend on the
+//! firmware, [`UefiInitrdFiles`] implements [`InitrdFiles`] with the
+//! boot services.
+
+use alloc::vec::Vec;
+
+use sha2::{Digest, Sha256};
+use uefi::{
+    fs::FileSystem,
+    prelude::*,
+    proto::{
+        device_path::text::DevicePathFromText, device_path::DevicePath, media::fs::SimpleFileSystem,
+    },
+    CStr16, CString16, Result,
+};
+
+use crate::initrd_verification::check_digest;
+use crate::measure::{Measurements, Measurer, TPM_PCR_INDEX_KERNEL_CONFIG};
+
+/// The size of a SHA-256 hash.
+const HASH_SIZE: usize = 32;
+
+/// The file referenced by a `.irdpath` section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InitrdPath {
+    /// The text device path of the volume, or `None` for the volume of
+    /// the running image.
+    pub device: Option<CString16>,
+    /// The path of the file on the volume, e.g. `\EFI\nixos\initrd`.
+    pub file: CString16,
+    /// The expected SHA-256 hash of the file.
+    pub hash: [u8; HASH_SIZE],
+}
+
+impl InitrdPath {
+    /// Parse the contents of the `.irdpath` section.
+    ///
+    /// Fails with `INVALID_PARAMETER` if the section does not contain
+    /// a file path or a valid hash.
+    pub fn parse(section: &str) -> Result<Self> {
+        let mut lines = section
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty());
+        let (Some(device_path), Some(hash), None) = (lines.next(), lines.next(), lines.next())
+        else {
+            return Err(Status::INVALID_PARAMETER.into());
+        };
+
+        // The file path is the last node, its text is the path itself.
+        let file_start = device_path.find('\\').ok_or(Status::INVALID_PARAMETER)?;
+        let (device, file) = device_path.split_at(file_start);
+        let device = match device.trim_end_matches('/') {
+            "" => None,
+            device => Some(to_cstring16(device)?),
+        };
+
+        Ok(Self {
+            device,
+            file: to_cstring16(file)?,
+            hash: parse_hash(hash).ok_or(Status::INVALID_PARAMETER)?,
+        })
+    }
+}
+
+fn to_cstring16(value: &str) -> Result<CString16> {
+    CString16::try_from(value).map_err(|_| Status::INVALID_PARAMETER.into())
+}
+
+/// Decode a SHA-256 hash from hex.
+fn parse_hash(hex: &str) -> Option<[u8; HASH_SIZE]> {
+    let hex = hex.as_bytes();
+    if hex.len() != 2 * HASH_SIZE {
+        return None;
+    }
+    let mut hash = [0; HASH_SIZE];
+    for (byte, digits) in hash.iter_mut().zip(hex.chunks_exact(2)) {
+        let digits = core::str::from_utf8(digits).ok()?;
+        *byte = u8::from_str_radix(digits, 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// Where the files of initrds are read from.
+pub trait InitrdFiles {
+    /// Read the file at `file` on the volume with the text device path
+    /// `device`, or on the volume of the running image.
+    fn read(&self, device: Option<&CStr16>, file: &CStr16) -> Result<Vec<u8>>;
+}
+
+/// Read the file referenced by `path` and verify it against its hash.
+///
+/// A file that does not match its hash is handled like a mismatching
+/// initrd in the thin stub, see [`check_digest`].
+pub fn load_initrd_path(
+    files: &impl InitrdFiles,
+    path: &InitrdPath,
+    secure_boot: bool,
+) -> Result<Vec<u8>> {
+    let data = files.read(path.device.as_deref(), &path.file)?;
+    check_digest(
+        &Sha256::digest(&data),
+        &path.hash,
+        "Initrd file",
+        secure_boot,
+    )?;
+    Ok(data)
+}
+
+/// Measure the `.irdpath` section and `data`, the file it references,
+/// into PCR 12.
+pub fn measure_initrd_path(
+    section: &[u8],
+    data: &[u8],
+    measurer: &mut impl Measurer,
+    measurements: &mut Measurements,
+) -> Result<()> {
+    measurements.measure(
+        &mut *measurer,
+        TPM_PCR_INDEX_KERNEL_CONFIG,
+        section,
+        ".irdpath",
+    )?;
+    measurements.measure(measurer, TPM_PCR_INDEX_KERNEL_CONFIG, data, ".irdpath file")
+}
+
+/// Reads files with the simple file system protocol.
+pub struct UefiInitrdFiles<'a> {
+    boot_services: &'a BootServices,
+}
+
+impl<'a> UefiInitrdFiles<'a> {
+    pub fn new(boot_services: &'a BootServices) -> Self {
+        Self { boot_services }
+    }
+
+    /// The handle of the volume with the text device path `device`.
+    fn volume(&self, device: &CStr16) -> Result<Handle> {
+        let from_text = self
+            .boot_services
+            .get_handle_for_protocol::<DevicePathFromText>()
+            .and_then(|handle| {
+                self.boot_services
+                    .open_protocol_exclusive::<DevicePathFromText>(handle)
+            })?;
+        let mut device_path: &DevicePath = from_text.convert_text_to_device_path(device)?;
+        self.boot_services
+            .locate_device_path::<SimpleFileSystem>(&mut device_path)
+    }
+}
+
+impl InitrdFiles for UefiInitrdFiles<'_> {
+    fn read(&self, device: Option<&CStr16>, file: &CStr16) -> Result<Vec<u8>> {
+        let file_system = match device {
+            Some(device) => {
+                let volume = self.volume(device)?;
+                self.boot_services
+                    .open_protocol_exclusive::<SimpleFileSystem>(volume)?
+            }
+            None => self
+                .boot_services
+                .get_image_file_system(self.boot_services.image_handle())?,
+        };
+        let mut file_system = FileSystem::new(file_system);
+        file_system
+            .read(file)
+            .map_err(|_| Status::LOAD_ERROR.into())
+    }
+}
@@ -0,0 +1,119 @@
+//! Verification of the initrd against the hash in the `.initrdh`
+//! section.
+//!
+//! The initrd is a plain cpio archive (possibly compressed), not a PE
+//! binary, so it cannot be verified by the firmware. Instead, its
+//! SHA-256 is embedded in the UKI, whose signature covers the section.
+//! A compressed initrd is hashed as it is stored: the kernel unpacks
+//! it, so the stub never has to decompress it.
+//!
+//! Without Secure Boot, a mismatching initrd only results in a warning,
+//! so hashing a large initrd on every boot is pure overhead during
+//! development. The `.initrdv` section can opt out of the check in this
+//! case. With Secure Boot, the initrd is always verified.
+//!
+//! Some firmware refuses the first read after boot with
+//! `SECURITY_VIOLATION` or hands out wrong data, so that the
+//! verification fails with `SECURITY_VIOLATION` although the initrd on
+//! the ESP is intact. With `once` in the `.initrdr` section, the stub
+//! reads and verifies the initrd a second time before it gives up.
+
+use uefi::Status;
+
+/// When the initrd is verified, as stored in the `.initrdv` PE section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InitrdVerification {
+    /// Always verify the initrd. This is the default without the
+    /// section.
+    #[default]
+    Always,
+    /// Only verify the initrd if Secure Boot is enabled.
+    SecureBoot,
+}
+
+impl InitrdVerification {
+    /// Parse the contents of the `.initrdv` section: `always` or
+    /// `secure-boot`.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "always" => Some(Self::Always),
+            "secure-boot" => Some(Self::SecureBoot),
+            _ => None,
+        }
+    }
+
+    /// Whether the initrd has to be verified.
+    pub fn required(self, secure_boot_enabled: bool) -> bool {
+        secure_boot_enabled || self == Self::Always
+    }
+}
+
+/// Whether a failed verification is retried, as stored in the
+/// `.initrdr` PE section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VerificationRetry {
+    /// Stop the boot on the first failure. This is the default without
+    /// the section.
+    #[default]
+    Never,
+    /// Try a second time if the first attempt fails with
+    /// `SECURITY_VIOLATION`.
+    Once,
+}
+
+impl VerificationRetry {
+    /// Parse the contents of the `.initrdr` section: `never` or `once`.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "never" => Some(Self::Never),
+            "once" => Some(Self::Once),
+            _ => None,
+        }
+    }
+}
+
+/// Run `verify`, which reads and verifies the data called `name`, and
+/// run it again if `retry` allows it and the first attempt was refused
+/// with `SECURITY_VIOLATION`, either by the firmware or by the
+/// verification.
+///
+/// `verify` gets the number of the attempt, starting at 0, and returns
+/// the data it read. Other errors and a second refusal are returned as
+/// they are.
+pub fn verify_with_retry<T>(
+    retry: VerificationRetry,
+    name: &str,
+    mut verify: impl FnMut(u32) -> uefi::Result<T>,
+) -> uefi::Result<T> {
+    match verify(0) {
+        Err(err)
+            if err.status() == Status::SECURITY_VIOLATION && retry == VerificationRetry::Once =>
+        {
+            log::warn!("Failed to verify {name}, reading it again.");
+            verify(1)
+        }
+        result => result,
+    }
+}
+
+/// Compare the hash of the data called `name` with its expected value.
+///
+/// In case of a mismatch:
+/// * If Secure Boot is active, an error message is logged, and the SECURITY_VIOLATION error is returned to stop the boot.
+/// * If Secure Boot is not active, only a warning is logged, and the boot process is allowed to continue.
+pub fn check_digest(
+    hash: &[u8],
+    expected_hash: &[u8],
+    name: &str,
+    secure_boot: bool,
+) -> uefi::Result<()> {
+    if hash != expected_hash {
+        if secure_boot {
+            log::error!("{name} hash does not match!");
+            return Err(Status::SECURITY_VIOLATION.into());
+        } else {
+            log::warn!("{name} hash does not match! Continuing anyway.");
+        }
+    }
+    Ok(())
+}
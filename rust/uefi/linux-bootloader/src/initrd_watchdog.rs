@@ -0,0 +1,140 @@
+//! Warn if the kernel never asks for its initrd.
+//!
+//! Linux requests its initrd via LoadFile2 early in its EFI stub. A
+//! kernel that does not (e.g. because it was built without support
+//! for it or is not a Linux kernel at all) may hang without telling
+//! why. The stub cannot interrupt the kernel, but it can leave a timer
+//! behind that logs a warning if the initrd was not requested a while
+//! after the kernel was started. Once the kernel exits boot services,
+//! the timer is gone.
+//!
+//! [`InitrdWatchdog`] does not depend on the firmware. It is armed
+//! with any [`WatchdogTimer`], e.g. a UEFI timer event in
+//! [`UefiWatchdogTimer`].
+
+use alloc::{format, string::String};
+use core::ffi::c_void;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use log::warn;
+use uefi::{
+    prelude::*,
+    table::boot::{EventType, TimerTrigger, Tpl},
+    Event, Result,
+};
+
+/// How long the kernel has to request its initrd before a warning is
+/// logged.
+pub const DEFAULT_WATCHDOG_SECONDS: u32 = 30;
+
+/// The watchdog that the initrd loader reports requests to.
+pub static INITRD_WATCHDOG: InitrdWatchdog = InitrdWatchdog::new();
+
+/// Whether the initrd was requested in time.
+pub struct InitrdWatchdog {
+    requested: AtomicBool,
+    /// The timeout the watchdog was armed with, 0 if it is not armed.
+    seconds: AtomicU32,
+}
+
+impl InitrdWatchdog {
+    pub const fn new() -> Self {
+        Self {
+            requested: AtomicBool::new(false),
+            seconds: AtomicU32::new(0),
+        }
+    }
+
+    /// Start `timer`, which has to call [`Self::expire`] after
+    /// `seconds`.
+    pub fn arm(&self, timer: &mut impl WatchdogTimer, seconds: u32) -> Result {
+        self.requested.store(false, Ordering::Release);
+        self.seconds.store(seconds, Ordering::Release);
+        timer.start(seconds)
+    }
+
+    /// Record that the kernel requested its initrd.
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::Release);
+    }
+
+    /// Handle the expiry of the timer and return the warning to log,
+    /// if the initrd was not requested.
+    ///
+    /// The warning is only returned once.
+    pub fn expire(&self) -> Option<String> {
+        let seconds = self.seconds.swap(0, Ordering::AcqRel);
+        if seconds == 0 || self.requested.load(Ordering::Acquire) {
+            return None;
+        }
+        Some(format!(
+            "The kernel did not request its initrd within {seconds} s after it was started. It may not support loading the initrd via LoadFile2."
+        ))
+    }
+}
+
+impl Default for InitrdWatchdog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A timer that fires once.
+pub trait WatchdogTimer {
+    /// Fire after `seconds`.
+    fn start(&mut self, seconds: u32) -> Result;
+}
+
+/// Log the warning of [`INITRD_WATCHDOG`], if there is one.
+unsafe extern "efiapi" fn watchdog_expired(_event: Event, _context: Option<NonNull<c_void>>) {
+    if let Some(warning) = INITRD_WATCHDOG.expire() {
+        warn!("{warning}");
+    }
+}
+
+/// A UEFI timer event that expires [`INITRD_WATCHDOG`].
+///
+/// The event is closed when this is dropped, i.e. if the kernel
+/// returns.
+pub struct UefiWatchdogTimer<'a> {
+    boot_services: &'a BootServices,
+    event: Event,
+}
+
+impl<'a> UefiWatchdogTimer<'a> {
+    pub fn new(boot_services: &'a BootServices) -> Result<Self> {
+        // SAFETY: The notification function only touches the
+        // watchdog and the logger, which live forever.
+        let event = unsafe {
+            boot_services.create_event(
+                EventType::TIMER | EventType::NOTIFY_SIGNAL,
+                Tpl::CALLBACK,
+                Some(watchdog_expired),
+                None,
+            )?
+        };
+        Ok(Self {
+            boot_services,
+            event,
+        })
+    }
+}
+
+impl WatchdogTimer for UefiWatchdogTimer<'_> {
+    fn start(&mut self, seconds: u32) -> Result {
+        // The timer period is given in units of 100ns.
+        self.boot_services.set_timer(
+            &self.event,
+            TimerTrigger::Relative(u64::from(seconds) * 10_000_000),
+        )
+    }
+}
+
+impl Drop for UefiWatchdogTimer<'_> {
+    fn drop(&mut self) {
+        // SAFETY: The event is not used anymore.
+        let event = unsafe { self.event.unsafe_clone() };
+        let _ = self.boot_services.close_event(event);
+    }
+}
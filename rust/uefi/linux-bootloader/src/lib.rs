@@ -2,11 +2,35 @@
 
 extern crate alloc;
 
+pub mod addons;
+pub mod boot;
+pub mod boot_fallback;
+pub mod chainload;
+pub mod cmdline;
+pub mod confirmation;
+pub mod countdown;
+pub mod deploy;
+pub mod devicetree;
+pub mod diagnostics;
 pub mod efivars;
+pub mod firmware_policy;
+pub mod firmware_setup;
+pub mod initrd_path;
+pub mod initrd_verification;
+pub mod initrd_watchdog;
 pub mod linux_loader;
+pub mod load_options;
 pub mod measure;
+pub mod menu;
 pub mod pe_loader;
 pub mod pe_section;
+pub mod section_handlers;
+pub mod section_hashes;
+pub mod self_hash_sections;
+pub mod selftest;
+pub mod serial;
+pub mod smbios;
 pub mod tpm;
+pub mod tpm_nv;
 pub mod uefi_helpers;
 pub mod unified_sections;
@@ -7,16 +7,20 @@
 
 use core::{ffi::c_void, pin::Pin, ptr::slice_from_raw_parts_mut};
 
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, vec, vec::Vec};
+use sha2::{Digest, Sha256};
 use uefi::{
     prelude::BootServices,
     proto::{
         device_path::{DevicePath, FfiDevicePath},
+        media::file::{File, FileInfo, RegularFile},
         unsafe_protocol,
     },
-    Handle, Identify, Result, ResultExt, Status,
+    Guid, Handle, Identify, Result, ResultExt, Status,
 };
 
+use crate::initrd_watchdog::INITRD_WATCHDOG;
+
 /// The Linux kernel's initrd loading device path.
 ///
 /// The Linux kernel points us to
@@ -40,11 +44,21 @@ use uefi::{
 // 		sizeof(struct efi_generic_dev_path)
 // 	}
 // };
-static mut DEVICE_PATH_PROTOCOL: [u8; 24] = [
+pub const LINUX_INITRD_DEVICE_PATH: [u8; 24] = [
     0x04, 0x03, 0x14, 0x00, 0x27, 0xe4, 0x68, 0x55, 0xfc, 0x68, 0x3d, 0x4f, 0xac, 0x74, 0xca, 0x55,
     0x52, 0x31, 0xcc, 0x68, 0x7f, 0xff, 0x04, 0x00,
 ];
 
+/// The node that terminates a device path.
+const END_ENTIRE_DEVICE_PATH: [u8; 4] = [0x7f, 0xff, 0x04, 0x00];
+
+/// How often installing a protocol interface is attempted before
+/// giving up.
+///
+/// Only OUT_OF_RESOURCES is retried. Some firmware reports it while its
+/// handle database grows, all other errors are permanent.
+pub const PROTOCOL_INSTALL_ATTEMPTS: usize = 3;
+
 /// The UEFI LoadFile2 protocol.
 ///
 /// This protocol has a single method to load a file.
@@ -60,7 +74,228 @@ struct LoadFile2Protocol {
     ) -> Status,
 
     // This is not part of the official protocol struct.
-    initrd_data: Vec<u8>,
+    initrd: InitrdSource,
+}
+
+/// Installs and uninstalls protocol interfaces, i.e. [`BootServices`]
+/// outside of tests.
+pub trait ProtocolInterfaces {
+    /// Install `interface` for `protocol` on `handle`, or on a new
+    /// handle if it is `None`.
+    ///
+    /// # Safety
+    ///
+    /// `interface` must stay valid until it is uninstalled.
+    unsafe fn install_protocol_interface(
+        &self,
+        handle: Option<Handle>,
+        protocol: &Guid,
+        interface: *mut c_void,
+    ) -> Result<Handle>;
+
+    /// Uninstall `interface` for `protocol` from `handle`.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may use `interface` after it was uninstalled.
+    unsafe fn uninstall_protocol_interface(
+        &self,
+        handle: Handle,
+        protocol: &Guid,
+        interface: *mut c_void,
+    ) -> Result<()>;
+}
+
+impl ProtocolInterfaces for BootServices {
+    unsafe fn install_protocol_interface(
+        &self,
+        handle: Option<Handle>,
+        protocol: &Guid,
+        interface: *mut c_void,
+    ) -> Result<Handle> {
+        BootServices::install_protocol_interface(self, handle, protocol, interface)
+    }
+
+    unsafe fn uninstall_protocol_interface(
+        &self,
+        handle: Handle,
+        protocol: &Guid,
+        interface: *mut c_void,
+    ) -> Result<()> {
+        BootServices::uninstall_protocol_interface(self, handle, protocol, interface)
+    }
+}
+
+/// Install a protocol interface, retrying up to
+/// [`PROTOCOL_INSTALL_ATTEMPTS`] times if the firmware is out of
+/// resources.
+///
+/// # Safety
+///
+/// See [`ProtocolInterfaces::install_protocol_interface`].
+unsafe fn install_with_retries(
+    interfaces: &impl ProtocolInterfaces,
+    handle: Option<Handle>,
+    protocol: &Guid,
+    interface: *mut c_void,
+) -> Result<Handle> {
+    let mut attempt = 1;
+    loop {
+        match interfaces.install_protocol_interface(handle, protocol, interface) {
+            Err(err)
+                if err.status() == Status::OUT_OF_RESOURCES
+                    && attempt < PROTOCOL_INSTALL_ATTEMPTS =>
+            {
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// A file that an initrd is read from, i.e. a [`RegularFile`] outside
+/// of tests.
+pub trait InitrdFile {
+    /// Continue reading at `position` bytes from the start of the file.
+    fn set_position(&mut self, position: u64) -> Result;
+
+    /// Read into `buffer` and return the number of bytes read, which is
+    /// 0 at the end of the file.
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize>;
+}
+
+impl InitrdFile for RegularFile {
+    fn set_position(&mut self, position: u64) -> Result {
+        RegularFile::set_position(self, position)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        RegularFile::read(self, buffer).map_err(|err| uefi::Error::from(err.status()))
+    }
+}
+
+/// Where the initrd that is served to Linux comes from.
+pub enum InitrdSource {
+    /// The initrd is kept in memory.
+    Memory(Vec<u8>),
+    /// The initrd is read from a file every time Linux asks for it.
+    ///
+    /// This avoids keeping a copy of (potentially very large)
+    /// initrds in memory. The file may have changed since it was
+    /// verified, so with `expected_hash`, the served bytes are hashed
+    /// again and Linux gets an error instead of a modified initrd.
+    File {
+        file: Box<dyn InitrdFile>,
+        size: usize,
+        expected_hash: Option<[u8; 32]>,
+    },
+}
+
+impl InitrdSource {
+    /// Serve the initrd from `file`, refusing it if its SHA-256 is not
+    /// `expected_hash`.
+    pub fn from_file(mut file: RegularFile, expected_hash: Option<[u8; 32]>) -> Result<Self> {
+        let size = file.get_boxed_info::<FileInfo>()?.file_size();
+
+        Ok(Self::File {
+            file: Box::new(file),
+            size: usize::try_from(size).map_err(|_| Status::BAD_BUFFER_SIZE)?,
+            expected_hash,
+        })
+    }
+
+    /// The size of the initrd in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            Self::Memory(data) => data.len(),
+            Self::File { size, .. } => *size,
+        }
+    }
+
+    /// Fill `output` with the initrd. `output` must be exactly as
+    /// large as the initrd.
+    ///
+    /// If a file does not have the expected hash, `output` is cleared
+    /// and `SECURITY_VIOLATION` is returned.
+    pub fn read_into(&mut self, output: &mut [u8]) -> Result<()> {
+        match self {
+            Self::Memory(data) => output.copy_from_slice(data),
+            Self::File {
+                file,
+                expected_hash,
+                ..
+            } => {
+                file.set_position(0)?;
+                let mut hasher = Sha256::new();
+                let mut filled = 0;
+                while filled < output.len() {
+                    match file.read(&mut output[filled..])? {
+                        // The file shrunk since we looked at its size.
+                        0 => return Err(Status::END_OF_FILE.into()),
+                        read => {
+                            hasher.update(&output[filled..filled + read]);
+                            filled += read;
+                        }
+                    }
+                }
+
+                if let Some(expected_hash) = expected_hash {
+                    if hasher.finalize()[..] != expected_hash[..] {
+                        log::error!("The initrd changed after it was verified!");
+                        output.fill(0);
+                        return Err(Status::SECURITY_VIOLATION.into());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The initrds to serve, keyed by the device path they are requested
+/// with.
+///
+/// Linux asks for its initrd with [`LINUX_INITRD_DEVICE_PATH`]. Other
+/// device paths can be used to hand different initrds to kernels
+/// started later, e.g. via kexec.
+pub struct InitrdTable {
+    /// The first entry is always the initrd of Linux.
+    entries: Vec<(Box<[u8]>, InitrdSource)>,
+}
+
+impl InitrdTable {
+    /// Create a table that only serves `linux_initrd` to Linux.
+    pub fn new(linux_initrd: InitrdSource) -> Self {
+        Self {
+            entries: vec![(Box::new(LINUX_INITRD_DEVICE_PATH), linux_initrd)],
+        }
+    }
+
+    /// Serve `initrd` when it is requested with `device_path`,
+    /// replacing any initrd previously registered for it.
+    ///
+    /// `device_path` must be a complete device path, i.e. terminated
+    /// by an end node.
+    pub fn insert(&mut self, device_path: &[u8], initrd: InitrdSource) -> Result<()> {
+        if !device_path.ends_with(&END_ENTIRE_DEVICE_PATH) {
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+
+        match self.get_mut(device_path) {
+            Some(existing) => *existing = initrd,
+            None => self.entries.push((device_path.into(), initrd)),
+        }
+        Ok(())
+    }
+
+    /// The initrd requested with `device_path`.
+    pub fn get_mut(&mut self, device_path: &[u8]) -> Option<&mut InitrdSource> {
+        self.entries
+            .iter_mut()
+            .find(|(path, _)| **path == *device_path)
+            .map(|(_, initrd)| initrd)
+    }
 }
 
 impl LoadFile2Protocol {
@@ -71,19 +306,20 @@ impl LoadFile2Protocol {
         buffer_size: Option<&mut usize>,
         buffer: *mut u8,
     ) -> Result<()> {
+        INITRD_WATCHDOG.request();
+
         let buffer_size = buffer_size.ok_or(uefi::Error::new(Status::INVALID_PARAMETER, ()))?;
-        if buffer.is_null() || *buffer_size < self.initrd_data.len() {
+        let initrd_size = self.initrd.size();
+        if buffer.is_null() || *buffer_size < initrd_size {
             // Give the caller a hint for the right buffer size.
-            *buffer_size = self.initrd_data.len();
+            *buffer_size = initrd_size;
             return Err(Status::BUFFER_TOO_SMALL.into());
         }
 
         let output_slice: &mut [u8] =
-            unsafe { &mut *slice_from_raw_parts_mut(buffer, self.initrd_data.len()) };
-
-        output_slice.copy_from_slice(&self.initrd_data);
+            unsafe { &mut *slice_from_raw_parts_mut(buffer, initrd_size) };
 
-        Ok(())
+        self.initrd.read_into(output_slice)
     }
 }
 
@@ -103,14 +339,21 @@ unsafe extern "efiapi" fn raw_load_file(
     .status()
 }
 
+/// An initrd whose protocols are installed on a handle.
+struct RegisteredInitrd {
+    handle: Handle,
+    /// The device path protocol is just the device path.
+    device_path: Box<[u8]>,
+    proto: Pin<Box<LoadFile2Protocol>>,
+}
+
 /// A RAII wrapper to install and uninstall the Linux initrd loading
 /// protocol.
 ///
 /// **Note:** You need to call [`InitrdLoader::uninstall`], before
 /// this is dropped.
 pub struct InitrdLoader {
-    proto: Pin<Box<LoadFile2Protocol>>,
-    handle: Handle,
+    initrds: Vec<RegisteredInitrd>,
     registered: bool,
 }
 
@@ -118,68 +361,185 @@ impl InitrdLoader {
     /// Create a new [`InitrdLoader`].
     ///
     /// `handle` is the handle where the protocols are registered
-    /// on. `file` is the file that is served to Linux.
-    pub fn new(boot_services: &BootServices, handle: Handle, initrd_data: Vec<u8>) -> Result<Self> {
+    /// on. `initrd_data` is the initrd that is served to Linux.
+    pub fn new(
+        boot_services: &impl ProtocolInterfaces,
+        handle: Handle,
+        initrd_data: Vec<u8>,
+    ) -> Result<Self> {
+        Self::from_source(boot_services, handle, InitrdSource::Memory(initrd_data))
+    }
+
+    /// Create a new [`InitrdLoader`] serving the initrd from `initrd`.
+    pub fn from_source(
+        boot_services: &impl ProtocolInterfaces,
+        handle: Handle,
+        initrd: InitrdSource,
+    ) -> Result<Self> {
+        Self::from_table(boot_services, handle, InitrdTable::new(initrd))
+    }
+
+    /// Create a new [`InitrdLoader`] serving all initrds in `table`.
+    ///
+    /// The initrd of Linux is registered on `handle`. As a handle can
+    /// only have a single device path, every other initrd is
+    /// registered on a new handle.
+    pub fn from_table(
+        boot_services: &impl ProtocolInterfaces,
+        handle: Handle,
+        table: InitrdTable,
+    ) -> Result<Self> {
+        let mut loader = InitrdLoader {
+            initrds: Vec::new(),
+            registered: true,
+        };
+
+        for (index, (device_path, initrd)) in table.entries.into_iter().enumerate() {
+            let handle = (index == 0).then_some(handle);
+            match Self::register(boot_services, handle, device_path, initrd) {
+                Ok(registered) => loader.initrds.push(registered),
+                Err(err) => {
+                    // Don't leave the initrds registered so far behind.
+                    // Failures are logged by `uninstall`, the error that
+                    // stopped the registration is more useful.
+                    let _ = loader.uninstall(boot_services);
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(loader)
+    }
+
+    /// Install the protocols serving `initrd` on `handle`, or on a new
+    /// handle if it is `None`.
+    ///
+    /// If LoadFile2 cannot be installed, the device path protocol is
+    /// uninstalled again, so that Linux does not find a handle without
+    /// an initrd.
+    fn register(
+        boot_services: &impl ProtocolInterfaces,
+        handle: Option<Handle>,
+        mut device_path: Box<[u8]>,
+        initrd: InitrdSource,
+    ) -> Result<RegisteredInitrd> {
         let mut proto = Box::pin(LoadFile2Protocol {
             load_file: raw_load_file,
-            initrd_data,
+            initrd,
         });
 
         // Linux finds the right handle by looking for something that
         // implements the device path protocol for the specific device
         // path.
-        unsafe {
-            let dp_proto: *mut u8 = DEVICE_PATH_PROTOCOL.as_mut_ptr();
+        let handle = unsafe {
+            let dp_proto: *mut u8 = device_path.as_mut_ptr();
 
-            boot_services.install_protocol_interface(
-                Some(handle),
+            let handle = install_with_retries(
+                boot_services,
+                handle,
                 &DevicePath::GUID,
                 dp_proto as *mut c_void,
             )?;
 
             let lf_proto: *mut LoadFile2Protocol = proto.as_mut().get_mut();
 
-            boot_services.install_protocol_interface(
+            if let Err(err) = install_with_retries(
+                boot_services,
                 Some(handle),
                 &LoadFile2Protocol::GUID,
                 lf_proto as *mut c_void,
-            )?;
-        }
+            ) {
+                if let Err(uninstall_err) = boot_services.uninstall_protocol_interface(
+                    handle,
+                    &DevicePath::GUID,
+                    dp_proto as *mut c_void,
+                ) {
+                    // The firmware still refers to the device path, so
+                    // it must never be freed.
+                    log::error!(
+                        "Failed to uninstall the initrd device path: {:?}. Leaking it.",
+                        uninstall_err.status()
+                    );
+                    Box::leak(device_path);
+                }
+                return Err(err);
+            }
+
+            handle
+        };
 
-        Ok(InitrdLoader {
+        Ok(RegisteredInitrd {
             handle,
+            device_path,
             proto,
-            registered: true,
         })
     }
 
-    pub fn uninstall(&mut self, boot_services: &BootServices) -> Result<()> {
+    /// Uninstall the protocols of all initrds.
+    ///
+    /// The memory of an initrd whose protocols cannot be uninstalled is
+    /// leaked, because the firmware may still refer to it. The other
+    /// initrds are uninstalled anyway and the first error is returned.
+    pub fn uninstall(&mut self, boot_services: &impl ProtocolInterfaces) -> Result<()> {
         // This should only be called once.
         assert!(self.registered);
 
-        unsafe {
-            let dp_proto: *mut u8 = &mut DEVICE_PATH_PROTOCOL[0];
-            boot_services.uninstall_protocol_interface(
-                self.handle,
-                &DevicePath::GUID,
-                dp_proto as *mut c_void,
-            )?;
+        let mut result = Ok(());
+        while let Some(mut initrd) = self.initrds.pop() {
+            let uninstalled = unsafe {
+                let dp_proto: *mut u8 = initrd.device_path.as_mut_ptr();
+                let dp_result = boot_services.uninstall_protocol_interface(
+                    initrd.handle,
+                    &DevicePath::GUID,
+                    dp_proto as *mut c_void,
+                );
 
-            let lf_proto: *mut LoadFile2Protocol = self.proto.as_mut().get_mut();
+                let lf_proto: *mut LoadFile2Protocol = initrd.proto.as_mut().get_mut();
+                let lf_result = boot_services.uninstall_protocol_interface(
+                    initrd.handle,
+                    &LoadFile2Protocol::GUID,
+                    lf_proto as *mut c_void,
+                );
+                dp_result.and(lf_result)
+            };
 
-            boot_services.uninstall_protocol_interface(
-                self.handle,
-                &LoadFile2Protocol::GUID,
-                lf_proto as *mut c_void,
-            )?;
+            if let Err(err) = uninstalled {
+                log::error!(
+                    "Failed to uninstall the initrd protocols: {:?}. Leaking the initrd.",
+                    err.status()
+                );
+                core::mem::forget(initrd);
+                result = result.and(Err(err));
+            }
         }
 
         self.registered = false;
 
-        Ok(())
+        result
     }
 }
 
+/// Check that Linux would find an initrd served by [`InitrdLoader`].
+///
+/// An empty initrd is registered on `handle`, looked up with
+/// [`LINUX_INITRD_DEVICE_PATH`] like Linux does and removed again.
+pub fn initrd_loader_works(boot_services: &BootServices, handle: Handle) -> bool {
+    let Ok(mut loader) = InitrdLoader::new(boot_services, handle, Vec::new()) else {
+        return false;
+    };
+
+    // SAFETY: The device path is complete, i.e. terminated by an end
+    // node.
+    let mut device_path =
+        unsafe { DevicePath::from_ffi_ptr(LINUX_INITRD_DEVICE_PATH.as_ptr().cast()) };
+    let found = boot_services
+        .locate_device_path::<LoadFile2Protocol>(&mut device_path)
+        .is_ok();
+
+    // Initrds that cannot be uninstalled are leaked by `uninstall`.
+    loader.uninstall(boot_services).is_ok() && found
+}
+
 impl Drop for InitrdLoader {
     fn drop(&mut self) {
         // Dropped without unregistering!
@@ -0,0 +1,75 @@
+//! The `LoadOptions` the stub passes to the kernel.
+//!
+//! By default, the load options are only the kernel command line. Some
+//! kernels and userspace instead expect them to start with the path of
+//! the image, like the UEFI shell passes `argv[0]`. With the `.loadopt`
+//! section, the stub prepends the path of the UKI, i.e. the same path
+//! it exports as `LoaderImageIdentifier`.
+
+use alloc::vec::Vec;
+
+use uefi::CStr16;
+
+/// What the load options of the kernel contain, as stored in the
+/// `.loadopt` PE section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LoadOptionsMode {
+    /// Only the command line. This is the default without the section.
+    #[default]
+    Cmdline,
+    /// The path of the UKI followed by the command line.
+    ImagePath,
+}
+
+impl LoadOptionsMode {
+    /// Parse the contents of the `.loadopt` section: `cmdline` or
+    /// `image-path`.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "cmdline" => Some(Self::Cmdline),
+            "image-path" => Some(Self::ImagePath),
+            _ => None,
+        }
+    }
+}
+
+/// Compose the load options of the kernel from its command line, a NUL
+/// terminated UCS-2 string.
+///
+/// `image_path` is the path of the running image. If it is unknown,
+/// only the command line is passed. A path with spaces is quoted, so
+/// that it stays a single argument.
+pub fn compose_load_options(
+    mode: LoadOptionsMode,
+    image_path: Option<&CStr16>,
+    cmdline: &[u8],
+) -> Vec<u8> {
+    let image_path = match (mode, image_path) {
+        (LoadOptionsMode::ImagePath, Some(image_path)) => image_path,
+        _ => return cmdline.to_vec(),
+    };
+
+    let image_path = image_path.to_u16_slice();
+    let mut chars = Vec::new();
+    if image_path.contains(&u16::from(b' ')) {
+        chars.push(u16::from(b'"'));
+        chars.extend(image_path);
+        chars.push(u16::from(b'"'));
+    } else {
+        chars.extend(image_path);
+    }
+
+    let mut cmdline: Vec<u16> = cmdline
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    while cmdline.last() == Some(&0) {
+        cmdline.pop();
+    }
+    if !cmdline.is_empty() {
+        chars.push(u16::from(b' '));
+        chars.extend(cmdline);
+    }
+    chars.push(0);
+    chars.into_iter().flat_map(u16::to_le_bytes).collect()
+}
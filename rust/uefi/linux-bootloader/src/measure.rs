@@ -1,51 +1,260 @@
-use log::info;
+//! Measure the unified sections of the image into the TPM.
+//!
+//! The `.dtbo` sections are measured into PCR 11 as well, because they
+//! change the devicetree the kernel sees.
+//!
+//! A TPM that fails partway (e.g. because it entered failure mode)
+//! should not keep the machine from booting, so by default a failed
+//! measurement is logged and the remaining sections are still
+//! measured. With [`MeasurementPolicy::Strict`], which lzbt selects by
+//! embedding `strict` in the `.measure` section, the first failure
+//! stops the boot instead. Secrets sealed against the PCRs cannot be
+//! unsealed after an incomplete measurement either way.
+//!
+//! [`measure_sections`] does not depend on the firmware. It measures
+//! with any [`Measurer`], e.g. the TCG2 protocol in [`Tcg2Measurer`].
+//!
+//! Configuration that the stub picks up outside of the unified sections,
+//! e.g. the command line chosen in the boot menu, is measured into PCR
+//! 12 like systemd-stub does, with the same policy. [`Measurements`]
+//! keeps track of these measurements.
+//!
+//! To debug measured boot, e.g. when the PCR values do not match the
+//! prediction in `.pcrsig`, lzbt embeds a `.pcrshow` section. The stub
+//! then prints what it measured with [`measurement_lines`] and the
+//! values of the extended PCRs right before it starts the kernel.
+
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::Write;
+use log::{error, info, warn};
+use sha2::{Digest, Sha256};
 use uefi::{
     cstr16,
+    prelude::BootServices,
     proto::tcg::PcrIndex,
     table::{runtime::VariableAttributes, Boot, SystemTable},
+    Status,
 };
 
 use crate::{
-    efivars::BOOT_LOADER_VENDOR_UUID, pe_section::pe_section_data, tpm::tpm_log_event_ascii,
-    uefi_helpers::PeInMemory, unified_sections::UnifiedSection,
+    devicetree::OVERLAY_SECTION,
+    efivars::BOOT_LOADER_VENDOR_UUID,
+    pe_section::{pe_section_data, pe_sections},
+    tpm::tpm_log_event_ascii,
+    tpm_nv::{read_u16, read_u32, submit, Tcg2Commands, TpmCommands, TPM_ST_NO_SESSIONS},
+    unified_sections::UnifiedSection,
 };
 
 const TPM_PCR_INDEX_KERNEL_IMAGE: PcrIndex = PcrIndex(11);
+/// The PCR of the kernel command line and other configuration from
+/// outside the unified sections.
+pub const TPM_PCR_INDEX_KERNEL_CONFIG: PcrIndex = PcrIndex(12);
+
+const TPM_CC_PCR_READ: u32 = 0x0000_017e;
+const TPM_ALG_SHA256: u16 = 0x000b;
+/// The size of the PCR selection bitmap, which covers the 24 PCRs every
+/// PC client TPM has.
+const PCR_SELECT_SIZE: usize = 3;
+
+/// What to do if a measurement fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeasurementPolicy {
+    /// Log the failure and continue.
+    Lenient,
+    /// Stop the boot.
+    Strict,
+}
+
+impl MeasurementPolicy {
+    /// Parse the contents of the `.measure` section. Without the
+    /// section, measurement is lenient.
+    pub fn from_section(section: Option<&str>) -> Self {
+        match section.map(str::trim) {
+            Some("strict") => Self::Strict,
+            Some("lenient") | None => Self::Lenient,
+            Some(other) => {
+                warn!("Ignoring unknown measurement policy {other:?}.");
+                Self::Lenient
+            }
+        }
+    }
+}
+
+/// Something that extends PCRs and logs the events.
+pub trait Measurer {
+    /// Measure `data` into `pcr_index`. Returns whether a measurement
+    /// was done.
+    fn measure(
+        &mut self,
+        pcr_index: PcrIndex,
+        data: &[u8],
+        description: &str,
+    ) -> uefi::Result<bool>;
+}
+
+impl<M: Measurer + ?Sized> Measurer for &mut M {
+    fn measure(
+        &mut self,
+        pcr_index: PcrIndex,
+        data: &[u8],
+        description: &str,
+    ) -> uefi::Result<bool> {
+        (**self).measure(pcr_index, data, description)
+    }
+}
+
+/// Measures with the TCG2 protocol of the firmware.
+pub struct Tcg2Measurer<'a> {
+    boot_services: &'a BootServices,
+}
+
+impl<'a> Tcg2Measurer<'a> {
+    pub fn new(boot_services: &'a BootServices) -> Self {
+        Self { boot_services }
+    }
+}
+
+impl Measurer for Tcg2Measurer<'_> {
+    fn measure(
+        &mut self,
+        pcr_index: PcrIndex,
+        data: &[u8],
+        description: &str,
+    ) -> uefi::Result<bool> {
+        tpm_log_event_ascii(self.boot_services, pcr_index, data, description)
+    }
+}
+
+/// A measurement that was done, to show it to the user later.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Measurement {
+    pub pcr_index: u32,
+    pub description: String,
+    /// The SHA-256 digest of the measured data, i.e. what the PCR was
+    /// extended with.
+    pub digest: [u8; 32],
+}
+
+/// Records the measurements that another [`Measurer`] did.
+pub struct RecordingMeasurer<M> {
+    measurer: M,
+    measurements: Vec<Measurement>,
+}
+
+impl<M> RecordingMeasurer<M> {
+    pub fn new(measurer: M) -> Self {
+        Self {
+            measurer,
+            measurements: Vec::new(),
+        }
+    }
+
+    /// The measurements that were done, in order.
+    pub fn into_measurements(self) -> Vec<Measurement> {
+        self.measurements
+    }
+}
+
+impl<M: Measurer> Measurer for RecordingMeasurer<M> {
+    fn measure(
+        &mut self,
+        pcr_index: PcrIndex,
+        data: &[u8],
+        description: &str,
+    ) -> uefi::Result<bool> {
+        let measured = self.measurer.measure(pcr_index, data, description)?;
+        if measured {
+            self.measurements.push(Measurement {
+                pcr_index: pcr_index.0,
+                description: description.into(),
+                digest: Sha256::digest(data).into(),
+            });
+        }
+        Ok(measured)
+    }
+}
+
+/// Measure `data`, called `description`, into `pcr_index`.
+///
+/// With [`MeasurementPolicy::Lenient`], a failure is logged and
+/// ignored. Returns whether a measurement was done.
+pub fn measure_data(
+    measurer: &mut impl Measurer,
+    policy: MeasurementPolicy,
+    pcr_index: PcrIndex,
+    data: &[u8],
+    description: &str,
+) -> uefi::Result<bool> {
+    match measurer.measure(pcr_index, data, description) {
+        Ok(measured) => Ok(measured),
+        Err(err) if policy == MeasurementPolicy::Lenient => {
+            warn!("Failed to measure `{description}`, continuing: {err:?}");
+            Ok(false)
+        }
+        Err(err) => {
+            error!("Failed to measure `{description}`: {err:?}");
+            Err(err)
+        }
+    }
+}
 
-pub fn measure_image(system_table: &SystemTable<Boot>, image: PeInMemory) -> uefi::Result<u32> {
+/// Measure the unified sections among `sections`, given by their
+/// names and contents, into PCR 11.
+///
+/// The devicetree overlays are measured along with them, in the order
+/// of `sections`, whether or not they are applied later.
+///
+/// Returns the number of sections that were measured.
+pub fn measure_sections<'a>(
+    measurer: &mut impl Measurer,
+    policy: MeasurementPolicy,
+    sections: impl IntoIterator<Item = (&'a str, &'a [u8])>,
+) -> uefi::Result<u32> {
+    let mut measurements = 0;
+    for (section_name, data) in sections {
+        let should_be_measured = match UnifiedSection::try_from(section_name) {
+            Ok(unified_section) => unified_section.should_be_measured(),
+            Err(_) => section_name == OVERLAY_SECTION,
+        };
+        if !should_be_measured {
+            continue;
+        }
+
+        info!("Measuring section `{}`...", section_name);
+        if measure_data(
+            measurer,
+            policy,
+            TPM_PCR_INDEX_KERNEL_IMAGE,
+            data,
+            section_name,
+        )? {
+            measurements += 1;
+        }
+    }
+    Ok(measurements)
+}
+
+/// Measure the unified sections of `pe_binary`, the running image, into
+/// PCR 11 and return the measurements that were done.
+pub fn measure_image(
+    system_table: &SystemTable<Boot>,
+    pe_binary: &[u8],
+    policy: MeasurementPolicy,
+) -> uefi::Result<Vec<Measurement>> {
     let runtime_services = system_table.runtime_services();
     let boot_services = system_table.boot_services();
 
-    // SAFETY: We get a slice that represents our currently running
-    // image and then parse the PE data structures from it. This is
-    // safe, because we don't touch any data in the data sections that
-    // might conceivably change while we look at the slice.
-    // (data sections := all unified sections that can be measured.)
-    let pe_binary = unsafe { image.as_slice() };
-    let pe = goblin::pe::PE::parse(pe_binary).map_err(|_err| uefi::Status::LOAD_ERROR)?;
+    let pe_sections = pe_sections(pe_binary).ok_or(uefi::Status::LOAD_ERROR)?;
 
-    let mut measurements = 0;
-    for section in pe.sections {
+    let mut sections = Vec::new();
+    for section in &pe_sections {
         let section_name = section.name().map_err(|_err| uefi::Status::UNSUPPORTED)?;
-        if let Ok(unified_section) = UnifiedSection::try_from(section_name) {
-            // UNSTABLE: && in the previous if is an unstable feature
-            // https://github.com/rust-lang/rust/issues/53667
-            if unified_section.should_be_measured() {
-                // Here, perform the TPM log event in ASCII.
-                if let Some(data) = pe_section_data(pe_binary, &section) {
-                    info!("Measuring section `{}`...", section_name);
-                    if tpm_log_event_ascii(
-                        boot_services,
-                        TPM_PCR_INDEX_KERNEL_IMAGE,
-                        data,
-                        section_name,
-                    )? {
-                        measurements += 1;
-                    }
-                }
-            }
+        if let Some(data) = pe_section_data(pe_binary, section) {
+            sections.push((section_name, data));
         }
     }
+    let mut measurer = RecordingMeasurer::new(Tcg2Measurer::new(boot_services));
+    let measurements = measure_sections(&mut measurer, policy, sections)?;
 
     if measurements > 0 {
         // If we did some measurements, expose a variable encoding the PCR where
@@ -58,5 +267,136 @@ pub fn measure_image(system_table: &SystemTable<Boot>, image: PeInMemory) -> uef
         )?;
     }
 
-    Ok(measurements)
+    Ok(measurer.into_measurements())
+}
+
+/// The measurements of the stub, and how to do more of them.
+///
+/// Without a TPM, nothing is measured.
+pub struct Measurements {
+    /// What to do if a measurement fails, `None` without a TPM.
+    policy: Option<MeasurementPolicy>,
+    done: Vec<Measurement>,
+}
+
+impl Measurements {
+    /// Measure with `policy`, or nothing if it is `None`.
+    pub fn new(policy: Option<MeasurementPolicy>) -> Self {
+        Self {
+            policy,
+            done: Vec::new(),
+        }
+    }
+
+    /// Record measurements that were already done, e.g. by
+    /// [`measure_image`].
+    pub fn record(&mut self, measurements: Vec<Measurement>) {
+        self.done.extend(measurements);
+    }
+
+    /// Measure `data` with `measurer`, see [`measure_data`].
+    pub fn measure(
+        &mut self,
+        measurer: &mut impl Measurer,
+        pcr_index: PcrIndex,
+        data: &[u8],
+        description: &str,
+    ) -> uefi::Result<()> {
+        let Some(policy) = self.policy else {
+            return Ok(());
+        };
+        let mut measurer = RecordingMeasurer::new(measurer);
+        measure_data(&mut measurer, policy, pcr_index, data, description)?;
+        self.record(measurer.into_measurements());
+        Ok(())
+    }
+
+    /// The measurements that were done, in order.
+    pub fn done(&self) -> &[Measurement] {
+        &self.done
+    }
+}
+
+/// Read the SHA-256 bank of PCR `pcr_index`.
+///
+/// Fails with `NOT_FOUND` if the TPM has no SHA-256 bank.
+pub fn read_pcr(tpm: &mut impl TpmCommands, pcr_index: u32) -> uefi::Result<[u8; 32]> {
+    let index = usize::try_from(pcr_index).map_err(|_| Status::INVALID_PARAMETER)?;
+    let mut select = [0; PCR_SELECT_SIZE];
+    *select.get_mut(index / 8).ok_or(Status::INVALID_PARAMETER)? |= 1 << (index % 8);
+
+    // TPML_PCR_SELECTION with a single selection.
+    let mut command = Vec::new();
+    command.extend(1u32.to_be_bytes());
+    command.extend(TPM_ALG_SHA256.to_be_bytes());
+    command.push(PCR_SELECT_SIZE as u8);
+    command.extend(select);
+
+    let response = submit(tpm, TPM_ST_NO_SESSIONS, TPM_CC_PCR_READ, &command)?;
+    // The update counter, the selection that was read and the digests.
+    // A bank that is not allocated is left out of the selection.
+    if read_u32(&response, 4).ok_or(Status::PROTOCOL_ERROR)? == 0 {
+        return Err(Status::NOT_FOUND.into());
+    }
+    let select_size = *response.get(10).ok_or(Status::PROTOCOL_ERROR)?;
+    let digests = 11 + usize::from(select_size);
+    let count = read_u32(&response, digests).ok_or(Status::PROTOCOL_ERROR)?;
+    let size = read_u16(&response, digests + 4).ok_or(Status::PROTOCOL_ERROR)?;
+    if count != 1 || size != 32 {
+        return Err(Status::PROTOCOL_ERROR.into());
+    }
+    let digest = response
+        .get(digests + 6..digests + 6 + 32)
+        .and_then(|digest| digest.try_into().ok())
+        .ok_or(Status::PROTOCOL_ERROR)?;
+    Ok(digest)
+}
+
+/// Render `measurements`, followed by the values of the PCRs they
+/// extended as returned by `read_pcr`.
+pub fn measurement_lines(
+    measurements: &[Measurement],
+    mut read_pcr: impl FnMut(u32) -> uefi::Result<[u8; 32]>,
+) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut pcrs = Vec::new();
+    for measurement in measurements {
+        lines.push(format!(
+            "PCR {} extended with {}: sha256:{}",
+            measurement.pcr_index,
+            measurement.description,
+            hex(&measurement.digest)
+        ));
+        if !pcrs.contains(&measurement.pcr_index) {
+            pcrs.push(measurement.pcr_index);
+        }
+    }
+    for pcr in pcrs {
+        lines.push(match read_pcr(pcr) {
+            Ok(value) => format!("PCR {pcr} is now sha256:{}", hex(&value)),
+            Err(err) => format!("PCR {pcr} could not be read: {:?}", err.status()),
+        });
+    }
+    lines
+}
+
+/// Print `measurements` and the current values of the PCRs they
+/// extended on the console.
+pub fn show_measurements(system_table: &mut SystemTable<Boot>, measurements: &[Measurement]) {
+    let lines = measurement_lines(measurements, |pcr| {
+        read_pcr(&mut Tcg2Commands::new(system_table.boot_services()), pcr)
+    });
+    if lines.is_empty() {
+        let _ = write!(system_table.stdout(), "Nothing was measured.\r\n");
+    }
+    for line in lines {
+        let _ = write!(system_table.stdout(), "{line}\r\n");
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
 }
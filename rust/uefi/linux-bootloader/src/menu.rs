@@ -0,0 +1,258 @@
+//! A boot menu for the command line variants embedded in the image.
+//!
+//! lzbt embeds additional command lines in the `.cmdl1` to `.cmdl9`
+//! sections and their labels, one per line, in `.cmdlbl`. The first
+//! label belongs to the default command line in `.cmdline`.
+//!
+//! The arrow keys move the selection, Enter boots the selected entry
+//! and a digit boots the entry with that number. Without any input,
+//! the first entry is booted after the timeout. Any key stops the
+//! timeout.
+//!
+//! Like [`crate::countdown`], the state machine in [`Menu`] does not
+//! depend on the firmware. [`run_menu`] drives it with any [`MenuIo`],
+//! e.g. the UEFI console in [`UefiMenuIo`].
+
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::Write;
+
+use uefi::{
+    prelude::*,
+    proto::console::text::{Key, ScanCode},
+    Char16, Event, Result,
+};
+
+use crate::countdown::{create_second_timer, next_key_or_tick};
+use crate::pe_section::{pe_section, pe_section_as_string};
+
+/// The sections that contain the command lines of the menu, by index.
+///
+/// This must match the list in lzbt.
+pub const CMDLINE_SECTIONS: [&str; 10] = [
+    ".cmdline", ".cmdl1", ".cmdl2", ".cmdl3", ".cmdl4", ".cmdl5", ".cmdl6", ".cmdl7", ".cmdl8",
+    ".cmdl9",
+];
+
+/// The timeout in seconds if the image does not specify one.
+pub const DEFAULT_TIMEOUT: u32 = 5;
+
+/// An entry of the boot menu.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MenuEntry {
+    pub label: String,
+    /// The section that contains the command line of this entry.
+    pub section: &'static str,
+}
+
+impl MenuEntry {
+    /// Map the labels of a `.cmdlbl` section to the command line
+    /// sections. Labels beyond the last section are ignored.
+    pub fn from_labels(labels: &str) -> Vec<Self> {
+        labels
+            .lines()
+            .zip(CMDLINE_SECTIONS)
+            .map(|(label, section)| Self {
+                label: label.into(),
+                section,
+            })
+            .collect()
+    }
+}
+
+/// The entries of the boot menu of an image, skipping those whose
+/// command line section is missing.
+///
+/// Images without a `.cmdlbl` section have no menu.
+pub fn menu_entries(image: &[u8]) -> Vec<MenuEntry> {
+    let Some(labels) = pe_section_as_string(image, ".cmdlbl") else {
+        return Vec::new();
+    };
+    MenuEntry::from_labels(&labels)
+        .into_iter()
+        .filter(|entry| matches!(pe_section(image, entry.section, None), Ok(Some(_))))
+        .collect()
+}
+
+/// Something that happened while the menu is shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuEvent {
+    /// A second has passed.
+    Tick,
+    Up,
+    Down,
+    Enter,
+    /// A digit was pressed.
+    Digit(usize),
+    /// Any other key was pressed.
+    OtherKey,
+}
+
+/// What is shown to the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuState {
+    /// Waiting for input. After `timeout` more seconds, the selected
+    /// entry is booted. `None` if the timeout was stopped.
+    Waiting {
+        selected: usize,
+        timeout: Option<u32>,
+    },
+    /// Booting the entry with this index.
+    Done(usize),
+}
+
+/// The state machine of the menu.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Menu {
+    entries: usize,
+    state: MenuState,
+}
+
+impl Menu {
+    /// Show a menu with `entries` entries that boots the first one
+    /// after `seconds`. A menu with a single entry or a timeout of 0
+    /// seconds is over immediately.
+    pub fn new(entries: usize, seconds: u32) -> Self {
+        let state = if entries <= 1 || seconds == 0 {
+            MenuState::Done(0)
+        } else {
+            MenuState::Waiting {
+                selected: 0,
+                timeout: Some(seconds),
+            }
+        };
+        Self { entries, state }
+    }
+
+    pub fn state(&self) -> MenuState {
+        self.state
+    }
+
+    /// Advance the state machine and return the new state.
+    pub fn handle(&mut self, event: MenuEvent) -> MenuState {
+        let MenuState::Waiting { selected, timeout } = self.state else {
+            return self.state;
+        };
+        self.state = match event {
+            MenuEvent::Enter => MenuState::Done(selected),
+            MenuEvent::Digit(index) if index < self.entries => MenuState::Done(index),
+            MenuEvent::Tick => match timeout {
+                Some(1) => MenuState::Done(selected),
+                timeout => MenuState::Waiting {
+                    selected,
+                    timeout: timeout.map(|seconds| seconds - 1),
+                },
+            },
+            MenuEvent::Up => MenuState::Waiting {
+                selected: selected.saturating_sub(1),
+                timeout: None,
+            },
+            MenuEvent::Down => MenuState::Waiting {
+                selected: (selected + 1).min(self.entries - 1),
+                timeout: None,
+            },
+            MenuEvent::Digit(_) | MenuEvent::OtherKey => MenuState::Waiting {
+                selected,
+                timeout: None,
+            },
+        };
+        self.state
+    }
+}
+
+/// Render the menu.
+pub fn menu_lines(entries: &[MenuEntry], state: MenuState) -> Vec<String> {
+    let selected = match state {
+        MenuState::Waiting { selected, .. } | MenuState::Done(selected) => selected,
+    };
+    let mut lines = entries
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            let marker = if index == selected { '>' } else { ' ' };
+            format!("{marker} {index}: {}", entry.label)
+        })
+        .collect::<Vec<_>>();
+    lines.push(match state {
+        MenuState::Waiting {
+            timeout: Some(seconds),
+            ..
+        } => format!("Booting the selected entry in {seconds} s."),
+        MenuState::Waiting { timeout: None, .. } => "Press Enter to boot.".into(),
+        MenuState::Done(_) => "Booting...".into(),
+    });
+    lines
+}
+
+/// The input and output of a menu.
+pub trait MenuIo {
+    /// Block until the next event.
+    fn next_event(&mut self) -> Result<MenuEvent>;
+
+    /// Show the current state to the user.
+    fn show(&mut self, entries: &[MenuEntry], state: MenuState);
+}
+
+/// Run the menu until an entry is chosen and return the section that
+/// contains its command line.
+pub fn run_menu(entries: &[MenuEntry], seconds: u32, io: &mut impl MenuIo) -> Result<&'static str> {
+    let mut menu = Menu::new(entries.len(), seconds);
+    loop {
+        let state = menu.state();
+        io.show(entries, state);
+        if let MenuState::Done(index) = state {
+            return Ok(entries
+                .get(index)
+                .map_or(CMDLINE_SECTIONS[0], |e| e.section));
+        }
+        menu.handle(io.next_event()?);
+    }
+}
+
+/// Runs a menu on the UEFI console.
+pub struct UefiMenuIo<'a> {
+    system_table: &'a mut SystemTable<Boot>,
+    timer: Event,
+}
+
+impl<'a> UefiMenuIo<'a> {
+    pub fn new(system_table: &'a mut SystemTable<Boot>) -> Result<Self> {
+        let timer = create_second_timer(system_table.boot_services())?;
+        Ok(Self {
+            system_table,
+            timer,
+        })
+    }
+}
+
+impl Drop for UefiMenuIo<'_> {
+    fn drop(&mut self) {
+        // SAFETY: The event is not used anymore.
+        let timer = unsafe { self.timer.unsafe_clone() };
+        let _ = self.system_table.boot_services().close_event(timer);
+    }
+}
+
+impl MenuIo for UefiMenuIo<'_> {
+    fn next_event(&mut self) -> Result<MenuEvent> {
+        Ok(match next_key_or_tick(self.system_table, &self.timer)? {
+            None => MenuEvent::Tick,
+            Some(Key::Special(ScanCode::UP)) => MenuEvent::Up,
+            Some(Key::Special(ScanCode::DOWN)) => MenuEvent::Down,
+            Some(Key::Printable(c)) if c == Char16::try_from('\r').unwrap() => MenuEvent::Enter,
+            Some(Key::Printable(c)) => match char::from(c).to_digit(10) {
+                Some(digit) => MenuEvent::Digit(digit as usize),
+                None => MenuEvent::OtherKey,
+            },
+            Some(_) => MenuEvent::OtherKey,
+        })
+    }
+
+    fn show(&mut self, entries: &[MenuEntry], state: MenuState) {
+        // Errors are ignored, the menu still boots the default entry
+        // without output.
+        let _ = self.system_table.stdout().clear();
+        for line in menu_lines(entries, state) {
+            let _ = write!(self.system_table.stdout(), "{line}\r\n");
+        }
+    }
+}
@@ -153,7 +153,11 @@ impl Image {
         if pe.entry >= image.len() {
             return Err(Status::LOAD_ERROR.into());
         }
-        let entry = unsafe { core::mem::transmute(&image[pe.entry]) };
+        let entry = unsafe {
+            core::mem::transmute::<&u8, extern "efiapi" fn(Handle, SystemTable<Boot>) -> Status>(
+                &image[pe.entry],
+            )
+        };
 
         Ok(Image { image, entry })
     }
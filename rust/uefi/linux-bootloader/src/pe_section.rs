@@ -4,33 +4,185 @@
 // and_then below and this can't be expressed with map.
 #![allow(clippy::bind_instead_of_map)]
 
-use alloc::{borrow::ToOwned, string::String};
-use goblin::pe::section_table::SectionTable;
+use alloc::{borrow::ToOwned, string::String, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use goblin::pe::{
+    header,
+    section_table::{SectionTable, SIZEOF_SECTION_TABLE},
+};
+use log::warn;
+use uefi::Status;
+
+/// Offset of the pointer to the PE signature (`e_lfanew`) in the DOS
+/// header.
+const PE_POINTER_OFFSET: usize = 0x3c;
+/// Size of the PE signature and the COFF file header.
+const COFF_HEADER_END: usize = 4 + 20;
+/// Offset of `Machine` in the COFF file header, from the PE signature.
+const MACHINE_OFFSET: usize = 4;
+/// Offset of `NumberOfSections` in the COFF file header, from the PE
+/// signature.
+const NUMBER_OF_SECTIONS_OFFSET: usize = 4 + 2;
+/// Offset of `SizeOfOptionalHeader` in the COFF file header, from the PE
+/// signature.
+const SIZE_OF_OPTIONAL_HEADER_OFFSET: usize = 4 + 16;
+
+/// The PE machine of the architecture the stub is built for.
+#[cfg(target_arch = "x86_64")]
+pub const NATIVE_MACHINE: u16 = header::COFF_MACHINE_X86_64;
+#[cfg(target_arch = "x86")]
+pub const NATIVE_MACHINE: u16 = header::COFF_MACHINE_X86;
+#[cfg(target_arch = "aarch64")]
+pub const NATIVE_MACHINE: u16 = header::COFF_MACHINE_ARM64;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64")))]
+compile_error!("The PE machine of the target architecture is unknown, add it to NATIVE_MACHINE.");
+
+/// Whether [`pe_sections`] already logged that it fell back to the
+/// section table. The stub looks up many sections of the same image.
+static FALLBACK_LOGGED: AtomicBool = AtomicBool::new(false);
 
 /// Extracts the data of a section in a loaded PE file
 /// based on the section table.
+///
+/// Returns `None` if the section lies outside of `pe_data` or is larger
+/// than its data in the file, because the section table may not have
+/// been checked by goblin, see [`pe_sections`].
 pub fn pe_section_data<'a>(pe_data: &'a [u8], section: &SectionTable) -> Option<&'a [u8]> {
+    if section.virtual_size > section.size_of_raw_data {
+        return None;
+    }
     let section_start: usize = section.virtual_address.try_into().ok()?;
+    let section_end = section_start.checked_add(section.virtual_size.try_into().ok()?)?;
+
+    pe_data.get(section_start..section_end)
+}
+
+/// Reads the section table of a loaded PE file.
+///
+/// goblin parses the whole image, including tables like the debug
+/// directory that the firmware ignores, and rejects the image if any of
+/// them are malformed. If it does, the section table is read directly
+/// from the COFF header instead, which is all that is needed to find
+/// the sections.
+pub fn pe_sections(pe_data: &[u8]) -> Option<Vec<SectionTable>> {
+    match goblin::pe::PE::parse(pe_data) {
+        Ok(pe) => Some(pe.sections),
+        Err(err) => {
+            if !FALLBACK_LOGGED.swap(true, Ordering::Relaxed) {
+                warn!("Failed to parse the PE image, reading only its section table: {err}");
+            }
+            parse_section_table(pe_data)
+        }
+    }
+}
+
+/// Reads the section table from the COFF header of a PE file.
+///
+/// Unlike goblin, this does not look at the optional header, except to
+/// skip it, and does not resolve long section names from the COFF
+/// string table, which images do not have.
+pub fn parse_section_table(pe_data: &[u8]) -> Option<Vec<SectionTable>> {
+    let pe_offset = pe_signature_offset(pe_data)?;
+    let number_of_sections = usize::from(read_u16(pe_data, pe_offset + NUMBER_OF_SECTIONS_OFFSET)?);
+    let optional_header_size = usize::from(read_u16(
+        pe_data,
+        pe_offset + SIZE_OF_OPTIONAL_HEADER_OFFSET,
+    )?);
+
+    let table_offset = pe_offset + COFF_HEADER_END + optional_header_size;
+    (0..number_of_sections)
+        .map(|index| {
+            let offset = table_offset + index * SIZEOF_SECTION_TABLE;
+            let header = pe_data.get(offset..offset + SIZEOF_SECTION_TABLE)?;
+            let mut name = [0; 8];
+            name.copy_from_slice(&header[..8]);
+            Some(SectionTable {
+                name,
+                real_name: None,
+                virtual_size: read_u32(header, 8)?,
+                virtual_address: read_u32(header, 12)?,
+                size_of_raw_data: read_u32(header, 16)?,
+                pointer_to_raw_data: read_u32(header, 20)?,
+                pointer_to_relocations: read_u32(header, 24)?,
+                pointer_to_linenumbers: read_u32(header, 28)?,
+                number_of_relocations: read_u16(header, 32)?,
+                number_of_linenumbers: read_u16(header, 34)?,
+                characteristics: read_u32(header, 36)?,
+            })
+        })
+        .collect()
+}
 
-    assert!(section.virtual_size <= section.size_of_raw_data);
-    let section_end: usize = section_start + usize::try_from(section.virtual_size).ok()?;
+/// Finds the PE signature of a PE file behind its DOS header.
+fn pe_signature_offset(pe_data: &[u8]) -> Option<usize> {
+    if pe_data.get(..2)? != b"MZ" {
+        return None;
+    }
+    let pe_offset = usize::try_from(read_u32(pe_data, PE_POINTER_OFFSET)?).ok()?;
+    if pe_data.get(pe_offset..pe_offset.checked_add(4)?)? != b"PE\0\0" {
+        return None;
+    }
+    Some(pe_offset)
+}
 
-    Some(&pe_data[section_start..section_end])
+/// Reads the machine from the COFF header of a PE file.
+pub fn pe_machine(pe_data: &[u8]) -> Option<u16> {
+    read_u16(pe_data, pe_signature_offset(pe_data)? + MACHINE_OFFSET)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset.checked_add(2)?)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset.checked_add(4)?)?.try_into().ok()?,
+    ))
+}
+
+/// Fails with `UNSUPPORTED` if `expected_machine` is set and the PE
+/// file is not built for that machine, e.g. [`NATIVE_MACHINE`].
+///
+/// Firmware with an emulator loads images of other architectures, which
+/// are not meant for the running one.
+pub fn check_pe_machine(pe_data: &[u8], expected_machine: Option<u16>) -> uefi::Result<()> {
+    if let Some(expected) = expected_machine {
+        let machine = pe_machine(pe_data);
+        if machine != Some(expected) {
+            warn!("Expected a PE image for machine {expected:#06x}, got {machine:#06x?}");
+            return Err(Status::UNSUPPORTED.into());
+        }
+    }
+    Ok(())
 }
 
 /// Extracts the data of a section of a loaded PE file
 /// based on the section name.
-pub fn pe_section<'a>(pe_data: &'a [u8], section_name: &str) -> Option<&'a [u8]> {
-    let pe_binary = goblin::pe::PE::parse(pe_data).ok()?;
+///
+/// If `expected_machine` is set, fails with `UNSUPPORTED` unless the PE
+/// file is built for that machine, see [`check_pe_machine`].
+pub fn pe_section<'a>(
+    pe_data: &'a [u8],
+    section_name: &str,
+    expected_machine: Option<u16>,
+) -> uefi::Result<Option<&'a [u8]>> {
+    check_pe_machine(pe_data, expected_machine)?;
+    Ok(find_section(pe_data, section_name))
+}
 
-    pe_binary
-        .sections
+fn find_section<'a>(pe_data: &'a [u8], section_name: &str) -> Option<&'a [u8]> {
+    pe_sections(pe_data)?
         .iter()
         .find(|s| s.name().map(|n| n == section_name).unwrap_or(false))
         .and_then(|s| pe_section_data(pe_data, s))
 }
 
 /// Extracts the data of a section of a loaded PE image and returns it as a string.
+///
+/// The machine is not checked, this is meant for the configuration of
+/// an image that was already read with [`pe_section`].
 pub fn pe_section_as_string<'a>(pe_data: &'a [u8], section_name: &str) -> Option<String> {
-    pe_section(pe_data, section_name).map(|data| core::str::from_utf8(data).unwrap().to_owned())
+    find_section(pe_data, section_name).map(|data| core::str::from_utf8(data).unwrap().to_owned())
 }
@@ -0,0 +1,113 @@
+//! Hand the sections of an image to handlers registered by name.
+//!
+//! A [`SectionRegistry`] walks the section table of the running image
+//! once and gives the contents of every section to the handlers that
+//! were registered for its name, e.g. `.initrd`, `.dtb`, `.splash` or a
+//! custom section. New sections can be experimented with by registering
+//! a [`SectionHandler`] without touching the boot path.
+//!
+//! The registry does not depend on the firmware. [`image_sections`]
+//! lists the sections of a loaded image, but any list of names and
+//! contents can be dispatched.
+
+use alloc::{string::String, vec::Vec};
+
+use uefi::{Result, Status};
+
+use crate::pe_section::{pe_section_data, pe_sections};
+
+/// Something that handles the contents of the sections with a name.
+pub trait SectionHandler {
+    /// The name of the sections to handle, e.g. `.initrd`.
+    fn name(&self) -> &str;
+
+    /// Handle the contents of one section. This is called for every
+    /// section with the name, in the order of the section table.
+    fn handle(&mut self, data: &[u8]) -> Result<()>;
+}
+
+/// The handlers of the sections of an image.
+#[derive(Default)]
+pub struct SectionRegistry<'a> {
+    handlers: Vec<&'a mut dyn SectionHandler>,
+}
+
+impl<'a> SectionRegistry<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a handler. Several handlers can be registered for the
+    /// same name, they are called in the order they were registered.
+    pub fn register(&mut self, handler: &'a mut dyn SectionHandler) {
+        self.handlers.push(handler);
+    }
+
+    /// Give every section to the handlers registered for its name.
+    /// Sections without a handler are skipped.
+    ///
+    /// Stops at the first handler that fails and returns its error.
+    pub fn dispatch<'b, N: AsRef<str>>(
+        &mut self,
+        sections: impl IntoIterator<Item = (N, &'b [u8])>,
+    ) -> Result<()> {
+        for (name, data) in sections {
+            let name = name.as_ref();
+            for handler in self.handlers.iter_mut().filter(|h| h.name() == name) {
+                handler.handle(data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Give the sections of the loaded image `image` to the handlers.
+    pub fn dispatch_image(&mut self, image: &[u8]) -> Result<()> {
+        self.dispatch(image_sections(image)?)
+    }
+}
+
+/// The names and contents of the sections of a loaded image, in the
+/// order of the section table. Sections without a valid name are
+/// skipped.
+pub fn image_sections(image: &[u8]) -> Result<Vec<(String, &[u8])>> {
+    let sections = pe_sections(image)
+        .ok_or(Status::LOAD_ERROR)?
+        .iter()
+        .filter_map(|section| {
+            let name = section.name().ok()?;
+            Some((name.into(), pe_section_data(image, section)?))
+        })
+        .collect();
+    Ok(sections)
+}
+
+/// A handler that copies the contents of all sections with a name.
+pub struct CollectSection<'n> {
+    name: &'n str,
+    data: Vec<u8>,
+}
+
+impl<'n> CollectSection<'n> {
+    pub fn new(name: &'n str) -> Self {
+        Self {
+            name,
+            data: Vec::new(),
+        }
+    }
+
+    /// The concatenated contents of the sections.
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl SectionHandler for CollectSection<'_> {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn handle(&mut self, data: &[u8]) -> Result<()> {
+        self.data.extend_from_slice(data);
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+//! Verify the sections of an image against the `.sechash` manifest.
+//!
+//! The manifest is a sequence of entries of 40 bytes: the name of a
+//! section, padded with zeros to 8 bytes, followed by the SHA-256 hash
+//! of its contents. Unlike the single hash in `.selfh`, it tells which
+//! section does not match.
+//!
+//! [`SectionHashes`] does not depend on the firmware. The sections are
+//! looked up with a function, e.g. [`pe_section`](crate::pe_section::pe_section)
+//! on the running image.
+
+use alloc::string::String;
+use core::fmt;
+
+use sha2::{Digest, Sha256};
+
+/// The size of a section name in an entry.
+const NAME_SIZE: usize = 8;
+
+/// The size of a SHA-256 hash.
+const HASH_SIZE: usize = 32;
+
+/// The size of an entry.
+const ENTRY_SIZE: usize = NAME_SIZE + HASH_SIZE;
+
+/// Why the sections of an image could not be verified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SectionHashError {
+    /// The manifest is empty, not a multiple of the entry size or a
+    /// name is not valid UTF-8.
+    Malformed,
+    /// A section in the manifest is not part of the image.
+    Missing(String),
+    /// The contents of a section do not match its hash.
+    Mismatch(String),
+}
+
+impl fmt::Display for SectionHashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed => f.write_str("malformed .sechash section"),
+            Self::Missing(name) => write!(f, "section {name} is missing"),
+            Self::Mismatch(name) => write!(f, "section {name} does not match its hash"),
+        }
+    }
+}
+
+/// A parsed `.sechash` manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectionHashes<'a> {
+    manifest: &'a [u8],
+}
+
+impl<'a> SectionHashes<'a> {
+    /// Parse the contents of a `.sechash` section.
+    pub fn parse(manifest: &'a [u8]) -> Result<Self, SectionHashError> {
+        if manifest.is_empty() || manifest.len() % ENTRY_SIZE != 0 {
+            return Err(SectionHashError::Malformed);
+        }
+        for entry in manifest.chunks_exact(ENTRY_SIZE) {
+            entry_name(entry)?;
+        }
+        Ok(Self { manifest })
+    }
+
+    /// The names of the sections and their expected hashes.
+    pub fn entries(&self) -> impl Iterator<Item = (&'a str, &'a [u8])> {
+        self.manifest.chunks_exact(ENTRY_SIZE).map(|entry| {
+            // The names were checked in `parse`.
+            (entry_name(entry).unwrap(), &entry[NAME_SIZE..])
+        })
+    }
+
+    /// Verify every section in the manifest. `section` returns the
+    /// contents of a section by name.
+    pub fn verify<'b>(
+        &self,
+        section: impl Fn(&str) -> Option<&'b [u8]>,
+    ) -> Result<(), SectionHashError> {
+        for (name, expected_hash) in self.entries() {
+            let data = section(name).ok_or_else(|| SectionHashError::Missing(name.into()))?;
+            if Sha256::digest(data).as_slice() != expected_hash {
+                return Err(SectionHashError::Mismatch(name.into()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The section name of an entry, without its padding.
+fn entry_name(entry: &[u8]) -> Result<&str, SectionHashError> {
+    let name = &entry[..NAME_SIZE];
+    let length = name.iter().position(|&b| b == 0).unwrap_or(NAME_SIZE);
+    match core::str::from_utf8(&name[..length]) {
+        Ok(name) if !name.is_empty() => Ok(name),
+        _ => Err(SectionHashError::Malformed),
+    }
+}
@@ -0,0 +1,46 @@
+//! Sections covered by the optional `.selfh` section.
+//!
+//! Generated from the list in lzbt by the test `self_hash_sections_match_stub` in
+//! `rust/tool/shared/src/pe.rs`. Do not edit, run it with
+//! `UPDATE_SELF_HASH_SECTIONS=1` instead.
+
+/// Sections covered by the optional `.selfh` section, in the order in
+/// which they are hashed.
+#[rustfmt::skip]
+pub const SELF_HASH_SECTIONS: [&str; 35] = [
+    ".text",
+    ".osrel",
+    ".cmdline",
+    ".initrd",
+    ".linux",
+    ".initrdh",
+    ".linuxh",
+    ".serial",
+    ".timeout",
+    ".initrdv",
+    ".deploy",
+    ".cmdlbl",
+    ".cmdl1",
+    ".cmdl2",
+    ".cmdl3",
+    ".cmdl4",
+    ".cmdl5",
+    ".cmdl6",
+    ".cmdl7",
+    ".cmdl8",
+    ".cmdl9",
+    ".fwmin",
+    ".measure",
+    ".smbios",
+    ".confirm",
+    ".chain",
+    ".loadopt",
+    ".fwsetup",
+    ".cmdlchk",
+    ".addons",
+    ".initrdr",
+    ".kfail",
+    ".tpmnv",
+    ".pcrshow",
+    ".irdpath",
+];
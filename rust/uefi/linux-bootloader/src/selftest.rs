@@ -0,0 +1,100 @@
+//! A self-test of the firmware features the stub relies on.
+//!
+//! The self-test image runs [`Capabilities::probe`] once on the target
+//! machine and writes the result to [`RESULT_PATH`] on the ESP, where
+//! lzbt reads it before installing. The result file has one `key=value`
+//! line per capability with the value `yes` or `no`, after a `version`
+//! line that is increased whenever the meaning of the keys changes.
+
+use alloc::{format, string::String};
+
+use uefi::{
+    cstr16,
+    fs::FileSystem,
+    prelude::*,
+    proto::console::gop::GraphicsOutput,
+    table::boot::{OpenProtocolAttributes, OpenProtocolParams},
+    CStr16, Result,
+};
+
+use crate::linux_loader::initrd_loader_works;
+use crate::tpm::tpm_available;
+
+/// Where the result is written on the ESP.
+pub const RESULT_PATH: &CStr16 = cstr16!("\\loader\\lanzaboote-selftest.txt");
+
+/// The version of the result format.
+///
+/// This must match the version lzbt understands.
+pub const RESULT_VERSION: u32 = 1;
+
+/// What the firmware supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Linux can find an initrd served with the LoadFile2 protocol.
+    /// Without this, the stub cannot hand the initrd to Linux.
+    pub load_file2: bool,
+    /// A TPM is available via the TCG2 protocol, which is needed for
+    /// measured boot.
+    pub tcg2: bool,
+    /// A graphical console is available via the Graphics Output
+    /// Protocol.
+    pub gop: bool,
+}
+
+impl Capabilities {
+    /// Probe the firmware. `handle` is the handle of the running image.
+    pub fn probe(boot_services: &BootServices, handle: Handle) -> Self {
+        Self {
+            load_file2: initrd_loader_works(boot_services, handle),
+            tcg2: tpm_available(boot_services),
+            gop: gop_available(boot_services, handle),
+        }
+    }
+
+    /// Render the result file.
+    pub fn render(&self) -> String {
+        let yes_no = |available| if available { "yes" } else { "no" };
+        format!(
+            "version={RESULT_VERSION}\nload-file2={}\ntcg2={}\ngop={}\n",
+            yes_no(self.load_file2),
+            yes_no(self.tcg2),
+            yes_no(self.gop)
+        )
+    }
+}
+
+/// Check whether a Graphics Output Protocol can be opened.
+fn gop_available(boot_services: &BootServices, handle: Handle) -> bool {
+    let Ok(gop_handle) = boot_services.get_handle_for_protocol::<GraphicsOutput>() else {
+        return false;
+    };
+    // SAFETY: The protocol is only opened to check that it is there,
+    // it is not used.
+    unsafe {
+        boot_services.open_protocol::<GraphicsOutput>(
+            OpenProtocolParams {
+                handle: gop_handle,
+                agent: handle,
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+    }
+    .is_ok()
+}
+
+/// Write the result to [`RESULT_PATH`] on the volume the image was
+/// loaded from.
+pub fn write_result(
+    boot_services: &BootServices,
+    handle: Handle,
+    capabilities: &Capabilities,
+) -> Result {
+    let file_system = boot_services.get_image_file_system(handle)?;
+    let mut file_system = FileSystem::new(file_system);
+    file_system
+        .create_dir_all(cstr16!("\\loader"))
+        .and_then(|()| file_system.write(RESULT_PATH, capabilities.render()))
+        .map_err(|_| Status::DEVICE_ERROR.into())
+}
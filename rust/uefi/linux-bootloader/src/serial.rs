@@ -0,0 +1,215 @@
+//! Duplicate log output to a serial console.
+//!
+//! Headless machines often lack a graphical console, which means that
+//! messages printed to the UEFI text output are lost. The logger in
+//! this module writes every record to the text output (like the
+//! logger of `uefi-services`) and, if configured, also to a device
+//! implementing the Serial I/O protocol.
+
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::fmt::{self, Write};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use log::{Log, Metadata, Record};
+use uefi::{
+    prelude::*,
+    proto::console::serial::Serial,
+    table::boot::{EventType, OpenProtocolAttributes, OpenProtocolParams, Tpl},
+    Event, Result,
+};
+
+/// The global logger installed by [`init_logger`].
+static LOGGER: SerialLogger = SerialLogger::new();
+
+/// Which serial device, if any, log output is duplicated to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialTarget {
+    /// Only log to the text output.
+    Disabled,
+    /// Log to the n-th handle supporting the Serial I/O protocol, in
+    /// the order in which the firmware enumerates them.
+    Index(usize),
+}
+
+impl SerialTarget {
+    /// Parse a serial target as it is stored in the `.serial` PE
+    /// section: either `off` or the index of a serial device.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "off" => Some(Self::Disabled),
+            index => index.parse().ok().map(Self::Index),
+        }
+    }
+}
+
+/// Logger writing to the UEFI text output and optionally to a serial
+/// device.
+///
+/// Like [`uefi::logger::Logger`], this must be disabled before boot
+/// services are exited.
+struct SerialLogger {
+    console: uefi::logger::Logger,
+    serial: AtomicPtr<Serial>,
+}
+
+impl SerialLogger {
+    /// Create a disabled logger.
+    const fn new() -> Self {
+        Self {
+            console: uefi::logger::Logger::new(),
+            serial: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Stop writing to any output.
+    fn disable(&self) {
+        self.console.disable();
+        self.serial.store(ptr::null_mut(), Ordering::Release);
+    }
+
+    fn write_serial(&self, record: &Record) {
+        let serial = self.serial.load(Ordering::Acquire);
+
+        if !serial.is_null() {
+            // SAFETY: The pointer was obtained from the firmware in
+            // `open_serial` and stays valid until boot services are
+            // exited, before which the logger has to be disabled.
+            let serial = unsafe { &mut *serial };
+            // Errors are ignored, there is nowhere left to report them.
+            let _ = write_record(serial, record);
+        }
+    }
+}
+
+impl Log for SerialLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.console.enabled(metadata) || !self.serial.load(Ordering::Acquire).is_null()
+    }
+
+    fn log(&self, record: &Record) {
+        self.console.log(record);
+        self.write_serial(record);
+    }
+
+    fn flush(&self) {}
+}
+
+// The logger is not thread-safe, but the UEFI boot environment only uses one processor.
+unsafe impl Sync for SerialLogger {}
+unsafe impl Send for SerialLogger {}
+
+/// Writer that translates LF into CRLF.
+struct CrLf<'a, W: Write>(&'a mut W);
+
+impl<W: Write> Write for CrLf<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut lines = s.split('\n');
+
+        if let Some(first) = lines.next() {
+            self.0.write_str(first)?;
+        }
+        for line in lines {
+            self.0.write_str("\r\n")?;
+            self.0.write_str(line)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Write `record` as a line to `serial`.
+///
+/// Serial consoles expect CRLF line endings, so line feeds in the
+/// message are translated.
+pub fn write_record(serial: &mut impl Write, record: &Record) -> fmt::Result {
+    writeln!(CrLf(serial), "[{:>5}]: {}", record.level(), record.args())
+}
+
+/// Pick the `index`-th of the `handles` supporting the Serial I/O
+/// protocol.
+///
+/// Returns `Ok(None)` if no device supports the protocol or the
+/// requested one does not exist.
+pub fn select_serial<T>(handles: Result<Vec<T>>, index: usize) -> Result<Option<T>> {
+    match handles {
+        Ok(handles) => Ok(handles.into_iter().nth(index)),
+        Err(err) if err.status() == Status::NOT_FOUND => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Open the serial device selected by `target`.
+///
+/// The protocol is opened non-exclusively, so the firmware's own
+/// serial console keeps working. Returns `Ok(None)` if no serial
+/// output is requested or the requested device does not exist.
+fn open_serial(boot_services: &BootServices, target: SerialTarget) -> Result<Option<*mut Serial>> {
+    let SerialTarget::Index(index) = target else {
+        return Ok(None);
+    };
+
+    let Some(handle) = select_serial(boot_services.find_handles::<Serial>(), index)? else {
+        return Ok(None);
+    };
+
+    // SAFETY: GetProtocol does not take ownership of the device, so
+    // nothing else is affected by us holding on to the interface.
+    let mut serial = unsafe {
+        boot_services.open_protocol::<Serial>(
+            OpenProtocolParams {
+                handle,
+                agent: boot_services.image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )?
+    };
+    let serial_ptr: *mut Serial = &mut *serial;
+    // Protocols opened with GetProtocol do not need to be closed and
+    // the interface outlives the scope of this function.
+    core::mem::forget(serial);
+
+    Ok(Some(serial_ptr))
+}
+
+/// Disable the logger once boot services are gone.
+unsafe extern "efiapi" fn exit_boot_services(_event: Event, _context: Option<NonNull<c_void>>) {
+    LOGGER.disable();
+}
+
+/// Set up logging to the text output and, if available, to the serial
+/// device selected by `target`.
+///
+/// This replaces the logger of `uefi-services`, so its `logger`
+/// feature must not be enabled. Returns whether log output is
+/// duplicated to a serial device.
+pub fn init_logger(system_table: &mut SystemTable<Boot>, target: SerialTarget) -> Result<bool> {
+    // SAFETY: The event registered below disables the logger before
+    // the text output becomes invalid.
+    unsafe { LOGGER.console.set_output(system_table.stdout()) };
+
+    let boot_services = system_table.boot_services();
+    // SAFETY: The callback only touches the logger, which lives
+    // forever.
+    unsafe {
+        boot_services.create_event(
+            EventType::SIGNAL_EXIT_BOOT_SERVICES,
+            Tpl::NOTIFY,
+            Some(exit_boot_services),
+            None,
+        )?;
+    }
+
+    // Failing to open the serial device must not prevent booting.
+    let serial = open_serial(boot_services, target).ok().flatten();
+    if let Some(serial) = serial {
+        LOGGER.serial.store(serial, Ordering::Release);
+    }
+
+    log::set_logger(&LOGGER).map_err(|_| Status::ALREADY_STARTED)?;
+    log::set_max_level(log::STATIC_MAX_LEVEL);
+
+    Ok(serial.is_some())
+}
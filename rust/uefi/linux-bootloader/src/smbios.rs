@@ -0,0 +1,199 @@
+//! Read extra kernel command line arguments from SMBIOS OEM strings.
+//!
+//! Some vendors pass boot parameters to a fleet of machines in SMBIOS
+//! Type 11 (OEM Strings) structures, e.g. from the BMC or the
+//! hypervisor. If the image contains an `.smbios` section, the stub
+//! looks for an OEM string that starts with the prefix in that section
+//! and appends the rest of the string to the command line. systemd-stub
+//! uses the prefix `io.systemd.stub.kernel-cmdline-extra=` for this.
+//!
+//! Anyone who can change the SMBIOS tables can inject arguments this
+//! way, so the stub only does it when Secure Boot is off.
+//!
+//! [`find_oem_string`] and [`append_cmdline`] do not depend on the
+//! firmware. They work on the raw structure table, which
+//! [`smbios_table`] finds in the configuration tables.
+
+use alloc::vec::Vec;
+use core::str;
+
+use uefi::{
+    prelude::*,
+    table::cfg::{SMBIOS3_GUID, SMBIOS_GUID},
+};
+
+/// The structure type of OEM strings.
+const TYPE_OEM_STRINGS: u8 = 11;
+
+/// The structure type that ends the table.
+const TYPE_END_OF_TABLE: u8 = 127;
+
+/// The size of the header of every structure: type, length and handle.
+const STRUCTURE_HEADER_SIZE: usize = 4;
+
+const SMBIOS3_ANCHOR: &[u8] = b"_SM3_";
+const SMBIOS3_ENTRY_POINT_SIZE: usize = 0x18;
+const SMBIOS_ANCHOR: &[u8] = b"_SM_";
+const SMBIOS_ENTRY_POINT_SIZE: usize = 0x1f;
+
+/// A structure in the SMBIOS structure table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Structure<'a> {
+    pub kind: u8,
+    /// The formatted area, including the header.
+    pub formatted: &'a [u8],
+    /// The strings that follow the formatted area, without their NUL
+    /// terminators.
+    pub strings: Vec<&'a [u8]>,
+}
+
+/// Iterates over the structures of an SMBIOS structure table.
+///
+/// Iteration stops at the end-of-table structure or at the first
+/// structure that is truncated.
+pub struct Structures<'a> {
+    table: &'a [u8],
+}
+
+impl<'a> Iterator for Structures<'a> {
+    type Item = Structure<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = self.table.get(..STRUCTURE_HEADER_SIZE)?;
+        let (kind, length) = (header[0], usize::from(header[1]));
+        if length < STRUCTURE_HEADER_SIZE {
+            self.table = &[];
+            return None;
+        }
+        let formatted = self.table.get(..length)?;
+
+        // The string set ends with an empty string. Without any
+        // strings, it consists of two NULs.
+        let mut strings = Vec::new();
+        let mut rest = &self.table[length..];
+        if rest.starts_with(&[0, 0]) {
+            rest = &rest[2..];
+        } else {
+            loop {
+                let Some(end) = rest.iter().position(|&b| b == 0) else {
+                    self.table = &[];
+                    return None;
+                };
+                strings.push(&rest[..end]);
+                rest = &rest[end + 1..];
+                if rest.first() == Some(&0) {
+                    rest = &rest[1..];
+                    break;
+                }
+            }
+        }
+
+        self.table = if kind == TYPE_END_OF_TABLE { &[] } else { rest };
+        Some(Structure {
+            kind,
+            formatted,
+            strings,
+        })
+    }
+}
+
+/// The structures of the SMBIOS structure table `table`.
+pub fn structures(table: &[u8]) -> Structures<'_> {
+    Structures { table }
+}
+
+/// The OEM strings in the SMBIOS structure table `table`, in order.
+pub fn oem_strings(table: &[u8]) -> Vec<&[u8]> {
+    structures(table)
+        .filter(|s| s.kind == TYPE_OEM_STRINGS)
+        .flat_map(|s| {
+            // The number of strings is the only field after the header.
+            let count = s
+                .formatted
+                .get(STRUCTURE_HEADER_SIZE)
+                .map_or(0, |&c| usize::from(c));
+            s.strings.into_iter().take(count)
+        })
+        .collect()
+}
+
+/// The rest of the first OEM string in `table` that starts with
+/// `prefix`.
+///
+/// Strings that are not valid UTF-8 are skipped.
+pub fn find_oem_string<'a>(table: &'a [u8], prefix: &str) -> Option<&'a str> {
+    oem_strings(table)
+        .into_iter()
+        .filter_map(|s| str::from_utf8(s).ok())
+        .find_map(|s| s.strip_prefix(prefix))
+}
+
+/// Append `extra` to the UCS-2 command line `cmdline`, separated by a
+/// space.
+///
+/// The result is NUL-terminated like the embedded command line, even
+/// if `cmdline` was not.
+pub fn append_cmdline(cmdline: &[u8], extra: &str) -> Vec<u8> {
+    let mut chars: Vec<u16> = cmdline
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    while chars.last() == Some(&0) {
+        chars.pop();
+    }
+    if !chars.is_empty() {
+        chars.push(u16::from(b' '));
+    }
+    chars.extend(extra.encode_utf16());
+    chars.push(0);
+    chars.into_iter().flat_map(u16::to_le_bytes).collect()
+}
+
+/// Parse an SMBIOS entry point and return the address and the
+/// (maximum) size of the structure table.
+///
+/// Both the 64 bit entry point of SMBIOS 3 and the 32 bit entry point
+/// of SMBIOS 2 are supported.
+pub fn parse_entry_point(entry_point: &[u8]) -> Option<(u64, usize)> {
+    if entry_point.starts_with(SMBIOS3_ANCHOR) {
+        let entry_point = entry_point.get(..SMBIOS3_ENTRY_POINT_SIZE)?;
+        let size = u32::from_le_bytes(entry_point[0x0c..0x10].try_into().ok()?);
+        let address = u64::from_le_bytes(entry_point[0x10..0x18].try_into().ok()?);
+        Some((address, size as usize))
+    } else if entry_point.starts_with(SMBIOS_ANCHOR) {
+        let entry_point = entry_point.get(..SMBIOS_ENTRY_POINT_SIZE)?;
+        let size = u16::from_le_bytes(entry_point[0x16..0x18].try_into().ok()?);
+        let address = u32::from_le_bytes(entry_point[0x18..0x1c].try_into().ok()?);
+        Some((address.into(), size.into()))
+    } else {
+        None
+    }
+}
+
+/// The SMBIOS structure table of the firmware, if it provides one.
+///
+/// The SMBIOS 3 table is preferred, because it may contain structures
+/// that do not fit into the 32 bit address space.
+pub fn smbios_table(system_table: &SystemTable<Boot>) -> Option<&'static [u8]> {
+    let config_table = system_table.config_table();
+    let (entry, size) = [
+        (SMBIOS3_GUID, SMBIOS3_ENTRY_POINT_SIZE),
+        (SMBIOS_GUID, SMBIOS_ENTRY_POINT_SIZE),
+    ]
+    .into_iter()
+    .find_map(|(guid, size)| {
+        let entry = config_table.iter().find(|entry| entry.guid == guid)?;
+        Some((entry.address as *const u8, size))
+    })?;
+
+    // SAFETY: The firmware keeps the entry point and the structure
+    // table in memory while boot services are running. The entry
+    // point gives the size of the table.
+    unsafe {
+        let (address, size) = parse_entry_point(core::slice::from_raw_parts(entry, size))?;
+        Some(core::slice::from_raw_parts(
+            usize::try_from(address).ok()? as *const u8,
+            size,
+        ))
+    }
+}
@@ -7,7 +7,9 @@ use uefi::{
     table::boot::ScopedProtocol,
 };
 
-fn open_capable_tpm2(boot_services: &BootServices) -> uefi::Result<ScopedProtocol<v2::Tcg>> {
+pub(crate) fn open_capable_tpm2(
+    boot_services: &BootServices,
+) -> uefi::Result<ScopedProtocol<'_, v2::Tcg>> {
     let tpm_handle = boot_services.get_handle_for_protocol::<v2::Tcg>()?;
     let mut tpm_protocol = boot_services.open_protocol_exclusive::<v2::Tcg>(tpm_handle)?;
 
@@ -0,0 +1,275 @@
+//! Boot parameters from a TPM NV index.
+//!
+//! Some deployments seal parts of their boot policy into TPM NV storage,
+//! where only the owner of the TPM can change it. A UKI with a `.tpmnv`
+//! section, e.g. `0x01500016`, reads that NV index through the TCG2
+//! protocol and applies the parameters in it on top of its own
+//! configuration. They are stored as `key=value` lines:
+//!
+//! - `cmdline-override=deny` ignores a command line passed by the boot
+//!   loader, which is otherwise used without Secure Boot.
+//! - `cmdline-extra=deny` ignores the command line from SMBIOS and from
+//!   addons.
+//!
+//! Parameters can only restrict what the UKI allows by itself. If the
+//! index does not exist or cannot be read, the stub boots with the
+//! defaults, i.e. only its own configuration applies.
+//!
+//! [`boot_parameters`] does not depend on the firmware, [`Tcg2Commands`]
+//! implements [`TpmCommands`] with the TCG2 protocol.
+
+use alloc::{vec, vec::Vec};
+
+use log::{info, warn};
+use uefi::{prelude::BootServices, Result, Status};
+
+use crate::tpm::open_capable_tpm2;
+
+pub(crate) const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+const TPM_ST_SESSIONS: u16 = 0x8002;
+const TPM_CC_NV_READ: u32 = 0x0000_014e;
+const TPM_CC_NV_READ_PUBLIC: u32 = 0x0000_0169;
+const TPM_RC_SUCCESS: u32 = 0;
+const TPM_RH_OWNER: u32 = 0x4000_0001;
+/// The handle of a password authorization session.
+const TPM_RS_PW: u32 = 0x4000_0009;
+/// The handle type of NV indexes, in the most significant byte.
+const TPM_HT_NV_INDEX: u8 = 0x01;
+
+const TPMA_NV_OWNERREAD: u32 = 1 << 17;
+const TPMA_NV_AUTHREAD: u32 = 1 << 18;
+const TPMA_NV_WRITTEN: u32 = 1 << 29;
+
+/// The size of the header of TPM commands and responses: tag, size and
+/// command or response code.
+const HEADER_SIZE: usize = 10;
+/// The most that is read with a single TPM2_NV_Read. Every TPM supports
+/// at least this much.
+const MAX_NV_READ: u16 = 512;
+/// The most that is read from the index, which is plenty for a few
+/// parameters.
+pub const MAX_PARAMETERS_SIZE: u16 = 2048;
+/// The size of the response buffer passed to the TPM.
+const MAX_RESPONSE_SIZE: usize = 4096;
+
+/// Something that executes raw TPM 2.0 commands.
+pub trait TpmCommands {
+    /// Submit `command` and return the response, including its header.
+    fn submit(&mut self, command: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// The boot parameters stored in the NV index. Every parameter defaults
+/// to what the UKI allows by itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootParameters {
+    /// Whether a command line passed by the boot loader may replace the
+    /// embedded one. This never happens with Secure Boot.
+    pub cmdline_override: bool,
+    /// Whether the command line may be extended from SMBIOS and addons.
+    pub cmdline_extra: bool,
+}
+
+impl Default for BootParameters {
+    fn default() -> Self {
+        Self {
+            cmdline_override: true,
+            cmdline_extra: true,
+        }
+    }
+}
+
+impl BootParameters {
+    /// Parse the contents of the NV index. They end at the first NUL,
+    /// since indexes are often larger than their contents. Unknown keys
+    /// and values are ignored.
+    pub fn parse(data: &[u8]) -> Self {
+        let data = data.split(|&b| b == 0).next().unwrap_or_default();
+        let mut parameters = Self::default();
+        for line in data.split(|&b| b == b'\n') {
+            let Ok(line) = core::str::from_utf8(line) else {
+                warn!("Ignoring boot parameter that is not UTF-8.");
+                continue;
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once('=').unwrap_or((line, ""));
+            let allowed = match value.trim() {
+                "allow" => true,
+                "deny" => false,
+                _ => {
+                    warn!("Ignoring boot parameter {line:?} from the TPM.");
+                    continue;
+                }
+            };
+            match key.trim() {
+                "cmdline-override" => parameters.cmdline_override = allowed,
+                "cmdline-extra" => parameters.cmdline_extra = allowed,
+                _ => warn!("Ignoring boot parameter {line:?} from the TPM."),
+            }
+        }
+        parameters
+    }
+
+    /// Whether the command line passed by the boot loader is used instead
+    /// of the embedded one.
+    pub fn use_passed_cmdline(&self, secure_boot_enabled: bool) -> bool {
+        !secure_boot_enabled && self.cmdline_override
+    }
+}
+
+/// Parse the contents of the `.tpmnv` section: the handle of an NV
+/// index in hexadecimal, e.g. `0x01500016`.
+pub fn parse_nv_index(section: &str) -> Option<u32> {
+    let section = section.trim();
+    let digits = section
+        .strip_prefix("0x")
+        .or_else(|| section.strip_prefix("0X"))?;
+    let index = u32::from_str_radix(digits, 16).ok()?;
+    (index.to_be_bytes()[0] == TPM_HT_NV_INDEX).then_some(index)
+}
+
+/// Read the boot parameters from the NV index `index`.
+///
+/// Falls back to the defaults if the index cannot be read.
+pub fn boot_parameters(tpm: &mut impl TpmCommands, index: u32) -> BootParameters {
+    match read_nv_index(tpm, index) {
+        Ok(data) => {
+            let parameters = BootParameters::parse(&data);
+            info!("Read boot parameters from TPM NV index {index:#010x}: {parameters:?}");
+            parameters
+        }
+        Err(err) => {
+            warn!("Failed to read TPM NV index {index:#010x}, using the defaults: {err:?}");
+            BootParameters::default()
+        }
+    }
+}
+
+/// Read the contents of the NV index `index`, at most
+/// [`MAX_PARAMETERS_SIZE`] bytes.
+///
+/// The index is read with an empty password. Indexes that can only be
+/// read with a policy fail with `ACCESS_DENIED`.
+pub fn read_nv_index(tpm: &mut impl TpmCommands, index: u32) -> Result<Vec<u8>> {
+    let (attributes, size) = read_nv_public(tpm, index)?;
+    if attributes & TPMA_NV_WRITTEN == 0 {
+        return Err(Status::NOT_FOUND.into());
+    }
+    let auth_handle = if attributes & TPMA_NV_AUTHREAD != 0 {
+        index
+    } else if attributes & TPMA_NV_OWNERREAD != 0 {
+        TPM_RH_OWNER
+    } else {
+        return Err(Status::ACCESS_DENIED.into());
+    };
+
+    let size = size.min(MAX_PARAMETERS_SIZE);
+    let mut data = Vec::with_capacity(usize::from(size));
+    let mut offset = 0;
+    while offset < size {
+        let chunk = (size - offset).min(MAX_NV_READ);
+        let mut command = Vec::new();
+        command.extend(auth_handle.to_be_bytes());
+        command.extend(index.to_be_bytes());
+        // An empty password session.
+        command.extend(9u32.to_be_bytes());
+        command.extend(TPM_RS_PW.to_be_bytes());
+        command.extend(0u16.to_be_bytes());
+        command.push(0);
+        command.extend(0u16.to_be_bytes());
+        command.extend(chunk.to_be_bytes());
+        command.extend(offset.to_be_bytes());
+
+        let response = submit(tpm, TPM_ST_SESSIONS, TPM_CC_NV_READ, &command)?;
+        // The parameters are preceded by their size, the buffer by its size.
+        let read = read_u16(&response, 4).ok_or(Status::PROTOCOL_ERROR)?;
+        let contents = response
+            .get(6..6 + usize::from(read))
+            .filter(|_| read <= chunk && read > 0)
+            .ok_or(Status::PROTOCOL_ERROR)?;
+        data.extend_from_slice(contents);
+        offset += read;
+    }
+    Ok(data)
+}
+
+/// Read the attributes and the size of the NV index `index`.
+fn read_nv_public(tpm: &mut impl TpmCommands, index: u32) -> Result<(u32, u16)> {
+    let response = submit(
+        tpm,
+        TPM_ST_NO_SESSIONS,
+        TPM_CC_NV_READ_PUBLIC,
+        &index.to_be_bytes(),
+    )?;
+    // TPM2B_NV_PUBLIC: its size, the index, the name algorithm, the
+    // attributes, the authorization policy with its size and the size of
+    // the contents.
+    let attributes = read_u32(&response, 8).ok_or(Status::PROTOCOL_ERROR)?;
+    let policy_size = read_u16(&response, 12).ok_or(Status::PROTOCOL_ERROR)?;
+    let size = read_u16(&response, 14 + usize::from(policy_size)).ok_or(Status::PROTOCOL_ERROR)?;
+    Ok((attributes, size))
+}
+
+/// Submit a command with `tag` and `code` and return the parameters of
+/// the response. Fails with `NOT_FOUND` if the TPM returns an error,
+/// e.g. because the index does not exist.
+pub(crate) fn submit(
+    tpm: &mut impl TpmCommands,
+    tag: u16,
+    code: u32,
+    body: &[u8],
+) -> Result<Vec<u8>> {
+    let size = u32::try_from(HEADER_SIZE + body.len()).map_err(|_| Status::INVALID_PARAMETER)?;
+    let mut command = Vec::with_capacity(HEADER_SIZE + body.len());
+    command.extend(tag.to_be_bytes());
+    command.extend(size.to_be_bytes());
+    command.extend(code.to_be_bytes());
+    command.extend(body);
+
+    let response = tpm.submit(&command)?;
+    let size = read_u32(&response, 2).ok_or(Status::PROTOCOL_ERROR)?;
+    let response_code = read_u32(&response, 6).ok_or(Status::PROTOCOL_ERROR)?;
+    if response_code != TPM_RC_SUCCESS {
+        warn!("The TPM failed command {code:#x} with {response_code:#x}.");
+        return Err(Status::NOT_FOUND.into());
+    }
+    let parameters = usize::try_from(size)
+        .ok()
+        .and_then(|size| response.get(HEADER_SIZE..size))
+        .ok_or(Status::PROTOCOL_ERROR)?;
+    Ok(parameters.to_vec())
+}
+
+pub(crate) fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+pub(crate) fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Submits commands to the TPM with the TCG2 protocol.
+pub struct Tcg2Commands<'a> {
+    boot_services: &'a BootServices,
+}
+
+impl<'a> Tcg2Commands<'a> {
+    pub fn new(boot_services: &'a BootServices) -> Self {
+        Self { boot_services }
+    }
+}
+
+impl TpmCommands for Tcg2Commands<'_> {
+    fn submit(&mut self, command: &[u8]) -> Result<Vec<u8>> {
+        let mut tpm = open_capable_tpm2(self.boot_services)?;
+        let mut response = vec![0; MAX_RESPONSE_SIZE];
+        tpm.submit_command(command, &mut response)?;
+        Ok(response)
+    }
+}
@@ -1,6 +1,14 @@
+use alloc::vec::Vec;
 use core::ffi::c_void;
 
-use uefi::{prelude::BootServices, proto::loaded_image::LoadedImage, Result};
+use uefi::{
+    prelude::BootServices,
+    proto::{
+        device_path::{build, DevicePath},
+        loaded_image::LoadedImage,
+    },
+    CStr16, Result, Status,
+};
 
 #[derive(Debug, Clone, Copy)]
 pub struct PeInMemory {
@@ -35,3 +43,25 @@ pub fn booted_image_file(boot_services: &BootServices) -> Result<PeInMemory> {
         image_size: usize::try_from(image_size).map_err(|_| uefi::Status::INVALID_PARAMETER)?,
     })
 }
+
+/// The device path of `path` on the volume of the running image, built
+/// in `buffer`.
+pub fn image_volume_path<'a>(
+    boot_services: &BootServices,
+    path: &CStr16,
+    buffer: &'a mut Vec<u8>,
+) -> Result<&'a DevicePath> {
+    let loaded_image =
+        boot_services.open_protocol_exclusive::<LoadedImage>(boot_services.image_handle())?;
+    let device = loaded_image.device().ok_or(Status::UNSUPPORTED)?;
+    let device_path = boot_services.open_protocol_exclusive::<DevicePath>(device)?;
+
+    let mut builder = build::DevicePathBuilder::with_vec(buffer);
+    for node in device_path.node_iter() {
+        builder = builder.push(&node).map_err(|_| Status::INVALID_PARAMETER)?;
+    }
+    builder
+        .push(&build::media::FilePath { path_name: path })
+        .and_then(|builder| builder.finalize())
+        .map_err(|_| Status::INVALID_PARAMETER.into())
+}
@@ -0,0 +1,185 @@
+use std::cell::RefCell;
+
+use linux_bootloader::addons::{
+    addon_cmdlines, addon_directories, addons_enabled, measure_addon_cmdlines, Addon, AddonServices,
+};
+use linux_bootloader::measure::{MeasurementPolicy, Measurements, Measurer};
+use uefi::{cstr16, proto::tcg::PcrIndex, CStr16, CString16, Status};
+
+const IMAGE_PATH: &CStr16 = cstr16!("\\EFI\\Linux\\nixos-generation-1.efi");
+
+/// A file on the ESP.
+struct MockFile {
+    path: &'static str,
+    /// Whether the firmware trusts its signature. With Secure Boot
+    /// enabled, `LoadImage` refuses untrusted images.
+    trusted: bool,
+    addon: Addon,
+}
+
+/// Boot services with Secure Boot enabled and files on the ESP.
+#[derive(Default)]
+struct MockBootServices {
+    files: Vec<MockFile>,
+    /// The paths of the loaded addons.
+    loaded: RefCell<Vec<String>>,
+}
+
+impl MockBootServices {
+    fn with_file(mut self, path: &'static str, trusted: bool, cmdline: &str) -> Self {
+        self.files.push(MockFile {
+            path,
+            trusted,
+            addon: Addon {
+                cmdline: Some(cmdline.into()),
+                has_kernel: false,
+            },
+        });
+        self
+    }
+}
+
+impl AddonServices for MockBootServices {
+    fn list_files(&self, directory: &CStr16) -> uefi::Result<Vec<CString16>> {
+        let prefix = format!("{directory}\\");
+        let names = self
+            .files
+            .iter()
+            .filter_map(|file| file.path.strip_prefix(&prefix))
+            .map(|name| CString16::try_from(name).unwrap())
+            .collect::<Vec<_>>();
+        if names.is_empty() {
+            return Err(Status::NOT_FOUND.into());
+        }
+        Ok(names)
+    }
+
+    fn load_addon(&self, path: &CStr16) -> uefi::Result<Addon> {
+        let file = self
+            .files
+            .iter()
+            .find(|file| file.path == path.to_string())
+            .ok_or(Status::NOT_FOUND)?;
+        if !file.trusted {
+            return Err(Status::SECURITY_VIOLATION.into());
+        }
+        self.loaded.borrow_mut().push(file.path.into());
+        Ok(file.addon.clone())
+    }
+}
+
+#[test]
+fn enable_addons_with_section() {
+    assert!(addons_enabled(Some("cmdline\n")));
+    assert!(!addons_enabled(Some("devicetree")));
+    assert!(!addons_enabled(None));
+}
+
+#[test]
+fn discover_global_and_image_addon_directories() {
+    assert_eq!(
+        addon_directories(Some(IMAGE_PATH)),
+        [
+            CString16::try_from("\\loader\\addons").unwrap(),
+            CString16::try_from("\\EFI\\Linux\\nixos-generation-1.efi.extra.d").unwrap(),
+        ]
+    );
+    assert_eq!(
+        addon_directories(None),
+        [CString16::try_from("\\loader\\addons").unwrap()]
+    );
+}
+
+#[test]
+fn append_global_addons_before_image_addons_in_name_order() {
+    let services = MockBootServices::default()
+        .with_file(
+            "\\EFI\\Linux\\nixos-generation-1.efi.extra.d\\debug.addon.efi",
+            true,
+            "loglevel=7\n",
+        )
+        .with_file("\\loader\\addons\\b.addon.efi", true, "quiet")
+        .with_file("\\loader\\addons\\a.ADDON.EFI", true, "iommu=pt")
+        .with_file("\\loader\\addons\\notes.txt", true, "ignored")
+        .with_file(
+            "\\EFI\\Linux\\nixos-generation-2.efi.extra.d\\other.addon.efi",
+            true,
+            "ignored",
+        );
+
+    assert_eq!(
+        addon_cmdlines(&services, Some(IMAGE_PATH)),
+        ["iommu=pt", "quiet", "loglevel=7"]
+    );
+    assert_eq!(
+        *services.loaded.borrow(),
+        [
+            "\\loader\\addons\\a.ADDON.EFI",
+            "\\loader\\addons\\b.addon.efi",
+            "\\EFI\\Linux\\nixos-generation-1.efi.extra.d\\debug.addon.efi",
+        ]
+    );
+}
+
+#[test]
+fn reject_unsigned_addon_with_secure_boot() {
+    let services = MockBootServices::default()
+        .with_file("\\loader\\addons\\evil.addon.efi", false, "init=/bin/sh")
+        .with_file("\\loader\\addons\\good.addon.efi", true, "quiet");
+
+    assert_eq!(addon_cmdlines(&services, Some(IMAGE_PATH)), ["quiet"]);
+    assert_eq!(
+        *services.loaded.borrow(),
+        ["\\loader\\addons\\good.addon.efi"]
+    );
+}
+
+#[test]
+fn ignore_ukis_and_sanitize_addon_cmdlines() {
+    let mut services = MockBootServices::default()
+        .with_file("\\loader\\addons\\a.addon.efi", true, " quiet\n\tsplash\n")
+        .with_file("\\loader\\addons\\b.addon.efi", true, "init=/bin/sh");
+    services.files[1].addon.has_kernel = true;
+
+    assert_eq!(addon_cmdlines(&services, None), ["quiet  splash"]);
+}
+
+/// Records what was measured.
+#[derive(Default)]
+struct MockTcg2 {
+    measured: Vec<(u32, Vec<u8>, String)>,
+}
+
+impl Measurer for MockTcg2 {
+    fn measure(
+        &mut self,
+        pcr_index: PcrIndex,
+        data: &[u8],
+        description: &str,
+    ) -> uefi::Result<bool> {
+        self.measured
+            .push((pcr_index.0, data.to_vec(), description.into()));
+        Ok(true)
+    }
+}
+
+#[test]
+fn measure_addon_cmdlines_into_pcr_12_in_order() {
+    let services = MockBootServices::default()
+        .with_file("\\loader\\addons\\b.addon.efi", true, "quiet")
+        .with_file("\\loader\\addons\\a.addon.efi", true, "iommu=pt\n");
+    let cmdlines = addon_cmdlines(&services, None);
+    let mut tcg2 = MockTcg2::default();
+    let mut measurements = Measurements::new(Some(MeasurementPolicy::Strict));
+
+    measure_addon_cmdlines(&cmdlines, &mut tcg2, &mut measurements).unwrap();
+
+    assert_eq!(
+        tcg2.measured,
+        [
+            (12, b"iommu=pt".to_vec(), "addon .cmdline".to_owned()),
+            (12, b"quiet".to_vec(), "addon .cmdline".to_owned()),
+        ]
+    );
+    assert_eq!(measurements.done().len(), 2);
+}
@@ -0,0 +1,488 @@
+use linux_bootloader::boot::{boot, BootFirmware, EmbeddedConfiguration};
+use linux_bootloader::initrd_path::InitrdFiles;
+use linux_bootloader::linux_loader::{InitrdFile, InitrdSource};
+use linux_bootloader::measure::{MeasurementPolicy, Measurements, Measurer};
+use linux_bootloader::self_hash_sections::SELF_HASH_SECTIONS;
+use sha2::{Digest, Sha256};
+use uefi::{proto::tcg::PcrIndex, CStr16, Status};
+
+const SECTION_ALIGNMENT: u32 = 0x1000;
+const KERNEL_PATH: &str = "\\EFI\\nixos\\kernel.efi";
+const INITRD_PATH: &str = "\\EFI\\nixos\\initrd.efi";
+const INITRD: &[u8] = b"a cpio archive";
+const INITRD_FILE_PATH: &str = "\\EFI\\nixos\\large-initrd";
+const INITRD_FILE: &[u8] = b" and a large initrd";
+
+/// Build a loaded PE32+ image with `sections`, laid out like the
+/// firmware maps it, i.e. with every section at its virtual address.
+fn image_with(sections: &[(&str, &[u8])]) -> Vec<u8> {
+    let pe_offset = 0x40;
+    let optional_header_size = 112 + 16 * 8;
+    // Every section starts on a new page.
+    let raw_sizes: Vec<u32> = sections
+        .iter()
+        .map(|(_, data)| (data.len() as u32 / SECTION_ALIGNMENT + 1) * SECTION_ALIGNMENT)
+        .collect();
+    let mut addresses = Vec::new();
+    let mut size = SECTION_ALIGNMENT;
+    for raw_size in &raw_sizes {
+        addresses.push(size);
+        size += raw_size;
+    }
+    let size = size as usize;
+    let mut image = vec![0; size];
+
+    image[..2].copy_from_slice(b"MZ");
+    image[0x3c..0x40].copy_from_slice(&(pe_offset as u32).to_le_bytes());
+
+    let coff = pe_offset + 4;
+    image[pe_offset..coff].copy_from_slice(b"PE\0\0");
+    image[coff..coff + 2].copy_from_slice(&0x8664u16.to_le_bytes());
+    image[coff + 2..coff + 4].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+    image[coff + 16..coff + 18].copy_from_slice(&(optional_header_size as u16).to_le_bytes());
+    image[coff + 18..coff + 20].copy_from_slice(&0x22u16.to_le_bytes());
+
+    let optional = coff + 20;
+    image[optional..optional + 2].copy_from_slice(&0x20bu16.to_le_bytes());
+    image[optional + 32..optional + 36].copy_from_slice(&SECTION_ALIGNMENT.to_le_bytes());
+    image[optional + 36..optional + 40].copy_from_slice(&0x200u32.to_le_bytes());
+    image[optional + 56..optional + 60].copy_from_slice(&(size as u32).to_le_bytes());
+    image[optional + 60..optional + 64].copy_from_slice(&0x200u32.to_le_bytes());
+    image[optional + 68..optional + 70].copy_from_slice(&10u16.to_le_bytes());
+    image[optional + 108..optional + 112].copy_from_slice(&16u32.to_le_bytes());
+
+    let table = optional + optional_header_size;
+    for (index, (name, data)) in sections.iter().enumerate() {
+        let address = addresses[index];
+        let header = table + index * 40;
+        image[header..header + name.len()].copy_from_slice(name.as_bytes());
+        image[header + 8..header + 12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        image[header + 12..header + 16].copy_from_slice(&address.to_le_bytes());
+        image[header + 16..header + 20].copy_from_slice(&raw_sizes[index].to_le_bytes());
+        image[header + 20..header + 24].copy_from_slice(&address.to_le_bytes());
+        image[header + 36..header + 40].copy_from_slice(&0x4000_0040u32.to_le_bytes());
+
+        let start = address as usize;
+        image[start..start + data.len()].copy_from_slice(data);
+    }
+    image
+}
+
+/// A kernel with the EFI stub, built for x86-64 like the tests.
+fn kernel_image() -> Vec<u8> {
+    image_with(&[(".text", &b"a kernel with the EFI stub"[..])])
+}
+
+fn hash(data: &[u8]) -> Vec<u8> {
+    Sha256::digest(data).to_vec()
+}
+
+/// The sections lzbt embeds into a thin stub for [`kernel_image`] and `INITRD`.
+fn stub_sections() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        (".cmdline", b"init=/init".to_vec()),
+        (".linux", KERNEL_PATH.into()),
+        (".linuxh", hash(&kernel_image())),
+        (".initrd", INITRD_PATH.into()),
+        (".initrdh", hash(INITRD)),
+    ]
+}
+
+fn stub_image(sections: &[(&str, Vec<u8>)]) -> Vec<u8> {
+    let sections = sections
+        .iter()
+        .map(|(name, data)| (*name, data.as_slice()))
+        .collect::<Vec<_>>();
+    image_with(&sections)
+}
+
+/// A file on the ESP that is read in chunks.
+struct MockFile {
+    data: Vec<u8>,
+    position: usize,
+}
+
+impl InitrdFile for MockFile {
+    fn set_position(&mut self, position: u64) -> uefi::Result {
+        self.position = position as usize;
+        Ok(())
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> uefi::Result<usize> {
+        let end = self.data.len().min(self.position + buffer.len());
+        let read = end - self.position;
+        buffer[..read].copy_from_slice(&self.data[self.position..end]);
+        self.position = end;
+        Ok(read)
+    }
+}
+
+/// What the mock firmware was asked to load.
+struct MockKernel {
+    kernel: Vec<u8>,
+    cmdline: Vec<u8>,
+    initrd: InitrdSource,
+}
+
+/// Firmware with the files in `files` on the ESP.
+struct MockFirmware {
+    secure_boot: bool,
+    files: Vec<(&'static str, Vec<u8>)>,
+    /// The paths of the files that were read, in order.
+    reads: Vec<String>,
+    /// Paths of files whose next read is refused with
+    /// `SECURITY_VIOLATION`, like buggy firmware does after boot.
+    refused: Vec<&'static str>,
+    /// The PCRs and descriptions of the measurements, in order.
+    measured: Vec<(u32, String)>,
+}
+
+impl MockFirmware {
+    fn new(secure_boot: bool) -> Self {
+        Self {
+            secure_boot,
+            files: vec![
+                (KERNEL_PATH, kernel_image()),
+                (INITRD_PATH, INITRD.to_vec()),
+                (INITRD_FILE_PATH, INITRD_FILE.to_vec()),
+            ],
+            reads: Vec::new(),
+            refused: Vec::new(),
+            measured: Vec::new(),
+        }
+    }
+
+    fn file(&mut self, path: &CStr16) -> uefi::Result<Vec<u8>> {
+        let path = path.to_string();
+        self.reads.push(path.clone());
+        if let Some(index) = self.refused.iter().position(|name| *name == path) {
+            self.refused.remove(index);
+            return Err(Status::SECURITY_VIOLATION.into());
+        }
+        self.files
+            .iter()
+            .find(|(name, _)| *name == path)
+            .map(|(_, data)| data.clone())
+            .ok_or(Status::NOT_FOUND.into())
+    }
+}
+
+impl InitrdFiles for MockFirmware {
+    fn read(&self, device: Option<&CStr16>, file: &CStr16) -> uefi::Result<Vec<u8>> {
+        assert!(device.is_none());
+        let file = file.to_string();
+        self.files
+            .iter()
+            .find(|(name, _)| *name == file)
+            .map(|(_, data)| data.clone())
+            .ok_or(Status::NOT_FOUND.into())
+    }
+}
+
+impl Measurer for MockFirmware {
+    fn measure(
+        &mut self,
+        pcr_index: PcrIndex,
+        _data: &[u8],
+        description: &str,
+    ) -> uefi::Result<bool> {
+        self.measured.push((pcr_index.0, description.into()));
+        Ok(true)
+    }
+}
+
+impl BootFirmware for MockFirmware {
+    type Kernel = MockKernel;
+
+    fn secure_boot(&self) -> bool {
+        self.secure_boot
+    }
+
+    fn read_file(&mut self, path: &CStr16) -> uefi::Result<Vec<u8>> {
+        self.file(path)
+    }
+
+    fn open_file(&mut self, path: &CStr16) -> uefi::Result<(Box<dyn InitrdFile>, usize)> {
+        let data = self.file(path)?;
+        let size = data.len();
+        let file: Box<dyn InitrdFile> = Box::new(MockFile { data, position: 0 });
+        Ok((file, size))
+    }
+
+    fn cmdline(
+        &mut self,
+        config: &EmbeddedConfiguration,
+        _secure_boot: bool,
+        _measurements: &mut Measurements,
+    ) -> uefi::Result<Vec<u8>> {
+        Ok(config.cmdline.to_string().into_bytes())
+    }
+
+    fn load_kernel(
+        &mut self,
+        kernel: &[u8],
+        cmdline: Vec<u8>,
+        initrd: InitrdSource,
+    ) -> uefi::Result<MockKernel> {
+        Ok(MockKernel {
+            kernel: kernel.to_vec(),
+            cmdline,
+            initrd,
+        })
+    }
+}
+
+fn serve(initrd: &mut InitrdSource) -> Vec<u8> {
+    let mut buffer = vec![0; initrd.size()];
+    initrd.read_into(&mut buffer).unwrap();
+    buffer
+}
+
+fn boot_image(
+    firmware: &mut MockFirmware,
+    image: &[u8],
+    dynamic_initrds: Vec<Vec<u8>>,
+    lazy_initrd: bool,
+) -> uefi::Result<MockKernel> {
+    boot(
+        firmware,
+        image,
+        ".cmdline",
+        dynamic_initrds,
+        lazy_initrd,
+        &mut Measurements::new(None),
+    )
+}
+
+#[test]
+fn load_kernel_and_initrd_from_esp() {
+    let mut firmware = MockFirmware::new(true);
+
+    let mut kernel = boot_image(
+        &mut firmware,
+        &stub_image(&stub_sections()),
+        vec![b" and a credential".to_vec()],
+        false,
+    )
+    .unwrap();
+
+    assert_eq!(kernel.kernel, kernel_image());
+    assert_eq!(kernel.cmdline, b"init=/init");
+    assert!(matches!(kernel.initrd, InitrdSource::Memory(_)));
+    assert_eq!(
+        serve(&mut kernel.initrd),
+        b"a cpio archive and a credential"
+    );
+    assert_eq!(firmware.reads, [KERNEL_PATH, INITRD_PATH]);
+}
+
+#[test]
+fn serve_initrd_from_esp_when_lazy() {
+    let mut firmware = MockFirmware::new(true);
+
+    let mut kernel =
+        boot_image(&mut firmware, &stub_image(&stub_sections()), vec![], true).unwrap();
+
+    assert!(matches!(kernel.initrd, InitrdSource::File { .. }));
+    assert_eq!(serve(&mut kernel.initrd), INITRD);
+}
+
+#[test]
+fn boot_without_initrd() {
+    let sections = stub_sections()
+        .into_iter()
+        .filter(|(name, _)| !name.starts_with(".initrd"))
+        .collect::<Vec<_>>();
+    let mut firmware = MockFirmware::new(true);
+
+    let mut kernel = boot_image(&mut firmware, &stub_image(&sections), vec![], false).unwrap();
+
+    assert_eq!(serve(&mut kernel.initrd), b"");
+    assert_eq!(firmware.reads, [KERNEL_PATH]);
+}
+
+/// The sections of a thin stub that appends `INITRD_FILE` to its initrd.
+fn sections_with_initrd_file() -> Vec<(&'static str, Vec<u8>)> {
+    let hash: String = hash(INITRD_FILE)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    let mut sections = stub_sections();
+    sections.push((
+        ".irdpath",
+        format!("{INITRD_FILE_PATH}\n{hash}").into_bytes(),
+    ));
+    sections
+}
+
+#[test]
+fn append_and_measure_initrd_file() {
+    for lazy_initrd in [false, true] {
+        let mut firmware = MockFirmware::new(true);
+
+        let mut kernel = boot(
+            &mut firmware,
+            &stub_image(&sections_with_initrd_file()),
+            ".cmdline",
+            vec![b" and a credential".to_vec()],
+            lazy_initrd,
+            &mut Measurements::new(Some(MeasurementPolicy::Strict)),
+        )
+        .unwrap();
+
+        // The file is appended in memory, even if the initrd could be
+        // served from the ESP otherwise.
+        assert!(matches!(kernel.initrd, InitrdSource::Memory(_)));
+        assert_eq!(
+            serve(&mut kernel.initrd),
+            b"a cpio archive and a large initrd and a credential"
+        );
+        assert_eq!(
+            firmware.measured,
+            [
+                (12, ".irdpath".to_owned()),
+                (12, ".irdpath file".to_owned())
+            ]
+        );
+    }
+}
+
+#[test]
+fn stop_if_initrd_file_is_missing_or_modified() {
+    let mut firmware = MockFirmware::new(true);
+    firmware.files.retain(|(name, _)| *name != INITRD_FILE_PATH);
+
+    let err = boot_image(
+        &mut firmware,
+        &stub_image(&sections_with_initrd_file()),
+        vec![],
+        false,
+    )
+    .err()
+    .unwrap();
+    assert_eq!(err.status(), Status::NOT_FOUND);
+
+    firmware
+        .files
+        .push((INITRD_FILE_PATH, b"a modified initrd".to_vec()));
+    let err = boot_image(
+        &mut firmware,
+        &stub_image(&sections_with_initrd_file()),
+        vec![],
+        false,
+    )
+    .err()
+    .unwrap();
+    assert_eq!(err.status(), Status::SECURITY_VIOLATION);
+}
+
+#[test]
+fn refuse_modified_files_only_with_secure_boot() {
+    for path in [KERNEL_PATH, INITRD_PATH] {
+        let mut firmware = MockFirmware::new(true);
+        firmware.files.retain(|(name, _)| *name != path);
+        firmware
+            .files
+            .push((path, image_with(&[(".text", &b"a modified kernel"[..])])));
+
+        let err = boot_image(&mut firmware, &stub_image(&stub_sections()), vec![], false)
+            .err()
+            .unwrap();
+        assert_eq!(err.status(), Status::SECURITY_VIOLATION);
+
+        // Without Secure Boot, the mismatch is only logged.
+        firmware.secure_boot = false;
+        assert!(boot_image(&mut firmware, &stub_image(&stub_sections()), vec![], false).is_ok());
+    }
+}
+
+#[test]
+fn refuse_kernel_for_other_machine() {
+    let mut kernel = kernel_image();
+    kernel[0x44..0x46].copy_from_slice(&0xaa64u16.to_le_bytes());
+    let mut sections = stub_sections();
+    sections.retain(|(name, _)| *name != ".linuxh");
+    sections.push((".linuxh", hash(&kernel)));
+    let mut firmware = MockFirmware::new(false);
+    firmware.files.retain(|(name, _)| *name != KERNEL_PATH);
+    firmware.files.push((KERNEL_PATH, kernel));
+
+    let err = boot_image(&mut firmware, &stub_image(&sections), vec![], false)
+        .err()
+        .unwrap();
+
+    assert_eq!(err.status(), Status::UNSUPPORTED);
+}
+
+#[test]
+fn stop_if_a_file_is_missing() {
+    let mut firmware = MockFirmware::new(false);
+    firmware.files.clear();
+
+    let err = boot_image(&mut firmware, &stub_image(&stub_sections()), vec![], false)
+        .err()
+        .unwrap();
+
+    assert_eq!(err.status(), Status::NOT_FOUND);
+}
+
+#[test]
+fn retry_refused_initrd_read_only_if_enabled() {
+    for lazy_initrd in [false, true] {
+        let mut sections = stub_sections();
+        let mut firmware = MockFirmware::new(true);
+        firmware.refused.push(INITRD_PATH);
+
+        let err = boot_image(&mut firmware, &stub_image(&sections), vec![], lazy_initrd)
+            .err()
+            .unwrap();
+        assert_eq!(err.status(), Status::SECURITY_VIOLATION);
+        assert_eq!(firmware.reads, [KERNEL_PATH, INITRD_PATH]);
+
+        sections.push((".initrdr", b"once".to_vec()));
+        let mut firmware = MockFirmware::new(true);
+        firmware.refused.push(INITRD_PATH);
+
+        let mut kernel =
+            boot_image(&mut firmware, &stub_image(&sections), vec![], lazy_initrd).unwrap();
+        assert_eq!(serve(&mut kernel.initrd), INITRD);
+        assert_eq!(firmware.reads, [KERNEL_PATH, INITRD_PATH, INITRD_PATH]);
+    }
+}
+
+#[test]
+fn stop_if_initrd_read_is_refused_again() {
+    let mut sections = stub_sections();
+    sections.push((".initrdr", b"once".to_vec()));
+    let mut firmware = MockFirmware::new(true);
+    firmware.refused = vec![INITRD_PATH, INITRD_PATH];
+
+    let err = boot_image(&mut firmware, &stub_image(&sections), vec![], false)
+        .err()
+        .unwrap();
+
+    assert_eq!(err.status(), Status::SECURITY_VIOLATION);
+    assert_eq!(firmware.reads, [KERNEL_PATH, INITRD_PATH, INITRD_PATH]);
+}
+
+#[test]
+fn refuse_image_that_does_not_match_its_self_hash() {
+    let mut sections = stub_sections();
+    let self_hash = Sha256::digest(
+        SELF_HASH_SECTIONS
+            .iter()
+            .filter_map(|name| sections.iter().find(|(n, _)| n == name))
+            .flat_map(|(_, data)| data.clone())
+            .collect::<Vec<_>>(),
+    );
+    sections.push((".selfh", self_hash.to_vec()));
+    let mut firmware = MockFirmware::new(false);
+    assert!(boot_image(&mut firmware, &stub_image(&sections), vec![], false).is_ok());
+
+    sections[0].1 = b"init=/bin/sh".to_vec();
+    let err = boot_image(&mut firmware, &stub_image(&sections), vec![], false)
+        .err()
+        .unwrap();
+
+    assert_eq!(err.status(), Status::SECURITY_VIOLATION);
+}
@@ -0,0 +1,158 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use linux_bootloader::boot_fallback::{
+    next_entry, parse_entry_list, start_with_fallback, KernelFailurePolicy,
+};
+use linux_bootloader::efivars::{VariableStore, BOOT_LOADER_VENDOR_UUID};
+use uefi::table::runtime::{VariableAttributes, VariableVendor};
+use uefi::{CStr16, Status};
+
+/// What happened in the firmware, in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Event {
+    Set(String, VariableAttributes, Vec<u8>),
+    Reset,
+}
+
+/// Variables of the boot loader vendor that record every write.
+struct MockStore<'a> {
+    variables: BTreeMap<String, Vec<u8>>,
+    events: &'a RefCell<Vec<Event>>,
+}
+
+impl<'a> MockStore<'a> {
+    /// The variables systemd-boot sets when it starts `selected`.
+    fn started_by_systemd_boot(events: &'a RefCell<Vec<Event>>, selected: &str) -> Self {
+        let mut variables = BTreeMap::new();
+        variables.insert(
+            "LoaderEntries".into(),
+            ucs2_list(&[
+                "nixos-generation-3.conf",
+                "nixos-generation-2.conf",
+                "nixos-generation-1.conf",
+            ]),
+        );
+        variables.insert("LoaderEntrySelected".into(), ucs2_list(&[selected]));
+        Self { variables, events }
+    }
+}
+
+impl VariableStore for MockStore<'_> {
+    fn exists(&self, name: &CStr16, vendor: &VariableVendor) -> bool {
+        *vendor == BOOT_LOADER_VENDOR_UUID && self.variables.contains_key(&name.to_string())
+    }
+
+    fn read(&self, name: &CStr16, vendor: &VariableVendor) -> uefi::Result<Vec<u8>> {
+        match self.variables.get(&name.to_string()) {
+            Some(data) if *vendor == BOOT_LOADER_VENDOR_UUID => Ok(data.clone()),
+            _ => Err(Status::NOT_FOUND.into()),
+        }
+    }
+
+    fn set(
+        &mut self,
+        name: &CStr16,
+        vendor: &VariableVendor,
+        attributes: VariableAttributes,
+        data: &[u8],
+    ) -> uefi::Result {
+        assert_eq!(*vendor, BOOT_LOADER_VENDOR_UUID);
+        self.events
+            .borrow_mut()
+            .push(Event::Set(name.to_string(), attributes, data.to_vec()));
+        self.variables.insert(name.to_string(), data.to_vec());
+        Ok(())
+    }
+}
+
+fn ucs2_list(entries: &[&str]) -> Vec<u8> {
+    entries
+        .iter()
+        .flat_map(|entry| entry.encode_utf16().chain([0]))
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+#[test]
+fn parse_kernel_failure_policy() {
+    assert_eq!(
+        KernelFailurePolicy::parse("next-entry\n"),
+        Some(KernelFailurePolicy::NextEntry)
+    );
+    assert_eq!(
+        KernelFailurePolicy::parse("return"),
+        Some(KernelFailurePolicy::Return)
+    );
+    assert_eq!(KernelFailurePolicy::parse("reboot"), None);
+}
+
+#[test]
+fn find_entry_after_selected_one() {
+    let entries = parse_entry_list(&ucs2_list(&["a.conf", "b.conf", "c.conf"]));
+
+    assert_eq!(entries, ["a.conf", "b.conf", "c.conf"]);
+    assert_eq!(next_entry(&entries, "a.conf"), Some("b.conf"));
+    assert_eq!(next_entry(&entries, "c.conf"), None);
+    assert_eq!(next_entry(&entries, "d.conf"), None);
+}
+
+#[test]
+fn request_next_entry_before_reset_on_kernel_failure() {
+    let events = RefCell::new(Vec::new());
+    let mut store = MockStore::started_by_systemd_boot(&events, "nixos-generation-3.conf");
+
+    let status = start_with_fallback(
+        KernelFailurePolicy::NextEntry,
+        &mut store,
+        || Status::LOAD_ERROR,
+        || events.borrow_mut().push(Event::Reset),
+    );
+
+    assert_eq!(status, Status::LOAD_ERROR);
+    assert_eq!(
+        *events.borrow(),
+        [
+            Event::Set(
+                "LoaderEntryOneShot".into(),
+                VariableAttributes::NON_VOLATILE
+                    | VariableAttributes::BOOTSERVICE_ACCESS
+                    | VariableAttributes::RUNTIME_ACCESS,
+                ucs2_list(&["nixos-generation-2.conf"]),
+            ),
+            Event::Reset,
+        ]
+    );
+}
+
+#[test]
+fn return_kernel_failure_without_fallback() {
+    let events = RefCell::new(Vec::new());
+    let mut store = MockStore::started_by_systemd_boot(&events, "nixos-generation-3.conf");
+
+    let status = start_with_fallback(
+        KernelFailurePolicy::Return,
+        &mut store,
+        || Status::LOAD_ERROR,
+        || events.borrow_mut().push(Event::Reset),
+    );
+
+    assert_eq!(status, Status::LOAD_ERROR);
+    assert!(events.borrow().is_empty());
+}
+
+#[test]
+fn return_kernel_failure_of_last_entry() {
+    let events = RefCell::new(Vec::new());
+    let mut store = MockStore::started_by_systemd_boot(&events, "nixos-generation-1.conf");
+
+    let status = start_with_fallback(
+        KernelFailurePolicy::NextEntry,
+        &mut store,
+        || Status::LOAD_ERROR,
+        || events.borrow_mut().push(Event::Reset),
+    );
+
+    assert_eq!(status, Status::LOAD_ERROR);
+    assert!(events.borrow().is_empty());
+}
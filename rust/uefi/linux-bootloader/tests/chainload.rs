@@ -0,0 +1,114 @@
+use core::cell::RefCell;
+use core::ffi::c_void;
+
+use linux_bootloader::chainload::{chainload, chainload_target, ChainloadServices};
+use uefi::{cstr16, CStr16, CString16, Handle, Status};
+
+const SYSTEMD_BOOT: &CStr16 = cstr16!("\\EFI\\systemd\\systemd-bootx64.efi");
+
+fn handle(value: usize) -> Handle {
+    // SAFETY: The mock never dereferences handles.
+    unsafe { Handle::from_ptr(value as *mut c_void) }.unwrap()
+}
+
+/// Boot services with files on the ESP that the firmware either trusts or
+/// rejects.
+#[derive(Default)]
+struct MockBootServices {
+    /// The files on the ESP and whether their signature is trusted.
+    files: Vec<(CString16, bool)>,
+    /// The paths of the loaded images.
+    loaded: RefCell<Vec<CString16>>,
+    /// The handles of the started images.
+    started: RefCell<Vec<usize>>,
+}
+
+impl MockBootServices {
+    fn with_file(path: &CStr16, trusted: bool) -> Self {
+        Self {
+            files: vec![(path.into(), trusted)],
+            ..Self::default()
+        }
+    }
+}
+
+impl ChainloadServices for MockBootServices {
+    fn load_image(&self, path: &CStr16) -> uefi::Result<Handle> {
+        let (_, trusted) = self
+            .files
+            .iter()
+            .find(|(file, _)| &**file == path)
+            .ok_or(Status::NOT_FOUND)?;
+        if !trusted {
+            return Err(Status::SECURITY_VIOLATION.into());
+        }
+        let mut loaded = self.loaded.borrow_mut();
+        loaded.push(path.into());
+        Ok(handle(loaded.len()))
+    }
+
+    fn start_image(&self, image: Handle) -> uefi::Result<()> {
+        self.started.borrow_mut().push(image.as_ptr() as usize);
+        Ok(())
+    }
+}
+
+#[test]
+fn resolve_chainload_path() {
+    let target = |section| chainload_target(Some(section), false);
+
+    assert_eq!(
+        target("\\EFI\\systemd\\systemd-bootx64.efi").as_deref(),
+        Some(SYSTEMD_BOOT)
+    );
+    assert_eq!(
+        target("/EFI/systemd/systemd-bootx64.efi\n").as_deref(),
+        Some(SYSTEMD_BOOT)
+    );
+    assert_eq!(target("EFI\\systemd\\systemd-bootx64.efi"), None);
+    assert_eq!(target("\\EFI\\..\\systemd-bootx64.efi"), None);
+    assert_eq!(target("\\EFI\\\\systemd-bootx64.efi"), None);
+    assert_eq!(target(""), None);
+    assert_eq!(chainload_target(None, false), None);
+}
+
+#[test]
+fn do_not_chainload_from_boot_loader() {
+    assert_eq!(
+        chainload_target(Some("\\EFI\\systemd\\systemd-bootx64.efi"), true),
+        None
+    );
+}
+
+#[test]
+fn start_verified_boot_loader() {
+    let boot_services = MockBootServices::with_file(SYSTEMD_BOOT, true);
+
+    assert_eq!(chainload(&boot_services, SYSTEMD_BOOT), Ok(true));
+    assert_eq!(
+        *boot_services.loaded.borrow(),
+        [CString16::from(SYSTEMD_BOOT)]
+    );
+    assert_eq!(*boot_services.started.borrow(), [1]);
+}
+
+#[test]
+fn boot_kernel_without_boot_loader() {
+    let boot_services = MockBootServices::default();
+
+    assert_eq!(chainload(&boot_services, SYSTEMD_BOOT), Ok(false));
+    assert!(boot_services.started.borrow().is_empty());
+}
+
+#[test]
+fn do_not_start_rejected_boot_loader() {
+    let boot_services = MockBootServices::with_file(SYSTEMD_BOOT, false);
+
+    assert_eq!(
+        chainload(&boot_services, SYSTEMD_BOOT)
+            .unwrap_err()
+            .status(),
+        Status::SECURITY_VIOLATION
+    );
+    assert!(boot_services.started.borrow().is_empty());
+}
@@ -0,0 +1,65 @@
+use linux_bootloader::cmdline::{check_cmdline, CmdlinePolicy, CmdlineProblem, MAX_CMDLINE_LENGTH};
+
+#[test]
+fn parse_cmdline_policy() {
+    assert_eq!(
+        CmdlinePolicy::parse("strict\n"),
+        Some(CmdlinePolicy::Strict)
+    );
+    assert_eq!(
+        CmdlinePolicy::parse("sanitize"),
+        Some(CmdlinePolicy::Sanitize)
+    );
+    assert_eq!(CmdlinePolicy::parse("reject"), None);
+}
+
+#[test]
+fn accept_clean_cmdline_in_both_modes() {
+    for policy in [CmdlinePolicy::Sanitize, CmdlinePolicy::Strict] {
+        assert_eq!(
+            check_cmdline("init=/init quiet", policy).unwrap(),
+            "init=/init quiet"
+        );
+    }
+}
+
+#[test]
+fn replace_control_characters_when_sanitizing() {
+    assert_eq!(
+        check_cmdline(
+            "init=/init\nquiet\tloglevel=4\r\u{7f}",
+            CmdlinePolicy::Sanitize
+        )
+        .unwrap(),
+        "init=/init quiet loglevel=4  "
+    );
+}
+
+#[test]
+fn refuse_control_characters_when_strict() {
+    assert_eq!(
+        check_cmdline("init=/init\nquiet\tloglevel=4", CmdlinePolicy::Strict),
+        Err(CmdlineProblem::ControlCharacter {
+            offset: 10,
+            character: '\n'
+        })
+    );
+}
+
+#[test]
+fn check_length_against_maximum() {
+    let longest = "a".repeat(MAX_CMDLINE_LENGTH);
+    let too_long = "a".repeat(MAX_CMDLINE_LENGTH + 1);
+
+    assert!(check_cmdline(&longest, CmdlinePolicy::Strict).is_ok());
+    assert_eq!(
+        check_cmdline(&too_long, CmdlinePolicy::Strict),
+        Err(CmdlineProblem::TooLong(MAX_CMDLINE_LENGTH + 1))
+    );
+    // The kernel truncates the command line itself, so sanitizing only
+    // warns.
+    assert_eq!(
+        check_cmdline(&too_long, CmdlinePolicy::Sanitize).unwrap(),
+        too_long
+    );
+}
@@ -0,0 +1,74 @@
+use std::collections::VecDeque;
+
+use linux_bootloader::confirmation::{
+    ask_confirmation, confirmation_required, ConfirmationEvent, ConfirmationIo,
+};
+use uefi::Status;
+
+/// Replays a fixed sequence of key presses and counts how often the
+/// question is shown.
+struct MockIo {
+    events: VecDeque<uefi::Result<ConfirmationEvent>>,
+    shown: usize,
+}
+
+impl MockIo {
+    fn new(events: impl IntoIterator<Item = uefi::Result<ConfirmationEvent>>) -> Self {
+        Self {
+            events: events.into_iter().collect(),
+            shown: 0,
+        }
+    }
+}
+
+impl ConfirmationIo for MockIo {
+    fn next_event(&mut self) -> uefi::Result<ConfirmationEvent> {
+        self.events
+            .pop_front()
+            .expect("The question waited for more key presses than expected")
+    }
+
+    fn show(&mut self) {
+        self.shown += 1;
+    }
+}
+
+#[test]
+fn require_confirmation_only_without_secure_boot() {
+    assert!(confirmation_required(Some("secure-boot-off"), false));
+    assert!(!confirmation_required(Some("secure-boot-off"), true));
+    assert!(!confirmation_required(None, false));
+    assert!(!confirmation_required(None, true));
+}
+
+#[test]
+fn boot_after_confirmation() {
+    let mut io = MockIo::new([
+        Ok(ConfirmationEvent::OtherKey),
+        Ok(ConfirmationEvent::Confirm),
+    ]);
+
+    assert!(ask_confirmation(&mut io).unwrap());
+    assert_eq!(io.shown, 1);
+    assert!(io.events.is_empty());
+}
+
+#[test]
+fn refuse_after_decline() {
+    let mut io = MockIo::new([Ok(ConfirmationEvent::Decline)]);
+
+    assert!(!ask_confirmation(&mut io).unwrap());
+}
+
+#[test]
+fn return_input_errors() {
+    let mut io = MockIo::new([
+        Ok(ConfirmationEvent::OtherKey),
+        Err(Status::DEVICE_ERROR.into()),
+    ]);
+
+    assert_eq!(
+        ask_confirmation(&mut io).unwrap_err().status(),
+        Status::DEVICE_ERROR
+    );
+}
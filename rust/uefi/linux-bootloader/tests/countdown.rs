@@ -0,0 +1,114 @@
+use std::collections::VecDeque;
+
+use linux_bootloader::countdown::{
+    run_countdown, Countdown, CountdownEvent, CountdownIo, CountdownState,
+};
+
+/// Replays a fixed sequence of timer ticks and key presses and records
+/// everything that is shown.
+struct MockIo {
+    events: VecDeque<CountdownEvent>,
+    shown: Vec<CountdownState>,
+}
+
+impl MockIo {
+    fn new(events: impl IntoIterator<Item = CountdownEvent>) -> Self {
+        Self {
+            events: events.into_iter().collect(),
+            shown: Vec::new(),
+        }
+    }
+}
+
+impl CountdownIo for MockIo {
+    fn next_event(&mut self) -> uefi::Result<CountdownEvent> {
+        Ok(self
+            .events
+            .pop_front()
+            .expect("The countdown waited for more events than expected"))
+    }
+
+    fn show(&mut self, state: CountdownState) {
+        self.shown.push(state);
+    }
+}
+
+#[test]
+fn boot_on_timeout() {
+    let mut io = MockIo::new([CountdownEvent::Tick; 3]);
+
+    run_countdown(3, &mut io).unwrap();
+
+    assert_eq!(
+        io.shown,
+        [
+            CountdownState::Running(3),
+            CountdownState::Running(2),
+            CountdownState::Running(1),
+            CountdownState::Done,
+        ]
+    );
+    assert!(io.events.is_empty());
+}
+
+#[test]
+fn boot_on_enter() {
+    let mut io = MockIo::new([CountdownEvent::Tick, CountdownEvent::Enter]);
+
+    run_countdown(5, &mut io).unwrap();
+
+    assert_eq!(
+        io.shown,
+        [
+            CountdownState::Running(5),
+            CountdownState::Running(4),
+            CountdownState::Done,
+        ]
+    );
+}
+
+#[test]
+fn pause_on_other_key() {
+    let mut io = MockIo::new([
+        CountdownEvent::OtherKey,
+        CountdownEvent::Tick,
+        CountdownEvent::Tick,
+        CountdownEvent::OtherKey,
+        CountdownEvent::Enter,
+    ]);
+
+    run_countdown(2, &mut io).unwrap();
+
+    assert_eq!(
+        io.shown,
+        [
+            CountdownState::Running(2),
+            CountdownState::Paused,
+            CountdownState::Paused,
+            CountdownState::Paused,
+            CountdownState::Paused,
+            CountdownState::Done,
+        ]
+    );
+}
+
+#[test]
+fn zero_seconds_boot_immediately() {
+    let mut io = MockIo::new([]);
+
+    run_countdown(0, &mut io).unwrap();
+
+    assert_eq!(io.shown, [CountdownState::Done]);
+    assert_eq!(Countdown::new(0).state(), CountdownState::Done);
+}
+
+#[test]
+fn stay_done() {
+    let mut countdown = Countdown::new(1);
+
+    assert_eq!(countdown.handle(CountdownEvent::Tick), CountdownState::Done);
+    assert_eq!(
+        countdown.handle(CountdownEvent::OtherKey),
+        CountdownState::Done
+    );
+}
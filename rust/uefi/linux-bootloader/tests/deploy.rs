@@ -0,0 +1,100 @@
+use linux_bootloader::deploy::{DeployError, DeployInfo, Slot};
+
+#[test]
+fn parse_valid() {
+    assert_eq!(
+        DeployInfo::parse(r#"{"slot":"b","version":"24.05.1"}"#),
+        Ok(DeployInfo {
+            slot: Slot::B,
+            version: "24.05.1".into(),
+        })
+    );
+}
+
+#[test]
+fn parse_with_whitespace_and_unknown_keys() {
+    let json = r#"
+        {
+            "version": "1.2 \"rc1\" é😀",
+            "extra": [1, -2.5e3, true, false, null, {"nested": {}}],
+            "slot": "a"
+        }
+    "#;
+
+    assert_eq!(
+        DeployInfo::parse(json),
+        Ok(DeployInfo {
+            slot: Slot::A,
+            version: "1.2 \"rc1\" \u{e9}\u{1f600}".into(),
+        })
+    );
+}
+
+#[test]
+fn reject_malformed_json() {
+    for json in [
+        "",
+        "{",
+        r#"{"slot":"a","version":"1"#,
+        r#"{"slot":"a","version":"1",}"#,
+        r#"{"slot":"a" "version":"1"}"#,
+        r#"{slot:"a","version":"1"}"#,
+        r#"{"slot":"a","version":"1"} trailing"#,
+        r#"{"slot":"a","version":"\x"}"#,
+        r#"{"slot":"a","version":"\ud83d"}"#,
+        "{\"slot\":\"a\",\"version\":\"line\nbreak\"}",
+        r#"{"slot":"a","version":"1","extra":01}"#,
+        r#"{"slot":"a","version":"1","extra":tru}"#,
+    ] {
+        assert!(
+            matches!(DeployInfo::parse(json), Err(DeployError::Syntax(_))),
+            "{json:?} was accepted"
+        );
+    }
+}
+
+#[test]
+fn reject_invalid_contents() {
+    assert_eq!(
+        DeployInfo::parse(r#"["a", "1"]"#),
+        Err(DeployError::NotAnObject)
+    );
+    assert_eq!(
+        DeployInfo::parse(r#"{"version":"1"}"#),
+        Err(DeployError::MissingSlot)
+    );
+    assert_eq!(
+        DeployInfo::parse(r#"{"slot":"c","version":"1"}"#),
+        Err(DeployError::InvalidSlot)
+    );
+    assert_eq!(
+        DeployInfo::parse(r#"{"slot":1,"version":"1"}"#),
+        Err(DeployError::InvalidSlot)
+    );
+    assert_eq!(
+        DeployInfo::parse(r#"{"slot":"a"}"#),
+        Err(DeployError::MissingVersion)
+    );
+    assert_eq!(
+        DeployInfo::parse(r#"{"slot":"a","version":24}"#),
+        Err(DeployError::MissingVersion)
+    );
+    assert_eq!(
+        DeployInfo::parse(r#"{"slot":"a","slot":"b","version":"1"}"#),
+        Err(DeployError::DuplicateKey)
+    );
+}
+
+#[test]
+fn reject_deeply_nested_values() {
+    let json = format!(
+        r#"{{"slot":"a","version":"1","extra":{}{}}}"#,
+        "[".repeat(100),
+        "]".repeat(100)
+    );
+
+    assert!(matches!(
+        DeployInfo::parse(&json),
+        Err(DeployError::Syntax(_))
+    ));
+}
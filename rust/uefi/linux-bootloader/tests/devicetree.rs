@@ -0,0 +1,97 @@
+//! The fixtures are the `.dts` files next to them in compiled form,
+//! including the overlay metadata that `dtc -@` generates.
+
+use linux_bootloader::devicetree::DeviceTree;
+
+const BASE: &[u8] = include_bytes!("fixtures/devicetree/base.dtb");
+const OVERLAY: &[u8] = include_bytes!("fixtures/devicetree/overlay.dtbo");
+
+fn string(value: &str) -> Vec<u8> {
+    [value.as_bytes(), b"\0"].concat()
+}
+
+fn cells(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_be_bytes()).collect()
+}
+
+fn property<'a>(tree: &'a DeviceTree, path: &str, name: &str) -> Option<&'a [u8]> {
+    tree.node(path)?.property(name)
+}
+
+#[test]
+fn roundtrip_devicetree() {
+    let tree = DeviceTree::parse(BASE).unwrap();
+
+    assert_eq!(DeviceTree::parse(&tree.to_bytes()).unwrap(), tree);
+    assert_eq!(
+        property(&tree, "/soc/serial@1000", "compatible"),
+        Some(&string("ns16550a")[..])
+    );
+}
+
+#[test]
+fn apply_overlay_to_base() {
+    let mut tree = DeviceTree::parse(BASE).unwrap();
+    tree.apply_overlay(&DeviceTree::parse(OVERLAY).unwrap())
+        .unwrap();
+    // The merged tree survives serialization.
+    let merged = DeviceTree::parse(&tree.to_bytes()).unwrap();
+
+    // Properties of the target are replaced, others are kept.
+    let serial = "/soc/serial@1000";
+    assert_eq!(
+        property(&merged, serial, "status"),
+        Some(&string("okay")[..])
+    );
+    assert_eq!(
+        property(&merged, serial, "compatible"),
+        Some(&string("ns16550a")[..])
+    );
+    assert_eq!(
+        property(&merged, "/", "model"),
+        Some(&string("Test board")[..])
+    );
+    assert_eq!(
+        property(&merged, "/", "compatible"),
+        Some(&string("lanzaboote,test")[..])
+    );
+
+    // The phandle of the new node is moved past the ones of the base
+    // and references to it are updated.
+    let led = format!("{serial}/led");
+    assert_eq!(property(&merged, &led, "phandle"), Some(&cells(&[3])[..]));
+    assert_eq!(property(&merged, "/leds", "led"), Some(&cells(&[3])[..]));
+
+    // References to labels of the base are resolved.
+    assert_eq!(property(&merged, &led, "gpios"), Some(&cells(&[2, 5])[..]));
+
+    // Labels of the overlay point into the base.
+    assert_eq!(
+        property(&merged, "/__symbols__", "led"),
+        Some(&string(&led)[..])
+    );
+    assert_eq!(
+        property(&merged, "/__symbols__", "uart0"),
+        Some(&string(serial)[..])
+    );
+
+    // Nothing of the overlay metadata ends up in the tree.
+    assert!(merged.node("/fragment@0").is_none());
+    assert!(merged.node("/__fixups__").is_none());
+}
+
+#[test]
+fn reject_overlay_with_unknown_label() {
+    let mut base = DeviceTree::parse(BASE).unwrap();
+    base.root.children.retain(|c| c.name != "__symbols__");
+
+    assert!(base
+        .apply_overlay(&DeviceTree::parse(OVERLAY).unwrap())
+        .is_err());
+}
+
+#[test]
+fn reject_garbage() {
+    assert!(DeviceTree::parse(b"not a devicetree").is_err());
+    assert!(DeviceTree::parse(&BASE[..BASE.len() - 8]).is_err());
+}
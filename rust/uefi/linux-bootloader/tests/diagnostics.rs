@@ -0,0 +1,156 @@
+use linux_bootloader::diagnostics::{
+    memory_map_lines, redact_cmdline, Diagnostics, MemoryRegion, MemorySummary,
+};
+use uefi::table::boot::MemoryType;
+
+fn diagnostics() -> Diagnostics {
+    Diagnostics {
+        secure_boot: true,
+        firmware_vendor: "EDK II".into(),
+        firmware_revision: 0x10000,
+        cmdline: Some("init=/nix/store/init quiet".into()),
+        sections: vec![(".text".into(), 1234), (".osrel".into(), 56)],
+        memory: Some(MemorySummary {
+            descriptors: 3,
+            total_pages: 512,
+            free_pages: 256,
+        }),
+    }
+}
+
+#[test]
+fn render_diagnostics() {
+    assert_eq!(
+        diagnostics().lines(),
+        [
+            "Lanzaboote diagnostics",
+            "Secure Boot: enabled",
+            "Firmware: EDK II (revision 0x10000)",
+            "Command line: init=/nix/store/init quiet",
+            "Sections:",
+            "  .text          1234 bytes",
+            "  .osrel           56 bytes",
+            "Memory: 1 MiB free of 2 MiB in 3 descriptors",
+        ]
+    );
+}
+
+#[test]
+fn render_missing_inputs() {
+    let diagnostics = Diagnostics {
+        secure_boot: false,
+        cmdline: None,
+        sections: Vec::new(),
+        memory: None,
+        ..diagnostics()
+    };
+
+    assert_eq!(
+        diagnostics.lines(),
+        [
+            "Lanzaboote diagnostics",
+            "Secure Boot: disabled",
+            "Firmware: EDK II (revision 0x10000)",
+            "Command line: (none)",
+            "Sections:",
+            "Memory: failed to read the memory map",
+        ]
+    );
+}
+
+#[test]
+fn summarize_memory_map() {
+    let summary = MemorySummary::from_descriptors([
+        (MemoryType::CONVENTIONAL, 100),
+        (MemoryType::LOADER_CODE, 10),
+        (MemoryType::CONVENTIONAL, 20),
+    ]);
+
+    assert_eq!(
+        summary,
+        MemorySummary {
+            descriptors: 3,
+            total_pages: 130,
+            free_pages: 120,
+        }
+    );
+}
+
+#[test]
+fn redact_secrets_in_cmdline() {
+    assert_eq!(
+        redact_cmdline(
+            "init=/init rd.luks.key=/keyfile:UUID=1234 quiet \
+             systemd.set_credential=passwd.hashed-password.root:$y$secret \
+             systemd.set_credential_binary=token luks.key=/other"
+        ),
+        "init=/init rd.luks.key=*** quiet \
+         systemd.set_credential=passwd.hashed-password.root:*** \
+         systemd.set_credential_binary=*** luks.key=***"
+    );
+}
+
+#[test]
+fn keep_other_params_in_cmdline() {
+    let cmdline = "init=/init rd.luks.uuid=1234 luks.keyfoo=bar quiet";
+    assert_eq!(redact_cmdline(cmdline), cmdline);
+
+    let diagnostics = Diagnostics {
+        cmdline: Some("init=/init luks.key=/secret".into()),
+        ..diagnostics()
+    };
+    assert_eq!(
+        diagnostics.lines()[3],
+        "Command line: init=/init luks.key=***"
+    );
+}
+
+fn region(ty: MemoryType, phys_start: u64, pages: u64) -> MemoryRegion {
+    MemoryRegion {
+        ty,
+        phys_start,
+        pages,
+    }
+}
+
+#[test]
+fn render_memory_map() {
+    // The firmware does not have to return the descriptors in order.
+    let regions = [
+        region(MemoryType::CONVENTIONAL, 0x100000, 0x700),
+        region(MemoryType::CONVENTIONAL, 0, 0xa0),
+        region(MemoryType::LOADER_CODE, 0x800000, 0x10),
+        // Adjacent to the previous region and of the same type.
+        region(MemoryType::LOADER_CODE, 0x810000, 0x10),
+        region(MemoryType::BOOT_SERVICES_DATA, 0x820000, 0x40000),
+        region(MemoryType::MMIO, 0xfec0_0000, 1),
+        region(MemoryType::custom(0x8000_0000), 0x1_0000_0000, 0x100),
+    ];
+
+    assert_eq!(
+        memory_map_lines(&regions),
+        [
+            "Memory map: 7 descriptors in 6 regions",
+            "  0x0000000000000000-0x000000000009ffff Conventional           640 KiB",
+            "  0x0000000000100000-0x00000000007fffff Conventional             7 MiB",
+            "  0x0000000000800000-0x000000000081ffff LoaderCode             128 KiB",
+            "  0x0000000000820000-0x000000004081ffff BootServicesData         1 GiB",
+            "  0x00000000fec00000-0x00000000fec00fff MemoryMappedIO           4 KiB",
+            "  0x0000000100000000-0x00000001000fffff 0x80000000               1 MiB",
+            "Totals:",
+            "  LoaderCode             128 KiB",
+            "  BootServicesData         1 GiB",
+            "  Conventional             7 MiB",
+            "  MemoryMappedIO           4 KiB",
+            "  0x80000000               1 MiB",
+        ]
+    );
+}
+
+#[test]
+fn render_empty_memory_map() {
+    assert_eq!(
+        memory_map_lines(&[]),
+        ["Memory map: 0 descriptors in 0 regions", "Totals:"]
+    );
+}
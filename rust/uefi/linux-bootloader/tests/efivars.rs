@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+
+use linux_bootloader::efivars::{
+    export_loader_variables, LoaderInfo, VariableStore, BOOT_LOADER_VENDOR_UUID,
+};
+use uefi::table::runtime::{VariableAttributes, VariableVendor};
+use uefi::{guid, CStr16, CString16, Status};
+
+/// Variables of the boot loader vendor, keyed by name.
+#[derive(Default)]
+struct MockStore(BTreeMap<String, Vec<u8>>);
+
+impl VariableStore for MockStore {
+    fn exists(&self, name: &CStr16, vendor: &VariableVendor) -> bool {
+        *vendor == BOOT_LOADER_VENDOR_UUID && self.0.contains_key(&name.to_string())
+    }
+
+    fn read(&self, name: &CStr16, vendor: &VariableVendor) -> uefi::Result<Vec<u8>> {
+        match self.0.get(&name.to_string()) {
+            Some(data) if *vendor == BOOT_LOADER_VENDOR_UUID => Ok(data.clone()),
+            _ => Err(Status::NOT_FOUND.into()),
+        }
+    }
+
+    fn set(
+        &mut self,
+        name: &CStr16,
+        vendor: &VariableVendor,
+        attributes: VariableAttributes,
+        data: &[u8],
+    ) -> uefi::Result {
+        assert_eq!(*vendor, BOOT_LOADER_VENDOR_UUID);
+        assert!(attributes.contains(VariableAttributes::RUNTIME_ACCESS));
+        self.0.insert(name.to_string(), data.to_vec());
+        Ok(())
+    }
+}
+
+impl MockStore {
+    fn get(&self, name: &str) -> Option<String> {
+        let data = self.0.get(name)?;
+        let chars = data
+            .chunks(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect::<Vec<_>>();
+        let (nul, chars) = chars.split_last().unwrap();
+        assert_eq!(*nul, 0, "{name} is not NUL terminated");
+        Some(String::from_utf16(chars).unwrap())
+    }
+}
+
+fn info() -> LoaderInfo {
+    LoaderInfo {
+        device_part_uuid: Some(guid!("4DB1A0E8-3C2F-4D8B-9B8E-2C1A6F0F1E2D")),
+        image_identifier: Some(CString16::try_from(r"\EFI\Linux\nixos-generation-1.efi").unwrap()),
+        firmware_vendor: CString16::try_from("EDK II").unwrap(),
+        firmware_revision: 0x0001_0000,
+        uefi_revision: (2 << 16) | 70,
+    }
+}
+
+#[test]
+fn export_variables_like_systemd_boot() {
+    let mut store = MockStore::default();
+    export_loader_variables(&mut store, &info());
+
+    assert_eq!(
+        store.get("LoaderDevicePartUUID").as_deref(),
+        Some("4db1a0e8-3c2f-4d8b-9b8e-2c1a6f0f1e2d")
+    );
+    assert_eq!(
+        store.get("LoaderImageIdentifier").as_deref(),
+        Some(r"\EFI\Linux\nixos-generation-1.efi")
+    );
+    assert_eq!(
+        store.get("LoaderFirmwareInfo").as_deref(),
+        Some("EDK II 1.00")
+    );
+    assert_eq!(
+        store.get("LoaderFirmwareType").as_deref(),
+        Some("UEFI 2.70")
+    );
+}
+
+#[test]
+fn keep_variables_of_the_boot_loader() {
+    let mut store = MockStore::default();
+    store.0.insert(
+        "LoaderImageIdentifier".into(),
+        b"\\\0E\0F\0I\0\0\0".to_vec(),
+    );
+    export_loader_variables(&mut store, &info());
+
+    assert_eq!(store.get("LoaderImageIdentifier").as_deref(), Some(r"\EFI"));
+    assert!(store.get("LoaderFirmwareInfo").is_some());
+}
+
+#[test]
+fn skip_unknown_partition() {
+    let mut store = MockStore::default();
+    export_loader_variables(
+        &mut store,
+        &LoaderInfo {
+            device_part_uuid: None,
+            image_identifier: None,
+            ..info()
+        },
+    );
+
+    assert_eq!(
+        store.0.keys().collect::<Vec<_>>(),
+        ["LoaderFirmwareInfo", "LoaderFirmwareType"]
+    );
+}
@@ -0,0 +1,112 @@
+use linux_bootloader::firmware_policy::{
+    FirmwareAction, FirmwareIdentity, FirmwarePolicy, FirmwarePolicyError,
+};
+
+/// Stands in for the system table.
+struct MockFirmware {
+    vendor: &'static str,
+    revision: u32,
+}
+
+impl FirmwareIdentity for MockFirmware {
+    fn firmware_vendor(&self) -> String {
+        self.vendor.into()
+    }
+
+    fn firmware_revision(&self) -> u32 {
+        self.revision
+    }
+}
+
+#[test]
+fn parse_policy() {
+    assert_eq!(
+        FirmwarePolicy::parse("revision=0x10002\nvendor=EDK II\naction=refuse\n"),
+        Ok(FirmwarePolicy {
+            min_revision: 0x10002,
+            vendor: Some("EDK II".into()),
+            action: FirmwareAction::Refuse,
+        })
+    );
+    assert_eq!(
+        FirmwarePolicy::parse(" revision = 65538 \n\n"),
+        Ok(FirmwarePolicy {
+            min_revision: 0x10002,
+            vendor: None,
+            action: FirmwareAction::Warn,
+        })
+    );
+}
+
+#[test]
+fn reject_malformed_policy() {
+    assert_eq!(
+        FirmwarePolicy::parse("action=warn"),
+        Err(FirmwarePolicyError::MissingRevision)
+    );
+    assert_eq!(
+        FirmwarePolicy::parse("revision=0x1g"),
+        Err(FirmwarePolicyError::InvalidRevision("0x1g".into()))
+    );
+    assert_eq!(
+        FirmwarePolicy::parse("revision=0x100000000"),
+        Err(FirmwarePolicyError::InvalidRevision("0x100000000".into()))
+    );
+    assert_eq!(
+        FirmwarePolicy::parse("revision=1\naction=halt"),
+        Err(FirmwarePolicyError::InvalidAction("halt".into()))
+    );
+    assert_eq!(
+        FirmwarePolicy::parse("revision=1\nrevision=2"),
+        Err(FirmwarePolicyError::DuplicateKey("revision".into()))
+    );
+    assert_eq!(
+        FirmwarePolicy::parse("revision=1\nversion=2"),
+        Err(FirmwarePolicyError::UnknownKey("version".into()))
+    );
+    assert_eq!(
+        FirmwarePolicy::parse("revision 1"),
+        Err(FirmwarePolicyError::Syntax("revision 1".into()))
+    );
+}
+
+#[test]
+fn check_revision_against_threshold() {
+    let policy = FirmwarePolicy::parse("revision=0x10002").unwrap();
+
+    let old = MockFirmware {
+        vendor: "EDK II",
+        revision: 0x10001,
+    };
+    let message = policy.check(&old).expect("Old firmware accepted");
+    assert!(
+        message.contains("EDK II is at revision 0x10001"),
+        "{message}"
+    );
+    assert!(message.contains("update the firmware"), "{message}");
+
+    for revision in [0x10002, 0x20000] {
+        let new = MockFirmware {
+            vendor: "EDK II",
+            revision,
+        };
+        assert_eq!(policy.check(&new), None);
+    }
+}
+
+#[test]
+fn only_check_firmware_of_vendor() {
+    let policy = FirmwarePolicy::parse("revision=0x10002\nvendor=EDK II").unwrap();
+
+    let other_vendor = MockFirmware {
+        vendor: "American Megatrends",
+        revision: 0x5,
+    };
+    assert_eq!(policy.check(&other_vendor), None);
+
+    let same_vendor = MockFirmware {
+        vendor: "EDK II",
+        revision: 0x5,
+    };
+    assert!(policy.check(&same_vendor).is_some());
+}
@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+
+use linux_bootloader::efivars::VariableStore;
+use linux_bootloader::firmware_setup::{
+    request_firmware_setup, SetupKey, EFI_OS_INDICATIONS_BOOT_TO_FW_UI,
+};
+use uefi::proto::console::text::{Key, ScanCode};
+use uefi::table::runtime::{VariableAttributes, VariableVendor};
+use uefi::{CStr16, Char16, Status};
+
+/// Global variables, keyed by name, with their attributes.
+#[derive(Default)]
+struct MockStore(BTreeMap<String, (VariableAttributes, Vec<u8>)>);
+
+impl MockStore {
+    fn with_supported(supported: u64) -> Self {
+        let mut store = Self::default();
+        store.0.insert(
+            "OsIndicationsSupported".into(),
+            (
+                VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
+                supported.to_le_bytes().to_vec(),
+            ),
+        );
+        store
+    }
+
+    fn os_indications(&self) -> Option<u64> {
+        let (attributes, data) = self.0.get("OsIndications")?;
+        assert!(attributes.contains(
+            VariableAttributes::NON_VOLATILE
+                | VariableAttributes::BOOTSERVICE_ACCESS
+                | VariableAttributes::RUNTIME_ACCESS
+        ));
+        Some(u64::from_le_bytes(data[..].try_into().unwrap()))
+    }
+}
+
+impl VariableStore for MockStore {
+    fn exists(&self, name: &CStr16, vendor: &VariableVendor) -> bool {
+        *vendor == VariableVendor::GLOBAL_VARIABLE && self.0.contains_key(&name.to_string())
+    }
+
+    fn read(&self, name: &CStr16, vendor: &VariableVendor) -> uefi::Result<Vec<u8>> {
+        match self.0.get(&name.to_string()) {
+            Some((_, data)) if *vendor == VariableVendor::GLOBAL_VARIABLE => Ok(data.clone()),
+            _ => Err(Status::NOT_FOUND.into()),
+        }
+    }
+
+    fn set(
+        &mut self,
+        name: &CStr16,
+        vendor: &VariableVendor,
+        attributes: VariableAttributes,
+        data: &[u8],
+    ) -> uefi::Result {
+        assert_eq!(*vendor, VariableVendor::GLOBAL_VARIABLE);
+        self.0.insert(name.to_string(), (attributes, data.to_vec()));
+        Ok(())
+    }
+}
+
+fn printable(c: char) -> Key {
+    Key::Printable(Char16::try_from(c).unwrap())
+}
+
+#[test]
+fn parse_setup_keys() {
+    assert_eq!(SetupKey::parse("F2"), Some(SetupKey::Function(2)));
+    assert_eq!(SetupKey::parse("F12\n"), Some(SetupKey::Function(12)));
+    assert_eq!(SetupKey::parse("Escape"), Some(SetupKey::Escape));
+    assert_eq!(SetupKey::parse("Delete"), Some(SetupKey::Delete));
+    assert_eq!(SetupKey::parse("s"), Some(SetupKey::Char('s')));
+    assert_eq!(SetupKey::parse("F"), Some(SetupKey::Char('F')));
+    assert_eq!(SetupKey::parse("F0"), None);
+    assert_eq!(SetupKey::parse("F13"), None);
+    assert_eq!(SetupKey::parse("setup"), None);
+    assert_eq!(SetupKey::parse(""), None);
+}
+
+#[test]
+fn match_pressed_keys() {
+    assert!(SetupKey::Function(2).matches(&Key::Special(ScanCode::FUNCTION_2)));
+    assert!(!SetupKey::Function(2).matches(&Key::Special(ScanCode::FUNCTION_12)));
+    assert!(SetupKey::Delete.matches(&Key::Special(ScanCode::DELETE)));
+    assert!(!SetupKey::Delete.matches(&Key::Special(ScanCode::ESCAPE)));
+    assert!(SetupKey::Char('s').matches(&printable('s')));
+    assert!(!SetupKey::Char('s').matches(&printable('S')));
+    assert!(!SetupKey::Escape.matches(&printable('s')));
+}
+
+#[test]
+fn set_boot_to_firmware_ui_bit() {
+    let mut store = MockStore::with_supported(EFI_OS_INDICATIONS_BOOT_TO_FW_UI);
+
+    request_firmware_setup(&mut store).unwrap();
+
+    assert_eq!(store.os_indications(), Some(1 << 0));
+}
+
+#[test]
+fn keep_other_os_indications() {
+    let mut store = MockStore::with_supported(EFI_OS_INDICATIONS_BOOT_TO_FW_UI | 1 << 2);
+    store.0.insert(
+        "OsIndications".into(),
+        (
+            VariableAttributes::empty(),
+            (1u64 << 2).to_le_bytes().to_vec(),
+        ),
+    );
+
+    request_firmware_setup(&mut store).unwrap();
+
+    assert_eq!(
+        store.os_indications(),
+        Some(EFI_OS_INDICATIONS_BOOT_TO_FW_UI | 1 << 2)
+    );
+}
+
+#[test]
+fn refuse_without_firmware_support() {
+    let mut store = MockStore::with_supported(1 << 2);
+    assert_eq!(
+        request_firmware_setup(&mut store).unwrap_err().status(),
+        Status::UNSUPPORTED
+    );
+    assert_eq!(store.os_indications(), None);
+
+    let mut store = MockStore::default();
+    assert_eq!(
+        request_firmware_setup(&mut store).unwrap_err().status(),
+        Status::UNSUPPORTED
+    );
+}
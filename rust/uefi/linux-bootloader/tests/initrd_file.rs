@@ -0,0 +1,101 @@
+use linux_bootloader::linux_loader::{InitrdFile, InitrdSource};
+use sha2::{Digest, Sha256};
+use uefi::Status;
+
+const INITRD: &[u8] = b"a cpio archive that is served from the ESP";
+
+/// A file protocol serving `data`, at most `chunk` bytes per read.
+struct MockFile {
+    data: Vec<u8>,
+    position: usize,
+    chunk: usize,
+}
+
+impl MockFile {
+    fn new(data: &[u8], chunk: usize) -> Self {
+        Self {
+            data: data.to_vec(),
+            position: 0,
+            chunk,
+        }
+    }
+}
+
+impl InitrdFile for MockFile {
+    fn set_position(&mut self, position: u64) -> uefi::Result {
+        self.position = position as usize;
+        Ok(())
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> uefi::Result<usize> {
+        let end = self
+            .data
+            .len()
+            .min(self.position + buffer.len().min(self.chunk));
+        let read = end - self.position;
+        buffer[..read].copy_from_slice(&self.data[self.position..end]);
+        self.position = end;
+        Ok(read)
+    }
+}
+
+fn hash(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+fn file_source(file: MockFile, expected_hash: Option<[u8; 32]>) -> InitrdSource {
+    InitrdSource::File {
+        file: Box::new(file),
+        size: INITRD.len(),
+        expected_hash,
+    }
+}
+
+fn serve(initrd: &mut InitrdSource) -> uefi::Result<Vec<u8>> {
+    let mut buffer = vec![0xff; initrd.size()];
+    initrd.read_into(&mut buffer)?;
+    Ok(buffer)
+}
+
+#[test]
+fn serve_file_in_short_reads() {
+    let mut initrd = file_source(MockFile::new(INITRD, 5), Some(hash(INITRD)));
+
+    assert_eq!(serve(&mut initrd).unwrap(), INITRD);
+    // Linux may ask again, e.g. after a failed first attempt.
+    assert_eq!(serve(&mut initrd).unwrap(), INITRD);
+}
+
+#[test]
+fn refuse_file_changed_after_verification() {
+    let mut changed = INITRD.to_vec();
+    changed[0] ^= 1;
+    let mut initrd = file_source(MockFile::new(&changed, 4096), Some(hash(INITRD)));
+
+    let mut buffer = vec![0xff; initrd.size()];
+    let err = initrd.read_into(&mut buffer).unwrap_err();
+    assert_eq!(err.status(), Status::SECURITY_VIOLATION);
+    assert!(buffer.iter().all(|&byte| byte == 0));
+}
+
+#[test]
+fn serve_unverified_file_as_is() {
+    let mut changed = INITRD.to_vec();
+    changed[0] ^= 1;
+    let mut initrd = file_source(MockFile::new(&changed, 4096), None);
+
+    assert_eq!(serve(&mut initrd).unwrap(), changed);
+}
+
+#[test]
+fn refuse_truncated_file() {
+    let mut initrd = file_source(
+        MockFile::new(&INITRD[..INITRD.len() - 1], 4096),
+        Some(hash(INITRD)),
+    );
+
+    assert_eq!(
+        serve(&mut initrd).unwrap_err().status(),
+        Status::END_OF_FILE
+    );
+}
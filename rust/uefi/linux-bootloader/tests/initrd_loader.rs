@@ -0,0 +1,209 @@
+use core::cell::RefCell;
+use core::ffi::c_void;
+use core::ops::RangeInclusive;
+
+use linux_bootloader::linux_loader::{
+    InitrdLoader, InitrdSource, InitrdTable, ProtocolInterfaces, PROTOCOL_INSTALL_ATTEMPTS,
+};
+use uefi::proto::device_path::DevicePath;
+use uefi::{guid, Guid, Handle, Identify, Status};
+
+const LOAD_FILE2_GUID: Guid = guid!("4006c0c1-fcb3-403e-996d-4a6c8724e06d");
+
+/// A vendor media device path with a made-up GUID.
+const OTHER_DEVICE_PATH: [u8; 24] = [
+    0x04, 0x03, 0x14, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c,
+    0x0d, 0x0e, 0x0f, 0x10, 0x7f, 0xff, 0x04, 0x00,
+];
+
+fn handle(value: usize) -> Handle {
+    // SAFETY: The mock never dereferences handles.
+    unsafe { Handle::from_ptr(value as *mut c_void) }.unwrap()
+}
+
+/// Firmware that keeps track of the installed protocols and fails as
+/// configured.
+#[derive(Default)]
+struct MockFirmware {
+    /// The installed protocols by handle.
+    installed: RefCell<Vec<(usize, Guid)>>,
+    /// The protocol of every install call.
+    install_calls: RefCell<Vec<Guid>>,
+    /// These calls (counting from 1) to install the protocol fail with
+    /// the status.
+    install_failure: Option<(Guid, Status, RangeInclusive<usize>)>,
+    /// Uninstalling this protocol fails.
+    uninstall_failure: Option<Guid>,
+    next_handle: RefCell<usize>,
+}
+
+impl MockFirmware {
+    fn failing_install(protocol: Guid, status: Status, calls: RangeInclusive<usize>) -> Self {
+        Self {
+            install_failure: Some((protocol, status, calls)),
+            ..Self::default()
+        }
+    }
+
+    fn installed(&self) -> Vec<(usize, Guid)> {
+        self.installed.borrow().clone()
+    }
+
+    fn install_calls(&self, protocol: Guid) -> usize {
+        self.install_calls
+            .borrow()
+            .iter()
+            .filter(|&&g| g == protocol)
+            .count()
+    }
+}
+
+impl ProtocolInterfaces for MockFirmware {
+    unsafe fn install_protocol_interface(
+        &self,
+        handle: Option<Handle>,
+        protocol: &Guid,
+        _interface: *mut c_void,
+    ) -> uefi::Result<Handle> {
+        self.install_calls.borrow_mut().push(*protocol);
+        if let Some((failing, status, calls)) = &self.install_failure {
+            if failing == protocol && calls.contains(&self.install_calls(*failing)) {
+                return Err((*status).into());
+            }
+        }
+
+        let handle = handle.unwrap_or_else(|| {
+            let mut next = self.next_handle.borrow_mut();
+            *next += 1;
+            self::handle(0x1000 + *next)
+        });
+        self.installed
+            .borrow_mut()
+            .push((handle.as_ptr() as usize, *protocol));
+        Ok(handle)
+    }
+
+    unsafe fn uninstall_protocol_interface(
+        &self,
+        handle: Handle,
+        protocol: &Guid,
+        _interface: *mut c_void,
+    ) -> uefi::Result<()> {
+        if self.uninstall_failure == Some(*protocol) {
+            return Err(Status::ACCESS_DENIED.into());
+        }
+        let mut installed = self.installed.borrow_mut();
+        let index = installed
+            .iter()
+            .position(|&entry| entry == (handle.as_ptr() as usize, *protocol))
+            .ok_or(Status::NOT_FOUND)?;
+        installed.remove(index);
+        Ok(())
+    }
+}
+
+#[test]
+fn install_and_uninstall_protocols() {
+    let firmware = MockFirmware::default();
+    let mut loader = InitrdLoader::new(&firmware, handle(1), b"initrd".to_vec()).unwrap();
+    assert_eq!(
+        firmware.installed(),
+        [(1, DevicePath::GUID), (1, LOAD_FILE2_GUID)]
+    );
+
+    loader.uninstall(&firmware).unwrap();
+    assert!(firmware.installed().is_empty());
+}
+
+#[test]
+fn uninstall_device_path_if_load_file2_fails() {
+    let firmware = MockFirmware::failing_install(LOAD_FILE2_GUID, Status::INVALID_PARAMETER, 1..=1);
+    let error = InitrdLoader::new(&firmware, handle(1), b"initrd".to_vec())
+        .err()
+        .unwrap();
+
+    assert_eq!(error.status(), Status::INVALID_PARAMETER);
+    assert!(firmware.installed().is_empty());
+    // Permanent errors are not retried.
+    assert_eq!(firmware.install_calls(LOAD_FILE2_GUID), 1);
+}
+
+#[test]
+fn retry_when_out_of_resources() {
+    let firmware = MockFirmware::failing_install(
+        LOAD_FILE2_GUID,
+        Status::OUT_OF_RESOURCES,
+        1..=PROTOCOL_INSTALL_ATTEMPTS - 1,
+    );
+    let mut loader = InitrdLoader::new(&firmware, handle(1), b"initrd".to_vec()).unwrap();
+    assert_eq!(
+        firmware.install_calls(LOAD_FILE2_GUID),
+        PROTOCOL_INSTALL_ATTEMPTS
+    );
+    loader.uninstall(&firmware).unwrap();
+
+    let firmware = MockFirmware::failing_install(
+        LOAD_FILE2_GUID,
+        Status::OUT_OF_RESOURCES,
+        1..=PROTOCOL_INSTALL_ATTEMPTS,
+    );
+    let error = InitrdLoader::new(&firmware, handle(1), b"initrd".to_vec())
+        .err()
+        .unwrap();
+    assert_eq!(error.status(), Status::OUT_OF_RESOURCES);
+    assert_eq!(
+        firmware.install_calls(LOAD_FILE2_GUID),
+        PROTOCOL_INSTALL_ATTEMPTS
+    );
+    assert!(firmware.installed().is_empty());
+}
+
+#[test]
+fn return_install_error_if_device_path_cannot_be_uninstalled() {
+    let firmware = MockFirmware {
+        uninstall_failure: Some(DevicePath::GUID),
+        ..MockFirmware::failing_install(LOAD_FILE2_GUID, Status::INVALID_PARAMETER, 1..=1)
+    };
+    let error = InitrdLoader::new(&firmware, handle(1), b"initrd".to_vec())
+        .err()
+        .unwrap();
+
+    // The device path is leaked instead of being freed while the
+    // firmware still refers to it.
+    assert_eq!(error.status(), Status::INVALID_PARAMETER);
+    assert_eq!(firmware.installed(), [(1, DevicePath::GUID)]);
+}
+
+#[test]
+fn uninstall_earlier_initrds_if_registration_fails() {
+    let mut table = InitrdTable::new(InitrdSource::Memory(b"linux".to_vec()));
+    table
+        .insert(&OTHER_DEVICE_PATH, InitrdSource::Memory(b"other".to_vec()))
+        .unwrap();
+    // The protocols of the first initrd install fine, the second
+    // LoadFile2 fails.
+    let firmware = MockFirmware::failing_install(LOAD_FILE2_GUID, Status::INVALID_PARAMETER, 2..=2);
+
+    let error = InitrdLoader::from_table(&firmware, handle(1), table)
+        .err()
+        .unwrap();
+    assert_eq!(error.status(), Status::INVALID_PARAMETER);
+    assert!(firmware.installed().is_empty());
+}
+
+#[test]
+fn leak_initrds_that_cannot_be_uninstalled() {
+    let firmware = MockFirmware::default();
+    let mut loader = InitrdLoader::new(&firmware, handle(1), b"initrd".to_vec()).unwrap();
+
+    let firmware = MockFirmware {
+        installed: firmware.installed,
+        uninstall_failure: Some(DevicePath::GUID),
+        ..MockFirmware::default()
+    };
+    let error = loader.uninstall(&firmware).unwrap_err();
+    assert_eq!(error.status(), Status::ACCESS_DENIED);
+    // The loader can be dropped, its initrd was leaked.
+    drop(loader);
+    assert_eq!(firmware.installed(), [(1, DevicePath::GUID)]);
+}
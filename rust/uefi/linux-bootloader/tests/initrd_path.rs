@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+
+use linux_bootloader::initrd_path::{
+    load_initrd_path, measure_initrd_path, InitrdFiles, InitrdPath,
+};
+use linux_bootloader::measure::{MeasurementPolicy, Measurements, Measurer};
+use sha2::{Digest, Sha256};
+use uefi::{cstr16, proto::tcg::PcrIndex, CStr16, CString16, Status};
+
+const DEVICE: &str = "PciRoot(0x0)/Pci(0x1,0x1)/HD(1,GPT,01234567-89AB-CDEF-0123-456789ABCDEF)";
+
+/// Files by their volume and path.
+struct MockFiles(HashMap<(Option<String>, String), Vec<u8>>);
+
+impl InitrdFiles for MockFiles {
+    fn read(&self, device: Option<&CStr16>, file: &CStr16) -> uefi::Result<Vec<u8>> {
+        let key = (device.map(|device| device.to_string()), file.to_string());
+        self.0.get(&key).cloned().ok_or(Status::NOT_FOUND.into())
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[test]
+fn parse_device_and_file() {
+    let section = format!("{DEVICE}/\\EFI\\nixos\\initrd\n{}\n", hex(b"initrd"));
+    let path = InitrdPath::parse(&section).unwrap();
+
+    assert_eq!(path.device, Some(CString16::try_from(DEVICE).unwrap()));
+    assert_eq!(path.file, cstr16!("\\EFI\\nixos\\initrd"));
+    assert_eq!(path.hash.as_slice(), Sha256::digest(b"initrd").as_slice());
+
+    let path = InitrdPath::parse(&format!("\\EFI\\nixos\\initrd\n{}", hex(b""))).unwrap();
+    assert_eq!(path.device, None);
+}
+
+#[test]
+fn reject_malformed_sections() {
+    let hash = hex(b"initrd");
+    for section in [
+        String::new(),
+        format!("{DEVICE}\n{hash}"),
+        format!("\\EFI\\nixos\\initrd\n{}", &hash[1..]),
+        format!("\\EFI\\nixos\\initrd\n{}zz", &hash[2..]),
+        format!("\\EFI\\nixos\\initrd\n{hash}\nextra"),
+    ] {
+        assert_eq!(
+            InitrdPath::parse(&section).unwrap_err().status(),
+            Status::INVALID_PARAMETER,
+            "{section:?}"
+        );
+    }
+}
+
+#[test]
+fn resolve_device_path_to_file() {
+    let files = MockFiles(HashMap::from([
+        (
+            (Some(DEVICE.to_string()), "\\EFI\\nixos\\initrd".to_string()),
+            b"large initrd".to_vec(),
+        ),
+        (
+            (None, "\\EFI\\nixos\\initrd".to_string()),
+            b"initrd next to the UKI".to_vec(),
+        ),
+    ]));
+
+    let section = format!("{DEVICE}/\\EFI\\nixos\\initrd\n{}", hex(b"large initrd"));
+    let path = InitrdPath::parse(&section).unwrap();
+    assert_eq!(
+        load_initrd_path(&files, &path, true).unwrap(),
+        b"large initrd"
+    );
+
+    let section = format!("\\EFI\\nixos\\initrd\n{}", hex(b"initrd next to the UKI"));
+    let path = InitrdPath::parse(&section).unwrap();
+    assert_eq!(
+        load_initrd_path(&files, &path, true).unwrap(),
+        b"initrd next to the UKI"
+    );
+
+    let section = format!("{DEVICE}/\\EFI\\nixos\\missing\n{}", hex(b""));
+    let path = InitrdPath::parse(&section).unwrap();
+    assert_eq!(
+        load_initrd_path(&files, &path, true).unwrap_err().status(),
+        Status::NOT_FOUND
+    );
+}
+
+#[test]
+fn reject_modified_file_with_secure_boot() {
+    let files = MockFiles(HashMap::from([(
+        (None, "\\EFI\\nixos\\initrd".to_string()),
+        b"modified initrd".to_vec(),
+    )]));
+    let path = InitrdPath::parse(&format!("\\EFI\\nixos\\initrd\n{}", hex(b"initrd"))).unwrap();
+
+    assert_eq!(
+        load_initrd_path(&files, &path, true).unwrap_err().status(),
+        Status::SECURITY_VIOLATION
+    );
+    assert_eq!(
+        load_initrd_path(&files, &path, false).unwrap(),
+        b"modified initrd"
+    );
+}
+
+/// Records what was measured.
+#[derive(Default)]
+struct MockTcg2 {
+    measured: Vec<(u32, Vec<u8>, String)>,
+}
+
+impl Measurer for MockTcg2 {
+    fn measure(
+        &mut self,
+        pcr_index: PcrIndex,
+        data: &[u8],
+        description: &str,
+    ) -> uefi::Result<bool> {
+        self.measured
+            .push((pcr_index.0, data.to_vec(), description.into()));
+        Ok(true)
+    }
+}
+
+#[test]
+fn measure_section_and_file_into_pcr_12() {
+    let files = MockFiles(HashMap::from([(
+        (None, "\\EFI\\nixos\\initrd".to_string()),
+        b"initrd".to_vec(),
+    )]));
+    let section = format!("\\EFI\\nixos\\initrd\n{}", hex(b"initrd"));
+    let path = InitrdPath::parse(&section).unwrap();
+    let data = load_initrd_path(&files, &path, true).unwrap();
+    let mut tcg2 = MockTcg2::default();
+    let mut measurements = Measurements::new(Some(MeasurementPolicy::Strict));
+
+    measure_initrd_path(section.as_bytes(), &data, &mut tcg2, &mut measurements).unwrap();
+
+    assert_eq!(
+        tcg2.measured,
+        [
+            (12, section.into_bytes(), ".irdpath".to_owned()),
+            (12, b"initrd".to_vec(), ".irdpath file".to_owned()),
+        ]
+    );
+}
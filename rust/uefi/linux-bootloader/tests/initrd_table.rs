@@ -0,0 +1,60 @@
+use linux_bootloader::linux_loader::{InitrdSource, InitrdTable, LINUX_INITRD_DEVICE_PATH};
+
+/// A vendor media device path with a made-up GUID.
+const OTHER_DEVICE_PATH: [u8; 24] = [
+    0x04, 0x03, 0x14, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c,
+    0x0d, 0x0e, 0x0f, 0x10, 0x7f, 0xff, 0x04, 0x00,
+];
+
+fn serve(table: &mut InitrdTable, device_path: &[u8]) -> Vec<u8> {
+    let initrd = table
+        .get_mut(device_path)
+        .expect("No initrd for device path");
+    let mut buffer = vec![0; initrd.size()];
+    initrd.read_into(&mut buffer).unwrap();
+    buffer
+}
+
+#[test]
+fn serve_linux_initrd_by_default() {
+    let mut table = InitrdTable::new(InitrdSource::Memory(b"linux".to_vec()));
+
+    assert_eq!(serve(&mut table, &LINUX_INITRD_DEVICE_PATH), b"linux");
+    assert!(table.get_mut(&OTHER_DEVICE_PATH).is_none());
+}
+
+#[test]
+fn serve_initrd_per_device_path() {
+    let mut table = InitrdTable::new(InitrdSource::Memory(b"linux".to_vec()));
+    table
+        .insert(&OTHER_DEVICE_PATH, InitrdSource::Memory(b"other".to_vec()))
+        .unwrap();
+
+    assert_eq!(serve(&mut table, &LINUX_INITRD_DEVICE_PATH), b"linux");
+    assert_eq!(serve(&mut table, &OTHER_DEVICE_PATH), b"other");
+}
+
+#[test]
+fn replace_initrd() {
+    let mut table = InitrdTable::new(InitrdSource::Memory(b"linux".to_vec()));
+    table
+        .insert(
+            &LINUX_INITRD_DEVICE_PATH,
+            InitrdSource::Memory(b"replaced".to_vec()),
+        )
+        .unwrap();
+
+    assert_eq!(serve(&mut table, &LINUX_INITRD_DEVICE_PATH), b"replaced");
+}
+
+#[test]
+fn reject_unterminated_device_path() {
+    let mut table = InitrdTable::new(InitrdSource::Memory(b"linux".to_vec()));
+
+    assert!(table
+        .insert(
+            &OTHER_DEVICE_PATH[..20],
+            InitrdSource::Memory(b"other".to_vec())
+        )
+        .is_err());
+}
@@ -0,0 +1,127 @@
+use std::cell::RefCell;
+
+use linux_bootloader::initrd_verification::{
+    check_digest, verify_with_retry, InitrdVerification, VerificationRetry,
+};
+use uefi::Status;
+
+#[test]
+fn always_verify_with_secure_boot() {
+    assert!(InitrdVerification::Always.required(true));
+    assert!(InitrdVerification::SecureBoot.required(true));
+}
+
+#[test]
+fn skip_verification_without_secure_boot_if_requested() {
+    assert!(InitrdVerification::Always.required(false));
+    assert!(!InitrdVerification::SecureBoot.required(false));
+}
+
+#[test]
+fn parse_section() {
+    assert_eq!(
+        InitrdVerification::parse("secure-boot"),
+        Some(InitrdVerification::SecureBoot)
+    );
+    assert_eq!(
+        InitrdVerification::parse("always\n"),
+        Some(InitrdVerification::Always)
+    );
+    // An unknown value must not weaken the verification, the stub falls back to the default.
+    assert_eq!(InitrdVerification::parse("never"), None);
+    assert_eq!(InitrdVerification::default(), InitrdVerification::Always);
+}
+
+#[test]
+fn accept_matching_hash() {
+    let hash = [7; 32];
+    assert!(check_digest(&hash, &hash, "Initrd", true).is_ok());
+    assert!(check_digest(&hash, &hash, "Initrd", false).is_ok());
+}
+
+#[test]
+fn reject_mismatching_hash_only_with_secure_boot() {
+    let error = check_digest(&[7; 32], &[8; 32], "Initrd", true).unwrap_err();
+    assert_eq!(error.status(), Status::SECURITY_VIOLATION);
+    // Without Secure Boot, the mismatch is only logged.
+    assert!(check_digest(&[7; 32], &[8; 32], "Initrd", false).is_ok());
+}
+
+/// Firmware that returns the results in `reads` for the reads of the
+/// initrd, one after the other.
+struct MockFirmware {
+    reads: RefCell<Vec<Status>>,
+    attempts: RefCell<Vec<u32>>,
+}
+
+impl MockFirmware {
+    fn new(reads: &[Status]) -> Self {
+        Self {
+            reads: RefCell::new(reads.iter().rev().copied().collect()),
+            attempts: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn verify(&self, attempt: u32) -> uefi::Result<()> {
+        self.attempts.borrow_mut().push(attempt);
+        match self.reads.borrow_mut().pop().expect("Read too often") {
+            Status::SUCCESS => Ok(()),
+            status => Err(status.into()),
+        }
+    }
+}
+
+#[test]
+fn parse_retry_section() {
+    assert_eq!(
+        VerificationRetry::parse("once\n"),
+        Some(VerificationRetry::Once)
+    );
+    assert_eq!(
+        VerificationRetry::parse("never"),
+        Some(VerificationRetry::Never)
+    );
+    assert_eq!(VerificationRetry::parse("always"), None);
+    assert_eq!(VerificationRetry::default(), VerificationRetry::Never);
+}
+
+#[test]
+fn retry_transient_violation_only_if_enabled() {
+    let firmware = MockFirmware::new(&[Status::SECURITY_VIOLATION, Status::SUCCESS]);
+    assert!(
+        verify_with_retry(VerificationRetry::Once, "the initrd", |a| firmware
+            .verify(a))
+        .is_ok()
+    );
+    assert_eq!(*firmware.attempts.borrow(), [0, 1]);
+
+    let firmware = MockFirmware::new(&[Status::SECURITY_VIOLATION, Status::SUCCESS]);
+    let error = verify_with_retry(VerificationRetry::Never, "the initrd", |a| {
+        firmware.verify(a)
+    })
+    .unwrap_err();
+    assert_eq!(error.status(), Status::SECURITY_VIOLATION);
+    assert_eq!(*firmware.attempts.borrow(), [0]);
+}
+
+#[test]
+fn stop_on_persistent_violation() {
+    let firmware = MockFirmware::new(&[Status::SECURITY_VIOLATION, Status::SECURITY_VIOLATION]);
+    let error = verify_with_retry(VerificationRetry::Once, "the initrd", |a| {
+        firmware.verify(a)
+    })
+    .unwrap_err();
+    assert_eq!(error.status(), Status::SECURITY_VIOLATION);
+    assert_eq!(*firmware.attempts.borrow(), [0, 1]);
+}
+
+#[test]
+fn do_not_retry_other_errors() {
+    let firmware = MockFirmware::new(&[Status::DEVICE_ERROR, Status::SUCCESS]);
+    let error = verify_with_retry(VerificationRetry::Once, "the initrd", |a| {
+        firmware.verify(a)
+    })
+    .unwrap_err();
+    assert_eq!(error.status(), Status::DEVICE_ERROR);
+    assert_eq!(*firmware.attempts.borrow(), [0]);
+}
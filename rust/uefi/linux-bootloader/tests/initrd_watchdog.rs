@@ -0,0 +1,58 @@
+use linux_bootloader::initrd_watchdog::{InitrdWatchdog, WatchdogTimer};
+
+/// Records the timeout it is started with. The tests expire the
+/// watchdog themselves to simulate the timer firing.
+#[derive(Default)]
+struct MockTimer {
+    started: Vec<u32>,
+}
+
+impl WatchdogTimer for MockTimer {
+    fn start(&mut self, seconds: u32) -> uefi::Result {
+        self.started.push(seconds);
+        Ok(())
+    }
+}
+
+#[test]
+fn warn_if_initrd_is_not_requested() {
+    let watchdog = InitrdWatchdog::new();
+    let mut timer = MockTimer::default();
+
+    watchdog.arm(&mut timer, 30).unwrap();
+    assert_eq!(timer.started, [30]);
+
+    let warning = watchdog.expire().expect("No warning");
+    assert!(warning.contains("within 30 s"), "{warning}");
+    // The warning is only logged once.
+    assert_eq!(watchdog.expire(), None);
+}
+
+#[test]
+fn stay_silent_if_initrd_is_requested() {
+    let watchdog = InitrdWatchdog::new();
+    let mut timer = MockTimer::default();
+
+    watchdog.arm(&mut timer, 30).unwrap();
+    watchdog.request();
+
+    assert_eq!(watchdog.expire(), None);
+}
+
+#[test]
+fn rearming_forgets_earlier_requests() {
+    let watchdog = InitrdWatchdog::new();
+    let mut timer = MockTimer::default();
+
+    // A request that was served before the watchdog was armed, e.g.
+    // to a kernel that returned, does not count.
+    watchdog.request();
+    watchdog.arm(&mut timer, 5).unwrap();
+
+    assert!(watchdog.expire().is_some());
+}
+
+#[test]
+fn ignore_expiry_without_arming() {
+    assert_eq!(InitrdWatchdog::new().expire(), None);
+}
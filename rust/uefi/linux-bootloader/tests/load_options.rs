@@ -0,0 +1,68 @@
+use linux_bootloader::load_options::{compose_load_options, LoadOptionsMode};
+use uefi::cstr16;
+
+/// Encode a string as UCS-2, as the command line is passed to the kernel.
+fn ucs2(value: &str) -> Vec<u8> {
+    value.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+#[test]
+fn parse_load_options_mode() {
+    assert_eq!(
+        LoadOptionsMode::parse("image-path\n"),
+        Some(LoadOptionsMode::ImagePath)
+    );
+    assert_eq!(
+        LoadOptionsMode::parse("cmdline"),
+        Some(LoadOptionsMode::Cmdline)
+    );
+    assert_eq!(LoadOptionsMode::parse("argv0"), None);
+}
+
+#[test]
+fn prepend_image_path_to_cmdline() {
+    let image_path = cstr16!("\\EFI\\Linux\\nixos-generation-1.efi");
+
+    assert_eq!(
+        compose_load_options(
+            LoadOptionsMode::ImagePath,
+            Some(image_path),
+            &ucs2("init=/init quiet\0")
+        ),
+        ucs2("\\EFI\\Linux\\nixos-generation-1.efi init=/init quiet\0")
+    );
+    assert_eq!(
+        compose_load_options(LoadOptionsMode::ImagePath, Some(image_path), &[]),
+        ucs2("\\EFI\\Linux\\nixos-generation-1.efi\0")
+    );
+}
+
+#[test]
+fn quote_image_path_with_spaces() {
+    assert_eq!(
+        compose_load_options(
+            LoadOptionsMode::ImagePath,
+            Some(cstr16!("\\EFI\\My Linux\\uki.efi")),
+            &ucs2("init=/init")
+        ),
+        ucs2("\"\\EFI\\My Linux\\uki.efi\" init=/init\0")
+    );
+}
+
+#[test]
+fn pass_only_cmdline_by_default() {
+    let cmdline = ucs2("init=/init\0");
+
+    assert_eq!(
+        compose_load_options(
+            LoadOptionsMode::Cmdline,
+            Some(cstr16!("\\EFI\\Linux\\uki.efi")),
+            &cmdline
+        ),
+        cmdline
+    );
+    assert_eq!(
+        compose_load_options(LoadOptionsMode::ImagePath, None, &cmdline),
+        cmdline
+    );
+}
@@ -0,0 +1,258 @@
+use linux_bootloader::measure::{
+    measure_sections, measurement_lines, read_pcr, MeasurementPolicy, Measurements, Measurer,
+    RecordingMeasurer, TPM_PCR_INDEX_KERNEL_CONFIG,
+};
+use linux_bootloader::tpm_nv::TpmCommands;
+use sha2::{Digest, Sha256};
+use uefi::{proto::tcg::PcrIndex, Status};
+
+/// Records the measured sections and fails to measure the ones in
+/// `failing`, like a TPM that enters failure mode.
+#[derive(Default)]
+struct MockTcg2 {
+    failing: Vec<&'static str>,
+    measured: Vec<(u32, String)>,
+}
+
+impl Measurer for MockTcg2 {
+    fn measure(
+        &mut self,
+        pcr_index: PcrIndex,
+        _data: &[u8],
+        description: &str,
+    ) -> uefi::Result<bool> {
+        if self.failing.contains(&description) {
+            return Err(Status::DEVICE_ERROR.into());
+        }
+        self.measured.push((pcr_index.0, description.into()));
+        Ok(true)
+    }
+}
+
+const SECTIONS: [(&str, &[u8]); 6] = [
+    (".text", b"code"),
+    (".osrel", b"ID=nixos"),
+    (".cmdline", b"init=/init"),
+    (".initrd", b"\\EFI\\nixos\\initrd.efi"),
+    (".linux", b"\\EFI\\nixos\\kernel.efi"),
+    (".pcrsig", b"{}"),
+];
+
+fn measured_names(tcg2: &MockTcg2) -> Vec<&str> {
+    tcg2.measured
+        .iter()
+        .map(|(_, name)| name.as_str())
+        .collect()
+}
+
+#[test]
+fn measure_unified_sections() {
+    let mut tcg2 = MockTcg2::default();
+
+    let measurements = measure_sections(&mut tcg2, MeasurementPolicy::Strict, SECTIONS).unwrap();
+
+    assert_eq!(measurements, 4);
+    assert_eq!(
+        measured_names(&tcg2),
+        [".osrel", ".cmdline", ".initrd", ".linux"]
+    );
+    assert!(tcg2.measured.iter().all(|(pcr, _)| *pcr == 11));
+}
+
+#[test]
+fn measure_overlays_in_file_order() {
+    let mut tcg2 = MockTcg2::default();
+    let sections: [(&str, &[u8]); 4] = [
+        (".dtbo", b"second-board"),
+        (".linux", b"\\EFI\\nixos\\kernel.efi"),
+        (".dtbo", b"first-board"),
+        (".dtb", b"board"),
+    ];
+
+    let measurements = measure_sections(&mut tcg2, MeasurementPolicy::Strict, sections).unwrap();
+
+    assert_eq!(measurements, 4);
+    assert_eq!(measured_names(&tcg2), [".dtbo", ".linux", ".dtbo", ".dtb"]);
+    assert!(tcg2.measured.iter().all(|(pcr, _)| *pcr == 11));
+}
+
+#[test]
+fn continue_after_failure_when_lenient() {
+    let mut tcg2 = MockTcg2 {
+        failing: vec![".cmdline"],
+        ..Default::default()
+    };
+
+    let measurements = measure_sections(&mut tcg2, MeasurementPolicy::Lenient, SECTIONS).unwrap();
+
+    assert_eq!(measurements, 3);
+    assert_eq!(measured_names(&tcg2), [".osrel", ".initrd", ".linux"]);
+}
+
+#[test]
+fn stop_at_failure_when_strict() {
+    let mut tcg2 = MockTcg2 {
+        failing: vec![".initrd"],
+        ..Default::default()
+    };
+
+    let err = measure_sections(&mut tcg2, MeasurementPolicy::Strict, SECTIONS).unwrap_err();
+
+    assert_eq!(err.status(), Status::DEVICE_ERROR);
+    assert_eq!(measured_names(&tcg2), [".osrel", ".cmdline"]);
+}
+
+#[test]
+fn measure_config_into_pcr_12() {
+    let mut tcg2 = MockTcg2::default();
+    let mut measurements = Measurements::new(Some(MeasurementPolicy::Strict));
+
+    measurements
+        .measure(&mut tcg2, TPM_PCR_INDEX_KERNEL_CONFIG, b"single", ".cmdl1")
+        .unwrap();
+
+    assert_eq!(tcg2.measured, [(12, ".cmdl1".to_owned())]);
+    assert_eq!(measurements.done().len(), 1);
+    assert_eq!(measurements.done()[0].pcr_index, 12);
+    assert_eq!(
+        measurements.done()[0].digest,
+        <[u8; 32]>::from(Sha256::digest(b"single"))
+    );
+}
+
+#[test]
+fn measure_nothing_without_tpm() {
+    let mut tcg2 = MockTcg2::default();
+    let mut measurements = Measurements::new(None);
+
+    measurements
+        .measure(&mut tcg2, TPM_PCR_INDEX_KERNEL_CONFIG, b"single", ".cmdl1")
+        .unwrap();
+
+    assert!(tcg2.measured.is_empty());
+    assert!(measurements.done().is_empty());
+}
+
+#[test]
+fn apply_policy_to_config_measurements() {
+    let mut tcg2 = MockTcg2 {
+        failing: vec![".cmdl1"],
+        ..Default::default()
+    };
+
+    let mut lenient = Measurements::new(Some(MeasurementPolicy::Lenient));
+    lenient
+        .measure(&mut tcg2, TPM_PCR_INDEX_KERNEL_CONFIG, b"single", ".cmdl1")
+        .unwrap();
+    assert!(lenient.done().is_empty());
+
+    let mut strict = Measurements::new(Some(MeasurementPolicy::Strict));
+    let err = strict
+        .measure(&mut tcg2, TPM_PCR_INDEX_KERNEL_CONFIG, b"single", ".cmdl1")
+        .unwrap_err();
+    assert_eq!(err.status(), Status::DEVICE_ERROR);
+}
+
+#[test]
+fn parse_measurement_policy() {
+    assert_eq!(
+        MeasurementPolicy::from_section(None),
+        MeasurementPolicy::Lenient
+    );
+    assert_eq!(
+        MeasurementPolicy::from_section(Some("strict\n")),
+        MeasurementPolicy::Strict
+    );
+    assert_eq!(
+        MeasurementPolicy::from_section(Some("lenient")),
+        MeasurementPolicy::Lenient
+    );
+    assert_eq!(
+        MeasurementPolicy::from_section(Some("paranoid")),
+        MeasurementPolicy::Lenient
+    );
+}
+
+/// A TPM whose SHA-256 bank of every PCR has the value `value`.
+struct MockPcrs {
+    value: [u8; 32],
+}
+
+impl TpmCommands for MockPcrs {
+    fn submit(&mut self, command: &[u8]) -> uefi::Result<Vec<u8>> {
+        // TPM2_PCR_Read
+        assert_eq!(command[6..10], 0x17eu32.to_be_bytes());
+        // The single selection of the command, echoed back.
+        let selection = &command[10..20];
+        let mut parameters = 1u32.to_be_bytes().to_vec();
+        parameters.extend(selection);
+        parameters.extend(1u32.to_be_bytes());
+        parameters.extend(32u16.to_be_bytes());
+        parameters.extend(self.value);
+
+        let mut response = 0x8001u16.to_be_bytes().to_vec();
+        response.extend((10 + parameters.len() as u32).to_be_bytes());
+        response.extend(0u32.to_be_bytes());
+        response.extend(parameters);
+        response.resize(4096, 0);
+        Ok(response)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[test]
+fn show_measured_sections() {
+    let mut measurer = RecordingMeasurer::new(MockTcg2 {
+        failing: vec![".initrd"],
+        ..Default::default()
+    });
+    measure_sections(&mut measurer, MeasurementPolicy::Lenient, SECTIONS).unwrap();
+    let measurements = measurer.into_measurements();
+
+    let pcr = Sha256::digest(b"pcr 11");
+    let mut tpm = MockPcrs { value: pcr.into() };
+    let lines = measurement_lines(&measurements, |index| read_pcr(&mut tpm, index));
+
+    assert_eq!(
+        lines,
+        [
+            format!(
+                "PCR 11 extended with .osrel: sha256:{}",
+                hex(&Sha256::digest(b"ID=nixos"))
+            ),
+            format!(
+                "PCR 11 extended with .cmdline: sha256:{}",
+                hex(&Sha256::digest(b"init=/init"))
+            ),
+            format!(
+                "PCR 11 extended with .linux: sha256:{}",
+                hex(&Sha256::digest(b"\\EFI\\nixos\\kernel.efi"))
+            ),
+            format!("PCR 11 is now sha256:{}", hex(&pcr)),
+        ]
+    );
+}
+
+#[test]
+fn show_unreadable_pcr() {
+    let mut measurer = RecordingMeasurer::new(MockTcg2::default());
+    measure_sections(
+        &mut measurer,
+        MeasurementPolicy::Strict,
+        SECTIONS[..2].iter().copied(),
+    )
+    .unwrap();
+
+    let lines = measurement_lines(&measurer.into_measurements(), |_| {
+        Err(Status::NOT_FOUND.into())
+    });
+
+    assert_eq!(lines.len(), 2);
+    assert!(
+        lines[1].starts_with("PCR 11 could not be read"),
+        "{lines:?}"
+    );
+}
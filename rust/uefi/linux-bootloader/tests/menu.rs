@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+
+use linux_bootloader::menu::{
+    menu_lines, run_menu, Menu, MenuEntry, MenuEvent, MenuIo, MenuState, CMDLINE_SECTIONS,
+};
+
+/// Replays a fixed sequence of timer ticks and key presses and records
+/// everything that is shown.
+struct MockIo {
+    events: VecDeque<MenuEvent>,
+    shown: Vec<MenuState>,
+}
+
+impl MockIo {
+    fn new(events: impl IntoIterator<Item = MenuEvent>) -> Self {
+        Self {
+            events: events.into_iter().collect(),
+            shown: Vec::new(),
+        }
+    }
+}
+
+impl MenuIo for MockIo {
+    fn next_event(&mut self) -> uefi::Result<MenuEvent> {
+        Ok(self
+            .events
+            .pop_front()
+            .expect("The menu waited for more events than expected"))
+    }
+
+    fn show(&mut self, _entries: &[MenuEntry], state: MenuState) {
+        self.shown.push(state);
+    }
+}
+
+fn entries() -> Vec<MenuEntry> {
+    MenuEntry::from_labels("Default\nDebug\nSingle user")
+}
+
+#[test]
+fn map_labels_to_sections() {
+    let sections = entries()
+        .into_iter()
+        .map(|entry| (entry.label, entry.section))
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        sections,
+        [
+            ("Default".to_string(), ".cmdline"),
+            ("Debug".to_string(), ".cmdl1"),
+            ("Single user".to_string(), ".cmdl2"),
+        ]
+    );
+}
+
+#[test]
+fn ignore_labels_without_section() {
+    let labels = (0..12)
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let entries = MenuEntry::from_labels(&labels);
+
+    assert_eq!(entries.len(), CMDLINE_SECTIONS.len());
+    assert_eq!(entries.last().unwrap().section, ".cmdl9");
+}
+
+#[test]
+fn boot_first_entry_after_timeout() {
+    let mut io = MockIo::new([MenuEvent::Tick, MenuEvent::Tick]);
+
+    assert_eq!(run_menu(&entries(), 2, &mut io).unwrap(), ".cmdline");
+    assert_eq!(
+        io.shown,
+        [
+            MenuState::Waiting {
+                selected: 0,
+                timeout: Some(2)
+            },
+            MenuState::Waiting {
+                selected: 0,
+                timeout: Some(1)
+            },
+            MenuState::Done(0),
+        ]
+    );
+}
+
+#[test]
+fn select_with_arrow_keys() {
+    let mut io = MockIo::new([
+        MenuEvent::Down,
+        MenuEvent::Down,
+        MenuEvent::Down,
+        MenuEvent::Up,
+        // The timeout is stopped, so ticks do not boot anymore.
+        MenuEvent::Tick,
+        MenuEvent::Tick,
+        MenuEvent::Enter,
+    ]);
+
+    assert_eq!(run_menu(&entries(), 1, &mut io).unwrap(), ".cmdl1");
+}
+
+#[test]
+fn select_with_digit() {
+    let mut io = MockIo::new([MenuEvent::Digit(7), MenuEvent::Digit(2)]);
+
+    assert_eq!(run_menu(&entries(), 5, &mut io).unwrap(), ".cmdl2");
+    // The digit without an entry only stops the timeout.
+    assert_eq!(
+        io.shown[1],
+        MenuState::Waiting {
+            selected: 0,
+            timeout: None
+        }
+    );
+}
+
+#[test]
+fn skip_menu_with_single_entry() {
+    let mut io = MockIo::new([]);
+
+    let entries = MenuEntry::from_labels("Default");
+
+    assert_eq!(run_menu(&entries, 5, &mut io).unwrap(), ".cmdline");
+    assert_eq!(Menu::new(3, 0).state(), MenuState::Done(0));
+}
+
+#[test]
+fn render_menu() {
+    let lines = menu_lines(
+        &entries(),
+        MenuState::Waiting {
+            selected: 1,
+            timeout: Some(3),
+        },
+    );
+
+    assert_eq!(
+        lines,
+        [
+            "  0: Default",
+            "> 1: Debug",
+            "  2: Single user",
+            "Booting the selected entry in 3 s.",
+        ]
+    );
+}
@@ -0,0 +1,192 @@
+use linux_bootloader::pe_section::{parse_section_table, pe_machine, pe_section, pe_sections};
+use uefi::Status;
+
+const SECTION_ALIGNMENT: u32 = 0x1000;
+const SECTIONS: [(&str, &[u8]); 2] = [
+    (".osrel", b"ID=nixos\n"),
+    (".initrd", b"\\EFI\\nixos\\initrd.efi"),
+];
+
+/// Build a loaded PE32+ image with `SECTIONS`, see [`image_with`].
+fn image(debug_directory: bool) -> Vec<u8> {
+    image_with(&SECTIONS, debug_directory)
+}
+
+/// Build a loaded PE32+ image with `sections`, laid out like the
+/// firmware maps it, i.e. with every section at its virtual address.
+///
+/// If `debug_directory` is set, the optional header points at a debug
+/// directory outside of the image. The firmware ignores it, but goblin
+/// rejects the image.
+fn image_with(sections: &[(&str, &[u8])], debug_directory: bool) -> Vec<u8> {
+    let pe_offset = 0x40;
+    let optional_header_size = 112 + 16 * 8;
+    // Every section starts on a new page.
+    let raw_sizes: Vec<u32> = sections
+        .iter()
+        .map(|(_, data)| (data.len() as u32 / SECTION_ALIGNMENT + 1) * SECTION_ALIGNMENT)
+        .collect();
+    let mut addresses = Vec::new();
+    let mut size = SECTION_ALIGNMENT;
+    for raw_size in &raw_sizes {
+        addresses.push(size);
+        size += raw_size;
+    }
+    let size = size as usize;
+    let mut image = vec![0; size];
+
+    image[..2].copy_from_slice(b"MZ");
+    image[0x3c..0x40].copy_from_slice(&(pe_offset as u32).to_le_bytes());
+
+    let coff = pe_offset + 4;
+    image[pe_offset..coff].copy_from_slice(b"PE\0\0");
+    image[coff..coff + 2].copy_from_slice(&0x8664u16.to_le_bytes());
+    image[coff + 2..coff + 4].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+    image[coff + 16..coff + 18].copy_from_slice(&(optional_header_size as u16).to_le_bytes());
+    image[coff + 18..coff + 20].copy_from_slice(&0x22u16.to_le_bytes());
+
+    let optional = coff + 20;
+    image[optional..optional + 2].copy_from_slice(&0x20bu16.to_le_bytes());
+    image[optional + 32..optional + 36].copy_from_slice(&SECTION_ALIGNMENT.to_le_bytes());
+    image[optional + 36..optional + 40].copy_from_slice(&0x200u32.to_le_bytes());
+    image[optional + 56..optional + 60].copy_from_slice(&(size as u32).to_le_bytes());
+    image[optional + 60..optional + 64].copy_from_slice(&0x200u32.to_le_bytes());
+    image[optional + 68..optional + 70].copy_from_slice(&10u16.to_le_bytes());
+    image[optional + 108..optional + 112].copy_from_slice(&16u32.to_le_bytes());
+    if debug_directory {
+        let entry = optional + 112 + 6 * 8;
+        image[entry..entry + 4].copy_from_slice(&0x7fff_0000u32.to_le_bytes());
+        image[entry + 4..entry + 8].copy_from_slice(&28u32.to_le_bytes());
+    }
+
+    let table = optional + optional_header_size;
+    for (index, (name, data)) in sections.iter().enumerate() {
+        let address = addresses[index];
+        let header = table + index * 40;
+        image[header..header + name.len()].copy_from_slice(name.as_bytes());
+        image[header + 8..header + 12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        image[header + 12..header + 16].copy_from_slice(&address.to_le_bytes());
+        image[header + 16..header + 20].copy_from_slice(&raw_sizes[index].to_le_bytes());
+        image[header + 20..header + 24].copy_from_slice(&address.to_le_bytes());
+        image[header + 36..header + 40].copy_from_slice(&0x4000_0040u32.to_le_bytes());
+
+        let start = address as usize;
+        image[start..start + data.len()].copy_from_slice(data);
+    }
+    image
+}
+
+#[test]
+fn fall_back_to_section_table_if_goblin_rejects_image() {
+    let image = image(true);
+    assert!(goblin::pe::PE::parse(&image).is_err());
+
+    for (name, data) in SECTIONS {
+        assert_eq!(pe_section(&image, name, None).unwrap(), Some(data));
+    }
+    assert_eq!(pe_section(&image, ".linux", None).unwrap(), None);
+    assert_eq!(
+        pe_sections(&image)
+            .unwrap()
+            .iter()
+            .map(|section| section.name().unwrap().to_owned())
+            .collect::<Vec<_>>(),
+        [".osrel", ".initrd"]
+    );
+}
+
+/// `image` with `value` written at `offset` in the header of the
+/// section `name`.
+fn with_header_field(image: &[u8], name: &str, offset: usize, value: u32) -> Vec<u8> {
+    let mut padded = [0; 8];
+    padded[..name.len()].copy_from_slice(name.as_bytes());
+    let header = image
+        .windows(8)
+        .position(|window| window == padded)
+        .unwrap();
+    let mut image = image.to_vec();
+    image[header + offset..header + offset + 4].copy_from_slice(&value.to_le_bytes());
+    image
+}
+
+#[test]
+fn skip_sections_outside_of_rejected_image() {
+    let image = image(true);
+
+    // Larger than its data in the file.
+    let larger = with_header_field(&image, ".osrel", 8, 2 * SECTION_ALIGNMENT);
+    assert_eq!(pe_section(&larger, ".osrel", None).unwrap(), None);
+    // Beyond the end of the image.
+    let beyond = with_header_field(&image, ".osrel", 12, u32::MAX);
+    assert_eq!(pe_section(&beyond, ".osrel", None).unwrap(), None);
+    // The other sections can still be read.
+    assert_eq!(
+        pe_section(&beyond, ".initrd", None).unwrap(),
+        Some(SECTIONS[1].1)
+    );
+}
+
+#[test]
+fn read_same_section_table_as_goblin() {
+    let image = image(false);
+    let pe = goblin::pe::PE::parse(&image).unwrap();
+
+    assert_eq!(parse_section_table(&image).unwrap(), pe.sections);
+}
+
+#[test]
+fn reject_image_without_pe_signature() {
+    let mut image = image(true);
+    image[0x40..0x44].copy_from_slice(b"NE\0\0");
+
+    assert_eq!(parse_section_table(&image), None);
+    assert_eq!(pe_section(&image, ".osrel", None).unwrap(), None);
+}
+
+#[test]
+fn reject_image_for_other_machine() {
+    let mut image = image(false);
+    image[0x44..0x46].copy_from_slice(&0xaa64u16.to_le_bytes());
+
+    assert_eq!(pe_machine(&image), Some(0xaa64));
+    assert_eq!(
+        pe_section(&image, ".osrel", Some(0x8664))
+            .unwrap_err()
+            .status(),
+        Status::UNSUPPORTED
+    );
+    assert_eq!(
+        pe_section(&image, ".osrel", Some(0xaa64)).unwrap(),
+        Some(SECTIONS[0].1)
+    );
+}
+
+#[test]
+fn accept_any_machine_without_expectation() {
+    let mut image = image(false);
+    image[0x44..0x46].copy_from_slice(&0xaa64u16.to_le_bytes());
+
+    assert_eq!(
+        pe_section(&image, ".osrel", None).unwrap(),
+        Some(SECTIONS[0].1)
+    );
+    assert_eq!(pe_section(&image, ".linux", None).unwrap(), None);
+}
+
+#[test]
+fn extract_embedded_kernel() {
+    // The kernel is a PE image itself, which is larger than a page.
+    let kernel_sections: [(&str, &[u8]); 2] = [(".text", &[0xcc; 0x1800]), (".osrel", b"kernel\n")];
+    let kernel = image_with(&kernel_sections, false);
+    let uki_sections: [(&str, &[u8]); 2] = [(".osrel", b"ID=nixos\n"), (".linux", &kernel)];
+    let uki = image_with(&uki_sections, false);
+
+    let extracted = pe_section(&uki, ".linux", None).unwrap().unwrap();
+    assert_eq!(extracted, kernel);
+    assert!(goblin::pe::PE::parse(extracted).is_ok());
+    assert_eq!(pe_machine(extracted), Some(0x8664));
+    assert_eq!(
+        pe_section(extracted, ".osrel", None).unwrap(),
+        Some(&b"kernel\n"[..])
+    );
+}
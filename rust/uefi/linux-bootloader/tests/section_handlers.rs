@@ -0,0 +1,86 @@
+use linux_bootloader::section_handlers::{CollectSection, SectionHandler, SectionRegistry};
+use uefi::Status;
+
+const SECTIONS: [(&str, &[u8]); 4] = [
+    (".osrel", b"ID=nixos\n"),
+    (".initrd", b"first"),
+    (".splash", b"BM"),
+    (".initrd", b"second"),
+];
+
+/// A custom handler that records what it receives and fails if asked
+/// to.
+struct RecordingHandler {
+    name: &'static str,
+    received: Vec<Vec<u8>>,
+    fail: bool,
+}
+
+impl RecordingHandler {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            received: Vec::new(),
+            fail: false,
+        }
+    }
+}
+
+impl SectionHandler for RecordingHandler {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn handle(&mut self, data: &[u8]) -> uefi::Result<()> {
+        self.received.push(data.to_vec());
+        if self.fail {
+            return Err(Status::UNSUPPORTED.into());
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn custom_handler_receives_its_section() {
+    let mut splash = RecordingHandler::new(".splash");
+    let mut registry = SectionRegistry::new();
+    registry.register(&mut splash);
+
+    registry.dispatch(SECTIONS).unwrap();
+
+    assert_eq!(splash.received, [b"BM".to_vec()]);
+}
+
+#[test]
+fn collect_sections_in_order() {
+    let mut initrd = CollectSection::new(".initrd");
+    let mut other = RecordingHandler::new(".initrd");
+    let mut unused = RecordingHandler::new(".dtb");
+    let mut registry = SectionRegistry::new();
+    registry.register(&mut initrd);
+    registry.register(&mut other);
+    registry.register(&mut unused);
+
+    registry.dispatch(SECTIONS).unwrap();
+
+    assert_eq!(initrd.into_data(), b"firstsecond");
+    assert_eq!(other.received, [b"first".to_vec(), b"second".to_vec()]);
+    assert!(unused.received.is_empty());
+}
+
+#[test]
+fn stop_at_failing_handler() {
+    let mut initrd = RecordingHandler::new(".initrd");
+    initrd.fail = true;
+    let mut splash = RecordingHandler::new(".splash");
+    let mut registry = SectionRegistry::new();
+    registry.register(&mut initrd);
+    registry.register(&mut splash);
+
+    assert_eq!(
+        registry.dispatch(SECTIONS).unwrap_err().status(),
+        Status::UNSUPPORTED
+    );
+    assert_eq!(initrd.received.len(), 1);
+    assert!(splash.received.is_empty());
+}
@@ -0,0 +1,81 @@
+use linux_bootloader::section_hashes::{SectionHashError, SectionHashes};
+use sha2::{Digest, Sha256};
+
+const SECTIONS: [(&str, &[u8]); 3] = [
+    (".text", b"code"),
+    (".cmdline", b"init=/nix/store/init"),
+    (".linuxh", b"0123456789abcdef0123456789abcdef"),
+];
+
+/// The manifest of `sections`, as written by lzbt.
+fn manifest(sections: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut manifest = Vec::new();
+    for (name, data) in sections {
+        let mut padded_name = [0; 8];
+        padded_name[..name.len()].copy_from_slice(name.as_bytes());
+        manifest.extend_from_slice(&padded_name);
+        manifest.extend_from_slice(&Sha256::digest(data));
+    }
+    manifest
+}
+
+/// Look up a section of `sections` by name.
+fn lookup<'a>(sections: &'a [(&str, &'a [u8])]) -> impl Fn(&str) -> Option<&'a [u8]> {
+    move |name| {
+        sections
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, data)| *data)
+    }
+}
+
+#[test]
+fn accept_matching_sections() {
+    let manifest = manifest(&SECTIONS);
+    let hashes = SectionHashes::parse(&manifest).unwrap();
+
+    assert_eq!(
+        hashes.entries().map(|(name, _)| name).collect::<Vec<_>>(),
+        [".text", ".cmdline", ".linuxh"]
+    );
+    assert_eq!(hashes.verify(lookup(&SECTIONS)), Ok(()));
+}
+
+#[test]
+fn detect_tampered_section() {
+    let manifest = manifest(&SECTIONS);
+    let hashes = SectionHashes::parse(&manifest).unwrap();
+    let mut tampered = SECTIONS;
+    tampered[1].1 = b"init=/nix/store/init rd.shell";
+
+    assert_eq!(
+        hashes.verify(lookup(&tampered)),
+        Err(SectionHashError::Mismatch(".cmdline".into()))
+    );
+}
+
+#[test]
+fn detect_missing_section() {
+    let manifest = manifest(&SECTIONS);
+    let hashes = SectionHashes::parse(&manifest).unwrap();
+
+    assert_eq!(
+        hashes.verify(lookup(&SECTIONS[..2])),
+        Err(SectionHashError::Missing(".linuxh".into()))
+    );
+}
+
+#[test]
+fn reject_malformed_manifests() {
+    let manifest = manifest(&SECTIONS);
+
+    assert_eq!(SectionHashes::parse(&[]), Err(SectionHashError::Malformed));
+    assert_eq!(
+        SectionHashes::parse(&manifest[..manifest.len() - 1]),
+        Err(SectionHashError::Malformed)
+    );
+    assert_eq!(
+        SectionHashes::parse(&[0; 40]),
+        Err(SectionHashError::Malformed)
+    );
+}
@@ -0,0 +1,15 @@
+use linux_bootloader::selftest::Capabilities;
+
+#[test]
+fn render_result() {
+    let capabilities = Capabilities {
+        load_file2: true,
+        tcg2: false,
+        gop: true,
+    };
+
+    assert_eq!(
+        capabilities.render(),
+        "version=1\nload-file2=yes\ntcg2=no\ngop=yes\n"
+    );
+}
@@ -0,0 +1,83 @@
+use core::fmt::{self, Write};
+
+use linux_bootloader::serial::{select_serial, write_record, SerialTarget};
+use log::{Level, Record};
+use uefi::Status;
+
+/// A Serial I/O device capturing the bytes written to it.
+#[derive(Default)]
+struct MockSerial {
+    bytes: Vec<u8>,
+}
+
+impl Write for MockSerial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.bytes.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[test]
+fn write_record_with_crlf() {
+    let mut serial = MockSerial::default();
+
+    write_record(
+        &mut serial,
+        &Record::builder()
+            .level(Level::Error)
+            .args(format_args!("Failed to boot:\nno kernel"))
+            .build(),
+    )
+    .unwrap();
+
+    assert_eq!(serial.bytes, b"[ERROR]: Failed to boot:\r\nno kernel\r\n");
+}
+
+#[test]
+fn write_records_in_order() {
+    let mut serial = MockSerial::default();
+
+    for (level, message) in [(Level::Info, "first"), (Level::Warn, "second")] {
+        write_record(
+            &mut serial,
+            &Record::builder()
+                .level(level)
+                .args(format_args!("{message}"))
+                .build(),
+        )
+        .unwrap();
+    }
+
+    assert_eq!(serial.bytes, b"[ INFO]: first\r\n[ WARN]: second\r\n");
+}
+
+#[test]
+fn select_serial_by_index() {
+    assert_eq!(select_serial(Ok(vec![10, 11]), 1), Ok(Some(11)));
+}
+
+#[test]
+fn skip_missing_serial() {
+    assert_eq!(select_serial(Ok(vec![10, 11]), 2), Ok(None));
+    assert_eq!(
+        select_serial::<usize>(Err(Status::NOT_FOUND.into()), 0),
+        Ok(None)
+    );
+}
+
+#[test]
+fn report_other_errors() {
+    assert_eq!(
+        select_serial::<usize>(Err(Status::DEVICE_ERROR.into()), 0)
+            .unwrap_err()
+            .status(),
+        Status::DEVICE_ERROR
+    );
+}
+
+#[test]
+fn parse_serial_target() {
+    assert_eq!(SerialTarget::parse("off"), Some(SerialTarget::Disabled));
+    assert_eq!(SerialTarget::parse(" 1\n"), Some(SerialTarget::Index(1)));
+    assert_eq!(SerialTarget::parse("com1"), None);
+}
@@ -0,0 +1,131 @@
+use linux_bootloader::smbios::{
+    append_cmdline, find_oem_string, oem_strings, parse_entry_point, structures,
+};
+
+const PREFIX: &str = "io.systemd.stub.kernel-cmdline-extra=";
+
+/// Encode an SMBIOS structure with the given formatted area after the
+/// header and the given strings.
+fn structure(kind: u8, handle: u16, formatted: &[u8], strings: &[&str]) -> Vec<u8> {
+    let mut data = vec![kind, 4 + formatted.len() as u8];
+    data.extend(handle.to_le_bytes());
+    data.extend(formatted);
+    for string in strings {
+        data.extend(string.as_bytes());
+        data.push(0);
+    }
+    if strings.is_empty() {
+        data.push(0);
+    }
+    data.push(0);
+    data
+}
+
+/// A structure table like the one of a VM with OEM strings passed by
+/// the hypervisor.
+fn mock_table() -> Vec<u8> {
+    let mut table = Vec::new();
+    // BIOS Information, referring to its vendor and version strings.
+    table.extend(structure(
+        0,
+        0,
+        &[1, 2, 0, 0xe8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        &["EDK II", "1.0"],
+    ));
+    // A structure without strings.
+    table.extend(structure(32, 1, &[0; 7], &[]));
+    table.extend(structure(
+        11,
+        2,
+        &[2],
+        &["vendor.fleet=42", &format!("{PREFIX}console=ttyS0 quiet")],
+    ));
+    table.extend(structure(127, 3, &[], &[]));
+    // Anything after the end of the table is ignored.
+    table.extend(structure(11, 4, &[1], &[&format!("{PREFIX}ignored")]));
+    table
+}
+
+#[test]
+fn walk_structures() {
+    let table = mock_table();
+    let kinds: Vec<u8> = structures(&table).map(|s| s.kind).collect();
+    assert_eq!(kinds, [0, 32, 11, 127]);
+
+    let bios = structures(&table).next().unwrap();
+    assert_eq!(bios.formatted.len(), 18);
+    assert_eq!(bios.strings, [b"EDK II".as_slice(), b"1.0"]);
+}
+
+#[test]
+fn extract_prefixed_oem_string() {
+    let table = mock_table();
+    assert_eq!(
+        oem_strings(&table),
+        [
+            b"vendor.fleet=42".as_slice(),
+            format!("{PREFIX}console=ttyS0 quiet").as_bytes()
+        ]
+    );
+    assert_eq!(find_oem_string(&table, PREFIX), Some("console=ttyS0 quiet"));
+    assert_eq!(find_oem_string(&table, "vendor.fleet="), Some("42"));
+    assert_eq!(find_oem_string(&table, "vendor.other="), None);
+}
+
+#[test]
+fn ignore_strings_beyond_count() {
+    let mut table = structure(11, 0, &[1], &["first", &format!("{PREFIX}quiet")]);
+    table.extend(structure(127, 1, &[], &[]));
+    assert_eq!(oem_strings(&table), [b"first".as_slice()]);
+    assert_eq!(find_oem_string(&table, PREFIX), None);
+}
+
+#[test]
+fn stop_at_truncated_structure() {
+    let mut table = structure(11, 0, &[1], &[&format!("{PREFIX}quiet")]);
+    let mut truncated = structure(11, 1, &[1], &[&format!("{PREFIX}evil")]);
+    // The string set of the last structure never ends.
+    truncated.truncate(truncated.len() - 2);
+    table.extend(truncated);
+
+    assert_eq!(structures(&table).count(), 1);
+    assert_eq!(find_oem_string(&table, PREFIX), Some("quiet"));
+    assert_eq!(structures(&[11, 2, 0, 0]).count(), 0);
+    assert_eq!(structures(&[]).count(), 0);
+}
+
+#[test]
+fn parse_entry_points() {
+    let mut smbios3 = b"_SM3_".to_vec();
+    smbios3.resize(0x18, 0);
+    smbios3[0x0c..0x10].copy_from_slice(&0x1234u32.to_le_bytes());
+    smbios3[0x10..0x18].copy_from_slice(&0x1_0000_0000u64.to_le_bytes());
+    assert_eq!(parse_entry_point(&smbios3), Some((0x1_0000_0000, 0x1234)));
+    assert_eq!(parse_entry_point(&smbios3[..0x10]), None);
+
+    let mut smbios = b"_SM_".to_vec();
+    smbios.resize(0x1f, 0);
+    smbios[0x16..0x18].copy_from_slice(&0x200u16.to_le_bytes());
+    smbios[0x18..0x1c].copy_from_slice(&0xf0000u32.to_le_bytes());
+    assert_eq!(parse_entry_point(&smbios), Some((0xf0000, 0x200)));
+
+    assert_eq!(parse_entry_point(b"_DMI_"), None);
+}
+
+fn ucs2(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+#[test]
+fn append_to_cmdline() {
+    assert_eq!(
+        append_cmdline(&ucs2("init=/init\0"), "quiet"),
+        ucs2("init=/init quiet\0")
+    );
+    // The command line passed by the boot loader may lack the NUL.
+    assert_eq!(
+        append_cmdline(&ucs2("init=/init"), "quiet"),
+        ucs2("init=/init quiet\0")
+    );
+    assert_eq!(append_cmdline(&[], "quiet"), ucs2("quiet\0"));
+}
@@ -0,0 +1,152 @@
+use linux_bootloader::tpm_nv::{
+    boot_parameters, parse_nv_index, read_nv_index, BootParameters, TpmCommands,
+};
+
+const INDEX: u32 = 0x0150_0016;
+const TPM_RC_HANDLE: u32 = 0x18b;
+const TPMA_NV_AUTHREAD: u32 = 1 << 18;
+const TPMA_NV_WRITTEN: u32 = 1 << 29;
+
+/// A TPM with a single NV index that can be read with an empty password.
+struct MockTpm {
+    /// The contents of the index, if it exists.
+    data: Option<Vec<u8>>,
+    /// The codes of the submitted commands.
+    commands: Vec<u32>,
+}
+
+impl MockTpm {
+    fn with_index(data: &[u8]) -> Self {
+        Self {
+            data: Some(data.to_vec()),
+            commands: Vec::new(),
+        }
+    }
+
+    fn respond(tag: u16, code: u32, parameters: &[u8]) -> Vec<u8> {
+        let mut response = Vec::new();
+        response.extend(tag.to_be_bytes());
+        response.extend((10 + parameters.len() as u32).to_be_bytes());
+        response.extend(code.to_be_bytes());
+        response.extend(parameters);
+        // The firmware hands back the whole buffer.
+        response.resize(4096, 0);
+        response
+    }
+}
+
+impl TpmCommands for MockTpm {
+    fn submit(&mut self, command: &[u8]) -> uefi::Result<Vec<u8>> {
+        let size = u32::from_be_bytes(command[2..6].try_into().unwrap());
+        assert_eq!(size as usize, command.len());
+        let code = u32::from_be_bytes(command[6..10].try_into().unwrap());
+        self.commands.push(code);
+        let handle = u32::from_be_bytes(command[10..14].try_into().unwrap());
+
+        let Some(data) = self.data.as_ref().filter(|_| handle == INDEX) else {
+            return Ok(Self::respond(0x8001, TPM_RC_HANDLE, &[]));
+        };
+        match code {
+            // TPM2_NV_ReadPublic
+            0x169 => {
+                let mut public = Vec::new();
+                public.extend(INDEX.to_be_bytes());
+                public.extend(0x000bu16.to_be_bytes());
+                public.extend((TPMA_NV_AUTHREAD | TPMA_NV_WRITTEN).to_be_bytes());
+                public.extend(2u16.to_be_bytes());
+                public.extend([0xaa, 0xbb]);
+                public.extend((data.len() as u16).to_be_bytes());
+                let mut parameters = (public.len() as u16).to_be_bytes().to_vec();
+                parameters.extend(public);
+                // The name of the index.
+                parameters.extend(0u16.to_be_bytes());
+                Ok(Self::respond(0x8001, 0, &parameters))
+            }
+            // TPM2_NV_Read
+            0x14e => {
+                assert_eq!(command[0..2], 0x8002u16.to_be_bytes());
+                let size =
+                    u16::from_be_bytes(command[command.len() - 4..][..2].try_into().unwrap());
+                let offset = u16::from_be_bytes(command[command.len() - 2..].try_into().unwrap());
+                assert!(size <= 512);
+                let chunk = &data[usize::from(offset)..usize::from(offset + size)];
+                let mut buffer = (chunk.len() as u16).to_be_bytes().to_vec();
+                buffer.extend(chunk);
+                let mut parameters = (buffer.len() as u32).to_be_bytes().to_vec();
+                parameters.extend(buffer);
+                // The response of the password session.
+                parameters.extend([0, 0, 1, 0, 0]);
+                Ok(Self::respond(0x8002, 0, &parameters))
+            }
+            _ => panic!("Unexpected command {code:#x}"),
+        }
+    }
+}
+
+#[test]
+fn parse_nv_index_handles() {
+    assert_eq!(parse_nv_index("0x01500016\n"), Some(INDEX));
+    assert_eq!(parse_nv_index("0X1500016"), Some(INDEX));
+    // Persistent objects are not NV indexes.
+    assert_eq!(parse_nv_index("0x81000001"), None);
+    assert_eq!(parse_nv_index("01500016"), None);
+}
+
+#[test]
+fn parse_boot_parameters() {
+    assert_eq!(
+        BootParameters::parse(b"# sealed\ncmdline-override=deny\ncmdline-extra = allow\n\0\0\xff"),
+        BootParameters {
+            cmdline_override: false,
+            cmdline_extra: true,
+        }
+    );
+    assert_eq!(
+        BootParameters::parse(b"cmdline-extra=maybe\nunknown=deny\n"),
+        BootParameters::default()
+    );
+}
+
+#[test]
+fn deny_cmdline_extensions_from_nv_index() {
+    let mut data = b"cmdline-extra=deny\n".to_vec();
+    data.resize(64, 0);
+    let mut tpm = MockTpm::with_index(&data);
+
+    let parameters = boot_parameters(&mut tpm, INDEX);
+
+    assert!(!parameters.cmdline_extra);
+    assert!(parameters.use_passed_cmdline(false));
+    assert!(!parameters.use_passed_cmdline(true));
+    assert_eq!(tpm.commands, [0x169, 0x14e]);
+}
+
+#[test]
+fn deny_passed_cmdline_without_secure_boot() {
+    let mut tpm = MockTpm::with_index(b"cmdline-override=deny\n");
+
+    let parameters = boot_parameters(&mut tpm, INDEX);
+
+    assert!(parameters.cmdline_extra);
+    assert!(!parameters.use_passed_cmdline(false));
+}
+
+#[test]
+fn read_large_index_in_chunks() {
+    let data = (0..1000).map(|i| i as u8).collect::<Vec<_>>();
+    let mut tpm = MockTpm::with_index(&data);
+
+    assert_eq!(read_nv_index(&mut tpm, INDEX).unwrap(), data);
+    assert_eq!(tpm.commands, [0x169, 0x14e, 0x14e]);
+}
+
+#[test]
+fn fall_back_to_defaults_without_index() {
+    let mut tpm = MockTpm {
+        data: None,
+        commands: Vec::new(),
+    };
+
+    assert_eq!(boot_parameters(&mut tpm, INDEX), BootParameters::default());
+    assert_eq!(tpm.commands, [0x169]);
+}
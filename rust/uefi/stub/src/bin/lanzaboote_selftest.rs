@@ -0,0 +1,37 @@
+//! Checks once on the target machine whether its firmware supports
+//! what the stub needs and records the result on the ESP for lzbt.
+
+#![no_main]
+#![no_std]
+#![deny(unsafe_op_in_unsafe_fn)]
+
+extern crate alloc;
+
+use core::fmt::Write;
+
+use linux_bootloader::selftest::{write_result, Capabilities};
+use uefi::prelude::*;
+
+/// How long the result stays on screen before returning to the
+/// firmware, in microseconds.
+const RESULT_DISPLAY_TIME: usize = 5_000_000;
+
+#[entry]
+fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
+    uefi_services::init(&mut system_table).unwrap();
+
+    let capabilities = Capabilities::probe(system_table.boot_services(), handle);
+    let written = write_result(system_table.boot_services(), handle, &capabilities);
+
+    let stdout = system_table.stdout();
+    // Errors are ignored, the result is on the ESP anyway.
+    for line in capabilities.render().lines() {
+        let _ = write!(stdout, "{line}\r\n");
+    }
+    if written.is_err() {
+        let _ = write!(stdout, "Failed to write the result to the ESP.\r\n");
+    }
+    system_table.boot_services().stall(RESULT_DISPLAY_TIME);
+
+    written.status()
+}
@@ -1,31 +1,35 @@
 use alloc::vec::Vec;
-use log::warn;
+use log::{info, warn};
 use uefi::{
     guid, prelude::*, proto::loaded_image::LoadedImage, table::runtime::VariableVendor, CStr16,
-    CString16, Result,
+    Result,
 };
 
-use linux_bootloader::linux_loader::InitrdLoader;
+use linux_bootloader::addons::{addon_cmdlines, measure_addon_cmdlines, UefiAddons};
+use linux_bootloader::diagnostics::log_memory_map;
+use linux_bootloader::efivars::image_identifier;
+use linux_bootloader::initrd_watchdog::{
+    UefiWatchdogTimer, DEFAULT_WATCHDOG_SECONDS, INITRD_WATCHDOG,
+};
+use linux_bootloader::linux_loader::{InitrdLoader, InitrdSource};
+use linux_bootloader::load_options::{compose_load_options, LoadOptionsMode};
+use linux_bootloader::measure::{Measurements, Tcg2Measurer};
 use linux_bootloader::pe_loader::Image;
-use linux_bootloader::pe_section::pe_section_as_string;
-
-/// Extract a string, stored as UTF-8, from a PE section.
-pub fn extract_string(pe_data: &[u8], section: &str) -> Result<CString16> {
-    let string = pe_section_as_string(pe_data, section).ok_or(Status::INVALID_PARAMETER)?;
-
-    Ok(CString16::try_from(string.as_str()).map_err(|_| Status::INVALID_PARAMETER)?)
-}
+use linux_bootloader::smbios::{append_cmdline, find_oem_string, smbios_table};
+use linux_bootloader::tpm_nv::{boot_parameters, BootParameters, Tcg2Commands};
 
 /// Obtain the kernel command line that should be used for booting.
 ///
 /// If Secure Boot is active, this is always the embedded one (since the one passed from the bootloader may come from a malicious type 1 entry).
-/// If Secure Boot is not active, the command line passed from the bootloader is used, falling back to the embedded one.
+/// If Secure Boot is not active, the command line passed from the bootloader is used, falling back to the embedded one,
+/// unless the boot parameters in the TPM deny it.
 pub fn get_cmdline(
     embedded: &CStr16,
     boot_services: &BootServices,
     secure_boot_enabled: bool,
+    parameters: &BootParameters,
 ) -> Vec<u8> {
-    if secure_boot_enabled {
+    if !parameters.use_passed_cmdline(secure_boot_enabled) {
         // The command line passed from the bootloader cannot be trusted, so it is not used when Secure Boot is active.
         embedded.as_bytes().to_vec()
     } else {
@@ -40,6 +44,84 @@ pub fn get_cmdline(
     }
 }
 
+/// Append the SMBIOS OEM string that starts with `prefix` to `cmdline`.
+///
+/// Nothing is appended if Secure Boot is active, since the SMBIOS tables are not covered by any signature.
+pub fn append_smbios_cmdline(
+    cmdline: Vec<u8>,
+    system_table: &SystemTable<Boot>,
+    prefix: Option<&str>,
+    secure_boot_enabled: bool,
+) -> Vec<u8> {
+    let Some(prefix) = prefix else {
+        return cmdline;
+    };
+    if secure_boot_enabled {
+        info!("Secure Boot is active, ignoring the command line from SMBIOS.");
+        return cmdline;
+    }
+    match smbios_table(system_table).and_then(|table| find_oem_string(table, prefix)) {
+        Some(extra) => {
+            info!("Appending {extra:?} from SMBIOS to the command line.");
+            append_cmdline(&cmdline, extra)
+        }
+        None => cmdline,
+    }
+}
+
+/// Read the boot parameters from the TPM NV index in the `.tpmnv`
+/// section. Without the section, only the embedded configuration
+/// applies.
+pub fn tpm_boot_parameters(boot_services: &BootServices, nv_index: Option<u32>) -> BootParameters {
+    match nv_index {
+        Some(index) => boot_parameters(&mut Tcg2Commands::new(boot_services), index),
+        None => BootParameters::default(),
+    }
+}
+
+/// Append the command lines of the addons on the ESP to `cmdline` if
+/// the `.addons` section enables them.
+///
+/// The command lines are measured into PCR 12 first, which fails if
+/// measurement is strict and the TPM does not cooperate.
+pub fn append_addon_cmdlines(
+    cmdline: Vec<u8>,
+    boot_services: &BootServices,
+    addons_enabled: bool,
+    measurements: &mut Measurements,
+) -> Result<Vec<u8>> {
+    if !addons_enabled {
+        return Ok(cmdline);
+    }
+    let image_path = image_identifier(boot_services);
+    let cmdlines = addon_cmdlines(&UefiAddons::new(boot_services), image_path.as_deref());
+    measure_addon_cmdlines(
+        &cmdlines,
+        &mut Tcg2Measurer::new(boot_services),
+        measurements,
+    )?;
+    Ok(cmdlines
+        .iter()
+        .fold(cmdline, |cmdline, extra| append_cmdline(&cmdline, extra)))
+}
+
+/// Compose the load options of the kernel from `cmdline` according to
+/// the `.loadopt` section.
+pub fn kernel_load_options(
+    cmdline: Vec<u8>,
+    boot_services: &BootServices,
+    mode: LoadOptionsMode,
+) -> Vec<u8> {
+    if mode == LoadOptionsMode::Cmdline {
+        return cmdline;
+    }
+    let image_path = image_identifier(boot_services);
+    if image_path.is_none() {
+        warn!("The path of the UKI is unknown, passing only the command line to the kernel.");
+    }
+    compose_load_options(mode, image_path.as_deref(), &cmdline)
+}
+
 /// Check whether Secure Boot is active, and we should be enforcing integrity checks.
 ///
 /// In case of doubt, true is returned to be on the safe side.
@@ -80,24 +162,71 @@ pub fn get_secure_boot_status(runtime_services: &RuntimeServices) -> bool {
     secure_boot_enabled
 }
 
-/// Boot the Linux kernel without checking the PE signature.
+/// A kernel that is loaded into memory and ready to be started.
+///
+/// Everything before the handoff to the kernel has happened at this
+/// point: the kernel is relocated and the initrd is served to it.
+pub struct LoadedKernel {
+    kernel: Image,
+    cmdline: Vec<u8>,
+    initrd_loader: InitrdLoader,
+}
+
+impl LoadedKernel {
+    /// Start the kernel.
+    ///
+    /// This only returns if the kernel fails to boot.
+    pub fn start(mut self, handle: Handle, system_table: &SystemTable<Boot>) -> Status {
+        // Only a warning is lost if the watchdog cannot be armed.
+        let watchdog =
+            UefiWatchdogTimer::new(system_table.boot_services()).and_then(|mut timer| {
+                INITRD_WATCHDOG.arm(&mut timer, DEFAULT_WATCHDOG_SECONDS)?;
+                Ok(timer)
+            });
+        if let Err(err) = &watchdog {
+            warn!("Failed to arm the initrd watchdog: {err:?}");
+        }
+
+        // SAFETY: The caller of `load_linux_unchecked` made sure that
+        // the kernel is trusted.
+        let status = unsafe { self.kernel.start(handle, system_table, &self.cmdline) };
+        drop(watchdog);
+
+        // The kernel only returns if it failed to take over, often because ExitBootServices was
+        // called with a stale memory map.
+        if cfg!(feature = "debug-memory-map") && status.is_error() {
+            warn!("The kernel failed to boot: {status:?}");
+            log_memory_map(system_table.boot_services());
+        }
+
+        if let Err(err) = self.initrd_loader.uninstall(system_table.boot_services()) {
+            return err.status();
+        }
+        status
+    }
+}
+
+/// Load the Linux kernel without checking the PE signature.
 ///
 /// We assume that the caller has made sure that the image is safe to
 /// be loaded using other means.
-pub fn boot_linux_unchecked(
+///
+/// The kernel is relocated into freshly allocated memory, so
+/// `kernel_data` may also point into the running image.
+pub fn load_linux_unchecked(
     handle: Handle,
-    system_table: SystemTable<Boot>,
-    kernel_data: Vec<u8>,
-    kernel_cmdline: &[u8],
-    initrd_data: Vec<u8>,
-) -> uefi::Result<()> {
-    let kernel =
-        Image::load(system_table.boot_services(), &kernel_data).expect("Failed to load the kernel");
-
-    let mut initrd_loader = InitrdLoader::new(system_table.boot_services(), handle, initrd_data)?;
+    boot_services: &BootServices,
+    kernel_data: &[u8],
+    kernel_cmdline: Vec<u8>,
+    initrd: InitrdSource,
+) -> uefi::Result<LoadedKernel> {
+    let kernel = Image::load(boot_services, kernel_data).expect("Failed to load the kernel");
 
-    let status = unsafe { kernel.start(handle, &system_table, kernel_cmdline) };
+    let initrd_loader = InitrdLoader::from_source(boot_services, handle, initrd)?;
 
-    initrd_loader.uninstall(system_table.boot_services())?;
-    status.to_result()
+    Ok(LoadedKernel {
+        kernel,
+        cmdline: kernel_cmdline,
+        initrd_loader,
+    })
 }
@@ -1,18 +1,24 @@
-use alloc::vec::Vec;
+use alloc::{string::String, vec::Vec};
+use log::error;
 use uefi::{prelude::*, CString16, Result};
 
-use crate::common::{boot_linux_unchecked, extract_string, get_cmdline, get_secure_boot_status};
-use linux_bootloader::pe_section::pe_section;
-use linux_bootloader::uefi_helpers::booted_image_file;
-
-/// Extract bytes from a PE section.
-pub fn extract_bytes(pe_data: &[u8], section: &str) -> Result<Vec<u8>> {
-    let bytes: Vec<u8> = pe_section(pe_data, section)
-        .ok_or(Status::INVALID_PARAMETER)?
-        .into();
-
-    Ok(bytes)
-}
+use crate::common::{
+    append_addon_cmdlines, append_smbios_cmdline, get_cmdline, get_secure_boot_status,
+    kernel_load_options, load_linux_unchecked, tpm_boot_parameters, LoadedKernel,
+};
+use linux_bootloader::addons::addons_enabled;
+use linux_bootloader::boot::extract_cmdline;
+use linux_bootloader::initrd_path::{
+    load_initrd_path, measure_initrd_path, InitrdPath, UefiInitrdFiles,
+};
+use linux_bootloader::linux_loader::InitrdSource;
+use linux_bootloader::load_options::LoadOptionsMode;
+use linux_bootloader::measure::{Measurements, Tcg2Measurer};
+use linux_bootloader::pe_section::{
+    check_pe_machine, pe_section, pe_section_as_string, NATIVE_MACHINE,
+};
+use linux_bootloader::section_handlers::{CollectSection, SectionRegistry};
+use linux_bootloader::tpm_nv::parse_nv_index;
 
 /// The configuration that is embedded at build time.
 ///
@@ -23,51 +29,123 @@ struct EmbeddedConfiguration {
     cmdline: CString16,
 
     /// The kernel as raw bytes.
-    kernel: Vec<u8>,
+    ///
+    /// This points into the `.linux` section of the running image
+    /// instead of being copied, because the kernel is relocated into
+    /// its own memory when it is loaded anyway.
+    kernel: &'static [u8],
 
-    /// The initrd as raw bytes.
+    /// The initrd as raw bytes: the contents of all `.initrd`
+    /// sections, in the order of the section table.
     initrd: Vec<u8>,
+
+    /// The file that is appended to the embedded initrd, see
+    /// [`linux_bootloader::initrd_path`].
+    initrd_path: Option<InitrdPath>,
+
+    /// The prefix of the SMBIOS OEM string to append to the command
+    /// line without Secure Boot.
+    smbios_prefix: Option<String>,
+
+    /// What the load options of the kernel contain.
+    load_options: LoadOptionsMode,
+
+    /// Whether the command lines of addons on the ESP are appended.
+    addons: bool,
+
+    /// The TPM NV index with boot parameters, if any.
+    tpm_nv_index: Option<u32>,
 }
 
 impl EmbeddedConfiguration {
-    fn new(file_data: &[u8]) -> Result<Self> {
+    /// Read the configuration, with the command line from
+    /// `cmdline_section`.
+    fn new(file_data: &'static [u8], cmdline_section: &str) -> Result<Self> {
+        let mut initrd = CollectSection::new(".initrd");
+        let mut registry = SectionRegistry::new();
+        registry.register(&mut initrd);
+        registry.dispatch_image(file_data)?;
+
         Ok(Self {
-            kernel: extract_bytes(file_data, ".linux")?,
-            initrd: extract_bytes(file_data, ".initrd")?,
-            cmdline: extract_string(file_data, ".cmdline")?,
+            kernel: pe_section(file_data, ".linux", Some(NATIVE_MACHINE))?
+                .ok_or(Status::INVALID_PARAMETER)?,
+            initrd: initrd.into_data(),
+            initrd_path: pe_section_as_string(file_data, ".irdpath")
+                .map(|section| InitrdPath::parse(&section))
+                .transpose()?,
+            smbios_prefix: pe_section_as_string(file_data, ".smbios"),
+            load_options: pe_section_as_string(file_data, ".loadopt")
+                .and_then(|value| LoadOptionsMode::parse(&value))
+                .unwrap_or_default(),
+            addons: addons_enabled(pe_section_as_string(file_data, ".addons").as_deref()),
+            tpm_nv_index: pe_section_as_string(file_data, ".tpmnv")
+                .and_then(|section| parse_nv_index(&section)),
+            cmdline: extract_cmdline(file_data, cmdline_section)?,
         })
     }
 }
 
-pub fn boot_linux(
+/// Load the kernel and initrd embedded in the stub.
+///
+/// Configuration from outside the image, e.g. addons, is measured with
+/// `measurements`.
+pub fn boot(
     handle: Handle,
-    mut system_table: SystemTable<Boot>,
+    system_table: &SystemTable<Boot>,
+    image: &'static [u8],
     dynamic_initrds: Vec<Vec<u8>>,
-) -> Status {
-    uefi_services::init(&mut system_table).unwrap();
-
-    // SAFETY: We get a slice that represents our currently running
-    // image and then parse the PE data structures from it. This is
-    // safe, because we don't touch any data in the data sections that
-    // might conceivably change while we look at the slice.
-    let mut config = unsafe {
-        EmbeddedConfiguration::new(
-            booted_image_file(system_table.boot_services())
-                .unwrap()
-                .as_slice(),
-        )
-        .expect("Failed to extract configuration from binary.")
-    };
+    cmdline_section: &str,
+    measurements: &mut Measurements,
+) -> uefi::Result<LoadedKernel> {
+    let mut config = EmbeddedConfiguration::new(image, cmdline_section).map_err(|err| {
+        error!("Failed to extract configuration from binary: {err:?}");
+        err
+    })?;
+    // Firmware with an emulator would start a kernel of another
+    // architecture through it.
+    check_pe_machine(config.kernel, Some(NATIVE_MACHINE))?;
 
     let secure_boot_enabled = get_secure_boot_status(system_table.runtime_services());
-    let cmdline = get_cmdline(
-        &config.cmdline,
-        system_table.boot_services(),
+    let parameters = tpm_boot_parameters(system_table.boot_services(), config.tpm_nv_index);
+    let cmdline = append_smbios_cmdline(
+        get_cmdline(
+            &config.cmdline,
+            system_table.boot_services(),
+            secure_boot_enabled,
+            &parameters,
+        ),
+        system_table,
+        config
+            .smbios_prefix
+            .as_deref()
+            .filter(|_| parameters.cmdline_extra),
         secure_boot_enabled,
     );
+    let cmdline = append_addon_cmdlines(
+        cmdline,
+        system_table.boot_services(),
+        config.addons && parameters.cmdline_extra,
+        measurements,
+    )?;
+    let cmdline = kernel_load_options(cmdline, system_table.boot_services(), config.load_options);
 
     let mut final_initrd = Vec::new();
     final_initrd.append(&mut config.initrd);
+    if let Some(initrd_path) = &config.initrd_path {
+        let files = UefiInitrdFiles::new(system_table.boot_services());
+        let mut initrd_file =
+            load_initrd_path(&files, initrd_path, secure_boot_enabled).map_err(|err| {
+                error!("Failed to load the initrd file: {err:?}");
+                err
+            })?;
+        measure_initrd_path(
+            pe_section(image, ".irdpath", Some(NATIVE_MACHINE))?.unwrap_or_default(),
+            &initrd_file,
+            &mut Tcg2Measurer::new(system_table.boot_services()),
+            measurements,
+        )?;
+        final_initrd.append(&mut initrd_file);
+    }
 
     // Correctness: dynamic initrds are supposed to be validated by caller,
     // i.e. they are system extension images or credentials
@@ -77,5 +155,11 @@ pub fn boot_linux(
         final_initrd.append(&mut extra_initrd);
     }
 
-    boot_linux_unchecked(handle, system_table, config.kernel, &cmdline, final_initrd).status()
+    load_linux_unchecked(
+        handle,
+        system_table.boot_services(),
+        config.kernel,
+        cmdline,
+        InitrdSource::Memory(final_initrd),
+    )
 }
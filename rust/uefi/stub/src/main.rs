@@ -16,12 +16,37 @@ mod thin;
 compile_error!("A thin and fat stub cannot be produced at the same time, disable either `thin` or `fat` feature");
 
 use alloc::vec::Vec;
-use linux_bootloader::efivars::{export_efi_variables, get_loader_features, EfiLoaderFeatures};
-use linux_bootloader::measure::measure_image;
+use linux_bootloader::boot_fallback::{start_with_fallback, KernelFailurePolicy};
+use linux_bootloader::chainload::{chainload, chainload_target, UefiChainload};
+use linux_bootloader::confirmation::{ask_confirmation, confirmation_required, UefiConfirmationIo};
+use linux_bootloader::countdown::{run_countdown, UefiCountdownIo};
+use linux_bootloader::deploy::{export_deploy_variables, DeployInfo};
+use linux_bootloader::devicetree::install_devicetree;
+use linux_bootloader::diagnostics::{pressed_key, show_diagnostics, Diagnostics};
+use linux_bootloader::efivars::{
+    export_efi_variables, get_loader_features, EfiLoaderFeatures, RuntimeVariables,
+};
+use linux_bootloader::firmware_policy::{FirmwareAction, FirmwarePolicy};
+use linux_bootloader::firmware_setup::{request_firmware_setup, SetupKey};
+use linux_bootloader::measure::{
+    measure_image, show_measurements, MeasurementPolicy, Measurements, Tcg2Measurer,
+    TPM_PCR_INDEX_KERNEL_CONFIG,
+};
+use linux_bootloader::menu::{
+    menu_entries, run_menu, UefiMenuIo, CMDLINE_SECTIONS, DEFAULT_TIMEOUT,
+};
+use linux_bootloader::pe_section::{pe_section, pe_section_as_string, NATIVE_MACHINE};
+use linux_bootloader::serial::{init_logger, SerialTarget};
 use linux_bootloader::tpm::tpm_available;
 use linux_bootloader::uefi_helpers::booted_image_file;
-use log::info;
-use uefi::prelude::*;
+use log::{error, info, warn};
+use uefi::{prelude::*, proto::console::text::Key, table::runtime::ResetType};
+
+#[cfg(feature = "fat")]
+use fat::boot;
+
+#[cfg(feature = "thin")]
+use thin::boot;
 
 /// Lanzaboote stub name
 pub static STUB_NAME: &str = concat!("lanzastub ", env!("CARGO_PKG_VERSION"));
@@ -41,22 +66,263 @@ fn print_logo() {
     );
 }
 
+/// Determine where log output should be duplicated to.
+///
+/// The `.serial` section contains either `off` or the index of the
+/// serial device to use. Without the section, we only log to the text
+/// output.
+fn serial_target(image: &[u8]) -> Option<SerialTarget> {
+    let section = pe_section_as_string(image, ".serial")?;
+    SerialTarget::parse(&section)
+}
+
+/// Determine how many seconds to wait before booting.
+///
+/// The `.timeout` section contains the number of seconds. Without the
+/// section, we boot right away.
+fn countdown_seconds(image: &[u8]) -> Option<u32> {
+    let section = pe_section_as_string(image, ".timeout")?;
+    section.trim().parse().ok()
+}
+
+/// Determine what to do if measuring the image fails.
+///
+/// The `.measure` section contains `strict` to stop the boot. Without
+/// the section, failures are only logged.
+fn measurement_policy(image: &[u8]) -> MeasurementPolicy {
+    let section = pe_section_as_string(image, ".measure");
+    MeasurementPolicy::from_section(section.as_deref())
+}
+
+/// Whether to print the measurements before starting the kernel.
+///
+/// This is the case if the image has a `.pcrshow` section.
+fn measurements_shown(image: &[u8]) -> bool {
+    pe_section_as_string(image, ".pcrshow").is_some()
+}
+
+/// Determine what to do if the kernel fails to start.
+///
+/// The `.kfail` section contains `next-entry` to boot the next entry of
+/// systemd-boot instead. Without the section, the error is returned to
+/// the firmware.
+fn kernel_failure_policy(image: &[u8]) -> KernelFailurePolicy {
+    let Some(section) = pe_section_as_string(image, ".kfail") else {
+        return KernelFailurePolicy::Return;
+    };
+    KernelFailurePolicy::parse(&section).unwrap_or_else(|| {
+        warn!("Ignoring malformed .kfail section: {section:?}");
+        KernelFailurePolicy::Return
+    })
+}
+
+/// Export the slot and version from the `.deploy` section, if there
+/// is one.
+fn export_deploy_info(system_table: &SystemTable<Boot>, image: &[u8]) {
+    let Some(section) = pe_section_as_string(image, ".deploy") else {
+        return;
+    };
+    match DeployInfo::parse(&section) {
+        Ok(info) => {
+            info!(
+                "Booting slot {} at version {}.",
+                info.slot.as_str(),
+                info.version
+            );
+            if let Err(err) = export_deploy_variables(system_table.runtime_services(), &info) {
+                warn!("Failed to export the deployment: {err:?}");
+            }
+        }
+        Err(err) => warn!("Ignoring malformed .deploy section: {err}"),
+    }
+}
+
+/// Check the firmware against the `.fwmin` section, if there is one.
+///
+/// Returns `false` if the firmware is too old to boot on.
+fn check_firmware(system_table: &SystemTable<Boot>, image: &[u8]) -> bool {
+    let Some(section) = pe_section_as_string(image, ".fwmin") else {
+        return true;
+    };
+    let policy = match FirmwarePolicy::parse(&section) {
+        Ok(policy) => policy,
+        Err(err) => {
+            warn!("Ignoring malformed .fwmin section: {err}");
+            return true;
+        }
+    };
+    match (policy.check(system_table), policy.action) {
+        (None, _) => true,
+        (Some(message), FirmwareAction::Warn) => {
+            warn!("{message}");
+            true
+        }
+        (Some(message), FirmwareAction::Refuse) => {
+            error!("{message}");
+            false
+        }
+    }
+}
+
+/// Ask the user to confirm booting without Secure Boot if the image has a
+/// `.confirm` section.
+///
+/// Returns `false` if booting was declined or the user cannot be asked.
+fn confirm_boot(system_table: &mut SystemTable<Boot>, image: &[u8]) -> bool {
+    let section = pe_section_as_string(image, ".confirm");
+    let secure_boot = common::get_secure_boot_status(system_table.runtime_services());
+    if !confirmation_required(section.as_deref(), secure_boot) {
+        return true;
+    }
+
+    warn!("Secure Boot is disabled, waiting for confirmation to boot.");
+    match ask_confirmation(&mut UefiConfirmationIo::new(system_table)) {
+        Ok(true) => true,
+        Ok(false) => {
+            error!("Booting without Secure Boot was declined.");
+            false
+        }
+        Err(err) => {
+            error!("Failed to ask for confirmation, not booting without Secure Boot: {err:?}");
+            false
+        }
+    }
+}
+
+/// Start the boot loader in the `.chain` section, if there is one and
+/// the stub was not started by a boot loader itself.
+///
+/// Returns `true` if the boot loader ran and exited successfully.
+fn maybe_chainload(system_table: &SystemTable<Boot>, image: &[u8]) -> bool {
+    let section = pe_section_as_string(image, ".chain");
+    let started_by_boot_loader = get_loader_features(system_table.runtime_services()).is_ok();
+    let Some(path) = chainload_target(section.as_deref(), started_by_boot_loader) else {
+        return false;
+    };
+
+    info!("Starting the boot loader at {path}.");
+    match chainload(&UefiChainload::new(system_table.boot_services()), &path) {
+        Ok(true) => true,
+        Ok(false) => {
+            info!("{path} does not exist, booting the kernel instead.");
+            false
+        }
+        Err(err) => {
+            warn!("Failed to start {path}, booting the kernel instead: {err:?}");
+            false
+        }
+    }
+}
+
+/// Reboot into the firmware setup if `key` is the one in the `.fwsetup`
+/// section.
+///
+/// Returns if `key` is another one or the firmware cannot be asked to
+/// show its setup.
+fn maybe_enter_firmware_setup(system_table: &SystemTable<Boot>, image: &[u8], key: &Key) {
+    let Some(section) = pe_section_as_string(image, ".fwsetup") else {
+        return;
+    };
+    let Some(setup_key) = SetupKey::parse(&section) else {
+        warn!("Ignoring malformed .fwsetup section: {section:?}");
+        return;
+    };
+    if !setup_key.matches(key) {
+        return;
+    }
+
+    match request_firmware_setup(&mut RuntimeVariables(system_table.runtime_services())) {
+        Ok(()) => {
+            info!("Rebooting into the firmware setup.");
+            system_table
+                .runtime_services()
+                .reset(ResetType::COLD, Status::SUCCESS, None)
+        }
+        Err(err) => warn!("Failed to request the firmware setup, booting instead: {err:?}"),
+    }
+}
+
+/// Measure the command line chosen in the boot menu into PCR 12, unless
+/// it is the one in `.cmdline`, which was measured with the image.
+fn measure_cmdline_variant(
+    system_table: &SystemTable<Boot>,
+    image: &[u8],
+    cmdline_section: &str,
+    measurements: &mut Measurements,
+) -> uefi::Result<()> {
+    if cmdline_section == CMDLINE_SECTIONS[0] {
+        return Ok(());
+    }
+    let Some(cmdline) = pe_section(image, cmdline_section, Some(NATIVE_MACHINE))? else {
+        return Ok(());
+    };
+    measurements.measure(
+        &mut Tcg2Measurer::new(system_table.boot_services()),
+        TPM_PCR_INDEX_KERNEL_CONFIG,
+        cmdline,
+        cmdline_section,
+    )
+}
+
+/// Show the diagnostic page if a key was pressed while the stub started.
+fn maybe_show_diagnostics(system_table: &mut SystemTable<Boot>, image: &[u8], key_pressed: bool) {
+    if !key_pressed {
+        return;
+    }
+    let secure_boot = common::get_secure_boot_status(system_table.runtime_services());
+    let diagnostics = Diagnostics::collect(system_table, image, secure_boot);
+    show_diagnostics(system_table, &diagnostics);
+}
+
 #[entry]
 fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
     uefi_services::init(&mut system_table).unwrap();
 
+    let image = match booted_image_file(system_table.boot_services()) {
+        Ok(image) => image,
+        Err(err) => {
+            error!("Failed to open the running image: {err:?}");
+            return err.status();
+        }
+    };
+    // SAFETY: The configuration is read from the sections of the
+    // running image, which the stub never modifies. The image is only
+    // looked up here and handed to everything that reads it.
+    let image = unsafe { image.as_slice() };
+
+    let target = serial_target(image).unwrap_or(SerialTarget::Disabled);
+    let serial_enabled = init_logger(&mut system_table, target).unwrap();
+
     print_logo();
 
-    if tpm_available(system_table.boot_services()) {
+    if target != SerialTarget::Disabled && !serial_enabled {
+        warn!("No serial device found, logging to the console only.");
+    }
+
+    // The key pressed while the stub started either requests the firmware
+    // setup or shows the diagnostic page.
+    let key = pressed_key(&mut system_table);
+    if let Some(key) = &key {
+        maybe_enter_firmware_setup(&system_table, image, key);
+    }
+    maybe_show_diagnostics(&mut system_table, image, key.is_some());
+
+    let policy = measurement_policy(image);
+    let tpm = tpm_available(system_table.boot_services());
+    let mut measurements = Measurements::new(tpm.then_some(policy));
+    if tpm {
         info!("TPM available, will proceed to measurements.");
         // Iterate over unified sections and measure them
-        // For now, ignore failures during measurements.
-        // TODO: in the future, devise a threat model where this can fail
-        // and ensure this hard-fail correctly.
-        let _ = measure_image(
-            &system_table,
-            booted_image_file(system_table.boot_services()).unwrap(),
-        );
+        match measure_image(&system_table, image, policy) {
+            Ok(done) => measurements.record(done),
+            Err(err) if policy == MeasurementPolicy::Strict => {
+                error!(
+                    "Measuring the image failed and strict measurement is enabled, not booting."
+                );
+                return err.status();
+            }
+            Err(err) => warn!("Failed to measure the image: {err:?}"),
+        }
         // TODO: Measure kernel parameters
         // TODO: Measure sysexts
     }
@@ -67,22 +333,88 @@ fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
             info!("Random seed is available, but lanzaboote does not support it yet.");
         }
     }
+    // The boot loader is started before the stub exports its variables,
+    // so that they describe the UKI that is eventually booted.
+    if maybe_chainload(&system_table, image) {
+        return Status::SUCCESS;
+    }
+
     export_efi_variables(STUB_NAME, &system_table).expect("Failed to export stub EFI variables");
+    export_deploy_info(&system_table, image);
 
-    let status;
-    // A list of dynamically assembled initrds, e.g. credential initrds or system extension
-    // initrds.
-    let dynamic_initrds: Vec<Vec<u8>> = Vec::new();
+    if !check_firmware(&system_table, image) {
+        return Status::UNSUPPORTED;
+    }
 
-    #[cfg(feature = "fat")]
-    {
-        status = fat::boot_linux(handle, system_table, dynamic_initrds)
+    if !confirm_boot(&mut system_table, image) {
+        return Status::SECURITY_VIOLATION;
     }
 
-    #[cfg(feature = "thin")]
+    let entries = menu_entries(image);
+    let cmdline_section = if entries.len() > 1 {
+        // The menu replaces the countdown and uses its timeout.
+        let seconds = countdown_seconds(image).unwrap_or(DEFAULT_TIMEOUT);
+        UefiMenuIo::new(&mut system_table)
+            .and_then(|mut io| run_menu(&entries, seconds, &mut io))
+            .unwrap_or_else(|err| {
+                warn!("Failed to run the boot menu, booting the default entry: {err:?}");
+                CMDLINE_SECTIONS[0]
+            })
+    } else {
+        if let Some(seconds) = countdown_seconds(image) {
+            // The countdown is a convenience, so boot anyway if it fails.
+            if let Err(err) = UefiCountdownIo::new(&mut system_table)
+                .and_then(|mut io| run_countdown(seconds, &mut io))
+            {
+                warn!("Failed to run the boot countdown: {err:?}");
+            }
+        }
+        CMDLINE_SECTIONS[0]
+    };
+
+    if let Err(err) =
+        measure_cmdline_variant(&system_table, image, cmdline_section, &mut measurements)
     {
-        status = thin::boot_linux(handle, system_table, dynamic_initrds).status()
+        error!("Measuring the command line failed and strict measurement is enabled, not booting.");
+        return err.status();
     }
 
-    status
+    if let Err(err) = install_devicetree(&system_table, image) {
+        warn!("Failed to install the devicetree: {err:?}");
+    }
+
+    // A list of dynamically assembled initrds, e.g. credential initrds or system extension
+    // initrds.
+    let dynamic_initrds: Vec<Vec<u8>> = Vec::new();
+
+    let failure_policy = kernel_failure_policy(image);
+    let show = measurements_shown(image);
+
+    let kernel = boot(
+        handle,
+        &system_table,
+        image,
+        dynamic_initrds,
+        cmdline_section,
+        &mut measurements,
+    );
+    // Everything is measured once the kernel is ready to start.
+    if show && kernel.is_ok() {
+        show_measurements(&mut system_table, measurements.done());
+    }
+    // A kernel that cannot be loaded, e.g. because its initrd is
+    // missing, fails like one that does not start.
+    start_with_fallback(
+        failure_policy,
+        &mut RuntimeVariables(system_table.runtime_services()),
+        || match kernel {
+            Ok(kernel) => kernel.start(handle, &system_table),
+            Err(err) => err.status(),
+        },
+        || {
+            system_table
+                .runtime_services()
+                .reset(ResetType::COLD, Status::SUCCESS, None)
+        },
+    )
 }
@@ -1,147 +1,172 @@
-use alloc::vec::Vec;
-use log::{error, warn};
-use sha2::{Digest, Sha256};
-use uefi::{fs::FileSystem, prelude::*, CString16, Result};
-
-use crate::common::{boot_linux_unchecked, extract_string, get_cmdline, get_secure_boot_status};
-use linux_bootloader::pe_section::pe_section;
-use linux_bootloader::uefi_helpers::booted_image_file;
-
-type Hash = sha2::digest::Output<Sha256>;
-
-/// The configuration that is embedded at build time.
-///
-/// After this stub is built, lzbt needs to embed configuration into the binary by adding PE
-/// sections. This struct represents that information.
-struct EmbeddedConfiguration {
-    /// The filename of the kernel to be booted. This filename is
-    /// relative to the root of the volume that contains the
-    /// lanzaboote binary.
-    kernel_filename: CString16,
-
-    /// The cryptographic hash of the kernel.
-    kernel_hash: Hash,
+use alloc::{boxed::Box, vec, vec::Vec};
+use log::error;
+use uefi::{
+    prelude::*,
+    proto::{
+        media::file::{File, FileAttribute, FileInfo, FileMode, RegularFile},
+        tcg::PcrIndex,
+    },
+    CStr16, Result,
+};
+
+use crate::common::{
+    append_addon_cmdlines, append_smbios_cmdline, get_cmdline, get_secure_boot_status,
+    kernel_load_options, load_linux_unchecked, tpm_boot_parameters, LoadedKernel,
+};
+use linux_bootloader::boot::{BootFirmware, EmbeddedConfiguration};
+use linux_bootloader::initrd_path::{InitrdFiles, UefiInitrdFiles};
+use linux_bootloader::linux_loader::{InitrdFile, InitrdSource};
+use linux_bootloader::measure::{Measurements, Measurer, Tcg2Measurer};
+
+/// Boots the kernel with the boot services.
+struct UefiBootFirmware<'a> {
+    handle: Handle,
+    system_table: &'a SystemTable<Boot>,
+}
 
-    /// The filename of the initrd to be passed to the kernel. See
-    /// `kernel_filename` for how to interpret these filenames.
-    initrd_filename: CString16,
+impl UefiBootFirmware<'_> {
+    /// Open a file on the volume that contains the stub.
+    fn open_image_file(&self, filename: &CStr16) -> Result<RegularFile> {
+        let mut file_system = self
+            .system_table
+            .boot_services()
+            .get_image_file_system(self.handle)?;
 
-    /// The cryptographic hash of the initrd. This hash is computed
-    /// over the whole PE binary, not only the embedded initrd.
-    initrd_hash: Hash,
+        file_system
+            .open_volume()?
+            .open(filename, FileMode::Read, FileAttribute::empty())?
+            .into_regular_file()
+            .ok_or(Status::INVALID_PARAMETER.into())
+    }
 
-    /// The kernel command-line.
-    cmdline: CString16,
+    /// Read a file on the volume that contains the stub into memory.
+    ///
+    /// Unlike [`FileSystem::read`](uefi::fs::FileSystem::read), this
+    /// keeps the status of the firmware, so that a refused read can be
+    /// retried.
+    fn read_image_file(&self, filename: &CStr16) -> Result<Vec<u8>> {
+        let mut file = self.open_image_file(filename)?;
+        let size = file_size(&mut file)?;
+        let mut data = vec![0; size];
+        let mut filled = 0;
+        while filled < size {
+            match InitrdFile::read(&mut file, &mut data[filled..])? {
+                // The file shrunk since we looked at its size.
+                0 => return Err(Status::END_OF_FILE.into()),
+                read => filled += read,
+            }
+        }
+        Ok(data)
+    }
 }
 
-/// Extract a SHA256 hash from a PE section.
-fn extract_hash(pe_data: &[u8], section: &str) -> Result<Hash> {
-    let array: [u8; 32] = pe_section(pe_data, section)
-        .ok_or(Status::INVALID_PARAMETER)?
-        .try_into()
-        .map_err(|_| Status::INVALID_PARAMETER)?;
-
-    Ok(array.into())
+/// The size of `file` in bytes.
+fn file_size(file: &mut RegularFile) -> Result<usize> {
+    let size = file.get_boxed_info::<FileInfo>()?.file_size();
+    Ok(usize::try_from(size).map_err(|_| Status::BAD_BUFFER_SIZE)?)
 }
 
-impl EmbeddedConfiguration {
-    fn new(file_data: &[u8]) -> Result<Self> {
-        Ok(Self {
-            kernel_filename: extract_string(file_data, ".linux")?,
-            kernel_hash: extract_hash(file_data, ".linuxh")?,
-
-            initrd_filename: extract_string(file_data, ".initrd")?,
-            initrd_hash: extract_hash(file_data, ".initrdh")?,
-
-            cmdline: extract_string(file_data, ".cmdline")?,
-        })
+impl InitrdFiles for UefiBootFirmware<'_> {
+    fn read(&self, device: Option<&CStr16>, file: &CStr16) -> Result<Vec<u8>> {
+        UefiInitrdFiles::new(self.system_table.boot_services()).read(device, file)
     }
 }
 
-/// Verify some data against its expected hash.
-///
-/// In case of a mismatch:
-/// * If Secure Boot is active, an error message is logged, and the SECURITY_VIOLATION error is returned to stop the boot.
-/// * If Secure Boot is not active, only a warning is logged, and the boot process is allowed to continue.
-fn check_hash(data: &[u8], expected_hash: Hash, name: &str, secure_boot: bool) -> uefi::Result<()> {
-    let hash_correct = Sha256::digest(data) == expected_hash;
-    if !hash_correct {
-        if secure_boot {
-            error!("{name} hash does not match!");
-            return Err(Status::SECURITY_VIOLATION.into());
-        } else {
-            warn!("{name} hash does not match! Continuing anyway.");
-        }
+impl Measurer for UefiBootFirmware<'_> {
+    fn measure(&mut self, pcr_index: PcrIndex, data: &[u8], description: &str) -> Result<bool> {
+        Tcg2Measurer::new(self.system_table.boot_services()).measure(pcr_index, data, description)
     }
-    Ok(())
 }
 
-pub fn boot_linux(
-    handle: Handle,
-    mut system_table: SystemTable<Boot>,
-    dynamic_initrds: Vec<Vec<u8>>,
-) -> uefi::Result<()> {
-    uefi_services::init(&mut system_table).unwrap();
-
-    // SAFETY: We get a slice that represents our currently running
-    // image and then parse the PE data structures from it. This is
-    // safe, because we don't touch any data in the data sections that
-    // might conceivably change while we look at the slice.
-    let config = unsafe {
-        EmbeddedConfiguration::new(
-            booted_image_file(system_table.boot_services())
-                .unwrap()
-                .as_slice(),
-        )
-        .expect("Failed to extract configuration from binary. Did you run lzbt?")
-    };
+impl BootFirmware for UefiBootFirmware<'_> {
+    type Kernel = LoadedKernel;
 
-    let secure_boot_enabled = get_secure_boot_status(system_table.runtime_services());
-
-    let kernel_data;
-    let mut initrd_data;
-
-    {
-        let file_system = system_table
-            .boot_services()
-            .get_image_file_system(handle)
-            .expect("Failed to get file system handle");
-        let mut file_system = FileSystem::new(file_system);
+    fn secure_boot(&self) -> bool {
+        get_secure_boot_status(self.system_table.runtime_services())
+    }
 
-        kernel_data = file_system
-            .read(&*config.kernel_filename)
-            .expect("Failed to read kernel file into memory");
-        initrd_data = file_system
-            .read(&*config.initrd_filename)
-            .expect("Failed to read initrd file into memory");
+    fn read_file(&mut self, path: &CStr16) -> Result<Vec<u8>> {
+        self.read_image_file(path).map_err(|err| {
+            error!("Failed to read {path}: {err:?}");
+            err
+        })
     }
 
-    let cmdline = get_cmdline(
-        &config.cmdline,
-        system_table.boot_services(),
-        secure_boot_enabled,
-    );
+    fn open_file(&mut self, path: &CStr16) -> Result<(Box<dyn InitrdFile>, usize)> {
+        let mut file = self.open_image_file(path)?;
+        let size = file_size(&mut file)?;
+        let file: Box<dyn InitrdFile> = Box::new(file);
+        Ok((file, size))
+    }
 
-    check_hash(
-        &kernel_data,
-        config.kernel_hash,
-        "Kernel",
-        secure_boot_enabled,
-    )?;
-    check_hash(
-        &initrd_data,
-        config.initrd_hash,
-        "Initrd",
-        secure_boot_enabled,
-    )?;
+    fn cmdline(
+        &mut self,
+        config: &EmbeddedConfiguration,
+        secure_boot: bool,
+        measurements: &mut Measurements,
+    ) -> Result<Vec<u8>> {
+        let boot_services = self.system_table.boot_services();
+        let parameters = tpm_boot_parameters(boot_services, config.tpm_nv_index);
+        let cmdline = append_smbios_cmdline(
+            get_cmdline(&config.cmdline, boot_services, secure_boot, &parameters),
+            self.system_table,
+            config
+                .smbios_prefix
+                .as_deref()
+                .filter(|_| parameters.cmdline_extra),
+            secure_boot,
+        );
+        let cmdline = append_addon_cmdlines(
+            cmdline,
+            boot_services,
+            config.addons && parameters.cmdline_extra,
+            measurements,
+        )?;
+        Ok(kernel_load_options(
+            cmdline,
+            boot_services,
+            config.load_options,
+        ))
+    }
 
-    // Correctness: dynamic initrds are supposed to be validated by caller,
-    // i.e. they are system extension images or credentials
-    // that are supposedly measured in TPM2.
-    // Therefore, it is normal to not verify their hashes against a configuration.
-    for mut extra_initrd in dynamic_initrds {
-        initrd_data.append(&mut extra_initrd);
+    fn load_kernel(
+        &mut self,
+        kernel: &[u8],
+        cmdline: Vec<u8>,
+        initrd: InitrdSource,
+    ) -> Result<LoadedKernel> {
+        load_linux_unchecked(
+            self.handle,
+            self.system_table.boot_services(),
+            kernel,
+            cmdline,
+            initrd,
+        )
     }
+}
 
-    boot_linux_unchecked(handle, system_table, kernel_data, &cmdline, initrd_data)
+/// Verify and load the kernel and initrd referenced by the stub, see
+/// [`linux_bootloader::boot::boot`].
+///
+/// Configuration from outside the image, e.g. addons, is measured with
+/// `measurements`.
+pub fn boot(
+    handle: Handle,
+    system_table: &SystemTable<Boot>,
+    image: &'static [u8],
+    dynamic_initrds: Vec<Vec<u8>>,
+    cmdline_section: &str,
+    measurements: &mut Measurements,
+) -> Result<LoadedKernel> {
+    linux_bootloader::boot::boot(
+        &mut UefiBootFirmware {
+            handle,
+            system_table,
+        },
+        image,
+        cmdline_section,
+        dynamic_initrds,
+        cfg!(feature = "lazy-initrd"),
+        measurements,
+    )
 }